@@ -0,0 +1,49 @@
+// Compare statsd parser of bioyino-metric with zero-copy parser of bioyino.
+// Run with `cargo bench --bench parser`, add `--features simd` to measure SIMD delimiter search.
+use bytes::BytesMut;
+use criterion::{criterion_group, criterion_main, Benchmark, Criterion, Throughput};
+
+use bioyino_metric::parser::{MetricParser, ParseErrorHandler};
+
+#[path = "../src/parser.rs"]
+#[allow(dead_code)]
+mod parser;
+
+use crate::parser::StatsdParser;
+
+type Float = f64;
+
+struct IgnoreErrors;
+
+impl ParseErrorHandler for IgnoreErrors {
+    fn handle(&self, _: &[u8], _: usize) {}
+}
+
+// a datagram of typical size with metrics of all types
+fn datagram() -> Vec<u8> {
+    let mut buf = Vec::new();
+    for i in 0..20 {
+        buf.extend_from_slice(format!("some.service.host{}.requests:1|c\nsome.service.host{}.latency:{}.5|ms|@0.1\nsome.service.host{}.queue:+{}|g\n", i, i, i * 3, i, i).as_bytes());
+    }
+    buf
+}
+
+fn parsers(c: &mut Criterion) {
+    let data = datagram();
+    let len = data.len() as u32;
+    let borrowed = data.clone();
+    c.bench(
+        "parse",
+        Benchmark::new("bioyino-metric", move |b| {
+            b.iter(|| {
+                let mut buf = BytesMut::from(&data[..]);
+                MetricParser::new(&mut buf, 10000, IgnoreErrors).count()
+            })
+        })
+        .with_function("zero-copy", move |b| b.iter(|| StatsdParser::new(&borrowed[..], 10000, IgnoreErrors).count()))
+        .throughput(Throughput::Bytes(len)),
+    );
+}
+
+criterion_group!(benches, parsers);
+criterion_main!(benches);
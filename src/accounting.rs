@@ -0,0 +1,188 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use futures::{Future, Stream};
+use lazy_static::lazy_static;
+use serde_derive::{Deserialize, Serialize};
+use slog::{info, o, warn, Logger};
+use tokio::timer::Interval;
+
+use crate::config::Accounting;
+use crate::Float;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static DEPTH: AtomicUsize = AtomicUsize::new(1);
+static MAX_PREFIXES: AtomicUsize = AtomicUsize::new(1000);
+
+/// Prefix everything over `accounting.max-prefixes` is counted under
+pub const OTHER_PREFIX: &str = "_other";
+
+lazy_static! {
+    static ref TALLIES: Mutex<Tallies> = Mutex::new(Tallies::default());
+}
+
+thread_local! {
+    // samples counted by the worker during the current task, they are added to global tallies
+    // under a single lock when the task is done
+    static LOCAL: RefCell<HashMap<Bytes, u64>> = RefCell::new(HashMap::new());
+}
+
+/// Volume of a single prefix
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct Tally {
+    /// Samples received from clients and passed ingestion rules
+    pub samples: u64,
+    /// Aggregated points sent to backend
+    pub points: u64,
+}
+
+#[derive(Debug, Default)]
+struct Tallies {
+    // since the last own stats report
+    interval: HashMap<Bytes, Tally>,
+    // since the last summary, prefixes here are the ones counted separately
+    summary: HashMap<Bytes, Tally>,
+}
+
+fn bump(tallies: &mut HashMap<Bytes, Tally>, prefix: &[u8], samples: u64, points: u64) {
+    match tallies.get_mut(prefix) {
+        Some(tally) => {
+            tally.samples += samples;
+            tally.points += points;
+        }
+        None => {
+            tallies.insert(Bytes::from(prefix), Tally { samples, points });
+        }
+    }
+}
+
+impl Tallies {
+    fn add(&mut self, prefix: &[u8], samples: u64, points: u64) {
+        let prefix = if self.summary.contains_key(prefix) || self.summary.len() < MAX_PREFIXES.load(Ordering::Relaxed) { prefix } else { OTHER_PREFIX.as_bytes() };
+        bump(&mut self.interval, prefix, samples, points);
+        bump(&mut self.summary, prefix, samples, points);
+    }
+}
+
+/// Apply accounting settings, must be called before workers are started
+pub fn init_accounting(options: &Accounting) {
+    DEPTH.store(options.depth.max(1), Ordering::Relaxed);
+    MAX_PREFIXES.store(options.max_prefixes, Ordering::Relaxed);
+    ENABLED.store(options.enabled, Ordering::Relaxed);
+}
+
+/// First `depth` segments of the name, tags are not a part of the prefix
+pub fn prefix(name: &[u8], depth: usize) -> &[u8] {
+    let name = name.iter().position(|c| *c == b';').map(|end| &name[..end]).unwrap_or(name);
+    name.iter().enumerate().filter(|(_, c)| **c == b'.').nth(depth.saturating_sub(1)).map(|(end, _)| &name[..end]).unwrap_or(name)
+}
+
+/// Count a sample of the metric to it's prefix
+pub fn count_sample(name: &[u8]) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let prefix = prefix(name, DEPTH.load(Ordering::Relaxed));
+    LOCAL.with(|local| {
+        let mut local = local.borrow_mut();
+        match local.get_mut(prefix) {
+            Some(samples) => *samples += 1,
+            None => {
+                local.insert(Bytes::from(prefix), 1);
+            }
+        }
+    });
+}
+
+/// Add samples counted by this thread to global tallies
+pub fn flush_samples() {
+    LOCAL.with(|local| {
+        let mut local = local.borrow_mut();
+        if local.len() == 0 {
+            return;
+        }
+        let mut tallies = TALLIES.lock().unwrap();
+        for (prefix, samples) in local.drain() {
+            tallies.add(&prefix, samples, 0);
+        }
+    });
+}
+
+/// Count aggregated points going to backend
+pub fn count_points(metrics: &[(Bytes, Float)]) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let depth = DEPTH.load(Ordering::Relaxed);
+    let mut points = HashMap::new();
+    for (name, _) in metrics {
+        *points.entry(prefix(name, depth)).or_insert(0u64) += 1;
+    }
+    let mut tallies = TALLIES.lock().unwrap();
+    for (prefix, points) in points {
+        tallies.add(prefix, 0, points);
+    }
+}
+
+/// Tallies since the previous call sorted by prefix, empty if accounting is disabled
+pub fn take_interval() -> Vec<(Bytes, Tally)> {
+    let mut tallies = TALLIES.lock().unwrap().interval.drain().collect::<Vec<_>>();
+    tallies.sort_by(|(a, _), (b, _)| a.cmp(b));
+    tallies
+}
+
+/// A future logging total samples and points of every prefix every `interval`, biggest ones first.
+/// Never gets ready.
+pub fn summarize_accounting(interval: Duration, log: Logger) -> impl Future<Item = (), Error = ()> {
+    let log = log.new(o!("source"=>"accounting"));
+    let err_log = log.clone();
+    let mut started = Instant::now();
+    Interval::new(Instant::now() + interval, interval)
+        .map_err(move |e| {
+            warn!(err_log, "accounting summary timer failed"; "error"=>e.to_string());
+        })
+        .for_each(move |_| {
+            let mut summary = TALLIES.lock().unwrap().summary.drain().collect::<Vec<_>>();
+            summary.sort_by(|(a_name, a), (b_name, b)| b.samples.cmp(&a.samples).then_with(|| a_name.cmp(b_name)));
+            let period_ms = started.elapsed().as_millis() as u64;
+            started = Instant::now();
+            let (samples, points) = summary.iter().fold((0, 0), |(samples, points), (_, tally)| (samples + tally.samples, points + tally.points));
+            info!(log, "accounting summary"; "prefixes"=>summary.len(), "samples"=>samples, "points"=>points, "period-ms"=>period_ms);
+            for (prefix, tally) in &summary {
+                info!(log, "accounting summary"; "prefix"=>String::from_utf8_lossy(prefix).into_owned(), "samples"=>tally.samples, "points"=>tally.points, "period-ms"=>period_ms);
+            }
+            Ok(())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn name_prefix() {
+        assert_eq!(prefix(b"team.service.requests", 1), b"team");
+        assert_eq!(prefix(b"team.service.requests", 2), b"team.service");
+        assert_eq!(prefix(b"team.service.requests", 5), b"team.service.requests");
+        assert_eq!(prefix(b"single;tag.with.dots=1", 1), b"single");
+    }
+
+    #[test]
+    fn prefix_limit() {
+        let mut tallies = Tallies::default();
+        MAX_PREFIXES.store(2, Ordering::Relaxed);
+        tallies.add(b"a", 3, 0);
+        tallies.add(b"b", 1, 0);
+        tallies.add(b"c", 1, 2);
+        tallies.add(b"a", 0, 5);
+        MAX_PREFIXES.store(1000, Ordering::Relaxed);
+        assert_eq!(tallies.summary.len(), 3);
+        assert_eq!(tallies.summary[&b"a"[..]], Tally { samples: 3, points: 5 });
+        assert_eq!(tallies.interval[OTHER_PREFIX.as_bytes()], Tally { samples: 1, points: 2 });
+    }
+}
@@ -0,0 +1,218 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::RwLock;
+
+use lazy_static::lazy_static;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::errors::GeneralError;
+use crate::SOURCE_DROPS;
+
+lazy_static! {
+    /// Source rules of statsd UDP listener
+    pub static ref STATSD_SOURCES: SourceFilter = SourceFilter::default();
+    /// Source rules of peer TCP listener
+    pub static ref PEER_SOURCES: SourceFilter = SourceFilter::default();
+}
+
+/// Network in CIDR notation, a plain address is a network of itself
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cidr {
+    addr: IpAddr,
+    len: u8,
+}
+
+// address as a number with the number of it's bits
+fn bits(ip: &IpAddr) -> (u128, u8) {
+    match ip {
+        IpAddr::V4(ip) => (u128::from(u32::from(*ip)), 32),
+        IpAddr::V6(ip) => (u128::from(*ip), 128),
+    }
+}
+
+// IPv4 clients of dual stack sockets come as IPv4-mapped IPv6 addresses, they are matched by IPv4 rules
+fn unmap(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, high, low] => IpAddr::V4(Ipv4Addr::new((high >> 8) as u8, high as u8, (low >> 8) as u8, low as u8)),
+            _ => ip,
+        },
+        ip => ip,
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        let (net, width) = bits(&self.addr);
+        let (ip, ip_width) = bits(&unmap(*ip));
+        if width != ip_width {
+            return false;
+        }
+        // shifting by the whole width compares nothing, so any address matches /0
+        let shift = u32::from(width - self.len);
+        net.checked_shr(shift).unwrap_or(0) == ip.checked_shr(shift).unwrap_or(0)
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = GeneralError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bad = |reason: &str| GeneralError::BadNetwork(s.to_string(), reason.to_string());
+        let mut parts = s.splitn(2, '/');
+        let addr = parts.next().unwrap_or_default().trim().parse::<IpAddr>().map_err(|_| bad("bad address"))?;
+        let (_, width) = bits(&addr);
+        let len = match parts.next() {
+            Some(len) => len.trim().parse::<u8>().map_err(|_| bad("bad prefix length"))?,
+            None => width,
+        };
+        if len > width {
+            return Err(bad("prefix length is longer than the address"));
+        }
+        Ok(Self { addr, len })
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.len)
+    }
+}
+
+#[derive(Debug)]
+struct Rule {
+    network: Cidr,
+    deny: bool,
+    drops: AtomicUsize,
+}
+
+/// Drops made by a single rule, as shown in stats
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct RuleDrops {
+    /// `deny <network>` or `not-allowed` for sources not in allow list
+    pub rule: String,
+    pub drops: usize,
+}
+
+/// Source address rules of a listener. Packets and connections from denied networks, or from networks
+/// not in allow list when it is set, are dropped before parsing. Drops are counted for every rule.
+#[derive(Debug, Default)]
+pub struct SourceFilter {
+    // there are rules, so addresses need to be checked
+    active: AtomicBool,
+    // deny rules go first, so they win over allowing ones
+    rules: RwLock<Vec<Rule>>,
+    not_allowed: AtomicUsize,
+}
+
+impl SourceFilter {
+    pub fn set(&self, allow: &[String], deny: &[String]) -> Result<(), GeneralError> {
+        let mut rules = Vec::with_capacity(allow.len() + deny.len());
+        for network in deny {
+            rules.push(Rule { network: network.parse()?, deny: true, drops: AtomicUsize::new(0) });
+        }
+        for network in allow {
+            rules.push(Rule { network: network.parse()?, deny: false, drops: AtomicUsize::new(0) });
+        }
+        self.active.store(rules.len() > 0, Ordering::Relaxed);
+        *self.rules.write().unwrap() = rules;
+        Ok(())
+    }
+
+    /// Check the source, counting the drop if it is not allowed
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        if !self.active.load(Ordering::Relaxed) {
+            return true;
+        }
+        let rules = self.rules.read().unwrap();
+        let mut allow_list = false;
+        for rule in rules.iter() {
+            if rule.deny {
+                if rule.network.contains(&ip) {
+                    rule.drops.fetch_add(1, Ordering::Relaxed);
+                    SOURCE_DROPS.add(1);
+                    return false;
+                }
+            } else if rule.network.contains(&ip) {
+                return true;
+            } else {
+                allow_list = true;
+            }
+        }
+        if allow_list {
+            self.not_allowed.fetch_add(1, Ordering::Relaxed);
+            SOURCE_DROPS.add(1);
+        }
+        !allow_list
+    }
+
+    /// The same as `allows` for a raw `sockaddr` filled by the kernel, sources of unknown family are not allowed
+    pub fn allows_raw(&self, sockaddr: &[u8]) -> bool {
+        if !self.active.load(Ordering::Relaxed) {
+            return true;
+        }
+        match sockaddr_ip(sockaddr) {
+            Some(ip) => self.allows(ip),
+            None => {
+                self.not_allowed.fetch_add(1, Ordering::Relaxed);
+                SOURCE_DROPS.add(1);
+                false
+            }
+        }
+    }
+
+    /// Drops of every rule since start, empty if there are no rules
+    pub fn drops(&self) -> Vec<RuleDrops> {
+        let rules = self.rules.read().unwrap();
+        let mut drops = rules.iter().filter(|rule| rule.deny).map(|rule| RuleDrops { rule: format!("deny {}", rule.network), drops: rule.drops.load(Ordering::Relaxed) }).collect::<Vec<_>>();
+        if rules.iter().any(|rule| !rule.deny) {
+            drops.push(RuleDrops { rule: "not-allowed".to_string(), drops: self.not_allowed.load(Ordering::Relaxed) });
+        }
+        drops
+    }
+}
+
+/// IPv4 address of `sockaddr_in` structure, `None` for other families
+pub fn sockaddr_ip(sockaddr: &[u8]) -> Option<IpAddr> {
+    if sockaddr.len() < 8 || u16::from_ne_bytes([sockaddr[0], sockaddr[1]]) != libc::AF_INET as u16 {
+        return None;
+    }
+    Some(IpAddr::V4(Ipv4Addr::new(sockaddr[4], sockaddr[5], sockaddr[6], sockaddr[7])))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cidr_match() {
+        let net: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains(&"10.1.2.3".parse().unwrap()));
+        assert!(!net.contains(&"10.2.0.1".parse().unwrap()));
+        assert!(net.contains(&"::ffff:10.1.0.1".parse().unwrap()));
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(&"192.168.0.1".parse().unwrap()));
+        assert!("fd00::/8".parse::<Cidr>().unwrap().contains(&"fd12::1".parse().unwrap()));
+        assert!(!"fd00::/8".parse::<Cidr>().unwrap().contains(&"10.0.0.1".parse().unwrap()));
+        assert_eq!("127.0.0.1".parse::<Cidr>().unwrap().to_string(), "127.0.0.1/32");
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn source_rules() {
+        let filter = SourceFilter::default();
+        assert!(filter.allows("1.2.3.4".parse().unwrap()));
+        assert_eq!(filter.drops(), Vec::new());
+
+        filter.set(&["10.0.0.0/8".to_string()], &["10.0.0.0/24".to_string()]).unwrap();
+        assert!(filter.allows("10.1.0.1".parse().unwrap()));
+        assert!(!filter.allows("10.0.0.1".parse().unwrap()));
+        assert!(!filter.allows("192.168.0.1".parse().unwrap()));
+        assert!(!filter.allows_raw(&[0u8; 20]));
+        let drops = filter.drops();
+        assert_eq!(drops, vec![RuleDrops { rule: "deny 10.0.0.0/24".to_string(), drops: 1 }, RuleDrops { rule: "not-allowed".to_string(), drops: 2 }]);
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::env;
+use std::io;
+use std::mem;
+use std::net::{SocketAddr, TcpListener as StdTcpListener, UdpSocket as StdUdpSocket};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use slog::{info, warn, Logger};
+use tokio::net::TcpListener;
+use tokio::reactor::Handle;
+
+use crate::util::reusing_listener;
+
+// the first descriptor passed by systemd, see sd_listen_fds(3)
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Names of listeners, matched with `FileDescriptorName=` of socket units
+pub const STATSD_SOCKET: &str = "statsd";
+pub const PEER_SOCKET: &str = "peer";
+pub const MANAGEMENT_SOCKET: &str = "management";
+
+lazy_static! {
+    // sockets passed, but not taken by any listener yet
+    static ref PASSED: Mutex<Vec<Passed>> = Mutex::new(Vec::new());
+    // TCP listeners taken or bound, kept to be cloned when a listener is restarted and to be handed off
+    static ref TAKEN: Mutex<HashMap<&'static str, StdTcpListener>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Debug)]
+struct Passed {
+    fd: RawFd,
+    name: Option<String>,
+    udp: bool,
+    local: Option<SocketAddr>,
+}
+
+impl Passed {
+    // by name if the unit sets it, otherwise by the address bound
+    fn matches(&self, udp: bool, name: &str, addr: &SocketAddr) -> bool {
+        if self.udp != udp {
+            return false;
+        }
+        if let Some(ref passed) = self.name {
+            if passed == name {
+                return true;
+            }
+        }
+        match self.local {
+            // systemd binds to [::] by default, so unspecified addresses of either side match any
+            Some(local) => local.port() == addr.port() && (local.ip() == addr.ip() || local.ip().is_unspecified() || addr.ip().is_unspecified()),
+            None => false,
+        }
+    }
+}
+
+fn socket_type(fd: RawFd) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let res = unsafe { libc::getsockopt(fd, libc::SOL_SOCKET, libc::SO_TYPE, &mut value as *mut libc::c_int as *mut libc::c_void, &mut len) };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(value)
+}
+
+// local address is taken through std socket, which is released without closing the descriptor
+fn local_addr(fd: RawFd, udp: bool) -> Option<SocketAddr> {
+    if udp {
+        let socket = unsafe { StdUdpSocket::from_raw_fd(fd) };
+        let local = socket.local_addr().ok();
+        socket.into_raw_fd();
+        local
+    } else {
+        let listener = unsafe { StdTcpListener::from_raw_fd(fd) };
+        let local = listener.local_addr().ok();
+        listener.into_raw_fd();
+        local
+    }
+}
+
+/// Sockets passed by systemd socket activation in LISTEN_FDS, or none if the process was not started this way.
+/// The environment is cleared, so the sockets are not passed further. Must be called before any listener is started.
+pub fn init_activation(log: &Logger) -> usize {
+    let pid = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse::<u32>().ok());
+    let count = env::var("LISTEN_FDS").ok().and_then(|count| count.parse::<RawFd>().ok()).unwrap_or(0);
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+    for var in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(var);
+    }
+    // the variables could be left by a parent started by systemd
+    if pid != Some(std::process::id()) || count <= 0 {
+        return 0;
+    }
+
+    let names = names.split(':').map(|name| if name.len() > 0 { Some(name.to_string()) } else { None }).chain(std::iter::repeat(None));
+    for (fd, name) in (LISTEN_FDS_START..LISTEN_FDS_START + count).zip(names) {
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+        pass_socket(fd, name, "systemd", log);
+    }
+    PASSED.lock().unwrap().len()
+}
+
+/// Add a socket passed by `source` to ones taken by listeners instead of binding
+pub fn pass_socket(fd: RawFd, name: Option<String>, source: &str, log: &Logger) {
+    let udp = match socket_type(fd) {
+        Ok(libc::SOCK_DGRAM) => true,
+        Ok(libc::SOCK_STREAM) => false,
+        Ok(kind) => {
+            warn!(log, "ignoring passed socket with unsupported type"; "source"=>source, "fd"=>fd, "type"=>kind);
+            return;
+        }
+        Err(e) => {
+            warn!(log, "ignoring passed descriptor"; "source"=>source, "fd"=>fd, "error"=>e.to_string());
+            return;
+        }
+    };
+    let local = local_addr(fd, udp);
+    info!(log, "socket passed"; "source"=>source, "fd"=>fd, "name"=>name.clone().unwrap_or_default(), "proto"=>if udp { "udp" } else { "tcp" }, "address"=>local.map(|local| local.to_string()).unwrap_or_default());
+    PASSED.lock().unwrap().push(Passed { fd, name, udp, local });
+}
+
+/// Close sockets passed, but not taken by any listener, returns the number of them
+pub fn close_unused() -> usize {
+    let mut passed = PASSED.lock().unwrap();
+    passed.drain(..).map(|passed| unsafe { libc::close(passed.fd) }).count()
+}
+
+/// TCP listeners with their names, to be handed off to the next process
+pub fn taken_tcp() -> Vec<(&'static str, RawFd)> {
+    TAKEN.lock().unwrap().iter().map(|(name, listener)| (*name, listener.as_raw_fd())).collect()
+}
+
+fn take(udp: bool, name: &str, addr: &SocketAddr) -> Vec<RawFd> {
+    let mut passed = PASSED.lock().unwrap();
+    let mut taken = Vec::new();
+    let mut idx = 0;
+    while idx < passed.len() {
+        if passed[idx].matches(udp, name, addr) {
+            taken.push(passed.remove(idx).fd);
+        } else {
+            idx += 1;
+        }
+    }
+    taken
+}
+
+/// Whether a socket for the listener is passed already, so the address is not bound by this process
+pub fn is_passed(udp: bool, name: &str, addr: &SocketAddr) -> bool {
+    PASSED.lock().unwrap().iter().any(|passed| passed.matches(udp, name, addr))
+}
+
+/// Statsd UDP sockets passed by systemd for the address, empty if there are none
+pub fn activated_udp(addr: &SocketAddr) -> Vec<StdUdpSocket> {
+    take(true, STATSD_SOCKET, addr).into_iter().map(|fd| unsafe { StdUdpSocket::from_raw_fd(fd) }).collect()
+}
+
+/// TCP listener passed by systemd for the address, it is kept, so a restarted listener gets it again
+pub fn activated_tcp(name: &'static str, addr: &SocketAddr) -> io::Result<Option<TcpListener>> {
+    let mut taken = TAKEN.lock().unwrap();
+    if !taken.contains_key(name) {
+        match take(false, name, addr).into_iter().next() {
+            Some(fd) => {
+                taken.insert(name, unsafe { StdTcpListener::from_raw_fd(fd) });
+            }
+            None => return Ok(None),
+        }
+    }
+    let listener = taken[name].try_clone()?;
+    listener.set_nonblocking(true)?;
+    TcpListener::from_std(listener, &Handle::default()).map(Some)
+}
+
+/// TCP listener passed by systemd or a newly bound one, which is kept the same way as passed ones
+pub fn tcp_listener(name: &'static str, addr: &SocketAddr) -> io::Result<TcpListener> {
+    match activated_tcp(name, addr)? {
+        Some(listener) => Ok(listener),
+        None => {
+            let listener = reusing_listener(addr)?;
+            let fd = unsafe { libc::fcntl(listener.as_raw_fd(), libc::F_DUPFD_CLOEXEC, 0) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            TAKEN.lock().unwrap().insert(name, unsafe { StdTcpListener::from_raw_fd(fd) });
+            Ok(listener)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passed_sockets_match() {
+        let passed = Passed { fd: 3, name: None, udp: true, local: Some("[::]:8125".parse().unwrap()) };
+        assert!(passed.matches(true, STATSD_SOCKET, &"127.0.0.1:8125".parse().unwrap()));
+        assert!(!passed.matches(true, STATSD_SOCKET, &"127.0.0.1:8126".parse().unwrap()));
+        assert!(!passed.matches(false, PEER_SOCKET, &"127.0.0.1:8125".parse().unwrap()));
+
+        let passed = Passed { fd: 4, name: Some("peer".to_string()), udp: false, local: Some("10.0.0.1:9000".parse().unwrap()) };
+        assert!(passed.matches(false, PEER_SOCKET, &"127.0.0.1:8136".parse().unwrap()));
+        assert!(!passed.matches(false, MANAGEMENT_SOCKET, &"10.0.0.2:9000".parse().unwrap()));
+        assert!(passed.matches(false, MANAGEMENT_SOCKET, &"0.0.0.0:9000".parse().unwrap()));
+    }
+}
@@ -1,6 +1,6 @@
 use std::collections::HashMap;
-use std::sync::atomic::Ordering;
 
+use futures::future::{ok, Either};
 use futures::stream::futures_unordered;
 use futures::sync::mpsc::{Sender, UnboundedSender};
 use futures::sync::oneshot;
@@ -12,7 +12,12 @@ use rayon::{iter::IntoParallelIterator, iter::ParallelIterator, ThreadPoolBuilde
 use serde_derive::{Deserialize, Serialize};
 use slog::{debug, info, Logger};
 
+use crate::arena::detach;
+use crate::cache::advance_epoch;
+use crate::config::Metrics;
+use crate::intern::{pin, rotate_names, NAMES};
 use crate::task::{aggregate_task, AggregateData, Task};
+use crate::trace::{Span, SpanContext};
 use crate::util::UpdateCounterOptions;
 use crate::{Cache, Float};
 use crate::{AGG_ERRORS, DROPS, EGRESS};
@@ -33,6 +38,27 @@ pub struct AggregateOptions {
     pub update_counter: Option<UpdateCounterOptions>,
     pub aggregation_mode: AggregationMode,
     pub multi_threads: usize,
+    /// Only rotate and aggregate metrics starting with this prefix
+    pub prefix: Option<Bytes>,
+    /// Flush trace aggregation is a part of, if it is sampled
+    pub trace: Option<SpanContext>,
+}
+
+impl AggregateOptions {
+    /// Make options from metric settings. Settings are checked at start, so incorrect combinations
+    /// are silently ignored here.
+    pub fn new(is_leader: bool, metrics: &Metrics) -> Self {
+        let update_counter = if metrics.count_updates && (metrics.update_counter_prefix.len() > 0 || metrics.update_counter_suffix.len() > 0) {
+            Some(UpdateCounterOptions { threshold: metrics.update_counter_threshold, prefix: metrics.update_counter_prefix.clone().into(), suffix: metrics.update_counter_suffix.clone().into() })
+        } else {
+            None
+        };
+        let multi_threads = match metrics.aggregation_threads {
+            Some(value) if metrics.aggregation_mode == AggregationMode::Separate => value,
+            _ => 0,
+        };
+        Self { is_leader, update_counter, aggregation_mode: metrics.aggregation_mode.clone(), multi_threads, prefix: None, trace: None }
+    }
 }
 
 pub struct Aggregator {
@@ -59,51 +85,99 @@ impl IntoFuture for Aggregator {
 
     fn into_future(self) -> Self::Future {
         let Self { options, chans, tx, log } = self;
-        let metrics = chans.clone().into_iter().map(|chan| {
+        let prefix = options.prefix.clone();
+        let mut span = Span::child_of(options.trace, "aggregate");
+        // names of metrics rotated out are kept for a while, so only full rotations make them older
+        let full_rotation = options.prefix.is_none();
+        // workers start a new cache generation on their next task, even if rotation task
+        // is far behind in their queues
+        let epoch = if full_rotation { advance_epoch() } else { 0 };
+        // followers send metrics from short caches to the leader with the next snapshot, the leader takes
+        // them right away, so forced and last flushes send everything received until now
+        let take_short = options.is_leader;
+        let metrics = chans.clone().into_iter().enumerate().map(move |(worker, chan)| {
             let (tx, rx) = oneshot::channel();
+            let prefix = prefix.clone();
+            let chan = if take_short { Either::A(chan.send(Task::TakeShort)) } else { Either::B(ok(chan)) };
             // TODO: change oneshots to single channel
             // to do that, task must run in new tokio, then we will not have to pass handle to it
             //handle.spawn(chan.send(Task::Rotate(tx)).then(|_| Ok(())));
-            chan.send(Task::Rotate(tx)).map_err(|_| ()).and_then(|_| rx.and_then(|m| Ok(m)).map_err(|_| ()))
+            chan.and_then(move |chan| chan.send(Task::Rotate(prefix, tx))).map_err(|_| ()).and_then(move |_| rx.map(move |m| (worker, m)).map_err(|_| ()))
         });
 
+        let recycle_chans = chans.clone();
         if !options.is_leader {
             info!(log, "not leader - clearing metrics");
-            // only get metrics from threads
-            let not_leader = futures_unordered(metrics).for_each(|_| Ok(()));
+            // only get metrics from threads, shards are cleared here, so the workers don't have to
+            let not_leader = futures_unordered(metrics).for_each(move |(worker, mut metrics)| {
+                if full_rotation {
+                    let samples = metrics.iter_mut().flat_map(|shard| shard.drain()).filter_map(|(_, mut metric)| detach(&mut metric)).collect();
+                    recycle(&recycle_chans, worker, metrics, samples);
+                }
+                Ok(())
+            }).map(move |_| {
+                if full_rotation {
+                    rotate_names(epoch);
+                }
+            });
             return Box::new(not_leader);
         }
 
         info!(log, "leader accumulating metrics");
-        let accumulate = futures_unordered(metrics).fold(HashMap::new(), move |mut acc: Cache, metrics| {
+        // ids of rotated metrics are not in caches anymore, so their names are kept until they are taken
+        let pin = pin();
+        let accumulate = futures_unordered(metrics).fold(HashMap::new(), move |mut acc: Cache, (worker, mut metrics)| {
+            let mut samples = Vec::new();
             metrics
-                .into_iter()
-                .map(|(name, metric)| {
+                .iter_mut()
+                .flat_map(|shard| shard.drain())
+                .map(|(name, mut metric)| {
+                    // arena buffers of the worker are given back, while the samples are aggregated from a copy
+                    if full_rotation {
+                        samples.extend(detach(&mut metric));
+                    }
                     if acc.contains_key(&name) {
                         acc.get_mut(&name).unwrap().aggregate(metric).unwrap_or_else(|_| {
-                            AGG_ERRORS.fetch_add(1, Ordering::Relaxed);
+                            AGG_ERRORS.add(1);
                         });
                     } else {
                         acc.insert(name, metric);
                     }
                 })
                 .last();
+            if full_rotation {
+                recycle(&recycle_chans, worker, metrics, samples);
+            }
             Ok(acc)
         });
 
         let aggregate = accumulate.and_then(move |accumulated| {
             debug!(log, "leader aggregating metrics");
+            if let Some(ref mut span) = span {
+                span.attr("metrics", accumulated.len());
+                span.attr("mode", format!("{:?}", options.aggregation_mode));
+            }
+            // metrics leave the server here, so names are taken back from the name table
+            let accumulated = {
+                let names = NAMES.read().unwrap();
+                accumulated.into_iter().map(|(id, metric)| (names.name(id).clone(), metric)).collect::<Vec<_>>()
+            };
+            drop(pin);
+            if full_rotation {
+                let (removed, left) = rotate_names(epoch);
+                debug!(log, "unused names removed"; "removed"=>removed, "names"=>left);
+            }
 
             match options.aggregation_mode {
                 AggregationMode::Single => {
                     accumulated
                         .into_iter()
                         .inspect(|_| {
-                            EGRESS.fetch_add(1, Ordering::Relaxed);
+                            EGRESS.add(1);
                         })
                         .map(move |(name, metric)| {
                             let buf = BytesMut::with_capacity(1024);
-                            let task_data = AggregateData { buf, name: Bytes::from(name), metric, options: options.clone(), response: tx.clone() };
+                            let task_data = AggregateData { buf, name, metric, options: options.clone(), response: tx.clone() };
                             aggregate_task(task_data);
                         })
                         .last();
@@ -112,14 +186,14 @@ impl IntoFuture for Aggregator {
                     accumulated
                         .into_iter()
                         .inspect(|_| {
-                            EGRESS.fetch_add(1, Ordering::Relaxed);
+                            EGRESS.add(1);
                         })
                         .enumerate()
                         .map(move |(num, (name, metric))| {
                             let buf = BytesMut::with_capacity(1024);
-                            let task_data = AggregateData { buf, name: Bytes::from(name), metric, options: options.clone(), response: tx.clone() };
+                            let task_data = AggregateData { buf, name, metric, options: options.clone(), response: tx.clone() };
                             spawn(chans[num % chans.len()].clone().send(Task::Aggregate(task_data)).map(|_| ()).map_err(|_| {
-                                DROPS.fetch_add(1, Ordering::Relaxed);
+                                DROPS.add(1);
                             }));
                         })
                         .last();
@@ -130,19 +204,27 @@ impl IntoFuture for Aggregator {
                         accumulated
                             .into_par_iter()
                             .inspect(|_| {
-                                EGRESS.fetch_add(1, Ordering::Relaxed);
+                                EGRESS.add(1);
                             })
                             .for_each(move |(name, metric)| {
                                 let buf = BytesMut::with_capacity(1024);
-                                let task_data = AggregateData { buf, name: Bytes::from(name), metric, options: options.clone(), response: tx.clone() };
+                                let task_data = AggregateData { buf, name, metric, options: options.clone(), response: tx.clone() };
                                 aggregate_task(task_data);
                             });
                     });
                 }
             };
 
+            // metrics are only dispatched to workers in common mode, the span does not wait for them
+            drop(span);
             Ok(())
         });
         Box::new(aggregate)
     }
 }
+
+// give drained shards and timer sample buffers back to the worker, they keep their capacity,
+// so the next generation does not have to grow from scratch
+fn recycle(chans: &[Sender<Task>], worker: usize, shards: Vec<Cache>, samples: Vec<Vec<Float>>) {
+    spawn(chans[worker].clone().send(Task::Recycle(shards, samples)).map(|_| ()).map_err(|_| ()));
+}
@@ -0,0 +1,104 @@
+use serde_derive::{Deserialize, Serialize};
+
+use crate::Float;
+
+#[cfg(all(feature = "jemalloc", feature = "mimalloc"))]
+compile_error!("features jemalloc and mimalloc cannot be enabled together");
+
+// glibc malloc fragments badly when timer sample vectors grow and get freed every interval,
+// both allocators reuse memory much better under this load
+#[cfg(feature = "jemalloc")]
+#[global_allocator]
+static GLOBAL: jemallocator::Jemalloc = jemallocator::Jemalloc;
+
+#[cfg(feature = "mimalloc")]
+#[global_allocator]
+static GLOBAL: mimalloc_allocator::MiMalloc = mimalloc_allocator::MiMalloc;
+
+/// Memory state as seen by the global allocator
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct AllocatorStats {
+    pub allocator: String,
+    /// Physical memory taken by the process
+    pub resident_bytes: usize,
+    /// Memory in pages used by allocator for allocations, including unused parts of them
+    pub active_bytes: usize,
+    /// Memory actually requested by the program, `None` if allocator does not count it
+    pub allocated_bytes: Option<usize>,
+    /// Share of resident memory not holding any allocations, `None` if allocated memory is unknown
+    pub fragmentation: Option<Float>,
+}
+
+impl AllocatorStats {
+    fn new(allocator: &str, resident_bytes: usize, active_bytes: usize, allocated_bytes: Option<usize>) -> Self {
+        let fragmentation = allocated_bytes.filter(|_| resident_bytes > 0).map(|allocated| resident_bytes.saturating_sub(allocated) as Float / resident_bytes as Float);
+        Self { allocator: allocator.to_string(), resident_bytes, active_bytes, allocated_bytes, fragmentation }
+    }
+
+    /// Stat names with values to send as own metrics
+    pub fn to_vec(&self) -> Vec<(&'static str, Float)> {
+        let mut values = vec![("allocator.resident", self.resident_bytes as Float), ("allocator.active", self.active_bytes as Float)];
+        if let Some(allocated) = self.allocated_bytes {
+            values.push(("allocator.allocated", allocated as Float));
+        }
+        if let Some(fragmentation) = self.fragmentation {
+            values.push(("allocator.fragmentation", fragmentation));
+        }
+        values
+    }
+}
+
+#[cfg(feature = "jemalloc")]
+pub fn allocator_stats() -> Option<AllocatorStats> {
+    use jemalloc_ctl::{epoch, stats};
+    // jemalloc caches statistics, they are only refreshed when epoch is advanced
+    epoch::advance().ok()?;
+    Some(AllocatorStats::new("jemalloc", stats::resident::read().ok()?, stats::active::read().ok()?, Some(stats::allocated::read().ok()?)))
+}
+
+#[cfg(feature = "mimalloc")]
+pub fn allocator_stats() -> Option<AllocatorStats> {
+    let (mut elapsed, mut user, mut system, mut rss, mut peak_rss, mut commit, mut peak_commit, mut faults) = (0, 0, 0, 0, 0, 0, 0, 0);
+    unsafe {
+        libmimalloc_sys::mi_process_info(&mut elapsed, &mut user, &mut system, &mut rss, &mut peak_rss, &mut commit, &mut peak_commit, &mut faults);
+    }
+    Some(AllocatorStats::new("mimalloc", rss, commit, None))
+}
+
+/// System allocator has no statistics, so only resident memory of the process is reported
+#[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+pub fn allocator_stats() -> Option<AllocatorStats> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let resident_pages = statm.split_whitespace().nth(1)?.parse::<usize>().ok()?;
+    let resident = resident_pages * unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    Some(AllocatorStats::new("system", resident, resident, None))
+}
+
+/// Give free memory of the system allocator back to the system, returns false if nothing could be done.
+/// jemalloc and mimalloc return unused pages by themselves, so nothing is done for them.
+#[cfg(all(target_os = "linux", target_env = "gnu", not(any(feature = "jemalloc", feature = "mimalloc"))))]
+pub fn release_memory() -> bool {
+    unsafe { libc::malloc_trim(0) == 1 }
+}
+
+#[cfg(not(all(target_os = "linux", target_env = "gnu", not(any(feature = "jemalloc", feature = "mimalloc")))))]
+pub fn release_memory() -> bool {
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fragmentation() {
+        let stats = AllocatorStats::new("test", 1000, 800, Some(600));
+        assert_eq!(stats.fragmentation, Some(0.4));
+        assert_eq!(stats.to_vec().len(), 4);
+        assert_eq!(AllocatorStats::new("test", 1000, 1000, None).to_vec().len(), 2);
+
+        #[cfg(target_os = "linux")]
+        assert!(allocator_stats().map(|stats| stats.resident_bytes > 0).unwrap_or(false));
+    }
+}
@@ -0,0 +1,239 @@
+use hyper::header::ACCEPT;
+use hyper::{Body, Method, Request, StatusCode};
+use serde_json::{self, json, Value};
+
+/// Prefix of the versioned management API, unversioned paths are kept for compatibility
+pub const API_V1: &str = "/api/v1";
+
+const JSON: &str = "application/json";
+
+/// Description of a management endpoint, used for endpoint listing, OpenAPI document and content negotiation
+pub struct Route {
+    pub method: &'static str,
+    /// Path relative to API prefix, path parameters are in braces
+    pub path: &'static str,
+    pub summary: &'static str,
+    /// Content types endpoint can answer with, the first one is the default
+    pub produces: &'static [&'static str],
+    /// Query parameters with their descriptions
+    pub params: &'static [(&'static str, &'static str)],
+}
+
+const fn route(method: &'static str, path: &'static str, summary: &'static str, produces: &'static [&'static str], params: &'static [(&'static str, &'static str)]) -> Route {
+    Route { method, path, summary, produces, params }
+}
+
+pub const ROUTES: &[Route] = &[
+    route("GET", "/", "list of available endpoints", &["text/plain"], &[]),
+    route("GET", "/openapi.json", "OpenAPI description of this API", &[JSON], &[]),
+    route("GET", "/status", "server status", &[JSON], &[]),
+    route("POST", "/consensus", "change consensus state", &[JSON], &[]),
+    route("GET", "/metrics/{name}", "current value of a metric", &[JSON], &[]),
+    route("GET", "/metrics", "current values of metrics matching the glob pattern", &[JSON], &[("pattern", "glob pattern")]),
+    route("GET", "/healthz", "liveness check, answers 503 if workers are stuck", &[JSON], &[]),
+    route("GET", "/readyz", "readiness check, answers 503 if any of dependencies is not available", &[JSON], &[]),
+    route("GET", "/stats", "internal counters, their rates since previous request and worker cache sizes", &[JSON], &[]),
+    route("GET", "/memory", "estimated memory taken by worker caches, metric name table, buffers, peer snapshots and backend queues", &[JSON], &[]),
+    route("GET", "/prometheus", "bioyino own metrics in Prometheus text format", &["text/plain"], &[]),
+    route(
+        "GET",
+        "/top",
+        "heaviest metric names, or prefixes with most names",
+        &[JSON],
+        &[("by", "samples, bytes or names"), ("n", "number of entries, 50 by default"), ("depth", "number of name parts in prefix when ranking by names, 2 by default")],
+    ),
+    route("GET", "/tail", "incoming metrics matching the pattern as server-sent events", &["text/event-stream"], &[("pattern", "glob pattern"), ("rate", "maximum events per second, 10 by default")]),
+    route("GET", "/debug/pprof/profile", "CPU profile in pprof format, needs management.profiling and pprof feature", &["application/octet-stream"], &[("seconds", "how long to sample, 10 by default")]),
+    route("GET", "/debug/pprof/flamegraph", "CPU profile as SVG flamegraph, needs management.profiling and pprof feature", &["image/svg+xml"], &[("seconds", "how long to sample, 10 by default")]),
+    route("GET", "/cluster", "peers with times of the last snapshot exchange and consensus state", &[JSON], &[]),
+    route("GET", "/maintenance", "whether the node is in maintenance and where statsd packets are forwarded", &[JSON], &[]),
+    route("GET", "/workers", "number of counting workers and removed ones still being drained", &[JSON], &[]),
+    route("GET", "/windows", "scheduled windows with options they change and whether they are active now", &[JSON], &[]),
+    route("GET", "/standby", "role of this node in hot-standby pair and when the primary was heard of", &[JSON], &[]),
+    route("GET", "/events", "last leader changes, backend failures and recoveries, reloads and memory pressure changes, oldest first", &[JSON], &[("since", "only events with bigger id"), ("kind", "leader, backend, reload or memory")]),
+    route("GET", "/rules", "current ingestion rules", &[JSON], &[]),
+    route("PUT", "/rules", "replace ingestion rules", &[JSON], &[("persist", "save rules to rules-file if true")]),
+    route("POST", "/rules", "change ingestion rules", &[JSON], &[("persist", "save rules to rules-file if true")]),
+    route("DELETE", "/rules/block", "remove a blocking rule", &[JSON], &[("pattern", "pattern of the rule"), ("persist", "save rules to rules-file if true")]),
+    route("DELETE", "/rules/rewrite", "remove a rewrite rule", &[JSON], &[("prefix", "prefix of the rule"), ("persist", "save rules to rules-file if true")]),
+    route("GET", "/quotas", "quotas of name prefixes with their usage", &[JSON], &[]),
+    route("PUT", "/quotas", "replace all quotas, change is lost on restart", &[JSON], &[]),
+    route("POST", "/quotas", "add a quota or replace the one with the same prefix, change is lost on restart", &[JSON], &[]),
+    route("DELETE", "/quotas", "remove the quota of a prefix", &[JSON], &[("prefix", "prefix of the quota")]),
+    route("GET", "/tunables", "parameters that can be changed without reloading configuration, with their bounds", &[JSON], &[]),
+    route("PUT", "/tunables/{name}", "change a tunable, body is {\"value\": <number>}, change is lost on restart", &[JSON], &[]),
+    route(
+        "POST",
+        "/dump",
+        "dump all current metrics in response or to a file in dump-dir",
+        &[JSON, "application/octet-stream", "text/plain"],
+        &[("format", "json or capnp"), ("file", "file name in dump-dir")],
+    ),
+    route("POST", "/reload", "reload configuration file, applying options that can be changed without restart section by section, all sections are rolled back if any fails", &[JSON], &[]),
+    route("POST", "/flush", "aggregate and send current metrics to backend immediately", &["text/plain"], &[("prefix", "only flush metrics with this prefix")]),
+    route("POST", "/leader", "step down from leadership or pin it to a node", &[JSON], &[]),
+    route("POST", "/maintenance", "enter or leave maintenance, body is {\"enabled\": <bool>, \"forward-to\": <statsd address>}", &[JSON], &[]),
+    route("POST", "/workers", "change the number of counting workers, body is {\"count\": <number>}, removed ones are drained in background", &[JSON], &[]),
+    route("POST", "/pause", "pause or resume receiving metrics and/or sending them to backend", &[JSON], &[]),
+];
+
+fn path_matches(template: &str, path: &str) -> bool {
+    match template.find('{') {
+        Some(pos) => path.len() > pos && path.starts_with(&template[..pos]),
+        None => template == path,
+    }
+}
+
+/// Find the description of endpoint handling the request
+pub fn find_route(method: &Method, path: &str) -> Option<&'static Route> {
+    ROUTES.iter().find(|route| route.method == method.as_str() && path_matches(route.path, path))
+}
+
+/// Human readable list of endpoints
+pub fn endpoint_list() -> String {
+    let mut list = String::from("Available endpoints:\n");
+    for route in ROUTES {
+        let params = route.params.iter().map(|(name, _)| format!("{}=<{}>", name, name)).collect::<Vec<_>>();
+        let params = if params.len() > 0 { format!("?{}", params.join("&")) } else { String::new() };
+        list.push_str(&format!("    {} {}{} - {}\n", route.method, route.path, params, route.summary));
+    }
+    list.push_str(&format!("All endpoints are also available under {} prefix\n", API_V1));
+    list
+}
+
+/// Check if client accepts any of the content types the endpoint can produce
+pub fn accepts(req: &Request<Body>, produces: &[&str]) -> bool {
+    let accept = match req.headers().get(ACCEPT).and_then(|value| value.to_str().ok()) {
+        Some(accept) => accept,
+        None => return true,
+    };
+    accept.split(',').map(|item| item.split(';').next().unwrap_or("").trim()).any(|accepted| {
+        accepted == "*/*"
+            || produces.iter().any(|produced| {
+                if accepted.ends_with("/*") {
+                    produced.starts_with(&accepted[..accepted.len() - 1])
+                } else {
+                    *produced == accepted
+                }
+            })
+    })
+}
+
+/// Machine readable error body. Original body is kept in details if it is JSON, or used as message otherwise.
+pub fn error_body(status: StatusCode, body: &[u8]) -> Vec<u8> {
+    let (message, details) = match serde_json::from_slice::<Value>(body) {
+        Ok(details) => (status.canonical_reason().unwrap_or("").to_string(), Some(details)),
+        Err(_) if body.len() > 0 => (String::from_utf8_lossy(body).into_owned(), None),
+        Err(_) => (status.canonical_reason().unwrap_or("").to_string(), None),
+    };
+    let error = json!({ "error": { "status": status.as_u16(), "message": message, "details": details } });
+    serde_json::to_vec_pretty(&error).unwrap() // TODO unwrap
+}
+
+/// OpenAPI 3 document generated from the route table
+pub fn openapi() -> Value {
+    let mut paths = serde_json::Map::new();
+    for route in ROUTES {
+        let mut parameters = route.params.iter().map(|(name, description)| json!({ "name": name, "in": "query", "required": false, "description": description, "schema": { "type": "string" } })).collect::<Vec<_>>();
+        if let (Some(start), Some(end)) = (route.path.find('{'), route.path.find('}')) {
+            parameters.push(json!({ "name": &route.path[start + 1..end], "in": "path", "required": true, "schema": { "type": "string" } }));
+        }
+        let content = route.produces.iter().map(|produced| (produced.to_string(), json!({}))).collect::<serde_json::Map<_, _>>();
+        let operation = json!({
+            "summary": route.summary,
+            "parameters": parameters,
+            "responses": {
+                "200": { "description": "success", "content": content },
+                "default": { "description": "error", "content": { JSON: { "schema": { "$ref": "#/components/schemas/Error" } } } },
+            },
+        });
+        let item = paths.entry(route.path.to_string()).or_insert_with(|| json!({}));
+        item[route.method.to_lowercase()] = operation;
+    }
+
+    json!({
+        "openapi": "3.0.0",
+        "info": { "title": "bioyino management API", "version": env!("CARGO_PKG_VERSION") },
+        "servers": [{ "url": API_V1 }],
+        "security": [{ "bearer": [] }],
+        "paths": paths,
+        "components": {
+            "securitySchemes": { "bearer": { "type": "http", "scheme": "bearer" } },
+            "schemas": {
+                "Error": {
+                    "type": "object",
+                    "properties": {
+                        "error": {
+                            "type": "object",
+                            "properties": {
+                                "status": { "type": "integer" },
+                                "message": { "type": "string" },
+                                "details": {},
+                            },
+                        },
+                    },
+                },
+            },
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routes() {
+        assert_eq!(find_route(&Method::GET, "/metrics/some.name").unwrap().path, "/metrics/{name}");
+        assert_eq!(find_route(&Method::GET, "/metrics").unwrap().path, "/metrics");
+        // path parameter cannot be empty
+        assert!(find_route(&Method::GET, "/metrics/").is_none());
+        assert!(find_route(&Method::PUT, "/tunables/").is_none());
+        assert!(find_route(&Method::DELETE, "/status").is_none());
+        assert_eq!(find_route(&Method::POST, "/rules").unwrap().summary, "change ingestion rules");
+        assert!(endpoint_list().contains("    GET /metrics?pattern=<pattern> - "));
+    }
+
+    #[test]
+    fn content_negotiation() {
+        let request = |accept: Option<&str>| {
+            let mut builder = Request::get("/stats");
+            if let Some(accept) = accept {
+                builder.header(ACCEPT, accept);
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+        let produces = &[JSON, "application/octet-stream"];
+        assert!(accepts(&request(None), produces));
+        assert!(accepts(&request(Some("text/html, application/json;q=0.9")), produces));
+        assert!(accepts(&request(Some("application/*")), produces));
+        assert!(accepts(&request(Some("*/*")), produces));
+        assert!(!accepts(&request(Some("text/*")), produces));
+        assert!(!accepts(&request(Some("text/plain")), produces));
+    }
+
+    #[test]
+    fn error_bodies() {
+        let error: Value = serde_json::from_slice(&error_body(StatusCode::BAD_REQUEST, b"bad file name")).unwrap();
+        assert_eq!(error, json!({ "error": { "status": 400, "message": "bad file name", "details": null } }));
+        let error: Value = serde_json::from_slice(&error_body(StatusCode::CONFLICT, br#"{"field": "count"}"#)).unwrap();
+        assert_eq!(error["error"]["message"], "Conflict");
+        assert_eq!(error["error"]["details"], json!({ "field": "count" }));
+        let error: Value = serde_json::from_slice(&error_body(StatusCode::NOT_FOUND, b"")).unwrap();
+        assert_eq!(error["error"]["message"], "Not Found");
+    }
+
+    #[test]
+    fn openapi_document() {
+        let document = openapi();
+        let metric = &document["paths"]["/metrics/{name}"]["get"];
+        assert_eq!(metric["parameters"][0]["in"], "path");
+        assert_eq!(metric["parameters"][0]["name"], "name");
+        assert_eq!(document["paths"]["/tunables/{name}"]["put"]["parameters"][0]["in"], "path");
+        // several methods of a path are kept together
+        let rules = &document["paths"]["/rules"];
+        assert!(rules["get"].is_object() && rules["put"].is_object() && rules["post"].is_object());
+        assert!(document["paths"]["/dump"]["post"]["responses"]["200"]["content"]["application/octet-stream"].is_object());
+        assert_eq!(document["paths"].as_object().unwrap().len(), ROUTES.iter().map(|route| route.path).collect::<std::collections::HashSet<_>>().len());
+    }
+}
@@ -0,0 +1,134 @@
+use std::mem;
+
+use bioyino_metric::{Metric, MetricType};
+
+use crate::{Float, ARENA_OVERFLOWS};
+
+// capacity of a buffer the arena allocates for a new timer, most timers get a few samples per interval
+const CHUNK: usize = 32;
+
+/// Timer sample buffers of one worker. A timer new to worker cache gets it's samples buffer from the
+/// arena, buffers are given back all together after the interval is aggregated, so in a steady state
+/// timers reuse the same memory every interval instead of growing new vectors. When the arena holds
+/// `limit` samples already, timers get their buffers from the allocator as usual.
+#[derive(Debug)]
+pub struct SampleArena {
+    limit: usize,
+    // samples capacity of all buffers owned by the arena, both free and given to timers
+    held: usize,
+    free: Vec<Vec<Float>>,
+}
+
+impl SampleArena {
+    /// Arena holding up to `limit` samples, 0 disables it
+    pub fn new(limit: usize) -> Self {
+        Self { limit, held: 0, free: Vec::new() }
+    }
+
+    /// Move samples of a timer to an arena buffer, other metrics are not touched
+    pub fn adopt(&mut self, metric: &mut Metric<Float>) {
+        if self.limit == 0 {
+            return;
+        }
+        if let MetricType::Timer(ref mut samples) = metric.mtype {
+            let mut buffer = match self.free.pop() {
+                Some(buffer) => buffer,
+                None if self.held + CHUNK.max(samples.len()) <= self.limit => {
+                    let buffer = Vec::with_capacity(CHUNK.max(samples.len()));
+                    self.held += buffer.capacity();
+                    buffer
+                }
+                None => {
+                    ARENA_OVERFLOWS.add(1);
+                    return;
+                }
+            };
+            buffer.extend_from_slice(&samples);
+            mem::swap(samples, &mut buffer);
+        }
+    }
+
+    /// Take back buffers of the aggregated interval. Buffers of timers that grew them keep the new capacity,
+    /// buffers over the limit and the ones never returned are left to the allocator. Buffers already given
+    /// to the next interval are not counted until they are returned, so the arena may briefly hold more than the limit.
+    pub fn reset<I: IntoIterator<Item = Vec<Float>>>(&mut self, buffers: I) {
+        if self.limit == 0 {
+            return;
+        }
+        self.held = self.free.iter().map(Vec::capacity).sum();
+        for mut buffer in buffers {
+            if self.held + buffer.capacity() > self.limit {
+                continue;
+            }
+            buffer.clear();
+            self.held += buffer.capacity();
+            self.free.push(buffer);
+        }
+    }
+
+    /// Samples capacity held by the arena
+    pub fn held(&self) -> usize {
+        self.held
+    }
+}
+
+/// Replace samples buffer of a timer with an exactly sized copy and return the buffer, so the timer
+/// can be aggregated while the buffer goes back to the worker arena
+pub fn detach(metric: &mut Metric<Float>) -> Option<Vec<Float>> {
+    match metric.mtype {
+        MetricType::Timer(ref mut samples) => {
+            let copy = samples.to_vec();
+            Some(mem::replace(samples, copy))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn timer(samples: &[Float]) -> Metric<Float> {
+        let mut metric = Metric::new(samples[0], MetricType::Timer(Vec::new()), None, None).unwrap();
+        samples[1..].iter().map(|value| metric.aggregate(Metric::new(*value, MetricType::Timer(Vec::new()), None, None).unwrap()).unwrap()).last();
+        metric
+    }
+
+    fn samples(metric: &Metric<Float>) -> &Vec<Float> {
+        match metric.mtype {
+            MetricType::Timer(ref samples) => samples,
+            _ => panic!("timer expected"),
+        }
+    }
+
+    #[test]
+    fn sample_arena() {
+        let mut arena = SampleArena::new(CHUNK * 2);
+        let mut metrics = (0..3).map(|i| timer(&[i as Float, 1f64])).collect::<Vec<_>>();
+        metrics.iter_mut().map(|metric| arena.adopt(metric)).last();
+        assert_eq!(arena.held(), CHUNK * 2);
+        // the third timer did not fit
+        assert_eq!(metrics.iter().map(|metric| samples(metric).capacity()).filter(|capacity| *capacity == CHUNK).count(), 2);
+        assert_eq!(samples(&metrics[0]), &vec![0f64, 1f64]);
+
+        let buffers = metrics.iter_mut().filter_map(detach).collect::<Vec<_>>();
+        assert_eq!(samples(&metrics[1]), &vec![1f64, 1f64]);
+        arena.reset(buffers);
+        assert_eq!(arena.held(), CHUNK * 2);
+
+        // returned buffers are reused without growing the arena
+        let mut metric = timer(&[5f64]);
+        arena.adopt(&mut metric);
+        assert_eq!(samples(&metric), &vec![5f64]);
+        assert_eq!(arena.held(), CHUNK * 2);
+
+        let mut counter = Metric::new(1f64, MetricType::Counter, None, None).unwrap();
+        arena.adopt(&mut counter);
+        assert_eq!(detach(&mut counter), None);
+
+        let mut disabled = SampleArena::new(0);
+        let mut metric = timer(&[1f64]);
+        disabled.adopt(&mut metric);
+        assert_eq!(disabled.held(), 0);
+    }
+}
@@ -0,0 +1,100 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+
+use hyper::StatusCode;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{self, Value};
+use slog::{info, warn, Logger};
+
+use crate::cluster::now_ms;
+use crate::{AUDIT_EVENTS, RUNTIME_CONFIG};
+
+// config keys holding secrets, their values are not written to audit log
+const SECRET_KEYS: &[&str] = &["management.tokens", "management.client-token"];
+
+/// A record about a state-changing management call
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct AuditRecord {
+    /// Milliseconds since UNIX epoch
+    pub time: u64,
+    /// Name of the token used, its role if token has no name, or "anonymous" if authentication is disabled
+    pub who: String,
+    /// Method and path of the call
+    pub action: String,
+    /// Query and body of the call
+    pub request: Value,
+    /// State the call could change as it was before the call, null if there is no such state
+    pub previous: Value,
+    pub status: u16,
+    /// Server answer, null if it is too big to be logged, like dumps
+    pub result: Value,
+}
+
+impl AuditRecord {
+    pub fn new(who: &str, action: String, request: Value, previous: Value, status: StatusCode, mut result: Value) -> Self {
+        hide_secrets(&mut result);
+        Self { time: now_ms(), who: who.to_string(), action, request, previous, status: status.as_u16(), result }
+    }
+}
+
+// reload reports show old and new values of changed options, secret ones are replaced
+fn hide_secrets(value: &mut Value) {
+    match value {
+        Value::Object(map) => {
+            let secret = map.get("key").and_then(|key| key.as_str()).map(|key| SECRET_KEYS.contains(&key)).unwrap_or(false);
+            for (name, value) in map.iter_mut() {
+                if secret && (name == "old" || name == "new") && !value.is_null() {
+                    *value = Value::String("<hidden>".to_string());
+                } else {
+                    hide_secrets(value);
+                }
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                hide_secrets(item);
+            }
+        }
+        _ => (),
+    }
+}
+
+/// Count the record and write it to log and to audit-log file if it is set
+pub fn audit(log: &Logger, record: AuditRecord) {
+    AUDIT_EVENTS.add(1);
+    // the record consists of plain values, so it is always serialized
+    let mut line = serde_json::to_vec(&record).unwrap();
+    info!(log, "audit"; "who"=>&record.who, "action"=>&record.action, "status"=>record.status, "record"=>String::from_utf8_lossy(&line).into_owned());
+
+    // audit file may be changed by reloading
+    let path = RUNTIME_CONFIG.read().unwrap().management.audit_log.clone();
+    if let Some(path) = path {
+        line.push(b'\n');
+        // records are rare, so the file is reopened every time, which also lets it be rotated by moving
+        if let Err(e) = OpenOptions::new().create(true).append(true).open(&path).and_then(|mut file| file.write_all(&line)) {
+            warn!(log, "error writing audit log"; "file"=>&path, "error"=>e.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn secrets_hidden() {
+        let report = json!({
+            "applied": [
+                { "key": "management.tokens", "old": null, "new": "[{ token = \"secret\" }]" },
+                { "key": "carbon.address", "old": "127.0.0.1:2003", "new": "127.0.0.2:2003" },
+            ],
+            "ignored": [],
+        });
+        let record = AuditRecord::new("ops", "POST /reload".to_string(), Value::Null, Value::Null, StatusCode::OK, report);
+        assert_eq!(record.result["applied"][0]["old"], Value::Null);
+        assert_eq!(record.result["applied"][0]["new"], json!("<hidden>"));
+        assert_eq!(record.result["applied"][1]["new"], json!("127.0.0.2:2003"));
+    }
+}
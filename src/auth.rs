@@ -0,0 +1,342 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::sync::{Arc, RwLock};
+
+use futures::future::{err, Either};
+use futures::Future;
+use hyper::header::AUTHORIZATION;
+use hyper::{Body, Method, Request, StatusCode};
+use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use lazy_static::lazy_static;
+use rustls::{AllowAnyAuthenticatedClient, BulkAlgorithm, Certificate, ClientConfig, NoClientAuth, PrivateKey, ProtocolVersion, RootCertStore, ServerConfig, SupportedCipherSuite, ALL_CIPHERSUITES};
+use serde_derive::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::webpki::{DNSName, DNSNameRef};
+use tokio_rustls::TlsConnector;
+
+use crate::config::{CipherPolicy, Tls, TlsVersion};
+use crate::errors::GeneralError;
+
+lazy_static! {
+    // settings of TLS servers, replaced on reload, so new connections use rotated certificates
+    static ref SERVER_TLS: RwLock<Option<Arc<ServerConfig>>> = RwLock::new(None);
+    // settings of TLS clients, peer and carbon connections made after reload use them
+    static ref CLIENT_TLS: RwLock<Option<Arc<ClientConfig>>> = RwLock::new(None);
+}
+
+/// Access level of a token
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub enum Role {
+    /// Can only see the server state
+    ReadOnly,
+    /// Can also change it: consensus, pausing, reloading, flushing
+    Admin,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ApiToken {
+    /// Token itself, can be left empty if `token-file` is set
+    #[serde(default)]
+    pub token: String,
+    /// File to read the token from instead of having it in configuration
+    #[serde(default)]
+    pub token_file: Option<String>,
+    pub role: Role,
+    /// Who uses the token, for audit records
+    #[serde(default)]
+    pub name: Option<String>,
+}
+
+impl ApiToken {
+    fn identity(&self) -> String {
+        match self.name {
+            Some(ref name) => name.clone(),
+            None => match self.role {
+                Role::ReadOnly => "read-only".to_string(),
+                Role::Admin => "admin".to_string(),
+            },
+        }
+    }
+}
+
+/// Role required to call the endpoint: reading is allowed for everyone, any changes require admin
+pub fn required_role(method: &Method) -> Role {
+    match *method {
+        Method::GET | Method::HEAD => Role::ReadOnly,
+        _ => Role::Admin,
+    }
+}
+
+// compare tokens not leaking the matched length through timing
+fn token_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Check the bearer token of request has enough permissions, returning who uses the token.
+/// Answers with a status code to return to client if it hasn't.
+pub fn authorize(tokens: &[ApiToken], req: &Request<Body>) -> Result<String, StatusCode> {
+    if tokens.len() == 0 {
+        // authentication is disabled
+        return Ok("anonymous".to_string());
+    }
+    let token = req.headers().get(AUTHORIZATION).and_then(|value| value.to_str().ok()).and_then(|value| if value.starts_with("Bearer ") { Some(value["Bearer ".len()..].trim()) } else { None }).ok_or(StatusCode::UNAUTHORIZED)?;
+    let known = tokens.iter().find(|known| token_eq(known.token.as_bytes(), token.as_bytes())).ok_or(StatusCode::UNAUTHORIZED)?;
+    if known.role >= required_role(req.method()) {
+        Ok(known.identity())
+    } else {
+        Err(StatusCode::FORBIDDEN)
+    }
+}
+
+fn open_pem(path: &str) -> Result<BufReader<File>, GeneralError> {
+    File::open(path).map(BufReader::new).map_err(GeneralError::Io)
+}
+
+fn read_cert_key(cert: &str, key: &str) -> Result<(Vec<Certificate>, PrivateKey), GeneralError> {
+    let certs = certs(&mut open_pem(cert)?).map_err(|_| GeneralError::Tls(format!("bad certificate file {}", cert)))?;
+    if certs.len() == 0 {
+        return Err(GeneralError::Tls(format!("no certificates found in {}", cert)));
+    }
+    // keys may be in any of both formats
+    let mut keys = pkcs8_private_keys(&mut open_pem(key)?).map_err(|_| GeneralError::Tls(format!("bad key file {}", key)))?;
+    if keys.len() == 0 {
+        keys = rsa_private_keys(&mut open_pem(key)?).map_err(|_| GeneralError::Tls(format!("bad key file {}", key)))?;
+    }
+    let key = keys.into_iter().next().ok_or(GeneralError::Tls(format!("no private key found in {}", key)))?;
+    Ok((certs, key))
+}
+
+fn read_roots(ca: &str) -> Result<RootCertStore, GeneralError> {
+    let mut roots = RootCertStore::empty();
+    let (added, _) = roots.add_pem_file(&mut open_pem(ca)?).map_err(|_| GeneralError::Tls(format!("bad CA file {}", ca)))?;
+    if added == 0 {
+        return Err(GeneralError::Tls(format!("no CA certificates found in {}", ca)));
+    }
+    Ok(roots)
+}
+
+fn versions(config: &Tls) -> Vec<ProtocolVersion> {
+    match config.min_version {
+        TlsVersion::V12 => vec![ProtocolVersion::TLSv1_3, ProtocolVersion::TLSv1_2],
+        TlsVersion::V13 => vec![ProtocolVersion::TLSv1_3],
+    }
+}
+
+fn ciphersuites(config: &Tls) -> Vec<&'static SupportedCipherSuite> {
+    ALL_CIPHERSUITES.iter().cloned().filter(|suite| config.cipher_policy == CipherPolicy::Default || suite.bulk != BulkAlgorithm::AES_128_GCM).collect()
+}
+
+/// Make TLS server settings reading certificate files, `None` means TLS is not configured
+pub fn tls_config(config: &Tls) -> Result<Option<Arc<ServerConfig>>, GeneralError> {
+    let (cert, key) = match (&config.cert, &config.key) {
+        (Some(cert), Some(key)) => (cert, key),
+        (None, None) => {
+            if config.client_ca.is_some() {
+                return Err(GeneralError::Configuration("tls.client-ca requires tls.cert and tls.key to be set"));
+            }
+            return Ok(None);
+        }
+        _ => return Err(GeneralError::Configuration("both tls.cert and tls.key must be set to enable TLS")),
+    };
+
+    let (certs, key) = read_cert_key(cert, key)?;
+    let mut server = match config.client_ca {
+        Some(ref ca) => ServerConfig::new(AllowAnyAuthenticatedClient::new(read_roots(ca)?)),
+        None => ServerConfig::new(NoClientAuth::new()),
+    };
+    server.set_single_cert(certs, key).map_err(|e| GeneralError::Tls(e.to_string()))?;
+    server.versions = versions(config);
+    server.ciphersuites = ciphersuites(config);
+    Ok(Some(Arc::new(server)))
+}
+
+/// Make TLS client settings for peer and carbon connections. Servers are verified with `tls.ca`, or with
+/// public roots if it is not set. Certificate and key are presented to servers authenticating clients.
+pub fn client_tls_config(config: &Tls) -> Result<Arc<ClientConfig>, GeneralError> {
+    let mut client = ClientConfig::new();
+    match config.ca {
+        Some(ref ca) => client.root_store = read_roots(ca)?,
+        None => client.root_store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS),
+    }
+    match (&config.cert, &config.key) {
+        (Some(cert), Some(key)) => {
+            let (certs, key) = read_cert_key(cert, key)?;
+            client.set_single_client_cert(certs, key);
+        }
+        (None, None) => (),
+        _ => return Err(GeneralError::Configuration("both tls.cert and tls.key must be set to enable TLS")),
+    }
+    client.versions = versions(config);
+    client.ciphersuites = ciphersuites(config);
+    Ok(Arc::new(client))
+}
+
+/// Name the certificate of server at `address` is verified for: `tls.server-name` if it is set, host part of the address otherwise
+pub fn tls_name(config: &Tls, address: &str) -> Result<DNSName, GeneralError> {
+    let name = match config.server_name {
+        Some(ref name) => name.as_str(),
+        None => address.split(':').next().unwrap_or(address), // split always has first element
+    };
+    DNSNameRef::try_from_ascii_str(name).map(|name| name.to_owned()).map_err(|_| GeneralError::Tls(format!("{} cannot be verified as a DNS name, set tls.server-name", name)))
+}
+
+/// Read certificates and keep TLS server settings, returning if TLS is enabled
+pub fn init_server_tls(config: &Tls) -> Result<bool, GeneralError> {
+    let server = tls_config(config)?;
+    let enabled = server.is_some();
+    *SERVER_TLS.write().unwrap() = server;
+    init_client_tls(config)?;
+    Ok(enabled)
+}
+
+/// Current TLS server settings, `None` if TLS is not enabled
+pub fn server_tls() -> Option<Arc<ServerConfig>> {
+    SERVER_TLS.read().unwrap().clone()
+}
+
+// servers listen either with TLS or without, so only certificates can be replaced
+fn replace_server_tls(current: &mut Option<Arc<ServerConfig>>, config: &Tls) -> Result<bool, GeneralError> {
+    match (current.is_some(), tls_config(config)?) {
+        (true, Some(server)) => {
+            *current = Some(server);
+            Ok(true)
+        }
+        (false, None) => Ok(false),
+        _ => Err(GeneralError::Configuration("enabling or disabling TLS needs restart")),
+    }
+}
+
+/// Read certificates again, so connections accepted or made after this use the new ones. Servers listen either
+/// with TLS or without, so enabling or disabling it needs restart.
+pub fn reload_server_tls(config: &Tls) -> Result<bool, GeneralError> {
+    let client = client_tls_config(config)?;
+    let enabled = replace_server_tls(&mut SERVER_TLS.write().unwrap(), config)?;
+    *CLIENT_TLS.write().unwrap() = Some(client);
+    Ok(enabled)
+}
+
+/// Read TLS client settings used by peer and carbon connections
+pub fn init_client_tls(config: &Tls) -> Result<(), GeneralError> {
+    *CLIENT_TLS.write().unwrap() = Some(client_tls_config(config)?);
+    Ok(())
+}
+
+/// Start TLS session over the connection to server, verifying it has a certificate for `name`
+pub fn connect_tls<S: AsyncRead + AsyncWrite>(conn: S, name: &DNSName) -> impl Future<Item = TlsStream<S>, Error = io::Error> {
+    match CLIENT_TLS.read().unwrap().clone() {
+        Some(client) => Either::A(TlsConnector::from(client).connect(name.as_ref(), conn)),
+        None => Either::B(err(io::Error::new(io::ErrorKind::Other, "TLS client settings are not read"))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::Stream;
+    use tokio::io::{read_exact, write_all};
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::runtime::current_thread::Runtime;
+    use tokio_rustls::TlsAcceptor;
+
+    fn request(method: Method, token: Option<&str>) -> Request<Body> {
+        let mut builder = Request::builder();
+        builder.method(method).uri("/status");
+        if let Some(token) = token {
+            builder.header(AUTHORIZATION, format!("Bearer {}", token));
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn token_permissions() {
+        let tokens = vec![ApiToken { token: "reader".to_string(), token_file: None, role: Role::ReadOnly, name: None }, ApiToken { token: "admin".to_string(), token_file: None, role: Role::Admin, name: Some("ops".to_string()) }];
+
+        assert_eq!(authorize(&[], &request(Method::POST, None)), Ok("anonymous".to_string()));
+        assert_eq!(authorize(&tokens, &request(Method::GET, None)), Err(StatusCode::UNAUTHORIZED));
+        assert_eq!(authorize(&tokens, &request(Method::GET, Some("unknown"))), Err(StatusCode::UNAUTHORIZED));
+        assert_eq!(authorize(&tokens, &request(Method::GET, Some("reader"))), Ok("read-only".to_string()));
+        assert_eq!(authorize(&tokens, &request(Method::POST, Some("reader"))), Err(StatusCode::FORBIDDEN));
+        assert_eq!(authorize(&tokens, &request(Method::POST, Some("admin"))), Ok("ops".to_string()));
+    }
+
+    fn testdata(file: &str) -> Option<String> {
+        Some(format!("{}/testdata/tls/{}", env!("CARGO_MANIFEST_DIR"), file))
+    }
+
+    // certificate for localhost signed by the test CA, it is valid for clients too
+    fn settings() -> Tls {
+        Tls { cert: testdata("cert.pem"), key: testdata("key.pem"), ca: testdata("ca.pem"), ..Tls::default() }
+    }
+
+    fn handshake(tls: &Tls, address: &str) -> Result<[u8; 2], io::Error> {
+        let acceptor = TlsAcceptor::from(tls_config(tls).unwrap().unwrap());
+        let connector = TlsConnector::from(client_tls_config(tls).unwrap());
+        let name = tls_name(tls, address).unwrap();
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let client = TcpStream::connect(&listener.local_addr().unwrap()).and_then(move |conn| connector.connect(name.as_ref(), conn)).and_then(|conn| read_exact(conn, [0u8; 2]));
+        let server = listener.incoming().into_future().map_err(|(e, _)| e).and_then(move |(conn, _)| acceptor.accept(conn.unwrap())).and_then(|conn| write_all(conn, b"ok"));
+        let mut runtime = Runtime::new().unwrap();
+        runtime.block_on(client.join(server)).map(|((_, buf), _)| buf)
+    }
+
+    #[test]
+    fn tls_connections() {
+        let mut tls = settings();
+        assert_eq!(handshake(&tls, "localhost:8136").unwrap(), *b"ok");
+        // client certificate is checked by server
+        tls.client_ca = testdata("ca.pem");
+        assert_eq!(handshake(&tls, "localhost:2003").unwrap(), *b"ok");
+        // server certificate is checked for the name
+        tls.server_name = Some("bioyino.example.com".to_string());
+        assert!(handshake(&tls, "localhost:2003").is_err());
+
+        assert!(tls_name(&Tls::default(), "127.0.0.1:8136").is_err());
+        tls.server_name = Some("localhost".to_string());
+        assert_eq!(tls_name(&tls, "127.0.0.1:8136").unwrap(), tls_name(&Tls::default(), "localhost:8136").unwrap());
+    }
+
+    #[test]
+    fn invalid_tls_files() {
+        assert!(tls_config(&settings()).unwrap().is_some());
+        assert!(client_tls_config(&settings()).is_ok());
+
+        let broken = vec![
+            Tls { cert: testdata("missing.pem"), ..settings() },
+            // files swapped contain no certificates or keys
+            Tls { cert: testdata("key.pem"), ..settings() },
+            Tls { key: testdata("cert.pem"), ..settings() },
+            Tls { key: None, ..settings() },
+        ];
+        for tls in &broken {
+            assert!(tls_config(tls).is_err());
+            assert!(client_tls_config(tls).is_err());
+        }
+        assert!(tls_config(&Tls { client_ca: testdata("key.pem"), ..settings() }).is_err());
+        assert!(client_tls_config(&Tls { ca: testdata("key.pem"), ..settings() }).is_err());
+    }
+
+    #[test]
+    fn reload_tls_files() {
+        let mut current = tls_config(&settings()).unwrap();
+        let first = current.clone().unwrap();
+        // broken files leave the current settings in place
+        assert!(replace_server_tls(&mut current, &Tls { key: testdata("cert.pem"), ..settings() }).is_err());
+        assert!(Arc::ptr_eq(current.as_ref().unwrap(), &first));
+        // files are read again even if options are the same
+        assert!(replace_server_tls(&mut current, &settings()).unwrap());
+        assert!(!Arc::ptr_eq(current.as_ref().unwrap(), &first));
+
+        assert!(replace_server_tls(&mut current, &Tls::default()).is_err());
+        let mut plain = None;
+        assert!(!replace_server_tls(&mut plain, &Tls::default()).unwrap());
+        assert!(replace_server_tls(&mut plain, &settings()).is_err());
+        assert!(plain.is_none());
+    }
+}
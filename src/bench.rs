@@ -0,0 +1,298 @@
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rand::rngs::ThreadRng;
+use rand::{thread_rng, Rng};
+use serde_derive::Serialize;
+
+use crate::errors::GeneralError;
+use crate::Float;
+
+/// Statsd metric types the generator can produce
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BenchType {
+    Counter,
+    Gauge,
+    Timer,
+    Set,
+}
+
+impl BenchType {
+    fn suffix(self) -> &'static str {
+        match self {
+            BenchType::Counter => "c",
+            BenchType::Gauge => "g",
+            BenchType::Timer => "ms",
+            BenchType::Set => "s",
+        }
+    }
+}
+
+/// Shares of metric types in generated traffic, written like `c=50,g=20,ms=25,s=5`
+#[derive(Debug, Clone, PartialEq)]
+pub struct TypeMix(Vec<(BenchType, u32)>);
+
+impl FromStr for TypeMix {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut mix = Vec::new();
+        for part in s.split(',').map(str::trim).filter(|part| part.len() > 0) {
+            let mut kv = part.splitn(2, '=');
+            let mtype = match kv.next() {
+                Some("c") => BenchType::Counter,
+                Some("g") => BenchType::Gauge,
+                Some("ms") | Some("h") => BenchType::Timer,
+                Some("s") => BenchType::Set,
+                _ => return Err(format!("bad metric type in {:?}, expected one of c, g, ms, s", part)),
+            };
+            let share = kv.next().unwrap_or("1").parse::<u32>().map_err(|_| format!("bad share in {:?}", part))?;
+            if share > 0 {
+                mix.push((mtype, share));
+            }
+        }
+        if mix.len() == 0 {
+            return Err("type mix must have at least one type with non-zero share".to_string());
+        }
+        Ok(TypeMix(mix))
+    }
+}
+
+impl TypeMix {
+    /// Type of the metric with this name index. A name always has the same type, so the server
+    /// does not get aggregation errors, and names are split between types according to their shares.
+    fn type_of(&self, idx: usize) -> BenchType {
+        let total = self.0.iter().map(|(_, share)| *share as usize).sum::<usize>();
+        let mut position = idx % total;
+        for (mtype, share) in &self.0 {
+            if position < *share as usize {
+                return *mtype;
+            }
+            position -= *share as usize;
+        }
+        unreachable!("position is always less than the sum of shares")
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BenchOptions {
+    pub target: SocketAddr,
+    /// Number of unique metric names
+    pub names: usize,
+    pub prefix: String,
+    pub types: TypeMix,
+    /// Datagrams per second over all threads, 0 means as fast as possible
+    pub rate: u64,
+    /// Maximum datagram size, lines are packed to datagrams until it is reached
+    pub payload: usize,
+    /// Number of DogStatsD tags added to every metric
+    pub tags: usize,
+    /// Number of different values of every tag
+    pub tag_values: usize,
+    pub duration: Duration,
+    pub threads: usize,
+}
+
+// a generator owned by one sending thread
+struct Generator<'a> {
+    options: &'a BenchOptions,
+    rng: ThreadRng,
+    // a line that did not fit to the previous datagram
+    pending: Option<String>,
+}
+
+impl<'a> Generator<'a> {
+    fn new(options: &'a BenchOptions) -> Self {
+        Self { options, rng: thread_rng(), pending: None }
+    }
+
+    fn line(&mut self) -> String {
+        let idx = self.rng.gen_range(0, self.options.names.max(1));
+        let mtype = self.options.types.type_of(idx);
+        let value: Float = match mtype {
+            BenchType::Counter => self.rng.gen_range(1, 10) as Float,
+            BenchType::Gauge => self.rng.gen_range(0, 1000) as Float,
+            BenchType::Timer => (self.rng.gen_range(0f64, 1000f64) * 100f64).round() / 100f64,
+            BenchType::Set => self.rng.gen_range(0, 100) as Float,
+        };
+        let mut line = format!("{}.{}:{}|{}", self.options.prefix, idx, value, mtype.suffix());
+        for tag in 0..self.options.tags {
+            line.push_str(if tag == 0 { "|#" } else { "," });
+            line.push_str(&format!("tag{}:value{}", tag, self.rng.gen_range(0, self.options.tag_values.max(1))));
+        }
+        line
+    }
+
+    /// Fill the buffer with lines up to the payload size, returns the number of metrics in it.
+    /// A single line longer than payload is sent as is.
+    fn fill(&mut self, buf: &mut Vec<u8>) -> usize {
+        buf.clear();
+        let mut metrics = 0;
+        loop {
+            let line = self.pending.take().unwrap_or_else(|| self.line());
+            if metrics > 0 && buf.len() + 1 + line.len() > self.options.payload {
+                self.pending = Some(line);
+                return metrics;
+            }
+            if metrics > 0 {
+                buf.push(b'\n');
+            }
+            buf.extend_from_slice(line.as_bytes());
+            metrics += 1;
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct ThreadReport {
+    packets: u64,
+    metrics: u64,
+    bytes: u64,
+    errors: u64,
+    // time of every send call in microseconds
+    latencies: Vec<u64>,
+}
+
+fn run_thread(options: &BenchOptions, rate: u64) -> Result<ThreadReport, GeneralError> {
+    let bind: SocketAddr = if options.target.is_ipv4() { "0.0.0.0:0".parse().unwrap() } else { "[::]:0".parse().unwrap() };
+    let socket = UdpSocket::bind(bind).map_err(GeneralError::Io)?;
+    socket.connect(options.target).map_err(GeneralError::Io)?;
+
+    let mut generator = Generator::new(options);
+    let mut report = ThreadReport::default();
+    let mut buf = Vec::with_capacity(options.payload);
+    let interval = if rate > 0 { Some(Duration::from_nanos(1_000_000_000 / rate)) } else { None };
+    let start = Instant::now();
+    let mut next = start;
+    while start.elapsed() < options.duration {
+        if let Some(interval) = interval {
+            let now = Instant::now();
+            if next > now {
+                thread::sleep(next - now);
+            } else if now - next > Duration::from_secs(1) {
+                // too far behind, the lost time is not made up with a burst
+                next = now;
+            }
+            next += interval;
+        }
+        let metrics = generator.fill(&mut buf);
+        let sent = Instant::now();
+        match socket.send(&buf) {
+            Ok(len) => {
+                report.packets += 1;
+                report.metrics += metrics as u64;
+                report.bytes += len as u64;
+            }
+            Err(_) => report.errors += 1,
+        }
+        let latency = sent.elapsed();
+        report.latencies.push(latency.as_secs() * 1_000_000 + latency.subsec_micros() as u64);
+    }
+    Ok(report)
+}
+
+// value below which `quantile` of sorted values are
+fn percentile(sorted: &[u64], quantile: f64) -> u64 {
+    if sorted.len() == 0 {
+        return 0;
+    }
+    let idx = ((sorted.len() - 1) as f64 * quantile).round() as usize;
+    sorted[idx]
+}
+
+/// Results of a benchmark run
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct BenchReport {
+    pub target: String,
+    pub elapsed_secs: Float,
+    pub packets: u64,
+    pub metrics: u64,
+    pub bytes: u64,
+    pub send_errors: u64,
+    pub packets_per_sec: Float,
+    pub metrics_per_sec: Float,
+    pub bytes_per_sec: Float,
+    /// Time of sending one datagram, in microseconds
+    pub latency_p50_us: u64,
+    pub latency_p90_us: u64,
+    pub latency_p99_us: u64,
+    pub latency_max_us: u64,
+}
+
+/// Send generated statsd traffic to the target from all threads and wait for them to finish
+pub fn run_bench(options: BenchOptions) -> Result<BenchReport, GeneralError> {
+    let threads = options.threads.max(1);
+    let rate = if options.rate > 0 { (options.rate / threads as u64).max(1) } else { 0 };
+    let start = Instant::now();
+    let handles = (0..threads)
+        .map(|i| {
+            let options = options.clone();
+            thread::Builder::new().name(format!("bioyino_bench{}", i)).spawn(move || run_thread(&options, rate)).map_err(GeneralError::Io)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut total = ThreadReport::default();
+    for handle in handles {
+        let report = handle.join().map_err(|_| GeneralError::Io(io::Error::new(io::ErrorKind::Other, "bench thread panicked")))??;
+        total.packets += report.packets;
+        total.metrics += report.metrics;
+        total.bytes += report.bytes;
+        total.errors += report.errors;
+        total.latencies.extend(report.latencies);
+    }
+    let elapsed = start.elapsed();
+    let elapsed = elapsed.as_secs() as Float + elapsed.subsec_nanos() as Float / 1e9;
+    total.latencies.sort_unstable();
+    Ok(BenchReport {
+        target: options.target.to_string(),
+        elapsed_secs: elapsed,
+        packets: total.packets,
+        metrics: total.metrics,
+        bytes: total.bytes,
+        send_errors: total.errors,
+        packets_per_sec: total.packets as Float / elapsed,
+        metrics_per_sec: total.metrics as Float / elapsed,
+        bytes_per_sec: total.bytes as Float / elapsed,
+        latency_p50_us: percentile(&total.latencies, 0.5),
+        latency_p90_us: percentile(&total.latencies, 0.9),
+        latency_p99_us: percentile(&total.latencies, 0.99),
+        latency_max_us: total.latencies.last().cloned().unwrap_or(0),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(target: SocketAddr) -> BenchOptions {
+        BenchOptions { target, names: 100, prefix: "bench".to_string(), types: "c=2,ms=1".parse().unwrap(), rate: 1000, payload: 200, tags: 2, tag_values: 5, duration: Duration::from_millis(100), threads: 2 }
+    }
+
+    #[test]
+    fn generate_traffic() {
+        assert!("c=1,x=2".parse::<TypeMix>().is_err());
+        assert!("c=0".parse::<TypeMix>().is_err());
+        let mix = "c=2,ms=1".parse::<TypeMix>().unwrap();
+        assert_eq!((0..6).map(|idx| mix.type_of(idx)).collect::<Vec<_>>(), vec![BenchType::Counter, BenchType::Counter, BenchType::Timer, BenchType::Counter, BenchType::Counter, BenchType::Timer]);
+        assert_eq!(percentile(&[1, 2, 3, 4, 5], 0.5), 3);
+
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let options = options(receiver.local_addr().unwrap());
+        let mut generator = Generator::new(&options);
+        let mut buf = Vec::new();
+        let metrics = generator.fill(&mut buf);
+        assert!(buf.len() <= options.payload);
+        let lines = buf.split(|c| *c == b'\n').collect::<Vec<_>>();
+        assert_eq!(lines.len(), metrics);
+        assert!(lines.iter().all(|line| line.starts_with(b"bench.") && line.windows(2).any(|w| w == b"|#")));
+
+        let report = run_bench(options).unwrap();
+        assert!(report.packets > 0);
+        assert!(report.metrics >= report.packets);
+        assert_eq!(report.send_errors, 0);
+    }
+}
@@ -0,0 +1,68 @@
+use serde_derive::{Deserialize, Serialize};
+
+use crate::config::System;
+use crate::incident::config_hash;
+use crate::RUNTIME_CONFIG;
+
+/// What the running binary is: version, source commit, build time and cargo features,
+/// together with hash of the configuration it runs with, so nodes can be compared with each other
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct BuildInfo {
+    pub version: String,
+    pub commit: String,
+    pub commit_date: String,
+    pub build_timestamp: String,
+    /// Optional cargo features enabled at build time
+    pub features: Vec<String>,
+    /// Hash of the active configuration, `None` if it could not be loaded
+    pub config_hash: Option<String>,
+}
+
+impl BuildInfo {
+    pub fn new(config: Option<&System>) -> Self {
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            commit: env!("VERGEN_SHA_SHORT").to_string(),
+            commit_date: env!("VERGEN_COMMIT_DATE").to_string(),
+            build_timestamp: env!("VERGEN_BUILD_TIMESTAMP").to_string(),
+            features: features().iter().map(|feature| feature.to_string()).collect(),
+            config_hash: config.map(config_hash),
+        }
+    }
+
+    /// Build info with hash of the configuration running now, changed by reloads
+    pub fn current() -> Self {
+        let config = RUNTIME_CONFIG.read().unwrap();
+        Self::new(Some(&**config))
+    }
+}
+
+/// Names of optional cargo features the binary was built with
+pub fn features() -> Vec<&'static str> {
+    let all = [("simd", cfg!(feature = "simd")), ("jemalloc", cfg!(feature = "jemalloc")), ("mimalloc", cfg!(feature = "mimalloc")), ("pprof", cfg!(feature = "pprof")), ("fuzzing", cfg!(feature = "fuzzing"))];
+    all.iter().filter(|(_, enabled)| *enabled).map(|(name, _)| *name).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_info() {
+        let mut config = System::default();
+        let info = BuildInfo::new(Some(&config));
+        assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(info.features.contains(&"simd".to_string()), cfg!(feature = "simd"));
+        // nodes with the same configuration have the same hash
+        assert_eq!(BuildInfo::new(Some(&config)).config_hash, info.config_hash);
+        config.carbon.interval += 1000;
+        assert_ne!(BuildInfo::new(Some(&config)).config_hash, info.config_hash);
+        assert_eq!(BuildInfo::new(None).config_hash, None);
+
+        // servers of older versions may not report some of the fields
+        let parsed: BuildInfo = serde_json::from_str(r#"{"version": "0.5.0", "config-hash": "0123456789abcdef"}"#).unwrap();
+        assert_eq!(parsed, BuildInfo { version: "0.5.0".to_string(), config_hash: Some("0123456789abcdef".to_string()), ..Default::default() });
+        assert_eq!(serde_json::from_str::<BuildInfo>(&serde_json::to_string(&info).unwrap()).unwrap(), info);
+    }
+}
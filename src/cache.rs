@@ -0,0 +1,173 @@
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use bioyino_metric::{Metric, MetricType};
+
+use crate::arena::SampleArena;
+use crate::intern::NameId;
+use crate::memory::sample_cap;
+use crate::{Cache, Float, AGG_ERRORS, CAPPED_METRICS, CAPPED_SAMPLES};
+
+/// Number of shards in every worker cache
+pub const CACHE_SHARDS: usize = 16;
+
+// generation of long caches, all workers switch to a new one as soon as it changes
+static EPOCH: AtomicUsize = AtomicUsize::new(0);
+
+/// Start a new cache generation, returns it's number
+pub fn advance_epoch() -> usize {
+    EPOCH.fetch_add(1, Ordering::AcqRel) + 1
+}
+
+pub fn current_epoch() -> usize {
+    EPOCH.load(Ordering::Acquire)
+}
+
+/// Empty shards for a new cache generation able to hold `capacity` metrics without rehashing
+pub fn new_generation(capacity: usize) -> Vec<Cache> {
+    (0..CACHE_SHARDS).map(|_| HashMap::with_capacity(capacity / CACHE_SHARDS)).collect()
+}
+
+/// Aggregate the metric into cache entry with the same name or add a new entry.
+/// Under memory pressure samples of timers already having too many of them are dropped, a timer
+/// reaching the cap is counted once, so the number of timers with approximate percentiles is known.
+pub fn update_metric(cache: &mut Cache, name: NameId, metric: Metric<Float>) {
+    upsert(cache, name, metric, None)
+}
+
+fn upsert(cache: &mut Cache, name: NameId, mut metric: Metric<Float>, arena: Option<&mut SampleArena>) {
+    match cache.entry(name) {
+        Entry::Occupied(ref mut entry) => {
+            let cap = sample_cap();
+            if let (MetricType::Timer(ref samples), Some(cap)) = (&entry.get().mtype, cap) {
+                if samples.len() >= cap {
+                    CAPPED_SAMPLES.add(1);
+                    return;
+                }
+            }
+            entry.get_mut().aggregate(metric).unwrap_or_else(|_| {
+                AGG_ERRORS.add(1);
+            });
+            if let (MetricType::Timer(ref samples), Some(cap)) = (&entry.get().mtype, cap) {
+                if samples.len() >= cap {
+                    CAPPED_METRICS.add(1);
+                }
+            }
+        }
+        Entry::Vacant(entry) => {
+            if let Some(arena) = arena {
+                arena.adopt(&mut metric);
+            }
+            entry.insert(metric);
+        }
+    };
+}
+
+/// Worker cache split to shards by name id. Whole shards can be taken out without copying,
+/// so snapshots and rotations do not stop the worker for the time of walking over all metrics.
+#[derive(Debug)]
+pub struct ShardedCache {
+    shards: Vec<Cache>,
+}
+
+impl ShardedCache {
+    pub fn new(shards: usize, capacity: usize) -> Self {
+        Self { shards: (0..shards).map(|_| HashMap::with_capacity(capacity / shards)).collect() }
+    }
+
+    fn shard(&self, id: &NameId) -> usize {
+        id.index() % self.shards.len()
+    }
+
+    pub fn get(&self, id: &NameId) -> Option<&Metric<Float>> {
+        self.shards[self.shard(id)].get(id)
+    }
+
+    pub fn contains_key(&self, id: &NameId) -> bool {
+        self.shards[self.shard(id)].contains_key(id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.len()).sum()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&NameId, &Metric<Float>)> {
+        self.shards.iter().flat_map(|shard| shard.iter())
+    }
+
+    pub fn shards(&self) -> &[Cache] {
+        &self.shards
+    }
+
+    pub fn update(&mut self, id: NameId, metric: Metric<Float>) {
+        let shard = self.shard(&id);
+        update_metric(&mut self.shards[shard], id, metric)
+    }
+
+    /// The same as `update`, but samples of a timer new to the cache are put to an arena buffer
+    pub fn update_in(&mut self, id: NameId, metric: Metric<Float>, arena: &mut SampleArena) {
+        let shard = self.shard(&id);
+        upsert(&mut self.shards[shard], id, metric, Some(arena))
+    }
+
+    /// Merge all metrics from the cache, which may be sharded differently, timers new to this cache
+    /// get their samples buffers from the arena
+    pub fn merge(&mut self, cache: Cache, arena: &mut SampleArena) {
+        cache.into_iter().map(|(id, metric)| self.update_in(id, metric, arena)).last();
+    }
+
+    /// Take all shards out leaving the cache empty, shard capacity is kept for the next interval
+    pub fn take(&mut self) -> Vec<Cache> {
+        self.shards.iter_mut().map(|shard| {
+            let capacity = shard.len();
+            mem::replace(shard, HashMap::with_capacity(capacity))
+        }).collect()
+    }
+
+    /// Replace all shards with the new generation and return the old one. Nothing is copied or
+    /// allocated here, so the worker only stops for the time of swapping a vector.
+    pub fn swap(&mut self, generation: Vec<Cache>) -> Vec<Cache> {
+        debug_assert_eq!(generation.len(), self.shards.len());
+        mem::replace(&mut self.shards, generation)
+    }
+
+    pub fn retain<F: FnMut(&NameId, &mut Metric<Float>) -> bool>(&mut self, mut f: F) {
+        self.shards.iter_mut().map(|shard| shard.retain(|id, metric| f(id, metric))).last();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::intern::intern;
+
+    #[test]
+    fn sharded_cache() {
+        let mut cache = ShardedCache::new(4, 16);
+        let metric = Metric::new(1f64, MetricType::Counter, None, None).unwrap();
+        let ids = (0..10).map(|i| intern(format!("sharded.cache.{}", i).as_bytes())).collect::<Vec<_>>();
+        for id in &ids {
+            cache.update(*id, metric.clone());
+        }
+        cache.update(ids[0], metric.clone());
+        assert_eq!(cache.len(), 10);
+        assert_eq!(cache.get(&ids[0]).unwrap().value, 2f64);
+
+        let taken = cache.take();
+        assert_eq!(taken.len(), 4);
+        assert_eq!(taken.iter().map(|shard| shard.len()).sum::<usize>(), 10);
+        assert_eq!(cache.len(), 0);
+
+        taken.into_iter().map(|shard| cache.merge(shard, &mut SampleArena::new(0))).last();
+        cache.retain(|id, _| *id != ids[1]);
+        assert_eq!(cache.len(), 9);
+        assert!(!cache.contains_key(&ids[1]));
+
+        let old = cache.swap((0..4).map(|_| HashMap::new()).collect());
+        assert_eq!(old.iter().map(|shard| shard.len()).sum::<usize>(), 9);
+        assert_eq!(cache.len(), 0);
+    }
+}
@@ -1,27 +1,109 @@
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::mem;
 use std::net::SocketAddr;
-use std::sync::atomic::Ordering;
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{self, Duration, Instant, SystemTime};
 
 use bytes::{BufMut, Bytes, BytesMut};
 use failure::Error;
 use ftoa;
 use futures::future::{err, Either};
 use futures::stream;
+use futures::sync::mpsc::{self, Sender};
 use futures::{Future, IntoFuture, Sink, Stream};
-use slog::{info, Logger};
+use lazy_static::lazy_static;
+use slog::{error, info, o, Logger};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
+use tokio::runtime::current_thread::{spawn, Runtime};
 use tokio_codec::{Decoder, Encoder};
+use tokio_rustls::webpki::DNSName;
 
+use crate::accounting::count_points;
+use crate::aggregate::{AggregateOptions, Aggregator};
+use crate::auth::{connect_tls, tls_name};
 use crate::errors::GeneralError;
+use crate::events::event;
+use crate::gaps::{last_values, mark_flushed, take_backfill};
+use crate::incident::backend_sent;
+use crate::latency::FLUSH_LATENCY;
+use crate::queue::FLUSH_QUEUE;
+use crate::stall::{StallOptions, StallWatch};
+use crate::spool::spool_metrics;
+use crate::stats::CARBON_BACKEND;
+use crate::task::Task;
+use crate::trace::Span;
 
-use crate::util::bound_stream;
-use crate::{Float, AGG_ERRORS};
+use crate::util::{bound_stream, try_resolve, BackoffRetryBuilder};
+use crate::maintenance::MAINTENANCE;
+use crate::{Float, AGG_ERRORS, BACKEND_OK, DROPS, EGRESS, FLUSH_PAUSED, IS_LEADER, RUNTIME_CONFIG};
+
+// aggregated metrics of an interval with its timestamp
+type IntervalMetrics = (Duration, Vec<(Bytes, Float)>);
+
+lazy_static! {
+    // aggregated metrics waiting for flushing to be resumed
+    static ref PAUSED_FLUSHES: Mutex<VecDeque<IntervalMetrics>> = { Mutex::new(VecDeque::new()) };
+}
+
+// queue metrics of the interval, returning intervals to be sent now: all of the queued ones, or none while flushing
+// is paused, when only `max_paused` last intervals are kept and metrics of older ones are dropped
+fn take_intervals(queue: &mut VecDeque<IntervalMetrics>, interval: IntervalMetrics, paused: bool, max_paused: usize) -> Vec<IntervalMetrics> {
+    queue.push_back(interval);
+    if !paused {
+        return queue.drain(..).collect();
+    }
+    while queue.len() > max_paused {
+        if let Some((_, dropped)) = queue.pop_front() {
+            DROPS.add(dropped.len());
+        }
+    }
+    Vec::new()
+}
+
+/// Time until the first flush for flushes to happen at `offset` from the start of every interval,
+/// intervals are counted from UNIX epoch, so all nodes agree on them
+pub fn first_flush_delay(now_ms: u64, interval: u64, offset: u64) -> u64 {
+    let interval = interval.max(1);
+    match (offset % interval + interval - now_ms % interval) % interval {
+        // do not flush right at the start, caches are empty anyway
+        0 => interval,
+        delay => delay,
+    }
+}
+
+// mark file path with last values of backfilled metrics in the interval, values are taken by the first one marking it,
+// none are left if a chunk has failed
+type IntervalMark = Option<(String, Arc<Mutex<Option<BTreeMap<String, Float>>>>)>;
+
+fn mark_interval(mark: &IntervalMark, ts: Duration, log: &Logger) {
+    if let Some((ref path, ref values)) = mark {
+        if let Some(values) = values.lock().unwrap().take() {
+            mark_flushed(path, ts, values).unwrap_or_else(|e| error!(log, "marking flushed interval"; "error"=>e.to_string()));
+        }
+    }
+}
+
+/// Estimated size of metrics being sent to backend right now
+pub static BACKEND_QUEUE_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+fn metrics_size(metrics: &[(Bytes, Float)]) -> usize {
+    metrics.iter().map(|(name, _)| name.len() + mem::size_of::<(Bytes, Float)>()).sum()
+}
+
+/// Estimated size of metrics kept while flushing is paused
+pub fn paused_flush_bytes() -> usize {
+    PAUSED_FLUSHES.lock().unwrap().iter().map(|(_, metrics)| metrics_size(metrics)).sum()
+}
 
 #[derive(Clone)]
 pub struct CarbonClientOptions {
     pub addr: SocketAddr,
     pub bind: Option<SocketAddr>,
+    /// Name to verify the backend certificate for when metrics are sent over TLS
+    pub tls: Option<DNSName>,
 }
 
 #[derive(Clone)]
@@ -46,7 +128,7 @@ impl CarbonBackend {
                     buf
                 }
                 Err(_) => {
-                    AGG_ERRORS.fetch_add(1, Ordering::Relaxed);
+                    AGG_ERRORS.add(1);
                     wr.into_inner()
                 }
             };
@@ -73,21 +155,212 @@ impl IntoFuture for CarbonBackend {
             None => Either::A(TcpStream::connect(&options.addr)),
         };
 
-        let elog = log.clone();
-        let future = stream_future.map_err(GeneralError::Io).and_then(move |conn| {
-            info!(log, "carbon backend sending metrics");
-            let writer = CarbonCodec::new().framed(conn);
-            let metric_stream = stream::iter_ok::<_, ()>(SharedIter::new(metrics));
-            metric_stream.map_err(|_| GeneralError::CarbonBackend).forward(writer.sink_map_err(|_| GeneralError::CarbonBackend)).map(move |_| info!(log, "carbon backend finished")).map_err(move |e| {
-                info!(elog, "carbon backend error");
-                e
-            })
+        let tls = options.tls;
+        let future = stream_future.map_err(GeneralError::Io).and_then(move |conn| match tls {
+            Some(name) => Either::A(connect_tls(conn, &name).map_err(GeneralError::Io).and_then(move |conn| send_metrics(conn, metrics, log))),
+            None => Either::B(send_metrics(conn, metrics, log)),
         });
 
         Box::new(future)
     }
 }
 
+fn send_metrics<S: AsyncRead + AsyncWrite>(conn: S, metrics: Arc<Vec<(Bytes, Bytes, Bytes)>>, log: Logger) -> impl Future<Item = (), Error = GeneralError> {
+    info!(log, "carbon backend sending metrics");
+    let elog = log.clone();
+    let conn = StallWatch::new(conn, "carbon", StallOptions::current(), log.clone());
+    let writer = CarbonCodec::new().framed(conn);
+    let metric_stream = stream::iter_ok::<_, ()>(SharedIter::new(metrics));
+    metric_stream.map_err(|_| GeneralError::CarbonBackend).forward(writer.sink_map_err(|_| GeneralError::CarbonBackend)).map(move |_| info!(log, "carbon backend finished")).map_err(move |e| {
+        info!(elog, "carbon backend error");
+        e
+    })
+}
+
+/// Rotate worker caches, aggregate the metrics and send them to carbon in a separate thread.
+/// Only metrics starting with `prefix` are taken if it is specified. The thread ends when all chunks are sent or given up on.
+pub fn flush_to_carbon(chans: Vec<Sender<Task>>, prefix: Option<Bytes>, log: Logger) -> Result<thread::JoinHandle<()>, GeneralError> {
+    let ts = SystemTime::now().duration_since(time::UNIX_EPOCH).map_err(|e| GeneralError::Time(e))?;
+    // flush is started at the interval end, so latency is counted from here to every chunk written
+    let started = Instant::now();
+
+    let config = RUNTIME_CONFIG.read().unwrap().clone();
+    let mut backend_opts = config.carbon.clone();
+    if backend_opts.chunks == 0 {
+        backend_opts.chunks = 1
+    }
+    let backend_addr = try_resolve(&backend_opts.address);
+    let backend_tls = if backend_opts.tls { Some(tls_name(&config.tls, &backend_opts.address)?) } else { None };
+    let carbon_log = log.new(o!("source"=>"carbon-flush"));
+
+    thread::Builder::new()
+        .name("bioyino_carbon".into())
+        .spawn(move || {
+            let carbon_log = carbon_log.clone();
+            let runtime_log = carbon_log.clone();
+
+            let mut runtime = match Runtime::new() {
+                Ok(runtime) => runtime,
+                Err(e) => {
+                    error!(carbon_log, "creating runtime for backend"; "error"=>e.to_string());
+                    return;
+                }
+            };
+
+            // a leader in maintenance aggregates, but sends nothing, as followers do
+            let is_leader = IS_LEADER.load(Ordering::SeqCst) && !MAINTENANCE.load(Ordering::SeqCst);
+
+            let mut options = AggregateOptions::new(is_leader, &config.metrics);
+            options.prefix = prefix;
+
+            if is_leader {
+                info!(carbon_log, "leader sending metrics");
+                let mut span = Span::root("flush");
+                let context = span.as_ref().map(Span::context);
+                options.trace = context;
+                let (backend_tx, backend_rx) = mpsc::unbounded();
+                let aggregator = Aggregator::new(options, chans, backend_tx, carbon_log.clone()).into_future();
+
+                runtime.spawn(aggregator);
+
+                let handle = runtime.handle();
+                let carbon_sender = backend_rx
+                    .inspect(|_| {
+                        FLUSH_QUEUE.pop(1);
+                        EGRESS.add(1);
+                    })
+                .collect()
+                    .map(move |metrics: Vec<(Bytes, Float)>| {
+                        count_points(&metrics);
+                        if let Some(ref mut span) = span {
+                            span.attr("metrics", metrics.len());
+                            span.attr("paused", FLUSH_PAUSED.load(Ordering::SeqCst));
+                        }
+                        // when flushing is paused, aggregated metrics are kept with their timestamps
+                        // and sent all together after resuming
+                        let batches = take_intervals(&mut PAUSED_FLUSHES.lock().unwrap(), (ts, metrics), FLUSH_PAUSED.load(Ordering::SeqCst), backend_opts.max_paused_intervals);
+                        if batches.is_empty() {
+                            info!(carbon_log, "flushing is paused, keeping metrics until resumed"; "intervals"=>PAUSED_FLUSHES.lock().unwrap().len());
+                            return;
+                        }
+                        // intervals missed while the server was down go first, they are older
+                        let backfill = take_backfill(true);
+                        let backfill_names = backend_opts.backfill.iter().map(|name| Bytes::from(name.as_bytes())).collect::<HashSet<_>>();
+
+                        let batches = backfill.into_iter().map(|batch| (batch, true)).chain(batches.into_iter().map(|batch| (batch, false)));
+                        for ((ts, metrics), backfilled) in batches {
+                            let carbon_log = carbon_log.clone();
+                            let backend_opts = backend_opts.clone();
+                            let backend_tls = backend_tls.clone();
+                            // the interval is marked as flushed when the last of it's chunks is sent, if none has failed,
+                            // backfilled points are not taken as last values
+                            let values = if backfilled { BTreeMap::new() } else { last_values(&metrics, &backfill_names) };
+                            let mark = backend_opts.flush_mark_file.clone().map(|path| (path, Arc::new(Mutex::new(Some(values)))));
+                            let unsent = Arc::new(AtomicUsize::new(0));
+                            if metrics.len() == 0 {
+                                mark_interval(&mark, ts, &carbon_log);
+                            }
+                            // chunk size cannot be zero, which happens when there is less metrics than chunks
+                            let chunk_size = ::std::cmp::max(metrics.len() / backend_opts.chunks, 1);
+                            // TODO we could do this without allocations
+                            // but in rust it's not so easy with these types
+                            // probably Pin API would help
+                            // probably changing to Arc<[Metric]> would
+                            metrics
+                                .chunks(chunk_size)
+                                .map(move |metrics| {
+                                    let queued = metrics_size(metrics);
+                                    BACKEND_QUEUE_BYTES.fetch_add(queued, Ordering::Relaxed);
+                                    let options = CarbonClientOptions { addr: backend_addr, bind: backend_opts.bind_address, tls: backend_tls.clone() };
+                                    let chunk = Arc::new(metrics.to_vec());
+                                    let backend = CarbonBackend::new(options, ts, chunk.clone(), carbon_log.clone());
+                                    let retrier = BackoffRetryBuilder { delay: backend_opts.connect_delay, delay_mul: backend_opts.connect_delay_multiplier, delay_max: backend_opts.connect_delay_max, retries: backend_opts.send_retries };
+                                    let carbon_log = carbon_log.clone();
+                                    let spool = backend_opts.spool_file.clone().map(|path| (path, backend_opts.spool_max_size));
+                                    let sent = metrics.len();
+                                    unsent.fetch_add(1, Ordering::SeqCst);
+                                    let (unsent, failed_unsent) = (unsent.clone(), unsent.clone());
+                                    let (mark, mark_log) = (mark.clone(), carbon_log.clone());
+                                    let failed_mark = mark.clone();
+                                    let mut chunk_span = Span::child_of(context, "flush-chunk");
+                                    if let Some(ref mut chunk_span) = chunk_span {
+                                        chunk_span.attr("metrics", metrics.len());
+                                    }
+                                    let retrier = retrier
+                                        .spawn(backend)
+                                        .then(move |result| {
+                                            // the chunk is traced until it is sent or given up on
+                                            if let (Some(chunk_span), Err(_)) = (chunk_span.as_mut(), result.as_ref()) {
+                                                chunk_span.attr("error", "gave up");
+                                            }
+                                            drop(chunk_span);
+                                            result
+                                        })
+                                        .map(move |_| {
+                                            BACKEND_QUEUE_BYTES.fetch_sub(queued, Ordering::Relaxed);
+                                            if !BACKEND_OK.swap(true, Ordering::Relaxed) {
+                                                event("backend", "carbon backend recovered".to_string());
+                                            }
+                                            backend_sent(true);
+                                            CARBON_BACKEND.metrics.add(sent);
+                                            CARBON_BACKEND.chunks.add(1);
+                                            FLUSH_LATENCY.record(started.elapsed());
+                                            if unsent.fetch_sub(1, Ordering::SeqCst) == 1 {
+                                                mark_interval(&mark, ts, &mark_log);
+                                            }
+                                        })
+                                        .map_err(move |e| {
+                                            BACKEND_QUEUE_BYTES.fetch_sub(queued, Ordering::Relaxed);
+                                            if BACKEND_OK.swap(false, Ordering::Relaxed) {
+                                                event("backend", format!("sending to carbon backend failed: {:?}", e));
+                                            }
+                                            backend_sent(false);
+                                            CARBON_BACKEND.errors.add(1);
+                                            error!(carbon_log.clone(), "Failed to send to graphite"; "error"=>format!("{:?}",e));
+                                            // the interval is not complete, so it is not marked
+                                            if let Some((_, ref values)) = failed_mark {
+                                                values.lock().unwrap().take();
+                                            }
+                                            failed_unsent.fetch_sub(1, Ordering::SeqCst);
+                                            // replayed on the next start
+                                            if let Some((path, max_size)) = spool {
+                                                match spool_metrics(&path, max_size, ts, &chunk) {
+                                                    Ok(spooled) => info!(carbon_log, "metrics given up on are spooled"; "file"=>&path, "spooled"=>spooled),
+                                                    Err(e) => error!(carbon_log, "spooling metrics failed"; "error"=>e.to_string()),
+                                                }
+                                            }
+                                        });
+                                    spawn(retrier);
+                                })
+                            .last();
+                        }
+                    });
+
+                handle.spawn(carbon_sender).unwrap_or_else(|e| {
+                    error!(runtime_log, "spawning sender"; "error"=>format!("{:?}", e));
+                });
+                runtime.run().unwrap_or_else(|e| {
+                    error!(runtime_log, "Failed to send to graphite"; "error"=>format!("{:?}", e));
+                });
+                // runtime.block_on(backend).unwrap_or_else(|e| {
+                //error!(carbon_log, "Failed to send to graphite"; "error"=>e);
+                // });
+            } else {
+                info!(carbon_log, "not leader, removing metrics");
+                let (backend_tx, _) = mpsc::unbounded();
+                let aggregator = Aggregator::new(options, chans, backend_tx, carbon_log.clone()).into_future();
+                runtime.block_on(aggregator.then(|_| Ok::<(), ()>(()))).unwrap_or_else(|e| error!(carbon_log, "Failed to join aggregated metrics"; "error"=>e));
+                // the leader has sent missed intervals or they are not missed, and the interval is flushed by it,
+                // so after restart only the time this node was down counts as a gap
+                take_backfill(false);
+                if let Some(path) = config.carbon.flush_mark_file.clone() {
+                    mark_interval(&Some((path, Arc::new(Mutex::new(Some(BTreeMap::new()))))), ts, &carbon_log);
+                }
+            }
+        })
+        .map_err(GeneralError::Io)
+}
+
 pub struct SharedIter<T> {
     inner: Arc<Vec<T>>,
     current: usize,
@@ -143,3 +416,35 @@ impl Encoder for CarbonCodec {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paused_intervals() {
+        let interval = |secs, names: &[&str]| (Duration::from_secs(secs), names.iter().map(|name| (Bytes::from(*name), 1f64)).collect::<Vec<_>>());
+        let mut queue = VecDeque::new();
+        assert_eq!(take_intervals(&mut queue, interval(10, &["a"]), true, 2), Vec::new());
+        assert_eq!(take_intervals(&mut queue, interval(20, &["b", "c"]), true, 2), Vec::new());
+        // the oldest interval is dropped when too many are kept
+        let drops = DROPS.get();
+        assert_eq!(take_intervals(&mut queue, interval(30, &["d"]), true, 2), Vec::new());
+        assert!(DROPS.get() >= drops + 1);
+        assert_eq!(queue.len(), 2);
+
+        // after resuming kept intervals are sent in order before the new one
+        let sent = take_intervals(&mut queue, interval(40, &["e"]), false, 2);
+        assert_eq!(sent.iter().map(|(ts, _)| ts.as_secs()).collect::<Vec<_>>(), vec![20, 30, 40]);
+        assert_eq!(sent[0].1.len(), 2);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn flush_phase() {
+        assert_eq!(first_flush_delay(61_000, 30000, 5000), 4000);
+        assert_eq!(first_flush_delay(66_000, 30000, 5000), 29000);
+        assert_eq!(first_flush_delay(65_000, 30000, 5000), 30000);
+        assert_eq!(first_flush_delay(65_000, 30000, 0), 25000);
+    }
+}
@@ -0,0 +1,636 @@
+use std::collections::HashSet;
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+use slog::Level;
+
+use crate::acl::Cidr;
+use crate::auth::{client_tls_config, tls_config, tls_name};
+use crate::config::{FlushOffset, LogTarget, StandbyRole, System};
+use crate::daemon::parse_umask;
+use crate::errors::GeneralError;
+use crate::incident::sentry_target;
+use crate::logdrain::{facility, JOURNALD_SOCKET};
+use crate::privileges::{capability_mask, resolve_group, resolve_user};
+use crate::quota::validate_quotas;
+use crate::rules::{RelabelAction, Rules};
+use crate::util::{get_hostname, resolve_addr, resolve_cpus};
+use crate::windows::validate_window;
+use crate::ConsensusKind;
+
+/// Problems found in configuration. Errors would make server fail or work wrong,
+/// warnings are settings that work, but are most probably not what was intended.
+#[derive(Debug, Default, PartialEq)]
+pub struct CheckReport {
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+impl CheckReport {
+    fn error(&mut self, message: String) {
+        self.errors.push(message)
+    }
+
+    fn warn(&mut self, message: String) {
+        self.warnings.push(message)
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.errors.len() == 0
+    }
+}
+
+/// Check configuration without starting anything, only name resolution is done over network
+pub fn check_config(system: &System) -> CheckReport {
+    let mut report = CheckReport { errors: Vec::new(), warnings: system.migration_warnings.clone() };
+    check_addresses(system, &mut report);
+    check_ports(system, &mut report);
+    check_privileges(system, &mut report);
+    check_sandbox(system, &mut report);
+    check_sockets(system, &mut report);
+    check_intervals(system, &mut report);
+    check_rules(system, &mut report);
+    check_management(system, &mut report);
+    report
+}
+
+// the address internal raft listens on, the same way raft module finds it
+fn raft_address(system: &System) -> Result<SocketAddr, GeneralError> {
+    match system.raft.this_node {
+        Some(ref node) => resolve_addr(node),
+        None => resolve_addr(&(get_hostname().ok_or(GeneralError::Configuration("cannot get hostname"))? + ":8138")),
+    }
+}
+
+fn check_addresses(system: &System, report: &mut CheckReport) {
+    if Level::from_str(&system.verbosity).is_err() {
+        report.error(format!("verbosity: unknown logging level {:?}", system.verbosity));
+    }
+    if let Err(e) = resolve_addr(&system.carbon.address) {
+        report.error(format!("carbon.address: {}", e));
+    }
+    for node in &system.network.nodes {
+        if let Err(e) = resolve_addr(node) {
+            report.error(format!("network.nodes: {}", e));
+        }
+    }
+
+    if system.consensus != ConsensusKind::Internal {
+        return;
+    }
+    if let Err(e) = raft_address(system) {
+        report.error(format!("raft.this-node: {}", e));
+    }
+    if system.raft.nodes.len() < 3 {
+        report.warn("raft.nodes: internal raft requires at least 3 nodes".to_string());
+    }
+    let mut ids = HashSet::new();
+    for (node, id) in &system.raft.nodes {
+        if let Err(e) = resolve_addr(node) {
+            report.error(format!("raft.nodes: {}", e));
+        }
+        if !ids.insert(id) {
+            report.error(format!("raft.nodes: id {} is used by more than one node", id));
+        }
+    }
+}
+
+// sockets of different protocols may share a port, sockets of the same one conflict
+// if their IPs are the same or any of them listens on all addresses
+fn conflicts(a: &SocketAddr, b: &SocketAddr) -> bool {
+    a.port() == b.port() && (a.ip() == b.ip() || a.ip().is_unspecified() || b.ip().is_unspecified())
+}
+
+fn check_ports(system: &System, report: &mut CheckReport) {
+    let network = &system.network;
+    let mut listeners = vec![("network.listen", "udp", network.listen), ("network.peer-listen", "tcp", network.peer_listen), ("network.mgmt-listen", "tcp", network.mgmt_listen)];
+    if system.consensus == ConsensusKind::Internal {
+        if let Ok(addr) = raft_address(system) {
+            listeners.push(("raft.this-node", "tcp", addr));
+        }
+    }
+    for (idx, (name, proto, addr)) in listeners.iter().enumerate() {
+        for (other_name, other_proto, other_addr) in listeners.iter().skip(idx + 1) {
+            if proto == other_proto && conflicts(addr, other_addr) {
+                report.error(format!("{} and {} both listen on {} port {}", name, other_name, proto, addr.port()));
+            }
+        }
+    }
+    if network.slow_connection_kill > 0 && network.slow_connection_kill < network.slow_connection {
+        report.warn(format!("network.slow-connection-kill: {}ms is less than slow-connection, connections are closed without being reported as slow first", network.slow_connection_kill));
+    }
+    let rules = [("statsd-allow", &network.statsd_allow), ("statsd-deny", &network.statsd_deny), ("peer-allow", &network.peer_allow), ("peer-deny", &network.peer_deny)];
+    for (name, networks) in rules.iter() {
+        for network in networks.iter() {
+            if let Err(e) = network.parse::<Cidr>() {
+                report.error(format!("network.{}: {}", name, e));
+            }
+        }
+    }
+    if network.statsd_hmac_key.as_ref().map(|key| key.len() == 0).unwrap_or(false) {
+        report.error("network.statsd-hmac-key: key cannot be empty".to_string());
+    } else if network.statsd_hmac_key.as_ref().map(|key| key.len() < 16).unwrap_or(false) {
+        report.warn("network.statsd-hmac-key: key is shorter than 16 bytes and may be guessed".to_string());
+    }
+    if network.statsd_hmac_key.is_some() && network.statsd_hmac_max_age < 1000 {
+        report.warn(format!("network.statsd-hmac-max-age: {}ms is less than timestamp precision, most packets will be dropped as expired", network.statsd_hmac_max_age));
+    }
+    if (network.statsd_rate_limit > 0 || network.peer_rate_limit > 0) && network.rate_limit_burst < 100 {
+        report.warn(format!("network.rate-limit-burst: {}ms leaves no room for bursts, metrics sent in batches will be dropped even below the rate", network.rate_limit_burst));
+    }
+    if let Some(ref sibling) = network.maintenance_forward {
+        match resolve_addr(sibling) {
+            Ok(addr) if addr == network.listen => report.error(format!("network.maintenance-forward: {} is this node's own statsd address", sibling)),
+            Ok(_) => (),
+            Err(e) => report.warn(format!("network.maintenance-forward: {}, maintenance cannot be started until it resolves", e)),
+        }
+    }
+}
+
+fn check_privileges(system: &System, report: &mut CheckReport) {
+    let privileges = &system.privileges;
+    if let Some(ref user) = privileges.user {
+        if let Err(e) = resolve_user(user) {
+            report.error(format!("privileges.user: {}", e));
+        }
+    }
+    if let Some(ref group) = privileges.group {
+        if let Err(e) = resolve_group(group) {
+            report.error(format!("privileges.group: {}", e));
+        }
+    }
+    if let Err(e) = capability_mask(&privileges.keep_capabilities) {
+        report.error(format!("privileges.keep-capabilities: {}", e));
+    }
+    // listeners are bound before dropping privileges, so ports below 1024 need no capabilities
+    if privileges.user.is_none() && privileges.group.is_none() && privileges.keep_capabilities.len() > 0 {
+        report.warn("privileges.keep-capabilities: user and group are not set, so privileges are not dropped".to_string());
+    }
+}
+
+fn check_sandbox(system: &System, report: &mut CheckReport) {
+    let sandbox = &system.sandbox;
+    if !sandbox.enabled {
+        return;
+    }
+    if !cfg!(any(target_arch = "x86_64", target_arch = "aarch64")) {
+        report.error("sandbox.enabled: sandboxing is only supported on x86_64 and aarch64".to_string());
+    }
+    for (name, paths) in [("read-paths", &sandbox.read_paths), ("write-paths", &sandbox.write_paths)].iter() {
+        for path in paths.iter() {
+            if !Path::new(path).exists() {
+                report.error(format!("sandbox.{}: {} does not exist", name, path));
+            }
+        }
+    }
+}
+
+fn check_sockets(system: &System, report: &mut CheckReport) {
+    let network = &system.network;
+    if !network.socket_autotune {
+        return;
+    }
+    if network.recv_buffer > network.max_recv_buffer {
+        report.warn("network.max-recv-buffer: less than recv-buffer, autotune will never grow the buffer".to_string());
+    }
+    if network.multimessage && network.max_mm_packets < network.mm_packets {
+        report.warn("network.max-mm-packets: less than mm-packets, autotune will never grow the batch".to_string());
+    }
+}
+
+fn check_intervals(system: &System, report: &mut CheckReport) {
+    let carbon = &system.carbon;
+    if carbon.interval == 0 {
+        report.error("carbon.interval: must be positive".to_string());
+    }
+    if carbon.chunks == 0 {
+        report.error("carbon.chunks: must be positive".to_string());
+    }
+    if let Some(FlushOffset::Fixed(offset)) = carbon.flush_offset {
+        if offset >= carbon.interval && carbon.interval > 0 {
+            report.warn(format!("carbon.flush-offset: {}ms is not less than interval {}ms, {}ms will be used", offset, carbon.interval, offset % carbon.interval));
+        }
+    }
+    let memory = &system.memory;
+    if !(memory.shrink_ratio >= 0f32 && memory.shrink_ratio < 1f32) {
+        report.error(format!("memory.shrink-ratio: {} must be from 0 to 1", memory.shrink_ratio));
+    }
+    if !(memory.limit_ratio >= 0f32 && memory.limit_ratio <= 1f32) {
+        report.error(format!("memory.limit-ratio: {} must be from 0 to 1", memory.limit_ratio));
+    }
+    // budget may be taken from cgroup memory limit of the host the server is started on
+    if memory.budget > 0 || memory.limit_ratio > 0f32 {
+        if !(memory.cap_samples_ratio <= memory.shed_ratio && memory.shed_ratio <= memory.flush_ratio) {
+            report.error(format!("memory: ratios must grow from cap-samples-ratio {} to shed-ratio {} to flush-ratio {}", memory.cap_samples_ratio, memory.shed_ratio, memory.flush_ratio));
+        }
+        if memory.timer_sample_cap == 0 {
+            report.error("memory.timer-sample-cap: must be positive".to_string());
+        }
+        if memory.check_interval >= carbon.interval && carbon.interval > 0 {
+            report.warn(format!("memory.check-interval: {}ms is not less than carbon.interval {}ms, budget will be exceeded before it is noticed", memory.check_interval, carbon.interval));
+        }
+    }
+    if system.shutdown.graceful && system.shutdown.timeout <= carbon.connect_delay {
+        report.warn(format!("shutdown.timeout: {}ms is not more than carbon.connect-delay {}ms, the last flush will be lost if backend needs a reconnect", system.shutdown.timeout, carbon.connect_delay));
+    }
+    if let Some(ref path) = system.shutdown.state_file {
+        if !system.shutdown.graceful {
+            report.warn("shutdown.state-file: caches are only saved on graceful shutdown, the file will never be written".to_string());
+        }
+    }
+    if let Some(ref path) = carbon.spool_file {
+        let dir = Path::new(path).parent().filter(|dir| dir.as_os_str().len() > 0).unwrap_or(Path::new("."));
+        if !dir.is_dir() {
+            report.error(format!("carbon.spool-file: directory {} does not exist", dir.display()));
+        }
+        if carbon.spool_max_age < carbon.interval {
+            report.warn(format!("carbon.spool-max-age: {}ms is less than carbon.interval {}ms, nothing spooled will be replayed", carbon.spool_max_age, carbon.interval));
+        }
+    }
+    if let Some(ref path) = carbon.flush_mark_file {
+        let dir = Path::new(path).parent().filter(|dir| dir.as_os_str().len() > 0).unwrap_or(Path::new("."));
+        if !dir.is_dir() {
+            report.error(format!("carbon.flush-mark-file: directory {} does not exist", dir.display()));
+        }
+    } else if carbon.backfill.len() > 0 {
+        report.warn("carbon.backfill: missed intervals are only found with carbon.flush-mark-file, nothing will be backfilled".to_string());
+    }
+    if carbon.backfill.len() > 0 && carbon.backfill_max_gap < carbon.interval {
+        report.warn(format!("carbon.backfill-max-gap: {}ms is less than carbon.interval {}ms, nothing will be backfilled", carbon.backfill_max_gap, carbon.interval));
+    }
+    let paths = [("state-file", &system.shutdown.state_file), ("handoff-socket", &system.shutdown.handoff_socket)];
+    for (name, path) in paths.iter() {
+        if let Some(ref path) = path {
+            let dir = Path::new(path).parent().filter(|dir| dir.as_os_str().len() > 0).unwrap_or(Path::new("."));
+            if !dir.is_dir() {
+                report.error(format!("shutdown.{}: directory {} does not exist", name, dir.display()));
+            }
+        }
+    }
+    let startup = &system.startup;
+    if startup.wait {
+        if startup.retry_delay >= startup.timeout {
+            report.warn(format!("startup.retry-delay: {}ms is not less than startup.timeout {}ms, dependencies are checked only once", startup.retry_delay, startup.timeout));
+        }
+        if startup.retry_delay_max < startup.retry_delay {
+            report.warn(format!("startup.retry-delay-max: {}ms is less than startup.retry-delay {}ms, checks are repeated every {}ms", startup.retry_delay_max, startup.retry_delay, startup.retry_delay_max));
+        }
+    }
+    let shards = &system.shards;
+    if shards.count == 0 {
+        report.error("shards.count: must be positive".to_string());
+    } else if shards.index >= shards.count {
+        report.error(format!("shards.index: {} must be less than shards.count {}", shards.index, shards.count));
+    } else if shards.count > 1 {
+        let span = (shards.count - 1) * shards.port_step as usize;
+        let (peer, mgmt) = (system.network.peer_listen.port() as usize, system.network.mgmt_listen.port() as usize);
+        if peer.max(mgmt) + span > u16::max_value() as usize {
+            report.error(format!("shards.port-step: ports of the last of {} shards are over 65535", shards.count));
+        }
+        let distance = peer.max(mgmt) - peer.min(mgmt);
+        if shards.port_step == 0 || (distance <= span && distance % shards.port_step as usize == 0) {
+            report.error(format!("shards.port-step: peer port of some shard is the management port of another one with step {}", shards.port_step));
+        }
+        if system.shutdown.handoff_socket.is_some() {
+            report.warn("shutdown.handoff-socket: only the first shard hands off on SIGWINCH, the others are stopped and started again".to_string());
+        }
+    }
+    let daemon = &system.daemon;
+    if let Some(ref umask) = daemon.umask {
+        if let Err(e) = parse_umask(umask) {
+            report.error(format!("daemon.umask: {}", e));
+        }
+    }
+    if let Some(ref dir) = daemon.work_dir {
+        if !Path::new(dir).is_dir() {
+            report.error(format!("daemon.work-dir: directory {} does not exist", dir));
+        }
+    }
+    if let Some(ref path) = daemon.pid_file {
+        let dir = Path::new(path).parent().filter(|dir| dir.as_os_str().len() > 0).unwrap_or(Path::new("."));
+        if !dir.is_dir() {
+            report.error(format!("daemon.pid-file: directory {} does not exist", dir.display()));
+        }
+    }
+    if daemon.detach && system.log.target() == LogTarget::Terminal {
+        report.warn("daemon.detach: log is written to terminal, which is closed when detached, so it is lost".to_string());
+    }
+    let mut window_names = HashSet::new();
+    for window in &system.windows {
+        if !window_names.insert(&window.name) {
+            report.error(format!("windows: name {:?} is used more than once", window.name));
+        }
+        if let Err(e) = validate_window(window) {
+            report.error(format!("windows: window {:?}: {}", window.name, e));
+        } else if window.set.len() == 0 {
+            report.warn(format!("windows: window {:?} changes no options", window.name));
+        }
+    }
+    match system.log.target() {
+        LogTarget::File if system.log.file.is_none() => report.error("log.target: file target needs log.file to be set".to_string()),
+        LogTarget::Syslog => {
+            if facility(&system.log.syslog_facility).is_none() {
+                report.error(format!("log.syslog-facility: unknown facility {}", system.log.syslog_facility));
+            }
+            if !system.log.syslog_address.starts_with('/') && system.log.syslog_address.parse::<SocketAddr>().is_err() {
+                report.error(format!("log.syslog-address: {} is neither a socket path nor IP:port", system.log.syslog_address));
+            }
+        }
+        LogTarget::Journald if !Path::new(JOURNALD_SOCKET).exists() => report.warn(format!("log.target: journald socket {} does not exist", JOURNALD_SOCKET)),
+        _ => (),
+    }
+    if let Some(ref file) = system.log.file {
+        match Path::new(file).parent() {
+            Some(dir) if dir.as_os_str().len() > 0 && !dir.is_dir() => report.error(format!("log.file: directory {} does not exist", dir.display())),
+            _ => (),
+        }
+        if system.log.max_size == 0 && system.log.rotate_interval == 0 {
+            report.warn(format!("log.file: {} is never rotated, set log.max-size or log.rotate-interval or rotate it by logrotate sending SIGUSR2", file));
+        }
+    }
+    if system.log.rate_limit > 0 && system.log.rate_limit_interval == 0 {
+        report.error("log.rate-limit-interval: must be more than 0 when rate-limit is set".to_string());
+    }
+    if let Some(ref endpoint) = system.tracing.endpoint {
+        if !endpoint.starts_with("http://") || endpoint.parse::<hyper::Uri>().is_err() {
+            report.error(format!("tracing.endpoint: {} is not an http:// URL", endpoint));
+        }
+        if !(system.tracing.sample_ratio > 0f64 && system.tracing.sample_ratio <= 1f64) {
+            report.error(format!("tracing.sample-ratio: {} must be more than 0 and up to 1", system.tracing.sample_ratio));
+        }
+    }
+    if let Some(ref dsn) = system.incidents.sentry_dsn {
+        if sentry_target(dsn).is_none() {
+            report.error(format!("incidents.sentry-dsn: {} is not a DSN like https://<key>@<host>/<project>", dsn));
+        }
+    }
+    if let Some(ref webhook) = system.incidents.webhook {
+        if !(webhook.starts_with("http://") || webhook.starts_with("https://")) || webhook.parse::<hyper::Uri>().is_err() {
+            report.error(format!("incidents.webhook: {} is not an http(s) URL", webhook));
+        }
+    }
+    if system.incidents.consensus_lost > 0 && system.consensus != ConsensusKind::None && system.incidents.consensus_lost < system.incidents.check_interval {
+        report.warn(format!("incidents.consensus-lost: {}ms is shorter than check-interval {}ms", system.incidents.consensus_lost, system.incidents.check_interval));
+    }
+    if system.supervision.restart_delay > system.supervision.restart_delay_max {
+        report.warn(format!("supervision.restart-delay: {}ms is bigger than restart-delay-max {}ms", system.supervision.restart_delay, system.supervision.restart_delay_max));
+    }
+    let health = &system.health;
+    if !(health.degraded_score >= 0f32 && health.degraded_score <= 100f32) {
+        report.error(format!("health.degraded-score: {} must be from 0 to 100", health.degraded_score));
+    }
+    if !(health.queue_ratio > 0f32 && health.queue_ratio <= 1f32) || !(health.drop_ratio > 0f32 && health.drop_ratio <= 1f32) {
+        report.error(format!("health: queue-ratio {} and drop-ratio {} must be above 0 and not above 1", health.queue_ratio, health.drop_ratio));
+    }
+    if system.accounting.enabled && (system.accounting.depth == 0 || system.accounting.max_prefixes == 0) {
+        report.error(format!("accounting: depth {} and max-prefixes {} must be positive", system.accounting.depth, system.accounting.max_prefixes));
+    }
+    if let Err(e) = validate_quotas(&system.accounting.quotas) {
+        report.error(format!("accounting.quotas: {}", e));
+    }
+    if carbon.connect_delay > carbon.connect_delay_max {
+        report.warn(format!("carbon.connect-delay: {}ms is bigger than connect-delay-max {}ms", carbon.connect_delay, carbon.connect_delay_max));
+    }
+
+    let network = &system.network;
+    if network.snapshot_interval == 0 {
+        report.error("network.snapshot-interval: must be positive".to_string());
+    } else if network.nodes.len() > 0 && network.snapshot_interval as u64 >= carbon.interval {
+        report.warn(format!("network.snapshot-interval: {}ms is not less than carbon.interval {}ms, snapshots from peers will miss flushes", network.snapshot_interval, carbon.interval));
+    }
+    if system.task_queue_autotune && system.task_queue_max_size < system.task_queue_size {
+        report.warn(format!("task-queue-max-size: {} is less than task-queue-size {}, queue sizes will not be tuned", system.task_queue_max_size, system.task_queue_size));
+    }
+    if network.batch_size == 0 {
+        report.error("network.batch-size: must be positive".to_string());
+    }
+    if network.buffer_flush_time >= carbon.interval && carbon.interval > 0 {
+        report.warn(format!("network.buffer-flush-time: {}ms is not less than carbon.interval {}ms, metrics will be flushed in later intervals", network.buffer_flush_time, carbon.interval));
+    }
+    if system.network_threads_ratio <= 0f32 || system.network_threads_ratio >= 1f32 {
+        report.warn(format!("network-threads-ratio: {} is not between 0 and 1, one of thread kinds will get a single thread in auto mode", system.network_threads_ratio));
+    }
+    for (option, spec) in &[("network-cpus", &system.network_cpus), ("counting-cpus", &system.counting_cpus)] {
+        if let Some(spec) = spec {
+            if let Err(e) = resolve_cpus(spec) {
+                report.error(format!("{}: {}", option, e));
+            }
+        }
+    }
+    if system.stats_interval > 0 && system.stats_interval < 100 {
+        report.warn(format!("stats-interval: {}ms is too small, 1000ms will be used", system.stats_interval));
+    }
+    for name in system.stats_tags.keys().chain(system.stats_node_tag.iter()) {
+        if name.len() == 0 || name.contains(|c| c == ';' || c == '=' || c == '!' || c == '^') {
+            report.error(format!("stats-tags: bad tag name {:?}", name));
+        }
+    }
+
+    match system.consensus {
+        ConsensusKind::Consul => {
+            let consul = &system.consul;
+            if consul.renew_time >= consul.session_ttl {
+                report.error(format!("consul.renew-time: {}ms is not less than session-ttl {}ms, session will expire before renewal", consul.renew_time, consul.session_ttl));
+            }
+            if consul.session_ttl < 10000 {
+                report.warn(format!("consul.session-ttl: {}ms is less than 10s, consul will use 10s instead", consul.session_ttl));
+            }
+        }
+        ConsensusKind::Internal => {
+            let raft = &system.raft;
+            if raft.election_timeout_min >= raft.election_timeout_max {
+                report.error(format!("raft.election-timeout-min: {}ms must be less than election-timeout-max {}ms", raft.election_timeout_min, raft.election_timeout_max));
+            }
+            if raft.heartbeat_timeout >= raft.election_timeout_min {
+                report.error(format!("raft.heartbeat-timeout: {}ms must be less than election-timeout-min {}ms", raft.heartbeat_timeout, raft.election_timeout_min));
+            }
+        }
+        ConsensusKind::Standby => {
+            let standby = &system.standby;
+            match (&standby.role, &standby.primary) {
+                (StandbyRole::Standby, None) => report.error("standby.primary: must be set on the standby".to_string()),
+                (StandbyRole::Standby, Some(primary)) => {
+                    if let Err(e) = resolve_addr(primary) {
+                        report.error(format!("standby.primary: {}", e));
+                    }
+                }
+                // the standby only knows the primary is alive by it's snapshots
+                (StandbyRole::Primary, _) if system.network.nodes.len() == 0 => report.error("network.nodes: primary must send snapshots to the standby".to_string()),
+                (StandbyRole::Primary, _) => (),
+            }
+            if standby.failover_timeout < system.network.snapshot_interval as u64 * 3 {
+                report.warn(format!("standby.failover-timeout: {}ms is less than 3 snapshot intervals, a snapshot or two lost makes the standby take over", standby.failover_timeout));
+            }
+        }
+        ConsensusKind::None => (),
+    }
+}
+
+// find mistakes in rules, patterns can be any strings, so only logical errors are found
+fn rules_problems(rules: &Rules, report: &mut CheckReport) {
+    for pattern in &rules.block {
+        if pattern.len() == 0 {
+            report.error("rules: empty block pattern".to_string());
+        } else if pattern.contains(char::is_whitespace) {
+            report.error(format!("rules: block pattern {:?} contains whitespace and will never match", pattern));
+        } else if pattern.chars().all(|c| c == '*') {
+            report.warn(format!("rules: block pattern {:?} blocks all metrics", pattern));
+        }
+    }
+    for (idx, rule) in rules.rewrite.iter().enumerate() {
+        if rule.prefix.len() == 0 {
+            report.warn("rules: rewrite rule with empty prefix is applied to all metrics".to_string());
+        }
+        // the first matching rule is applied, so rules with longer prefixes after shorter ones never work
+        if let Some(earlier) = rules.rewrite[..idx].iter().find(|earlier| rule.prefix.starts_with(&earlier.prefix)) {
+            report.warn(format!("rules: rewrite of prefix {:?} is never applied because of earlier rule for {:?}", rule.prefix, earlier.prefix));
+        }
+    }
+    for rule in &rules.block_tagged {
+        if rule.tags.keys().any(|tag| tag.len() == 0 || tag.contains(|c: char| c == ';' || c == '=' || c.is_whitespace())) {
+            report.error(format!("rules: block-tagged rule for {:?} has a tag name that never appears in metrics", rule.name));
+        } else if rule.tags.len() == 0 && rule.name.chars().all(|c| c == '*') {
+            report.warn("rules: block-tagged rule without tags and name pattern blocks all metrics".to_string());
+        }
+    }
+    for (idx, rule) in rules.relabel.iter().enumerate() {
+        if let Err(e) = rule.validate() {
+            report.error(format!("rules: relabel rule {}: {}", idx + 1, e));
+        } else if rule.action == RelabelAction::Map && rule.map.len() == 0 {
+            report.warn(format!("rules: relabel rule {} maps nothing", idx + 1));
+        }
+    }
+    if rules.max_names == Some(0) {
+        report.warn("rules: max-names is 0, all metrics will be dropped".to_string());
+    }
+}
+
+fn check_rules(system: &System, report: &mut CheckReport) {
+    let path = match system.metrics.rules_file {
+        Some(ref path) => path,
+        None => return,
+    };
+    match Rules::from_file(path) {
+        Ok(rules) => rules_problems(&rules, report),
+        Err(GeneralError::Io(ref e)) if e.kind() == ErrorKind::NotFound => report.warn(format!("metrics.rules-file: {} does not exist, it will be created when rules are persisted", path)),
+        Err(e) => report.error(format!("metrics.rules-file: {}: {}", path, e)),
+    }
+}
+
+fn check_management(system: &System, report: &mut CheckReport) {
+    let management = &system.management;
+    match tls_config(&system.tls) {
+        Ok(None) if system.network.peer_tls => report.error("network.peer-tls: tls.cert and tls.key must be set".to_string()),
+        Ok(_) => (),
+        Err(e) => report.error(format!("tls: {}", e)),
+    }
+    if system.network.peer_tls || system.carbon.tls {
+        if let Err(e) = client_tls_config(&system.tls) {
+            report.error(format!("tls: {}", e));
+        }
+    }
+    if system.network.peer_tls {
+        for node in &system.network.nodes {
+            if let Err(e) = tls_name(&system.tls, node) {
+                report.error(format!("network.nodes: {}", e));
+            }
+        }
+    }
+    if system.carbon.tls {
+        if let Err(e) = tls_name(&system.tls, &system.carbon.address) {
+            report.error(format!("carbon.address: {}", e));
+        }
+    }
+    let mut tokens = HashSet::new();
+    for token in &management.tokens {
+        if token.token.len() == 0 {
+            report.error("management.tokens: empty token".to_string());
+        }
+        if !tokens.insert(&token.token) {
+            report.error("management.tokens: the same token is listed more than once".to_string());
+        }
+    }
+    if let Some(ref client) = management.client_token {
+        if management.tokens.len() > 0 && !tokens.contains(client) {
+            report.warn("management.client-token: token is not in tokens, query subcommand will be rejected by this server".to_string());
+        }
+    }
+    if let Some(ref file) = system.metrics.warmup_dump {
+        if !Path::new(file).is_file() {
+            report.warn(format!("metrics.warmup-dump: {} does not exist, server will start without names", file));
+        }
+    }
+    if let Some(ref dir) = management.dump_dir {
+        if !Path::new(dir).is_dir() {
+            report.warn(format!("management.dump-dir: {} is not a directory", dir));
+        }
+    }
+    if let Some(ref file) = management.audit_log {
+        if !Path::new(file).parent().map(|dir| dir.as_os_str().len() == 0 || dir.is_dir()).unwrap_or(true) {
+            report.warn(format!("management.audit-log: directory of {} does not exist", file));
+        }
+    }
+    if let Some(ref file) = management.state_report_file {
+        if !Path::new(file).parent().map(|dir| dir.as_os_str().len() == 0 || dir.is_dir()).unwrap_or(true) {
+            report.warn(format!("management.state-report-file: directory of {} does not exist", file));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rules::{RewriteRule, TagRule};
+
+    #[test]
+    fn peer_tls_checks() {
+        let mut system = System::default();
+        system.network.peer_tls = true;
+        system.network.nodes = vec!["127.0.0.2:8136".to_string()];
+        let report = check_config(&system);
+        assert!(report.errors.contains(&"network.peer-tls: tls.cert and tls.key must be set".to_string()));
+        assert!(report.errors.iter().any(|error| error.starts_with("network.nodes: TLS configuration: 127.0.0.2 cannot be verified")));
+
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/tls");
+        system.tls.cert = Some(format!("{}/cert.pem", dir));
+        system.tls.key = Some(format!("{}/key.pem", dir));
+        system.tls.ca = Some(format!("{}/ca.pem", dir));
+        system.tls.server_name = Some("localhost".to_string());
+        let report = check_config(&system);
+        assert!(!report.errors.iter().any(|error| error.contains("tls")), "{:?}", report.errors);
+    }
+
+    #[test]
+    fn semantic_checks() {
+        let mut system = System::default();
+        system.network.mgmt_listen = "0.0.0.0:8136".parse().unwrap();
+        system.carbon.interval = 1000;
+        system.network.nodes = vec!["127.0.0.2:8136".to_string()];
+        let report = check_config(&system);
+        assert_eq!(report.errors, vec!["network.peer-listen and network.mgmt-listen both listen on tcp port 8136".to_string()]);
+        assert_eq!(report.warnings.len(), 1);
+        assert!(report.warnings[0].starts_with("network.snapshot-interval"));
+
+        system.network.mgmt_listen = "0.0.0.0:8137".parse().unwrap();
+        system.network.socket_autotune = true;
+        system.network.multimessage = true;
+        system.network.max_mm_packets = 10;
+        let report = check_config(&system);
+        assert!(report.errors.is_empty());
+        assert!(report.warnings.iter().any(|warning| warning.starts_with("network.max-mm-packets")));
+
+        let rules = Rules {
+            block: vec!["*".to_string(), "bad pattern".to_string()],
+            max_names: None,
+            rewrite: vec![RewriteRule { prefix: "a.".to_string(), replacement: "b.".to_string() }, RewriteRule { prefix: "a.b.".to_string(), replacement: "c.".to_string() }],
+            block_tagged: vec![TagRule::default()],
+            relabel: Vec::new(),
+        };
+        let mut report = CheckReport::default();
+        rules_problems(&rules, &mut report);
+        assert_eq!(report.errors.len(), 1);
+        assert_eq!(report.warnings.len(), 3);
+    }
+}
@@ -0,0 +1,236 @@
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use lazy_static::lazy_static;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::config::System;
+use crate::tunables::PEER_ALIVE_INTERVALS;
+use crate::util::resolve_addr;
+use crate::{ConsensusKind, ConsensusState, CONSENSUS_REACHABLE, CONSENSUS_STATE, IS_LEADER};
+
+lazy_static! {
+    // snapshot exchange times by peer IP: peers connect from random ports, so only IP is known for incoming snapshots
+    static ref PEERS: Mutex<PeerTable> = { Mutex::new(PeerTable::default()) };
+}
+
+/// Source of current time for cluster logic, so it can be driven by simulated time in tests
+pub trait Clock {
+    /// Milliseconds since UNIX epoch, or since any other fixed moment
+    fn now_ms(&self) -> u64;
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> u64 {
+        now_ms()
+    }
+}
+
+/// Times of the last snapshot exchange with a peer, in milliseconds since UNIX epoch
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct PeerTimes {
+    pub last_sent: Option<u64>,
+    pub last_send_error: Option<u64>,
+    pub last_received: Option<u64>,
+    /// Number of snapshots exchanged since start
+    #[serde(default)]
+    pub sent: usize,
+    #[serde(default)]
+    pub send_errors: usize,
+    #[serde(default)]
+    pub received: usize,
+}
+
+impl PeerTimes {
+    /// Whether snapshots were exchanged with the peer not earlier than `alive_after`
+    pub fn is_alive(&self, alive_after: u64) -> bool {
+        self.last_sent.into_iter().chain(self.last_received).any(|time| time >= alive_after)
+    }
+}
+
+/// Snapshot exchange times of all peers by IP
+#[derive(Debug, Clone, Default)]
+pub struct PeerTable {
+    peers: BTreeMap<IpAddr, PeerTimes>,
+}
+
+impl PeerTable {
+    fn update<F: FnOnce(&mut PeerTimes)>(&mut self, ip: IpAddr, f: F) {
+        f(self.peers.entry(ip).or_insert_with(PeerTimes::default));
+    }
+
+    pub fn sent(&mut self, ip: IpAddr, now: u64) {
+        self.update(ip, |times| {
+            times.last_sent = Some(now);
+            times.sent += 1;
+        });
+    }
+
+    pub fn send_failed(&mut self, ip: IpAddr, now: u64) {
+        self.update(ip, |times| {
+            times.last_send_error = Some(now);
+            times.send_errors += 1;
+        });
+    }
+
+    pub fn received(&mut self, ip: IpAddr, now: u64) {
+        self.update(ip, |times| {
+            times.last_received = Some(now);
+            times.received += 1;
+        });
+    }
+
+    pub fn get(&self, ip: &IpAddr) -> Option<&PeerTimes> {
+        self.peers.get(ip)
+    }
+}
+
+pub fn now_ms() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    now.as_secs() * 1000 + now.subsec_millis() as u64
+}
+
+/// Remember snapshot was sent to peer successfully
+pub fn snapshot_sent(addr: &SocketAddr) {
+    PEERS.lock().unwrap().sent(addr.ip(), SystemClock.now_ms());
+}
+
+/// Remember sending snapshot to peer failed
+pub fn snapshot_send_failed(addr: &SocketAddr) {
+    PEERS.lock().unwrap().send_failed(addr.ip(), SystemClock.now_ms());
+}
+
+/// Remember snapshot was received from peer
+pub fn snapshot_received(addr: &SocketAddr) {
+    PEERS.lock().unwrap().received(addr.ip(), SystemClock.now_ms());
+}
+
+/// Time the last snapshot was received from the IP
+pub fn last_received(ip: &IpAddr) -> Option<u64> {
+    PEERS.lock().unwrap().get(ip).and_then(|times| times.last_received)
+}
+
+/// Snapshot exchange of every peer seen since start
+pub fn peer_times() -> Vec<(IpAddr, PeerTimes)> {
+    PEERS.lock().unwrap().peers.iter().map(|(ip, times)| (*ip, times.clone())).collect()
+}
+
+/// A view of a single peer
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct PeerView {
+    /// Address as specified in configuration
+    pub address: String,
+    /// Address was resolved successfully
+    pub resolved: bool,
+    pub alive: bool,
+    /// Peer is a member of internal Raft cluster
+    pub raft_member: bool,
+    #[serde(flatten)]
+    pub times: PeerTimes,
+}
+
+/// Consensus as seen by this node
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ConsensusView {
+    pub kind: ConsensusKind,
+    pub state: ConsensusState,
+    pub reachable: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ClusterView {
+    pub version: String,
+    /// Role of this node, only leader sends metrics to backend
+    pub role: String,
+    pub consensus: ConsensusView,
+    /// Peers from network.nodes, peer versions and roles are not exchanged in the peer protocol,
+    /// so they should be asked on their own management ports
+    pub peers: Vec<PeerView>,
+    /// Nodes sending snapshots to this one, but not listed in network.nodes
+    pub unknown_senders: BTreeMap<String, PeerTimes>,
+}
+
+/// Join the peer exchange data with consensus state
+pub fn cluster_view(config: &System) -> ClusterView {
+    let peers = PEERS.lock().unwrap().clone();
+    let alive_after = now_ms().saturating_sub(config.network.snapshot_interval as u64 * PEER_ALIVE_INTERVALS.get() as u64);
+    let raft_members = config.raft.nodes.keys().filter_map(|node| resolve_addr(node).ok()).map(|addr| addr.ip()).collect::<Vec<_>>();
+
+    let mut known = Vec::new();
+    let views = config
+        .network
+        .nodes
+        .iter()
+        .map(|node| {
+            let addr = resolve_addr(node).ok();
+            let times = addr.and_then(|addr| peers.get(&addr.ip()).cloned()).unwrap_or_default();
+            let alive = times.is_alive(alive_after);
+            let raft_member = addr.map(|addr| raft_members.contains(&addr.ip())).unwrap_or(false);
+            if let Some(addr) = addr {
+                known.push(addr.ip());
+            }
+            PeerView { address: node.clone(), resolved: addr.is_some(), alive, raft_member, times }
+        })
+        .collect();
+
+    let unknown_senders = peers.peers.into_iter().filter(|(ip, times)| !known.contains(ip) && times.last_received.is_some()).map(|(ip, times)| (ip.to_string(), times)).collect();
+
+    ClusterView {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        role: if IS_LEADER.load(Ordering::SeqCst) { "leader".to_string() } else { "follower".to_string() },
+        consensus: ConsensusView { kind: config.consensus.clone(), state: CONSENSUS_STATE.lock().unwrap().clone(), reachable: CONSENSUS_REACHABLE.load(Ordering::Relaxed) },
+        peers: views,
+        unknown_senders,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peer_liveness() {
+        let mut table = PeerTable::default();
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        table.send_failed(ip, 100);
+        assert!(!table.get(&ip).unwrap().is_alive(0));
+        table.received(ip, 200);
+        table.sent(ip, 300);
+        let times = table.get(&ip).unwrap();
+        assert_eq!((times.sent, times.send_errors, times.received), (1, 1, 1));
+        assert!(times.is_alive(300));
+        assert!(!times.is_alive(301));
+    }
+
+    #[test]
+    fn cluster_peers() {
+        let mut config = System::default();
+        config.network.nodes = vec!["127.0.83.1:8136".to_string(), "127.0.83.2:8136".to_string(), "no port".to_string()];
+        config.raft.nodes.insert("127.0.83.2:8138".to_string(), 2);
+        snapshot_sent(&"127.0.83.1:8136".parse().unwrap());
+        snapshot_send_failed(&"127.0.83.2:8136".parse().unwrap());
+        // incoming snapshots come from random ports
+        snapshot_received(&"127.0.83.3:40000".parse().unwrap());
+
+        let view = cluster_view(&config);
+        assert_eq!(view.peers.len(), 3);
+        assert!(view.peers[0].alive && view.peers[0].resolved && !view.peers[0].raft_member);
+        assert!(!view.peers[1].alive && view.peers[1].raft_member);
+        assert!(view.peers[1].times.last_send_error.is_some());
+        assert_eq!(view.peers[1].times.send_errors, 1);
+        assert!(!view.peers[2].resolved && !view.peers[2].alive);
+        assert!(view.unknown_senders["127.0.83.3"].last_received.is_some());
+        assert_eq!(view.unknown_senders["127.0.83.3"].received, 1);
+        assert!(!view.unknown_senders.contains_key("127.0.83.1"));
+    }
+}
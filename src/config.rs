@@ -1,24 +1,42 @@
-use std::collections::HashMap;
-use std::fs::File;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fmt;
+use std::fs::{self, File};
 use std::io::Read;
 use std::net::SocketAddr;
 use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::time::Duration;
 
 use clap::{app_from_crate, crate_authors, crate_description, crate_name, crate_version, value_t, Arg, SubCommand};
 use toml;
 
+use serde::de::{self, Deserializer, Visitor};
+use serde::ser::Serializer;
 use serde_derive::{Deserialize, Serialize};
 
 use raft_tokio::RaftOptions;
 
 use crate::aggregate::AggregationMode;
-use crate::management::{ConsensusAction, LeaderAction, MgmtCommand};
+use crate::auth::ApiToken;
+use crate::bench::{BenchOptions, TypeMix};
+use crate::ctl::OutputFormat;
+use crate::errors::GeneralError;
+use crate::management::{ConsensusAction, LeaderAction, LeaderCommand, MgmtCommand, PauseTarget};
+use crate::migrate::{migrate, CONFIG_VERSION};
+use crate::rules::{RewriteRule, Rules, RulesChange};
+use crate::shards::apply_shard;
+use crate::units::{duration_ms, parse_duration, parse_size, size_bytes};
+use crate::util::try_resolve;
 use crate::{ConsensusKind, ConsensusState};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
 pub struct System {
+    /// Version of configuration layout, older configurations are migrated with warnings
+    pub config_version: u32,
+
     /// Logging level
     pub verbosity: String,
 
@@ -31,51 +49,238 @@ pub struct System {
     /// Consul settings
     pub consul: Consul,
 
+    /// Hot-standby pair settings
+    pub standby: Standby,
+
     /// Metric settings
     pub metrics: Metrics,
 
     /// Carbon backend settings
     pub carbon: Carbon,
 
-    /// Number of networking threads, use 0 for number of CPUs
-    pub n_threads: usize,
+    /// Management API security settings
+    pub management: Management,
+
+    /// Memory budget settings
+    pub memory: Memory,
+
+    /// Log file settings
+    pub log: Logging,
+
+    /// Tracing of pipeline stages
+    pub tracing: Tracing,
+
+    /// Reporting of panics and repeated failures
+    pub incidents: Incidents,
+
+    /// Restarting of subsystems after panics
+    pub supervision: Supervision,
+
+    /// Health score and degraded mode
+    pub health: Health,
+
+    /// Counting of metric volume per name prefix
+    pub accounting: Accounting,
+
+    /// User to run as after starting as root
+    pub privileges: Privileges,
+
+    /// Restrictions of threads handling untrusted input
+    pub sandbox: Sandbox,
+
+    /// Certificates and TLS settings shared by servers and clients using TLS
+    pub tls: Tls,
+
+    /// Stopping on SIGTERM and SIGINT
+    pub shutdown: Shutdown,
+
+    /// Waiting for dependencies on start
+    pub startup: Startup,
+
+    /// Running as a classic daemon from init scripts
+    pub daemon: Daemon,
 
-    /// Number of aggregating(worker) threads, set to 0 to use all CPU cores
-    pub w_threads: usize,
+    /// Running several independent instances sharing statsd port on one host
+    pub shards: Shards,
+
+    /// Scheduled periods with some of reloadable options changed
+    pub windows: Vec<Window>,
+
+    /// Number of networking threads, use 0 for number of CPUs or "auto" to take a share of CPUs
+    pub n_threads: ThreadCount,
+
+    /// Number of aggregating(worker) threads, set to 0 to use all CPU cores or "auto" to take a share of CPUs
+    pub w_threads: ThreadCount,
+
+    /// Share of available CPUs given to networking threads when thread counts are "auto",
+    /// the rest is given to aggregating threads
+    pub network_threads_ratio: f32,
+
+    /// CPUs to pin networking threads to, either a CPU list like "0-3,8" or a NUMA node like "node0"
+    pub network_cpus: Option<String>,
+
+    /// CPUs to pin aggregating(worker) threads to, the same format as network-cpus
+    pub counting_cpus: Option<String>,
 
     /// queue size for single counting thread before packet is dropped
     pub task_queue_size: usize,
 
+    /// Change queue sizes of counting threads between task-queue-size and task-queue-max-size
+    /// depending on their backlog
+    pub task_queue_autotune: bool,
+
+    /// Maximum queue size for single counting thread when queue sizes are tuned
+    pub task_queue_max_size: usize,
+
     /// Should we start as leader state enabled or not
     pub start_as_leader: bool,
 
     /// How often to gather own stats, in ms. Use 0 to disable (stats are still gathered, but not included in
     /// metric dump)
+    #[serde(deserialize_with = "duration_ms")]
     pub stats_interval: u64,
 
     /// Prefix to send own metrics with
     pub stats_prefix: String,
 
+    /// Graphite tags to send own metrics with
+    pub stats_tags: BTreeMap<String, String>,
+
+    /// Tag to send the name of this node in with own metrics
+    pub stats_node_tag: Option<String>,
+
     /// Consensus kind to use
     pub consensus: ConsensusKind,
+
+    /// Directory with configuration fragments(*.toml, *.yaml, *.yml or *.json files) to merge into this
+    /// configuration in the order of file names, relative to the directory of this file
+    pub include: Option<String>,
+
+    /// Path the configuration was loaded from, used for reloading
+    #[serde(skip)]
+    pub config_path: Option<String>,
+
+    /// Format of configuration file if it was set explicitly instead of detecting by extension
+    #[serde(skip)]
+    pub config_format: Option<ConfigFormat>,
+
+    /// Options overridden by command line and environment, applied again on reloading
+    #[serde(skip)]
+    pub overrides: Vec<(String, String)>,
+
+    /// Messages about outdated options found when loading
+    #[serde(skip)]
+    pub migration_warnings: Vec<String>,
+}
+
+/// Number of threads, either fixed or derived from the number of available CPUs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ThreadCount {
+    Fixed(usize),
+    Auto,
+}
+
+impl serde::Serialize for ThreadCount {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            ThreadCount::Fixed(count) => serializer.serialize_u64(*count as u64),
+            ThreadCount::Auto => serializer.serialize_str("auto"),
+        }
+    }
+}
+
+struct ThreadCountVisitor;
+
+impl<'de> Visitor<'de> for ThreadCountVisitor {
+    type Value = ThreadCount;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("number of threads or \"auto\"")
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<ThreadCount, E> {
+        Ok(ThreadCount::Fixed(value as usize))
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<ThreadCount, E> {
+        if value < 0 {
+            return Err(E::custom(format!("bad number of threads {}", value)));
+        }
+        self.visit_u64(value as u64)
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<ThreadCount, E> {
+        match value {
+            "auto" => Ok(ThreadCount::Auto),
+            _ => value.parse().map(ThreadCount::Fixed).map_err(|_| E::custom(format!("bad number of threads {:?}, expected number or \"auto\"", value))),
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for ThreadCount {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(ThreadCountVisitor)
+    }
+}
+
+impl ThreadCount {
+    /// Resolve the number of threads given the number of CPUs and the share of them to take in auto mode,
+    /// there is always at least one thread
+    pub fn resolve(&self, cpus: usize, share: f32) -> usize {
+        match self {
+            ThreadCount::Fixed(0) => cpus,
+            ThreadCount::Fixed(count) => *count,
+            ThreadCount::Auto => ((cpus as f32 * share).round() as usize).max(1),
+        }
+    }
 }
 
 impl Default for System {
     fn default() -> Self {
         Self {
+            config_version: CONFIG_VERSION,
             verbosity: "warn".to_string(),
             network: Network::default(),
             raft: Raft::default(),
             consul: Consul::default(),
+            standby: Standby::default(),
             metrics: Metrics::default(),
             carbon: Carbon::default(),
-            n_threads: 4,
-            w_threads: 4,
+            management: Management::default(),
+            memory: Memory::default(),
+            log: Logging::default(),
+            tracing: Tracing::default(),
+            incidents: Incidents::default(),
+            supervision: Supervision::default(),
+            health: Health::default(),
+            accounting: Accounting::default(),
+            privileges: Privileges::default(),
+            sandbox: Sandbox::default(),
+            tls: Tls::default(),
+            shutdown: Shutdown::default(),
+            startup: Startup::default(),
+            daemon: Daemon::default(),
+            shards: Shards::default(),
+            windows: Vec::new(),
+            n_threads: ThreadCount::Fixed(4),
+            w_threads: ThreadCount::Fixed(4),
+            network_threads_ratio: 0.25,
+            network_cpus: None,
+            counting_cpus: None,
             stats_interval: 10000,
             task_queue_size: 2048,
+            task_queue_autotune: false,
+            task_queue_max_size: 16384,
             start_as_leader: false,
             stats_prefix: "resources.monitoring.bioyino".to_string(),
+            stats_tags: BTreeMap::new(),
+            stats_node_tag: None,
             consensus: ConsensusKind::None,
+            include: None,
+            config_path: None,
+            config_format: None,
+            overrides: Vec::new(),
+            migration_warnings: Vec::new(),
         }
     }
 }
@@ -103,8 +308,16 @@ pub struct Metrics {
     /// Whether we should spam parsing errors in logs
     pub log_parse_errors: bool,
 
+    /// Number of recent lines with parsing errors shown in stats
+    pub parse_error_samples: usize,
+
+    /// Interval of logging parsing errors summary, ms, 0 to disable
+    #[serde(deserialize_with = "duration_ms")]
+    pub parse_error_summary: u64,
+
     /// Maximum length of data parser can keep in buffer befor considering it trash and throwing
     /// away
+    #[serde(deserialize_with = "size_bytes")]
     pub max_unparsed_buffer: usize,
 
     /// Choose the way of aggregation
@@ -112,6 +325,81 @@ pub struct Metrics {
 
     /// Number of threads when aggregating in "multi" mode
     pub aggregation_threads: Option<usize>,
+
+    /// File to load ingestion rules from and save them to when changed by management API
+    pub rules_file: Option<String>,
+
+    /// Type to give metrics sent without type(`name:value`), they are parse errors if not set
+    pub untyped_as: Option<UntypedAs>,
+
+    /// Number of timer samples every worker keeps in reusable buffers between intervals, 0 to disable
+    pub sample_arena_size: usize,
+
+    /// Number of unique metric names expected, worker caches and name table are allocated for them at start
+    pub expected_metrics: usize,
+
+    /// Dump made by management API to load metric names from at start
+    pub warmup_dump: Option<String>,
+
+    /// Worker tasks running longer than this are logged and counted, ms, 0 to disable
+    #[serde(deserialize_with = "duration_ms")]
+    pub slow_task: u64,
+
+    /// Lowercase tag names, so tags differing only in case are the same tag
+    pub lowercase_tag_names: bool,
+
+    /// Characters allowed in tag names and values
+    pub tag_chars: TagChars,
+
+    /// Maximum number of tags of a metric, 0 for no limit
+    pub max_tags: usize,
+
+    /// Maximum length of a tag name or value, 0 for no limit
+    pub max_tag_length: usize,
+
+    /// Maximum length of all tags of a metric in canonical form, 0 for no limit
+    pub max_tag_bytes: usize,
+
+    /// What to do with metrics having tags over limits
+    pub tag_overflow: TagOverflow,
+}
+
+/// Handling of tags over limits
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub enum TagOverflow {
+    /// Drop the tags over limits, keeping the rest
+    Drop,
+    /// Drop all tags of the metric and tag it as overflowed
+    Bucket,
+}
+
+/// Characters allowed in tags, the rest are replaced with `_`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub enum TagChars {
+    /// Anything graphite accepts in tagged series
+    Graphite,
+    /// ASCII letters, digits, `_`, `-` and `.` only
+    Strict,
+}
+
+/// Type of metrics received without type
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub enum UntypedAs {
+    Gauge,
+    Counter,
+}
+
+impl UntypedAs {
+    /// Type part of statsd line for this type
+    pub fn suffix(&self) -> &'static [u8] {
+        match self {
+            UntypedAs::Gauge => b"|g",
+            UntypedAs::Counter => b"|c",
+        }
+    }
 }
 
 impl Default for Metrics {
@@ -124,9 +412,23 @@ impl Default for Metrics {
             update_counter_threshold: 200,
             consistent_parsing: true,
             log_parse_errors: false,
+            parse_error_samples: 16,
+            parse_error_summary: 60000,
             max_unparsed_buffer: 10000,
             aggregation_mode: AggregationMode::Single,
             aggregation_threads: None,
+            rules_file: None,
+            untyped_as: None,
+            sample_arena_size: 262144,
+            expected_metrics: 0,
+            warmup_dump: None,
+            slow_task: 1000,
+            lowercase_tag_names: false,
+            tag_chars: TagChars::Graphite,
+            max_tags: 0,
+            max_tag_length: 0,
+            max_tag_bytes: 0,
+            tag_overflow: TagOverflow::Drop,
         }
     }
 }
@@ -143,16 +445,22 @@ pub struct Carbon {
     /// client bind address
     pub bind_address: Option<SocketAddr>,
 
+    /// Connect to the backend over TLS with `[tls]` settings
+    pub tls: bool,
+
     /// How often to send metrics to this backend, ms
+    #[serde(deserialize_with = "duration_ms")]
     pub interval: u64,
 
     /// How much to sleep when connection to backend fails, ms
+    #[serde(deserialize_with = "duration_ms")]
     pub connect_delay: u64,
 
     /// Multiply delay to this value for each consequent connection failure
     pub connect_delay_multiplier: f32,
 
     /// Maximum retry delay, ms
+    #[serde(deserialize_with = "duration_ms")]
     pub connect_delay_max: u64,
 
     /// How much times to retry when sending data to backend before giving up and dropping all metrics
@@ -164,6 +472,112 @@ pub struct Carbon {
     /// per-connection processing and working ineffectively when lots of metrics is sent in one
     /// connection
     pub chunks: usize,
+
+    /// Maximum number of aggregated intervals to keep in memory while flushing is paused,
+    /// the oldest ones are dropped when exceeded
+    pub max_paused_intervals: usize,
+
+    /// Phase of flushes inside the interval, so nodes sending to the same backend can flush
+    /// at different times. Flushes are aligned to wall clock only when this is set.
+    pub flush_offset: Option<FlushOffset>,
+
+    /// File to append metrics given up on after all retries to, they are replayed on the next start
+    pub spool_file: Option<String>,
+
+    /// Maximum size of the spool, points over it are dropped
+    #[serde(deserialize_with = "size_bytes")]
+    pub spool_max_size: usize,
+
+    /// Points spooled longer ago than this are not replayed, ms
+    #[serde(deserialize_with = "duration_ms")]
+    pub spool_max_age: u64,
+
+    /// File to keep the timestamp of the last interval flushed completely in, to find intervals missed while the server
+    /// was not running
+    pub flush_mark_file: Option<String>,
+
+    /// Names of metrics, as sent to backend, to send points for in missed intervals
+    pub backfill: Vec<String>,
+
+    /// Value of backfilled points
+    pub backfill_value: BackfillValue,
+
+    /// Missed intervals are only counted if the server was not running for longer than this, ms
+    #[serde(deserialize_with = "duration_ms")]
+    pub backfill_max_gap: u64,
+}
+
+/// Value of points sent for intervals missed during restart
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum BackfillValue {
+    Zero,
+    /// The value sent for the metric before restart
+    Last,
+}
+
+/// Offset of flush time from the start of interval
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FlushOffset {
+    /// Fixed offset, ms
+    Fixed(u64),
+    /// Offset derived from the hash of node name, spreading nodes over the interval
+    Hash,
+}
+
+impl serde::Serialize for FlushOffset {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            FlushOffset::Fixed(offset) => serializer.serialize_u64(*offset),
+            FlushOffset::Hash => serializer.serialize_str("hash"),
+        }
+    }
+}
+
+struct FlushOffsetVisitor;
+
+impl<'de> Visitor<'de> for FlushOffsetVisitor {
+    type Value = FlushOffset;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("offset in milliseconds, duration with units or \"hash\"")
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<FlushOffset, E> {
+        Ok(FlushOffset::Fixed(value))
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<FlushOffset, E> {
+        if value < 0 {
+            return Err(E::custom(format!("bad flush offset {}, must not be negative", value)));
+        }
+        self.visit_u64(value as u64)
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<FlushOffset, E> {
+        match value {
+            "hash" => Ok(FlushOffset::Hash),
+            _ => parse_duration(value).map(FlushOffset::Fixed).map_err(E::custom),
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for FlushOffset {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(FlushOffsetVisitor)
+    }
+}
+
+impl Carbon {
+    /// Offset of flushes from the start of interval for the node with this name, `None` if flushes
+    /// are not aligned
+    pub fn flush_offset_ms(&self, node: &str) -> Option<u64> {
+        match self.flush_offset? {
+            FlushOffset::Fixed(offset) => Some(offset % self.interval.max(1)),
+            // FNV-1a, which gives the same result on every build unlike std hashers
+            FlushOffset::Hash => Some(node.bytes().fold(0xcbf29ce484222325u64, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3)) % self.interval.max(1)),
+        }
+    }
 }
 
 impl Default for Carbon {
@@ -172,16 +586,597 @@ impl Default for Carbon {
             //            enabled: true,
             address: "127.0.0.1:2003".to_string(),
             bind_address: None,
+            tls: false,
             interval: 30000,
             connect_delay: 250,
             connect_delay_multiplier: 2f32,
             connect_delay_max: 10000,
             send_retries: 30,
             chunks: 1,
+            max_paused_intervals: 120,
+            flush_offset: None,
+            spool_file: None,
+            spool_max_size: 64 * 1024 * 1024,
+            spool_max_age: 86400000,
+            flush_mark_file: None,
+            backfill: Vec::new(),
+            backfill_value: BackfillValue::Zero,
+            backfill_max_gap: 3600000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct Memory {
+    /// Memory allowed for caches, timer samples, peer snapshots and backend queues, 0 to disable the budget.
+    /// Usage is estimated, so the process takes more memory than this.
+    #[serde(deserialize_with = "size_bytes")]
+    pub budget: usize,
+
+    /// Share of cgroup memory limit to take as the budget when budget is 0, 0 to only use the budget set
+    pub limit_ratio: f32,
+
+    /// How often to check memory usage against the budget, ms
+    #[serde(deserialize_with = "duration_ms")]
+    pub check_interval: u64,
+
+    /// Share of budget after which timers stop accepting samples over timer-sample-cap
+    pub cap_samples_ratio: f32,
+
+    /// Maximum number of samples in a timer when samples are capped
+    pub timer_sample_cap: usize,
+
+    /// Share of budget after which incoming metrics are dropped
+    pub shed_ratio: f32,
+
+    /// Share of budget after which metrics are flushed to backend without waiting for the interval end
+    pub flush_ratio: f32,
+
+    /// Cache shards filled less than this share of their capacity are allocated again for the number
+    /// of metrics they had, 0 to never shrink them
+    pub shrink_ratio: f32,
+
+    /// How often to give free memory back to the system, ms, 0 to disable
+    #[serde(deserialize_with = "duration_ms")]
+    pub trim_interval: u64,
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self { budget: 0, limit_ratio: 0.5, check_interval: 5000, cap_samples_ratio: 0.8, timer_sample_cap: 10000, shed_ratio: 0.9, flush_ratio: 1.0, shrink_ratio: 0.25, trim_interval: 60000 }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct Logging {
+    /// Where to write log, file if `file` is set and terminal otherwise by default
+    pub target: Option<LogTarget>,
+
+    /// File to write log to instead of terminal
+    pub file: Option<String>,
+
+    /// Size of log file it is rotated at, 0 to not rotate by size
+    #[serde(deserialize_with = "size_bytes")]
+    pub max_size: usize,
+
+    /// How often to rotate log file, ms, 0 to not rotate by time
+    #[serde(deserialize_with = "duration_ms")]
+    pub rotate_interval: u64,
+
+    /// Number of rotated files to keep
+    pub max_files: usize,
+
+    /// Compress rotated files with gzip
+    pub compress: bool,
+
+    /// Unix socket path or host:port of UDP syslog server
+    pub syslog_address: String,
+
+    /// Syslog facility name
+    pub syslog_facility: String,
+
+    /// Maximum number of records of a single log call per rate-limit-interval, 0 to log everything
+    pub rate_limit: usize,
+
+    /// Interval records are limited in, ms
+    #[serde(deserialize_with = "duration_ms")]
+    pub rate_limit_interval: u64,
+}
+
+impl Default for Logging {
+    fn default() -> Self {
+        Self { target: None, file: None, max_size: 100 * 1024 * 1024, rotate_interval: 0, max_files: 5, compress: false, syslog_address: "/dev/log".to_string(), syslog_facility: "daemon".to_string(), rate_limit: 100, rate_limit_interval: 10000 }
+    }
+}
+
+impl Logging {
+    pub fn target(&self) -> LogTarget {
+        match (&self.target, &self.file) {
+            (Some(target), _) => target.clone(),
+            (None, Some(_)) => LogTarget::File,
+            (None, None) => LogTarget::Terminal,
         }
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct Tracing {
+    /// OTLP/HTTP endpoint to send spans to, tracing is disabled if not set
+    pub endpoint: Option<String>,
+
+    /// Share of received buffers, snapshots and flushes to trace, from 0 to 1
+    pub sample_ratio: f64,
+
+    /// How often to send finished spans, ms
+    #[serde(deserialize_with = "duration_ms")]
+    pub export_interval: u64,
+
+    /// Maximum number of finished spans waiting to be sent, new ones are dropped over it
+    pub max_queued_spans: usize,
+
+    /// Service name spans are sent with
+    pub service_name: String,
+}
+
+impl Default for Tracing {
+    fn default() -> Self {
+        Self { endpoint: None, sample_ratio: 0.001, export_interval: 5000, max_queued_spans: 4096, service_name: "bioyino".to_string() }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct Incidents {
+    /// Sentry DSN to report incidents to
+    pub sentry_dsn: Option<String>,
+
+    /// File to read `sentry-dsn` from
+    pub sentry_dsn_file: Option<String>,
+
+    /// URL to POST incidents to in JSON
+    pub webhook: Option<String>,
+
+    /// File to read `webhook` from, the URL may have credentials in it
+    pub webhook_file: Option<String>,
+
+    /// Report backend as unreachable after this number of failed sends in a row, 0 to disable
+    pub backend_failures: usize,
+
+    /// Report consensus as lost after being unreachable for this long, ms, 0 to disable
+    #[serde(deserialize_with = "duration_ms")]
+    pub consensus_lost: u64,
+
+    /// How often to check for failures, ms
+    #[serde(deserialize_with = "duration_ms")]
+    pub check_interval: u64,
+}
+
+impl Default for Incidents {
+    fn default() -> Self {
+        Self { sentry_dsn: None, sentry_dsn_file: None, webhook: None, webhook_file: None, backend_failures: 5, consensus_lost: 30000, check_interval: 1000 }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct Supervision {
+    /// Delay before restarting a panicked subsystem, doubled with every crash in restart window, ms
+    #[serde(deserialize_with = "duration_ms")]
+    pub restart_delay: u64,
+
+    /// Maximum delay before restarting, ms
+    #[serde(deserialize_with = "duration_ms")]
+    pub restart_delay_max: u64,
+
+    /// Stop the server when a subsystem panics more times than this in restart window
+    pub max_restarts: usize,
+
+    /// Window crashes are counted in, ms
+    #[serde(deserialize_with = "duration_ms")]
+    pub restart_window: u64,
+}
+
+impl Default for Supervision {
+    fn default() -> Self {
+        Self { restart_delay: 1000, restart_delay_max: 30000, max_restarts: 5, restart_window: 300000 }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct Health {
+    /// Node is reported as degraded by /readyz when health score is below this, 0 to never report it
+    pub degraded_score: f32,
+
+    /// Share of worker queue capacity at which queues score nothing
+    pub queue_ratio: f32,
+
+    /// Share of dropped packets at which drops score nothing
+    pub drop_ratio: f32,
+
+    /// How often to count the score, ms
+    #[serde(deserialize_with = "duration_ms")]
+    pub check_interval: u64,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self { degraded_score: 50.0, queue_ratio: 0.9, drop_ratio: 0.05, check_interval: 1000 }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct Accounting {
+    /// Count samples received and points sent to backend for every name prefix
+    pub enabled: bool,
+
+    /// Number of name segments making a prefix
+    pub depth: usize,
+
+    /// Maximum number of prefixes counted separately, the rest are counted as "_other"
+    pub max_prefixes: usize,
+
+    /// How often to log totals of every prefix, ms
+    #[serde(deserialize_with = "duration_ms")]
+    pub summary_interval: u64,
+
+    /// Limits of names and samples of name prefixes, they can be changed through management API until restart
+    pub quotas: Vec<Quota>,
+}
+
+impl Default for Accounting {
+    fn default() -> Self {
+        Self { enabled: false, depth: 1, max_prefixes: 1000, summary_interval: 86400000, quotas: Vec::new() }
+    }
+}
+
+/// What to do with samples over quota
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverQuota {
+    Drop,
+    /// Keep `sample-ratio` of samples over quota
+    Sample,
+    /// Aggregate samples over quota into a single `<prefix>._overflow.<type>` metric per type
+    Overflow,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct Quota {
+    /// Name prefix, whole segments only: "team" limits "team.requests", but not "teamcity.requests"
+    pub prefix: String,
+
+    /// Distinct names(tags included) accepted in an aggregation interval, 0 for no limit
+    pub max_names: usize,
+
+    /// Samples per second accepted, 0 for no limit
+    pub max_rate: usize,
+
+    pub over_quota: OverQuota,
+
+    /// Share of samples over quota kept with `over-quota = "sample"`
+    pub sample_ratio: f64,
+}
+
+impl Default for Quota {
+    fn default() -> Self {
+        Self { prefix: String::new(), max_names: 0, max_rate: 0, over_quota: OverQuota::Drop, sample_ratio: 0.1 }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct Privileges {
+    /// User, by name or id, to switch to before receiving any data when started as root
+    pub user: Option<String>,
+
+    /// Group to switch to, the primary group of the user if not set
+    pub group: Option<String>,
+
+    /// Capabilities to keep after switching, like "net-admin" for changing busy polling at runtime
+    pub keep_capabilities: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct Sandbox {
+    /// Restrict syscalls and filesystem access of network and worker threads with seccomp and Landlock after they start
+    pub enabled: bool,
+
+    /// Paths sandboxed threads may read under
+    pub read_paths: Vec<String>,
+
+    /// Paths sandboxed threads may read and write under
+    pub write_paths: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct Shutdown {
+    /// On SIGTERM and SIGINT stop receiving, aggregate metrics in caches and send them to backend before exiting
+    pub graceful: bool,
+
+    /// Exit after this long even if the last flush is not finished, ms
+    #[serde(deserialize_with = "duration_ms")]
+    pub timeout: u64,
+
+    /// Write caches to this file instead of flushing them on shutdown and merge them into the first interval after start
+    pub state_file: Option<String>,
+
+    /// Unix socket to hand listening sockets off to the next process over on restart
+    pub handoff_socket: Option<String>,
+
+    /// Pass caches to the next process too, they are flushed otherwise
+    pub handoff_caches: bool,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self { graceful: true, timeout: 30000, state_file: None, handoff_socket: None, handoff_caches: true }
+    }
+}
+
+/// What to do when dependencies are not up in `startup.timeout`
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum StartupTimeout {
+    /// Exit with an error, so the service manager restarts the server
+    Fail,
+    /// Start anyway, with dependencies not up reported as warnings
+    Start,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct Startup {
+    /// Wait for peer and backend names to resolve, consul to have a leader and listening ports to be free before starting
+    pub wait: bool,
+
+    /// Give up waiting after this long, ms
+    #[serde(deserialize_with = "duration_ms")]
+    pub timeout: u64,
+
+    /// Delay before checking a dependency again, doubled every time, ms
+    #[serde(deserialize_with = "duration_ms")]
+    pub retry_delay: u64,
+
+    /// Maximum delay between checks, ms
+    #[serde(deserialize_with = "duration_ms")]
+    pub retry_delay_max: u64,
+
+    pub on_timeout: StartupTimeout,
+}
+
+impl Default for Startup {
+    fn default() -> Self {
+        Self { wait: false, timeout: 60000, retry_delay: 500, retry_delay_max: 5000, on_timeout: StartupTimeout::Fail }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct Daemon {
+    /// File to write process id to, removed on exit
+    pub pid_file: Option<String>,
+
+    /// Fork to background and detach from the terminal, the command exits when the server is started
+    pub detach: bool,
+
+    /// Permission mask for created files, octal like "027"
+    pub umask: Option<String>,
+
+    /// Directory to change to, "/" when detached if not set
+    pub work_dir: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct Shards {
+    /// Number of instances to run, each in it's own process with it's own statsd sockets, workers and caches
+    pub count: usize,
+
+    /// Index of this instance, set for started instances by the first one
+    pub index: usize,
+
+    /// Peer and management ports of every next instance are this much higher
+    pub port_step: u16,
+}
+
+impl Default for Shards {
+    fn default() -> Self {
+        Self { count: 1, index: 0, port_step: 100 }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct Window {
+    /// Name to see the window by in logs, events and management API
+    pub name: String,
+
+    /// When the window starts, cron-like "minute hour day-of-month month day-of-week" in UTC, like "0 10 * * 1-5"
+    pub schedule: String,
+
+    /// How long the window lasts after every start, ms
+    #[serde(deserialize_with = "duration_ms")]
+    pub duration: u64,
+
+    /// Reloadable options changed during the window by their full names, values are given like in `--set`
+    pub set: BTreeMap<String, String>,
+}
+
+impl Default for Window {
+    fn default() -> Self {
+        Self { name: String::new(), schedule: String::new(), duration: 3600000, set: BTreeMap::new() }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub enum LogTarget {
+    Terminal,
+    File,
+    /// RFC5424 messages to syslog-address
+    Syslog,
+    /// Native journald protocol
+    Journald,
+}
+
+/// Lowest TLS version accepted
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum TlsVersion {
+    #[serde(rename = "1.2")]
+    V12,
+    #[serde(rename = "1.3")]
+    V13,
+}
+
+/// Cipher suites offered
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CipherPolicy {
+    /// All suites supported, they are AEAD with forward secrecy anyway
+    Default,
+    /// Only suites with 256 bit keys
+    Strict,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct Tls {
+    /// PEM file with certificate chain, TLS is enabled when both certificate and key are set
+    pub cert: Option<String>,
+
+    /// PEM file with private key
+    pub key: Option<String>,
+
+    /// PEM file with CA certificates to verify clients with, enables mutual TLS
+    pub client_ca: Option<String>,
+
+    /// PEM file with CA certificates to verify peer and carbon servers with, well-known CAs are used if not set
+    pub ca: Option<String>,
+
+    /// Name peer and carbon certificates are verified for instead of the host part of their addresses
+    pub server_name: Option<String>,
+
+    /// Lowest TLS version accepted
+    pub min_version: TlsVersion,
+
+    /// Cipher suites offered
+    pub cipher_policy: CipherPolicy,
+}
+
+impl Default for Tls {
+    fn default() -> Self {
+        Self { cert: None, key: None, client_ca: None, ca: None, server_name: None, min_version: TlsVersion::V12, cipher_policy: CipherPolicy::Default }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct Management {
+    /// Tokens allowed to access the API. When empty, no authentication is done
+    pub tokens: Vec<ApiToken>,
+
+    /// Token to use for `query` subcommand
+    pub client_token: Option<String>,
+
+    /// File to read `client-token` from
+    pub client_token_file: Option<String>,
+
+    /// Directory to write cache dumps to
+    pub dump_dir: Option<String>,
+
+    /// File to append records about state-changing calls to, one JSON per line
+    pub audit_log: Option<String>,
+
+    /// File to append state reports made on SIGUSR1 to, they are logged when it is not set
+    pub state_report_file: Option<String>,
+
+    /// Number of last significant events kept for /events, 0 to disable
+    pub event_log_size: usize,
+
+    /// Allow taking CPU profiles with /debug/pprof endpoints, server must be built with pprof feature
+    pub profiling: bool,
+
+    /// Maximum duration of a profile, ms
+    #[serde(deserialize_with = "duration_ms")]
+    pub profile_max_duration: usize,
+
+    /// CPU samples per second taken while profiling
+    pub profile_frequency: i32,
+}
+
+impl Default for Management {
+    fn default() -> Self {
+        Self { tokens: Vec::new(), client_token: None, client_token_file: None, dump_dir: None, audit_log: None, state_report_file: None, event_log_size: 256, profiling: false, profile_max_duration: 60000, profile_frequency: 99 }
+    }
+}
+
+// mounted secrets usually end with a newline which is not a part of the secret
+fn read_secret(path: &str) -> Result<String, GeneralError> {
+    let secret = fs::read_to_string(path).map_err(|e| GeneralError::Secret(path.to_string(), e.to_string()))?;
+    Ok(secret.trim_end_matches(|c| c == '\n' || c == '\r').to_string())
+}
+
+impl Management {
+    /// Read secrets set by `*-file` options, a secret cannot be set both ways
+    pub fn load_secrets(&mut self) -> Result<(), GeneralError> {
+        for token in &mut self.tokens {
+            if let Some(ref path) = token.token_file {
+                if token.token.len() > 0 {
+                    return Err(GeneralError::Secret(path.clone(), "token and token-file cannot be set at the same time".to_string()));
+                }
+                token.token = read_secret(path)?;
+            }
+        }
+        if let Some(ref path) = self.client_token_file {
+            if self.client_token.is_some() {
+                return Err(GeneralError::Secret(path.clone(), "client-token and client-token-file cannot be set at the same time".to_string()));
+            }
+            self.client_token = Some(read_secret(path)?);
+        }
+        Ok(())
+    }
+}
+
+impl Incidents {
+    /// Read secrets set by `*-file` options, a secret cannot be set both ways
+    pub fn load_secrets(&mut self) -> Result<(), GeneralError> {
+        if let Some(ref path) = self.sentry_dsn_file {
+            if self.sentry_dsn.is_some() {
+                return Err(GeneralError::Secret(path.clone(), "sentry-dsn and sentry-dsn-file cannot be set at the same time".to_string()));
+            }
+            self.sentry_dsn = Some(read_secret(path)?);
+        }
+        if let Some(ref path) = self.webhook_file {
+            if self.webhook.is_some() {
+                return Err(GeneralError::Secret(path.clone(), "webhook and webhook-file cannot be set at the same time".to_string()));
+            }
+            self.webhook = Some(read_secret(path)?);
+        }
+        Ok(())
+    }
+}
+
+impl Network {
+    /// Read secrets set by `*-file` options, a secret cannot be set both ways
+    pub fn load_secrets(&mut self) -> Result<(), GeneralError> {
+        if let Some(ref path) = self.statsd_hmac_key_file {
+            if self.statsd_hmac_key.is_some() {
+                return Err(GeneralError::Secret(path.clone(), "statsd-hmac-key and statsd-hmac-key-file cannot be set at the same time".to_string()));
+            }
+            self.statsd_hmac_key = Some(read_secret(path)?);
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
 pub struct Network {
@@ -194,11 +1189,15 @@ pub struct Network {
     /// Snapshot client bind address
     pub peer_client_bind: Option<SocketAddr>,
 
+    /// Use TLS with `[tls]` settings for peer server and snapshot client, both certificate and key must be set
+    pub peer_tls: bool,
+
     /// Address and port for management server to listen on
     pub mgmt_listen: SocketAddr,
 
     /// UDP buffer size for single packet. Needs to be around MTU. Packet's bytes after that value
     /// may be lost
+    #[serde(deserialize_with = "size_bytes")]
     pub bufsize: usize,
 
     /// Enable multimessage(recvmmsg) mode
@@ -211,12 +1210,15 @@ pub struct Network {
     pub mm_async: bool,
 
     /// A timeout to return from multimessage mode syscall
+    #[serde(deserialize_with = "duration_ms")]
     pub mm_timeout: u64,
 
     /// A timer to flush incoming buffer making sure metrics are not stuck there
+    #[serde(deserialize_with = "duration_ms")]
     pub buffer_flush_time: u64,
 
     /// A length of incoming buffer to flush it making sure metrics are not stuck there
+    #[serde(deserialize_with = "size_bytes")]
     pub buffer_flush_length: usize,
 
     /// Nmber of green threads for single-message mode
@@ -229,7 +1231,78 @@ pub struct Network {
     pub nodes: Vec<String>,
 
     /// Interval to send snapshots to nodes, ms
+    #[serde(deserialize_with = "duration_ms")]
     pub snapshot_interval: usize,
+
+    /// Type of untyped metrics received by statsd listener, overrides `metrics.untyped-as`
+    pub untyped_as: Option<UntypedAs>,
+
+    /// Number of metrics received from agents by peer listener to collect before sending them to a worker
+    pub batch_size: usize,
+
+    /// A timer to send incomplete batches of metrics from agents to workers, ms
+    #[serde(deserialize_with = "duration_ms")]
+    pub batch_flush_time: u64,
+
+    /// Receive buffer of statsd UDP sockets(SO_RCVBUF), 0 to leave the system default
+    #[serde(deserialize_with = "size_bytes")]
+    pub recv_buffer: usize,
+
+    /// Microseconds to busy poll statsd UDP sockets for new packets(SO_BUSY_POLL), 0 to disable
+    pub busy_poll: u32,
+
+    /// Grow receive buffer and multimessage batch of statsd UDP listener when the kernel drops packets
+    pub socket_autotune: bool,
+
+    /// Maximum receive buffer autotune can set
+    #[serde(deserialize_with = "size_bytes")]
+    pub max_recv_buffer: usize,
+
+    /// Maximum multimessage batch autotune can set
+    pub max_mm_packets: usize,
+
+    /// TCP connection making no progress for this long is logged as slow, ms, 0 to disable
+    #[serde(deserialize_with = "duration_ms")]
+    pub slow_connection: u64,
+
+    /// Close slow TCP connections after making no progress for this long, ms, 0 to never close them
+    #[serde(deserialize_with = "duration_ms")]
+    pub slow_connection_kill: u64,
+
+    /// Networks(CIDR) statsd packets are accepted from, all if empty
+    pub statsd_allow: Vec<String>,
+
+    /// Networks(CIDR) statsd packets are dropped from, they win over allowed ones
+    pub statsd_deny: Vec<String>,
+
+    /// Networks(CIDR) peer connections are accepted from, all if empty
+    pub peer_allow: Vec<String>,
+
+    /// Networks(CIDR) peer connections are closed from, they win over allowed ones
+    pub peer_deny: Vec<String>,
+
+    /// Shared key statsd packets must be signed with, packets without a valid signature are dropped
+    pub statsd_hmac_key: Option<String>,
+
+    /// File to read `statsd-hmac-key` from
+    pub statsd_hmac_key_file: Option<String>,
+
+    /// Signed packets with timestamp differing from the current time more than this are dropped, ms
+    #[serde(deserialize_with = "duration_ms")]
+    pub statsd_hmac_max_age: u64,
+
+    /// Metrics per second accepted from statsd packets by all workers together, the rest are dropped, 0 for no limit
+    pub statsd_rate_limit: usize,
+
+    /// Metrics per second accepted from agents over peer protocol, snapshots of other nodes are not limited, 0 for no limit
+    pub peer_rate_limit: usize,
+
+    /// Unused rate is saved up for at most this long, allowing bursts over the limit, ms
+    #[serde(deserialize_with = "duration_ms")]
+    pub rate_limit_burst: u64,
+
+    /// Statsd address of a sibling node to forward statsd packets to in maintenance mode
+    pub maintenance_forward: Option<String>,
 }
 
 impl Default for Network {
@@ -238,6 +1311,7 @@ impl Default for Network {
             listen: "127.0.0.1:8125".parse().unwrap(),
             peer_listen: "127.0.0.1:8136".parse().unwrap(),
             peer_client_bind: None,
+            peer_tls: false,
             mgmt_listen: "127.0.0.1:8137".parse().unwrap(),
             bufsize: 1500,
             multimessage: false,
@@ -250,6 +1324,27 @@ impl Default for Network {
             async_sockets: 4,
             nodes: Vec::new(),
             snapshot_interval: 1000,
+            untyped_as: None,
+            batch_size: 1000,
+            batch_flush_time: 100,
+            recv_buffer: 0,
+            busy_poll: 0,
+            socket_autotune: false,
+            max_recv_buffer: 33554432,
+            max_mm_packets: 200,
+            slow_connection: 10000,
+            slow_connection_kill: 0,
+            statsd_allow: Vec::new(),
+            statsd_deny: Vec::new(),
+            peer_allow: Vec::new(),
+            peer_deny: Vec::new(),
+            statsd_hmac_key: None,
+            statsd_hmac_key_file: None,
+            statsd_hmac_max_age: 30000,
+            statsd_rate_limit: 0,
+            peer_rate_limit: 0,
+            rate_limit_burst: 1000,
+            maintenance_forward: None,
         }
     }
 }
@@ -264,9 +1359,11 @@ pub struct Consul {
     pub agent: SocketAddr,
 
     /// TTL of consul session, ms (consul cannot set it to less than 10s)
+    #[serde(deserialize_with = "duration_ms")]
     pub session_ttl: usize,
 
     /// How often to renew consul session, ms
+    #[serde(deserialize_with = "duration_ms")]
     pub renew_time: usize,
 
     /// Name of ke to be locked in consul
@@ -279,19 +1376,52 @@ impl Default for Consul {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub enum StandbyRole {
+    /// Always leads
+    Primary,
+    /// Leads while the primary is not heard of
+    Standby,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct Standby {
+    /// Role of this node in the pair
+    pub role: StandbyRole,
+
+    /// Peer address of the primary, snapshots from it's IP show it is alive
+    pub primary: Option<String>,
+
+    /// Standby takes over when there were no snapshots from the primary for this long, ms
+    #[serde(deserialize_with = "duration_ms")]
+    pub failover_timeout: u64,
+}
+
+impl Default for Standby {
+    fn default() -> Self {
+        Self { role: StandbyRole::Primary, primary: None, failover_timeout: 5000 }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
 pub struct Raft {
     /// Delay raft after start (ms)
+    #[serde(deserialize_with = "duration_ms")]
     pub start_delay: u64,
 
     /// Raft heartbeat timeout (ms)
+    #[serde(deserialize_with = "duration_ms")]
     pub heartbeat_timeout: u64,
 
     /// Raft heartbeat timeout (ms)
+    #[serde(deserialize_with = "duration_ms")]
     pub election_timeout_min: u64,
 
     /// Raft heartbeat timeout (ms)
+    #[serde(deserialize_with = "duration_ms")]
     pub election_timeout_max: u64,
 
     /// Name of this node. By default is taken by resolving hostname in DNS.
@@ -322,37 +1452,373 @@ impl Raft {
 #[derive(Debug)]
 pub enum Command {
     Daemon,
-    Query(MgmtCommand, String),
+    /// Only check the configuration, error of loading the file is kept if there was one
+    Check(Option<String>),
+    /// Print version, commit, features and configuration hash, error of loading the file is kept if there was one
+    BuildInfo(Option<String>),
+    /// Print default configuration
+    GenerateConfig,
+    Query(MgmtCommand, String, OutputFormat),
+    /// Send generated statsd traffic and print achieved rates
+    Bench(BenchOptions, OutputFormat),
+}
+
+/// Substitute `${NAME}` and `${NAME:-default}` with values of environment variables found by `lookup`,
+/// `$${` is a literal `${`. Commented out lines are left as is, so examples in comments need no variables.
+pub fn interpolate_env<F: Fn(&str) -> Option<String>>(input: &str, lookup: F) -> Result<String, GeneralError> {
+    let mut output = String::with_capacity(input.len());
+    for (idx, line) in input.split('\n').enumerate() {
+        if idx > 0 {
+            output.push('\n');
+        }
+        if line.trim_start().starts_with('#') {
+            output.push_str(line);
+            continue;
+        }
+        let mut rest = line;
+        while let Some(start) = rest.find("${") {
+            if rest[..start].ends_with('$') {
+                output.push_str(&rest[..start - 1]);
+                output.push_str("${");
+                rest = &rest[start + 2..];
+                continue;
+            }
+            output.push_str(&rest[..start]);
+            let end = rest[start..].find('}').ok_or_else(|| GeneralError::Interpolation(format!("unclosed variable in line {}", idx + 1)))? + start;
+            let expr = &rest[start + 2..end];
+            let (name, default) = match expr.find(":-") {
+                Some(pos) => (&expr[..pos], Some(&expr[pos + 2..])),
+                None => (expr, None),
+            };
+            if name.len() == 0 || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(GeneralError::Interpolation(format!("bad variable name {:?}", name)));
+            }
+            match (lookup(name), default) {
+                // empty variable is treated as unset, the same way shell does it for :-
+                (Some(ref value), _) if value.len() > 0 => output.push_str(value),
+                (_, Some(default)) => output.push_str(default),
+                (_, None) => return Err(GeneralError::Interpolation(format!("environment variable {} is not set", name))),
+            }
+            rest = &rest[end + 1..];
+        }
+        output.push_str(rest);
+    }
+    Ok(output)
+}
+
+/// Syntax of configuration files, all of them are converted to the same structure
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConfigFormat {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl FromStr for ConfigFormat {
+    type Err = GeneralError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "toml" => Ok(ConfigFormat::Toml),
+            "yaml" | "yml" => Ok(ConfigFormat::Yaml),
+            "json" => Ok(ConfigFormat::Json),
+            _ => Err(GeneralError::Configuration("unknown configuration format")),
+        }
+    }
+}
+
+impl ConfigFormat {
+    /// Detect format by file extension
+    pub fn from_path(path: &Path) -> Option<Self> {
+        path.extension().and_then(|ext| ext.to_str()).and_then(|ext| ext.parse().ok())
+    }
+
+    fn parse(&self, input: &str) -> Result<toml::Value, GeneralError> {
+        match self {
+            ConfigFormat::Toml => toml::de::from_str(input).map_err(GeneralError::ConfigParse),
+            // TOML has no nulls, so they are reported as errors instead of being silently dropped
+            ConfigFormat::Yaml => serde_yaml::from_str(input).map_err(|e| GeneralError::ConfigSyntax("YAML", e.to_string())),
+            ConfigFormat::Json => serde_json::from_str(input).map_err(|e| GeneralError::ConfigSyntax("JSON", e.to_string())),
+        }
+    }
+}
+
+// read a single file with environment variables substituted, the format is detected
+// by extension if not set, files without known extension are TOML
+fn read_config(path: &Path, format: Option<ConfigFormat>) -> Result<toml::Value, GeneralError> {
+    let mut file = File::open(path).map_err(GeneralError::Io)?;
+    let mut config_str = String::new();
+    file.read_to_string(&mut config_str).map_err(GeneralError::Io)?;
+    let config_str = interpolate_env(&config_str, |name| env::var(name).ok())?;
+    format.or_else(|| ConfigFormat::from_path(path)).unwrap_or(ConfigFormat::Toml).parse(&config_str)
+}
+
+/// Merge configuration fragment into `base`: tables are merged key by key, arrays are appended,
+/// other values are replaced
+pub fn merge_toml(base: &mut toml::Value, fragment: toml::Value) {
+    match (base, fragment) {
+        (toml::Value::Table(base), toml::Value::Table(fragment)) => {
+            for (key, value) in fragment {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_toml(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (toml::Value::Array(base), toml::Value::Array(fragment)) => base.extend(fragment),
+        (base, fragment) => *base = fragment,
+    }
+}
+
+// configuration files of include directory in any of known formats, ordered by name
+fn fragment_files(dir: &Path) -> Result<Vec<PathBuf>, GeneralError> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir).map_err(GeneralError::Io)? {
+        let path = entry.map_err(GeneralError::Io)?.path();
+        if path.is_file() && ConfigFormat::from_path(&path).is_some() {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+// environment variables which are not options, but start with the same prefix
+const NOT_OVERRIDES: &[&str] = &["BIOYINO_ARGS"];
+
+/// Split `key.path=value` argument of `--set` option
+pub fn parse_override(arg: &str) -> Result<(String, String), GeneralError> {
+    match arg.find('=') {
+        Some(pos) if pos > 0 => Ok((arg[..pos].trim().to_string(), arg[pos + 1..].to_string())),
+        _ => Err(GeneralError::Override(arg.to_string(), "expected key.path=value".to_string())),
+    }
+}
+
+/// Take overrides from `BIOYINO_*` variables, `__` separates sections and `_` is a dash in option name,
+/// i.e. `BIOYINO_CARBON__CONNECT_DELAY` overrides `carbon.connect-delay`
+pub fn env_overrides<I: Iterator<Item = (String, String)>>(vars: I) -> Vec<(String, String)> {
+    let mut overrides = vars
+        .filter(|(name, _)| name.starts_with("BIOYINO_") && name.len() > "BIOYINO_".len() && !NOT_OVERRIDES.contains(&name.as_str()))
+        .map(|(name, value)| (name["BIOYINO_".len()..].to_lowercase().split("__").map(|part| part.replace('_', "-")).collect::<Vec<_>>().join("."), value))
+        .collect::<Vec<_>>();
+    // environment has no order, but overrides should be applied the same way every time
+    overrides.sort();
+    overrides
+}
+
+// values are parsed as TOML, so numbers, booleans, arrays and tables can be set,
+// anything that is not a valid TOML value is taken as a string to avoid quoting addresses and names
+fn override_value(value: &str) -> toml::Value {
+    match toml::de::from_str::<toml::value::Table>(&format!("value = {}", value)) {
+        Ok(mut table) => table.remove("value").unwrap_or_else(|| toml::Value::String(value.to_string())),
+        Err(_) => toml::Value::String(value.to_string()),
+    }
+}
+
+/// Set the option at dotted `key` path, creating missing sections
+pub fn apply_override(config: &mut toml::Value, key: &str, value: &str) -> Result<(), GeneralError> {
+    let mut current = config;
+    let mut parts = key.split('.').peekable();
+    while let Some(part) = parts.next() {
+        if part.len() == 0 {
+            return Err(GeneralError::Override(key.to_string(), "empty part of option name".to_string()));
+        }
+        let table = current.as_table_mut().ok_or_else(|| GeneralError::Override(key.to_string(), "parent option is not a section".to_string()))?;
+        if parts.peek().is_none() {
+            table.insert(part.to_string(), override_value(value));
+            return Ok(());
+        }
+        current = table.entry(part.to_string()).or_insert_with(|| toml::Value::Table(toml::value::Table::new()));
+    }
+    Ok(())
 }
 
 impl System {
+    /// Type of untyped metrics received by statsd listener
+    pub fn statsd_untyped_as(&self) -> Option<UntypedAs> {
+        self.network.untyped_as.or(self.metrics.untyped_as)
+    }
+
+    /// Numbers of networking and aggregating threads for the number of available CPUs
+    pub fn thread_counts(&self, cpus: usize) -> (usize, usize) {
+        let ratio = self.network_threads_ratio.max(0f32).min(1f32);
+        (self.n_threads.resolve(cpus, ratio), self.w_threads.resolve(cpus, 1f32 - ratio))
+    }
+
+    /// Read and parse configuration file with all included fragments, then apply overrides
+    /// in the order they are listed, without any other actions
+    pub fn from_file(path: &str, format: Option<ConfigFormat>, overrides: &[(String, String)]) -> Result<Self, GeneralError> {
+        let mut config = read_config(Path::new(path), format)?;
+        let include = config.get("include").and_then(|include| include.as_str()).map(|include| include.to_string());
+        if let Some(include) = include {
+            let dir = Path::new(path).parent().unwrap_or(Path::new("")).join(include);
+            for file in fragment_files(&dir)? {
+                let fragment = read_config(&file, None).map_err(|e| GeneralError::Fragment(file.display().to_string(), e.to_string()))?;
+                if fragment.get("include").is_some() {
+                    return Err(GeneralError::Fragment(file.display().to_string(), "included files cannot include other files".to_string()));
+                }
+                merge_toml(&mut config, fragment);
+            }
+        }
+        for (key, value) in overrides {
+            apply_override(&mut config, key, value)?;
+        }
+        // overrides may use old names too, so migration goes after them
+        let migration_warnings = migrate(&mut config);
+        let mut system: System = config.try_into().map_err(GeneralError::ConfigParse)?;
+        // the same is done on reload, so the running configuration is compared to the one of the same shard
+        apply_shard(&mut system);
+        // secrets are read every time, so reloading picks up rotated ones
+        system.management.load_secrets()?;
+        system.network.load_secrets()?;
+        system.incidents.load_secrets()?;
+        system.config_path = Some(path.to_string());
+        system.config_format = format;
+        system.overrides = overrides.to_vec();
+        system.migration_warnings = migration_warnings;
+        Ok(system)
+    }
+
+    /// Parse configuration from a string the same way a file is parsed, but without includes, environment
+    /// variables and secret files, so the result depends on input only
+    pub fn from_source(input: &str, format: ConfigFormat) -> Result<Self, GeneralError> {
+        let mut config = format.parse(input)?;
+        let migration_warnings = migrate(&mut config);
+        let mut system: System = config.try_into().map_err(GeneralError::ConfigParse)?;
+        system.migration_warnings = migration_warnings;
+        Ok(system)
+    }
+
     pub fn load() -> (Self, Command) {
         // This is a first copy of args - with the "config" option
         let app = app_from_crate!()
             .long_version(concat!(crate_version!(), " ", env!("VERGEN_COMMIT_DATE"), " ", env!("VERGEN_SHA_SHORT")))
             .arg(Arg::with_name("config").help("configuration file path").long("config").short("c").required(true).takes_value(true).default_value("/etc/bioyino/bioyino.toml"))
+            .arg(Arg::with_name("config-format").long("config-format").help("configuration file format, detected by file extension by default, TOML if extension is unknown").takes_value(true).possible_values(&["toml", "yaml", "json"]))
             .arg(Arg::with_name("verbosity").short("v").help("logging level").takes_value(true))
-            .subcommand(SubCommand::with_name("query").about("send a management command to running bioyino server").arg(Arg::with_name("host").short("h").default_value("127.0.0.1:8137")).subcommand(SubCommand::with_name("status").about("get server state")).subcommand(SubCommand::with_name("consensus").arg(Arg::with_name("action").index(1)).arg(Arg::with_name("leader_action").index(2).default_value("unchanged"))))
+            .arg(Arg::with_name("check").long("check").help("check configuration and exit without starting the server"))
+            .arg(Arg::with_name("buildinfo").long("buildinfo").help("print version, commit, build date, enabled features and configuration hash and exit"))
+            .arg(Arg::with_name("set").long("set").help("override configuration option, i.e. --set carbon.interval=10s, can be repeated").takes_value(true).value_name("KEY=VALUE").multiple(true).number_of_values(1))
+            .subcommand(SubCommand::with_name("generate-config").about("print default configuration with descriptions of all options"))
+            .subcommand(SubCommand::with_name("bench").about("send generated statsd traffic to the address and report achieved rates and send latencies").arg(Arg::with_name("target").index(1).help("statsd address to send metrics to").default_value("127.0.0.1:8125")).arg(Arg::with_name("names").long("names").help("number of unique metric names").default_value("1000")).arg(Arg::with_name("prefix").long("prefix").help("prefix of metric names").default_value("bioyino.bench")).arg(Arg::with_name("types").long("types").help("shares of metric types, a name always has the same type").default_value("c=50,g=20,ms=25,s=5")).arg(Arg::with_name("rate").long("rate").help("datagrams per second, 0 to send as fast as possible").default_value("10000")).arg(Arg::with_name("payload").long("payload").help("maximum datagram size, lines are packed until it is reached").default_value("1400")).arg(Arg::with_name("tags").long("tags").help("number of DogStatsD tags added to every metric").default_value("0")).arg(Arg::with_name("tag-values").long("tag-values").help("number of different values of every tag").default_value("10")).arg(Arg::with_name("duration").long("duration").help("how long to send, i.e. 30s").default_value("10s")).arg(Arg::with_name("threads").long("threads").help("number of sending threads").default_value("1")).arg(Arg::with_name("output").short("o").long("output").help("output format").possible_values(&["table", "json"]).default_value("table")))
+            .subcommand(SubCommand::with_name("query").alias("ctl").about("send a management command to running bioyino server").arg(Arg::with_name("host").short("h").default_value("127.0.0.1:8137")).arg(Arg::with_name("output").short("o").long("output").help("output format").possible_values(&["table", "json"]).default_value("table")).subcommand(SubCommand::with_name("status").about("get server state").arg(Arg::with_name("cluster").long("cluster").help("show peers and consensus as seen by the server"))).subcommand(SubCommand::with_name("consensus").arg(Arg::with_name("action").index(1)).arg(Arg::with_name("leader_action").index(2).default_value("unchanged"))).subcommand(SubCommand::with_name("pause").about("pause receiving metrics(ingestion), sending them to backend(flush) or both(all)").arg(Arg::with_name("target").index(1).default_value("all"))).subcommand(SubCommand::with_name("resume").about("resume what was paused").arg(Arg::with_name("target").index(1).default_value("all"))).subcommand(SubCommand::with_name("leader").about("override leadership until consensus is enabled again").subcommand(SubCommand::with_name("step-down").about("stop being a leader")).subcommand(SubCommand::with_name("pin").about("make the node a leader, must be sent to every node").arg(Arg::with_name("node").index(1).required(true)))).subcommand(SubCommand::with_name("stats").about("show internal counters and their rates")).subcommand(SubCommand::with_name("flush").about("aggregate and send metrics to backend right now, must be sent to leader").arg(Arg::with_name("prefix").index(1).help("only flush metrics with this prefix"))).subcommand(SubCommand::with_name("tail").about("show incoming metrics matching the pattern until interrupted").arg(Arg::with_name("pattern").index(1).required(true)).arg(Arg::with_name("rate").long("rate").help("maximum metrics per second").default_value("10"))).subcommand(SubCommand::with_name("tunables").about("show parameters that can be changed without reloading configuration")).subcommand(SubCommand::with_name("tune").about("change a tunable until restart").arg(Arg::with_name("name").index(1).required(true)).arg(Arg::with_name("value").index(2).required(true))).subcommand(SubCommand::with_name("rules").about("show or change ingestion rules").arg(Arg::with_name("persist").long("persist").help("save changed rules to rules-file")).subcommand(SubCommand::with_name("show").about("show current rules")).subcommand(SubCommand::with_name("block").about("drop metrics matching the pattern").arg(Arg::with_name("pattern").index(1).required(true))).subcommand(SubCommand::with_name("unblock").about("remove a blocking rule").arg(Arg::with_name("pattern").index(1).required(true))).subcommand(SubCommand::with_name("rewrite").about("replace a name prefix").arg(Arg::with_name("prefix").index(1).required(true)).arg(Arg::with_name("replacement").index(2).required(true))).subcommand(SubCommand::with_name("unrewrite").about("remove a rewrite rule").arg(Arg::with_name("prefix").index(1).required(true))).subcommand(SubCommand::with_name("max-names").about("limit unique names per worker, no limit if number is not specified").arg(Arg::with_name("number").index(1))).subcommand(SubCommand::with_name("replace").about("replace all rules with ones from file").arg(Arg::with_name("file").index(1).required(true)))))
             .get_matches();
 
+        if app.subcommand_matches("generate-config").is_some() {
+            // configuration file may not exist yet, so it is not read
+            return (System::default(), Command::GenerateConfig);
+        }
+
+        if let Some(args) = app.subcommand_matches("bench") {
+            // load is generated for some other server, so configuration is not needed
+            let options = BenchOptions {
+                target: try_resolve(args.value_of("target").unwrap()),
+                names: value_t!(args.value_of("names"), usize).expect("bad number of names"),
+                prefix: args.value_of("prefix").unwrap().to_string(),
+                types: value_t!(args.value_of("types"), TypeMix).expect("bad type mix"),
+                rate: value_t!(args.value_of("rate"), u64).expect("bad rate"),
+                payload: parse_size(args.value_of("payload").unwrap()).expect("bad payload size") as usize,
+                tags: value_t!(args.value_of("tags"), usize).expect("bad number of tags"),
+                tag_values: value_t!(args.value_of("tag-values"), usize).expect("bad number of tag values"),
+                duration: Duration::from_millis(parse_duration(args.value_of("duration").unwrap()).expect("bad duration")),
+                threads: value_t!(args.value_of("threads"), usize).expect("bad number of threads"),
+            };
+            let output = value_t!(args.value_of("output"), OutputFormat).expect("bad output format");
+            return (System::default(), Command::Bench(options, output));
+        }
+
         let config = value_t!(app.value_of("config"), String).expect("config file must be string");
-        let mut file = File::open(&config).expect(&format!("opening config file at {}", &config));
-        let mut config_str = String::new();
-        file.read_to_string(&mut config_str).expect("reading config file");
-        let mut system: System = toml::de::from_str(&config_str).expect("parsing config");
+        let check = app.is_present("check");
+        let buildinfo = app.is_present("buildinfo");
+        // command line has priority over environment
+        let mut overrides = env_overrides(env::vars());
+        for arg in app.values_of("set").into_iter().flatten() {
+            match parse_override(arg) {
+                Ok(option) => overrides.push(option),
+                Err(e) if check => return (System::default(), Command::Check(Some(e.to_string()))),
+                Err(e) if buildinfo => return (System::default(), Command::BuildInfo(Some(e.to_string()))),
+                Err(e) => panic!("{}", e),
+            }
+        }
+        let format = app.value_of("config-format").map(|format| format.parse().expect("bad config format"));
+        let mut system = match System::from_file(&config, format, &overrides) {
+            Ok(system) => system,
+            Err(e) if check => return (System::default(), Command::Check(Some(format!("loading config file at {}: {}", &config, e)))),
+            Err(e) if buildinfo => return (System::default(), Command::BuildInfo(Some(format!("loading config file at {}: {}", &config, e)))),
+            Err(e) => panic!("loading config file at {}: {}", &config, e),
+        };
 
         if let Some(v) = app.value_of("verbosity") {
             system.verbosity = v.into()
         }
 
-        if let Some(query) = app.subcommand_matches("query") {
+        if check {
+            (system, Command::Check(None))
+        } else if buildinfo {
+            (system, Command::BuildInfo(None))
+        } else if let Some(query) = app.subcommand_matches("query") {
             let server = value_t!(query.value_of("host"), String).expect("bad server");
-            if let Some(_) = query.subcommand_matches("status") {
-                (system, Command::Query(MgmtCommand::Status, server))
+            let output = value_t!(query.value_of("output"), OutputFormat).expect("bad output format");
+            if let Some(args) = query.subcommand_matches("status") {
+                let command = if args.is_present("cluster") { MgmtCommand::ClusterStatus } else { MgmtCommand::Status };
+                (system, Command::Query(command, server, output))
             } else if let Some(args) = query.subcommand_matches("consensus") {
                 let c_action = value_t!(args.value_of("action"), ConsensusAction).expect("bad consensus action");
                 let l_action = value_t!(args.value_of("leader_action"), LeaderAction).expect("bad leader action");
-                (system, Command::Query(MgmtCommand::ConsensusCommand(c_action, l_action), server))
+                (system, Command::Query(MgmtCommand::ConsensusCommand(c_action, l_action), server, output))
+            } else if let Some(args) = query.subcommand_matches("pause") {
+                let target = value_t!(args.value_of("target"), PauseTarget).expect("bad pause target");
+                (system, Command::Query(MgmtCommand::Pause(target), server, output))
+            } else if let Some(args) = query.subcommand_matches("resume") {
+                let target = value_t!(args.value_of("target"), PauseTarget).expect("bad pause target");
+                (system, Command::Query(MgmtCommand::Resume(target), server, output))
+            } else if let Some(args) = query.subcommand_matches("leader") {
+                let command = if let Some(pin) = args.subcommand_matches("pin") {
+                    LeaderCommand::Pin(value_t!(pin.value_of("node"), String).expect("bad node name"))
+                } else if args.subcommand_matches("step-down").is_some() {
+                    LeaderCommand::StepDown
+                } else {
+                    panic!("leader command requires step-down or pin subcommand")
+                };
+                (system, Command::Query(MgmtCommand::Leader(command), server, output))
+            } else if query.subcommand_matches("stats").is_some() {
+                (system, Command::Query(MgmtCommand::Stats, server, output))
+            } else if let Some(args) = query.subcommand_matches("flush") {
+                (system, Command::Query(MgmtCommand::Flush(args.value_of("prefix").map(|prefix| prefix.to_string())), server, output))
+            } else if let Some(args) = query.subcommand_matches("tail") {
+                let pattern = value_t!(args.value_of("pattern"), String).expect("bad pattern");
+                let rate = value_t!(args.value_of("rate"), u32).expect("bad rate");
+                (system, Command::Query(MgmtCommand::Tail(pattern, rate), server, output))
+            } else if query.subcommand_matches("tunables").is_some() {
+                (system, Command::Query(MgmtCommand::Tunables, server, output))
+            } else if let Some(args) = query.subcommand_matches("tune") {
+                let name = value_t!(args.value_of("name"), String).expect("bad tunable name");
+                let value = value_t!(args.value_of("value"), usize).expect("bad tunable value");
+                (system, Command::Query(MgmtCommand::SetTunable(name, value), server, output))
+            } else if let Some(args) = query.subcommand_matches("rules") {
+                let persist = args.is_present("persist");
+                let command = match args.subcommand() {
+                    ("block", Some(block)) => MgmtCommand::ChangeRules(RulesChange::AddBlock(value_t!(block.value_of("pattern"), String).expect("bad pattern")), persist),
+                    ("unblock", Some(unblock)) => MgmtCommand::ChangeRules(RulesChange::RemoveBlock(value_t!(unblock.value_of("pattern"), String).expect("bad pattern")), persist),
+                    ("rewrite", Some(rewrite)) => {
+                        let rule = RewriteRule { prefix: value_t!(rewrite.value_of("prefix"), String).expect("bad prefix"), replacement: value_t!(rewrite.value_of("replacement"), String).expect("bad replacement") };
+                        MgmtCommand::ChangeRules(RulesChange::AddRewrite(rule), persist)
+                    }
+                    ("unrewrite", Some(unrewrite)) => MgmtCommand::ChangeRules(RulesChange::RemoveRewrite(value_t!(unrewrite.value_of("prefix"), String).expect("bad prefix")), persist),
+                    ("max-names", Some(max)) => {
+                        let number = max.value_of("number").map(|number| number.parse::<usize>().expect("bad number of names"));
+                        MgmtCommand::ChangeRules(RulesChange::SetMaxNames(number), persist)
+                    }
+                    ("replace", Some(replace)) => {
+                        let file = value_t!(replace.value_of("file"), String).expect("bad file name");
+                        let rules = Rules::from_file(&file).unwrap_or_else(|e| panic!("loading rules file at {}: {}", &file, e));
+                        MgmtCommand::ChangeRules(RulesChange::Replace(rules), persist)
+                    }
+                    _ => MgmtCommand::ShowRules,
+                };
+                (system, Command::Query(command, server, output))
             } else {
                 // shold be unreachable
                 unreachable!("clap bug?")
@@ -362,3 +1828,145 @@ impl System {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::cgroup_cpu_limit;
+    use toml::Value;
+
+    #[test]
+    fn env_interpolation() {
+        let lookup = |name: &str| match name {
+            "CARBON_ADDR" => Some("10.0.0.1:2003".to_string()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        };
+        let input = "address = \"${CARBON_ADDR}\"\nbind = \"${BIND:-127.0.0.1:8125}\"\nprefix = \"${EMPTY:-default}$${LITERAL}\"\n# ${MISSING}\n";
+        let expected = "address = \"10.0.0.1:2003\"\nbind = \"127.0.0.1:8125\"\nprefix = \"default${LITERAL}\"\n# ${MISSING}\n";
+        assert_eq!(interpolate_env(input, lookup).unwrap(), expected);
+        assert!(interpolate_env("address = \"${MISSING}\"", lookup).is_err());
+        assert!(interpolate_env("address = \"${CARBON_ADDR\"", lookup).is_err());
+    }
+
+    #[test]
+    fn units_in_config() {
+        let system: System = toml::de::from_str("stats-interval = \"1m\"\n[carbon]\ninterval = \"30s\"\nconnect-delay = 100\n[network]\nbuffer-flush-length = \"64KiB\"\nsnapshot-interval = \"500ms\"\n").unwrap();
+        assert_eq!(system.stats_interval, 60000);
+        assert_eq!(system.carbon.interval, 30000);
+        assert_eq!(system.carbon.connect_delay, 100);
+        assert_eq!(system.network.buffer_flush_length, 65536);
+        assert_eq!(system.network.snapshot_interval, 500);
+        assert!(toml::de::from_str::<System>("[carbon]\ninterval = \"30 parsecs\"\n").is_err());
+    }
+
+    #[test]
+    fn options_overridden() {
+        let env = vec![("BIOYINO_CARBON__CONNECT_DELAY".to_string(), "1s".to_string()), ("BIOYINO_ARGS".to_string(), "-c /etc/bioyino.toml".to_string()), ("HOME".to_string(), "/root".to_string())];
+        let mut overrides = env_overrides(env.into_iter());
+        assert_eq!(overrides, vec![("carbon.connect-delay".to_string(), "1s".to_string())]);
+        overrides.push(parse_override("carbon.address=10.0.0.1:2003").unwrap());
+        overrides.push(parse_override("network.nodes=[\"a:8136\", \"b:8136\"]").unwrap());
+        overrides.push(parse_override("n-threads=8").unwrap());
+        assert!(parse_override("=8").is_err());
+
+        let mut config: toml::Value = toml::de::from_str("n-threads = 4\n").unwrap();
+        for (key, value) in &overrides {
+            apply_override(&mut config, key, value).unwrap();
+        }
+        let system: System = config.try_into().unwrap();
+        assert_eq!(system.carbon.connect_delay, 1000);
+        assert_eq!(system.carbon.address, "10.0.0.1:2003".to_string());
+        assert_eq!(system.network.nodes, vec!["a:8136".to_string(), "b:8136".to_string()]);
+        assert_eq!(system.n_threads, ThreadCount::Fixed(8));
+
+        let mut config: toml::Value = toml::de::from_str("n-threads = 4\n").unwrap();
+        assert!(apply_override(&mut config, "n-threads.value", "1").is_err());
+    }
+
+    #[test]
+    fn auto_threads() {
+        let mut system: System = toml::de::from_str("n-threads = \"auto\"\nw-threads = \"auto\"\n").unwrap();
+        assert_eq!(system.thread_counts(8), (2, 6));
+        assert_eq!(system.thread_counts(1), (1, 1));
+        system.network_threads_ratio = 0.5;
+        system.w_threads = ThreadCount::Fixed(0);
+        assert_eq!(system.thread_counts(6), (3, 6));
+        assert_eq!(Value::try_from(&system).unwrap()["n-threads"], Value::String("auto".to_string()));
+        assert!(toml::de::from_str::<System>("n-threads = \"many\"\n").is_err());
+
+        assert_eq!(cgroup_cpu_limit(Some("max 100000\n"), None, None), None);
+        assert_eq!(cgroup_cpu_limit(Some("250000 100000\n"), None, None), Some(3));
+        assert_eq!(cgroup_cpu_limit(None, Some("-1\n"), Some("100000\n")), None);
+        assert_eq!(cgroup_cpu_limit(None, Some("200000\n"), Some("100000\n")), Some(2));
+    }
+
+    #[test]
+    fn secrets_from_files() {
+        let dir = env::temp_dir().join(format!("bioyino-secrets-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("token").display().to_string();
+        fs::write(&path, "from-file\n").unwrap();
+
+        let config = format!("[management]\nclient-token-file = {:?}\ntokens = [{{ token-file = {:?}, role = \"admin\" }}, {{ token = \"inline\", role = \"read-only\" }}]\n", path, path);
+        let mut system: System = toml::de::from_str(&config).unwrap();
+        system.management.load_secrets().unwrap();
+        assert_eq!(system.management.client_token, Some("from-file".to_string()));
+        assert_eq!(system.management.tokens[0].token, "from-file".to_string());
+        assert_eq!(system.management.tokens[1].token, "inline".to_string());
+
+        system.management.client_token_file = Some(path.clone());
+        assert!(system.management.load_secrets().is_err());
+
+        let config = format!("[incidents]\nsentry-dsn-file = {:?}\nwebhook = \"http://alerts\"\nwebhook-file = {:?}\n", path, path);
+        let mut system: System = toml::de::from_str(&config).unwrap();
+        assert!(system.incidents.load_secrets().is_err());
+        system.incidents.sentry_dsn = None;
+        system.incidents.webhook = None;
+        system.incidents.load_secrets().unwrap();
+        assert_eq!(system.incidents.sentry_dsn, Some("from-file".to_string()));
+        assert_eq!(system.incidents.webhook, Some("from-file".to_string()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn yaml_and_json() {
+        let yaml = "n-threads: 8\ncarbon:\n  interval: 10s\n  address: \"10.0.0.1:2003\"\nnetwork:\n  nodes:\n    - a:8136\n";
+        let json = r#"{"n-threads": 8, "carbon": {"interval": "10s", "address": "10.0.0.1:2003"}, "network": {"nodes": ["a:8136"]}}"#;
+        let yaml = System::from_source(yaml, ConfigFormat::Yaml).unwrap();
+        let json = System::from_source(json, ConfigFormat::Json).unwrap();
+        for system in &[yaml, json] {
+            assert_eq!(system.n_threads, ThreadCount::Fixed(8));
+            assert_eq!(system.carbon.interval, 10000);
+            assert_eq!(system.carbon.address, "10.0.0.1:2003".to_string());
+            assert_eq!(system.network.nodes, vec!["a:8136".to_string()]);
+        }
+        assert!(ConfigFormat::Yaml.parse("carbon:\n  address: ~\n").is_err());
+        assert_eq!(ConfigFormat::from_path(Path::new("/etc/bioyino/bioyino.yml")), Some(ConfigFormat::Yaml));
+        assert_eq!(ConfigFormat::from_path(Path::new("/etc/bioyino/bioyino.conf")), None);
+    }
+
+    #[test]
+    fn flush_offsets() {
+        let mut system: System = toml::de::from_str("[carbon]\ninterval = \"30s\"\nflush-offset = \"5s\"\n").unwrap();
+        assert_eq!(system.carbon.flush_offset_ms("node1"), Some(5000));
+        system.carbon.flush_offset = Some(FlushOffset::Fixed(65000));
+        assert_eq!(system.carbon.flush_offset_ms("node1"), Some(5000));
+
+        let system: System = toml::de::from_str("[carbon]\nflush-offset = \"hash\"\n").unwrap();
+        let first = system.carbon.flush_offset_ms("node1").unwrap();
+        assert!(first < system.carbon.interval);
+        assert_eq!(system.carbon.flush_offset_ms("node1"), Some(first));
+        assert_ne!(system.carbon.flush_offset_ms("node2"), Some(first));
+        assert_eq!(System::default().carbon.flush_offset_ms("node1"), None);
+    }
+
+    #[test]
+    fn fragments_merged() {
+        let mut base: toml::Value = toml::de::from_str("n-threads = 4\n[network]\nnodes = [\"a:8136\"]\nlisten = \"127.0.0.1:8125\"\n").unwrap();
+        let fragment: toml::Value = toml::de::from_str("n-threads = 8\n[network]\nnodes = [\"b:8136\"]\n[carbon]\ninterval = 1000\n").unwrap();
+        merge_toml(&mut base, fragment);
+        let expected: toml::Value = toml::de::from_str("n-threads = 8\n[network]\nnodes = [\"a:8136\", \"b:8136\"]\nlisten = \"127.0.0.1:8125\"\n[carbon]\ninterval = 1000\n").unwrap();
+        assert_eq!(base, expected);
+    }
+}
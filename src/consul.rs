@@ -1,7 +1,10 @@
+use std::cell::RefCell;
 use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::atomic::Ordering;
 use std::time::{Duration, Instant};
 
-use futures::future::{err, loop_fn, ok, Either, Future, IntoFuture, Loop};
+use futures::future::{err, ok, Future, IntoFuture};
 use futures::Stream;
 use hyper;
 use hyper::header::{HeaderValue, CONTENT_LENGTH, CONTENT_TYPE};
@@ -9,13 +12,15 @@ use hyper::{Method, StatusCode};
 use mime::WWW_FORM_URLENCODED;
 use serde_json::{self, from_slice};
 use slog::{Logger,o, warn, debug} ;
+use tokio::executor::current_thread::spawn;
 use tokio::timer::{self, Delay, Interval};
 
 use failure_derive::Fail;
 use serde_derive::Deserialize;
 
+use crate::cluster::{Clock, SystemClock};
 use crate::util::switch_leader;
-use crate::{ConsensusState, CONSENSUS_STATE};
+use crate::{ConsensusState, CONSENSUS_REACHABLE, CONSENSUS_STATE};
 
 #[derive(Fail, Debug)]
 pub enum ConsulError {
@@ -53,9 +58,206 @@ struct ConsulSessionResponse {
     id: String,
 }
 
-pub struct ConsulConsensus {
+// how often the lease is checked, renewing and retrying are done with their own intervals
+const LEASE_TICK: u64 = 100;
+
+/// A request of consensus to consul agent
+#[derive(Debug, Clone, PartialEq)]
+pub enum LeaseRequest {
+    CreateSession,
+    Renew(String),
+    Acquire(String),
+}
+
+impl LeaseRequest {
+    /// Answer to use when the real one did not come in time
+    pub fn timed_out(&self) -> LeaseAnswer {
+        match self {
+            LeaseRequest::CreateSession => LeaseAnswer::Session(Ok(None)),
+            LeaseRequest::Renew(_) => LeaseAnswer::Renew(Err(ConsulError::ConnectionTimeout)),
+            LeaseRequest::Acquire(_) => LeaseAnswer::Acquire(Err(ConsulError::ConnectionTimeout)),
+        }
+    }
+}
+
+/// Answer of consul agent to a `LeaseRequest`
+#[derive(Debug)]
+pub enum LeaseAnswer {
+    /// New session id, `None` if session was not created in time
+    Session(Result<Option<String>, ConsulError>),
+    Renew(Result<(), ConsulError>),
+    /// Whether the key is held by the session
+    Acquire(Result<bool, ConsulError>),
+}
+
+/// Send the request to consul agent with the transport, errors are a part of the answer
+pub fn send_lease_request<T: ConsulTransport>(transport: &T, request: LeaseRequest, key: &str, ttl: Duration) -> Box<Future<Item = LeaseAnswer, Error = ()>> {
+    match request {
+        LeaseRequest::CreateSession => Box::new(transport.create_session(ttl).then(|res| Ok(LeaseAnswer::Session(res)))),
+        LeaseRequest::Renew(session) => Box::new(transport.renew(session, ttl).then(|res| Ok(LeaseAnswer::Renew(res)))),
+        LeaseRequest::Acquire(session) => Box::new(transport.acquire(session, key.to_string()).then(|res| Ok(LeaseAnswer::Acquire(res)))),
+    }
+}
+
+/// Leadership logic of consul consensus without any I/O: it tells what to ask consul agent and when
+/// and changes state by the answers. All times are milliseconds of a `Clock`.
+#[derive(Debug, Clone)]
+pub struct ConsulLease {
+    ttl: u64,
+    renew_time: u64,
+    error_pause: u64,
+    session: Option<String>,
+    // no requests are made before this time
+    next_at: u64,
+    // answers to the current round of requests not received yet
+    waiting: usize,
+    // start of the current round, acquired key is held from this moment
+    round_at: u64,
+    reachable: bool,
+    // consul releases the key of a session that was not renewed for ttl, so another node may
+    // become a leader after that, leadership is given up by this time unless the key is acquired again
+    leader_until: Option<u64>,
+}
+
+impl ConsulLease {
+    pub fn new(ttl: Duration, renew_time: Duration, error_pause: Duration) -> Self {
+        let ms = |d: Duration| d.as_secs() * 1000 + d.subsec_millis() as u64;
+        Self { ttl: ms(ttl), renew_time: ms(renew_time), error_pause: ms(error_pause), session: None, next_at: 0, waiting: 0, round_at: 0, reachable: false, leader_until: None }
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.leader_until.is_some()
+    }
+
+    pub fn reachable(&self) -> bool {
+        self.reachable
+    }
+
+    /// Requests to send at `now`, empty if it is not time yet or answers to previous ones are awaited.
+    /// Leadership expires here too, so it should be called often. Nothing is requested if `enabled` is false.
+    pub fn poll(&mut self, now: u64, enabled: bool) -> Vec<LeaseRequest> {
+        if self.leader_until.map(|until| now >= until).unwrap_or(false) {
+            self.leader_until = None;
+        }
+        if self.waiting > 0 || now < self.next_at {
+            return Vec::new();
+        }
+        self.round_at = now;
+        let session = match self.session {
+            Some(ref session) => session.clone(),
+            None if enabled => {
+                self.waiting = 1;
+                return vec![LeaseRequest::CreateSession];
+            }
+            None => {
+                self.next_at = now + self.error_pause;
+                return Vec::new();
+            }
+        };
+        self.next_at = now + self.renew_time;
+        if !enabled {
+            return Vec::new();
+        }
+        self.waiting = 2;
+        vec![LeaseRequest::Renew(session.clone()), LeaseRequest::Acquire(session)]
+    }
+
+    /// Change state by the answer received at `now`
+    pub fn on_answer(&mut self, now: u64, answer: &LeaseAnswer) {
+        match answer {
+            LeaseAnswer::Session(Ok(session)) => self.on_session(now, Ok::<_, ()>(session.clone())),
+            LeaseAnswer::Session(Err(_)) => self.on_session(now, Err(())),
+            LeaseAnswer::Renew(res) => self.on_renew(now, res.as_ref().map(|_| ())),
+            LeaseAnswer::Acquire(res) => self.on_acquire(now, res.as_ref().map(|acquired| *acquired)),
+        }
+    }
+
+    fn on_session<E>(&mut self, now: u64, answer: Result<Option<String>, E>) {
+        self.waiting = 0;
+        match answer {
+            Ok(Some(session)) => {
+                self.reachable = true;
+                self.session = Some(session);
+                self.next_at = now + self.renew_time;
+            }
+            // timeout takes long enough by itself
+            Ok(None) => {
+                self.reachable = false;
+                self.next_at = now;
+            }
+            Err(_) => {
+                self.reachable = false;
+                self.next_at = now + self.error_pause;
+            }
+        }
+    }
+
+    fn on_renew<E>(&mut self, now: u64, answer: Result<(), E>) {
+        self.waiting = self.waiting.saturating_sub(1);
+        if answer.is_err() {
+            self.lose_session(now);
+        }
+    }
+
+    fn on_acquire<E>(&mut self, now: u64, answer: Result<bool, E>) {
+        self.waiting = self.waiting.saturating_sub(1);
+        match answer {
+            // the session may be lost by renew error in the same round
+            Ok(true) if self.session.is_some() => self.leader_until = Some(self.round_at + self.ttl),
+            Ok(true) => (),
+            Ok(false) => self.leader_until = None,
+            Err(_) => self.lose_session(now),
+        }
+    }
+
+    // a new session is created after a pause, leadership is kept until it expires by itself
+    fn lose_session(&mut self, now: u64) {
+        self.reachable = false;
+        if self.session.take().is_some() {
+            self.next_at = now + self.error_pause;
+        }
+    }
+}
+
+/// Consul agent API used by consensus. Answers are futures, so they may come from network or from a simulation.
+pub trait ConsulTransport {
+    /// Create a session, `None` if it was not created in time
+    fn create_session(&self, ttl: Duration) -> Box<Future<Item = Option<String>, Error = ConsulError>>;
+    fn renew(&self, session: String, ttl: Duration) -> Box<Future<Item = (), Error = ConsulError>>;
+    /// Try to lock the key with the session, `true` if the key is held by it now
+    fn acquire(&self, session: String, key: String) -> Box<Future<Item = bool, Error = ConsulError>>;
+}
+
+/// Transport to a real consul agent over HTTP
+pub struct HttpTransport {
     log: Logger,
     agent: SocketAddr,
+}
+
+impl HttpTransport {
+    pub fn new(log: &Logger, agent: SocketAddr) -> Self {
+        Self { log: log.clone(), agent }
+    }
+}
+
+impl ConsulTransport for HttpTransport {
+    fn create_session(&self, ttl: Duration) -> Box<Future<Item = Option<String>, Error = ConsulError>> {
+        ConsulSession { log: self.log.new(o!("source"=>"consul-session")), agent: self.agent, ttl }.into_future()
+    }
+
+    fn renew(&self, sid: String, ttl: Duration) -> Box<Future<Item = (), Error = ConsulError>> {
+        ConsulRenew { agent: self.agent, sid, ttl }.into_future()
+    }
+
+    fn acquire(&self, sid: String, key: String) -> Box<Future<Item = bool, Error = ConsulError>> {
+        ConsulAcquire { agent: self.agent, sid, key }.into_future()
+    }
+}
+
+pub struct ConsulConsensus<T: ConsulTransport = HttpTransport, C: Clock = SystemClock> {
+    log: Logger,
+    transport: T,
+    clock: C,
     key: String,
     session_ttl: Duration,
     renew_time: Duration,
@@ -64,9 +266,16 @@ pub struct ConsulConsensus {
 
 impl ConsulConsensus {
     pub fn new(log: &Logger, agent: SocketAddr, key: String) -> Self {
+        Self::with_transport(log, HttpTransport::new(log, agent), SystemClock, key)
+    }
+}
+
+impl<T: ConsulTransport, C: Clock> ConsulConsensus<T, C> {
+    pub fn with_transport(log: &Logger, transport: T, clock: C, key: String) -> Self {
         Self {
             log: log.new(o!("source"=>"consensus")),
-            agent,
+            transport,
+            clock,
             key: key,
             session_ttl: Duration::from_secs(7),
             renew_time: Duration::from_secs(1),
@@ -91,128 +300,51 @@ impl ConsulConsensus {
     }
 }
 
-impl IntoFuture for ConsulConsensus {
+impl<T: ConsulTransport + 'static, C: Clock + 'static> IntoFuture for ConsulConsensus<T, C> {
     type Item = ();
     type Error = ConsulError;
     type Future = Box<Future<Item = Self::Item, Error = Self::Error>>;
 
     fn into_future(self) -> Self::Future {
-        let Self {
-            log,
-            agent,
-            key,
-            session_ttl,
-            renew_time,
-            error_pause,
-        } = self;
-
-        let renew_loop = loop_fn((), move |()| {
-            let key = key.clone();
-            let log = log.clone();
-            let session = ConsulSession {
-                log: log.new(o!("source"=>"consul-session")),
-                agent: agent.clone(),
-                ttl: session_ttl.clone(),
-            };
-
-            let renewlog = log.clone();
-            /*
-               let session_retrier = BackoffRetryBuilder {
-               delay: 1000,
-               delay_mul: 1f32,
-               delay_max: 1000,
-               retries: ::std::usize::MAX,
-               };
-
-               let session = session_retrier.spawn(session);
-               */
-            // this tries to reconnect to consul infinitely
-            let loop_session = loop_fn(session, move |session| {
-                let log = log.clone();
-                let new_session = session.clone();
-                let should_connect = {
-                    let state = &*CONSENSUS_STATE.lock().unwrap();
-                    // connect to consul only in Enabled/Paused state
-                    state != &ConsensusState::Disabled
-                };
-
-                if should_connect {
-                    Either::A(session.into_future().then(move |res| match res {
-                        Err(e) => {
-                            warn!(log, "error getting consul session"; "error" => format!("{}", e));
-                            let new_session = new_session.clone();
-                            Box::new(
-                                Delay::new(Instant::now() + error_pause)
-                                .then(move |_| Ok(Loop::Continue(new_session))),
-                                )
-                                as Box<Future<Item = Loop<_, _>, Error = _>>
-                                //ok(Loop::Continue(new_session))
-                        }
-                        Ok(None) => {
-                            warn!(log, "timed out getting consul session");
-                            Box::new(ok(Loop::Continue(new_session)))
-                        }
-                        Ok(Some(s)) => Box::new(ok(Loop::Break(s))),
-                    }))
-                } else {
-                    Either::B(
-                        Delay::new(Instant::now() + error_pause)
-                        .then(move |_| Ok(Loop::Continue(new_session))),
-                        )
-                }
-            });
-
-            // the returned future will work until renew error
-            let renew =
-                // make connection, then start to renew
-                loop_session
-                .and_then(move |sid| {
-                    let timer = Interval::new(Instant::now()+renew_time, renew_time);
-
-                    timer.map_err(|e| ConsulError::Timer(e)).for_each(move |_| {
-                        let log = renewlog.clone();
-                        let should_renew = {
-                            let state = &*CONSENSUS_STATE.lock().unwrap();
-                            // renew the key only in Enabled/Paused state
-                            state != &ConsensusState::Disabled
-                        };
-                        if should_renew {
-                            let renew = ConsulRenew {
-                                agent: agent,
-                                sid: sid.clone(),
-                                ttl: session_ttl,
-                            }.into_future()
-                            .map_err(move |e| {
-                                warn!(log, "session renew error"; "error"=> format!("{}",e));
-                                e
-                            });
-
-                            let log = renewlog.clone();
-                            let acquire = ConsulAcquire {
-                                log: log.new(o!("source"=>"consul-acquire")),
-                                agent,
-                                sid: sid.clone(),
-                                key: key.clone(),
-                            }.into_future()
-                            .map_err(move |e| {
-                                warn!(log, "session acquire error"; "error"=>format!("{:?}", e));
-                                e
-                            });
-
-                            Either::A(renew.join(acquire).map(|_|()))
-                        } else {
-                            Either::B(ok(()))// as Box<Future<Item=(), Error=ConsulError>>)
-                        }
-                    })
+        let Self { log, transport, clock, key, session_ttl, renew_time, error_pause } = self;
+        let lease = Rc::new(RefCell::new(ConsulLease::new(session_ttl, renew_time, error_pause)));
+        let clock = Rc::new(clock);
+
+        let tick = Duration::from_millis(LEASE_TICK);
+        let timer = Interval::new(Instant::now(), tick).map_err(ConsulError::Timer).for_each(move |_| {
+            // connect to consul and renew the key only in Enabled/Paused state
+            let enabled = *CONSENSUS_STATE.lock().unwrap() != ConsensusState::Disabled;
+            let was_leader = lease.borrow().is_leader();
+            let requests = lease.borrow_mut().poll(clock.now_ms(), enabled);
+            if was_leader && !lease.borrow().is_leader() {
+                warn!(log, "consul session expired without renewing");
+                switch_leader(false, &log);
+            }
+            for request in requests {
+                let (lease, clock, log) = (lease.clone(), clock.clone(), log.clone());
+                // answers not received during session ttl are of no use anymore
+                let timed_out = request.timed_out();
+                let timeout = Delay::new(Instant::now() + session_ttl).then(move |_| Ok::<_, ()>(timed_out));
+                let answer = send_lease_request(&transport, request, &key, session_ttl).select(timeout).map(|(answer, _)| answer).map_err(|_| ()).map(move |answer| {
+                    match answer {
+                        LeaseAnswer::Session(Ok(None)) => warn!(log, "timed out getting consul session"),
+                        LeaseAnswer::Session(Err(ref e)) => warn!(log, "error getting consul session"; "error" => format!("{}", e)),
+                        LeaseAnswer::Renew(Err(ref e)) => warn!(log, "session renew error"; "error"=> format!("{}", e)),
+                        LeaseAnswer::Acquire(Err(ref e)) => warn!(log, "session acquire error"; "error"=>format!("{:?}", e)),
+                        _ => (),
+                    }
+                    let mut lease = lease.borrow_mut();
+                    lease.on_answer(clock.now_ms(), &answer);
+                    CONSENSUS_REACHABLE.store(lease.reachable(), Ordering::Relaxed);
+                    if let LeaseAnswer::Acquire(_) = answer {
+                        switch_leader(lease.is_leader(), &log);
+                    }
                 });
-
-            // restart the whole loop as soon as ANY future exits with any result
-            // (is is supposed to exit only with error)
-            renew.then(move |_| {
-                Delay::new(Instant::now() + error_pause).then(move |_| Ok(Loop::Continue(())))
-            })
+                spawn(answer);
+            }
+            Ok(())
         });
-        Box::new(renew_loop)
+        Box::new(timer)
     }
 }
 
@@ -362,20 +494,18 @@ impl IntoFuture for ConsulRenew {
 }
 
 pub struct ConsulAcquire {
-    log: Logger,
     agent: SocketAddr,
     sid: String,
     key: String,
 }
 
 impl IntoFuture for ConsulAcquire {
-    type Item = ();
+    type Item = bool;
     type Error = ConsulError;
     type Future = Box<Future<Item = Self::Item, Error = Self::Error>>;
 
     fn into_future(self) -> Self::Future {
         let Self {
-            log,
             agent,
             sid,
             key,
@@ -401,21 +531,7 @@ impl IntoFuture for ConsulAcquire {
                         let acquired: bool =
                             //try!(from_slice(&body).map_err(|e| ConsulError::Parsing(e)));
                             from_slice(&body).map_err(|e| ConsulError::Parsing(e))?;
-
-                        switch_leader(acquired, &log);
-                        // let should_set = {
-                        //let state = &*CONSENSUS_STATE.lock().unwrap();
-                        //// only set leader when consensus is enabled
-                        //state == &ConsensusState::Enabled
-                        //};
-                        //if should_set {
-                        //let is_leader = IS_LEADER.load(Ordering::SeqCst);
-                        //if is_leader != acquired {
-                        //warn!(log, "leader state change: {} -> {}", is_leader, acquired);
-                        //}
-                        //IS_LEADER.store(acquired, Ordering::SeqCst);
-                        //}
-                        Ok(())
+                        Ok(acquired)
                     })
             });
         Box::new(acquire)
@@ -0,0 +1,127 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use lazy_static::lazy_static;
+
+/// Maximum number of counters in the program, listener counters included
+pub const MAX_COUNTERS: usize = 128;
+
+// index the next counter gets on it's first use
+static NEXT_INDEX: AtomicUsize = AtomicUsize::new(0);
+const NO_INDEX: usize = usize::max_value();
+
+struct Cells {
+    values: Vec<AtomicUsize>,
+}
+
+impl Cells {
+    fn new() -> Self {
+        Self { values: (0..MAX_COUNTERS).map(|_| AtomicUsize::new(0)).collect() }
+    }
+}
+
+lazy_static! {
+    // cells of all running threads, values of finished threads are moved to RETIRED under the same lock,
+    // so they are never lost or counted twice
+    static ref THREAD_CELLS: Mutex<Vec<Arc<Cells>>> = Mutex::new(Vec::new());
+    static ref RETIRED: Cells = Cells::new();
+}
+
+struct LocalCells(Arc<Cells>);
+
+impl LocalCells {
+    fn register() -> Self {
+        let cells = Arc::new(Cells::new());
+        THREAD_CELLS.lock().unwrap().push(cells.clone());
+        LocalCells(cells)
+    }
+}
+
+impl Drop for LocalCells {
+    fn drop(&mut self) {
+        let mut cells = THREAD_CELLS.lock().unwrap();
+        for (retired, value) in RETIRED.values.iter().zip(self.0.values.iter()) {
+            retired.fetch_add(value.load(Ordering::Relaxed), Ordering::Relaxed);
+        }
+        cells.retain(|cells| !Arc::ptr_eq(cells, &self.0));
+    }
+}
+
+thread_local! {
+    static LOCAL: LocalCells = LocalCells::register();
+}
+
+/// Internal statistics counter. Every thread increments it's own cell without locked instructions,
+/// cells of all threads are summed when the counter is read, which only happens when stats are taken.
+/// New counters only need to be declared as statics.
+#[derive(Debug)]
+pub struct Counter {
+    index: AtomicUsize,
+}
+
+impl Counter {
+    pub const fn new() -> Self {
+        Self { index: AtomicUsize::new(NO_INDEX) }
+    }
+
+    fn index(&self) -> usize {
+        let index = self.index.load(Ordering::Relaxed);
+        if index != NO_INDEX {
+            return index;
+        }
+        let next = NEXT_INDEX.fetch_add(1, Ordering::Relaxed);
+        assert!(next < MAX_COUNTERS, "too many counters, MAX_COUNTERS should be increased");
+        // another thread may have given the counter an index already, the new one is wasted then
+        match self.index.compare_exchange(NO_INDEX, next, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => next,
+            Err(index) => index,
+        }
+    }
+
+    pub fn add(&self, value: usize) {
+        let index = self.index();
+        LOCAL
+            .try_with(|local| {
+                // the cell is only written by this thread, so there is no need to lock the bus
+                let cell = &local.0.values[index];
+                cell.store(cell.load(Ordering::Relaxed).wrapping_add(value), Ordering::Relaxed);
+            })
+            // thread is finishing and it's cells are gone already
+            .unwrap_or_else(|_| {
+                RETIRED.values[index].fetch_add(value, Ordering::Relaxed);
+            });
+    }
+
+    /// Sum of all threads, increments being done at the same time may be missed
+    pub fn get(&self) -> usize {
+        let index = self.index();
+        let cells = THREAD_CELLS.lock().unwrap();
+        cells.iter().fold(RETIRED.values[index].load(Ordering::Relaxed), |sum, cells| sum.wrapping_add(cells.values[index].load(Ordering::Relaxed)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn thread_counters() {
+        static FIRST: Counter = Counter::new();
+        static SECOND: Counter = Counter::new();
+        FIRST.add(1);
+        let threads = (0..4)
+            .map(|_| {
+                thread::spawn(|| {
+                    (0..1000).map(|_| FIRST.add(1)).last();
+                    SECOND.add(2);
+                })
+            })
+            .collect::<Vec<_>>();
+        threads.into_iter().map(|thread| thread.join().unwrap()).last();
+        // finished threads have their values retired
+        assert_eq!(FIRST.get(), 4001);
+        assert_eq!(SECOND.get(), 8);
+        assert_ne!(FIRST.index(), SECOND.index());
+    }
+}
@@ -0,0 +1,157 @@
+use std::str::FromStr;
+
+use serde_json::{self, Value};
+
+use crate::management::MgmtError;
+
+/// How `query` subcommand prints server answers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    /// Aligned columns for humans
+    Table,
+    /// JSON as returned by server, for scripts
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = MgmtError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(OutputFormat::Table),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(MgmtError::BadCommand),
+        }
+    }
+}
+
+fn scalar(value: &Value) -> String {
+    match value {
+        Value::Null => "-".to_string(),
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+// arrays of [name, value] pairs, like stats rates, are shown as objects
+fn as_pairs(items: &[Value]) -> Option<Vec<(String, &Value)>> {
+    items
+        .iter()
+        .map(|item| match item {
+            Value::Array(pair) if pair.len() == 2 => pair[0].as_str().map(|name| (name.to_string(), &pair[1])),
+            _ => None,
+        })
+        .collect()
+}
+
+// flatten nested objects to dot-separated keys, arrays of objects are collected separately to be shown as tables
+fn flatten<'a>(prefix: &str, value: &'a Value, rows: &mut Vec<(String, String)>, tables: &mut Vec<(String, &'a [Value])>) {
+    let key = |name: &str| if prefix.len() > 0 { format!("{}.{}", prefix, name) } else { name.to_string() };
+    match value {
+        Value::Object(map) => {
+            for (name, value) in map {
+                flatten(&key(name), value, rows, tables);
+            }
+        }
+        Value::Array(items) if items.len() > 0 && items.iter().all(|item| item.is_object() || item.is_null()) => tables.push((prefix.to_string(), items)),
+        Value::Array(items) => match as_pairs(items) {
+            Some(ref pairs) if pairs.len() > 0 => {
+                for (name, value) in pairs {
+                    flatten(&key(name), value, rows, tables);
+                }
+            }
+            _ => rows.push((prefix.to_string(), items.iter().map(scalar).collect::<Vec<_>>().join(","))),
+        },
+        other => rows.push((prefix.to_string(), scalar(other))),
+    }
+}
+
+fn write_columns(out: &mut String, lines: &[Vec<String>]) {
+    let columns = lines.iter().map(|line| line.len()).max().unwrap_or(0);
+    let widths = (0..columns).map(|idx| lines.iter().filter_map(|line| line.get(idx)).map(|cell| cell.chars().count()).max().unwrap_or(0)).collect::<Vec<_>>();
+    for line in lines {
+        let cells = line.iter().zip(widths.iter()).map(|(cell, width)| format!("{:width$}", cell, width = width)).collect::<Vec<_>>();
+        out.push_str(cells.join("  ").trim_end());
+        out.push('\n');
+    }
+}
+
+// objects become rows with columns named by their flattened keys, null items, like workers not answering, are rows of dashes
+fn write_table(out: &mut String, items: &[Value]) {
+    let mut header: Vec<String> = Vec::new();
+    let mut rows = Vec::new();
+    for item in items {
+        let (mut row, mut nested) = (Vec::new(), Vec::new());
+        if !item.is_null() {
+            // tables nested into table cells are not shown
+            flatten("", item, &mut row, &mut nested);
+        }
+        for (key, _) in &row {
+            if !header.contains(key) {
+                header.push(key.clone());
+            }
+        }
+        rows.push(row);
+    }
+    let mut lines = vec![header.iter().map(|key| key.to_uppercase()).collect::<Vec<_>>()];
+    for row in rows {
+        lines.push(header.iter().map(|key| row.iter().find(|(name, _)| name == key).map(|(_, value)| value.clone()).unwrap_or("-".to_string())).collect());
+    }
+    write_columns(out, &lines);
+}
+
+/// Render server answer for printing
+pub fn render(value: &Value, format: OutputFormat) -> String {
+    if format == OutputFormat::Json {
+        // a value that was just parsed can always be serialized back
+        return serde_json::to_string_pretty(value).unwrap();
+    }
+    let mut out = String::new();
+    if let Value::Array(items) = value {
+        write_table(&mut out, items);
+        return out;
+    }
+    let (mut rows, mut tables) = (Vec::new(), Vec::new());
+    flatten("", value, &mut rows, &mut tables);
+    write_columns(&mut out, &rows.into_iter().map(|(key, value)| vec![key, value]).collect::<Vec<_>>());
+    for (name, items) in tables {
+        out.push_str(&format!("\n{}:\n", name));
+        write_table(&mut out, items);
+    }
+    out
+}
+
+/// Render one server-sent event of tail endpoint, comments and keepalives give `None`
+pub fn render_event(event: &str, format: OutputFormat) -> Option<String> {
+    let data = event.lines().filter(|line| line.starts_with("data:")).map(|line| line["data:".len()..].trim()).collect::<Vec<_>>().join("\n");
+    if data.len() == 0 {
+        return None;
+    }
+    match (format, serde_json::from_str::<Value>(&data)) {
+        (OutputFormat::Table, Ok(sample)) => Some(format!("{}  {}  {}", scalar(&sample["type"]), scalar(&sample["value"]), scalar(&sample["name"]))),
+        _ => Some(data),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn table_output() {
+        let value = json!({
+            "uptime-ms": 10,
+            "consensus": { "kind": "none", "reachable": true },
+            "rates": [["egress", 1.5], ["ingress", 2.0]],
+            "peers": [{ "address": "node1:8136", "alive": true }, { "address": "node2:8136", "alive": false, "last-sent": 5 }],
+        });
+        let rendered = render(&value, OutputFormat::Table);
+        assert!(rendered.contains("consensus.kind       none\n"));
+        assert!(rendered.contains("rates.ingress        2.0\n"));
+        assert!(rendered.contains("\npeers:\nADDRESS     ALIVE  LAST-SENT\nnode1:8136  true   -\nnode2:8136  false  5\n"));
+
+        assert_eq!(render_event(": tailing a.*\n\n", OutputFormat::Table), None);
+        assert_eq!(render_event("data: {\"name\":\"a.b\",\"type\":\"counter\",\"value\":1.0}\n\n", OutputFormat::Table), Some("counter  1.0  a.b".to_string()));
+    }
+}
@@ -0,0 +1,213 @@
+use std::env;
+use std::ffi::CString;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::process;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+
+use crate::config::Daemon;
+use crate::errors::GeneralError;
+
+lazy_static! {
+    // pid file written by this process, removed on exit
+    static ref PID_FILE: Mutex<Option<String>> = Mutex::new(None);
+}
+
+fn daemon_error(what: &str, e: io::Error) -> GeneralError {
+    GeneralError::Daemon(format!("{}: {}", what, e))
+}
+
+/// Parse permission mask written in octal, like "027"
+pub fn parse_umask(umask: &str) -> Result<libc::mode_t, String> {
+    match u32::from_str_radix(umask.trim(), 8) {
+        Ok(mask) if mask <= 0o777 => Ok(mask as libc::mode_t),
+        _ => Err(format!("bad umask {:?}, expected octal number up to 777", umask)),
+    }
+}
+
+/// Process id in pid file contents, `None` if there is none
+pub fn read_pid(contents: &str) -> Option<libc::pid_t> {
+    contents.trim().parse::<libc::pid_t>().ok().filter(|pid| *pid > 0)
+}
+
+fn process_alive(pid: libc::pid_t) -> bool {
+    // permission error means the process exists, but belongs to another user
+    unsafe { libc::kill(pid, 0) == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM) }
+}
+
+// blocking write of the whole buffer to a raw descriptor
+fn write_fd(fd: libc::c_int, buf: &[u8]) {
+    let mut written = 0;
+    while written < buf.len() {
+        let n = unsafe { libc::write(fd, buf[written..].as_ptr() as *const libc::c_void, buf.len() - written) };
+        if n <= 0 {
+            return;
+        }
+        written += n as usize;
+    }
+}
+
+// fork and leave the session in the child, the parent exits when the grandchild reports it has started,
+// with the status and the error it reports
+fn detach() -> Result<libc::c_int, GeneralError> {
+    let mut fds = [0 as libc::c_int; 2];
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(daemon_error("creating pipe", io::Error::last_os_error()));
+    }
+    match unsafe { libc::fork() } {
+        -1 => return Err(daemon_error("forking", io::Error::last_os_error())),
+        0 => (),
+        _ => {
+            unsafe { libc::close(fds[1]) };
+            let mut report = Vec::new();
+            let mut buf = [0u8; 512];
+            loop {
+                match unsafe { libc::read(fds[0], buf.as_mut_ptr() as *mut libc::c_void, buf.len()) } {
+                    n if n > 0 => report.extend_from_slice(&buf[..n as usize]),
+                    _ => break,
+                }
+            }
+            // nothing is reported if the daemon died before starting
+            match report.split_first() {
+                Some((&0, _)) => process::exit(0),
+                Some((_, error)) => println!("error: {}", String::from_utf8_lossy(error)),
+                None => println!("error: daemon exited before starting"),
+            }
+            process::exit(1);
+        }
+    }
+    unsafe { libc::close(fds[0]) };
+    if unsafe { libc::setsid() } == -1 {
+        return Err(daemon_error("starting session", io::Error::last_os_error()));
+    }
+    // the second fork makes the daemon not a session leader, so it never gets a controlling terminal
+    match unsafe { libc::fork() } {
+        -1 => Err(daemon_error("forking", io::Error::last_os_error())),
+        0 => Ok(fds[1]),
+        _ => unsafe { libc::_exit(0) },
+    }
+}
+
+fn null_stdio() -> Result<(), GeneralError> {
+    let path = CString::new("/dev/null").unwrap();
+    let null = unsafe { libc::open(path.as_ptr(), libc::O_RDWR) };
+    if null < 0 {
+        return Err(daemon_error("opening /dev/null", io::Error::last_os_error()));
+    }
+    for fd in 0..3 {
+        if unsafe { libc::dup2(null, fd) } < 0 {
+            return Err(daemon_error("redirecting standard streams", io::Error::last_os_error()));
+        }
+    }
+    if null > 2 {
+        unsafe { libc::close(null) };
+    }
+    Ok(())
+}
+
+fn write_pid_file(path: &str) -> Result<(), GeneralError> {
+    // written to a temporary file and renamed, so the file is never seen empty
+    let tmp = format!("{}.tmp", path);
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&tmp)
+        .and_then(|mut file| file.write_all(format!("{}\n", process::id()).as_bytes()))
+        .and_then(|_| fs::rename(&tmp, path))
+        .map_err(|e| daemon_error(&format!("writing pid file {}", path), e))?;
+    *PID_FILE.lock().unwrap() = Some(path.to_string());
+    Ok(())
+}
+
+// checked before detaching, so the error is seen on the terminal, and while the parent is known: the process
+// handing sockets off to this one is it's parent and is still running
+fn check_pid_file(path: &str) -> Result<(), GeneralError> {
+    match fs::read_to_string(path).ok().as_ref().and_then(|contents| read_pid(contents)) {
+        Some(pid) if pid != unsafe { libc::getpid() } && pid != unsafe { libc::getppid() } && process_alive(pid) => Err(GeneralError::Daemon(format!("pid file {} names running process {}", path, pid))),
+        _ => Ok(()),
+    }
+}
+
+fn start(options: &Daemon, detached: bool) -> Result<(), GeneralError> {
+    if let Some(ref umask) = options.umask {
+        let mask = parse_umask(umask).map_err(GeneralError::Daemon)?;
+        unsafe { libc::umask(mask) };
+    }
+    // relative to the directory the server is started in, the path is kept absolute to be removed on exit
+    if let Some(ref path) = options.pid_file {
+        let path = env::current_dir().map_err(|e| daemon_error("getting current directory", e))?.join(path);
+        write_pid_file(&path.to_string_lossy())?;
+    }
+    // a detached process does not keep the directory it was started in busy
+    let work_dir = options.work_dir.clone().or_else(|| if detached { Some("/".to_string()) } else { None });
+    if let Some(ref dir) = work_dir {
+        env::set_current_dir(dir).map_err(|e| daemon_error(&format!("changing directory to {}", dir), e))?;
+    }
+    if detached {
+        null_stdio()?;
+    }
+    Ok(())
+}
+
+/// Apply `daemon` options: detach from the terminal, set umask and working directory and write the pid file.
+/// When detaching, the command started exits after the daemon is started, with an error if it could not.
+/// Must be called before any thread is started, because forking keeps only the calling thread.
+pub fn daemonize(options: &Daemon) -> Result<(), GeneralError> {
+    if let Some(ref path) = options.pid_file {
+        check_pid_file(path)?;
+    }
+    if !options.detach {
+        return start(options, false);
+    }
+    let report = detach()?;
+    match start(options, true) {
+        Ok(()) => {
+            write_fd(report, &[0]);
+            unsafe { libc::close(report) };
+            Ok(())
+        }
+        Err(e) => {
+            write_fd(report, format!("\x01{}", e).as_bytes());
+            process::exit(1);
+        }
+    }
+}
+
+/// Remove the pid file on exit, unless the next process taking over after handoff has written it's own pid already
+pub fn remove_pid_file() {
+    if let Some(path) = PID_FILE.lock().unwrap().take() {
+        let own = fs::read_to_string(&path).ok().as_ref().and_then(|contents| read_pid(contents)) == Some(process::id() as libc::pid_t);
+        if own {
+            // the file may be not writable after privileges are dropped, nothing can be done on exit then
+            fs::remove_file(&path).unwrap_or(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn daemon_options() {
+        assert_eq!(parse_umask("027"), Ok(0o027));
+        assert_eq!(parse_umask("0022"), Ok(0o022));
+        assert!(parse_umask("999").is_err());
+        assert!(parse_umask("1777").is_err());
+
+        assert_eq!(read_pid("1234\n"), Some(1234));
+        assert_eq!(read_pid(""), None);
+        assert_eq!(read_pid("-1"), None);
+
+        let path = std::env::temp_dir().join(format!("bioyino-pid-{}", process::id()));
+        let path = path.to_str().unwrap();
+        write_pid_file(path).unwrap();
+        assert_eq!(read_pid(&fs::read_to_string(path).unwrap()), Some(process::id() as libc::pid_t));
+        assert!(check_pid_file(path).is_ok());
+        remove_pid_file();
+        assert!(fs::metadata(path).is_err());
+    }
+}
@@ -26,4 +26,70 @@ pub enum GeneralError {
 
     #[fail(display = "configuration error: {}", _0)]
     Configuration(&'static str),
+
+    #[fail(display = "substituting environment variables in configuration: {}", _0)]
+    Interpolation(String),
+
+    #[fail(display = "in included configuration file {}: {}", _0, _1)]
+    Fragment(String, String),
+
+    #[fail(display = "overriding option {}: {}", _0, _1)]
+    Override(String, String),
+
+    #[fail(display = "reading secret from {}: {}", _0, _1)]
+    Secret(String, String),
+
+    #[fail(display = "parsing {} configuration: {}", _0, _1)]
+    ConfigSyntax(&'static str, String),
+
+    #[fail(display = "parsing configuration: {}", _0)]
+    ConfigParse(#[cause] ::toml::de::Error),
+
+    #[fail(display = "converting configuration: {}", _0)]
+    ConfigConvert(#[cause] ::toml::ser::Error),
+
+    #[fail(display = "failed resolving {}", _0)]
+    Resolve(String),
+
+    #[fail(display = "applying {} section of configuration failed, all changes are rolled back", _0)]
+    ReloadRollback(String, crate::reload::ReloadReport),
+
+    #[fail(display = "TLS configuration: {}", _0)]
+    Tls(String),
+
+    #[fail(display = "bad network {}: {}", _0, _1)]
+    BadNetwork(String, String),
+
+    #[fail(display = "dropping privileges: {}", _0)]
+    Privileges(String),
+
+    #[fail(display = "sandboxing: {}", _0)]
+    Sandbox(String),
+
+    #[fail(display = "bad quota of prefix {:?}: {}", _0, _1)]
+    Quota(String, String),
+
+    #[fail(display = "cache state file {}: {}", _0, _1)]
+    State(String, String),
+
+    #[fail(display = "{} is not available: {}", _0, _1)]
+    Startup(String, String),
+
+    #[fail(display = "spool file {}: {}", _0, _1)]
+    Spool(String, String),
+
+    #[fail(display = "changing workers: {}", _0)]
+    Workers(String),
+
+    #[fail(display = "daemonizing: {}", _0)]
+    Daemon(String),
+
+    #[fail(display = "window {}: {}", _0, _1)]
+    Window(String, String),
+
+    #[fail(display = "flush mark file {}: {}", _0, _1)]
+    FlushMark(String, String),
+
+    #[fail(display = "bad relabel rule for {:?}: {}", _0, _1)]
+    Relabel(String, String),
 }
@@ -0,0 +1,98 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::cluster::now_ms;
+
+lazy_static! {
+    pub static ref EVENTS: EventLog = EventLog::new(256);
+}
+
+/// A significant change of server state
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Event {
+    /// Number of the event since start, so clients can ask only for new ones
+    pub id: u64,
+    pub at_ms: u64,
+    /// What has changed: leader, backend, reload, memory, panic, health, shutdown, maintenance, workers or windows
+    pub kind: String,
+    pub message: String,
+}
+
+#[derive(Debug)]
+struct Ring {
+    capacity: usize,
+    next_id: u64,
+    events: VecDeque<Event>,
+}
+
+/// Last events kept in a ring, older ones are dropped when it is full
+#[derive(Debug)]
+pub struct EventLog {
+    ring: Mutex<Ring>,
+}
+
+impl EventLog {
+    fn new(capacity: usize) -> Self {
+        Self { ring: Mutex::new(Ring { capacity, next_id: 1, events: VecDeque::with_capacity(capacity) }) }
+    }
+
+    /// Number of events kept, 0 disables the log
+    pub fn set_capacity(&self, capacity: usize) {
+        let mut ring = self.ring.lock().unwrap();
+        ring.capacity = capacity;
+        while ring.events.len() > capacity {
+            ring.events.pop_front();
+        }
+    }
+
+    pub fn record(&self, kind: &str, message: String) {
+        let mut ring = self.ring.lock().unwrap();
+        if ring.capacity == 0 {
+            return;
+        }
+        if ring.events.len() >= ring.capacity {
+            ring.events.pop_front();
+        }
+        let id = ring.next_id;
+        ring.next_id += 1;
+        ring.events.push_back(Event { id, at_ms: now_ms(), kind: kind.to_string(), message });
+    }
+
+    /// Events with id bigger than `since` and of the kind if it is set, oldest first
+    pub fn list(&self, since: u64, kind: Option<&str>) -> Vec<Event> {
+        let ring = self.ring.lock().unwrap();
+        ring.events.iter().filter(|event| event.id > since && kind.map(|kind| event.kind == kind).unwrap_or(true)).cloned().collect()
+    }
+}
+
+/// Record an event to the global log
+pub fn event(kind: &str, message: String) {
+    EVENTS.record(kind, message);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_ring() {
+        let events = EventLog::new(3);
+        events.record("leader", "became leader".to_string());
+        events.record("backend", "backend failed".to_string());
+        events.record("backend", "backend recovered".to_string());
+        events.record("reload", "config reloaded".to_string());
+        let all = events.list(0, None);
+        assert_eq!(all.iter().map(|event| event.id).collect::<Vec<_>>(), vec![2, 3, 4]);
+        assert_eq!(events.list(2, Some("backend")).iter().map(|event| event.message.as_str()).collect::<Vec<_>>(), vec!["backend recovered"]);
+
+        events.set_capacity(1);
+        assert_eq!(events.list(0, None)[0].kind, "reload");
+        events.set_capacity(0);
+        events.record("memory", "pressure increased".to_string());
+        assert_eq!(events.list(0, None).len(), 0);
+    }
+}
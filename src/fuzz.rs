@@ -0,0 +1,79 @@
+//! Entry points for fuzzing, built with `fuzzing` feature. Every function takes arbitrary bytes, feeds
+//! them to one of the decoders the server exposes to the network or to the operator and must never
+//! panic whatever the input is. Targets for cargo-fuzz calling them are in `fuzz/` directory.
+use std::str;
+
+use bioyino_metric::parser::ParseErrorHandler;
+
+use crate::config::{ConfigFormat, System};
+use crate::parser::StatsdParser;
+use crate::peer::decode_message;
+
+// the same limit workers use for the tail of a buffer without newline
+const MAX_UNPARSED: usize = 1024;
+
+struct IgnoreErrors;
+
+impl ParseErrorHandler for IgnoreErrors {
+    fn handle(&self, _: &[u8], _: usize) {}
+}
+
+/// Parse a buffer of statsd lines as worker does, returns the number of parsed metrics
+pub fn fuzz_statsd(data: &[u8]) -> usize {
+    let mut parser = StatsdParser::new(data, MAX_UNPARSED, IgnoreErrors);
+    let count = parser.by_ref().count();
+    assert!(parser.consumed() <= data.len());
+    count
+}
+
+/// Decode a capnp message as peer server does, returns the number of decoded metrics
+pub fn fuzz_capnp(data: &[u8]) -> usize {
+    decode_message(data).map(|metrics| metrics.len()).unwrap_or(0)
+}
+
+/// Deserialize configuration, the first byte chooses the format, the rest is the text of configuration.
+/// Returns whether the configuration was accepted.
+pub fn fuzz_config(data: &[u8]) -> bool {
+    let (format, input) = match data.split_first() {
+        Some((format, input)) => (*format, input),
+        None => return false,
+    };
+    let format = match format % 3 {
+        0 => ConfigFormat::Toml,
+        1 => ConfigFormat::Yaml,
+        _ => ConfigFormat::Json,
+    };
+    match str::from_utf8(input) {
+        Ok(input) => System::from_source(input, format).is_ok(),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bioyino_metric::{Metric, MetricType};
+
+    use crate::peer::serialize_snapshot;
+    use crate::Cache;
+
+    #[test]
+    fn fuzz_entry_points() {
+        assert_eq!(fuzz_statsd(b"a:1|c\nb:2|g\nbad\nc:"), 2);
+        assert_eq!(fuzz_statsd(b""), 0);
+
+        let mut cache = Cache::new();
+        cache.insert(crate::intern::intern(b"fuzz.metric"), Metric::new(1f64, MetricType::Counter, None, None).unwrap());
+        let buf = serialize_snapshot(&[cache]).unwrap();
+        assert_eq!(fuzz_capnp(&buf), 1);
+        assert_eq!(fuzz_capnp(&buf[1..]), 0);
+        assert_eq!(fuzz_capnp(b"\xff\xff\xff\xff"), 0);
+
+        assert!(fuzz_config(b"\x00verbosity = \"info\"\n"));
+        assert!(fuzz_config(b"\x02{}"));
+        assert!(!fuzz_config(b"\x01n-threads: [\n"));
+        assert!(!fuzz_config(b"\x00\xff"));
+        assert!(!fuzz_config(b""));
+    }
+}
@@ -0,0 +1,158 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::mem;
+use std::sync::Mutex;
+use std::time::{self, Duration, SystemTime};
+
+use bytes::Bytes;
+use lazy_static::lazy_static;
+use serde_derive::{Deserialize, Serialize};
+use slog::{info, warn, Logger};
+
+use crate::config::{BackfillValue, Carbon};
+use crate::errors::GeneralError;
+use crate::events::event;
+use crate::{Float, BACKFILLED_POINTS, FLUSH_GAPS};
+
+lazy_static! {
+    // the mark as last written, last values of metrics not sent since start are kept from the file
+    static ref MARK: Mutex<FlushMark> = Mutex::new(FlushMark::default());
+    // points for intervals missed while the server was down, sent with the first flush
+    static ref BACKFILL: Mutex<Vec<(Duration, Vec<(Bytes, Float)>)>> = Mutex::new(Vec::new());
+}
+
+/// Contents of `carbon.flush-mark-file`
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct FlushMark {
+    /// Timestamp of the last interval flushed completely, seconds since UNIX epoch
+    pub timestamp: u64,
+    /// Last values sent for metrics in `carbon.backfill`
+    pub last_values: BTreeMap<String, Float>,
+}
+
+/// Timestamps of intervals that should have been flushed after the one at `last` and before `now`
+pub fn missed_intervals(last: u64, now: u64, interval: u64) -> Vec<u64> {
+    let interval = interval.max(1);
+    (1..).map(|n| last + n * interval).take_while(|ts| *ts < now).collect()
+}
+
+/// Points of backfilled metrics for every missed interval, metrics with no last value known are not backfilled
+/// with `BackfillValue::Last`
+pub fn backfill_points(missed: &[u64], names: &[String], value: BackfillValue, last_values: &BTreeMap<String, Float>) -> Vec<(Duration, Vec<(Bytes, Float)>)> {
+    let points = names
+        .iter()
+        .filter_map(|name| match value {
+            BackfillValue::Zero => Some((Bytes::from(name.as_bytes()), 0f64)),
+            BackfillValue::Last => last_values.get(name).map(|last| (Bytes::from(name.as_bytes()), *last)),
+        })
+        .collect::<Vec<_>>();
+    if points.len() == 0 {
+        return Vec::new();
+    }
+    missed.iter().map(|ts| (Duration::from_secs(*ts), points.clone())).collect()
+}
+
+/// Values of metrics to backfill among the ones being flushed
+pub fn last_values(metrics: &[(Bytes, Float)], names: &HashSet<Bytes>) -> BTreeMap<String, Float> {
+    if names.len() == 0 {
+        return BTreeMap::new();
+    }
+    metrics.iter().filter(|(name, _)| names.contains(name)).map(|(name, value)| (String::from_utf8_lossy(name).into_owned(), *value)).collect()
+}
+
+/// Read the mark left by the previous run and look for intervals it did not flush. Missed intervals are counted in
+/// `flush-gap` stat and, if the gap is not longer than `carbon.backfill-max-gap`, points for `carbon.backfill` metrics
+/// are prepared for them to be sent with the first flush. Returns the number of missed intervals.
+pub fn detect_gap(options: &Carbon, log: &Logger) -> Result<usize, GeneralError> {
+    let path = match options.flush_mark_file {
+        Some(ref path) => path,
+        None => return Ok(0),
+    };
+    let mark_error = |e: String| GeneralError::FlushMark(path.clone(), e);
+    let mark = match fs::read(path) {
+        Ok(data) => serde_json::from_slice::<FlushMark>(&data).map_err(|e| mark_error(e.to_string()))?,
+        // nothing was flushed yet
+        Err(_) => return Ok(0),
+    };
+    *MARK.lock().unwrap() = mark.clone();
+
+    let now = SystemTime::now().duration_since(time::UNIX_EPOCH).map_err(GeneralError::Time)?.as_secs();
+    let missed = missed_intervals(mark.timestamp, now, options.interval / 1000);
+    if missed.len() == 0 {
+        return Ok(0);
+    }
+    FLUSH_GAPS.add(missed.len());
+    let gap = now - mark.timestamp;
+    warn!(log, "intervals were not flushed before start"; "missed"=>missed.len(), "last-flushed"=>mark.timestamp, "gap-s"=>gap);
+    event("backend", format!("{} intervals were not flushed before start, last one flushed at {}", missed.len(), mark.timestamp));
+
+    if options.backfill.len() > 0 {
+        if gap * 1000 > options.backfill_max_gap {
+            info!(log, "gap is longer than carbon.backfill-max-gap, not backfilling"; "gap-s"=>gap);
+        } else {
+            let points = backfill_points(&missed, &options.backfill, options.backfill_value, &mark.last_values);
+            info!(log, "backfilling missed intervals"; "intervals"=>points.len(), "metrics"=>points.first().map(|(_, points)| points.len()).unwrap_or(0));
+            *BACKFILL.lock().unwrap() = points;
+        }
+    }
+    Ok(missed.len())
+}
+
+/// Take points prepared to backfill missed intervals, the first flush takes them whether it sends them or not,
+/// they are counted as backfilled if `sending`
+pub fn take_backfill(sending: bool) -> Vec<(Duration, Vec<(Bytes, Float)>)> {
+    let points = mem::replace(&mut *BACKFILL.lock().unwrap(), Vec::new());
+    if sending {
+        BACKFILLED_POINTS.add(points.iter().map(|(_, points)| points.len()).sum());
+    }
+    points
+}
+
+/// Record the interval at `ts` as flushed along with last values of metrics sent in it. Marks of older intervals,
+/// like paused or backfilled ones, only add last values.
+pub fn mark_flushed(path: &str, ts: Duration, values: BTreeMap<String, Float>) -> Result<(), GeneralError> {
+    let mark_error = |e: String| GeneralError::FlushMark(path.to_string(), e);
+    let mut mark = MARK.lock().unwrap();
+    mark.timestamp = mark.timestamp.max(ts.as_secs());
+    mark.last_values.extend(values);
+    let data = serde_json::to_vec(&*mark).map_err(|e| mark_error(e.to_string()))?;
+    // renamed, so the mark is never seen half-written after a crash
+    let tmp = format!("{}.tmp", path);
+    fs::write(&tmp, &data).and_then(|_| fs::rename(&tmp, path)).map_err(|e| mark_error(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flush_gaps() {
+        assert_eq!(missed_intervals(1000, 1031, 30), vec![1030]);
+        assert_eq!(missed_intervals(1000, 1030, 30), Vec::<u64>::new());
+        assert_eq!(missed_intervals(1000, 1100, 30), vec![1030, 1060, 1090]);
+
+        let names = vec!["critical.requests".to_string(), "critical.errors".to_string()];
+        let mut last = BTreeMap::new();
+        last.insert("critical.requests".to_string(), 42f64);
+        let zero = backfill_points(&[1030, 1060], &names, BackfillValue::Zero, &last);
+        assert_eq!(zero.len(), 2);
+        assert_eq!(zero[1], (Duration::from_secs(1060), vec![(Bytes::from("critical.requests"), 0f64), (Bytes::from("critical.errors"), 0f64)]));
+        let kept = backfill_points(&[1030], &names, BackfillValue::Last, &last);
+        assert_eq!(kept, vec![(Duration::from_secs(1030), vec![(Bytes::from("critical.requests"), 42f64)])]);
+        assert_eq!(backfill_points(&[1030], &names, BackfillValue::Last, &BTreeMap::new()), Vec::new());
+
+        let wanted = names.iter().map(|name| Bytes::from(name.as_bytes())).collect::<HashSet<_>>();
+        let metrics = vec![(Bytes::from("critical.errors"), 3f64), (Bytes::from("other"), 1f64)];
+        assert_eq!(last_values(&metrics, &wanted).into_iter().collect::<Vec<_>>(), vec![("critical.errors".to_string(), 3f64)]);
+
+        let path = std::env::temp_dir().join(format!("bioyino-mark-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        mark_flushed(path, Duration::from_secs(1060), last_values(&metrics, &wanted)).unwrap();
+        mark_flushed(path, Duration::from_secs(1030), BTreeMap::new()).unwrap();
+        let mark: FlushMark = serde_json::from_slice(&fs::read(path).unwrap()).unwrap();
+        fs::remove_file(path).unwrap();
+        assert_eq!(mark.timestamp, 1060);
+        assert_eq!(mark.last_values.get("critical.errors"), Some(&3f64));
+    }
+}
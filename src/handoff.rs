@@ -0,0 +1,246 @@
+use std::env;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::process::Command;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use futures::sync::mpsc::Sender;
+use slog::{info, o, warn, Logger};
+use tokio::runtime::current_thread::Runtime;
+
+use crate::activation::{close_unused, pass_socket, taken_tcp, STATSD_SOCKET};
+use crate::config::Shutdown;
+use crate::events::event;
+use crate::shutdown::{send_state, stop, take_state, SHUTTING_DOWN};
+use crate::task::Task;
+use crate::udp::STATSD_UDP_SOCKET;
+use crate::workers::WorkerSet;
+
+// descriptors passed in one message, far more than there are listeners
+const MAX_FDS: usize = 64;
+// sent by the new process when it's listeners are started
+const READY: u8 = b'R';
+
+/// Set when listening sockets are read by the new process, network threads stop reading them
+pub static HANDED_OFF: AtomicBool = AtomicBool::new(false);
+
+/// Send `data` with descriptors attached as SCM_RIGHTS, the receiver gets it's own copies of them
+pub fn send_fds(stream: &UnixStream, data: &[u8], fds: &[RawFd]) -> io::Result<()> {
+    if fds.len() == 0 || fds.len() > MAX_FDS {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("cannot pass {} descriptors", fds.len())));
+    }
+    let fds_len = (fds.len() * mem::size_of::<RawFd>()) as u32;
+    let mut control = vec![0u8; unsafe { libc::CMSG_SPACE(fds_len) } as usize];
+    let mut iov = libc::iovec { iov_base: data.as_ptr() as *mut libc::c_void, iov_len: data.len() };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = control.len() as _;
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(fds_len) as _;
+        ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg) as *mut RawFd, fds.len());
+    }
+    if unsafe { libc::sendmsg(stream.as_raw_fd(), &msg, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Receive data sent by `send_fds` with descriptors attached to it, they are closed on exec
+pub fn recv_fds(stream: &UnixStream) -> io::Result<(Vec<u8>, Vec<RawFd>)> {
+    let mut data = vec![0u8; 4096];
+    let mut control = vec![0u8; unsafe { libc::CMSG_SPACE((MAX_FDS * mem::size_of::<RawFd>()) as u32) } as usize];
+    let mut iov = libc::iovec { iov_base: data.as_mut_ptr() as *mut libc::c_void, iov_len: data.len() };
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = control.len() as _;
+    let received = unsafe { libc::recvmsg(stream.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    data.truncate(received as usize);
+
+    let mut fds = Vec::new();
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let count = ((*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize) / mem::size_of::<RawFd>();
+                let first = libc::CMSG_DATA(cmsg) as *const RawFd;
+                fds.extend((0..count).map(|idx| ptr::read_unaligned(first.add(idx))));
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        fds.drain(..).map(|fd| unsafe { libc::close(fd) }).last();
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "too many descriptors passed"));
+    }
+    if received == 0 && fds.len() == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed"));
+    }
+    Ok((data, fds))
+}
+
+// statsd sockets and TCP listeners with names matched by activation
+fn listening_sockets() -> Vec<(&'static str, RawFd)> {
+    let mut sockets = STATSD_UDP_SOCKET.fds().into_iter().map(|fd| (STATSD_SOCKET, fd)).collect::<Vec<_>>();
+    sockets.extend(taken_tcp());
+    sockets
+}
+
+/// Write caches as their length followed by the snapshot message, returns the number of metrics sent
+pub fn hand_state(mut stream: UnixStream, chans: &[Sender<Task>], runtime: &mut Runtime, timeout: Duration) -> Result<usize, String> {
+    let (state, metrics) = take_state(chans, runtime, timeout)?;
+    stream.set_write_timeout(Some(timeout.max(Duration::from_millis(1)))).map_err(|e| e.to_string())?;
+    stream.write_all(&(state.len() as u64).to_be_bytes()).and_then(|_| stream.write_all(&state)).map_err(|e| e.to_string())?;
+    Ok(metrics)
+}
+
+/// Start the same binary with the same arguments, it takes sockets over `shutdown.handoff-socket`.
+/// The path the binary was started by is used, so an upgraded binary at that path is started.
+pub fn start_next_process() -> io::Result<u32> {
+    let mut args = env::args_os();
+    let program = args.next().ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no program name in arguments"))?;
+    // the child becomes the main process of the service and answers the watchdog instead of this one
+    Command::new(program).args(args).env_remove("WATCHDOG_PID").spawn().map(|child| child.id())
+}
+
+/// Wait for the next process on `path` and hand listening sockets off to it. When it reports it is started,
+/// this process stops the same way it does on SIGTERM, passing caches to it if `shutdown.handoff-caches` is set.
+/// If it exits before starting, this one goes on working.
+pub fn serve_handoff(path: &str, options: Shutdown, chans: Vec<Sender<Task>>, buffer_flags: Arc<Vec<AtomicBool>>, log: &Logger) -> io::Result<()> {
+    let log = log.new(o!("source"=>"handoff"));
+    // the socket is left by the previous process, which has handed off already
+    if fs::metadata(path).is_ok() {
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    let mut chans = WorkerSet::for_caches(chans);
+    thread::Builder::new().name("bioyino_handoff".into()).spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!(log, "accepting handoff connection"; "error"=>e.to_string());
+                    continue;
+                }
+            };
+            let sockets = listening_sockets();
+            let names = sockets.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(":");
+            let fds = sockets.iter().map(|(_, fd)| *fd).collect::<Vec<_>>();
+            if let Err(e) = send_fds(&stream, names.as_bytes(), &fds) {
+                warn!(log, "handing sockets off failed"; "error"=>e.to_string());
+                continue;
+            }
+            info!(log, "sockets handed off, waiting for the next process to start"; "sockets"=>names);
+
+            let mut ready = [0u8];
+            match stream.read_exact(&mut ready) {
+                Ok(()) if ready[0] == READY => (),
+                _ => {
+                    warn!(log, "next process did not start, going on");
+                    continue;
+                }
+            }
+            if SHUTTING_DOWN.swap(true, Ordering::SeqCst) {
+                warn!(log, "next process started during shutdown, it gets no caches");
+                continue;
+            }
+            info!(log, "next process started, stopping");
+            event("shutdown", "sockets handed off to the next process, stopping".to_string());
+            chans.refresh();
+            stop(&options, chans.to_vec(), buffer_flags, Some(stream), &log);
+        }
+    })?;
+    Ok(())
+}
+
+/// Take listening sockets of the process running on `path`, they are used by listeners the same way as ones
+/// passed by systemd. Returns the connection to finish the handoff with, none if no process is listening.
+pub fn take_handoff(path: &str, log: &Logger) -> Option<UnixStream> {
+    let stream = UnixStream::connect(path).ok()?;
+    match recv_fds(&stream) {
+        Ok((names, fds)) => {
+            let names = String::from_utf8_lossy(&names).split(':').map(String::from).collect::<Vec<_>>();
+            info!(log, "taking sockets of the previous process"; "sockets"=>fds.len());
+            for (fd, name) in fds.into_iter().zip(names.into_iter().map(Some).chain(std::iter::repeat(None))) {
+                pass_socket(fd, name, "handoff", log);
+            }
+            Some(stream)
+        }
+        Err(e) => {
+            warn!(log, "taking sockets of the previous process failed"; "error"=>e.to_string());
+            None
+        }
+    }
+}
+
+/// Report listeners are started to the previous process, so it stops, and add caches it sends to workers
+pub fn finish_handoff(mut stream: UnixStream, chans: Vec<Sender<Task>>, log: &Logger) {
+    // sockets not taken, i.e. when statsd mode changed, would get their share of packets nobody reads
+    let closed = close_unused();
+    if closed > 0 {
+        warn!(log, "sockets of the previous process not used, packets queued in them are lost"; "sockets"=>closed);
+    }
+    if let Err(e) = stream.write_all(&[READY]) {
+        warn!(log, "reporting start to the previous process failed"; "error"=>e.to_string());
+        return;
+    }
+    let log = log.clone();
+    thread::Builder::new()
+        .name("bioyino_handoff".into())
+        .spawn(move || {
+            let mut len = [0u8; 8];
+            if stream.read_exact(&mut len).is_err() {
+                info!(log, "previous process passed no caches");
+                return;
+            }
+            let mut state = vec![0u8; u64::from_be_bytes(len) as usize];
+            match stream.read_exact(&mut state).map_err(|e| e.to_string()).and_then(|_| send_state(&state, &chans)) {
+                Ok(metrics) => info!(log, "caches of the previous process taken"; "metrics"=>metrics),
+                Err(e) => warn!(log, "taking caches of the previous process failed"; "error"=>e),
+            }
+        })
+        .map(|_| ())
+        .unwrap_or_else(|e| warn!(log, "starting handoff thread failed"; "error"=>e.to_string()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::UdpSocket;
+    use std::os::unix::io::FromRawFd;
+
+    #[test]
+    fn passed_descriptors() {
+        let (left, right) = UnixStream::pair().unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        send_fds(&left, b"statsd", &[socket.as_raw_fd()]).unwrap();
+
+        let (names, fds) = recv_fds(&right).unwrap();
+        assert_eq!(names, b"statsd".to_vec());
+        assert_eq!(fds.len(), 1);
+        assert_ne!(fds[0], socket.as_raw_fd());
+        // the descriptor received is the same socket
+        let passed = unsafe { UdpSocket::from_raw_fd(fds[0]) };
+        assert_eq!(passed.local_addr().unwrap(), socket.local_addr().unwrap());
+
+        assert!(send_fds(&left, b"", &[]).is_err());
+        drop(left);
+        assert!(recv_fds(&right).is_err());
+    }
+}
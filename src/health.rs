@@ -0,0 +1,340 @@
+use std::collections::BTreeMap;
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use futures::future::{join_all, Future};
+use futures::sync::mpsc::Sender;
+use futures::sync::oneshot;
+use futures::{Sink, Stream};
+use lazy_static::lazy_static;
+use serde_derive::{Deserialize, Serialize};
+use slog::{info, o, warn, Logger};
+use tokio::timer::{Interval, Timeout};
+
+use crate::config::{Health, System};
+use crate::events::event;
+use crate::maintenance::{maintenance_status, MaintenanceStatus};
+use crate::queue::{worker_queues, QueueValues};
+use crate::stats::Counters;
+use crate::task::Task;
+use crate::workers::worker_count;
+use crate::tunables::WORKER_PING_TIMEOUT;
+use crate::{ConsensusKind, Float, BACKEND_OK, CONSENSUS_REACHABLE, PEER_LISTENING, RUNTIME_CONFIG, STATSD_LISTENING};
+
+lazy_static! {
+    static ref SCORE: Mutex<HealthScore> = Mutex::new(HealthScore::default());
+}
+
+// share of the score every component gives when it is fine
+const QUEUES_WEIGHT: Float = 30f64;
+const DROPS_WEIGHT: Float = 30f64;
+const BACKEND_WEIGHT: Float = 20f64;
+const CONSENSUS_WEIGHT: Float = 20f64;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub enum CheckStatus {
+    Ok,
+    Fail,
+    // check does not make sense in current configuration
+    Skipped,
+    // node is in maintenance, it works, but should not be sent to
+    Draining,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Check {
+    pub status: CheckStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+impl Check {
+    pub fn ok() -> Self {
+        Self { status: CheckStatus::Ok, message: None }
+    }
+
+    pub fn fail<S: Into<String>>(message: S) -> Self {
+        Self { status: CheckStatus::Fail, message: Some(message.into()) }
+    }
+
+    pub fn skipped<S: Into<String>>(message: S) -> Self {
+        Self { status: CheckStatus::Skipped, message: Some(message.into()) }
+    }
+
+    fn maintenance() -> Self {
+        match maintenance_status() {
+            MaintenanceStatus { enabled: true, forward_to: Some(sibling), .. } => Self { status: CheckStatus::Draining, message: Some(format!("in maintenance, statsd is forwarded to {}", sibling)) },
+            MaintenanceStatus { enabled: true, .. } => Self { status: CheckStatus::Draining, message: Some("in maintenance".to_string()) },
+            _ => Self::ok(),
+        }
+    }
+
+    fn from_flag(flag: &AtomicBool, message: &str) -> Self {
+        if flag.load(Ordering::Relaxed) {
+            Self::ok()
+        } else {
+            Self::fail(message)
+        }
+    }
+}
+
+/// Result of all checks, answered by /healthz and /readyz
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct HealthReport {
+    pub status: CheckStatus,
+    pub checks: BTreeMap<String, Check>,
+    /// The last health score counted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub score: Option<HealthScore>,
+}
+
+impl HealthReport {
+    pub fn new(checks: BTreeMap<String, Check>) -> Self {
+        let status = if checks.values().any(|check| check.status == CheckStatus::Fail) {
+            CheckStatus::Fail
+        } else if checks.values().any(|check| check.status == CheckStatus::Draining) {
+            CheckStatus::Draining
+        } else {
+            CheckStatus::Ok
+        };
+        Self { status, checks, score: Some(HealthScore::current()) }
+    }
+
+    /// The node is alive, draining nodes are too
+    pub fn is_ok(&self) -> bool {
+        self.status != CheckStatus::Fail
+    }
+
+    /// The node should be sent to
+    pub fn is_ready(&self) -> bool {
+        self.status == CheckStatus::Ok
+    }
+}
+
+/// Ping every worker through its task queue. A full queue or a stuck worker
+/// both make the check fail.
+pub fn check_workers(chans: &[Sender<Task>]) -> impl Future<Item = Check, Error = ()> + Send {
+    let pings = chans
+        .iter()
+        .enumerate()
+        .map(|(idx, chan)| {
+            let (tx, rx) = oneshot::channel();
+            let ping = chan.clone().send(Task::Ping(tx)).map_err(|_| ()).and_then(|_| rx.map_err(|_| ()));
+            Timeout::new(ping, Duration::from_millis(WORKER_PING_TIMEOUT.get() as u64)).then(move |res| Ok::<_, ()>(if res.is_ok() { None } else { Some(idx) }))
+        })
+        .collect::<Vec<_>>();
+
+    join_all(pings).map(|failed| {
+        let failed = failed.into_iter().filter_map(|idx| idx).map(|idx| idx.to_string()).collect::<Vec<_>>();
+        if failed.len() == 0 {
+            Check::ok()
+        } else {
+            Check::fail(format!("workers not responding: {}", failed.join(", ")))
+        }
+    })
+}
+
+/// Liveness only checks the process is able to do it's main job: workers are processing tasks
+pub fn liveness(chans: &[Sender<Task>]) -> impl Future<Item = HealthReport, Error = ()> + Send {
+    check_workers(chans).map(|workers| {
+        let mut checks = BTreeMap::new();
+        checks.insert("workers".to_string(), workers);
+        checks.insert("maintenance".to_string(), Check::maintenance());
+        HealthReport::new(checks)
+    })
+}
+
+/// Readiness additionally checks all the dependencies are available
+pub fn readiness(chans: &[Sender<Task>], config: &System) -> impl Future<Item = HealthReport, Error = ()> + Send {
+    let mut checks = BTreeMap::new();
+    checks.insert("statsd-listener".to_string(), Check::from_flag(&STATSD_LISTENING, "statsd socket is not bound"));
+    checks.insert("peer-listener".to_string(), Check::from_flag(&PEER_LISTENING, "peer server is not listening"));
+    let consensus = match config.consensus {
+        ConsensusKind::None => Check::skipped("consensus is disabled"),
+        _ => Check::from_flag(&CONSENSUS_REACHABLE, "consensus is not reachable"),
+    };
+    checks.insert("consensus".to_string(), consensus);
+    checks.insert("backend".to_string(), Check::from_flag(&BACKEND_OK, "last attempt to send metrics to backend failed"));
+    let score = HealthScore::current();
+    let health = if score.degraded { Check::fail(format!("health score {:.0} is below {}", score.score, config.health.degraded_score)) } else { Check::ok() };
+    checks.insert("health-score".to_string(), health);
+    checks.insert("maintenance".to_string(), Check::maintenance());
+
+    check_workers(chans).map(move |workers| {
+        checks.insert("workers".to_string(), workers);
+        HealthReport::new(checks)
+    })
+}
+
+/// What the health score is counted from, taken over the last check interval
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthInputs {
+    /// Worker queue depths with capacities
+    pub queues: Vec<QueueValues>,
+    /// Packets received and dropped by listeners, except drops on pause, which are intended
+    pub received: usize,
+    pub dropped: usize,
+    pub backend_ok: bool,
+    /// `None` if there is no consensus
+    pub consensus_ok: Option<bool>,
+}
+
+impl HealthInputs {
+    pub fn new(delta: &Counters, queues: Vec<QueueValues>, consensus: &ConsensusKind) -> Self {
+        let received = delta.statsd_udp.packets + delta.peer_tcp.packets;
+        let dropped = (delta.statsd_udp.drops + delta.peer_tcp.drops).saturating_sub(delta.paused_drop);
+        let consensus_ok = match consensus {
+            ConsensusKind::None => None,
+            _ => Some(CONSENSUS_REACHABLE.load(Ordering::Relaxed)),
+        };
+        Self { queues, received, dropped, backend_ok: BACKEND_OK.load(Ordering::Relaxed), consensus_ok }
+    }
+}
+
+/// Overall node health from 0 to 100 with scores of it's components from 0 to 1. Every component
+/// gives it's share of the score when it is fine: queues and drops 30 each, backend and consensus 20 each.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct HealthScore {
+    pub score: Float,
+    /// Score is below `health.degraded-score`
+    pub degraded: bool,
+    pub components: BTreeMap<String, Float>,
+}
+
+impl Default for HealthScore {
+    fn default() -> Self {
+        // nothing is known before the first check, so the node is not blamed
+        Self { score: 100f64, degraded: false, components: BTreeMap::new() }
+    }
+}
+
+impl HealthScore {
+    pub fn new(inputs: &HealthInputs, options: &Health) -> Self {
+        // a component score falls linearly from 1 to 0 when the value grows to the threshold
+        let falling = |value: Float, threshold: f32| if threshold > 0f32 { (1f64 - value / threshold as Float).max(0f64) } else { 1f64 };
+        let fill = inputs.queues.iter().filter(|queue| queue.capacity > 0).map(|queue| queue.depth as Float / queue.capacity as Float).fold(0f64, Float::max);
+        let drops = if inputs.received > 0 { inputs.dropped as Float / inputs.received as Float } else { 0f64 };
+        let flag = |ok: bool| if ok { 1f64 } else { 0f64 };
+
+        let mut components = BTreeMap::new();
+        components.insert("queues".to_string(), falling(fill, options.queue_ratio));
+        components.insert("drops".to_string(), falling(drops, options.drop_ratio));
+        components.insert("backend".to_string(), flag(inputs.backend_ok));
+        components.insert("consensus".to_string(), flag(inputs.consensus_ok.unwrap_or(true)));
+        let score = components["queues"] * QUEUES_WEIGHT + components["drops"] * DROPS_WEIGHT + components["backend"] * BACKEND_WEIGHT + components["consensus"] * CONSENSUS_WEIGHT;
+        Self { score, degraded: score < options.degraded_score as Float, components }
+    }
+
+    /// The score counted by the last check
+    pub fn current() -> Self {
+        SCORE.lock().unwrap().clone()
+    }
+}
+
+/// A future counting the health score every `interval` and logging when the node becomes degraded or recovers.
+/// Thresholds are taken from runtime config on every check, so they can be reloaded. Never gets ready.
+pub fn watch_health(interval: Duration, log: Logger) -> impl Future<Item = (), Error = ()> {
+    let log = log.new(o!("source"=>"health"));
+    let err_log = log.clone();
+    let mut last = Counters::load();
+    Interval::new(Instant::now() + interval, interval)
+        .map_err(move |e| {
+            warn!(err_log, "health check timer failed"; "error"=>e.to_string());
+        })
+        .for_each(move |_| {
+            let current = Counters::load();
+            let delta = current.delta(&last);
+            last = current;
+            let (options, consensus) = {
+                let config = RUNTIME_CONFIG.read().unwrap();
+                (config.health.clone(), config.consensus.clone())
+            };
+            let score = HealthScore::new(&HealthInputs::new(&delta, worker_queues(worker_count()), &consensus), &options);
+            let previous = mem::replace(&mut *SCORE.lock().unwrap(), score.clone());
+            if score.degraded && !previous.degraded {
+                warn!(log, "node is degraded"; "score"=>score.score, "components"=>format!("{:?}", score.components));
+                event("health", format!("degraded, health score {:.0}", score.score));
+            } else if !score.degraded && previous.degraded {
+                info!(log, "node is healthy again"; "score"=>score.score);
+                event("health", format!("recovered, health score {:.0}", score.score));
+            }
+            Ok(())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::sync::mpsc;
+    use futures::Stream;
+    use tokio::runtime::current_thread::Runtime;
+
+    #[test]
+    fn report_status() {
+        let mut checks = BTreeMap::new();
+        checks.insert("consensus".to_string(), Check::skipped("consensus is disabled"));
+        checks.insert("workers".to_string(), Check::ok());
+        let report = HealthReport::new(checks.clone());
+        assert_eq!(report.status, CheckStatus::Ok);
+        assert!(report.is_ok() && report.is_ready());
+
+        // draining node is alive, but is not sent to
+        checks.insert("maintenance".to_string(), Check { status: CheckStatus::Draining, message: None });
+        let report = HealthReport::new(checks.clone());
+        assert_eq!(report.status, CheckStatus::Draining);
+        assert!(report.is_ok() && !report.is_ready());
+
+        checks.insert("backend".to_string(), Check::fail("last attempt to send metrics to backend failed"));
+        let report = HealthReport::new(checks);
+        assert_eq!(report.status, CheckStatus::Fail);
+        assert!(!report.is_ok() && !report.is_ready());
+    }
+
+    #[test]
+    fn liveness_pings_workers() {
+        let mut runtime = Runtime::new().unwrap();
+        let (answering, tasks) = mpsc::channel(4);
+        runtime.spawn(tasks.for_each(|task| {
+            if let Task::Ping(tx) = task {
+                tx.send(()).unwrap_or(());
+            }
+            Ok(())
+        }));
+        let report = runtime.block_on(liveness(&[answering.clone()])).unwrap();
+        assert_eq!(report.checks["workers"], Check::ok());
+
+        // queue of a stuck worker is never read
+        let (stuck, _tasks) = mpsc::channel(4);
+        let report = runtime.block_on(liveness(&[answering, stuck])).unwrap();
+        assert_eq!(report.checks["workers"], Check::fail("workers not responding: 1"));
+        assert!(!report.is_ok());
+    }
+
+    #[test]
+    fn health_score() {
+        let options = Health { queue_ratio: 0.5, ..Health::default() };
+        let queue = |depth| QueueValues { depth, high_watermark: depth, capacity: 100 };
+        let mut inputs = HealthInputs { queues: vec![queue(0), queue(25)], received: 1000, dropped: 0, backend_ok: true, consensus_ok: None };
+        let score = HealthScore::new(&inputs, &options);
+        assert_eq!(score.components["queues"], 0.5);
+        assert_eq!(score.score, 85f64);
+        assert!(!score.degraded);
+
+        inputs.dropped = 100;
+        inputs.backend_ok = false;
+        let score = HealthScore::new(&inputs, &options);
+        assert_eq!(score.components["drops"], 0f64);
+        assert_eq!(score.score, 35f64);
+        assert!(score.degraded);
+
+        inputs.consensus_ok = Some(false);
+        assert_eq!(HealthScore::new(&inputs, &Health { degraded_score: 0f32, ..options }).degraded, false);
+    }
+}
@@ -0,0 +1,269 @@
+use std::collections::HashMap;
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::RwLock;
+
+use bytes::Bytes;
+use lazy_static::lazy_static;
+
+use crate::cache::current_epoch;
+
+// names not used by any worker during this number of cache epochs are removed from the table, workers mark names
+// in their caches on every full rotation, so a name is only removed when it left all caches
+const KEEP_ROTATIONS: usize = 2;
+
+// number of readers holding ids taken out of worker caches, names are not removed while there are any
+static PINS: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    /// Names of all metrics in caches, shared by all workers, so the same name gets the same id everywhere
+    pub static ref NAMES: RwLock<Interner> = RwLock::new(Interner::default());
+}
+
+/// Id of a metric name in the name table. Caches, snapshots and aggregation work with ids,
+/// names are only taken back when metrics are sent out of the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NameId(u32);
+
+impl NameId {
+    pub fn index(&self) -> usize {
+        self.0 as usize
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Interner {
+    ids: HashMap<Bytes, NameId>,
+    names: Vec<Bytes>,
+    // cache epoch the name was used in last time
+    used: Vec<AtomicUsize>,
+    free: Vec<NameId>,
+}
+
+impl Interner {
+    /// Id of the name if it is in the table
+    pub fn get(&self, name: &[u8]) -> Option<NameId> {
+        self.ids.get(name).cloned()
+    }
+
+    /// Mark the name as used during cache `epoch`
+    pub fn touch(&self, id: NameId, epoch: usize) {
+        self.used[id.0 as usize].fetch_max(epoch, Ordering::Relaxed);
+    }
+
+    /// Id of the name, the name is copied to the table if it is not there, so names in the table
+    /// never hold receive buffers they were parsed from
+    pub fn intern(&mut self, name: &[u8], epoch: usize) -> NameId {
+        if let Some(id) = self.get(name) {
+            self.touch(id, epoch);
+            return id;
+        }
+        let name = Bytes::from(name);
+        let id = match self.free.pop() {
+            Some(id) => {
+                self.names[id.0 as usize] = name.clone();
+                id
+            }
+            None => {
+                self.names.push(name.clone());
+                self.used.push(AtomicUsize::new(0));
+                NameId(self.names.len() as u32 - 1)
+            }
+        };
+        self.touch(id, epoch);
+        self.ids.insert(name, id);
+        id
+    }
+
+    pub fn name(&self, id: NameId) -> &Bytes {
+        &self.names[id.0 as usize]
+    }
+
+    /// Remove names not used for a while before cache `epoch`, returns number of names removed.
+    /// Ids of removed names are given to new names, so they must not be in any cache anymore.
+    pub fn rotate(&mut self, epoch: usize) -> usize {
+        let Self { ids, names, used, free } = self;
+        let before = ids.len();
+        ids.retain(|_, id| {
+            let idx = id.0 as usize;
+            if used[idx].load(Ordering::Relaxed) + KEEP_ROTATIONS > epoch {
+                return true;
+            }
+            names[idx] = Bytes::new();
+            free.push(*id);
+            false
+        });
+        before - ids.len()
+    }
+
+    /// Make place for `additional` names, so the table is not rehashed while they are added
+    pub fn reserve(&mut self, additional: usize) {
+        self.ids.reserve(additional);
+        self.names.reserve(additional);
+        self.used.reserve(additional);
+    }
+
+    /// Number of names in the table
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    /// Approximate memory taken by the table, not counting hashmap internals
+    pub fn size(&self) -> usize {
+        self.names.iter().map(|name| name.len()).sum::<usize>() + self.names.capacity() * (mem::size_of::<Bytes>() * 2 + mem::size_of::<NameId>() + mem::size_of::<AtomicUsize>())
+    }
+}
+
+/// Names a worker has ids for. Known names are found without locking the global table, so it is only
+/// locked for names new to the worker.
+#[derive(Debug, Default)]
+pub struct LocalNames {
+    // names share the bytes with the global table, with the number of the worker rotation they were used in
+    ids: HashMap<Bytes, (NameId, usize)>,
+    rotation: usize,
+}
+
+impl LocalNames {
+    pub fn get(&self, name: &[u8]) -> Option<NameId> {
+        self.ids.get(name).map(|(id, _)| *id)
+    }
+
+    /// Id of the name, looked up in the global table and added there if the worker did not have it
+    pub fn intern(&mut self, name: &[u8]) -> NameId {
+        let rotation = self.rotation;
+        if let Some((id, used)) = self.ids.get_mut(name) {
+            *used = rotation;
+            return *id;
+        }
+        let id = intern(name);
+        let name = NAMES.read().unwrap().name(id).clone();
+        self.ids.insert(name, (id, rotation));
+        id
+    }
+
+    /// Forget names not used since the previous rotation if `cached` says they are not in worker caches anymore,
+    /// and mark the rest in the global table, so it keeps them. Must be run on every full rotation, after
+    /// the rotated caches are taken out, returns the number of names forgotten.
+    pub fn rotate<F: Fn(&NameId) -> bool>(&mut self, cached: F) -> usize {
+        let rotation = self.rotation;
+        let epoch = current_epoch();
+        let table = NAMES.read().unwrap();
+        let before = self.ids.len();
+        self.ids.retain(|_, (id, used)| {
+            if *used != rotation && !cached(id) {
+                return false;
+            }
+            table.touch(*id, epoch);
+            true
+        });
+        self.rotation += 1;
+        before - self.ids.len()
+    }
+
+    pub fn len(&self) -> usize {
+        self.ids.len()
+    }
+}
+
+/// Guard keeping names in the table while ids taken out of worker caches are not turned back to names yet
+#[derive(Debug)]
+pub struct NamesPin(());
+
+impl Drop for NamesPin {
+    fn drop(&mut self) {
+        PINS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Keep all names until the returned guard is dropped. Must be taken before asking workers for their caches.
+pub fn pin() -> NamesPin {
+    PINS.fetch_add(1, Ordering::SeqCst);
+    NamesPin(())
+}
+
+/// Id of the name in global table, the name is added if it is not there yet
+pub fn intern(name: &[u8]) -> NameId {
+    let epoch = current_epoch();
+    {
+        let names = NAMES.read().unwrap();
+        if let Some(id) = names.get(name) {
+            names.touch(id, epoch);
+            return id;
+        }
+    }
+    NAMES.write().unwrap().intern(name, epoch)
+}
+
+/// Remove names unused before cache `epoch` from global table, after workers have run the rotation of it. Names
+/// are kept while someone holds a pin, returns the number of names removed and left.
+pub fn rotate_names(epoch: usize) -> (usize, usize) {
+    let mut names = NAMES.write().unwrap();
+    // a pin taken after the check is taken before it's holder asks workers for caches, and ids in caches are marked
+    let removed = if PINS.load(Ordering::SeqCst) == 0 { names.rotate(epoch) } else { 0 };
+    (removed, names.len())
+}
+
+/// Add names to global table in advance, like names of the previous run at startup. Names are
+/// removed by rotations as usual if they do not come again. Returns the number of names added.
+pub fn preload<I: IntoIterator<Item = Bytes>>(names: I) -> usize {
+    let epoch = current_epoch();
+    let mut table = NAMES.write().unwrap();
+    let before = table.len();
+    let names = names.into_iter();
+    table.reserve(names.size_hint().0);
+    names.map(|name| table.intern(&name, epoch)).last();
+    table.len() - before
+}
+
+/// Name by id from the global table
+pub fn name_of(id: NameId) -> Bytes {
+    NAMES.read().unwrap().name(id).clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_interned_and_removed() {
+        let mut names = Interner::default();
+        let first = names.intern(b"some.metric", 1);
+        let second = names.intern(b"other.metric", 1);
+        assert_ne!(first, second);
+        assert_eq!(names.intern(b"some.metric", 1), first);
+        assert_eq!(names.get(b"other.metric"), Some(second));
+        assert_eq!(names.name(first), &Bytes::from("some.metric"));
+
+        // names are kept for a while after being used last time
+        assert_eq!(names.rotate(2), 0);
+        names.touch(first, 2);
+        // marks never go back, even if touched with an older epoch
+        names.touch(first, 1);
+        assert_eq!(names.rotate(3), 1);
+        assert_eq!(names.get(b"other.metric"), None);
+        assert_eq!(names.len(), 1);
+
+        // ids of removed names are reused
+        assert_eq!(names.intern(b"new.metric", 3), second);
+        assert_eq!(names.name(second), &Bytes::from("new.metric"));
+    }
+
+    #[test]
+    fn local_names_kept_while_cached() {
+        let mut local = LocalNames::default();
+        let cached = local.intern(b"local.names.cached");
+        let gone = local.intern(b"local.names.gone");
+        assert_eq!(local.intern(b"local.names.cached"), cached);
+        assert_eq!(local.get(b"local.names.gone"), Some(gone));
+
+        // names used since the previous rotation are kept even if they were rotated out of caches
+        assert_eq!(local.rotate(|_| false), 0);
+        // the cached name is kept however many rotations pass without it being used
+        assert_eq!(local.rotate(|id| *id == cached), 1);
+        for _ in 0..5 {
+            assert_eq!(local.rotate(|id| *id == cached), 0);
+        }
+        assert_eq!(local.get(b"local.names.gone"), None);
+        assert_eq!(local.get(b"local.names.cached"), Some(cached));
+    }
+}
@@ -0,0 +1,107 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::Float;
+
+/// Upper bounds of histogram buckets in microseconds, the last bucket has no bound
+pub const BOUNDS_US: [u64; 15] = [100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000, 1_000_000, 2_500_000, 5_000_000];
+
+lazy_static! {
+    /// Time from receiving a statsd packet to putting metrics from it to worker cache
+    pub static ref INGEST_LATENCY: LatencyHistogram = LatencyHistogram::new("ingest");
+    /// Time from interval end to metrics being written to backend
+    pub static ref FLUSH_LATENCY: LatencyHistogram = LatencyHistogram::new("flush");
+}
+
+/// All histograms reported in stats
+pub fn histograms() -> [&'static LatencyHistogram; 2] {
+    [&INGEST_LATENCY, &FLUSH_LATENCY]
+}
+
+#[derive(Debug)]
+pub struct LatencyHistogram {
+    pub name: &'static str,
+    buckets: [AtomicUsize; 16],
+    sum_us: AtomicUsize,
+}
+
+impl LatencyHistogram {
+    pub fn new(name: &'static str) -> Self {
+        Self { name, buckets: Default::default(), sum_us: AtomicUsize::new(0) }
+    }
+
+    pub fn record(&self, elapsed: Duration) {
+        let us = elapsed.as_secs() * 1_000_000 + u64::from(elapsed.subsec_micros());
+        let bucket = BOUNDS_US.iter().position(|bound| us <= *bound).unwrap_or(BOUNDS_US.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_us.fetch_add(us as usize, Ordering::Relaxed);
+    }
+
+    pub fn values(&self) -> LatencyValues {
+        LatencyValues { buckets: self.buckets.iter().map(|bucket| bucket.load(Ordering::Relaxed)).collect(), sum_us: self.sum_us.load(Ordering::Relaxed) }
+    }
+}
+
+/// Histogram counts since start or for some period if taken as a difference
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct LatencyValues {
+    /// Number of values in every bucket, not cumulative
+    pub buckets: Vec<usize>,
+    pub sum_us: usize,
+}
+
+impl LatencyValues {
+    pub fn delta(&self, prev: &LatencyValues) -> Self {
+        let buckets = self.buckets.iter().enumerate().map(|(idx, count)| count.wrapping_sub(prev.buckets.get(idx).cloned().unwrap_or(0))).collect();
+        Self { buckets, sum_us: self.sum_us.wrapping_sub(prev.sum_us) }
+    }
+
+    pub fn count(&self) -> usize {
+        self.buckets.iter().sum()
+    }
+
+    /// Upper bound of the bucket the quantile falls into, ms. Values over the last bound are
+    /// counted as the last bound.
+    pub fn quantile(&self, q: Float) -> Float {
+        let rank = (self.count() as Float * q).ceil() as usize;
+        let mut seen = 0;
+        for (idx, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank && seen > 0 {
+                return BOUNDS_US[idx.min(BOUNDS_US.len() - 1)] as Float / 1000f64;
+            }
+        }
+        0f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latency_quantiles() {
+        let histogram = LatencyHistogram::new("test");
+        let empty = histogram.values();
+        assert_eq!(empty.quantile(0.5), 0f64);
+
+        (0..90).map(|_| histogram.record(Duration::from_micros(700))).last();
+        (0..9).map(|_| histogram.record(Duration::from_millis(20))).last();
+        histogram.record(Duration::from_secs(10));
+        let values = histogram.values().delta(&empty);
+        assert_eq!(values.count(), 100);
+        assert_eq!(values.buckets[3], 90);
+        assert_eq!(values.quantile(0.5), 1f64);
+        assert_eq!(values.quantile(0.99), 25f64);
+        assert_eq!(values.quantile(1f64), 5000f64);
+        assert_eq!(values.sum_us, 90 * 700 + 9 * 20_000 + 10_000_000);
+
+        let prev = histogram.values();
+        histogram.record(Duration::from_micros(50));
+        assert_eq!(histogram.values().delta(&prev).buckets[0], 1);
+    }
+}
@@ -0,0 +1,184 @@
+// General
+//pub mod bigint;
+pub mod accounting;
+pub mod acl;
+pub mod aggregate;
+pub mod alloc;
+pub mod api;
+pub mod arena;
+pub mod audit;
+pub mod auth;
+pub mod bench;
+pub mod buildinfo;
+pub mod cache;
+pub mod carbon;
+pub mod check;
+pub mod cluster;
+pub mod config;
+pub mod consul;
+pub mod counter;
+pub mod ctl;
+pub mod daemon;
+pub mod errors;
+pub mod events;
+pub mod gaps;
+pub mod handoff;
+pub mod health;
+pub mod incident;
+pub mod management;
+pub mod memory;
+pub mod migrate;
+pub mod notify;
+pub mod parse_errors;
+pub mod parser;
+pub mod peer;
+pub mod privileges;
+pub mod profile;
+pub mod queue;
+pub mod quota;
+pub mod raft;
+pub mod ratelimit;
+pub mod reload;
+pub mod report;
+pub mod rules;
+pub mod sandbox;
+pub mod server;
+pub mod shards;
+pub mod shutdown;
+pub mod signing;
+#[cfg(test)]
+pub mod sim;
+pub mod stall;
+pub mod standby;
+pub mod stats;
+pub mod supervise;
+pub mod tags;
+pub mod tail;
+pub mod task;
+pub mod intern;
+pub mod latency;
+pub mod limits;
+pub mod logdrain;
+pub mod logfile;
+pub mod maintenance;
+pub mod spool;
+pub mod startup;
+pub mod template;
+pub mod trace;
+pub mod tunables;
+pub mod udp;
+pub mod units;
+pub mod util;
+pub mod windows;
+pub mod workers;
+
+#[cfg(feature = "fuzzing")]
+pub mod fuzz;
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, Mutex, RwLock};
+
+use bioyino_metric::metric::Metric;
+use lazy_static::lazy_static;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::config::System;
+use crate::counter::Counter;
+use crate::errors::GeneralError;
+use crate::intern::NameId;
+
+// floating type used all over the code, can be changed to f32, to use less memory at the price of
+// precision
+// TODO: make in into compilation feature
+pub type Float = f64;
+
+// a type to store pre-aggregated data
+pub type Cache = HashMap<NameId, Metric<Float>>;
+
+// statistic counters, see `counter::Counter` for how they are counted
+pub static PARSE_ERRORS: Counter = Counter::new();
+pub static AGG_ERRORS: Counter = Counter::new();
+pub static PEER_ERRORS: Counter = Counter::new();
+pub static INGRESS: Counter = Counter::new();
+pub static INGRESS_METRICS: Counter = Counter::new();
+pub static EGRESS: Counter = Counter::new();
+pub static DROPS: Counter = Counter::new();
+pub static PAUSED_DROPS: Counter = Counter::new();
+pub static FILTERED: Counter = Counter::new();
+pub static AUDIT_EVENTS: Counter = Counter::new();
+pub static SHED_DROPS: Counter = Counter::new();
+pub static CAPPED_SAMPLES: Counter = Counter::new();
+pub static CAPPED_METRICS: Counter = Counter::new();
+pub static EARLY_FLUSHES: Counter = Counter::new();
+pub static ARENA_OVERFLOWS: Counter = Counter::new();
+pub static SLOW_TASKS: Counter = Counter::new();
+pub static CACHE_SHRINKS: Counter = Counter::new();
+pub static SLOW_CONNECTIONS: Counter = Counter::new();
+pub static KILLED_CONNECTIONS: Counter = Counter::new();
+pub static PANICS: Counter = Counter::new();
+pub static SOURCE_DROPS: Counter = Counter::new();
+pub static UNSIGNED_DROPS: Counter = Counter::new();
+pub static EXPIRED_DROPS: Counter = Counter::new();
+pub static BAD_SIGNATURE_DROPS: Counter = Counter::new();
+pub static STATSD_RATE_DROPS: Counter = Counter::new();
+pub static PEER_RATE_DROPS: Counter = Counter::new();
+pub static QUOTA_DROPS: Counter = Counter::new();
+pub static SPOOLED_POINTS: Counter = Counter::new();
+pub static SPOOL_REPLAYED: Counter = Counter::new();
+pub static SPOOL_DISCARDS: Counter = Counter::new();
+pub static FLUSH_GAPS: Counter = Counter::new();
+pub static BACKFILLED_POINTS: Counter = Counter::new();
+pub static DROPPED_TAGS: Counter = Counter::new();
+pub static TAG_OVERFLOWS: Counter = Counter::new();
+
+// switched by management commands
+pub static INGESTION_PAUSED: AtomicBool = AtomicBool::new(false);
+pub static FLUSH_PAUSED: AtomicBool = AtomicBool::new(false);
+
+// readiness flags, set by subsystems when their state changes
+pub static STATSD_LISTENING: AtomicBool = AtomicBool::new(false);
+pub static PEER_LISTENING: AtomicBool = AtomicBool::new(false);
+pub static CONSENSUS_REACHABLE: AtomicBool = AtomicBool::new(false);
+// there were no failures yet, so backend is considered working until the first send
+pub static BACKEND_OK: AtomicBool = AtomicBool::new(true);
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub enum ConsensusState {
+    Enabled,
+    Paused,
+    Disabled,
+}
+
+impl FromStr for ConsensusState {
+    type Err = GeneralError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "enabled" | "enable" => Ok(ConsensusState::Enabled),
+            "disabled" | "disable" => Ok(ConsensusState::Disabled),
+            "pause" | "paused" => Ok(ConsensusState::Paused),
+            _ => Err(GeneralError::UnknownState),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub enum ConsensusKind {
+    None,
+    Consul,
+    Internal,
+    Standby,
+}
+
+lazy_static! {
+    pub static ref CONSENSUS_STATE: Mutex<ConsensusState> = { Mutex::new(ConsensusState::Disabled) };
+
+    // configuration as seen by parts that support reloading, see reload.rs
+    pub static ref RUNTIME_CONFIG: RwLock<Arc<System>> = { RwLock::new(Arc::new(System::default())) };
+}
+
+pub static IS_LEADER: AtomicBool = AtomicBool::new(false);
@@ -0,0 +1,126 @@
+use std::fs;
+
+use lazy_static::lazy_static;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::config::{Memory, Network};
+use crate::util::cgroup_cpu_limit;
+
+// statsd receive buffers of all sockets together take at most this share of the memory limit
+const RECV_BUFFER_SHARE: usize = 8;
+
+lazy_static! {
+    /// Limits detected when first used, which is on start, before threads are sandboxed
+    pub static ref RESOURCE_LIMITS: ResourceLimits = ResourceLimits::detect();
+}
+
+/// CPU and memory available to the process, from the host and from cgroup limits set by container runtimes
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ResourceLimits {
+    /// CPUs of the host allowed by CPU affinity
+    pub host_cpus: usize,
+    /// CPU limit set by cgroup quota, rounded up
+    pub cgroup_cpus: Option<usize>,
+    /// CPUs thread counts are computed for
+    pub cpus: usize,
+    /// Memory of the host, `None` if it could not be read
+    pub host_memory_bytes: Option<usize>,
+    /// Memory limit set by cgroup, `None` if there is no limit less than host memory
+    pub cgroup_memory_bytes: Option<usize>,
+}
+
+/// Memory limit set by cgroup. Takes contents of cgroup v2 `memory.max` or of cgroup v1 `memory.limit_in_bytes`,
+/// "max" or a limit not less than host memory, as v1 reports no limit with a huge number, gives `None`
+pub fn cgroup_memory_limit(memory_max: Option<&str>, v1_limit: Option<&str>, host_memory: Option<usize>) -> Option<usize> {
+    let limit = memory_max.or(v1_limit)?.trim().parse::<usize>().ok()?;
+    match host_memory {
+        Some(host) if limit >= host => None,
+        _ if limit == 0 => None,
+        _ => Some(limit),
+    }
+}
+
+/// Value of `MemTotal` in contents of /proc/meminfo, bytes
+pub fn meminfo_total(meminfo: &str) -> Option<usize> {
+    let line = meminfo.lines().find(|line| line.starts_with("MemTotal:"))?;
+    let mut parts = line["MemTotal:".len()..].split_whitespace();
+    let value = parts.next()?.parse::<usize>().ok()?;
+    match parts.next() {
+        Some("kB") => Some(value * 1024),
+        None => Some(value),
+        _ => None,
+    }
+}
+
+impl ResourceLimits {
+    /// Read limits of the host and of the cgroup the process is in
+    pub fn detect() -> Self {
+        let read = |path: &str| fs::read_to_string(path).ok();
+        let host_cpus = num_cpus::get();
+        let cgroup_cpus = cgroup_cpu_limit(read("/sys/fs/cgroup/cpu.max").as_ref().map(|s| s.as_str()), read("/sys/fs/cgroup/cpu/cpu.cfs_quota_us").as_ref().map(|s| s.as_str()), read("/sys/fs/cgroup/cpu/cpu.cfs_period_us").as_ref().map(|s| s.as_str()));
+        let host_memory_bytes = read("/proc/meminfo").and_then(|meminfo| meminfo_total(&meminfo));
+        let cgroup_memory_bytes = cgroup_memory_limit(read("/sys/fs/cgroup/memory.max").as_ref().map(|s| s.as_str()), read("/sys/fs/cgroup/memory/memory.limit_in_bytes").as_ref().map(|s| s.as_str()), host_memory_bytes);
+        let cpus = match cgroup_cpus {
+            Some(limit) if limit < host_cpus => limit.max(1),
+            _ => host_cpus,
+        };
+        Self { host_cpus, cgroup_cpus, cpus, host_memory_bytes, cgroup_memory_bytes }
+    }
+
+    /// Memory budget for the options, `memory.budget` if it is set, otherwise `memory.limit-ratio` of cgroup memory limit,
+    /// no budget outside of containers
+    pub fn memory_budget(&self, options: &Memory) -> usize {
+        match self.cgroup_memory_bytes {
+            _ if options.budget > 0 => options.budget,
+            Some(limit) if options.limit_ratio > 0f32 => (limit as f64 * options.limit_ratio.min(1f32) as f64) as usize,
+            _ => 0,
+        }
+    }
+
+    /// Lower receive buffer sizes of statsd sockets, so `sockets` of them fit into their share of cgroup memory limit,
+    /// returns true if anything was lowered
+    pub fn cap_recv_buffers(&self, network: &mut Network, sockets: usize) -> bool {
+        let cap = match self.cgroup_memory_bytes {
+            Some(limit) => limit / RECV_BUFFER_SHARE / sockets.max(1),
+            None => return false,
+        };
+        let capped = network.recv_buffer > cap || network.max_recv_buffer > cap;
+        network.recv_buffer = network.recv_buffer.min(cap);
+        network.max_recv_buffer = network.max_recv_buffer.min(cap);
+        capped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn container_limits() {
+        let host = Some(16 << 30);
+        assert_eq!(cgroup_memory_limit(Some("max\n"), None, host), None);
+        assert_eq!(cgroup_memory_limit(Some("1073741824\n"), None, host), Some(1 << 30));
+        assert_eq!(cgroup_memory_limit(None, Some("9223372036854771712\n"), host), None);
+        assert_eq!(cgroup_memory_limit(None, Some("536870912"), host), Some(512 << 20));
+        assert_eq!(cgroup_memory_limit(None, None, host), None);
+        assert_eq!(meminfo_total("MemTotal:       16318104 kB\nMemFree:         1000 kB\n"), Some(16318104 * 1024));
+
+        let limits = ResourceLimits { host_cpus: 32, cgroup_cpus: Some(2), cpus: 2, host_memory_bytes: host, cgroup_memory_bytes: Some(1 << 30) };
+        let mut memory = Memory::default();
+        assert_eq!(limits.memory_budget(&memory), ((1 << 30) as f64 * memory.limit_ratio as f64) as usize);
+        memory.budget = 1000;
+        assert_eq!(limits.memory_budget(&memory), 1000);
+        memory.budget = 0;
+        memory.limit_ratio = 0f32;
+        assert_eq!(limits.memory_budget(&memory), 0);
+        memory.limit_ratio = 0.5;
+        assert_eq!(ResourceLimits::default().memory_budget(&memory), 0);
+
+        let mut network = Network::default();
+        network.recv_buffer = 64 << 20;
+        assert!(limits.cap_recv_buffers(&mut network, 4));
+        assert_eq!((network.recv_buffer, network.max_recv_buffer), (32 << 20, 32 << 20));
+        assert!(!ResourceLimits::default().cap_recv_buffers(&mut network, 4));
+    }
+}
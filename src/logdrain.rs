@@ -0,0 +1,325 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use slog::{b, Drain, Level, OwnedKVList, Record, RecordLocation, RecordStatic, KV};
+
+use crate::config::Logging;
+use crate::util::get_hostname;
+
+/// Path of journald socket for native protocol messages
+pub const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Syslog severity of log level, journald uses the same priorities
+pub fn severity(level: Level) -> u8 {
+    match level {
+        Level::Critical => 2,
+        Level::Error => 3,
+        Level::Warning => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// Facility number by name as in syslog.conf
+pub fn facility(name: &str) -> Option<u8> {
+    let facility = match name {
+        "kern" => 0,
+        "user" => 1,
+        "mail" => 2,
+        "daemon" => 3,
+        "auth" => 4,
+        "syslog" => 5,
+        "lpr" => 6,
+        "news" => 7,
+        "uucp" => 8,
+        "cron" => 9,
+        "authpriv" => 10,
+        "ftp" => 11,
+        _ if name.starts_with("local") => 16 + name[5..].parse::<u8>().ok().filter(|local| *local < 8)?,
+        _ => return None,
+    };
+    Some(facility)
+}
+
+// key-value pairs of a record and it's loggers
+#[derive(Default)]
+struct Fields(Vec<(String, String)>);
+
+impl slog::Serializer for Fields {
+    fn emit_arguments(&mut self, key: slog::Key, value: &fmt::Arguments) -> slog::Result {
+        self.0.push((key.to_string(), value.to_string()));
+        Ok(())
+    }
+}
+
+fn fields(record: &Record, values: &OwnedKVList) -> io::Result<Vec<(String, String)>> {
+    let mut fields = Fields::default();
+    record.kv().serialize(record, &mut fields).and_then(|_| values.serialize(record, &mut fields)).map_err(|_| io::Error::new(io::ErrorKind::Other, "formatting log record"))?;
+    Ok(fields.0)
+}
+
+/// UTC time in RFC3339 format with milliseconds
+pub fn rfc3339(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let (days, seconds) = ((since.as_secs() / 86400) as i64, since.as_secs() % 86400);
+    // civil date from days since epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z", year, month, day, seconds / 3600, seconds / 60 % 60, seconds % 60, since.subsec_millis())
+}
+
+/// Syslog line in RFC5424 format, key-value pairs are appended to the message
+pub fn rfc5424(pri: u8, time: SystemTime, hostname: &str, pid: u32, message: &str, fields: &[(String, String)]) -> String {
+    let mut line = format!("<{}>1 {} {} bioyino {} - - {}", pri, rfc3339(time), hostname, pid, message);
+    for (key, value) in fields {
+        if value.contains(' ') || value.contains('"') {
+            line.push_str(&format!(" {}={:?}", key, value));
+        } else {
+            line.push_str(&format!(" {}={}", key, value));
+        }
+    }
+    line
+}
+
+enum SyslogSocket {
+    Udp(UdpSocket, SocketAddr),
+    Unix(UnixDatagram, PathBuf),
+}
+
+/// Sends log records to syslog over UDP or unix datagram socket
+pub struct SyslogDrain {
+    socket: SyslogSocket,
+    facility: u8,
+    hostname: String,
+    pid: u32,
+}
+
+impl SyslogDrain {
+    /// Address is either a path to unix socket like /dev/log or host:port for UDP
+    pub fn new(address: &str, facility: u8) -> io::Result<Self> {
+        let socket = if address.starts_with('/') {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect(address)?;
+            SyslogSocket::Unix(socket, PathBuf::from(address))
+        } else {
+            let remote = address.parse::<SocketAddr>().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+            let bind: SocketAddr = if remote.is_ipv4() { "0.0.0.0:0".parse().unwrap() } else { "[::]:0".parse().unwrap() };
+            SyslogSocket::Udp(UdpSocket::bind(bind)?, remote)
+        };
+        Ok(Self { socket, facility, hostname: get_hostname().unwrap_or_else(|| "-".to_string()), pid: std::process::id() })
+    }
+}
+
+impl Drain for SyslogDrain {
+    type Ok = ();
+    type Err = io::Error;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> io::Result<()> {
+        let pri = self.facility * 8 + severity(record.level());
+        let line = rfc5424(pri, SystemTime::now(), &self.hostname, self.pid, &record.msg().to_string(), &fields(record, values)?);
+        match self.socket {
+            SyslogSocket::Udp(ref socket, remote) => socket.send_to(line.as_bytes(), remote).map(|_| ()),
+            SyslogSocket::Unix(ref socket, ref path) => {
+                // syslog daemon may have been restarted, the socket is connected again then
+                socket.send(line.as_bytes()).or_else(|_| socket.connect(path).and_then(|_| socket.send(line.as_bytes()))).map(|_| ())
+            }
+        }
+    }
+}
+
+// field names journald accepts: uppercase letters, digits and underscores, not starting with underscore
+fn journald_key(key: &str) -> String {
+    let key = key.chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' }).collect::<String>();
+    key.trim_start_matches('_').to_string()
+}
+
+/// Message in journald native protocol, values with line endings are written in binary form
+pub fn journald_message(priority: u8, message: &str, fields: &[(String, String)]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    let mut field = |key: &str, value: &str| {
+        if key.is_empty() {
+            return;
+        }
+        buf.extend_from_slice(key.as_bytes());
+        if value.contains('\n') {
+            buf.push(b'\n');
+            buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            buf.push(b'=');
+        }
+        buf.extend_from_slice(value.as_bytes());
+        buf.push(b'\n');
+    };
+    field("MESSAGE", message);
+    field("PRIORITY", &priority.to_string());
+    field("SYSLOG_IDENTIFIER", "bioyino");
+    for (key, value) in fields {
+        field(&journald_key(key), value);
+    }
+    buf
+}
+
+/// Sends log records to systemd-journald with their key-value pairs as journal fields
+pub struct JournaldDrain {
+    socket: UnixDatagram,
+}
+
+impl JournaldDrain {
+    pub fn new() -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(JOURNALD_SOCKET)?;
+        Ok(Self { socket })
+    }
+}
+
+impl Drain for JournaldDrain {
+    type Ok = ();
+    type Err = io::Error;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> io::Result<()> {
+        let message = journald_message(severity(record.level()), &record.msg().to_string(), &fields(record, values)?);
+        self.socket.send(&message).map(|_| ())
+    }
+}
+
+// records of a single log call site in the current interval
+struct Class {
+    started: Instant,
+    passed: usize,
+    suppressed: usize,
+    level: Level,
+    message: String,
+}
+
+/// Passes at most `limit` records of every log call site per interval, so a flood of the same
+/// warning does not fill the disk. The number of suppressed records is logged when the interval of
+/// their call site is over, at the next record of any site. Critical records are never suppressed.
+pub struct RateLimit<D> {
+    drain: D,
+    limit: usize,
+    interval: Duration,
+    classes: Mutex<HashMap<(&'static str, u32), Class>>,
+}
+
+impl<D: Drain> RateLimit<D> {
+    pub fn new(drain: D, options: &Logging) -> Self {
+        Self { drain, limit: options.rate_limit, interval: Duration::from_millis(options.rate_limit_interval), classes: Mutex::new(HashMap::new()) }
+    }
+
+    fn summary(&self, (file, line): (&'static str, u32), class: &Class, values: &OwnedKVList) -> Result<D::Ok, D::Err> {
+        let location = RecordLocation { file, line, column: 0, function: "", module: "" };
+        let rs = RecordStatic { location: &location, tag: "", level: class.level };
+        let suppressed = class.suppressed;
+        let message = &class.message;
+        self.drain.log(&Record::new(&rs, &format_args!("suppressed {} similar messages: {}", suppressed, message), b!("suppressed"=>suppressed, "location"=>format!("{}:{}", file, line))), values)
+    }
+}
+
+impl<D: Drain<Ok = ()>> Drain for RateLimit<D> {
+    type Ok = ();
+    type Err = D::Err;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> Result<(), D::Err> {
+        if self.limit == 0 || record.level() == Level::Critical {
+            return self.drain.log(record, values);
+        }
+        let now = Instant::now();
+        let key = (record.file(), record.line());
+        let mut classes = self.classes.lock().unwrap();
+        // intervals of all sites are checked, so summaries are not delayed until the same site logs again
+        let mut result = Ok(());
+        let interval = self.interval;
+        let expired = classes.iter().filter(|(_, class)| now.duration_since(class.started) >= interval).map(|(key, _)| *key).collect::<Vec<_>>();
+        for expired in expired {
+            if let Some(class) = classes.remove(&expired) {
+                if class.suppressed > 0 {
+                    result = result.and(self.summary(expired, &class, values));
+                }
+            }
+        }
+        let class = classes.entry(key).or_insert_with(|| Class { started: now, passed: 0, suppressed: 0, level: record.level(), message: String::new() });
+        if class.passed < self.limit {
+            class.passed += 1;
+            drop(classes);
+            result.and(self.drain.log(record, values))
+        } else {
+            class.suppressed += 1;
+            class.message = record.msg().to_string();
+            result
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use slog::{o, warn, Logger};
+    use std::sync::Arc;
+
+    #[test]
+    fn syslog_format() {
+        assert_eq!(facility("daemon"), Some(3));
+        assert_eq!(facility("local7"), Some(23));
+        assert_eq!(facility("local8"), None);
+        assert_eq!(facility("unknown"), None);
+
+        assert_eq!(rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(rfc3339(UNIX_EPOCH + Duration::from_millis(951_782_400_123)), "2000-02-29T00:00:00.123Z");
+        assert_eq!(rfc3339(UNIX_EPOCH + Duration::from_secs(1_700_000_000)), "2023-11-14T22:13:20.000Z");
+
+        let fields = vec![("source".to_string(), "carbon".to_string()), ("error".to_string(), "connection refused".to_string())];
+        let line = rfc5424(3 * 8 + severity(Level::Warning), UNIX_EPOCH, "host", 42, "sending failed", &fields);
+        assert_eq!(line, "<28>1 1970-01-01T00:00:00.000Z host bioyino 42 - - sending failed source=carbon error=\"connection refused\"");
+
+        let fields = vec![("worker-id".to_string(), "1".to_string()), ("multi".to_string(), "a\nb".to_string())];
+        let message = journald_message(severity(Level::Error), "failed", &fields);
+        let mut expected = b"MESSAGE=failed\nPRIORITY=3\nSYSLOG_IDENTIFIER=bioyino\nWORKER_ID=1\nMULTI\n".to_vec();
+        expected.extend_from_slice(&3u64.to_le_bytes());
+        expected.extend_from_slice(b"a\nb\n");
+        assert_eq!(message, expected);
+    }
+
+    // keeps messages of all records
+    #[derive(Clone, Default)]
+    struct Collect(Arc<Mutex<Vec<String>>>);
+
+    impl Drain for Collect {
+        type Ok = ();
+        type Err = slog::Never;
+
+        fn log(&self, record: &Record, _: &OwnedKVList) -> Result<(), slog::Never> {
+            self.0.lock().unwrap().push(record.msg().to_string());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn rate_limit() {
+        let collect = Collect::default();
+        let options = Logging { rate_limit: 2, rate_limit_interval: 50, ..Default::default() };
+        let log = Logger::root(RateLimit::new(collect.clone(), &options), o!());
+        for idx in 0..5 {
+            warn!(log, "bad incoming message {}", idx);
+        }
+        warn!(log, "other message");
+        assert_eq!(*collect.0.lock().unwrap(), vec!["bad incoming message 0", "bad incoming message 1", "other message"]);
+
+        std::thread::sleep(Duration::from_millis(60));
+        warn!(log, "other message");
+        let messages = collect.0.lock().unwrap().clone();
+        assert_eq!(messages[3..], ["suppressed 3 similar messages: bad incoming message 4".to_string(), "other message".to_string()]);
+    }
+}
@@ -0,0 +1,164 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use crate::config::Logging;
+
+/// Set by SIGUSR2 handler, the log file is opened again before the next write, so files moved by
+/// logrotate are released
+pub static REOPEN_LOG: AtomicBool = AtomicBool::new(false);
+
+/// Log file rotated when it grows over the maximum size or gets older than rotation interval.
+/// Rotated files are renamed to `<file>.1`, `<file>.2` and so on, `<file>.1` being the newest one.
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: u64,
+    interval: Option<Duration>,
+    max_files: usize,
+    compress: bool,
+    file: File,
+    written: u64,
+    opened: Instant,
+    // compression of the previous rotated file
+    compressing: Option<JoinHandle<()>>,
+}
+
+fn open(path: &Path) -> io::Result<(File, u64)> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let written = file.metadata()?.len();
+    Ok((file, written))
+}
+
+fn numbered(path: &Path, number: usize, compressed: bool) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", number));
+    if compressed {
+        name.push(".gz");
+    }
+    PathBuf::from(name)
+}
+
+// compress a rotated file to the same name with .gz added and remove it
+fn compress_file(path: &Path) -> io::Result<()> {
+    let mut target = path.as_os_str().to_owned();
+    target.push(".gz");
+    let target = PathBuf::from(target);
+    let mut encoder = GzEncoder::new(File::create(&target)?, Compression::default());
+    io::copy(&mut File::open(path)?, &mut encoder)?;
+    encoder.finish()?;
+    fs::remove_file(path)
+}
+
+impl RotatingFile {
+    pub fn new(path: &str, options: &Logging) -> io::Result<Self> {
+        let path = PathBuf::from(path);
+        let (file, written) = open(&path)?;
+        let interval = if options.rotate_interval > 0 { Some(Duration::from_millis(options.rotate_interval)) } else { None };
+        Ok(Self { path, max_size: options.max_size as u64, interval, max_files: options.max_files, compress: options.compress, file, written, opened: Instant::now(), compressing: None })
+    }
+
+    fn needs_rotation(&self, incoming: usize) -> bool {
+        (self.max_size > 0 && self.written > 0 && self.written + incoming as u64 > self.max_size) || self.interval.map(|interval| self.opened.elapsed() >= interval).unwrap_or(false)
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        let (file, written) = open(&self.path)?;
+        self.file = file;
+        self.written = written;
+        self.opened = Instant::now();
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        // the previous file must be compressed before files are shifted, or the compressed one would replace another file
+        if let Some(compressing) = self.compressing.take() {
+            compressing.join().unwrap_or(());
+        }
+        if self.max_files == 0 {
+            // no rotated files are kept, so the current one is just started over
+            self.file.set_len(0)?;
+            self.written = 0;
+            self.opened = Instant::now();
+            return Ok(());
+        }
+        // files rotated without compression are kept, if it was turned off, so both kinds are shifted
+        for compressed in &[false, true] {
+            let _ = fs::remove_file(numbered(&self.path, self.max_files, *compressed));
+            for number in (1..self.max_files).rev() {
+                let from = numbered(&self.path, number, *compressed);
+                if from.exists() {
+                    fs::rename(&from, numbered(&self.path, number + 1, *compressed))?;
+                }
+            }
+        }
+        let rotated = numbered(&self.path, 1, false);
+        fs::rename(&self.path, &rotated)?;
+        self.reopen()?;
+        if self.compress {
+            // compressing big files takes time, logging must not wait for it
+            let compressing = thread::Builder::new().name("bioyino_logzip".into()).spawn(move || compress_file(&rotated).unwrap_or_else(|e| eprintln!("compressing rotated log {}: {}", rotated.display(), e)))?;
+            self.compressing = Some(compressing);
+        }
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if REOPEN_LOG.swap(false, Ordering::Relaxed) {
+            self.reopen()?;
+        }
+        if self.needs_rotation(buf.len()) {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn rotate_log_file() {
+        let dir = env::temp_dir().join(format!("bioyino-log-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("bioyino.log");
+        let options = Logging { max_size: 10, max_files: 2, ..Default::default() };
+        let mut file = RotatingFile::new(path.to_str().unwrap(), &options).unwrap();
+        for line in &["first line\n", "second line\n", "third line\n", "fourth line\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        file.flush().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth line\n");
+        assert_eq!(fs::read_to_string(numbered(&path, 1, false)).unwrap(), "third line\n");
+        assert_eq!(fs::read_to_string(numbered(&path, 2, false)).unwrap(), "second line\n");
+        // files over max-files are removed
+        assert!(!numbered(&path, 3, false).exists());
+
+        // file moved away by logrotate is released after the signal
+        fs::rename(&path, dir.join("moved.log")).unwrap();
+        REOPEN_LOG.store(true, Ordering::Relaxed);
+        file.write_all(b"new\n").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new\n");
+
+        compress_file(&numbered(&path, 2, false)).unwrap();
+        assert!(numbered(&path, 2, true).exists());
+        assert!(!numbered(&path, 2, false).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,114 +1,160 @@
-// General
-//pub mod bigint;
-pub mod aggregate;
-pub mod carbon;
-pub mod config;
-pub mod consul;
-pub mod errors;
-pub mod management;
-pub mod peer;
-pub mod raft;
-pub mod server;
-pub mod task;
-pub mod udp;
-pub mod util;
-
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::fs;
+use std::path::Path;
+use std::process;
 use std::str::FromStr;
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::thread;
-use std::time::{self, Duration, Instant, SystemTime};
+use std::time::{Duration, Instant};
 
 use slog::{error, info, o, Drain, Level};
 
-use bytes::Bytes;
 use futures::future::{empty, ok};
 use futures::sync::mpsc;
 use futures::{Future, IntoFuture, Stream};
-use lazy_static::lazy_static;
-use serde_derive::{Deserialize, Serialize};
 use slog::warn;
 
-use tokio::runtime::current_thread::{spawn, Runtime};
+use tokio::runtime::current_thread::Runtime;
 use tokio::timer::{Delay, Interval};
+use tokio_rustls::TlsAcceptor;
+use tokio_signal::unix::{Signal, SIGHUP, SIGINT, SIGTERM, SIGUSR1, SIGUSR2};
+
+use bioyino::udp::{autotune_udp, bind_udp, start_async_udp, start_sync_udp};
+
+use bioyino::accounting::{init_accounting, summarize_accounting};
+use bioyino::activation::{tcp_listener, MANAGEMENT_SOCKET, PEER_SOCKET};
+use bioyino::acl::{PEER_SOURCES, STATSD_SOURCES};
+use bioyino::aggregate::AggregationMode;
+use bioyino::auth::{init_server_tls, server_tls};
+use bioyino::bench::run_bench;
+use bioyino::buildinfo::BuildInfo;
+use bioyino::carbon::{first_flush_delay, flush_to_carbon};
+use bioyino::check::{check_config, CheckReport};
+use bioyino::cluster::now_ms;
+use bioyino::config::{Command, Consul, LogTarget, Metrics, Network, System};
+use bioyino::consul::ConsulConsensus;
+use bioyino::ctl::{render, OutputFormat};
+use bioyino::daemon::daemonize;
+use bioyino::errors::GeneralError;
+use bioyino::health::watch_health;
+use bioyino::intern::{preload, NAMES};
+use bioyino::management::{dump_names, DumpFormat, MgmtClient, MgmtError, MgmtServer};
+use bioyino::logdrain::{facility, JournaldDrain, RateLimit, SyslogDrain};
+use bioyino::logfile::{RotatingFile, REOPEN_LOG};
+use bioyino::limits::RESOURCE_LIMITS;
+use bioyino::memory::{trim_memory, watch_memory};
+use bioyino::notify::{notify_ready, watch_dog};
+use bioyino::parse_errors::{summarize_parse_errors, PARSE_ERROR_STATS};
+use bioyino::events::EVENTS;
+use bioyino::gaps::detect_gap;
+use bioyino::handoff::{finish_handoff, serve_handoff, start_next_process, take_handoff};
+use bioyino::queue::{autotune_queues, is_ingestion, WORKER_QUEUES};
+use bioyino::quota::set_quotas;
+use bioyino::peer::{NativeProtocolServer, NativeProtocolSnapshot};
+use bioyino::privileges::drop_privileges;
+use bioyino::raft::start_internal_raft;
+use bioyino::spool::replay_spool;
+use bioyino::startup::wait_dependencies;
+use bioyino::ratelimit::set_rate_limits;
+use bioyino::tags::set_tag_options;
+use bioyino::report::dump_state;
+use bioyino::reload::Reloader;
+use bioyino::rules::init_rules;
+use bioyino::sandbox::enter_sandbox;
+use bioyino::shards::{follow_first_shard, shard_cpus, signal_shards, start_shards};
+use bioyino::windows::run_windows;
+use bioyino::shutdown::{restore_state, shutdown as shut_down};
+use bioyino::signing::init_signing;
+use bioyino::tunables::init_tunables;
+use bioyino::standby::watch_standby;
+use bioyino::stats::init_stats;
+use bioyino::supervise::{init_supervision, spawn_supervised, supervised};
+use bioyino::task::{Task, TaskRunner};
+use bioyino::template::default_config;
+use bioyino::trace::{export_spans, init_tracing};
+use bioyino::incident::{config_hash, init_incidents, watch_incidents};
+use bioyino::workers::{all_workers, init_workers};
+use bioyino::util::{get_hostname, pin_thread, resolve_cpus, stats_tags, try_resolve, BackoffRetryBuilder, OwnStats};
+use bioyino::{ConsensusKind, ConsensusState, CONSENSUS_STATE, IS_LEADER, PEER_ERRORS, PEER_LISTENING, RUNTIME_CONFIG};
 
-use crate::udp::{start_async_udp, start_sync_udp};
-use bioyino_metric::metric::Metric;
-
-use crate::aggregate::{AggregateOptions, AggregationMode, Aggregator};
-use crate::carbon::{CarbonBackend, CarbonClientOptions};
-use crate::config::{Command, Consul, Metrics, Network, System};
-use crate::consul::ConsulConsensus;
-use crate::errors::GeneralError;
-use crate::management::{MgmtClient, MgmtServer};
-use crate::peer::{NativeProtocolServer, NativeProtocolSnapshot};
-use crate::raft::start_internal_raft;
-use crate::task::{Task, TaskRunner};
-use crate::util::{try_resolve, BackoffRetryBuilder, OwnStats, UpdateCounterOptions};
-
-// floating type used all over the code, can be changed to f32, to use less memory at the price of
-// precision
-// TODO: make in into compilation feature
-pub type Float = f64;
-
-// a type to store pre-aggregated data
-pub type Cache = HashMap<Bytes, Metric<Float>>;
-
-// statistic counters
-pub static PARSE_ERRORS: AtomicUsize = AtomicUsize::new(0);
-pub static AGG_ERRORS: AtomicUsize = AtomicUsize::new(0);
-pub static PEER_ERRORS: AtomicUsize = AtomicUsize::new(0);
-pub static INGRESS: AtomicUsize = AtomicUsize::new(0);
-pub static INGRESS_METRICS: AtomicUsize = AtomicUsize::new(0);
-pub static EGRESS: AtomicUsize = AtomicUsize::new(0);
-pub static DROPS: AtomicUsize = AtomicUsize::new(0);
-
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
-#[serde(rename_all = "kebab-case", deny_unknown_fields)]
-pub enum ConsensusState {
-    Enabled,
-    Paused,
-    Disabled,
-}
+fn main() {
+    let (system, command) = System::load();
 
-impl FromStr for ConsensusState {
-    type Err = GeneralError;
+    if let Command::GenerateConfig = command {
+        print!("{}", default_config());
+        return;
+    }
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "enabled" | "enable" => Ok(ConsensusState::Enabled),
-            "disabled" | "disable" => Ok(ConsensusState::Disabled),
-            "pause" | "paused" => Ok(ConsensusState::Paused),
-            _ => Err(GeneralError::UnknownState),
+    if let Command::Check(load_error) = command {
+        // loading error means nothing else can be checked
+        let report = match load_error {
+            Some(e) => CheckReport { errors: vec![e], warnings: Vec::new() },
+            None => check_config(&system),
+        };
+        for warning in &report.warnings {
+            println!("warning: {}", warning);
+        }
+        for error in &report.errors {
+            println!("error: {}", error);
+        }
+        if !report.is_ok() {
+            process::exit(1);
         }
+        println!("configuration is OK");
+        return;
     }
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
-#[serde(rename_all = "kebab-case", deny_unknown_fields)]
-pub enum ConsensusKind {
-    None,
-    Consul,
-    Internal,
-}
 
-lazy_static! {
-    pub static ref CONSENSUS_STATE: Mutex<ConsensusState> = { Mutex::new(ConsensusState::Disabled) };
-}
+    if let Command::BuildInfo(load_error) = command {
+        let info = BuildInfo::new(if load_error.is_none() { Some(&system) } else { None });
+        // info is a plain struct, it is always serializable
+        print!("{}", render(&serde_json::to_value(&info).unwrap(), OutputFormat::Table));
+        if let Some(e) = load_error {
+            println!("error: {}", e);
+        }
+        return;
+    }
 
-pub static IS_LEADER: AtomicBool = AtomicBool::new(false);
+    if let Command::Bench(options, output) = command {
+        match run_bench(options) {
+            // report is a plain struct, it is always serializable
+            Ok(report) => print!("{}", render(&serde_json::to_value(&report).unwrap(), output)),
+            Err(e) => {
+                println!("error: {}", e);
+                process::exit(1);
+            }
+        }
+        return;
+    }
 
-fn main() {
-    let (system, command) = System::load();
+    // shards started by the first one are already in the background and go away with it
+    let (shard_count, shard_index) = (system.shards.count.max(1), system.shards.index);
+    if shard_index > 0 {
+        if !follow_first_shard() {
+            process::exit(0);
+        }
+    } else if let Err(e) = daemonize(&system.daemon) {
+        // forking keeps only the calling thread, so this goes before any thread is started
+        println!("error: {}", e);
+        process::exit(1);
+    }
 
+    // limits of the container are taken before anything is sized by them, shards share them equally
+    let limits = RESOURCE_LIMITS.clone();
+    let cpus = (limits.cpus / shard_count).max(1);
+    let (n_threads, w_threads) = system.thread_counts(cpus);
+    let mut system = system;
+    let statsd_sockets = if system.network.multimessage { n_threads } else { system.network.async_sockets } * shard_count;
+    let recv_buffers_capped = limits.cap_recv_buffers(&mut system.network, statsd_sockets);
     let config = system.clone();
     let System {
+        config_version: _,
         verbosity,
         network: Network {
             listen,
             peer_listen,
             peer_client_bind,
+            peer_tls,
             mgmt_listen,
             bufsize,
             multimessage,
@@ -121,47 +167,153 @@ fn main() {
             async_sockets,
             nodes,
             snapshot_interval,
+            untyped_as: _,
+            batch_size: _,
+            batch_flush_time: _,
+            recv_buffer: _,
+            busy_poll: _,
+            socket_autotune,
+            max_recv_buffer,
+            max_mm_packets,
+            slow_connection: _,
+            slow_connection_kill: _,
+            statsd_allow,
+            statsd_deny,
+            peer_allow,
+            peer_deny,
+            statsd_hmac_key: _,
+            statsd_hmac_key_file: _,
+            statsd_hmac_max_age: _,
+            statsd_rate_limit: _,
+            peer_rate_limit: _,
+            rate_limit_burst: _,
         },
         raft,
         consul: Consul { start_as: consul_start_as, agent, session_ttl: consul_session_ttl, renew_time: consul_renew_time, key_name: consul_key },
+        standby,
         metrics: Metrics {
             //           max_metrics,
-            mut count_updates,
+            count_updates,
             update_counter_prefix,
             update_counter_suffix,
-            update_counter_threshold,
+            update_counter_threshold: _,
             aggregation_mode,
             aggregation_threads,
             consistent_parsing: _,
             log_parse_errors: _,
+            parse_error_samples,
+            parse_error_summary,
             max_unparsed_buffer: _,
+            rules_file: _,
+            untyped_as: _,
+            sample_arena_size: _,
+            slow_task: _,
+            lowercase_tag_names: _,
+            tag_chars: _,
+            max_tags: _,
+            max_tag_length: _,
+            max_tag_bytes: _,
+            tag_overflow: _,
+            expected_metrics,
+            warmup_dump,
         },
         carbon,
-        n_threads,
-        w_threads,
+        management,
+        memory,
+        log: log_options,
+        tracing,
+        incidents,
+        supervision: _,
+        health,
+        accounting,
+        privileges,
+        sandbox: _,
+        tls,
+        shutdown,
+        startup,
+        daemon: _,
+        shards: _,
+        windows: _,
+        n_threads: _,
+        w_threads: _,
+        network_threads_ratio: _,
+        network_cpus,
+        counting_cpus,
         stats_interval: s_interval,
         task_queue_size,
+        task_queue_autotune,
+        task_queue_max_size,
         start_as_leader,
         stats_prefix,
+        stats_tags,
+        stats_node_tag,
         consensus,
+        include: _,
+        config_path: _,
+        config_format: _,
+        overrides: _,
+        migration_warnings,
     } = system;
 
     let verbosity = Level::from_str(&verbosity).expect("bad verbosity");
 
+    init_stats();
+    init_accounting(&accounting);
+    set_quotas(&accounting.quotas).expect("bad accounting.quotas");
+    // configuration is checked already, so networks are valid
+    STATSD_SOURCES.set(&statsd_allow, &statsd_deny).expect("bad network.statsd-allow or network.statsd-deny");
+    PEER_SOURCES.set(&peer_allow, &peer_deny).expect("bad network.peer-allow or network.peer-deny");
+    init_signing(&config.network).expect("bad network.statsd-hmac-key");
+    set_rate_limits(&config.network);
+    set_tag_options(&config.metrics);
+
+    // panics of any thread are reported, so this goes before threads are started
+    init_supervision();
+    if incidents.sentry_dsn.is_some() || incidents.webhook.is_some() {
+        init_incidents(&incidents, config_hash(&config));
+    }
+
     let mut runtime = Runtime::new().expect("creating runtime for main thread");
 
     // Set logging
-    let decorator = slog_term::TermDecorator::new().build();
-    let drain = slog_term::FullFormat::new(decorator).build().fuse();
-    let filter = slog::LevelFilter::new(drain, verbosity).fuse();
-    let drain = slog_async::Async::new(filter).build().fuse();
+    let drain = match log_options.target() {
+        LogTarget::File => {
+            let file = log_options.file.as_ref().expect("log.file must be set for file log target");
+            let file = RotatingFile::new(file, &log_options).expect("opening log file");
+            let drain = slog_term::FullFormat::new(slog_term::PlainDecorator::new(file)).build().fuse();
+            let filter = slog::LevelFilter::new(drain, verbosity).fuse();
+            slog_async::Async::new(RateLimit::new(filter, &log_options)).build().fuse()
+        }
+        // records failed to be sent are lost rather than stopping the server
+        LogTarget::Syslog => {
+            let facility = facility(&log_options.syslog_facility).expect("bad syslog facility");
+            let drain = SyslogDrain::new(&log_options.syslog_address, facility).expect("connecting to syslog").ignore_res();
+            let filter = slog::LevelFilter::new(drain, verbosity).fuse();
+            slog_async::Async::new(RateLimit::new(filter, &log_options)).build().fuse()
+        }
+        LogTarget::Journald => {
+            let drain = JournaldDrain::new().expect("connecting to journald").ignore_res();
+            let filter = slog::LevelFilter::new(drain, verbosity).fuse();
+            slog_async::Async::new(RateLimit::new(filter, &log_options)).build().fuse()
+        }
+        LogTarget::Terminal => {
+            let decorator = slog_term::TermDecorator::new().build();
+            let drain = slog_term::FullFormat::new(decorator).build().fuse();
+            let filter = slog::LevelFilter::new(drain, verbosity).fuse();
+            slog_async::Async::new(RateLimit::new(filter, &log_options)).build().fuse()
+        }
+    };
     let rlog = slog::Logger::root(drain, o!("program"=>"bioyino"));
     // this lets root logger live as long as it needs
     let _guard = slog_scope::set_global_logger(rlog.clone());
 
-    if let Command::Query(command, dest) = command {
-        let dest = try_resolve(&dest);
-        let command = MgmtClient::new(rlog.clone(), dest.clone(), command);
+    for message in &migration_warnings {
+        warn!(rlog, "outdated configuration"; "message"=>message);
+    }
+
+    if let Command::Query(command, host, output) = command {
+        let dest = try_resolve(&host);
+        let command = MgmtClient::new(rlog.clone(), dest.clone(), command).with_auth(&host, &management, &tls).with_output(output);
 
         runtime.block_on(command.into_future()).unwrap_or_else(|e| {
             warn!(rlog,
@@ -173,44 +325,117 @@ fn main() {
         return;
     }
 
+    let build = BuildInfo::new(Some(&config));
+    info!(rlog, "starting bioyino"; "version"=>build.version, "commit"=>build.commit, "commit-date"=>build.commit_date, "built"=>build.build_timestamp, "features"=>build.features.join(","), "config-hash"=>build.config_hash.unwrap_or_default());
+
     if count_updates && update_counter_prefix.len() == 0 && update_counter_suffix.len() == 0 {
         warn!(rlog, "update counting suffix and prefix are empty, update counting disabled to avoid metric rewriting");
-        count_updates = false;
     }
 
-    let update_counter_prefix: Bytes = update_counter_prefix.into();
-    let update_counter_suffix: Bytes = update_counter_suffix.into();
+    if aggregation_threads.is_some() && aggregation_mode != AggregationMode::Separate {
+        info!(rlog, "aggregation_threads parameter only works in \"separate\" mode and will be ignored");
+    }
 
     let config = Arc::new(config);
+    *RUNTIME_CONFIG.write().unwrap() = config.clone();
+    init_rules(&config.metrics.rules_file).expect("loading rules file");
+    init_tunables(&config).expect("setting tunables from config");
     let log = rlog.new(o!("thread" => "main"));
+    init_activation(&log);
+    // sockets of the previous process are taken before any listener is started
+    let handoff = shutdown.handoff_socket.as_ref().and_then(|path| take_handoff(path, &log));
+    let handed_off = handoff.is_some();
+    // names, consul and ports are waited for in this order, before anything depending on them is started
+    wait_dependencies(&config, &log).expect("waiting for startup dependencies");
+    if shard_count > 1 && shard_index == 0 {
+        info!(log, "starting shards"; "shards"=>shard_count);
+        start_shards(shard_count, &log);
+    }
+
+    info!(log, "resource limits"; "host-cpus"=>limits.host_cpus, "cgroup-cpus"=>limits.cgroup_cpus.unwrap_or(0), "host-memory"=>limits.host_memory_bytes.unwrap_or(0), "cgroup-memory"=>limits.cgroup_memory_bytes.unwrap_or(0));
+    if recv_buffers_capped {
+        info!(log, "statsd receive buffers are lowered to fit cgroup memory limit"; "recv-buffer"=>config.network.recv_buffer, "max-recv-buffer"=>config.network.max_recv_buffer);
+    }
+    info!(log, "starting threads"; "cpus"=>cpus, "network"=>n_threads, "counting"=>w_threads);
+    let network_cpus = network_cpus.map(|spec| shard_cpus(&resolve_cpus(&spec).expect("resolving network-cpus"), shard_index, shard_count)).unwrap_or_default();
+    let counting_cpus = counting_cpus.map(|spec| shard_cpus(&resolve_cpus(&spec).expect("resolving counting-cpus"), shard_index, shard_count)).unwrap_or_default();
 
     // Init task options before initializing task threads
 
+    // names of the previous run are known in advance, so the name table is not rehashed while they come
+    NAMES.write().unwrap().reserve(expected_metrics);
+    if let Some(path) = warmup_dump {
+        let path = Path::new(&path);
+        let names = fs::read(path).map_err(MgmtError::Io).and_then(|dump| dump_names(&dump, &DumpFormat::from_path(path)));
+        match names {
+            Ok(names) => info!(log, "metric names loaded from dump"; "file"=>format!("{}", path.display()), "names"=>preload(names)),
+            Err(e) => warn!(log, "error loading metric names from dump"; "file"=>format!("{}", path.display()), "error"=>e.to_string()),
+        }
+    }
+    // a name may come to any worker, but usually they are distributed evenly
+    let cache_capacity = if expected_metrics > 0 { expected_metrics / w_threads + 1 } else { 8192 };
+
     // Start counting threads
     info!(log, "starting counting threads");
-    let mut chans = Vec::with_capacity(w_threads);
-    for i in 0..w_threads {
-        let (tx, rx) = mpsc::channel(task_queue_size);
-        chans.push(tx);
-        let tlog = log.clone();
-        let cf = config.clone();
-        thread::Builder::new()
-            .name(format!("bioyino_cnt{}", i).into())
-            .spawn(move || {
-                let runner = TaskRunner::new(tlog, cf, 8192);
-                let mut runtime = Runtime::new().expect("creating runtime for counting worker");
-                let future = rx
-                    .fold(runner, move |mut runner, task: Task| {
-                        runner.run(task);
-                        Ok(runner)
-                    })
+    // with autotune channels get the maximal size and the queue is limited by it's soft capacity
+    let channel_size = if task_queue_autotune { task_queue_max_size.max(task_queue_size) } else { task_queue_size };
+    // workers are started the same way when added by management command
+    let (worker_log, worker_config) = (log.clone(), config.clone());
+    let spawn_worker = move |i: usize, retired: Arc<AtomicBool>| {
+        let (tx, rx) = mpsc::channel(channel_size);
+        WORKER_QUEUES[i].set_capacity(task_queue_size);
+        let tlog = worker_log.clone();
+        let cf = worker_config.clone();
+        let cpus = counting_cpus.clone();
+        // the receiver outlives panics of the worker, so tasks sent to it are processed by the restarted one
+        let rx = RefCell::new(rx);
+        spawn_supervised(format!("bioyino_cnt{}", i), worker_log.clone(), move || {
+            // caches are allocated after pinning to be local to thread's NUMA node
+            if let Err(e) = pin_thread(&cpus, i) {
+                warn!(tlog, "pinning counting thread to CPU"; "error"=>e.to_string());
+            }
+            let runner = TaskRunner::new(tlog.clone(), cf.clone(), cache_capacity);
+            let mut runtime = Runtime::new().expect("creating runtime for counting worker");
+            enter_sandbox(&cf.sandbox, &tlog);
+            let mut rx = rx.borrow_mut();
+            let retired = retired.clone();
+            let future = rx
+                .by_ref()
+                // a removed worker exits after it's caches are drained
+                .take_while(move |_| Ok(!retired.load(Ordering::SeqCst)))
+                .fold(runner, move |mut runner, task: Task| {
+                    if is_ingestion(&task) {
+                        WORKER_QUEUES[i].pop(1);
+                    }
+                    runner.run(task);
+                    Ok(runner)
+                })
                 .map(|_| ())
-                    .map_err(|_| ());
-                //        let future = rx.for_each(|task: Task| ok(runner.run(task)));
-                runtime.block_on(future).expect("worker thread failed");
-            })
-        .expect("starting counting worker thread");
+                .map_err(|_| ());
+            //        let future = rx.for_each(|task: Task| ok(runner.run(task)));
+            runtime.block_on(future).expect("worker thread failed");
+        })
+        .map(|_| tx)
+    };
+    let chans = init_workers(w_threads, Box::new(spawn_worker)).expect("starting counting worker threads");
+    if task_queue_autotune {
+        info!(log, "worker queue sizes are tuned automatically"; "min"=>task_queue_size, "max"=>channel_size);
+        runtime.spawn(autotune_queues(task_queue_size, channel_size, Duration::from_secs(1), rlog.clone()));
+    }
+
+    if let Some(ref path) = shutdown.state_file {
+        // state older than an interval belongs to one already sent by another node
+        match restore_state(path, Duration::from_millis(carbon.interval), &chans, &log) {
+            Ok(0) => (),
+            Ok(metrics) => info!(log, "caches restored"; "file"=>path, "metrics"=>metrics),
+            Err(e) => warn!(log, "restoring caches failed"; "error"=>e.to_string()),
+        }
     }
+    // intervals missed while the server was down are counted and backfilled by the first flush
+    detect_gap(&carbon, &log).unwrap_or_else(|e| {
+        warn!(log, "reading flush mark failed"; "error"=>e.to_string());
+        0
+    });
 
     let stats_prefix = stats_prefix.trim_end_matches(".").to_string();
 
@@ -218,16 +443,73 @@ fn main() {
     let own_stat_chan = chans[0].clone();
     let own_stat_log = rlog.clone();
     info!(log, "starting own stats counter");
-    let own_stats = OwnStats::new(s_interval, stats_prefix, own_stat_chan, own_stat_log);
+    let node_name = config.raft.this_node.clone().or_else(get_hostname).unwrap_or_default();
+    let stats_tags = stats_tags(&stats_tags, &stats_node_tag, &node_name);
+    let own_stats = OwnStats::new(s_interval, stats_prefix, stats_tags, own_stat_chan, own_stat_log);
     runtime.spawn(own_stats);
 
-    info!(log, "starting snapshot sender");
+    // budget is taken from runtime config, so the watcher is started even with no budget to allow setting it by reload
+    info!(log, "starting memory watcher"; "budget"=>limits.memory_budget(&memory));
+    runtime.spawn(watch_memory(chans.clone(), Duration::from_millis(memory.check_interval.max(100)), rlog.clone()));
+    if memory.trim_interval > 0 {
+        runtime.spawn(trim_memory(Duration::from_millis(memory.trim_interval), rlog.clone()));
+    }
+
+    if accounting.enabled {
+        info!(log, "starting accounting"; "depth"=>accounting.depth, "summary-interval"=>accounting.summary_interval);
+        runtime.spawn(summarize_accounting(Duration::from_millis(accounting.summary_interval.max(1000)), rlog.clone()));
+    }
+
+    info!(log, "starting health scoring"; "degraded-score"=>health.degraded_score);
+    runtime.spawn(watch_health(Duration::from_millis(health.check_interval.max(100)), rlog.clone()));
+
+    if incidents.sentry_dsn.is_some() || incidents.webhook.is_some() {
+        info!(log, "starting incident reporting");
+        runtime.spawn(watch_incidents(incidents, consensus.clone(), rlog.clone()));
+    }
+
+    if tracing.endpoint.is_some() {
+        info!(log, "starting span exporter"; "sample-ratio"=>tracing.sample_ratio);
+        init_tracing(&tracing);
+        runtime.spawn(export_spans(tracing, rlog.clone()));
+    }
+
+    PARSE_ERROR_STATS.set_capacity(parse_error_samples);
+    EVENTS.set_capacity(management.event_log_size);
+    if parse_error_summary > 0 {
+        runtime.spawn(summarize_parse_errors(Duration::from_millis(parse_error_summary), rlog.clone()));
+    }
+
+    // peer and carbon connections are made and accepted with these settings too, so they are read before starting them
+    let tls_enabled = init_server_tls(&tls).expect("TLS settings");
+    assert!(tls_enabled || !peer_tls, "network.peer-tls requires tls.cert and tls.key to be set");
+
+    // all listeners are bound and certificates are read while still being root, so ports below 1024 and files
+    // readable only by root can be used. TCP listeners are kept by activation and taken again by servers started
+    // below, so no data from network is received before privileges are dropped
+    tcp_listener(PEER_SOCKET, &peer_listen).expect("binding peer server");
+    tcp_listener(MANAGEMENT_SOCKET, &mgmt_listen).expect("binding management server");
+    let udp_sockets = bind_udp(&log, listen, &config, multimessage, async_sockets, bufsize, mm_packets);
+    drop_privileges(&privileges, &log).expect("dropping privileges");
+
+    // metrics given up on before a crash or restart drain in background, while new ones are flushed as usual,
+    // the backend may be connected to over TLS, so this goes after reading the settings
+    replay_spool(&carbon, &tls, &log).unwrap_or_else(|e| {
+        warn!(log, "replaying spool failed"; "error"=>e.to_string());
+        None
+    });
+
+    info!(log, "starting snapshot sender"; "tls"=>peer_tls);
     let snap_log = rlog.clone();
     let snap_err_log = rlog.clone();
 
-    let snapshot = NativeProtocolSnapshot::new(&snap_log, nodes, peer_client_bind, Duration::from_millis(snapshot_interval as u64), &chans).into_future().map_err(move |e| {
-        PEER_ERRORS.fetch_add(1, Ordering::Relaxed);
-        info!(snap_err_log, "error sending snapshot";"error"=>format!("{}", e));
+    let snapshot = NativeProtocolSnapshot::new(&snap_log, nodes, peer_client_bind, Duration::from_millis(snapshot_interval as u64), &chans);
+    let snapshot = supervised("snapshot-sender", &rlog, move || {
+        let snap_err_log = snap_err_log.clone();
+        snapshot.clone().into_future().map_err(move |e| {
+            PEER_ERRORS.add(1);
+            info!(snap_err_log, "error sending snapshot";"error"=>format!("{}", e));
+        })
     });
     runtime.spawn(snapshot);
 
@@ -236,11 +518,14 @@ fn main() {
     let peer_server_ret = BackoffRetryBuilder { delay: 1, delay_mul: 1f32, delay_max: 1, retries: ::std::usize::MAX };
 
     let peer_server = NativeProtocolServer::new(rlog.clone(), peer_listen, chans.clone());
-    let peer_server = peer_server_ret
-        .spawn(peer_server)
-        // with unlimited number of retries, BackoffRetry will never return any error
-        // server logs all erros inside itself
-        .map_err(|_| ());
+    let peer_server = supervised("peer-server", &rlog, move || {
+        peer_server_ret
+            .clone()
+            .spawn(peer_server.clone())
+            // with unlimited number of retries, BackoffRetry will never return any error
+            // server logs all erros inside itself
+            .map_err(|_| ())
+    });
 
     runtime.spawn(peer_server);
 
@@ -292,6 +577,10 @@ fn main() {
             consensus.set_renew_time(Duration::from_millis(consul_renew_time as u64));
             runtime.spawn(consensus.into_future().map_err(|_| ())); // TODO errors
         }
+        ConsensusKind::Standby => {
+            info!(log, "starting hot-standby pair"; "role"=>format!("{:?}", standby.role), "primary"=>standby.primary.clone().unwrap_or_default());
+            runtime.spawn(watch_standby(standby, consensus_log));
+        }
         ConsensusKind::None => {
             if !start_as_leader {
                 // starting as non-leader in this mode can be useful for agent mode
@@ -304,126 +593,115 @@ fn main() {
     info!(log, "starting management server");
     let m_serv_log = rlog.clone();
     let m_serv_err_log = rlog.clone();
-    let m_server = hyper::Server::bind(&mgmt_listen).serve(move || ok::<_, hyper::Error>(MgmtServer::new(m_serv_log.clone(), &mgmt_listen))).map_err(move |e| {
-        warn!(m_serv_err_log, "management server gone with error: {:?}", e);
-    });
+    let m_config = config.clone();
+    let reloader = Reloader::new(&rlog);
+    let m_reloader = reloader.clone();
+    let new_service = move || ok::<_, hyper::Error>(MgmtServer::new(m_serv_log.clone(), &mgmt_listen, all_workers(), m_config.clone(), m_reloader.clone()));
+    if tls_enabled {
+        info!(log, "management server uses TLS"; "client-auth"=>tls.client_ca.is_some(), "min-version"=>format!("{:?}", tls.min_version), "cipher-policy"=>format!("{:?}", tls.cipher_policy));
+        let hs_log = rlog.clone();
+        let incoming = tcp_listener(MANAGEMENT_SOCKET, &mgmt_listen)
+            .expect("binding management server")
+            .incoming()
+            .map(move |stream| {
+                let hs_log = hs_log.clone();
+                // settings are taken for every connection, so reloaded certificates are used right away
+                let acceptor = TlsAcceptor::from(server_tls().expect("TLS cannot be disabled without restart"));
+                acceptor.accept(stream).then(move |res| {
+                    // failed handshake should not stop the server
+                    Ok::<_, std::io::Error>(res.map_err(|e| warn!(hs_log, "management TLS handshake failed"; "error"=>e.to_string())).ok())
+                })
+            })
+            .buffer_unordered(64)
+            .filter_map(|stream| stream);
+        let m_server = hyper::Server::builder(incoming).serve(new_service).map_err(move |e| {
+            warn!(m_serv_err_log, "management server gone with error: {:?}", e);
+        });
+        runtime.spawn(m_server);
+    } else {
+        // listener is kept to be handed off on restart
+        let listener = tcp_listener(MANAGEMENT_SOCKET, &mgmt_listen).expect("binding management server");
+        let m_server = hyper::Server::builder(listener.incoming()).serve(new_service).map_err(move |e| {
+            warn!(m_serv_err_log, "management server gone with error: {:?}", e);
+        });
+        runtime.spawn(m_server);
+    }
 
-    runtime.spawn(m_server);
+    if config.windows.len() > 0 {
+        info!(log, "starting window scheduler"; "windows"=>config.windows.len());
+    }
+    // windows may be added by reloading, so the scheduler is always running
+    runtime.spawn(run_windows(reloader.clone(), rlog.clone()));
+
+    info!(log, "starting config reload handler");
+    let hup_log = rlog.clone();
+    let hup_err_log = rlog.clone();
+    let sighup = Signal::new(SIGHUP)
+        .flatten_stream()
+        .for_each(move |_| {
+            info!(hup_log, "SIGHUP received, reloading config");
+            signal_shards(SIGHUP);
+            reloader.reload().map(|_| ()).unwrap_or_else(|e| {
+                warn!(hup_log, "config reload failed, nothing changed"; "error"=>e.to_string());
+            });
+            Ok(())
+        })
+        .map_err(move |e| {
+            warn!(hup_err_log, "SIGHUP handler gone with error"; "error"=>e.to_string());
+        });
+    runtime.spawn(sighup);
+
+    if log_options.target() == LogTarget::File {
+        let usr_log = rlog.clone();
+        let usr_err_log = rlog.clone();
+        let sigusr = Signal::new(SIGUSR2)
+            .flatten_stream()
+            .for_each(move |_| {
+                // the file is reopened by the logging thread before writing this message
+                REOPEN_LOG.store(true, Ordering::Relaxed);
+                signal_shards(SIGUSR2);
+                info!(usr_log, "SIGUSR2 received, reopening log file");
+                Ok(())
+            })
+            .map_err(move |e| {
+                warn!(usr_err_log, "SIGUSR2 handler gone with error"; "error"=>e.to_string());
+            });
+        runtime.spawn(sigusr);
+    }
 
     info!(log, "starting carbon backend");
-    let tchans = chans.clone();
     let carbon_log = rlog.clone();
 
+    // interval cannot be reloaded, all other carbon options are taken from runtime config on every tick
     let dur = Duration::from_millis(carbon.interval);
-    let carbon_timer = Interval::new(Instant::now() + dur, dur);
-    let mut carbon_config = carbon.clone();
-    if carbon_config.chunks == 0 {
-        carbon_config.chunks = 1
-    }
-    let multi_threads = match aggregation_threads {
-        Some(value) if aggregation_mode == AggregationMode::Separate => value,
-        Some(_) => {
-            info!(carbon_log, "aggregation_threads parameter only works in \"separate\" mode and will be ignored");
-            0
+    let node = config.raft.this_node.clone().or_else(get_hostname).unwrap_or_default();
+    let first = match carbon.flush_offset_ms(&node) {
+        Some(offset) => {
+            info!(log, "flushes are aligned to wall clock"; "offset-ms"=>offset, "node"=>&node);
+            Duration::from_millis(first_flush_delay(now_ms(), carbon.interval, offset))
         }
-        None if aggregation_mode == AggregationMode::Separate => 0,
-        _ => 0,
+        None => dur,
     };
-
-    let carbon_timer = carbon_timer.map_err(|e| GeneralError::Timer(e)).for_each(move |_tick| {
-        let ts = SystemTime::now().duration_since(time::UNIX_EPOCH).map_err(|e| GeneralError::Time(e))?;
-
-        let backend_addr = try_resolve(&carbon.address);
-        let tchans = tchans.clone();
-        let carbon_log = carbon_log.clone();
-
-        let update_counter_prefix = update_counter_prefix.clone();
-        let update_counter_suffix = update_counter_suffix.clone();
-        let backend_opts = carbon_config.clone();
-        let aggregation_mode = aggregation_mode.clone();
-        thread::Builder::new()
-            .name("bioyino_carbon".into())
-            .spawn(move || {
-                let carbon_log = carbon_log.clone();
-                let runtime_log = carbon_log.clone();
-
-                let mut runtime = match Runtime::new() {
-                    Ok(runtime) => runtime,
-                    Err(e) => {
-                        error!(carbon_log, "creating runtime for backend"; "error"=>e.to_string());
-                        return;
-                    }
-                };
-
-                let is_leader = IS_LEADER.load(Ordering::SeqCst);
-
-                let options = AggregateOptions {
-                    is_leader,
-                    update_counter: if count_updates { Some(UpdateCounterOptions { threshold: update_counter_threshold, prefix: update_counter_prefix, suffix: update_counter_suffix }) } else { None },
-                    aggregation_mode,
-                    multi_threads,
-                };
-
-                if is_leader {
-                    info!(carbon_log, "leader sending metrics");
-                    let (backend_tx, backend_rx) = mpsc::unbounded();
-                    let aggregator = Aggregator::new(options, tchans, backend_tx, carbon_log.clone()).into_future();
-
-                    runtime.spawn(aggregator);
-
-                    let handle = runtime.handle();
-                    let carbon_sender = backend_rx
-                        .inspect(|_| {
-                            EGRESS.fetch_add(1, Ordering::Relaxed);
-                        })
-                    .collect()
-                        .map(move |metrics| {
-                            let carbon_log = carbon_log.clone();
-                            let carbon = backend_opts.clone();
-                            let chunk_size = metrics.len() / carbon.chunks;
-                            // TODO we could do this without allocations
-                            // but in rust it's not so easy with these types
-                            // probably Pin API would help
-                            // probably changing to Arc<[Metric]> would
-                            metrics
-                                .chunks(chunk_size)
-                                .map(move |metrics| {
-                                    let options = CarbonClientOptions { addr: backend_addr, bind: backend_opts.bind_address };
-                                    let backend = CarbonBackend::new(options, ts, Arc::new(metrics.to_vec()), carbon_log.clone());
-                                    let retrier = BackoffRetryBuilder { delay: backend_opts.connect_delay, delay_mul: backend_opts.connect_delay_multiplier, delay_max: backend_opts.connect_delay_max, retries: backend_opts.send_retries };
-                                    let carbon_log = carbon_log.clone();
-                                    let retrier = retrier.spawn(backend).map_err(move |e| {
-                                        error!(carbon_log.clone(), "Failed to send to graphite"; "error"=>format!("{:?}",e));
-                                    });
-                                    spawn(retrier);
-                                })
-                            .last();
-                        });
-
-                    handle.spawn(carbon_sender).unwrap_or_else(|e| {
-                        error!(runtime_log, "spawning sender"; "error"=>format!("{:?}", e));
-                    });           ;
-                    runtime.run().unwrap_or_else(|e| {
-                        error!(runtime_log, "Failed to send to graphite"; "error"=>format!("{:?}", e));
-                    });
-                    // runtime.block_on(backend).unwrap_or_else(|e| {
-                    //error!(carbon_log, "Failed to send to graphite"; "error"=>e);
-                    // });
-                } else {
-                    info!(carbon_log, "not leader, removing metrics");
-                    let (backend_tx, _) = mpsc::unbounded();
-                    let aggregator = Aggregator::new(options, tchans, backend_tx, carbon_log.clone()).into_future();
-                    runtime.block_on(aggregator.then(|_| Ok::<(), ()>(()))).unwrap_or_else(|e| error!(carbon_log, "Failed to join aggregated metrics"; "error"=>e));
-                }
+    let tlog = rlog.clone();
+    // timer restarted after a panic is not aligned to wall clock anymore
+    let mut first = Some(first);
+    let carbon_timer = supervised("carbon-timer", &rlog, move || {
+        let carbon_timer = Interval::new(Instant::now() + first.take().unwrap_or(dur), dur);
+        let (carbon_log, tlog) = (carbon_log.clone(), tlog.clone());
+        carbon_timer
+            .map_err(|e| GeneralError::Timer(e))
+            .for_each(move |_tick| {
+                // workers may be added or removed at runtime, so all of them are taken every time
+                flush_to_carbon(all_workers(), None, carbon_log.clone()).map(|_| ()).unwrap_or_else(|e| {
+                    error!(carbon_log, "flushing metrics to carbon"; "error"=>e.to_string());
+                });
+                Ok(())
+            })
+            .map_err(move |e| {
+                warn!(tlog, "error running carbon"; "error"=>e.to_string());
             })
-        .expect("starting thread for sending to graphite");
-        Ok(())
     });
-
-    let tlog = rlog.clone();
-    runtime.spawn(carbon_timer.map_err(move |e| {
-        warn!(tlog, "error running carbon"; "error"=>e.to_string());
-    }));
+    runtime.spawn(carbon_timer);
 
     // For each thread we create
     let mut flush_flags = Arc::new(Vec::new());
@@ -450,11 +728,82 @@ fn main() {
         }));
     }
 
+    info!(log, "starting shutdown handler"; "graceful"=>shutdown.graceful);
+    for (signal, name) in [(SIGTERM, "SIGTERM"), (SIGINT, "SIGINT")].iter().cloned() {
+        let (sig_flags, sig_log, sig_err_log, options) = (flush_flags.clone(), rlog.clone(), rlog.clone(), shutdown.clone());
+        let handler = Signal::new(signal)
+            .flatten_stream()
+            .for_each(move |_| {
+                shut_down(&options, all_workers(), sig_flags.clone(), name, &sig_log);
+                Ok(())
+            })
+            .map_err(move |e| {
+                warn!(sig_err_log, "signal handler gone with error"; "signal"=>name, "error"=>e.to_string());
+            });
+        runtime.spawn(handler);
+    }
+
+    if socket_autotune {
+        info!(log, "statsd UDP socket is tuned automatically"; "max-recv-buffer"=>max_recv_buffer, "max-mm-packets"=>if multimessage { max_mm_packets } else { 0 });
+        runtime.spawn(autotune_udp(listen.port(), max_recv_buffer, max_mm_packets, Duration::from_secs(5), log.clone()));
+    }
+
     if multimessage {
-        start_sync_udp(log, listen, &chans, config.clone(), n_threads, bufsize, mm_packets, mm_async, mm_timeout, flush_flags.clone());
+        start_sync_udp(log, udp_sockets, &chans, config.clone(), n_threads, &network_cpus, bufsize, mm_packets, mm_async, mm_timeout, flush_flags.clone());
     } else {
-        start_async_udp(log, listen, &chans, config.clone(), n_threads, greens, async_sockets, bufsize, flush_flags.clone());
+        start_async_udp(log, udp_sockets, &chans, config.clone(), n_threads, &network_cpus, greens, bufsize, flush_flags.clone());
+    }
+
+    if let Some(ref path) = shutdown.handoff_socket {
+        if let Some(stream) = handoff {
+            // peer listener is bound when the runtime starts, the previous process must go on receiving until then
+            let (h_chans, h_log) = (chans.clone(), rlog.clone());
+            let started = Interval::new(Instant::now(), Duration::from_millis(10))
+                .map_err(|_| ())
+                .skip_while(|_| Ok(!PEER_LISTENING.load(Ordering::Relaxed)))
+                .into_future()
+                .map(move |_| {
+                    info!(h_log, "listeners started, finishing handoff");
+                    finish_handoff(stream, h_chans, &h_log);
+                })
+                .map_err(|_| ());
+            runtime.spawn(started);
+        }
+        info!(log, "starting handoff listener"; "path"=>path);
+        serve_handoff(path, shutdown.clone(), chans.clone(), flush_flags.clone(), &rlog).expect("listening for handoff");
+
+        let winch_log = rlog.clone();
+        let winch_err_log = rlog.clone();
+        let sigwinch = Signal::new(libc::SIGWINCH)
+            .flatten_stream()
+            .for_each(move |_| {
+                match start_next_process() {
+                    Ok(pid) => info!(winch_log, "SIGWINCH received, started the next process to hand off to"; "pid"=>pid),
+                    Err(e) => warn!(winch_log, "SIGWINCH received, starting the next process failed"; "error"=>e.to_string()),
+                }
+                Ok(())
+            })
+            .map_err(move |e| {
+                warn!(winch_err_log, "SIGWINCH handler gone with error"; "error"=>e.to_string());
+            });
+        runtime.spawn(sigwinch);
     }
 
+    let (usr_log, usr_err_log) = (rlog.clone(), rlog.clone());
+    let sigusr = Signal::new(SIGUSR1)
+        .flatten_stream()
+        .map_err(move |e| {
+            warn!(usr_err_log, "SIGUSR1 handler gone with error"; "error"=>e.to_string());
+        })
+        .for_each(move |_| {
+            info!(usr_log, "SIGUSR1 received, reporting state");
+            dump_state(&all_workers(), &usr_log)
+        });
+    runtime.spawn(sigusr);
+
+    // both do nothing unless started by systemd with Type=notify and WatchdogSec=
+    runtime.spawn(notify_ready(consensus, handed_off, rlog.clone()));
+    runtime.spawn(watch_dog(chans.clone(), rlog.clone()));
+
     runtime.block_on(empty::<(), ()>()).expect("running runtime in main thread");
 }
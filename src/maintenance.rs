@@ -0,0 +1,149 @@
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+
+use lazy_static::lazy_static;
+use serde_derive::{Deserialize, Serialize};
+use slog::{info, warn, Logger};
+
+use crate::cluster::now_ms;
+use crate::counter::Counter;
+use crate::errors::GeneralError;
+use crate::events::event;
+use crate::util::resolve_addr;
+use crate::{IS_LEADER, RUNTIME_CONFIG};
+
+/// Set in maintenance mode: the node does not flush to backend and reports itself as draining
+pub static MAINTENANCE: AtomicBool = AtomicBool::new(false);
+
+static FORWARDED: Counter = Counter::new();
+static FORWARD_ERRORS: Counter = Counter::new();
+
+lazy_static! {
+    static ref STATE: RwLock<Option<MaintenanceState>> = RwLock::new(None);
+}
+
+#[derive(Debug)]
+struct MaintenanceState {
+    since: u64,
+    forward_to: Option<String>,
+    // socket made when maintenance starts, because sandboxed network threads cannot make one
+    forward: Option<(UdpSocket, SocketAddr)>,
+}
+
+/// Body of POST /maintenance
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct MaintenanceCommand {
+    pub enabled: bool,
+    /// Statsd address to forward packets to instead of `network.maintenance-forward`
+    #[serde(default)]
+    pub forward_to: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct MaintenanceStatus {
+    pub enabled: bool,
+    /// Time maintenance started, ms since UNIX epoch
+    pub since: Option<u64>,
+    pub forward_to: Option<String>,
+    /// Packets forwarded and failed to be forwarded since start
+    pub forwarded: usize,
+    pub forward_errors: usize,
+}
+
+pub fn maintenance_status() -> MaintenanceStatus {
+    let state = STATE.read().unwrap();
+    MaintenanceStatus {
+        enabled: state.is_some(),
+        since: state.as_ref().map(|state| state.since),
+        forward_to: state.as_ref().and_then(|state| state.forward_to.clone()),
+        forwarded: FORWARDED.get(),
+        forward_errors: FORWARD_ERRORS.get(),
+    }
+}
+
+fn forward_socket(address: &str) -> Result<(UdpSocket, SocketAddr), GeneralError> {
+    let addr = resolve_addr(address)?;
+    let bind: SocketAddr = if addr.is_ipv4() { "0.0.0.0:0".parse().unwrap() } else { "[::]:0".parse().unwrap() };
+    let socket = UdpSocket::bind(bind).map_err(GeneralError::Io)?;
+    // network threads must never wait for the sibling
+    socket.set_nonblocking(true).map_err(GeneralError::Io)?;
+    Ok((socket, addr))
+}
+
+/// Enter or leave maintenance. Statsd packets are forwarded to `forward_to` or `network.maintenance-forward`
+/// while in maintenance if either is set, otherwise they are still aggregated and sent to peers as usual.
+pub fn set_maintenance(command: MaintenanceCommand, log: &Logger) -> Result<MaintenanceStatus, GeneralError> {
+    let mut state = STATE.write().unwrap();
+    if !command.enabled {
+        if state.take().is_some() {
+            MAINTENANCE.store(false, Ordering::SeqCst);
+            info!(log, "maintenance finished");
+            event("maintenance", "maintenance finished".to_string());
+        }
+    } else {
+        // forwarding target may be changed while in maintenance
+        let forward_to = command.forward_to.or_else(|| RUNTIME_CONFIG.read().unwrap().network.maintenance_forward.clone());
+        let forward = match forward_to {
+            Some(ref address) => Some(forward_socket(address)?),
+            None => None,
+        };
+        let since = state.as_ref().map(|state| state.since).unwrap_or_else(now_ms);
+        *state = Some(MaintenanceState { since, forward_to: forward_to.clone(), forward });
+        if !MAINTENANCE.swap(true, Ordering::SeqCst) {
+            if IS_LEADER.load(Ordering::SeqCst) {
+                warn!(log, "leader entered maintenance, nothing is sent to backend until it steps down or maintenance finishes");
+            }
+            info!(log, "maintenance started"; "forward-to"=>forward_to.clone().unwrap_or_default());
+            event("maintenance", format!("maintenance started, forwarding to {}", forward_to.unwrap_or_else(|| "nowhere".to_string())));
+        }
+    }
+    drop(state);
+    Ok(maintenance_status())
+}
+
+/// Forward a statsd packet to the sibling if it is set in maintenance, returns false if the packet
+/// should be handled by this node
+pub fn forward(packet: &[u8]) -> bool {
+    if !MAINTENANCE.load(Ordering::Relaxed) {
+        return false;
+    }
+    match *STATE.read().unwrap() {
+        Some(MaintenanceState { forward: Some((ref socket, addr)), .. }) => {
+            match socket.send_to(packet, addr) {
+                Ok(_) => FORWARDED.add(1),
+                Err(_) => FORWARD_ERRORS.add(1),
+            }
+            true
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::prepare_log;
+
+    #[test]
+    fn maintenance_forwarding() {
+        let log = prepare_log("maintenance_forwarding");
+        let sibling = UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(!forward(b"before:1|c"));
+
+        let command = MaintenanceCommand { enabled: true, forward_to: Some(sibling.local_addr().unwrap().to_string()) };
+        let status = set_maintenance(command, &log).unwrap();
+        assert!(status.enabled && status.since.is_some());
+        assert!(forward(b"forwarded:1|c"));
+        let mut buf = [0u8; 64];
+        let len = sibling.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"forwarded:1|c");
+
+        let status = set_maintenance(MaintenanceCommand { enabled: false, forward_to: None }, &log).unwrap();
+        assert!(!status.enabled);
+        assert!(status.forwarded >= 1);
+        assert!(!forward(b"after:1|c"));
+    }
+}
@@ -1,20 +1,61 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::BufReader;
 use std::net::SocketAddr;
+use std::path::Path;
 use std::str::FromStr;
 use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
 
-use futures::future::{err, ok, Future, IntoFuture};
-use futures::Stream;
-use serde_json;
+use bytes::Bytes;
+use futures::future::{err, join_all, ok, Future, IntoFuture};
+use futures::sync::mpsc::Sender;
+use futures::sync::oneshot;
+use futures::{Sink, Stream};
+use serde_json::{self, json, Value};
 use slog::{Logger, warn, o, info};
 
 use hyper::service::Service;
+use hyper::client::HttpConnector;
+use hyper::header::{HeaderValue, AUTHORIZATION};
 use hyper::{self, Body, Method, Request, Response, StatusCode};
+use hyper_rustls::HttpsConnector;
+use rustls::ClientConfig;
 
 use failure_derive::Fail;
 use serde_derive::{Serialize, Deserialize};
 
+use bioyino_metric::{Metric, MetricType};
 use failure::{Compat, Fail as FailTrait};
-use crate::{ConsensusState, CONSENSUS_STATE, IS_LEADER};
+
+use crate::api::{accepts, endpoint_list, error_body, find_route, openapi, API_V1};
+use crate::audit::{audit, AuditRecord};
+use crate::auth::{authorize, required_role, Role};
+use crate::carbon::flush_to_carbon;
+use crate::cluster::cluster_view;
+use crate::config::{Management, Quota, System, Tls};
+use crate::ctl::{render, render_event, OutputFormat};
+use crate::errors::GeneralError;
+use crate::events::{event, EVENTS};
+use crate::health::{liveness, readiness, HealthReport};
+use crate::maintenance::{maintenance_status, set_maintenance, MaintenanceCommand};
+use crate::windows::window_status;
+use crate::workers::{scale_workers, workers_status, WorkersCommand};
+use crate::intern::{pin, NAMES};
+use crate::peer::{decode_message, snapshot_message};
+use crate::profile::{profile_cpu, ProfileError, ProfileFormat};
+use crate::quota::{put_quota, quotas, remove_quota, set_quotas};
+use crate::standby::standby_status;
+use crate::reload::Reloader;
+use crate::rules::{change_rules, RulesChange, RULES};
+use crate::stats::{collect_memory, collect_stats, collect_top, render_prometheus, worker_stats, Counters, TopBy};
+use crate::tags::normalize;
+use crate::tail::subscribe;
+use crate::tunables::{find_tunable, tunable_values, TunableChange, MAX_TAILS};
+use crate::task::{MetricQuery, Task};
+use crate::util::get_hostname;
+use crate::{Cache, ConsensusState, Float, AGG_ERRORS, CONSENSUS_STATE, FLUSH_PAUSED, INGESTION_PAUSED, IS_LEADER, RUNTIME_CONFIG};
 
 #[derive(Fail, Debug)]
 pub enum MgmtError {
@@ -35,6 +76,24 @@ pub enum MgmtError {
 
     #[fail(display = "response not sent")]
     Response,
+
+    #[fail(display = "error sending task to worker thread")]
+    TaskSend,
+
+    #[fail(display = "bad CA certificate file {}", _0)]
+    BadCa(String),
+
+    #[fail(display = "dump-dir is not set in configuration")]
+    NoDumpDir,
+
+    #[fail(display = "bad dump: {}", _0)]
+    BadDump(String),
+
+    #[fail(display = "server answered {}: {}", _0, _1)]
+    Server(u16, String),
+
+    #[fail(display = "bad request URL {}: {}", _0, _1)]
+    BadUrl(String, String),
 }
 
 // Top level list of available commands
@@ -45,6 +104,100 @@ pub enum MgmtCommand {
     Status,
     // send a command to consensus module
     ConsensusCommand(ConsensusAction, LeaderAction),
+    // stop receiving metrics or sending them to backend
+    Pause(PauseTarget),
+    // undo the pause
+    Resume(PauseTarget),
+    // server will answer with ClusterView message
+    ClusterStatus,
+    // manual leadership control
+    Leader(LeaderCommand),
+    // server will answer with StatsReport message
+    Stats,
+    // aggregate and send metrics to backend right now, optionally only ones with the prefix
+    Flush(Option<String>),
+    // server will answer with current Rules
+    ShowRules,
+    // change ingestion rules, saving them to rules file if flag is set
+    ChangeRules(RulesChange, bool),
+    // stream incoming metrics matching the pattern, no more than the number per second
+    Tail(String, u32),
+    // server will answer with values of tunables
+    Tunables,
+    // change a tunable value without reloading configuration
+    SetTunable(String, usize),
+}
+
+impl MgmtCommand {
+    // method, path relative to API prefix and body of the request performing the command
+    fn request(&self) -> Result<(Method, String, Option<Vec<u8>>), MgmtError> {
+        let json = |value: &MgmtCommand| serde_json::to_vec_pretty(value).map(Some).map_err(MgmtError::Encode);
+        Ok(match self {
+            MgmtCommand::Status => (Method::GET, "status".to_string(), None),
+            MgmtCommand::ClusterStatus => (Method::GET, "cluster".to_string(), None),
+            MgmtCommand::Stats => (Method::GET, "stats".to_string(), None),
+            MgmtCommand::ConsensusCommand(..) => (Method::POST, "consensus".to_string(), json(self)?),
+            MgmtCommand::Pause(_) | MgmtCommand::Resume(_) => (Method::POST, "pause".to_string(), json(self)?),
+            MgmtCommand::Leader(_) => (Method::POST, "leader".to_string(), json(self)?),
+            MgmtCommand::Flush(None) => (Method::POST, "flush".to_string(), None),
+            MgmtCommand::Flush(Some(prefix)) => (Method::POST, format!("flush?{}", query(&[("prefix", prefix.clone())])), None),
+            MgmtCommand::ShowRules => (Method::GET, "rules".to_string(), None),
+            MgmtCommand::ChangeRules(RulesChange::RemoveBlock(pattern), persist) => (Method::DELETE, format!("rules/block?{}", query(&[("pattern", pattern.clone()), ("persist", persist.to_string())])), None),
+            MgmtCommand::ChangeRules(RulesChange::RemoveRewrite(prefix), persist) => (Method::DELETE, format!("rules/rewrite?{}", query(&[("prefix", prefix.clone()), ("persist", persist.to_string())])), None),
+            MgmtCommand::ChangeRules(RulesChange::Replace(rules), persist) => (Method::PUT, format!("rules?{}", query(&[("persist", persist.to_string())])), Some(serde_json::to_vec_pretty(rules).map_err(MgmtError::Encode)?)),
+            MgmtCommand::ChangeRules(change, persist) => (Method::POST, format!("rules?{}", query(&[("persist", persist.to_string())])), Some(serde_json::to_vec_pretty(change).map_err(MgmtError::Encode)?)),
+            MgmtCommand::Tail(pattern, rate) => (Method::GET, format!("tail?{}", query(&[("pattern", pattern.clone()), ("rate", rate.to_string())])), None),
+            MgmtCommand::Tunables => (Method::GET, "tunables".to_string(), None),
+            MgmtCommand::SetTunable(name, value) => (Method::PUT, format!("tunables/{}", percent_encode(name)), Some(serde_json::to_vec_pretty(&TunableChange { value: *value }).map_err(MgmtError::Encode)?)),
+        })
+    }
+}
+
+// Manual leadership override, both commands stop consensus from changing leader state until
+// consensus is enabled again by consensus command
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub enum LeaderCommand {
+    // stop being a leader. Consul session of this node expires, so another node takes the lock,
+    // with internal raft there will be no leader until consensus is enabled back on this node
+    StepDown,
+    // the named node becomes a leader, any other node stops being one, so the command
+    // should be sent to every node
+    Pin(String),
+}
+
+// check if the name given by user is one of the names of this node
+fn is_this_node(name: &str, config: &System) -> bool {
+    // names are compared without ports, so the node can be named by host only
+    let host = |name: &str| name.splitn(2, ':').next().unwrap_or("").to_string();
+    let mut names = vec![config.network.peer_listen.to_string(), config.network.mgmt_listen.to_string()];
+    names.extend(get_hostname());
+    names.extend(config.raft.this_node.clone());
+    names.iter().any(|own| host(own) == host(name))
+}
+
+// What part of the pipeline to pause or resume
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub enum PauseTarget {
+    // incoming metrics are dropped and counted
+    Ingestion,
+    // metrics are aggregated, but kept in memory instead of being sent
+    Flush,
+    // both of the above
+    All,
+}
+
+impl FromStr for PauseTarget {
+    type Err = Compat<MgmtError>;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ingestion" | "ingest" | "input" => Ok(PauseTarget::Ingestion),
+            "flush" | "flushing" | "output" => Ok(PauseTarget::Flush),
+            "all" | "both" => Ok(PauseTarget::All),
+            _ => Err(MgmtError::BadCommand.compat()),
+        }
+    }
 }
 
 // Turn consensus off for time(in milliseconds).
@@ -102,6 +255,8 @@ impl FromStr for LeaderAction {
 struct ServerStatus {
     leader_status: bool,
     consensus_status: ConsensusState,
+    ingestion_paused: bool,
+    flush_paused: bool,
 }
 
 impl ServerStatus {
@@ -110,46 +265,607 @@ impl ServerStatus {
         Self {
             leader_status: IS_LEADER.load(Ordering::SeqCst),
             consensus_status: state.clone(),
+            ingestion_paused: INGESTION_PAUSED.load(Ordering::SeqCst),
+            flush_paused: FLUSH_PAUSED.load(Ordering::SeqCst),
+        }
+    }
+}
+
+// current state of a metric as seen by workers, answered to metric queries
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct MetricValue {
+    #[serde(rename = "type")]
+    mtype: String,
+    value: Float,
+    update_counter: u32,
+    // timer samples, empty for other types
+    samples: Vec<Float>,
+    // what will be sent to backend if aggregation happened right now
+    aggregated: BTreeMap<String, Float>,
+}
+
+impl MetricValue {
+    fn new(metric: Metric<Float>) -> Self {
+        let (mtype, samples) = match metric.mtype {
+            MetricType::Counter => ("counter", Vec::new()),
+            MetricType::Gauge(_) => ("gauge", Vec::new()),
+            MetricType::Timer(ref samples) => ("timer", samples.clone()),
+            MetricType::Set(_) => ("set", Vec::new()),
+            _ => ("other", Vec::new()),
+        };
+        let value = metric.value;
+        let update_counter = metric.update_counter;
+        let aggregated = metric.into_iter().map(|(suffix, value)| (suffix.to_string(), value)).collect();
+        Self { mtype: mtype.to_string(), value, update_counter, samples, aggregated }
+    }
+}
+
+// ask all workers for metrics matching the query and join the answers together
+fn query_cache(chans: &[Sender<Task>], query: MetricQuery) -> impl Future<Item = Cache, Error = MgmtError> + Send {
+    let answers = chans
+        .iter()
+        .map(|chan| {
+            let (tx, rx) = oneshot::channel();
+            chan.clone().send(Task::Query(query.clone(), tx)).map_err(|_| MgmtError::TaskSend).and_then(|_| rx.map_err(|_| MgmtError::Response))
+        })
+        .collect::<Vec<_>>();
+
+    join_all(answers).map(|answers| {
+        let mut joined: Cache = HashMap::new();
+        answers
+            .into_iter()
+            .flat_map(|cache| cache.into_iter())
+            .map(|(name, metric)| {
+                if let Some(existing) = joined.get_mut(&name) {
+                    existing.aggregate(metric).unwrap_or_else(|_| {
+                        AGG_ERRORS.add(1);
+                    });
+                    return;
+                }
+                joined.insert(name, metric);
+            })
+            .last();
+        joined
+    })
+}
+
+fn query_metrics(chans: &[Sender<Task>], query: MetricQuery) -> impl Future<Item = BTreeMap<String, MetricValue>, Error = MgmtError> + Send {
+    // names of metrics found are taken after workers answer, the ids may be rotated out by then
+    let names_pin = pin();
+    query_cache(chans, query).map(move |joined| {
+        let _pin = names_pin;
+        let names = NAMES.read().unwrap();
+        joined.into_iter().map(|(id, metric)| (String::from_utf8_lossy(names.name(id)).into_owned(), MetricValue::new(metric))).collect()
+    })
+}
+
+/// Format of cache dump
+#[derive(Clone, Debug, PartialEq)]
+pub enum DumpFormat {
+    Json,
+    // the same message as snapshots sent to peers, so the dump can be sent to peer port as is
+    Capnp,
+}
+
+impl FromStr for DumpFormat {
+    type Err = MgmtError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(DumpFormat::Json),
+            "capnp" => Ok(DumpFormat::Capnp),
+            _ => Err(MgmtError::BadCommand),
+        }
+    }
+}
+
+impl DumpFormat {
+    /// Format of a dump file by extension, files other than `*.capnp` are JSON
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("capnp") => DumpFormat::Capnp,
+            _ => DumpFormat::Json,
+        }
+    }
+}
+
+/// Names of all metrics in a dump made by `/dump`
+pub fn dump_names(dump: &[u8], format: &DumpFormat) -> Result<Vec<Bytes>, MgmtError> {
+    match format {
+        DumpFormat::Json => {
+            let values: BTreeMap<String, Value> = serde_json::from_slice(dump).map_err(MgmtError::Decode)?;
+            Ok(values.into_iter().map(|(name, _)| Bytes::from(name)).collect())
+        }
+        DumpFormat::Capnp => decode_message(dump).map(|metrics| metrics.into_iter().map(|(name, _)| name).collect()).map_err(|e| MgmtError::BadDump(e.to_string())),
+    }
+}
+
+fn serialize_dump(cache: Cache, format: &DumpFormat) -> Result<Vec<u8>, MgmtError> {
+    match format {
+        DumpFormat::Json => {
+            let names = NAMES.read().unwrap();
+            let values = cache.into_iter().map(|(id, metric)| (String::from_utf8_lossy(names.name(id)).into_owned(), MetricValue::new(metric))).collect::<BTreeMap<_, _>>();
+            serde_json::to_vec(&values).map_err(MgmtError::Encode)
+        }
+        DumpFormat::Capnp => {
+            let mut buf = Vec::new();
+            capnp::serialize::write_message(&mut buf, &snapshot_message(&[cache])).map_err(MgmtError::Io)?;
+            Ok(buf)
+        }
+    }
+}
+
+// get a value of parameter from URL query string, like pattern from /metrics?pattern=value
+fn query_param(req: &Request<Body>, name: &str) -> Option<String> {
+    req.uri().query().unwrap_or("").split('&').filter_map(|pair| {
+        let mut split = pair.splitn(2, '=');
+        match (split.next(), split.next()) {
+            (Some(key), Some(value)) if key == name => Some(String::from_utf8_lossy(&percent_decode(value)).into_owned()),
+            _ => None,
+        }
+    }).next()
+}
+
+// decode %XX sequences of URL path or query, tagged names have `;` and `=` encoded by clients,
+// sequences that are not valid are kept as is
+fn percent_decode(s: &str) -> Vec<u8> {
+    let s = s.as_bytes();
+    let hex = |c: u8| (c as char).to_digit(16).map(|d| d as u8);
+    let mut decoded = Vec::with_capacity(s.len());
+    let mut i = 0;
+    while i < s.len() {
+        match (s[i], s.get(i + 1).cloned().and_then(hex), s.get(i + 2).cloned().and_then(hex)) {
+            (b'%', Some(high), Some(low)) => {
+                decoded.push(high << 4 | low);
+                i += 3;
+            }
+            (c, _, _) => {
+                decoded.push(c);
+                i += 1;
+            }
         }
     }
+    decoded
+}
+
+// encode all bytes except unreserved characters as %XX, so values with `;`, `=`, `&` and spaces
+// are read by server as they are
+fn percent_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for c in s.bytes() {
+        match c {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(c as char),
+            c => encoded.push_str(&format!("%{:02X}", c)),
+        }
+    }
+    encoded
+}
+
+// query string with percent-encoded values
+fn query(params: &[(&str, String)]) -> String {
+    params.iter().map(|(name, value)| format!("{}={}", name, percent_encode(value))).collect::<Vec<_>>().join("&")
+}
+
+// metric name from /metrics/<name> path, tags may come in any order
+fn path_metric_name(path: &str) -> Bytes {
+    normalize(Bytes::from(percent_decode(&path["/metrics/".len()..])))
+}
+
+// profiling duration in seconds from the query, if it is positive and not more than `max_duration` milliseconds
+fn profile_seconds(value: &str, max_duration: u64) -> Option<u64> {
+    let seconds = value.parse::<u64>().ok()?;
+    match seconds.checked_mul(1000) {
+        Some(ms) if seconds > 0 && ms <= max_duration => Some(seconds),
+        _ => None,
+    }
+}
+
+fn health_response(mut response: Response<Body>, report: HealthReport, ready: bool) -> Response<Body> {
+    // draining nodes are alive, but not ready
+    if !report.is_ok() || (ready && !report.is_ready()) {
+        *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    }
+    let body = serde_json::to_vec_pretty(&report).unwrap(); // TODO unwrap
+    *response.body_mut() = Body::from(body);
+    response
+}
+
+// apply the rules change answering with new rules
+fn change_rules_response(mut response: Response<Body>, change: RulesChange, persist: bool, log: &Logger) -> Response<Body> {
+    let path = if persist {
+        match RUNTIME_CONFIG.read().unwrap().metrics.rules_file.clone() {
+            Some(path) => Some(path),
+            None => {
+                *response.status_mut() = StatusCode::BAD_REQUEST;
+                *response.body_mut() = Body::from("rules-file is not set in configuration, rules cannot be persisted");
+                return response;
+            }
+        }
+    } else {
+        None
+    };
+
+    info!(log, "changing ingestion rules"; "change"=>format!("{:?}", change), "persist"=>persist);
+    match change_rules(change, path.as_ref().map(|path| path.as_str())) {
+        Ok(Some(rules)) => {
+            let body = serde_json::to_vec_pretty(&*rules).unwrap(); // TODO unwrap
+            *response.body_mut() = Body::from(body);
+        }
+        Ok(None) => {
+            *response.status_mut() = StatusCode::NOT_FOUND;
+        }
+        Err(e @ GeneralError::Relabel(..)) => {
+            *response.status_mut() = StatusCode::BAD_REQUEST;
+            *response.body_mut() = Body::from(e.to_string());
+        }
+        Err(e) => {
+            warn!(log, "saving rules failed, rules not changed"; "error"=>e.to_string());
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            *response.body_mut() = Body::from(e.to_string());
+        }
+    }
+    response
+}
+
+// JSON bodies are kept as is in audit records, other ones as strings
+fn body_value(body: &[u8]) -> Value {
+    if body.len() == 0 {
+        return Value::Null;
+    }
+    serde_json::from_slice(body).unwrap_or_else(|_| Value::String(String::from_utf8_lossy(body).into_owned()))
+}
+
+fn request_value(query: Option<&str>, body: &[u8]) -> Value {
+    json!({ "query": query, "body": body_value(body) })
+}
+
+// the part of server state the call to `path` may change
+fn previous_state(path: &str) -> Value {
+    let state = match path {
+        "/consensus" | "/leader" | "/pause" => serde_json::to_value(ServerStatus::new()),
+        path if path.starts_with("/rules") => serde_json::to_value(&*RULES.read().unwrap().clone()),
+        path if path.starts_with("/tunables") => serde_json::to_value(tunable_values()),
+        "/quotas" => serde_json::to_value(quotas()),
+        "/maintenance" => serde_json::to_value(maintenance_status()),
+        "/workers" => serde_json::to_value(workers_status()),
+        "/windows" => serde_json::to_value(window_status()),
+        _ => Ok(Value::Null),
+    };
+    // the state consists of plain values, so it is always serialized
+    state.unwrap()
 }
 
+fn error_response(status: StatusCode, body: &[u8]) -> Response<Body> {
+    let mut response = Response::new(Body::from(error_body(status, body)));
+    *response.status_mut() = status;
+    response.headers_mut().insert(hyper::header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    response
+}
+
+#[derive(Clone)]
 pub struct MgmtServer {
     log: Logger,
+    chans: Vec<Sender<Task>>,
+    config: Arc<System>,
+    reloader: Reloader,
 }
 
 impl MgmtServer {
-    pub fn new(log: Logger, address: &SocketAddr) -> Self {
+    pub fn new(log: Logger, address: &SocketAddr, chans: Vec<Sender<Task>>, config: Arc<System>, reloader: Reloader) -> Self {
         Self {
             log: log.new(o!("source"=>"management-server", "server"=>format!("{}", address))),
+            chans,
+            config,
+            reloader,
         }
     }
-}
 
-impl Service for MgmtServer {
-    type ReqBody = Body;
-    type ResBody = Body;
-    type Error = hyper::Error;
-    type Future = Box<Future<Item = Response<Self::ResBody>, Error = Self::Error> + Send>;
-    fn call(&mut self, req: Request<Self::ReqBody>) -> Self::Future {
-        let mut response = Response::new(Body::empty());
+    // check permissions of unversioned request and route it, auditing state-changing calls
+    fn handle(&mut self, req: Request<Body>) -> Box<Future<Item = Response<Body>, Error = hyper::Error> + Send> {
+        let log = self.log.clone();
+        let changing = required_role(req.method()) == Role::Admin;
+        let action = format!("{} {}", req.method(), req.uri().path());
+        // tokens are taken from runtime config, so they can be changed by reloading
+        let who = match authorize(&RUNTIME_CONFIG.read().unwrap().management.tokens, &req) {
+            Ok(who) => who,
+            Err(status) => {
+                warn!(log, "management request not authorized"; "path"=>req.uri().path(), "status"=>status.as_u16());
+                if changing {
+                    audit(&log, AuditRecord::new("unauthorized", action, request_value(req.uri().query(), &[]), Value::Null, status, Value::Null));
+                }
+                let mut response = Response::new(Body::empty());
+                *response.status_mut() = status;
+                return Box::new(ok(response));
+            }
+        };
+        if !changing {
+            return self.route(req);
+        }
 
+        // body is consumed by handlers, so it is read here to be kept for the record
+        let previous = previous_state(req.uri().path());
+        let keep_result = req.uri().path() != "/dump";
+        let (parts, body) = req.into_parts();
+        let mut server = self.clone();
+        let fut = body.concat2().and_then(move |body| {
+            let request = request_value(parts.uri.query(), &body);
+            server.route(Request::from_parts(parts, Body::from(body))).and_then(move |response| {
+                let (parts, body) = response.into_parts();
+                body.concat2().map(move |body| {
+                    let result = if keep_result { body_value(&body) } else { Value::Null };
+                    audit(&log, AuditRecord::new(&who, action, request, previous, parts.status, result));
+                    Response::from_parts(parts, Body::from(body))
+                })
+            })
+        });
+        Box::new(fut)
+    }
+
+    // route request to its handler
+    fn route(&mut self, req: Request<Body>) -> Box<Future<Item = Response<Body>, Error = hyper::Error> + Send> {
+        let mut response = Response::new(Body::empty());
         let log = self.log.clone();
+
         match (req.method(), req.uri().path()) {
             (&Method::GET, "/") => {
-                *response.body_mut() = Body::from(
-                    "Available endpoints:
-    status - will show server status
-    consensus - posting will change consensus state",
-    );
+                *response.body_mut() = Body::from(endpoint_list());
+                Box::new(ok(response))
+            }
+            (&Method::GET, "/openapi.json") => {
+                let body = serde_json::to_vec_pretty(&openapi()).unwrap(); // TODO unwrap
+                response.headers_mut().insert(hyper::header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+                *response.body_mut() = Body::from(body);
+                Box::new(ok(response))
+            }
+            (&Method::GET, "/healthz") => {
+                let fut = liveness(&self.chans).then(move |report| {
+                    // checks never fail themselves, they only report failures
+                    Ok::<_, hyper::Error>(health_response(response, report.unwrap(), false))
+                });
+                Box::new(fut)
+            }
+            (&Method::GET, "/readyz") => {
+                let fut = readiness(&self.chans, &self.config).then(move |report| {
+                    // checks never fail themselves, they only report failures
+                    Ok::<_, hyper::Error>(health_response(response, report.unwrap(), true))
+                });
+                Box::new(fut)
+            }
+            (&Method::GET, "/stats") => {
+                let fut = collect_stats(&self.chans).then(move |report| {
+                    // stats collection never fails, workers not answering are reported as null
+                    let body = serde_json::to_vec_pretty(&report.unwrap()).unwrap(); // TODO unwrap
+                    *response.body_mut() = Body::from(body);
+                    Ok::<_, hyper::Error>(response)
+                });
+                Box::new(fut)
+            }
+            (&Method::GET, "/rules") => {
+                let rules = RULES.read().unwrap().clone();
+                let body = serde_json::to_vec_pretty(&*rules).unwrap(); // TODO unwrap
+                *response.body_mut() = Body::from(body);
+                Box::new(ok(response))
+            }
+            (&Method::GET, "/quotas") => {
+                let body = serde_json::to_vec_pretty(&quotas()).unwrap(); // TODO unwrap
+                *response.body_mut() = Body::from(body);
+                Box::new(ok(response))
+            }
+            (&Method::GET, "/tunables") => {
+                let body = serde_json::to_vec_pretty(&tunable_values()).unwrap(); // TODO unwrap
+                *response.body_mut() = Body::from(body);
+                Box::new(ok(response))
+            }
+            (&Method::GET, "/top") => {
+                let n = query_param(&req, "n").unwrap_or("50".to_string()).parse::<usize>();
+                let depth = query_param(&req, "depth").unwrap_or("2".to_string()).parse::<usize>();
+                let by = match (query_param(&req, "by").as_ref().map(|by| by.as_str()), depth) {
+                    (None, _) | (Some("samples"), _) => Some(TopBy::Samples),
+                    (Some("bytes"), _) => Some(TopBy::Bytes),
+                    (Some("names"), Ok(depth)) if depth > 0 => Some(TopBy::Names(depth)),
+                    _ => None,
+                };
+                match (by, n) {
+                    (Some(by), Ok(n)) if n > 0 => {
+                        let fut = collect_top(&self.chans, by, n).then(move |top| {
+                            match top {
+                                Ok(top) => {
+                                    let body = serde_json::to_vec_pretty(&top).unwrap(); // TODO unwrap
+                                    *response.body_mut() = Body::from(body);
+                                }
+                                Err(()) => {
+                                    warn!(log, "error collecting top metrics from workers");
+                                    *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                                }
+                            }
+                            Ok::<_, hyper::Error>(response)
+                        });
+                        Box::new(fut)
+                    }
+                    _ => {
+                        *response.status_mut() = StatusCode::BAD_REQUEST;
+                        *response.body_mut() = Body::from("by must be one of samples, bytes or names, n and depth must be positive numbers");
+                        Box::new(ok(response))
+                    }
+                }
+            }
+            (&Method::GET, "/tail") => {
+                let rate = query_param(&req, "rate").unwrap_or("10".to_string()).parse::<u32>();
+                let (pattern, rate) = match (query_param(&req, "pattern"), rate) {
+                    (Some(pattern), Ok(rate)) if rate > 0 => (pattern, rate),
+                    _ => {
+                        *response.status_mut() = StatusCode::BAD_REQUEST;
+                        *response.body_mut() = Body::from("pattern is required, rate must be a positive number");
+                        return Box::new(ok(response));
+                    }
+                };
+                match subscribe(MetricQuery::Glob(pattern.clone()), rate) {
+                    Some(rx) => {
+                        info!(log, "tail started"; "pattern"=>&pattern, "rate"=>rate);
+                        let greeting = ::futures::stream::once(Ok(Bytes::from(format!(": tailing {}\n\n", pattern))));
+                        let events = greeting.chain(rx).map(hyper::Chunk::from).map_err(|_| ::std::io::Error::new(::std::io::ErrorKind::Other, "tail closed"));
+                        response.headers_mut().insert(hyper::header::CONTENT_TYPE, HeaderValue::from_static("text/event-stream"));
+                        response.headers_mut().insert(hyper::header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+                        *response.body_mut() = Body::wrap_stream(events);
+                    }
+                    None => {
+                        *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+                        *response.body_mut() = Body::from(format!("too many tails, maximum is {}", MAX_TAILS.get()));
+                    }
+                }
                 Box::new(ok(response))
             }
+            (&Method::GET, "/memory") => {
+                let fut = collect_memory(&self.chans).then(move |report| {
+                    // workers not answering are reported as null, so this never fails
+                    let body = serde_json::to_vec_pretty(&report.unwrap()).unwrap(); // TODO unwrap
+                    *response.body_mut() = Body::from(body);
+                    Ok::<_, hyper::Error>(response)
+                });
+                Box::new(fut)
+            }
+            (&Method::GET, "/maintenance") => {
+                let body = serde_json::to_vec_pretty(&maintenance_status()).unwrap(); // TODO unwrap
+                *response.body_mut() = Body::from(body);
+                Box::new(ok(response))
+            }
+            (&Method::GET, "/workers") => {
+                let body = serde_json::to_vec_pretty(&workers_status()).unwrap(); // TODO unwrap
+                *response.body_mut() = Body::from(body);
+                Box::new(ok(response))
+            }
+            (&Method::GET, "/windows") => {
+                let body = serde_json::to_vec_pretty(&window_status()).unwrap(); // TODO unwrap
+                *response.body_mut() = Body::from(body);
+                Box::new(ok(response))
+            }
+            (&Method::GET, "/standby") => {
+                let body = serde_json::to_vec_pretty(&standby_status()).unwrap(); // TODO unwrap
+                *response.body_mut() = Body::from(body);
+                Box::new(ok(response))
+            }
+            (&Method::GET, "/cluster") => {
+                // node list may be changed by reloading, so runtime config is used
+                let view = cluster_view(&RUNTIME_CONFIG.read().unwrap());
+                let body = serde_json::to_vec_pretty(&view).unwrap(); // TODO unwrap
+                *response.body_mut() = Body::from(body);
+                Box::new(ok(response))
+            }
+            (&Method::GET, "/events") => {
+                let since = match query_param(&req, "since").map(|since| since.parse::<u64>()) {
+                    Some(Ok(since)) => since,
+                    Some(Err(_)) => {
+                        *response.status_mut() = StatusCode::BAD_REQUEST;
+                        *response.body_mut() = Body::from("since must be an event id");
+                        return Box::new(ok(response));
+                    }
+                    None => 0,
+                };
+                let events = EVENTS.list(since, query_param(&req, "kind").as_ref().map(|kind| kind.as_str()));
+                let body = serde_json::to_vec_pretty(&events).unwrap(); // TODO unwrap
+                *response.body_mut() = Body::from(body);
+                Box::new(ok(response))
+            }
+            (&Method::GET, "/prometheus") => {
+                let fut = worker_stats(&self.chans).then(move |workers| {
+                    // same as stats, it never fails
+                    let body = render_prometheus(&Counters::load(), &workers.unwrap());
+                    response.headers_mut().insert(hyper::header::CONTENT_TYPE, hyper::header::HeaderValue::from_static("text/plain; version=0.0.4"));
+                    *response.body_mut() = Body::from(body);
+                    Ok::<_, hyper::Error>(response)
+                });
+                Box::new(fut)
+            }
+            (&Method::GET, path) if path.starts_with("/metrics/") => {
+                let fut = query_metrics(&self.chans, MetricQuery::Exact(path_metric_name(path))).then(move |res| {
+                    match res {
+                        Ok(ref found) if found.len() == 0 => {
+                            *response.status_mut() = StatusCode::NOT_FOUND;
+                        }
+                        Ok(found) => {
+                            let body = serde_json::to_vec_pretty(&found).unwrap(); // TODO unwrap
+                            *response.body_mut() = Body::from(body);
+                        }
+                        Err(e) => {
+                            warn!(log, "error querying metric"; "error"=>e.to_string());
+                            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                        }
+                    }
+                    Ok::<_, hyper::Error>(response)
+                });
+                Box::new(fut)
+            }
+            (&Method::GET, "/metrics") => {
+                let pattern = match query_param(&req, "pattern") {
+                    Some(pattern) => pattern,
+                    None => {
+                        *response.status_mut() = StatusCode::BAD_REQUEST;
+                        *response.body_mut() = Body::from("pattern parameter is required");
+                        return Box::new(ok(response));
+                    }
+                };
+                let fut = query_metrics(&self.chans, MetricQuery::Glob(pattern)).then(move |res| {
+                    match res {
+                        Ok(found) => {
+                            let body = serde_json::to_vec_pretty(&found).unwrap(); // TODO unwrap
+                            *response.body_mut() = Body::from(body);
+                        }
+                        Err(e) => {
+                            warn!(log, "error querying metrics"; "error"=>e.to_string());
+                            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                        }
+                    }
+                    Ok::<_, hyper::Error>(response)
+                });
+                Box::new(fut)
+            }
             (&Method::GET, "/status") => {
                 let status = ServerStatus::new();
                 let body = serde_json::to_vec_pretty(&status).unwrap(); // TODO unwrap
                 *response.body_mut() = Body::from(body);
                 Box::new(ok(response))
             }
+            (&Method::GET, path) if path == "/debug/pprof/profile" || path == "/debug/pprof/flamegraph" => {
+                let format = if path.ends_with("flamegraph") { ProfileFormat::Flamegraph } else { ProfileFormat::Pprof };
+                let (enabled, max_duration, frequency) = {
+                    let management = &RUNTIME_CONFIG.read().unwrap().management;
+                    (management.profiling, management.profile_max_duration as u64, management.profile_frequency)
+                };
+                if !enabled {
+                    *response.status_mut() = StatusCode::FORBIDDEN;
+                    *response.body_mut() = Body::from("profiling is disabled by management.profiling option");
+                    return Box::new(ok(response));
+                }
+                let seconds = match profile_seconds(&query_param(&req, "seconds").unwrap_or("10".to_string()), max_duration) {
+                    Some(seconds) => seconds,
+                    None => {
+                        *response.status_mut() = StatusCode::BAD_REQUEST;
+                        *response.body_mut() = Body::from(format!("seconds must be a positive number not more than {}", max_duration / 1000));
+                        return Box::new(ok(response));
+                    }
+                };
+                info!(log, "profiling started"; "seconds"=>seconds, "format"=>format!("{:?}", format));
+                let fut = profile_cpu(Duration::from_secs(seconds), frequency, format).then(move |res| {
+                    match res {
+                        Ok(body) => {
+                            response.headers_mut().insert(hyper::header::CONTENT_TYPE, HeaderValue::from_static(format.content_type()));
+                            *response.body_mut() = Body::from(body);
+                        }
+                        Err(e) => {
+                            warn!(log, "profiling failed"; "error"=>e.to_string());
+                            *response.status_mut() = match e {
+                                ProfileError::NotSupported => StatusCode::NOT_IMPLEMENTED,
+                                ProfileError::Busy => StatusCode::CONFLICT,
+                                ProfileError::Profiler(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                            };
+                            *response.body_mut() = Body::from(e.to_string());
+                        }
+                    }
+                    Ok::<_, hyper::Error>(response)
+                });
+                Box::new(fut)
+            }
             (&Method::GET, _) => {
                 *response.status_mut() = StatusCode::NOT_FOUND;
                 Box::new(ok(response))
@@ -171,10 +887,14 @@ impl Service for MgmtServer {
 
                             match leader_action {
                                 LeaderAction::Enable => {
-                                    IS_LEADER.store(true, Ordering::SeqCst);
+                                    if !IS_LEADER.swap(true, Ordering::SeqCst) {
+                                        event("leader", "became leader by consensus command".to_string());
+                                    }
                                 }
                                 LeaderAction::Disable => {
-                                    IS_LEADER.store(false, Ordering::SeqCst);
+                                    if IS_LEADER.swap(false, Ordering::SeqCst) {
+                                        event("leader", "stopped being leader by consensus command".to_string());
+                                    }
                                 }
                                 _ => (),
                             };
@@ -205,6 +925,303 @@ impl Service for MgmtServer {
 
                 Box::new(fut)
             }
+            (&Method::PUT, "/rules") | (&Method::POST, "/rules") => {
+                let persist = query_param(&req, "persist").map(|value| value == "true").unwrap_or(false);
+                let replace = req.method() == &Method::PUT;
+                let fut = req.into_body().concat2().map(move |body| {
+                    let change = if replace { serde_json::from_slice(&*body).map(RulesChange::Replace) } else { serde_json::from_slice(&*body) };
+                    match change {
+                        Ok(change) => change_rules_response(response, change, persist, &log),
+                        Err(e) => {
+                            info!(log, "error parsing rules"; "error"=>e.to_string());
+                            *response.status_mut() = StatusCode::BAD_REQUEST;
+                            *response.body_mut() = Body::from(e.to_string());
+                            response
+                        }
+                    }
+                });
+                Box::new(fut)
+            }
+            (&Method::DELETE, path) if path == "/rules/block" || path == "/rules/rewrite" => {
+                let persist = query_param(&req, "persist").map(|value| value == "true").unwrap_or(false);
+                let change = if path == "/rules/block" { query_param(&req, "pattern").map(RulesChange::RemoveBlock) } else { query_param(&req, "prefix").map(RulesChange::RemoveRewrite) };
+                match change {
+                    Some(change) => Box::new(ok(change_rules_response(response, change, persist, &log))),
+                    None => {
+                        *response.status_mut() = StatusCode::BAD_REQUEST;
+                        Box::new(ok(response))
+                    }
+                }
+            }
+            (&Method::PUT, "/quotas") | (&Method::POST, "/quotas") => {
+                let replace = req.method() == &Method::PUT;
+                let fut = req.into_body().concat2().map(move |body| {
+                    let result = if replace { serde_json::from_slice::<Vec<Quota>>(&*body).map_err(|e| e.to_string()).and_then(|all| set_quotas(&all).map_err(|e| e.to_string())) } else { serde_json::from_slice::<Quota>(&*body).map_err(|e| e.to_string()).and_then(|quota| put_quota(quota).map_err(|e| e.to_string())) };
+                    match result {
+                        Ok(()) => {
+                            info!(log, "quotas changed"; "replaced"=>replace);
+                            let body = serde_json::to_vec_pretty(&quotas()).unwrap(); // TODO unwrap
+                            *response.body_mut() = Body::from(body);
+                        }
+                        Err(e) => {
+                            info!(log, "error changing quotas"; "error"=>&e);
+                            *response.status_mut() = StatusCode::BAD_REQUEST;
+                            *response.body_mut() = Body::from(e);
+                        }
+                    }
+                    response
+                });
+                Box::new(fut)
+            }
+            (&Method::DELETE, "/quotas") => {
+                match query_param(&req, "prefix") {
+                    Some(ref prefix) if remove_quota(prefix) => {
+                        info!(log, "quota removed"; "prefix"=>prefix);
+                        let body = serde_json::to_vec_pretty(&quotas()).unwrap(); // TODO unwrap
+                        *response.body_mut() = Body::from(body);
+                    }
+                    Some(_) => *response.status_mut() = StatusCode::NOT_FOUND,
+                    None => *response.status_mut() = StatusCode::BAD_REQUEST,
+                }
+                Box::new(ok(response))
+            }
+            (&Method::PUT, path) if path.starts_with("/tunables/") => {
+                let tunable = match find_tunable(&path["/tunables/".len()..]) {
+                    Some(tunable) => tunable,
+                    None => {
+                        *response.status_mut() = StatusCode::NOT_FOUND;
+                        *response.body_mut() = Body::from("unknown tunable");
+                        return Box::new(ok(response));
+                    }
+                };
+                let fut = req.into_body().concat2().map(move |body| {
+                    let result = serde_json::from_slice::<TunableChange>(&*body).map_err(|e| e.to_string()).and_then(|change| tunable.set(change.value).map(|old| (old, change.value)));
+                    match result {
+                        Ok((old, new)) => {
+                            info!(log, "tunable changed"; "name"=>tunable.name, "old"=>old, "new"=>new);
+                            let body = serde_json::to_vec_pretty(&json!({ "name": tunable.name, "old": old, "new": new })).unwrap(); // TODO unwrap
+                            *response.body_mut() = Body::from(body);
+                        }
+                        Err(e) => {
+                            info!(log, "error changing tunable"; "name"=>tunable.name, "error"=>&e);
+                            *response.status_mut() = StatusCode::BAD_REQUEST;
+                            *response.body_mut() = Body::from(e);
+                        }
+                    }
+                    response
+                });
+                Box::new(fut)
+            }
+            (&Method::POST, "/dump") => {
+                let format = query_param(&req, "format").unwrap_or("json".to_string());
+                let format = match DumpFormat::from_str(&format) {
+                    Ok(format) => format,
+                    Err(_) => {
+                        *response.status_mut() = StatusCode::BAD_REQUEST;
+                        *response.body_mut() = Body::from("format must be json or capnp");
+                        return Box::new(ok(response));
+                    }
+                };
+                // only plain names are allowed so the dump cannot be written outside of dump directory
+                let file = match query_param(&req, "file") {
+                    Some(ref name) if name.len() == 0 || name.contains('/') || name.starts_with('.') => {
+                        *response.status_mut() = StatusCode::BAD_REQUEST;
+                        *response.body_mut() = Body::from("bad file name");
+                        return Box::new(ok(response));
+                    }
+                    Some(name) => match RUNTIME_CONFIG.read().unwrap().management.dump_dir {
+                        Some(ref dir) => Some(Path::new(dir).join(name)),
+                        None => {
+                            *response.status_mut() = StatusCode::BAD_REQUEST;
+                            *response.body_mut() = Body::from(MgmtError::NoDumpDir.to_string());
+                            return Box::new(ok(response));
+                        }
+                    },
+                    None => None,
+                };
+
+                let names_pin = pin();
+                let fut = query_cache(&self.chans, MetricQuery::All).then(move |res| {
+                    let dumped = res.and_then(|cache| {
+                        let len = cache.len();
+                        serialize_dump(cache, &format).map(|dump| (len, dump))
+                    });
+                    drop(names_pin);
+                    match dumped {
+                        Ok((len, dump)) => match file {
+                            Some(file) => match fs::write(&file, dump) {
+                                Ok(()) => {
+                                    info!(log, "metrics dumped"; "file"=>format!("{}", file.display()), "metrics"=>len);
+                                    *response.body_mut() = Body::from(format!("{} metrics dumped to {}", len, file.display()));
+                                }
+                                Err(e) => {
+                                    warn!(log, "error writing dump"; "error"=>e.to_string());
+                                    *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                                    *response.body_mut() = Body::from(e.to_string());
+                                }
+                            },
+                            None => {
+                                let content_type = if format == DumpFormat::Json { "application/json" } else { "application/octet-stream" };
+                                response.headers_mut().insert(hyper::header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+                                *response.body_mut() = Body::from(dump);
+                            }
+                        },
+                        Err(e) => {
+                            warn!(log, "error dumping metrics"; "error"=>e.to_string());
+                            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                        }
+                    }
+                    Ok::<_, hyper::Error>(response)
+                });
+                Box::new(fut)
+            }
+            (&Method::POST, "/leader") => {
+                let config = self.config.clone();
+                let fut = req.into_body().concat2().map(move |body| {
+                    match serde_json::from_slice(&*body) {
+                        Ok(MgmtCommand::Leader(command)) => {
+                            let leader = match command {
+                                LeaderCommand::StepDown => false,
+                                LeaderCommand::Pin(ref node) => is_this_node(node, &config),
+                            };
+                            {
+                                // keep consensus from overriding the manual choice, consul session is not
+                                // renewed in disabled state, so the lock is freed for other nodes
+                                let mut constate = CONSENSUS_STATE.lock().unwrap();
+                                *constate = if leader { ConsensusState::Paused } else { ConsensusState::Disabled };
+                            }
+                            IS_LEADER.store(leader, Ordering::SeqCst);
+                            event("leader", format!("leadership overridden: {:?}", command));
+
+                            let status = ServerStatus::new();
+                            info!(log, "leadership overridden"; "command"=>format!("{:?}", command), "leader_state"=>status.leader_status);
+                            let body = serde_json::to_vec_pretty(&status).unwrap(); // TODO unwrap
+                            *response.body_mut() = Body::from(body);
+                        }
+                        Ok(command) => {
+                            info!(log, "bad command received"; "command"=>format!("{:?}", command));
+                            *response.status_mut() = StatusCode::BAD_REQUEST;
+                        }
+                        Err(e) => {
+                            info!(log, "error parsing command"; "error"=>e.to_string());
+                            *response.status_mut() = StatusCode::BAD_REQUEST;
+                        }
+                    }
+                    response
+                });
+                Box::new(fut)
+            }
+            (&Method::POST, "/reload") => {
+                match self.reloader.reload() {
+                    Ok(report) => {
+                        let body = serde_json::to_vec_pretty(&report).unwrap(); // TODO unwrap
+                        *response.body_mut() = Body::from(body);
+                    }
+                    // per-section results tell which section failed and what was rolled back
+                    Err(GeneralError::ReloadRollback(section, report)) => {
+                        warn!(log, "config reload rolled back"; "section"=>&section);
+                        let body = serde_json::to_vec_pretty(&report).unwrap(); // TODO unwrap
+                        *response.status_mut() = StatusCode::UNPROCESSABLE_ENTITY;
+                        *response.body_mut() = Body::from(body);
+                    }
+                    Err(e) => {
+                        warn!(log, "config reload failed"; "error"=>e.to_string());
+                        *response.status_mut() = StatusCode::BAD_REQUEST;
+                        *response.body_mut() = Body::from(e.to_string());
+                    }
+                }
+                Box::new(ok(response))
+            }
+            (&Method::POST, "/flush") => {
+                if !IS_LEADER.load(Ordering::SeqCst) {
+                    // non-leader would just drop the metrics, which is not what flush is expected to do
+                    *response.status_mut() = StatusCode::CONFLICT;
+                    *response.body_mut() = Body::from("not a leader, nothing to flush");
+                    return Box::new(ok(response));
+                }
+                let prefix = query_param(&req, "prefix").map(Bytes::from);
+                info!(log, "forced flush requested"; "prefix"=>format!("{:?}", prefix));
+                match flush_to_carbon(self.chans.clone(), prefix, log.clone()) {
+                    Ok(_) => {
+                        *response.status_mut() = StatusCode::ACCEPTED;
+                    }
+                    Err(e) => {
+                        warn!(log, "forced flush failed"; "error"=>e.to_string());
+                        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                        *response.body_mut() = Body::from(e.to_string());
+                    }
+                }
+                Box::new(ok(response))
+            }
+            (&Method::POST, "/maintenance") => {
+                let fut = req.into_body().concat2().map(move |body| {
+                    match serde_json::from_slice::<MaintenanceCommand>(&*body).map_err(|e| e.to_string()).and_then(|command| set_maintenance(command, &log).map_err(|e| e.to_string())) {
+                        Ok(status) => {
+                            let body = serde_json::to_vec_pretty(&status).unwrap(); // TODO unwrap
+                            *response.body_mut() = Body::from(body);
+                        }
+                        Err(e) => {
+                            info!(log, "error changing maintenance"; "error"=>&e);
+                            *response.status_mut() = StatusCode::BAD_REQUEST;
+                            *response.body_mut() = Body::from(e);
+                        }
+                    }
+                    response
+                });
+                Box::new(fut)
+            }
+            (&Method::POST, "/workers") => {
+                let fut = req.into_body().concat2().map(move |body| {
+                    match serde_json::from_slice::<WorkersCommand>(&*body).map_err(|e| e.to_string()).and_then(|command| scale_workers(command.count, &log).map_err(|e| e.to_string())) {
+                        Ok(status) => {
+                            let body = serde_json::to_vec_pretty(&status).unwrap(); // TODO unwrap
+                            *response.body_mut() = Body::from(body);
+                        }
+                        Err(e) => {
+                            info!(log, "error changing workers"; "error"=>&e);
+                            *response.status_mut() = StatusCode::BAD_REQUEST;
+                            *response.body_mut() = Body::from(e);
+                        }
+                    }
+                    response
+                });
+                Box::new(fut)
+            }
+            (&Method::POST, "/pause") => {
+                let fut = req.into_body().concat2().map(move |body| {
+                    let (paused, target) = match serde_json::from_slice(&*body) {
+                        Ok(MgmtCommand::Pause(target)) => (true, target),
+                        Ok(MgmtCommand::Resume(target)) => (false, target),
+                        Ok(command) => {
+                            info!(log, "bad command received"; "command"=>format!("{:?}", command));
+                            *response.status_mut() = StatusCode::BAD_REQUEST;
+                            return response;
+                        }
+                        Err(e) => {
+                            info!(log, "error parsing command"; "error"=>e.to_string());
+                            *response.status_mut() = StatusCode::BAD_REQUEST;
+                            return response;
+                        }
+                    };
+
+                    match target {
+                        PauseTarget::Ingestion => INGESTION_PAUSED.store(paused, Ordering::SeqCst),
+                        PauseTarget::Flush => FLUSH_PAUSED.store(paused, Ordering::SeqCst),
+                        PauseTarget::All => {
+                            INGESTION_PAUSED.store(paused, Ordering::SeqCst);
+                            FLUSH_PAUSED.store(paused, Ordering::SeqCst);
+                        }
+                    }
+
+                    let status = ServerStatus::new();
+                    let body = serde_json::to_vec_pretty(&status).unwrap(); // TODO unwrap
+                    *response.body_mut() = Body::from(body);
+                    info!(log, "pause state changed"; "ingestion_paused"=>status.ingestion_paused, "flush_paused"=>status.flush_paused);
+                    response
+                });
+
+                Box::new(fut)
+            }
             (&Method::POST, _) => {
                 *response.status_mut() = StatusCode::NOT_FOUND;
                 Box::new(ok(response))
@@ -217,11 +1234,62 @@ impl Service for MgmtServer {
     }
 }
 
+impl Service for MgmtServer {
+    type ReqBody = Body;
+    type ResBody = Body;
+    type Error = hyper::Error;
+    type Future = Box<Future<Item = Response<Self::ResBody>, Error = Self::Error> + Send>;
+    fn call(&mut self, req: Request<Self::ReqBody>) -> Self::Future {
+        if !req.uri().path().starts_with(API_V1) {
+            return self.handle(req);
+        }
+
+        // versioned request is handled the same way after stripping the prefix, but gets content negotiation
+        // and JSON error bodies
+        let (mut parts, body) = req.into_parts();
+        let path = match &parts.uri.path()[API_V1.len()..] {
+            "" => "/".to_string(),
+            path => path.to_string(),
+        };
+        let path_and_query = match parts.uri.query() {
+            Some(query) => format!("{}?{}", path, query),
+            None => path.clone(),
+        };
+        parts.uri = match path_and_query.parse() {
+            Ok(uri) => uri,
+            Err(_) => return Box::new(ok(error_response(StatusCode::BAD_REQUEST, b"bad request path"))),
+        };
+        let req = Request::from_parts(parts, body);
+
+        if let Some(route) = find_route(req.method(), &path) {
+            if !accepts(&req, route.produces) {
+                let message = format!("endpoint can only answer with {}", route.produces.join(", "));
+                return Box::new(ok(error_response(StatusCode::NOT_ACCEPTABLE, message.as_bytes())));
+            }
+        }
+
+        let fut = self.handle(req).and_then(|response| {
+            if response.status().is_success() {
+                return Box::new(ok(response)) as Box<Future<Item = Response<Body>, Error = hyper::Error> + Send>;
+            }
+            let status = response.status();
+            Box::new(response.into_body().concat2().map(move |body| error_response(status, &body)))
+        });
+        Box::new(fut)
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct MgmtClient {
     log: Logger,
     address: SocketAddr,
     command: MgmtCommand,
+    // host part of URL, differs from address when TLS is used, because certificates are issued for names
+    host: String,
+    token: Option<String>,
+    // server certificate to trust additionally to well-known roots, also switches client to https
+    tls_ca: Option<String>,
+    output: OutputFormat,
 }
 
 impl MgmtClient {
@@ -231,10 +1299,54 @@ impl MgmtClient {
                 .new(o!("source"=>"management-client", "server"=>format!("{}", address.clone()))),
                 address,
                 command,
+                host: address.to_string(),
+                token: None,
+                tls_ca: None,
+                output: OutputFormat::Table,
+        }
+    }
+
+    pub fn with_output(mut self, output: OutputFormat) -> Self {
+        self.output = output;
+        self
+    }
+
+    /// Take token from management config and TLS settings, `host` is the server name as specified by user
+    pub fn with_auth(mut self, host: &str, management: &Management, tls: &Tls) -> Self {
+        self.token = management.client_token.clone();
+        if tls.cert.is_some() {
+            self.tls_ca = tls.cert.clone();
+            self.host = host.to_string();
         }
+        self
+    }
+}
+
+// take the message out of JSON error answered by API, falling back to the whole body
+fn server_error(body: &[u8]) -> String {
+    match serde_json::from_slice::<Value>(body) {
+        Ok(value) => match (value["error"]["message"].as_str(), &value["error"]["details"]) {
+            (Some(message), Value::Null) => message.to_string(),
+            (Some(message), details) => format!("{}: {}", message, details),
+            (None, _) => value.to_string(),
+        },
+        Err(_) => String::from_utf8_lossy(body).into_owned(),
     }
 }
 
+// a client able to talk both http and https
+fn make_client(tls_ca: &Option<String>) -> Result<hyper::Client<HttpsConnector<HttpConnector>>, MgmtError> {
+    let mut tls = ClientConfig::new();
+    tls.root_store.add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS);
+    if let Some(ca) = tls_ca {
+        let mut file = BufReader::new(File::open(ca).map_err(MgmtError::Io)?);
+        tls.root_store.add_pem_file(&mut file).map_err(|_| MgmtError::BadCa(ca.clone()))?;
+    }
+    let mut http = HttpConnector::new(1);
+    http.enforce_http(false);
+    Ok(hyper::Client::builder().build(HttpsConnector::from((http, tls))))
+}
+
 impl IntoFuture for MgmtClient {
     type Item = ();
     type Error = MgmtError;
@@ -243,96 +1355,77 @@ impl IntoFuture for MgmtClient {
     fn into_future(self) -> Self::Future {
         let Self {
             log,
-            address,
+            address: _,
             command,
+            host,
+            token,
+            tls_ca,
+            output,
         } = self;
         let mut req = hyper::Request::default();
+        let scheme = if tls_ca.is_some() { "https" } else { "http" };
+        if let Some(token) = token {
+            let value = HeaderValue::from_str(&format!("Bearer {}", token)).expect("bad client token");
+            req.headers_mut().insert(AUTHORIZATION, value);
+        }
+        let client = match make_client(&tls_ca) {
+            Ok(client) => client,
+            Err(e) => return Box::new(err(e)),
+        };
+
+        let (method, path, body) = match command.request() {
+            Ok(request) => request,
+            Err(e) => return Box::new(err(e)),
+        };
+        *req.method_mut() = method;
+        let url = format!("{}://{}{}/{}", scheme, host, API_V1, path);
+        *req.uri_mut() = match url.parse() {
+            Ok(uri) => uri,
+            Err(e) => return Box::new(err(MgmtError::BadUrl(url, format!("{}", e)))),
+        };
+        if let Some(body) = body {
+            *req.body_mut() = Body::from(body);
+        }
 
         info!(log, "received command {:?}", command);
-        match command {
-            MgmtCommand::Status => {
-                *req.method_mut() = Method::GET;
-                *req.uri_mut() = format!("http://{}/status", address)
-                    .parse()
-                    .expect("creating url for management command ");
-
-                let client = hyper::Client::new();
-                let clog = log.clone();
-                let future = client.request(req).then(move |res| match res {
-                    Err(e) => Box::new(err(MgmtError::Http(e))),
-                    Ok(resp) => {
-                        if resp.status() == StatusCode::OK {
-                            let body = resp
-                                .into_body()
-                                .concat2()
-                                .map_err(|e| MgmtError::Http(e))
-                                .map(move |body| {
-                                    match serde_json::from_slice::<ServerStatus>(&*body) {
-                                        Ok(status) => {
-                                            println!("{:?}", status);
-                                        }
-                                        Err(e) => {
-                                            println!(
-                                                "Error parsing server response: {}",
-                                                e.to_string()
-                                            );
-                                        }
-                                    }
-                                });
-                            Box::new(body) as Box<Future<Item = (), Error = MgmtError>>
-                        } else {
-                            Box::new(ok(warn!(
-                                        clog,
-                                        "Bad status returned from server: {:?}", resp
-                            )))
-                        }
-                    }
-                });
-                Box::new(future)
-            }
-            command @ MgmtCommand::ConsensusCommand(_, _) => {
-                *req.method_mut() = Method::POST;
-                *req.uri_mut() = format!("http://{}/consensus", address)
-                    .parse()
-                    .expect("creating url for management command");
-                let body = serde_json::to_vec_pretty(&command).unwrap();
-                *req.body_mut() = Body::from(body);
-
-                let client = hyper::Client::new();
-                let clog = log.clone();
-                let future = client.request(req).then(move |res| match res {
-                    Err(e) => Box::new(err(MgmtError::Http(e))),
-                    Ok(resp) => {
-                        if resp.status() == StatusCode::OK {
-                            let body = resp
-                                .into_body()
-                                .concat2()
-                                .map_err(|e| MgmtError::Http(e))
-                                .map(move |body| {
-                                    match serde_json::from_slice::<ServerStatus>(&*body) {
-                                        Ok(status) => {
-                                            println!("New server state: {:?}", status);
-                                        }
-                                        Err(e) => {
-                                            println!(
-                                                "Error parsing server response: {}",
-                                                e.to_string()
-                                            );
-                                        }
-                                    }
-                                });
-                            Box::new(body) as Box<Future<Item = (), Error = MgmtError>>
-                        } else {
-                            Box::new(ok(warn!(
-                                        clog,
-                                        "Bad status returned from server: {:?}", resp
-                            )))
+        let tail = if let MgmtCommand::Tail(..) = command { true } else { false };
+        let future = client.request(req).map_err(MgmtError::Http).and_then(move |resp| {
+            let status = resp.status();
+            if !status.is_success() {
+                let body = resp.into_body().concat2().map_err(MgmtError::Http).and_then(move |body| Err(MgmtError::Server(status.as_u16(), server_error(&body))));
+                return Box::new(body) as Box<Future<Item = (), Error = MgmtError>>;
+            }
+            if tail {
+                // events are printed as soon as they come, until user interrupts the command
+                let events = resp
+                    .into_body()
+                    .map_err(MgmtError::Http)
+                    .fold(String::new(), move |mut buf, chunk| {
+                        buf.push_str(&String::from_utf8_lossy(&chunk));
+                        while let Some(pos) = buf.find("\n\n") {
+                            let event = buf.drain(..pos + 2).collect::<String>();
+                            if let Some(line) = render_event(&event, output) {
+                                println!("{}", line);
+                            }
                         }
-                    }
-                });
-                Box::new(future)
+                        Ok::<_, MgmtError>(buf)
+                    })
+                    .map(|_| ());
+                return Box::new(events);
             }
-        }
+            let body = resp.into_body().concat2().map_err(MgmtError::Http).map(move |body| {
+                if body.len() == 0 {
+                    println!("{}", status);
+                    return;
+                }
+                match serde_json::from_slice::<Value>(&*body) {
+                    Ok(value) => print!("{}", render(&value, output)),
+                    Err(_) => println!("{}", String::from_utf8_lossy(&*body)),
+                }
+            });
+            Box::new(body)
+        });
+        Box::new(future)
     }
 }
 
@@ -347,6 +1440,8 @@ mod test {
     use tokio::runtime::current_thread::Runtime;
     use tokio::timer::Delay;
 
+    use bioyino_metric::protocol_capnp::message as cmsg;
+
     use super::*;
     fn prepare_log() -> Logger {
         // Set logging
@@ -368,7 +1463,7 @@ mod test {
         let c_serv_err_log = rlog.clone();
         let s_addr = address.clone();
         let server = hyper::Server::bind(&address)
-            .serve(move || ok::<_, hyper::Error>(MgmtServer::new(c_serv_log.clone(), &s_addr)))
+            .serve(move || ok::<_, hyper::Error>(MgmtServer::new(c_serv_log.clone(), &s_addr, Vec::new(), Arc::new(System::default()), Reloader::new(&c_serv_log))))
             .map_err(move |e| {
                 warn!(c_serv_err_log, "management server gone with error: {:?}", e);
             });
@@ -412,6 +1507,8 @@ mod test {
                         ServerStatus {
                             consensus_status: ConsensusState::Enabled,
                             leader_status: true,
+                            ingestion_paused: false,
+                            flush_paused: false,
                         }
                     )
                 })
@@ -421,4 +1518,129 @@ mod test {
         let test_delay = Delay::new(test_timeout);
         runtime.block_on(test_delay).expect("runtime");
     }
+
+    #[test]
+    fn leader_commands() {
+        let mut config = System::default();
+        config.network.peer_listen = "10.0.0.1:8136".parse().unwrap();
+        config.raft.this_node = Some("node1.example.org:8138".to_string());
+        assert!(is_this_node("10.0.0.1:8136", &config));
+        assert!(is_this_node("10.0.0.1", &config));
+        assert!(is_this_node("node1.example.org", &config));
+        assert!(!is_this_node("node2.example.org:8138", &config));
+
+        // server expects the same message the client sends
+        let command = MgmtCommand::Leader(LeaderCommand::Pin("node1.example.org".to_string()));
+        let json = serde_json::to_string(&command).unwrap();
+        assert_eq!(json, r#"{"leader":{"pin":"node1.example.org"}}"#);
+        match serde_json::from_str(&json).unwrap() {
+            MgmtCommand::Leader(LeaderCommand::Pin(node)) => assert_eq!(node, "node1.example.org"),
+            command => panic!("unexpected command {:?}", command),
+        }
+        assert!(serde_json::from_str::<MgmtCommand>(r#"{"leader":"step-down"}"#).is_ok());
+        assert!(serde_json::from_str::<MgmtCommand>(r#"{"leader":"resign"}"#).is_err());
+    }
+
+    #[test]
+    fn dump_formats() {
+        let mut cache = Cache::new();
+        cache.insert(crate::intern::intern(b"dump.formats.counter"), Metric::new(5f64, MetricType::Counter, None, None).unwrap());
+        cache.insert(crate::intern::intern(b"dump.formats.gauge"), Metric::new(2f64, MetricType::Gauge(None), None, None).unwrap());
+
+        let dump = serialize_dump(cache.clone(), &DumpFormat::Json).unwrap();
+        let values: BTreeMap<String, MetricValue> = serde_json::from_slice(&dump).unwrap();
+        assert_eq!(values.len(), 2);
+        assert_eq!(values["dump.formats.counter"].mtype, "counter");
+        assert_eq!(values["dump.formats.counter"].value, 5f64);
+        assert_eq!(values["dump.formats.gauge"].mtype, "gauge");
+
+        // capnp dump is a snapshot message peers are able to read
+        let dump = serialize_dump(cache, &DumpFormat::Capnp).unwrap();
+        let reader = capnp::serialize::read_message(&mut &dump[..], capnp::message::ReaderOptions::new()).unwrap();
+        let mut metrics = match reader.get_root::<cmsg::Reader>().unwrap().which().unwrap() {
+            cmsg::Snapshot(reader) => reader.unwrap().iter().map(|reader| Metric::<Float>::from_capnp(reader).unwrap()).collect::<Vec<_>>(),
+            _ => panic!("dump is not a snapshot message"),
+        };
+        metrics.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(metrics.iter().map(|(name, metric)| (name.clone(), metric.value)).collect::<Vec<_>>(), vec![(Bytes::from("dump.formats.counter"), 5f64), (Bytes::from("dump.formats.gauge"), 2f64)]);
+
+        assert_eq!(DumpFormat::from_str("capnp").unwrap(), DumpFormat::Capnp);
+        assert!(DumpFormat::from_str("xml").is_err());
+    }
+
+    #[test]
+    fn dump_name_set() {
+        let mut cache = Cache::new();
+        cache.insert(crate::intern::intern(b"dump.names.metric"), Metric::new(1f64, MetricType::Counter, None, None).unwrap());
+        for format in &[DumpFormat::Json, DumpFormat::Capnp] {
+            let dump = serialize_dump(cache.clone(), format).unwrap();
+            assert_eq!(dump_names(&dump, format).unwrap(), vec![Bytes::from("dump.names.metric")]);
+        }
+        assert!(dump_names(b"[1, 2]", &DumpFormat::Json).is_err());
+        assert_eq!(DumpFormat::from_path(Path::new("/var/lib/bioyino/metrics.capnp")), DumpFormat::Capnp);
+        assert_eq!(DumpFormat::from_path(Path::new("metrics.json")), DumpFormat::Json);
+    }
+
+    // status of the answer to a request that is handled without reaching workers
+    fn answer_status(method: Method, uri: &str, body: &str) -> StatusCode {
+        let mut server = MgmtServer::new(prepare_log(), &"127.0.0.1:8137".parse().unwrap(), Vec::new(), Arc::new(System::default()), Reloader::new(&prepare_log()));
+        let req = Request::builder().method(method).uri(uri).body(Body::from(body.to_string())).unwrap();
+        server.call(req).wait().unwrap().status()
+    }
+
+    #[test]
+    fn bad_requests() {
+        assert_eq!(answer_status(Method::GET, "/top?n=0", ""), StatusCode::BAD_REQUEST);
+        assert_eq!(answer_status(Method::GET, "/top?by=names&depth=0", ""), StatusCode::BAD_REQUEST);
+        // relabel rules without tags they need are not installed
+        assert_eq!(answer_status(Method::POST, "/rules", r#"{"add-relabel": {"action": "copy", "source": "dc"}}"#), StatusCode::BAD_REQUEST);
+        assert_eq!(answer_status(Method::PUT, "/rules", r#"{"relabel": [{"action": "from-name", "name": "a.*", "target": "x", "value": "$2"}]}"#), StatusCode::BAD_REQUEST);
+        assert!(RULES.read().unwrap().relabel.is_empty());
+    }
+
+    #[test]
+    fn profile_duration() {
+        assert_eq!(profile_seconds("10", 60_000), Some(10));
+        assert_eq!(profile_seconds("60", 60_000), Some(60));
+        assert_eq!(profile_seconds("61", 60_000), None);
+        assert_eq!(profile_seconds("0", 60_000), None);
+        assert_eq!(profile_seconds("-1", 60_000), None);
+        // would overflow when converted to milliseconds
+        assert_eq!(profile_seconds(&u64::max_value().to_string(), u64::max_value()), None);
+        assert_eq!(profile_seconds("18446744073709552", 60_000), None);
+    }
+
+    #[test]
+    fn encoded_command_queries() {
+        let (method, path, _) = MgmtCommand::Tail("requests;env=prod&x *".to_string(), 5).request().unwrap();
+        assert_eq!(method, Method::GET);
+        assert_eq!(path, "tail?pattern=requests%3Benv%3Dprod%26x%20%2A&rate=5");
+        // server reads the value as it was given
+        let req = Request::get(format!("/{}", path).as_str()).body(Body::empty()).unwrap();
+        assert_eq!(query_param(&req, "pattern"), Some("requests;env=prod&x *".to_string()));
+        assert_eq!(query_param(&req, "rate"), Some("5".to_string()));
+
+        let (_, path, _) = MgmtCommand::ChangeRules(RulesChange::RemoveBlock("a b".to_string()), true).request().unwrap();
+        assert_eq!(path, "rules/block?pattern=a%20b&persist=true");
+
+        // a host that cannot be a part of URL is an error, not a panic
+        let mut client = MgmtClient::new(prepare_log(), "127.0.0.1:8137".parse().unwrap(), MgmtCommand::Status);
+        client.host = "bad host".to_string();
+        match client.into_future().wait() {
+            Err(MgmtError::BadUrl(url, _)) => assert_eq!(url, "http://bad host/api/v1/status"),
+            res => panic!("unexpected result {:?}", res.map_err(|e| e.to_string())),
+        }
+    }
+
+    #[test]
+    fn encoded_metric_names() {
+        assert_eq!(path_metric_name("/metrics/requests%3Bhost%3Dweb1%3benv%3Dprod"), Bytes::from("requests;env=prod;host=web1"));
+        assert_eq!(path_metric_name("/metrics/plain.name"), Bytes::from("plain.name"));
+        // broken sequences are not decoded
+        assert_eq!(percent_decode("100%25%2%zz%"), b"100%%2%zz%".to_vec());
+
+        let req = Request::get("/metrics?kind=x&pattern=requests%3Benv%3D*").body(Body::empty()).unwrap();
+        assert_eq!(query_param(&req, "pattern"), Some("requests;env=*".to_string()));
+        assert_eq!(query_param(&req, "prefix"), None);
+    }
 }
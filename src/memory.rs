@@ -0,0 +1,170 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use futures::future::{ok, Either, Future};
+use futures::sync::mpsc::Sender;
+use futures::Stream;
+use serde_derive::{Deserialize, Serialize};
+use slog::{debug, info, o, warn, Logger};
+use tokio::timer::Interval;
+
+use crate::alloc::{allocator_stats, release_memory};
+use crate::carbon::flush_to_carbon;
+use crate::config::Memory;
+use crate::events::event;
+use crate::limits::RESOURCE_LIMITS;
+use crate::stats::collect_memory;
+use crate::task::Task;
+use crate::workers::WorkerSet;
+use crate::{EARLY_FLUSHES, RUNTIME_CONFIG};
+
+// current pressure level and the limit of timer samples applied at it, 0 means no limit
+static PRESSURE: AtomicUsize = AtomicUsize::new(0);
+static SAMPLE_CAP: AtomicUsize = AtomicUsize::new(0);
+
+/// How close memory usage is to the budget. Every level also applies all actions of the lower ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Pressure {
+    Normal,
+    /// New timer samples are dropped for timers having too many of them
+    CapSamples,
+    /// Incoming metrics from statsd and agents are dropped
+    Shed,
+    /// Metrics are flushed to backend before the interval ends, so caches are emptied
+    Flush,
+}
+
+impl Pressure {
+    fn from_level(level: usize) -> Self {
+        match level {
+            0 => Pressure::Normal,
+            1 => Pressure::CapSamples,
+            2 => Pressure::Shed,
+            _ => Pressure::Flush,
+        }
+    }
+
+    pub fn current() -> Self {
+        Self::from_level(PRESSURE.load(Ordering::Relaxed))
+    }
+
+    /// Pressure level for the number of bytes used, budget of 0 means there is no budget
+    pub fn for_usage(used: usize, budget: usize, options: &Memory) -> Self {
+        if budget == 0 {
+            return Pressure::Normal;
+        }
+        let ratio = used as f64 / budget as f64;
+        if ratio >= options.flush_ratio as f64 {
+            Pressure::Flush
+        } else if ratio >= options.shed_ratio as f64 {
+            Pressure::Shed
+        } else if ratio >= options.cap_samples_ratio as f64 {
+            Pressure::CapSamples
+        } else {
+            Pressure::Normal
+        }
+    }
+}
+
+/// Set the current level, returns the previous one
+fn set_pressure(pressure: Pressure, sample_cap: usize) -> Pressure {
+    SAMPLE_CAP.store(if pressure >= Pressure::CapSamples { sample_cap } else { 0 }, Ordering::Relaxed);
+    Pressure::from_level(PRESSURE.swap(pressure as usize, Ordering::Relaxed))
+}
+
+/// Maximum number of samples a timer may have now, `None` if there is no limit
+pub fn sample_cap() -> Option<usize> {
+    match SAMPLE_CAP.load(Ordering::Relaxed) {
+        0 => None,
+        cap => Some(cap),
+    }
+}
+
+/// Whether incoming metrics should be dropped to save memory
+pub fn shedding() -> bool {
+    PRESSURE.load(Ordering::Relaxed) >= Pressure::Shed as usize
+}
+
+/// A future checking memory used by caches, timer samples, snapshots and backend queues against
+/// the budget every `interval` and switching pressure level. Budget and thresholds are taken from
+/// runtime config on every check, so they can be reloaded. Without a budget set, it is taken from cgroup memory limit.
+/// Never gets ready.
+pub fn watch_memory(chans: Vec<Sender<Task>>, interval: Duration, log: Logger) -> impl Future<Item = (), Error = ()> {
+    let log = log.new(o!("source"=>"memory"));
+    let err_log = log.clone();
+    let mut chans = WorkerSet::for_caches(chans);
+    Interval::new(Instant::now() + interval, interval)
+        .map_err(move |e| {
+            warn!(err_log, "memory check timer failed"; "error"=>e.to_string());
+        })
+        .for_each(move |_| {
+            let options = RUNTIME_CONFIG.read().unwrap().memory.clone();
+            let budget = RESOURCE_LIMITS.memory_budget(&options);
+            if budget == 0 {
+                set_pressure(Pressure::Normal, 0);
+                return Either::A(ok(()));
+            }
+            chans.refresh();
+            let chans = chans.to_vec();
+            let log = log.clone();
+            Either::B(collect_memory(&chans).map(move |report| {
+                let pressure = Pressure::for_usage(report.total_bytes, budget, &options);
+                let previous = set_pressure(pressure, options.timer_sample_cap);
+                if pressure > previous {
+                    warn!(log, "memory pressure increased"; "level"=>format!("{:?}", pressure), "used"=>report.total_bytes, "budget"=>budget);
+                    event("memory", format!("pressure increased to {:?}, {} of {} bytes used", pressure, report.total_bytes, budget));
+                } else if pressure < previous {
+                    info!(log, "memory pressure decreased"; "level"=>format!("{:?}", pressure), "used"=>report.total_bytes, "budget"=>budget);
+                    event("memory", format!("pressure decreased to {:?}, {} of {} bytes used", pressure, report.total_bytes, budget));
+                }
+                // flushing takes time, so it is only started once when the level is reached
+                if pressure == Pressure::Flush && previous != Pressure::Flush {
+                    EARLY_FLUSHES.add(1);
+                    flush_to_carbon(chans, None, log.clone()).map(|_| ()).unwrap_or_else(|e| {
+                        warn!(log, "early flush failed"; "error"=>e.to_string());
+                    });
+                }
+            }))
+        })
+}
+
+/// Periodically give memory freed after cardinality spikes back to the system
+pub fn trim_memory(interval: Duration, log: Logger) -> impl Future<Item = (), Error = ()> {
+    let log = log.new(o!("source"=>"memory"));
+    let err_log = log.clone();
+    Interval::new(Instant::now() + interval, interval)
+        .map_err(move |e| {
+            warn!(err_log, "memory trim timer failed"; "error"=>e.to_string());
+        })
+        .for_each(move |_| {
+            let resident = || allocator_stats().map(|stats| stats.resident_bytes).unwrap_or(0);
+            let before = resident();
+            if release_memory() {
+                debug!(log, "memory trimmed"; "released"=>before.saturating_sub(resident()));
+            }
+            Ok(())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pressure_levels() {
+        let options = Memory::default();
+        assert_eq!(Pressure::for_usage(1 << 40, 0, &options), Pressure::Normal);
+        assert_eq!(Pressure::for_usage(100, 1000, &options), Pressure::Normal);
+        assert_eq!(Pressure::for_usage(800, 1000, &options), Pressure::CapSamples);
+        assert_eq!(Pressure::for_usage(950, 1000, &options), Pressure::Shed);
+        assert_eq!(Pressure::for_usage(1200, 1000, &options), Pressure::Flush);
+
+        assert_eq!(set_pressure(Pressure::Shed, 10), Pressure::Normal);
+        assert_eq!(sample_cap(), Some(10));
+        assert!(shedding());
+        assert_eq!(set_pressure(Pressure::Normal, 10), Pressure::Shed);
+        assert_eq!(sample_cap(), None);
+        assert!(!shedding());
+    }
+}
@@ -0,0 +1,192 @@
+use toml::Value;
+
+/// Version of configuration layout this build understands. Configurations without `config-version`
+/// are considered to be of version 1, which is the layout of releases before 0.5.
+pub const CONFIG_VERSION: u32 = 2;
+
+enum Change {
+    /// Option was removed, with the explanation of what to do instead
+    Removed(&'static str),
+    /// One of option values was renamed
+    ValueRenamed(&'static str, &'static str),
+}
+
+struct Migration {
+    /// Dotted option path as it was before the change
+    key: &'static str,
+    /// The first configuration version with the change
+    since: u32,
+    change: Change,
+}
+
+const fn migration(key: &'static str, since: u32, change: Change) -> Migration {
+    Migration { key, since, change }
+}
+
+// Old names of options that are still accepted with a warning whatever config-version is,
+// so the binary can be upgraded before the configuration is changed.
+// Newer renames go last, so options renamed more than once are renamed step by step.
+const LEGACY_NAMES: &[(&str, &str)] = &[
+    ("metrics.multi-threads", "metrics.aggregation-threads"),
+    ("management.tls-cert", "tls.cert"),
+    ("management.tls-key", "tls.key"),
+    ("management.tls-client-ca", "tls.client-ca"),
+];
+
+// changes that cannot be done by renaming, they depend on config-version
+const MIGRATIONS: &[Migration] = &[
+    migration("metrics.aggregation-mode", 2, Change::ValueRenamed("multi", "separate")),
+    migration("metrics.max-metrics", 2, Change::Removed("the limit was never implemented, remove the option")),
+    migration("carbon.enabled", 2, Change::Removed("carbon backend is always enabled, remove the option")),
+    migration("consul.start-disabled", 2, Change::Removed("use consul.start-as = \"disabled\" instead")),
+];
+
+fn get<'a>(config: &'a Value, key: &str) -> Option<&'a Value> {
+    key.split('.').fold(Some(config), |value, part| value.and_then(|value| value.get(part)))
+}
+
+fn take(config: &mut Value, key: &str) -> Option<Value> {
+    let (parent, name) = match key.rfind('.') {
+        Some(pos) => (&key[..pos], &key[pos + 1..]),
+        None => ("", key),
+    };
+    let mut table = config;
+    for part in parent.split('.').filter(|part| part.len() > 0) {
+        table = table.get_mut(part)?;
+    }
+    table.as_table_mut()?.remove(name)
+}
+
+fn put(config: &mut Value, key: &str, value: Value) {
+    let mut table = config;
+    let mut parts = key.split('.').peekable();
+    while let Some(part) = parts.next() {
+        let current = match table.as_table_mut() {
+            Some(current) => current,
+            None => return,
+        };
+        if parts.peek().is_none() {
+            current.insert(part.to_string(), value);
+            return;
+        }
+        table = current.entry(part.to_string()).or_insert_with(|| Value::Table(toml::value::Table::new()));
+    }
+}
+
+/// Move options with legacy names to their current names
+pub fn rename_legacy(config: &mut Value) -> Vec<String> {
+    let mut messages = Vec::new();
+    for (old, new) in LEGACY_NAMES {
+        if let Some(value) = take(config, old) {
+            if get(config, new).is_some() {
+                messages.push(format!("{}: option is deprecated and ignored, because {} is also set", old, new));
+            } else {
+                messages.push(format!("{}: option is deprecated, use {} instead", old, new));
+                put(config, new, value);
+            }
+        }
+    }
+    messages
+}
+
+/// Bring configuration to the current layout: options with legacy names are renamed, options
+/// changed since the config-version set in configuration are migrated. Messages about what should be
+/// changed in the file are returned.
+pub fn migrate(config: &mut Value) -> Vec<String> {
+    let mut messages = rename_legacy(config);
+    let version = match config.get("config-version").and_then(|version| version.as_integer()) {
+        Some(version) => version as u32,
+        None => 1,
+    };
+    if version > CONFIG_VERSION {
+        messages.push(format!("config-version: {} is newer than {} supported by this build, some options may not be recognized", version, CONFIG_VERSION));
+        return messages;
+    }
+
+    let renamed = messages.len();
+    for migration in MIGRATIONS.iter().filter(|migration| migration.since > version) {
+        match migration.change {
+            Change::Removed(advice) => {
+                if take(config, migration.key).is_some() {
+                    messages.push(format!("{}: option is removed since config version {}, {}", migration.key, migration.since, advice));
+                }
+            }
+            Change::ValueRenamed(old, new) => {
+                if get(config, migration.key).and_then(|value| value.as_str()) == Some(old) {
+                    messages.push(format!("{}: value {:?} is renamed to {:?} since config version {}", migration.key, old, new, migration.since));
+                    put(config, migration.key, Value::String(new.to_string()));
+                }
+            }
+        }
+    }
+    if messages.len() > renamed {
+        messages.push(format!("config-version: set it to {} after changing the options above", CONFIG_VERSION));
+    }
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ConfigFormat, System};
+
+    #[test]
+    fn old_options_migrated() {
+        let mut config: Value = toml::de::from_str("[metrics]\naggregation-mode = \"multi\"\nmulti-threads = 4\nmax-metrics = 10\n[carbon]\ninterval = 1000\n").unwrap();
+        let messages = migrate(&mut config);
+        assert_eq!(messages.len(), 4);
+        assert_eq!(messages[0], "metrics.multi-threads: option is deprecated, use metrics.aggregation-threads instead".to_string());
+        assert!(messages[1].starts_with("metrics.aggregation-mode: value \"multi\" is renamed to \"separate\""));
+        let expected: Value = toml::de::from_str("[metrics]\naggregation-mode = \"separate\"\naggregation-threads = 4\n[carbon]\ninterval = 1000\n").unwrap();
+        assert_eq!(config, expected);
+
+        let mut config: Value = toml::de::from_str("config-version = 2\n[metrics]\nmax-metrics = 10\n").unwrap();
+        assert_eq!(migrate(&mut config), Vec::<String>::new());
+
+        // legacy names do not depend on version
+        let mut config: Value = toml::de::from_str("config-version = 2\n[metrics]\nmulti-threads = 4\naggregation-threads = 2\n").unwrap();
+        assert_eq!(migrate(&mut config), vec!["metrics.multi-threads: option is deprecated and ignored, because metrics.aggregation-threads is also set".to_string()]);
+        assert_eq!(get(&config, "metrics.aggregation-threads"), Some(&Value::Integer(2)));
+
+        let mut config: Value = toml::de::from_str("config-version = 3\n").unwrap();
+        assert_eq!(migrate(&mut config).len(), 1);
+
+        let mut config: Value = toml::de::from_str("[network]\nlisten = \"127.0.0.1:8125\"\n").unwrap();
+        put(&mut config, "network.nodes", Value::Array(Vec::new()));
+        assert_eq!(take(&mut config, "network.listen"), Some(Value::String("127.0.0.1:8125".to_string())));
+        assert_eq!(get(&config, "network.nodes"), Some(&Value::Array(Vec::new())));
+    }
+
+    #[test]
+    fn legacy_names_accepted() {
+        let mut config: Value = toml::de::from_str("config-version = 2\n[metrics]\nmulti-threads = 3\n").unwrap();
+        let messages = rename_legacy(&mut config);
+        assert_eq!(messages, vec!["metrics.multi-threads: option is deprecated, use metrics.aggregation-threads instead".to_string()]);
+        let expected: Value = toml::de::from_str("config-version = 2\n[metrics]\naggregation-threads = 3\n").unwrap();
+        assert_eq!(config, expected);
+
+        // configuration of the current version is loaded with old names, only deprecations are reported
+        let mut config: Value = toml::de::from_str("config-version = 2\n[metrics]\nmulti-threads = 3\n").unwrap();
+        let messages = migrate(&mut config);
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].contains("is deprecated"));
+        let system: System = config.try_into().unwrap();
+        assert_eq!(system.metrics.aggregation_threads, Some(3));
+    }
+
+    #[test]
+    fn legacy_tls_names_moved() {
+        let mut config: Value = toml::de::from_str("config-version = 2\n[management]\ntls-cert = \"cert.pem\"\ntls-key = \"key.pem\"\n").unwrap();
+        let messages = rename_legacy(&mut config);
+        assert_eq!(messages, vec!["management.tls-cert: option is deprecated, use tls.cert instead".to_string(), "management.tls-key: option is deprecated, use tls.key instead".to_string()]);
+        // options are moved to a section that did not exist
+        let expected: Value = toml::de::from_str("config-version = 2\n[management]\n[tls]\ncert = \"cert.pem\"\nkey = \"key.pem\"\n").unwrap();
+        assert_eq!(config, expected);
+
+        let system = System::from_source("config-version = 2\n[metrics]\nmulti-threads = 3\n[management]\ntls-client-ca = \"ca.pem\"\n", ConfigFormat::Toml).unwrap();
+        assert_eq!(system.metrics.aggregation_threads, Some(3));
+        assert_eq!(system.tls.client_ca, Some("ca.pem".to_string()));
+        assert_eq!(system.migration_warnings.len(), 2);
+        assert!(system.migration_warnings.iter().all(|warning| warning.contains("is deprecated")));
+    }
+}
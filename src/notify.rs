@@ -0,0 +1,181 @@
+use std::env;
+use std::io;
+use std::mem;
+use std::process;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use futures::future::{Either, Future};
+use futures::sync::mpsc::Sender;
+use futures::Stream;
+use slog::{info, o, warn, Logger};
+use tokio::timer::Interval;
+
+use crate::health::{check_workers, CheckStatus};
+use crate::task::Task;
+use crate::workers::WorkerSet;
+use crate::{ConsensusKind, CONSENSUS_REACHABLE, IS_LEADER, PEER_LISTENING, STATSD_LISTENING};
+
+// how often readiness is checked until it is reported
+const READY_CHECK: u64 = 100;
+
+/// Send `state` to the service manager over `NOTIFY_SOCKET`, returns false if it is not set,
+/// i.e. the process is not started by systemd with `Type=notify`
+pub fn sd_notify(state: &str) -> io::Result<bool> {
+    match env::var("NOTIFY_SOCKET") {
+        Ok(addr) => notify_to(&addr, state).map(|_| true),
+        Err(_) => Ok(false),
+    }
+}
+
+/// Send a datagram to a unix socket at `addr`, a leading `@` means the abstract namespace
+pub fn notify_to(addr: &str, state: &str) -> io::Result<()> {
+    let mut sockaddr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    sockaddr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    let path = addr.as_bytes();
+    if path.len() == 0 || path.len() >= sockaddr.sun_path.len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("bad notify socket address {:?}", addr)));
+    }
+    for (dst, src) in sockaddr.sun_path.iter_mut().zip(path) {
+        *dst = *src as libc::c_char;
+    }
+    if path[0] == b'@' {
+        sockaddr.sun_path[0] = 0;
+    }
+    let len = mem::size_of::<libc::sa_family_t>() + path.len();
+
+    let fd = unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let sent = unsafe { libc::sendto(fd, state.as_ptr() as *const libc::c_void, state.len(), libc::MSG_NOSIGNAL, &sockaddr as *const libc::sockaddr_un as *const libc::sockaddr, len as libc::socklen_t) };
+    let result = if sent < 0 { Err(io::Error::last_os_error()) } else { Ok(()) };
+    unsafe { libc::close(fd) };
+    result
+}
+
+/// How often to ping the watchdog: half of `WATCHDOG_USEC`, none if it is not set or is meant for another process
+pub fn watchdog_interval(usec: Option<&str>, pid: Option<&str>, own_pid: u32) -> Option<Duration> {
+    if let Some(pid) = pid {
+        if pid.parse::<u32>().ok() != Some(own_pid) {
+            return None;
+        }
+    }
+    match usec.and_then(|usec| usec.parse::<u64>().ok()) {
+        Some(usec) if usec > 0 => Some(Duration::from_micros(usec / 2)),
+        _ => None,
+    }
+}
+
+// what readiness is still waiting for, none when ready
+fn waiting_for(consensus: &ConsensusKind) -> Option<&'static str> {
+    if !STATSD_LISTENING.load(Ordering::Relaxed) {
+        return Some("statsd listener");
+    }
+    if !PEER_LISTENING.load(Ordering::Relaxed) {
+        return Some("peer listener");
+    }
+    let consensus_up = match consensus {
+        ConsensusKind::None => true,
+        // a standby never hears of a primary that is down, but is up once it took over
+        ConsensusKind::Standby => CONSENSUS_REACHABLE.load(Ordering::Relaxed) || IS_LEADER.load(Ordering::Relaxed),
+        _ => CONSENSUS_REACHABLE.load(Ordering::Relaxed),
+    };
+    if !consensus_up {
+        return Some("consensus");
+    }
+    None
+}
+
+/// Report READY=1 once statsd and peer listeners are started and consensus is reachable. A process taking sockets
+/// over from a previous one reports itself as the main one, so the service is tracked by it's PID after handoff.
+/// Does nothing if the process is not started with `Type=notify`.
+pub fn notify_ready(consensus: ConsensusKind, handed_off: bool, log: Logger) -> impl Future<Item = (), Error = ()> {
+    let log = log.new(o!("source"=>"sd-notify"));
+    if env::var_os("NOTIFY_SOCKET").is_none() {
+        return Either::A(futures::future::ok(()));
+    }
+    let mut last = None;
+    let status_log = log.clone();
+    let interval = Duration::from_millis(READY_CHECK);
+    let future = Interval::new(Instant::now(), interval)
+        .map_err(|_| ())
+        .skip_while(move |_| {
+            let waiting = waiting_for(&consensus);
+            if waiting.is_some() && waiting != last {
+                last = waiting;
+                let status = format!("STATUS=waiting for {}", waiting.unwrap_or_default());
+                sd_notify(&status).unwrap_or_else(|e| warn!(status_log, "notifying service manager"; "error"=>e.to_string()));
+            }
+            Ok(waiting.is_some())
+        })
+        .into_future()
+        .map(move |_| {
+            let mut state = "READY=1\nSTATUS=receiving metrics".to_string();
+            if handed_off {
+                state.push_str(&format!("\nMAINPID={}", process::id()));
+            }
+            match sd_notify(&state) {
+                Ok(_) => info!(log, "readiness reported to service manager"),
+                Err(e) => warn!(log, "reporting readiness to service manager"; "error"=>e.to_string()),
+            }
+        })
+        .map_err(|_| ());
+    Either::B(future)
+}
+
+/// Ping the systemd watchdog every half of `WatchdogSec=` while workers answer their pings. It runs in the main
+/// event loop, so a wedged loop or stuck workers stop the pings and the service is restarted by systemd.
+pub fn watch_dog(chans: Vec<Sender<Task>>, log: Logger) -> impl Future<Item = (), Error = ()> {
+    let log = log.new(o!("source"=>"watchdog"));
+    let usec = env::var("WATCHDOG_USEC").ok();
+    let pid = env::var("WATCHDOG_PID").ok();
+    let interval = match watchdog_interval(usec.as_ref().map(String::as_str), pid.as_ref().map(String::as_str), process::id()) {
+        Some(interval) if env::var_os("NOTIFY_SOCKET").is_some() => interval,
+        _ => return Either::A(futures::future::ok(())),
+    };
+    info!(log, "answering service manager watchdog"; "interval-ms"=>interval.as_secs() * 1000 + interval.subsec_millis() as u64);
+    let err_log = log.clone();
+    let mut chans = WorkerSet::for_caches(chans);
+    let future = Interval::new(Instant::now(), interval)
+        .map_err(move |e| warn!(err_log, "watchdog timer failed"; "error"=>e.to_string()))
+        .for_each(move |_| {
+            let log = log.clone();
+            chans.refresh();
+            check_workers(&chans).map(move |workers| {
+                if workers.status == CheckStatus::Fail {
+                    // no ping, so systemd restarts the service if workers do not come back in time
+                    warn!(log, "watchdog not answered"; "reason"=>workers.message.unwrap_or_default());
+                } else if let Err(e) = sd_notify("WATCHDOG=1") {
+                    warn!(log, "pinging watchdog"; "error"=>e.to_string());
+                }
+            })
+        });
+    Either::B(future)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixDatagram;
+
+    #[test]
+    fn watchdog_notify() {
+        assert_eq!(watchdog_interval(Some("30000000"), None, 10), Some(Duration::from_secs(15)));
+        assert_eq!(watchdog_interval(Some("30000000"), Some("10"), 10), Some(Duration::from_secs(15)));
+        // left by the parent process
+        assert_eq!(watchdog_interval(Some("30000000"), Some("9"), 10), None);
+        assert_eq!(watchdog_interval(Some("0"), None, 10), None);
+        assert_eq!(watchdog_interval(None, None, 10), None);
+
+        let path = env::temp_dir().join(format!("bioyino-notify-{}.sock", process::id()));
+        let socket = UnixDatagram::bind(&path).unwrap();
+        notify_to(path.to_str().unwrap(), "READY=1").unwrap();
+        let mut buf = [0u8; 64];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(notify_to("", "READY=1").is_err());
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use futures::{Future, Stream};
+use lazy_static::lazy_static;
+use serde_derive::{Deserialize, Serialize};
+use slog::{o, warn, Logger};
+use tokio::timer::Interval;
+
+use crate::cluster::now_ms;
+use crate::parser::{error_kind, ParseErrorKind};
+
+// every error of a kind with the number divisible by this goes to samples, including the first one
+const SAMPLE_EVERY: usize = 100;
+// bytes of a line kept in a sample
+const SAMPLE_LINE_LEN: usize = 256;
+
+lazy_static! {
+    pub static ref PARSE_ERROR_STATS: ParseErrorStats = ParseErrorStats::new(16);
+}
+
+/// Errors of statsd parsing by kind and a few lines that caused them. Samples are kept in a ring,
+/// so they always show recent errors without logging all of them.
+#[derive(Debug)]
+pub struct ParseErrorStats {
+    counts: [AtomicUsize; 9],
+    capacity: AtomicUsize,
+    samples: Mutex<VecDeque<ParseErrorSample>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ParseErrorSample {
+    pub kind: String,
+    /// Offending line with non-printable bytes escaped as `\xNN`
+    pub line: String,
+    pub at_ms: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ParseErrorReport {
+    /// Number of errors of every kind since start
+    pub kinds: Vec<(String, usize)>,
+    /// Recent lines having errors, oldest first
+    pub samples: Vec<ParseErrorSample>,
+}
+
+impl ParseErrorStats {
+    fn new(capacity: usize) -> Self {
+        Self { counts: Default::default(), capacity: AtomicUsize::new(capacity), samples: Mutex::new(VecDeque::with_capacity(capacity)) }
+    }
+
+    /// Number of samples kept, 0 disables sampling
+    pub fn set_capacity(&self, capacity: usize) {
+        self.capacity.store(capacity, Ordering::Relaxed);
+        let mut samples = self.samples.lock().unwrap();
+        while samples.len() > capacity {
+            samples.pop_front();
+        }
+    }
+
+    /// Count an error for a buffer position the parser has given up on
+    pub fn record(&self, input: &[u8], pos: usize) {
+        let rest = &input[pos.min(input.len())..];
+        // the parser only gives up on lines without ending when they are too long
+        let (kind, line) = match rest.iter().position(|c| *c == b'\n') {
+            Some(len) => (error_kind(&rest[..len]), &rest[..len]),
+            None => (ParseErrorKind::TooLong, rest),
+        };
+        self.record_kind(kind, line);
+    }
+
+    pub fn record_kind(&self, kind: ParseErrorKind, line: &[u8]) {
+        let count = self.counts[kind as usize].fetch_add(1, Ordering::Relaxed);
+        let capacity = self.capacity.load(Ordering::Relaxed);
+        if capacity == 0 || count % SAMPLE_EVERY != 0 {
+            return;
+        }
+        let sample = ParseErrorSample { kind: kind.name().to_string(), line: escape(line, SAMPLE_LINE_LEN), at_ms: now_ms() };
+        let mut samples = self.samples.lock().unwrap();
+        if samples.len() >= capacity {
+            samples.pop_front();
+        }
+        samples.push_back(sample);
+    }
+
+    pub fn counts(&self) -> Vec<(ParseErrorKind, usize)> {
+        ParseErrorKind::ALL.iter().map(|kind| (*kind, self.counts[*kind as usize].load(Ordering::Relaxed))).collect()
+    }
+
+    pub fn report(&self) -> ParseErrorReport {
+        let kinds = self.counts().into_iter().map(|(kind, count)| (kind.name().to_string(), count)).collect();
+        let samples = self.samples.lock().unwrap().iter().cloned().collect();
+        ParseErrorReport { kinds, samples }
+    }
+
+    fn last_sample(&self) -> Option<ParseErrorSample> {
+        self.samples.lock().unwrap().back().cloned()
+    }
+}
+
+/// Printable ASCII is kept as is, everything else including backslash is escaped, lines over
+/// `max` bytes are cut
+pub fn escape(line: &[u8], max: usize) -> String {
+    let mut escaped = String::with_capacity(line.len().min(max));
+    for byte in line.iter().take(max).cloned() {
+        match byte {
+            b' '..=b'~' if byte != b'\\' => escaped.push(byte as char),
+            _ => escaped.push_str(&format!("\\x{:02x}", byte)),
+        }
+    }
+    if line.len() > max {
+        escaped.push_str("...");
+    }
+    escaped
+}
+
+/// Log a line with the number of errors of every kind seen since the previous one, nothing is
+/// logged for intervals without errors
+pub fn summarize_parse_errors(interval: Duration, log: Logger) -> impl Future<Item = (), Error = ()> {
+    let log = log.new(o!("source"=>"parse-errors"));
+    let err_log = log.clone();
+    let mut prev = PARSE_ERROR_STATS.counts();
+    Interval::new(Instant::now() + interval, interval)
+        .map_err(move |e| {
+            warn!(err_log, "parse error summary timer failed"; "error"=>e.to_string());
+        })
+        .for_each(move |_| {
+            let counts = PARSE_ERROR_STATS.counts();
+            let delta = counts.iter().zip(prev.iter()).map(|((kind, count), (_, prev))| (*kind, count.wrapping_sub(*prev))).filter(|(_, count)| *count > 0).collect::<Vec<_>>();
+            prev = counts;
+            if delta.len() > 0 {
+                let total = delta.iter().map(|(_, count)| count).sum::<usize>();
+                let kinds = delta.iter().map(|(kind, count)| format!("{}={}", kind.name(), count)).collect::<Vec<_>>().join(" ");
+                let sample = PARSE_ERROR_STATS.last_sample().map(|sample| sample.line).unwrap_or_default();
+                warn!(log, "parse errors"; "total"=>total, "kinds"=>kinds, "sample"=>sample);
+            }
+            Ok(())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sampled_parse_errors() {
+        assert_eq!(escape(b"bad\tline\\\xff", 100), "bad\\x09line\\x5c\\xff");
+        assert_eq!(escape(b"long line", 4), "long...");
+
+        let stats = ParseErrorStats::new(2);
+        stats.record(b"good:1|c\nbad\nworse:1|x\n", 9);
+        stats.record(b"good:1|c\nbad\nworse:1|x\n", 13);
+        stats.record(b"unfinished", 0);
+        let report = stats.report();
+        assert_eq!(report.kinds.iter().filter(|(_, count)| *count > 0).map(|(kind, count)| (kind.as_str(), *count)).collect::<Vec<_>>(), vec![("no-value", 1), ("bad-type", 1), ("too-long", 1)]);
+        // the oldest sample is pushed out
+        assert_eq!(report.samples.iter().map(|sample| sample.line.as_str()).collect::<Vec<_>>(), vec!["worse:1|x", "unfinished"]);
+
+        // only every SAMPLE_EVERY-th error of a kind is sampled
+        (0..SAMPLE_EVERY).map(|_| stats.record_kind(ParseErrorKind::NoType, b"name:1")).last();
+        assert_eq!(stats.report().samples.len(), 2);
+        assert_eq!(stats.report().samples[1].kind, "no-type");
+        stats.record_kind(ParseErrorKind::NoType, b"name:2");
+        assert_eq!(stats.report().samples[1].line, "name:2");
+
+        stats.set_capacity(0);
+        stats.record_kind(ParseErrorKind::EmptyName, b":1|c");
+        assert_eq!(stats.report().samples.len(), 0);
+    }
+}
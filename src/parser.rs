@@ -0,0 +1,323 @@
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::str;
+
+use bioyino_metric::parser::ParseErrorHandler;
+use bioyino_metric::{Metric, MetricType};
+
+use crate::tags::{canonical, TagOptions};
+use crate::Float;
+
+/// Statsd parser working on a borrowed buffer: names are returned as slices of it, so nothing is
+/// allocated for metrics, which names are already known. Buffer consists of lines like
+/// `name:value|type[|@sampling][|#tags]`, the last line may be incomplete and is left unparsed then.
+pub struct StatsdParser<'a, E: ParseErrorHandler> {
+    buf: &'a [u8],
+    pos: usize,
+    max_unparsed: usize,
+    handler: E,
+}
+
+impl<'a, E: ParseErrorHandler> StatsdParser<'a, E> {
+    pub fn new(buf: &'a [u8], max_unparsed: usize, handler: E) -> Self {
+        Self { buf, pos: 0, max_unparsed, handler }
+    }
+
+    /// Number of bytes from the start of buffer that were parsed or skipped as errors
+    pub fn consumed(&self) -> usize {
+        self.pos
+    }
+}
+
+// memchr checks for AVX2 and SSE2 in runtime and falls back to the plain search if there are none
+#[cfg(feature = "simd")]
+fn find(byte: u8, input: &[u8]) -> Option<usize> {
+    memchr::memchr(byte, input)
+}
+
+#[cfg(not(feature = "simd"))]
+fn find(byte: u8, input: &[u8]) -> Option<usize> {
+    input.iter().position(|c| *c == byte)
+}
+
+fn parse_number<T: str::FromStr>(input: &[u8]) -> Option<T> {
+    str::from_utf8(input).ok().and_then(|input| input.parse().ok())
+}
+
+// tags in the name, `name;tag=value` as in graphite or `name,tag=value` as in influx statsd
+fn name_tags<'a>(name: &'a [u8], tags: &mut Vec<(&'a [u8], &'a [u8])>) -> Result<&'a [u8], ParseErrorKind> {
+    let pos = match name.iter().position(|c| *c == b';' || *c == b',') {
+        Some(pos) => pos,
+        None => return Ok(name),
+    };
+    let separator = name[pos];
+    for tag in name[pos + 1..].split(|c| *c == separator) {
+        let eq = find(b'=', tag).ok_or(ParseErrorKind::BadTags)?;
+        if eq == 0 || eq == tag.len() - 1 {
+            return Err(ParseErrorKind::BadTags);
+        }
+        tags.push((&tag[..eq], &tag[eq + 1..]));
+    }
+    Ok(&name[..pos])
+}
+
+// DogStatsD tags field without `#`: `tag:value,tag:value`, tags without values are skipped, as they cannot be
+// a part of canonical name
+fn field_tags<'a>(field: &'a [u8], tags: &mut Vec<(&'a [u8], &'a [u8])>) -> Result<(), ParseErrorKind> {
+    for tag in field.split(|c| *c == b',') {
+        match find(b':', tag) {
+            Some(0) => return Err(ParseErrorKind::BadTags),
+            Some(colon) if colon < tag.len() - 1 => tags.push((&tag[..colon], &tag[colon + 1..])),
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+fn parse<'a>(line: &'a [u8]) -> Result<(Cow<'a, [u8]>, Metric<Float>), ParseErrorKind> {
+    let line = if line.ends_with(b"\r") { &line[..line.len() - 1] } else { line };
+    let colon = find(b':', line).ok_or(ParseErrorKind::NoValue)?;
+    let (name, rest) = (&line[..colon], &line[colon + 1..]);
+    let pipe = find(b'|', rest).ok_or(ParseErrorKind::NoType)?;
+    let (value, rest) = (&rest[..pipe], &rest[pipe + 1..]);
+    if name.len() == 0 {
+        return Err(ParseErrorKind::EmptyName);
+    }
+    if value.len() == 0 {
+        return Err(ParseErrorKind::EmptyValue);
+    }
+    let mut tags = Vec::new();
+    let name = name_tags(name, &mut tags)?;
+    if name.len() == 0 {
+        return Err(ParseErrorKind::EmptyName);
+    }
+    let mut fields = rest.split(|c| *c == b'|');
+    let mtype = fields.next().unwrap_or(b"");
+    let mut sampling = None;
+    for field in fields {
+        match field.first() {
+            Some(b'@') if sampling.is_none() => sampling = Some(parse_number::<f32>(&field[1..]).ok_or(ParseErrorKind::BadSampling)?),
+            Some(b'#') => field_tags(&field[1..], &mut tags)?,
+            _ => return Err(ParseErrorKind::BadSampling),
+        }
+    }
+
+    let bad_value = ParseErrorKind::BadValue;
+    let (mtype, value) = match mtype {
+        b"c" => (MetricType::Counter, parse_number::<Float>(value).ok_or(bad_value)?),
+        // signed gauges change the value instead of setting it
+        b"g" => match value[0] {
+            b'+' => (MetricType::Gauge(Some(1)), parse_number::<Float>(&value[1..]).ok_or(bad_value)?),
+            b'-' => (MetricType::Gauge(Some(-1)), parse_number::<Float>(&value[1..]).ok_or(bad_value)?),
+            _ => (MetricType::Gauge(None), parse_number::<Float>(value).ok_or(bad_value)?),
+        },
+        b"ms" | b"h" => (MetricType::Timer(Vec::new()), parse_number::<Float>(value).ok_or(bad_value)?),
+        b"s" => (MetricType::Set(HashSet::new()), parse_number::<Float>(value).ok_or(bad_value)?),
+        _ => return Err(ParseErrorKind::BadType),
+    };
+    if !value.is_finite() {
+        return Err(bad_value);
+    }
+    let metric = Metric::new(value, mtype, None, sampling).map_err(|_| bad_value)?;
+    Ok((canonical(name, &tags, TagOptions::current()), metric))
+}
+
+/// Parse a single line without line ending. Tags given in graphite or influx syntax in the name, or in DogStatsD `|#tag:value`
+/// field, are put into the name in canonical form, so only names of tagged metrics are allocated.
+pub fn parse_line(line: &[u8]) -> Option<(Cow<[u8]>, Metric<Float>)> {
+    parse(line).ok()
+}
+
+/// Reason of a line not being parsed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    /// No `:` between name and value
+    NoValue,
+    /// No `|` between value and type
+    NoType,
+    EmptyName,
+    EmptyValue,
+    /// Anything except `@rate` or `#tags` after type
+    BadSampling,
+    BadType,
+    /// Value is not a finite number
+    BadValue,
+    /// Unfinished line longer than parser keeps
+    TooLong,
+    /// Tag without name, or without value in the name
+    BadTags,
+}
+
+impl ParseErrorKind {
+    pub const ALL: [ParseErrorKind; 9] = [
+        ParseErrorKind::NoValue,
+        ParseErrorKind::NoType,
+        ParseErrorKind::EmptyName,
+        ParseErrorKind::EmptyValue,
+        ParseErrorKind::BadSampling,
+        ParseErrorKind::BadType,
+        ParseErrorKind::BadValue,
+        ParseErrorKind::TooLong,
+        ParseErrorKind::BadTags,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ParseErrorKind::NoValue => "no-value",
+            ParseErrorKind::NoType => "no-type",
+            ParseErrorKind::EmptyName => "empty-name",
+            ParseErrorKind::EmptyValue => "empty-value",
+            ParseErrorKind::BadSampling => "bad-sampling",
+            ParseErrorKind::BadType => "bad-type",
+            ParseErrorKind::BadValue => "bad-value",
+            ParseErrorKind::TooLong => "too-long",
+            ParseErrorKind::BadTags => "bad-tags",
+        }
+    }
+}
+
+/// Find out why `parse_line` did not parse a line
+pub fn error_kind(line: &[u8]) -> ParseErrorKind {
+    parse(line).err().unwrap_or(ParseErrorKind::BadValue)
+}
+
+impl<'a, E: ParseErrorHandler> Iterator for StatsdParser<'a, E> {
+    type Item = (Cow<'a, [u8]>, Metric<Float>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let buf = self.buf;
+        while self.pos < buf.len() {
+            let start = self.pos;
+            let line = match find(b'\n', &buf[start..]) {
+                Some(len) => {
+                    self.pos = start + len + 1;
+                    &buf[start..start + len]
+                }
+                None => {
+                    // datagrams usually have no line ending at the end, but the rest may also be
+                    // the beginning of a line coming in the next buffer
+                    match parse_line(&buf[start..]) {
+                        Some(parsed) => {
+                            self.pos = buf.len();
+                            return Some(parsed);
+                        }
+                        None if buf.len() - start > self.max_unparsed => {
+                            self.pos = buf.len();
+                            self.handler.handle(buf, start);
+                        }
+                        None => (),
+                    }
+                    return None;
+                }
+            };
+            if line.len() == 0 {
+                continue;
+            }
+            match parse_line(line) {
+                Some(parsed) => return Some(parsed),
+                None => self.handler.handle(buf, start),
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    struct CountErrors(Rc<Cell<usize>>);
+
+    impl ParseErrorHandler for CountErrors {
+        fn handle(&self, _: &[u8], _: usize) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    #[test]
+    fn delimiter_search() {
+        // vectorized search goes in blocks, so delimiters are placed around block boundaries and in the tail
+        let mut buf = vec![b'a'; 100];
+        assert_eq!(find(b'|', &buf), None);
+        for pos in 0..buf.len() {
+            buf[pos] = b'|';
+            assert_eq!(find(b'|', &buf), Some(pos));
+            assert_eq!(find(b'|', &buf[..pos]), None);
+            buf[pos] = b'a';
+        }
+        buf[33] = b':';
+        buf[70] = b':';
+        assert_eq!(find(b':', &buf[34..]), Some(36));
+        assert_eq!(find(b':', &[]), None);
+
+        let long = format!("{}:1|c\n{}:2|g\n", "a".repeat(64), "b".repeat(31));
+        let parsed = StatsdParser::new(long.as_bytes(), 1000, CountErrors(Rc::new(Cell::new(0)))).map(|(name, metric)| (name.len(), metric.value)).collect::<Vec<_>>();
+        assert_eq!(parsed, vec![(64, 1f64), (31, 2f64)]);
+    }
+
+    #[test]
+    fn parse_borrowed_names() {
+        let errors = Rc::new(Cell::new(0));
+        let buf = b"trash\ngorets1:+1000|g\ngorets2:-1000|g|@0.5\ntimer:12.5|ms\r\nbad:1|x\ncounter:3|c\n\nlast:1|c";
+        let mut parser = StatsdParser::new(&buf[..], 100, CountErrors(errors.clone()));
+        let parsed = (&mut parser).collect::<Vec<_>>();
+        assert_eq!(parsed.iter().map(|(name, _)| &name[..]).collect::<Vec<_>>(), vec![&b"gorets1"[..], b"gorets2", b"timer", b"counter", b"last"]);
+        assert_eq!(parsed[0].1.mtype, MetricType::Gauge(Some(1)));
+        assert_eq!(parsed[1].1.value, 1000f64);
+        assert_eq!(parsed[1].1.mtype, MetricType::Gauge(Some(-1)));
+        assert_eq!(parsed[1].1.sampling, Some(0.5f32));
+        assert_eq!(parsed[2].1.value, 12.5f64);
+        assert_eq!(errors.get(), 2);
+        assert_eq!(parser.consumed(), buf.len());
+
+        // incomplete line is left for the next buffer until it is too long
+        let buf = b"counter:3|c\nincompl";
+        let mut parser = StatsdParser::new(&buf[..], 100, CountErrors(errors.clone()));
+        assert_eq!((&mut parser).count(), 1);
+        assert_eq!(parser.consumed(), 12);
+        let mut parser = StatsdParser::new(&buf[..], 3, CountErrors(errors.clone()));
+        assert_eq!((&mut parser).count(), 1);
+        assert_eq!(parser.consumed(), buf.len());
+        assert_eq!(errors.get(), 3);
+    }
+
+    #[test]
+    fn parse_error_kinds() {
+        let lines: &[(&[u8], ParseErrorKind)] = &[
+            (b"trash", ParseErrorKind::NoValue),
+            (b"name:1", ParseErrorKind::NoType),
+            (b":1|c", ParseErrorKind::EmptyName),
+            (b"name:|c", ParseErrorKind::EmptyValue),
+            (b"name:1|c|0.5", ParseErrorKind::BadSampling),
+            (b"name:1|c|@x", ParseErrorKind::BadSampling),
+            (b"name:1|x\r", ParseErrorKind::BadType),
+            (b"name:x|ms", ParseErrorKind::BadValue),
+            (b"name:inf|g", ParseErrorKind::BadValue),
+            (b"name,env:1|c", ParseErrorKind::BadTags),
+            (b"name;env=:1|c", ParseErrorKind::BadTags),
+            (b"name:1|c|#:prod", ParseErrorKind::BadTags),
+            (b"name:1|c|@0.5|@0.5", ParseErrorKind::BadSampling),
+        ];
+        for (line, kind) in lines {
+            assert!(parse_line(line).is_none());
+            assert_eq!(error_kind(line), *kind, "{}", String::from_utf8_lossy(line));
+        }
+    }
+
+    #[test]
+    fn parse_tags() {
+        let lines: &[&[u8]] = &[b"requests:1|c|#host:web1,env:prod", b"requests;host=web1;env=prod:1|c", b"requests,env=prod,host=web1:1|c", b"requests;env=prod:1|c|@0.5|#host:web1,canary"];
+        for line in lines {
+            let (name, metric) = parse_line(line).unwrap();
+            assert_eq!(&name[..], &b"requests;env=prod;host=web1"[..], "{}", String::from_utf8_lossy(line));
+            assert_eq!(metric.mtype, MetricType::Counter);
+        }
+        assert_eq!(parse_line(b"requests:1|c|@0.5|#host:web1").unwrap().1.sampling, Some(0.5f32));
+        match parse_line(b"plain.name:1|c").unwrap().0 {
+            Cow::Borrowed(name) => assert_eq!(name, b"plain.name"),
+            Cow::Owned(_) => panic!("untagged name is copied"),
+        }
+    }
+}
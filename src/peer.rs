@@ -1,27 +1,66 @@
+use std::cell::RefCell;
+use std::io;
+use std::mem;
 use std::net::SocketAddr;
-use std::sync::atomic::Ordering;
-use std::sync::Arc;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::{Duration, Instant};
 
+use bytes::Bytes;
 use capnp;
-use capnp::message::{Builder, ReaderOptions};
+use capnp::message::{Allocator, Builder, HeapAllocator, ReaderOptions, ScratchSpaceHeapAllocator};
+use capnp::Word;
 use capnp_futures::ReadStream;
 use failure_derive::Fail;
-use futures::future::{err, join_all, Either, Future, IntoFuture};
+use lazy_static::lazy_static;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use futures::future::{err, join_all, ok, Either, Future, IntoFuture};
 use futures::sync::mpsc::Sender;
 use futures::sync::oneshot;
 use futures::{Sink, Stream};
-use slog::{debug, error as log_error, o, warn, Logger};
+use slog::{debug, error as log_error, info, o, warn, Logger};
 use tokio::executor::current_thread::spawn;
+use tokio::io::{write_all, AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
 use tokio::timer::Interval;
+use tokio_rustls::webpki::DNSName;
+use tokio_rustls::TlsAcceptor;
 
 use bioyino_metric::protocol_capnp::{message as cmsg, message::Builder as CBuilder};
 use bioyino_metric::{Metric, MetricError};
 
+use crate::acl::PEER_SOURCES;
+use crate::activation::{tcp_listener, PEER_SOCKET};
+use crate::auth::{connect_tls, server_tls, tls_name};
+use crate::cluster::{snapshot_received, snapshot_send_failed, snapshot_sent};
+use crate::intern::{pin, NAMES};
+use crate::memory::shedding;
+use crate::queue::send_task;
+use crate::ratelimit::{Allowance, PEER_LIMIT};
+use crate::stall::{StallOptions, StallWatch};
+use crate::stats::{cache_size, PEER_TCP};
+use crate::tags::normalize;
 use crate::task::Task;
-use crate::util::{bound_stream, reusing_listener, try_resolve, BackoffRetryBuilder};
-use crate::{Cache, Float, PEER_ERRORS};
+use crate::trace::Span;
+use crate::tunables::SNAPSHOT_SCRATCH;
+use crate::util::{bound_stream, resolve_addr, try_resolve, BackoffRetryBuilder};
+use crate::workers::WorkerSet;
+use crate::{Cache, Float, INGESTION_PAUSED, PAUSED_DROPS, PEER_ERRORS, PEER_LISTENING, RUNTIME_CONFIG, SHED_DROPS};
+
+lazy_static! {
+    // building capnp message for a big snapshot takes hundreds of milliseconds, so it is done
+    // in a separate thread not to stall connections of the event loop
+    static ref SNAPSHOT_POOL: ThreadPool = ThreadPoolBuilder::new().thread_name(|i| format!("bioyino_snap{}", i)).num_threads(1).build().expect("creating snapshot serialization thread");
+}
+
+thread_local! {
+    // snapshots are built in the space left from the previous one, so capnp does not allocate and zero
+    // new segments every interval, the space is zeroed back by capnp when the message is dropped
+    static SNAPSHOT_SPACE: RefCell<Vec<Word>> = RefCell::new(Vec::new());
+}
+
+/// Estimated size of the last snapshot taken for sending to peers
+pub static PEER_SNAPSHOT_BYTES: AtomicUsize = AtomicUsize::new(0);
 
 const CAPNP_READER_OPTIONS: ReaderOptions = ReaderOptions { traversal_limit_in_words: 8 * 1024 * 1024 * 1024, nesting_limit: 16 };
 
@@ -55,6 +94,55 @@ pub enum PeerError {
     Metric(MetricError),
 }
 
+/// Collects metrics received from agents into chunks of fixed size, so workers get a few big tasks
+/// instead of a task per metric. Chunks are sent to workers round-robin.
+struct MetricBatcher {
+    chans: WorkerSet,
+    next: usize,
+    size: usize,
+    batch: Vec<(Bytes, Metric<Float>)>,
+    allowance: Allowance,
+    log: Logger,
+}
+
+impl MetricBatcher {
+    fn new(chans: WorkerSet, size: usize, log: Logger) -> Self {
+        Self { chans, next: 0, size: size.max(1), batch: Vec::new(), allowance: Allowance::new(&PEER_LIMIT), log }
+    }
+
+    fn push(&mut self, name: Bytes, metric: Metric<Float>) {
+        if !self.allowance.allow() {
+            PEER_TCP.drops.add(1);
+            return;
+        }
+        self.batch.push((name, metric));
+        if self.batch.len() >= self.size {
+            self.flush();
+        }
+    }
+
+    /// Send the collected metrics even if there are less of them than the batch size
+    fn flush(&mut self) {
+        if self.batch.len() == 0 {
+            return;
+        }
+        let batch = mem::replace(&mut self.batch, Vec::new());
+        self.send(Task::AddMetrics(batch));
+    }
+
+    /// Send the task to the next worker, snapshots are sent this way as is, because they are already big
+    fn send(&mut self, task: Task) {
+        self.chans.refresh();
+        let worker = self.next % self.chans.len();
+        self.next = (worker + 1) % self.chans.len();
+        let log = self.log.clone();
+        spawn(send_task(self.chans[worker].clone(), worker, task).map_err(move |_| {
+            PEER_TCP.drops.add(1);
+            warn!(log, "error sending metrics to worker");
+        }));
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct NativeProtocolServer {
     log: Logger,
@@ -75,51 +163,49 @@ impl IntoFuture for NativeProtocolServer {
 
     fn into_future(self) -> Self::Future {
         let Self { log, listen, chans } = self;
+        let tls = RUNTIME_CONFIG.read().unwrap().network.peer_tls;
+        // connections accepted after workers are changed take the channels again
+        let chans = WorkerSet::for_ingestion(chans);
         let serv_log = log.clone();
 
-        let listener = match reusing_listener(&listen) {
+        let listener = match tcp_listener(PEER_SOCKET, &listen) {
             Ok(l) => l,
             Err(e) => {
+                PEER_LISTENING.store(false, Ordering::Relaxed);
                 return Box::new(err(PeerError::Io(e)));
             }
         };
+        PEER_LISTENING.store(true, Ordering::Relaxed);
 
         let future = listener
             .incoming()
             .map_err(|e| PeerError::Io(e))
             .for_each(move |conn| {
-                let peer_addr = conn.peer_addr().map(|addr| addr.to_string()).unwrap_or("[UNCONNECTED]".into());
-                let transport = ReadStream::new(conn, CAPNP_READER_OPTIONS);
-
+                let remote = conn.peer_addr().ok();
+                // connections from sources not allowed are dropped right away, closing them
+                if !remote.map(|addr| PEER_SOURCES.allows(addr.ip())).unwrap_or(false) {
+                    PEER_TCP.drops.add(1);
+                    return Ok(());
+                }
+                let peer_addr = remote.map(|addr| addr.to_string()).unwrap_or("[UNCONNECTED]".into());
                 let log = log.new(o!("remote"=>peer_addr));
-                let elog = log.clone();
-
+                if !tls {
+                    spawn(receive_metrics(conn, remote, &chans, log));
+                    return Ok(());
+                }
+                // settings are taken for every connection, so reloaded certificates are used right away
+                let acceptor = TlsAcceptor::from(server_tls().expect("TLS cannot be disabled without restart"));
                 let chans = chans.clone();
-                let mut chans = chans.into_iter().cycle();
-
-                let receiver = transport
-                    .then(move |reader| {
-                        // decode incoming capnp data into message
-                        // FIXME unwraps
-                        let reader = reader.map_err(PeerError::Capnp)?;
-                        let reader = reader.get_root::<cmsg::Reader>().map_err(PeerError::Capnp)?;
-                        let next_chan = chans.next().unwrap();
-                        parse_and_send(reader, next_chan, log.clone()).map_err(|e| {
-                            warn!(log, "bad incoming message"; "error" => e.to_string());
-                            PeerError::Metric(e)
-                        })
-                    })
-                .map_err(move |e| {
-                    warn!(elog, "snapshot server client error"; "error"=>format!("{:?}", e));
-                })
-                .for_each(|_| {
-                    // Consume all messages from the stream
-                    Ok(())
+                let hs_log = log.clone();
+                let handshake = acceptor.accept(conn).map_err(move |e| {
+                    PEER_TCP.drops.add(1);
+                    warn!(hs_log, "peer TLS handshake failed"; "error"=>e.to_string());
                 });
-                spawn(receiver);
+                spawn(handshake.and_then(move |conn| receive_metrics(conn, remote, &chans, log)));
                 Ok(())
             })
         .map_err(move |e| {
+            PEER_LISTENING.store(false, Ordering::Relaxed);
             log_error!(serv_log, "snapshot server gone with error"; "error"=>format!("{:?}", e));
             e
         });
@@ -127,54 +213,187 @@ impl IntoFuture for NativeProtocolServer {
     }
 }
 
-fn parse_and_send(reader: cmsg::Reader, next_chan: Sender<Task>, log: Logger) -> Result<(), MetricError> {
+// read messages from the peer connection until it is closed, sending metrics to workers
+fn receive_metrics<S: AsyncRead + 'static>(conn: S, remote: Option<SocketAddr>, chans: &WorkerSet, log: Logger) -> impl Future<Item = (), Error = ()> {
+    // peers stuck in the middle of a snapshot would hold the read buffer forever
+    let transport = ReadStream::new(StallWatch::new(conn, "peer-server", StallOptions::current(), log.clone()), CAPNP_READER_OPTIONS);
+    let elog = log.clone();
+
+    let (batch_size, batch_flush_time) = {
+        let config = RUNTIME_CONFIG.read().unwrap();
+        (config.network.batch_size, config.network.batch_flush_time)
+    };
+    let batcher = Rc::new(RefCell::new(MetricBatcher::new(chans.clone(), batch_size, log.clone())));
+    if batch_flush_time > 0 {
+        // the timer stops after the connection is closed and the batcher is gone
+        let weak = Rc::downgrade(&batcher);
+        let dur = Duration::from_millis(batch_flush_time);
+        spawn(Interval::new(Instant::now() + dur, dur).map_err(|_| ()).for_each(move |_| match weak.upgrade() {
+            Some(batcher) => {
+                batcher.borrow_mut().flush();
+                Ok(())
+            }
+            None => Err(()),
+        }));
+    }
+    let last_batch = batcher.clone();
+
+    transport
+        .then(move |reader| {
+            // decode incoming capnp data into message
+            // FIXME unwraps
+            PEER_TCP.packets.add(1);
+            let reader = reader.map_err(|e| {
+                PEER_TCP.parse_errors.add(1);
+                PeerError::Capnp(e)
+            })?;
+            let reader = reader.get_root::<cmsg::Reader>().map_err(PeerError::Capnp)?;
+            parse_and_send(reader, &mut batcher.borrow_mut(), remote).map_err(|e| {
+                PEER_TCP.parse_errors.add(1);
+                warn!(log, "bad incoming message"; "error" => e.to_string());
+                PeerError::Metric(e)
+            })
+        })
+    .map_err(move |e| {
+        warn!(elog, "snapshot server client error"; "error"=>format!("{:?}", e));
+    })
+    .for_each(|_| {
+        // Consume all messages from the stream
+        Ok(())
+    })
+    .then(move |result| {
+        last_batch.borrow_mut().flush();
+        result
+    })
+}
+
+fn parse_and_send(reader: cmsg::Reader, batcher: &mut MetricBatcher, remote: Option<SocketAddr>) -> Result<(), MetricError> {
     match reader.which().map_err(MetricError::CapnpSchema)? {
+        // snapshots are replicated data, not a new metrics, so only agent messages are dropped on pause
+        cmsg::Single(_) | cmsg::Multi(_) if INGESTION_PAUSED.load(Ordering::Relaxed) => {
+            PAUSED_DROPS.add(1);
+            PEER_TCP.drops.add(1);
+            Ok(())
+        }
+        cmsg::Single(_) | cmsg::Multi(_) if shedding() => {
+            SHED_DROPS.add(1);
+            PEER_TCP.drops.add(1);
+            Ok(())
+        }
         cmsg::Single(reader) => {
             let reader = reader.map_err(MetricError::Capnp)?;
             let (name, metric) = Metric::<Float>::from_capnp(reader)?;
-            let future = next_chan
-                .send(Task::AddMetric(name, metric))
-                .map(|_| ()) // drop next sender
-                .map_err(|_| PeerError::TaskSend);
-            let elog = log.clone();
-            spawn(future.map_err(move |e| {
-                warn!(elog, "error joining snapshot: {:?}", e);
-            }));
+            PEER_TCP.metrics.add(1);
+            batcher.push(normalize(name), metric);
             Ok(())
         }
         cmsg::Multi(reader) => {
             let reader = reader.map_err(MetricError::Capnp)?;
-            let mut metrics = Vec::new();
-            reader.iter().map(|reader| Metric::<Float>::from_capnp(reader).map(|(name, metric)| metrics.push((name, metric)))).last();
-            let future = next_chan
-                .send(Task::AddMetrics(metrics))
-                .map(|_| ()) // drop next sender
-                .map_err(|_| PeerError::TaskSend);
-            let elog = log.clone();
-            spawn(future.map_err(move |e| {
-                warn!(elog, "error joining snapshot: {:?}", e);
-            }));
+            let mut count = 0;
+            reader
+                .iter()
+                .map(|reader| {
+                    Metric::<Float>::from_capnp(reader).map(|(name, metric)| {
+                        count += 1;
+                        batcher.push(normalize(name), metric)
+                    })
+                })
+                .last();
+            PEER_TCP.metrics.add(count);
             Ok(())
         }
         cmsg::Snapshot(reader) => {
             let reader = reader.map_err(MetricError::Capnp)?;
+            if let Some(remote) = remote {
+                snapshot_received(&remote);
+            }
             let mut metrics = Vec::new();
-            reader.iter().map(|reader| Metric::<Float>::from_capnp(reader).map(|(name, metric)| metrics.push((name, metric)))).last();
-            let future = next_chan
-                .send(Task::AddSnapshot(metrics))
-                .map(|_| ()) // drop next sender
-                .map_err(|_| PeerError::TaskSend);
-            let elog = log.clone();
-            spawn(future.map_err(move |e| {
-                warn!(elog, "error joining snapshot: {:?}", e);
-            }));
+            // nodes of older versions send tagged names as they were received
+            reader.iter().map(|reader| Metric::<Float>::from_capnp(reader).map(|(name, metric)| metrics.push((normalize(name), metric)))).last();
+            PEER_TCP.metrics.add(metrics.len());
+            batcher.send(Task::AddSnapshot(metrics));
             Ok(())
         }
     }
 }
 
+/// Serialize snapshot message with the framing capnp stream transport uses, so it can be written
+/// to peer connection as is
+pub fn serialize_snapshot(metrics: &[Cache]) -> Result<Bytes, io::Error> {
+    SNAPSHOT_SPACE.with(|space| {
+        let mut space = space.borrow_mut();
+        let (buf, words) = {
+            let mut message = Builder::new(ScratchSpaceHeapAllocator::new(&mut space[..]));
+            fill_snapshot(&mut message, metrics);
+            let (words, segments) = {
+                let segments = message.get_segments_for_output();
+                (segments.iter().map(|segment| segment.len()).sum::<usize>(), segments.len())
+            };
+            // segment table takes a word per two segments, rounded up
+            let mut buf = Vec::with_capacity((words + segments / 2 + 1) * 8);
+            capnp::serialize::write_message(&mut buf, &message)?;
+            (buf, words)
+        };
+        // space only grows to the biggest snapshot seen, so snapshots changing in size do not reallocate it
+        let limit = SNAPSHOT_SCRATCH.get() / 8;
+        if words > space.len() && words <= limit {
+            *space = Word::allocate_zeroed_vec(words);
+        } else if space.len() > limit {
+            *space = Vec::new();
+        }
+        Ok(Bytes::from(buf))
+    })
+}
+
+/// Decode one message framed the same way as on peer connections and return all metrics from it
+/// regardless of message kind. No global state is touched, so it is safe to feed it with any data.
+pub fn decode_message(buf: &[u8]) -> Result<Vec<(Bytes, Metric<Float>)>, PeerError> {
+    let message = capnp::serialize::read_message(&mut &buf[..], CAPNP_READER_OPTIONS).map_err(PeerError::Capnp)?;
+    let reader = message.get_root::<cmsg::Reader>().map_err(PeerError::Capnp)?;
+    match reader.which().map_err(PeerError::CapnpSchema)? {
+        cmsg::Single(reader) => {
+            let reader = reader.map_err(PeerError::Capnp)?;
+            Ok(vec![Metric::<Float>::from_capnp(reader).map_err(PeerError::Metric)?])
+        }
+        cmsg::Multi(reader) | cmsg::Snapshot(reader) => {
+            let reader = reader.map_err(PeerError::Capnp)?;
+            reader.iter().map(|reader| Metric::<Float>::from_capnp(reader).map_err(PeerError::Metric)).collect()
+        }
+    }
+}
+
+/// Build a snapshot message out of caches, the same message is used to send caches to peers
+pub fn snapshot_message(metrics: &[Cache]) -> Builder<HeapAllocator> {
+    let mut snapshot_message = Builder::new_default();
+    fill_snapshot(&mut snapshot_message, metrics);
+    snapshot_message
+}
+
+fn fill_snapshot<A: Allocator>(snapshot_message: &mut Builder<A>, metrics: &[Cache]) {
+    {
+        let builder = snapshot_message.init_root::<CBuilder>();
+        let flat_len = metrics.iter().flat_map(|hmap| hmap.iter()).count();
+        let mut multi_metric = builder.init_snapshot(flat_len as u32);
+        let names = NAMES.read().unwrap();
+        metrics
+            .iter()
+            .flat_map(|hmap| hmap.into_iter())
+            .enumerate()
+            .map(|(idx, (id, metric))| {
+                let mut c_metric = multi_metric.reborrow().get(idx as u32);
+                let name = unsafe { ::std::str::from_utf8_unchecked(names.name(*id)) };
+                c_metric.set_name(name);
+                metric.fill_capnp(&mut c_metric);
+            })
+        .last();
+    }
+}
+
+#[derive(Clone)]
 pub struct NativeProtocolSnapshot {
-    nodes: Vec<SocketAddr>,
+    node_names: Vec<String>,
+    // resolved addresses with the names they were resolved from
+    nodes: Vec<(String, SocketAddr)>,
     client_bind: Option<SocketAddr>,
     interval: Duration,
     chans: Vec<Sender<Task>>,
@@ -183,8 +402,8 @@ pub struct NativeProtocolSnapshot {
 
 impl NativeProtocolSnapshot {
     pub fn new(log: &Logger, nodes: Vec<String>, client_bind: Option<SocketAddr>, interval: Duration, chans: &Vec<Sender<Task>>) -> Self {
-        let nodes = nodes.into_iter().map(|node| try_resolve(&node)).collect::<Vec<_>>();
-        Self { log: log.new(o!("source"=>"peer-client")), nodes, client_bind, interval, chans: chans.clone() }
+        let resolved = nodes.iter().map(|node| (node.clone(), try_resolve(&node))).collect::<Vec<_>>();
+        Self { log: log.new(o!("source"=>"peer-client")), node_names: nodes, nodes: resolved, client_bind, interval, chans: chans.clone() }
     }
 }
 
@@ -194,12 +413,44 @@ impl IntoFuture for NativeProtocolSnapshot {
     type Future = Box<Future<Item = Self::Item, Error = Self::Error>>;
 
     fn into_future(self) -> Self::Future {
-        let Self { log, nodes, client_bind, interval, chans } = self;
+        let Self { log, mut node_names, mut nodes, client_bind, interval, chans } = self;
+        // snapshots must be taken from workers added at runtime and from removed ones until they are drained
+        let mut chans = WorkerSet::for_caches(chans);
 
         let timer = Interval::new(Instant::now() + interval, interval);
         let future = timer.map_err(|e| PeerError::Timer(e)).for_each(move |_| {
-            let chans = chans.clone();
+            let tls = {
+                // node list can be changed by config reload
+                let config = RUNTIME_CONFIG.read().unwrap();
+                if config.network.nodes != node_names {
+                    node_names = config.network.nodes.clone();
+                    nodes = node_names
+                        .iter()
+                        .filter_map(|node| {
+                            resolve_addr(node)
+                                .map(|address| (node.clone(), address))
+                                .map_err(|e| {
+                                    warn!(log, "skipping peer node"; "error"=>e.to_string());
+                                })
+                                .ok()
+                        })
+                        .collect();
+                    info!(log, "peer node list changed"; "nodes"=>format!("{:?}", nodes));
+                }
+                if config.network.peer_tls {
+                    Some(config.tls.clone())
+                } else {
+                    None
+                }
+            };
+            chans.refresh();
+            let chans = chans.to_vec();
             let nodes = nodes.clone();
+            let span = Span::root("snapshot");
+            let context = span.as_ref().map(Span::context);
+            let take = Span::child_of(context, "snapshot-take");
+            // ids in snapshot are turned to names after the workers may have rotated them out
+            let names_pin = pin();
 
             let metrics = chans
                 .into_iter()
@@ -212,28 +463,81 @@ impl IntoFuture for NativeProtocolSnapshot {
 
             let get_metrics = join_all(metrics)
                 .map_err(|_| {
-                    PEER_ERRORS.fetch_add(1, Ordering::Relaxed);
+                    PEER_ERRORS.add(1);
                     PeerError::TaskSend
                 })
-            .and_then(move |mut metrics| {
-                metrics.retain(|m| m.len() > 0);
-                Ok(Arc::new(metrics))
+            .and_then(move |metrics| {
+                // every worker answers with non-empty shards of it's cache
+                let metrics = metrics.into_iter().flat_map(|shards| shards.into_iter()).collect::<Vec<_>>();
+                PEER_SNAPSHOT_BYTES.store(metrics.iter().map(cache_size).sum(), Ordering::Relaxed);
+                if let Some(mut take) = take {
+                    take.attr("shards", metrics.len());
+                }
+                Ok(metrics)
             });
 
-            // All nodes have to receive the same metrics
-            // so we don't parallel connections and metrics fetching
+            // All nodes have to receive the same metrics, so the snapshot is serialized once
+            // out of the event loop and the same buffer is sent to every node
             let log = log.clone();
-            get_metrics.and_then(move |metrics| {
+            let serialize_log = log.clone();
+            get_metrics
+                .and_then(move |metrics| {
+                    if nodes.len() == 0 {
+                        return Either::A(ok::<_, PeerError>((nodes, Bytes::new())));
+                    }
+                    let (tx, rx) = oneshot::channel();
+                    SNAPSHOT_POOL.spawn(move || {
+                        let start = Instant::now();
+                        let mut serialize = Span::child_of(context, "snapshot-serialize");
+                        let snapshot = serialize_snapshot(&metrics);
+                        drop(names_pin);
+                        if let (Some(serialize), Ok(snapshot)) = (serialize.as_mut(), snapshot.as_ref()) {
+                            serialize.attr("bytes", snapshot.len());
+                        }
+                        drop(serialize);
+                        debug!(serialize_log, "snapshot serialized"; "metrics"=>metrics.iter().map(|cache| cache.len()).sum::<usize>(), "elapsed"=>format!("{:?}", start.elapsed()));
+                        // the receiver is only gone when the event loop is stopped
+                        tx.send(snapshot).unwrap_or(());
+                    });
+                    Either::B(rx.map_err(|_| PeerError::TaskSend).and_then(|snapshot| snapshot.map_err(PeerError::Io)).map(move |snapshot| (nodes, snapshot)))
+                })
+            .and_then(move |(nodes, snapshot)| {
+                if let Some(mut span) = span {
+                    span.attr("nodes", nodes.len());
+                    span.attr("bytes", snapshot.len());
+                }
                 nodes
                     .into_iter()
-                    .map(move |address| {
-                        let metrics = metrics.clone();
+                    .map(move |(node, address)| {
+                        let snapshot = snapshot.clone();
                         let log = log.clone();
                         let peer_client_ret = BackoffRetryBuilder { delay: 500, delay_mul: 2f32, delay_max: 5000, retries: 3 };
-                        let options = SnapshotClientOptions { address: address, bind: client_bind };
-                        let client = SnapshotSender::new(metrics, options, log.clone());
-                        spawn(peer_client_ret.spawn(client).map_err(move |e| {
-                            warn!(log, "snapshot client removed after giving up trying"; "error"=>format!("{:?}", e));
+                        // names are checked on reload, so a bad one can only come from a bad config at start
+                        let tls = match tls {
+                            Some(ref tls) => match tls_name(tls, &node) {
+                                Ok(name) => Some(name),
+                                Err(e) => {
+                                    warn!(log, "snapshot is not sent to peer"; "peer"=>node, "error"=>e.to_string());
+                                    return;
+                                }
+                            },
+                            None => None,
+                        };
+                        let options = SnapshotClientOptions { address: address, bind: client_bind, tls };
+                        let client = SnapshotSender::new(snapshot, options, log.clone());
+                        let mut send = Span::child_of(context, "snapshot-send");
+                        if let Some(ref mut send) = send {
+                            send.attr("peer", address);
+                        }
+                        spawn(peer_client_ret.spawn(client).then(move |result| {
+                            // retries are a part of sending
+                            if let (Some(send), Err(_)) = (send.as_mut(), result.as_ref()) {
+                                send.attr("error", "gave up");
+                            }
+                            drop(send);
+                            result.map(|_| ()).map_err(move |e| {
+                                warn!(log, "snapshot client removed after giving up trying"; "error"=>format!("{:?}", e));
+                            })
                         }));
                     })
                 .last();
@@ -248,18 +552,21 @@ impl IntoFuture for NativeProtocolSnapshot {
 pub struct SnapshotClientOptions {
     address: SocketAddr,
     bind: Option<SocketAddr>,
+    // name to verify the peer certificate for when snapshots are sent over TLS
+    tls: Option<DNSName>,
 }
 
+/// Sends serialized snapshot to a single peer
 #[derive(Clone)]
 pub struct SnapshotSender {
-    metrics: Arc<Vec<Cache>>,
+    snapshot: Bytes,
     options: SnapshotClientOptions,
     log: Logger,
 }
 
 impl SnapshotSender {
-    pub fn new(metrics: Arc<Vec<Cache>>, options: SnapshotClientOptions, log: Logger) -> Self {
-        Self { metrics, options, log }
+    pub fn new(snapshot: Bytes, options: SnapshotClientOptions, log: Logger) -> Self {
+        Self { snapshot, options, log }
     }
 }
 
@@ -269,8 +576,9 @@ impl IntoFuture for SnapshotSender {
     type Future = Box<Future<Item = Self::Item, Error = Self::Error>>;
 
     fn into_future(self) -> Self::Future {
-        let Self { metrics, log, options } = self;
+        let Self { snapshot, log, options } = self;
         let elog = log.clone();
+        let address = options.address;
         let stream_future = match options.bind {
             Some(bind_addr) => match bound_stream(&bind_addr) {
                 Ok(std_stream) => Either::A(TcpStream::connect_std(std_stream, &options.address, &tokio::reactor::Handle::default())),
@@ -279,35 +587,16 @@ impl IntoFuture for SnapshotSender {
             None => Either::A(TcpStream::connect(&options.address)),
         };
 
+        let tls = options.tls;
         let sender = stream_future
             .map_err(|e| PeerError::Io(e))
-            .and_then(move |conn| {
-                let codec = ::capnp_futures::serialize::Transport::new(conn, CAPNP_READER_OPTIONS);
-
-                let mut snapshot_message = Builder::new_default();
-                {
-                    let builder = snapshot_message.init_root::<CBuilder>();
-                    let flat_len = metrics.iter().flat_map(|hmap| hmap.iter()).count();
-                    let mut multi_metric = builder.init_snapshot(flat_len as u32);
-                    metrics
-                        .iter()
-                        .flat_map(|hmap| hmap.into_iter())
-                        .enumerate()
-                        .map(|(idx, (name, metric))| {
-                            let mut c_metric = multi_metric.reborrow().get(idx as u32);
-                            let name = unsafe { ::std::str::from_utf8_unchecked(&name) };
-                            c_metric.set_name(name);
-                            metric.fill_capnp(&mut c_metric);
-                        })
-                    .last();
-                }
-                codec.send(snapshot_message).map(|_| ()).map_err(move |e| {
-                    debug!(log, "codec error"; "error"=>e.to_string());
-                    PeerError::Capnp(e)
-                })
+            .and_then(move |conn| match tls {
+                Some(name) => Either::A(connect_tls(conn, &name).map_err(PeerError::Io).and_then(move |conn| write_snapshot(conn, snapshot, address, log))),
+                None => Either::B(write_snapshot(conn, snapshot, address, log)),
             })
         .map_err(move |e| {
-            PEER_ERRORS.fetch_add(1, Ordering::Relaxed);
+            PEER_ERRORS.add(1);
+            snapshot_send_failed(&address);
             debug!(elog, "error sending snapshot: {}", e);
             e
         });
@@ -315,6 +604,38 @@ impl IntoFuture for SnapshotSender {
     }
 }
 
+fn write_snapshot<S: AsyncWrite>(conn: S, snapshot: Bytes, address: SocketAddr, log: Logger) -> impl Future<Item = (), Error = PeerError> {
+    let conn = StallWatch::new(conn, "peer-client", StallOptions::current(), log.new(o!("remote"=>address.to_string())));
+    write_all(conn, snapshot).map(move |_| snapshot_sent(&address)).map_err(move |e| {
+        debug!(log, "snapshot write error"; "error"=>e.to_string());
+        PeerError::Io(e)
+    })
+}
+
+/// Send a serialized snapshot once to every peer node from runtime config, for metrics taken out of workers
+/// outside of snapshot intervals. Resolves to the number of nodes that got it.
+pub fn send_to_peers(snapshot: Bytes, log: &Logger) -> impl Future<Item = usize, Error = ()> {
+    let (nodes, bind, tls) = {
+        let config = RUNTIME_CONFIG.read().unwrap();
+        let tls = if config.network.peer_tls { Some(config.tls.clone()) } else { None };
+        (config.network.nodes.clone(), config.network.peer_client_bind, tls)
+    };
+    let sends = nodes
+        .into_iter()
+        .filter_map(|node| {
+            let address = resolve_addr(&node).map_err(|e| warn!(log, "skipping peer node"; "error"=>e.to_string())).ok()?;
+            let tls = match tls {
+                Some(ref tls) => Some(tls_name(tls, &node).map_err(|e| warn!(log, "snapshot is not sent to peer"; "peer"=>node.as_str(), "error"=>e.to_string())).ok()?),
+                None => None,
+            };
+            let options = SnapshotClientOptions { address, bind, tls };
+            let peer_client_ret = BackoffRetryBuilder { delay: 500, delay_mul: 2f32, delay_max: 5000, retries: 3 };
+            Some(peer_client_ret.spawn(SnapshotSender::new(snapshot.clone(), options, log.clone())).then(|result| Ok::<_, ()>(result.is_ok())))
+        })
+        .collect::<Vec<_>>();
+    join_all(sends).map(|sent| sent.into_iter().filter(|sent| *sent).count())
+}
+
 #[cfg(test)]
 mod test {
 
@@ -356,6 +677,72 @@ mod test {
         (runtime, rx, address)
     }
 
+    #[test]
+    fn serialized_snapshot() {
+        let mut cache = Cache::new();
+        let metric = Metric::new(1f64, MetricType::Counter, None, None).unwrap();
+        cache.insert(crate::intern::intern(b"serialized.snapshot.metric"), metric.clone());
+        let buf = serialize_snapshot(&[cache.clone()]).unwrap();
+
+        assert_eq!(decode_message(&buf).unwrap(), vec![(Bytes::from("serialized.snapshot.metric"), metric.clone())]);
+        assert!(decode_message(&buf[..buf.len() - 1]).is_err());
+
+        // the next snapshots are built in the space left from the first one, smaller ones must not see it's data
+        let big = (0..1000).map(|i| (crate::intern::intern(format!("serialized.snapshot.big.{}", i).as_bytes()), metric.clone())).collect::<Cache>();
+        assert_eq!(decode_message(&serialize_snapshot(&[big]).unwrap()).unwrap().len(), 1000);
+        SNAPSHOT_SPACE.with(|space| assert!(space.borrow().len() > 0));
+        assert_eq!(decode_message(&serialize_snapshot(&[cache]).unwrap()).unwrap(), vec![(Bytes::from("serialized.snapshot.metric"), metric)]);
+    }
+
+    #[test]
+    fn snapshot_scratch_reused() {
+        let metric = Metric::new(1f64, MetricType::Timer(Vec::new()), None, None).unwrap();
+        let cache = |count: usize| (0..count).map(|i| (crate::intern::intern(format!("snapshot.scratch.{}", i).as_bytes()), metric.clone())).collect::<Cache>();
+        // snapshots built in scratch space may be split into segments differently, but carry the same metrics
+        let same_as_plain = |caches: &[Cache]| {
+            let mut plain = Vec::new();
+            capnp::serialize::write_message(&mut plain, &snapshot_message(caches)).unwrap();
+            decode_message(&serialize_snapshot(caches).unwrap()).unwrap() == decode_message(&plain).unwrap()
+        };
+        let (small, big) = (cache(10), cache(2000));
+        assert!(same_as_plain(&[small.clone()]));
+        let grown = SNAPSHOT_SPACE.with(|space| space.borrow().len());
+        assert!(grown > 0);
+        assert!(same_as_plain(&[big, small.clone()]));
+        let biggest = SNAPSHOT_SPACE.with(|space| space.borrow().len());
+        assert!(biggest > grown);
+
+        // space is kept for the next big snapshot when a smaller one is taken
+        assert!(same_as_plain(&[small]));
+        assert_eq!(SNAPSHOT_SPACE.with(|space| space.borrow().len()), biggest);
+    }
+
+    #[test]
+    fn metric_batches() {
+        let (tx, rx) = mpsc::channel(10);
+        let mut runtime = Runtime::new().expect("creating runtime for test");
+        let metric = Metric::new(1f64, MetricType::Counter, None, None).unwrap();
+        runtime
+            .block_on(futures::future::lazy(move || {
+                let mut batcher = MetricBatcher::new(WorkerSet::for_ingestion(vec![tx]), 2, prepare_log("test_metric_batches"));
+                for _ in 0..5 {
+                    batcher.push("batched.metric".into(), metric.clone());
+                }
+                batcher.flush();
+                Ok::<(), ()>(())
+            }))
+            .unwrap();
+
+        // the stream ends when batches are sent and the batcher is gone
+        let sizes = runtime
+            .block_on(rx.map(|task| match task {
+                Task::AddMetrics(metrics) => metrics.len(),
+                _ => 0,
+            }).collect())
+            .unwrap();
+        assert_eq!(sizes, vec![2, 2, 1]);
+    }
+
     #[test]
     fn test_peer_protocol_capnp() {
         let test_timeout = Instant::now() + Duration::from_secs(3);
@@ -379,7 +766,7 @@ mod test {
                 runner.run(task);
                 Ok(runner)
             })
-        .and_then(move |runner| {
+        .and_then(move |mut runner| {
             let single_name: Bytes = "complex.test.bioyino_single".into();
             let multi_name: Bytes = "complex.test.bioyino_multi".into();
             let shot_name: Bytes = "complex.test.bioyino_snapshot".into();
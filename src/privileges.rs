@@ -0,0 +1,146 @@
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::ptr;
+
+use slog::{info, warn, Logger};
+
+use crate::config::Privileges;
+use crate::errors::GeneralError;
+
+// linux/capability.h
+const CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+/// Capabilities that can be kept after changing the user, by name used in `privileges.keep-capabilities`
+pub const CAPABILITIES: &[(&str, u32)] = &[("net-bind-service", 10), ("net-admin", 12), ("net-raw", 13), ("ipc-lock", 14), ("sys-nice", 23), ("sys-resource", 24)];
+
+#[repr(C)]
+struct CapHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// Bit mask of capabilities by their names
+pub fn capability_mask(names: &[String]) -> Result<u64, GeneralError> {
+    names.iter().try_fold(0u64, |mask, name| match CAPABILITIES.iter().find(|(known, _)| known == name) {
+        Some((_, bit)) => Ok(mask | 1u64 << bit),
+        None => Err(GeneralError::Privileges(format!("unknown capability {}, known ones are {}", name, CAPABILITIES.iter().map(|(known, _)| *known).collect::<Vec<_>>().join(", ")))),
+    })
+}
+
+fn os_error(what: &str) -> GeneralError {
+    GeneralError::Privileges(format!("{}: {}", what, io::Error::last_os_error()))
+}
+
+/// User id and primary group of the user given by name or number
+pub fn resolve_user(user: &str) -> Result<(libc::uid_t, libc::gid_t), GeneralError> {
+    let name = CString::new(user).map_err(|_| GeneralError::Privileges(format!("bad user name {:?}", user)))?;
+    let mut pwd: libc::passwd = unsafe { mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let mut found = ptr::null_mut();
+    unsafe { libc::getpwnam_r(name.as_ptr(), &mut pwd, buf.as_mut_ptr(), buf.len(), &mut found) };
+    if !found.is_null() {
+        return Ok((pwd.pw_uid, pwd.pw_gid));
+    }
+    // numeric users may have no passwd entry, they get the group of the same number
+    user.parse::<libc::uid_t>().map(|uid| (uid, uid)).map_err(|_| GeneralError::Privileges(format!("unknown user {}", user)))
+}
+
+/// Group id of the group given by name or number
+pub fn resolve_group(group: &str) -> Result<libc::gid_t, GeneralError> {
+    let name = CString::new(group).map_err(|_| GeneralError::Privileges(format!("bad group name {:?}", group)))?;
+    let mut grp: libc::group = unsafe { mem::zeroed() };
+    let mut buf = vec![0 as libc::c_char; 16384];
+    let mut found = ptr::null_mut();
+    unsafe { libc::getgrnam_r(name.as_ptr(), &mut grp, buf.as_mut_ptr(), buf.len(), &mut found) };
+    if !found.is_null() {
+        return Ok(grp.gr_gid);
+    }
+    group.parse::<libc::gid_t>().map_err(|_| GeneralError::Privileges(format!("unknown group {}", group)))
+}
+
+fn set_capabilities(mask: u64) -> Result<(), GeneralError> {
+    let mut header = CapHeader { version: CAPABILITY_VERSION_3, pid: 0 };
+    let low = mask as u32;
+    let high = (mask >> 32) as u32;
+    let data = [CapData { effective: low, permitted: low, inheritable: 0 }, CapData { effective: high, permitted: high, inheritable: 0 }];
+    if unsafe { libc::syscall(libc::SYS_capset, &mut header as *mut CapHeader, data.as_ptr()) } != 0 {
+        return Err(os_error("setting capabilities"));
+    }
+    Ok(())
+}
+
+/// Switch to `privileges.user` and `privileges.group` when started as root, keeping only capabilities
+/// from `privileges.keep-capabilities`. Must be called from the main thread: user is changed for all threads,
+/// but capabilities are kept only by the calling one and threads started by it afterwards.
+pub fn drop_privileges(options: &Privileges, log: &Logger) -> Result<(), GeneralError> {
+    if options.user.is_none() && options.group.is_none() {
+        return Ok(());
+    }
+    if unsafe { libc::geteuid() } != 0 {
+        warn!(log, "not started as root, privileges are left as is"; "uid"=>unsafe { libc::geteuid() });
+        return Ok(());
+    }
+    let keep = capability_mask(&options.keep_capabilities)?;
+    let (uid, user_gid) = match options.user {
+        Some(ref user) => resolve_user(user)?,
+        None => (0, 0),
+    };
+    let gid = match options.group {
+        Some(ref group) => resolve_group(group)?,
+        None => user_gid,
+    };
+
+    // capabilities are dropped on changing user from root unless they are asked to be kept
+    if keep != 0 && unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0) } != 0 {
+        return Err(os_error("keeping capabilities"));
+    }
+    // supplementary groups of root would give access to it's files
+    if unsafe { libc::setgroups(1, &gid) } != 0 {
+        return Err(os_error("dropping supplementary groups"));
+    }
+    if unsafe { libc::setgid(gid) } != 0 {
+        return Err(os_error("changing group"));
+    }
+    if options.user.is_some() {
+        if unsafe { libc::setuid(uid) } != 0 {
+            return Err(os_error("changing user"));
+        }
+        // make sure there is no way back
+        if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+            return Err(GeneralError::Privileges("root privileges could be restored after changing user".to_string()));
+        }
+    }
+    // when the user is left as root, all capabilities beside the kept ones are dropped too
+    if keep != 0 || options.user.is_none() {
+        set_capabilities(keep)?;
+    }
+    if keep != 0 {
+        unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 0, 0, 0, 0) };
+    }
+    info!(log, "privileges dropped"; "uid"=>uid, "gid"=>gid, "capabilities"=>options.keep_capabilities.join(","));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_names() {
+        assert_eq!(resolve_user("root").unwrap(), (0, 0));
+        assert_eq!(resolve_user("65534").unwrap().0, 65534);
+        assert!(resolve_user("no-such-user-here").is_err());
+        assert_eq!(resolve_group("0").unwrap(), 0);
+        assert_eq!(capability_mask(&["net-bind-service".to_string(), "net-admin".to_string()]).unwrap(), 1 << 10 | 1 << 12);
+        assert!(capability_mask(&["cap_everything".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,102 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use failure_derive::Fail;
+use futures::future::{err, Either};
+use futures::sync::oneshot;
+use futures::Future;
+
+// the profiler is process-wide, so only one profile can be taken at a time
+static PROFILING: AtomicBool = AtomicBool::new(false);
+
+/// What to produce from collected CPU samples
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProfileFormat {
+    /// SVG flamegraph to look at in browser
+    Flamegraph,
+    /// Protobuf profile for `go tool pprof` and compatible tools
+    Pprof,
+}
+
+impl ProfileFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ProfileFormat::Flamegraph => "image/svg+xml",
+            ProfileFormat::Pprof => "application/octet-stream",
+        }
+    }
+}
+
+#[derive(Fail, Debug)]
+pub enum ProfileError {
+    #[fail(display = "server is built without pprof feature")]
+    NotSupported,
+
+    #[fail(display = "another profile is being taken")]
+    Busy,
+
+    #[fail(display = "profiler failed: {}", _0)]
+    Profiler(String),
+}
+
+/// Sample CPU of the whole process `frequency` times a second for `duration`. Sampling is done in
+/// a separate thread, so the caller's event loop is not blocked.
+pub fn profile_cpu(duration: Duration, frequency: i32, format: ProfileFormat) -> impl Future<Item = Vec<u8>, Error = ProfileError> {
+    if !cfg!(feature = "pprof") {
+        return Either::A(err(ProfileError::NotSupported));
+    }
+    if PROFILING.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_err() {
+        return Either::A(err(ProfileError::Busy));
+    }
+    let (tx, rx) = oneshot::channel();
+    let spawned = thread::Builder::new().name("bioyino_pprof".into()).spawn(move || {
+        let result = take_profile(duration, frequency, format);
+        PROFILING.store(false, Ordering::SeqCst);
+        tx.send(result).unwrap_or(());
+    });
+    if let Err(e) = spawned {
+        PROFILING.store(false, Ordering::SeqCst);
+        return Either::A(err(ProfileError::Profiler(e.to_string())));
+    }
+    Either::B(rx.map_err(|_| ProfileError::Profiler("profiling thread stopped".to_string())).and_then(|result| result))
+}
+
+#[cfg(feature = "pprof")]
+fn take_profile(duration: Duration, frequency: i32, format: ProfileFormat) -> Result<Vec<u8>, ProfileError> {
+    use pprof::protos::Message;
+
+    let profiler = |e: pprof::Error| ProfileError::Profiler(e.to_string());
+    let guard = pprof::ProfilerGuard::new(frequency).map_err(profiler)?;
+    thread::sleep(duration);
+    let report = guard.report().build().map_err(profiler)?;
+    let mut body = Vec::new();
+    match format {
+        ProfileFormat::Flamegraph => report.flamegraph(&mut body).map_err(profiler)?,
+        ProfileFormat::Pprof => report.pprof().map_err(profiler)?.encode(&mut body).map_err(|e| ProfileError::Profiler(e.to_string()))?,
+    }
+    Ok(body)
+}
+
+#[cfg(not(feature = "pprof"))]
+fn take_profile(_duration: Duration, _frequency: i32, _format: ProfileFormat) -> Result<Vec<u8>, ProfileError> {
+    Err(ProfileError::NotSupported)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cpu_profile() {
+        let result = profile_cpu(Duration::from_millis(200), 99, ProfileFormat::Flamegraph).wait();
+        if cfg!(feature = "pprof") {
+            assert!(result.unwrap().starts_with(b"<?xml"));
+        } else {
+            match result {
+                Err(ProfileError::NotSupported) => (),
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+    }
+}
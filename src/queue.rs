@@ -0,0 +1,181 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use futures::sync::mpsc::Sender;
+use futures::{Future, Sink, Stream};
+use lazy_static::lazy_static;
+use serde_derive::{Deserialize, Serialize};
+use slog::{info, o, warn, Logger};
+use tokio::timer::Interval;
+
+use crate::task::Task;
+use crate::workers::worker_count;
+
+// there cannot be more workers than this, queues of workers above the real number are never used
+pub const MAX_WORKERS: usize = 1024;
+
+lazy_static! {
+    /// Ingestion tasks sent to every worker and not taken by it yet, both from statsd and peer listeners
+    pub static ref WORKER_QUEUES: Vec<QueueGauge> = (0..MAX_WORKERS).map(|_| QueueGauge::new()).collect();
+}
+
+/// Aggregated metrics sent by workers and not taken by backend sender yet
+pub static FLUSH_QUEUE: QueueGauge = QueueGauge::new();
+
+/// Depth of a channel counted by both of its sides. futures channels do not tell their length,
+/// so senders count what they put and receivers count what they take.
+pub struct QueueGauge {
+    depth: AtomicUsize,
+    high: AtomicUsize,
+    // soft limit of the queue, senders that cannot wait drop messages when it is reached
+    capacity: AtomicUsize,
+}
+
+impl QueueGauge {
+    const fn new() -> Self {
+        Self { depth: AtomicUsize::new(0), high: AtomicUsize::new(0), capacity: AtomicUsize::new(0) }
+    }
+
+    pub fn push(&self, count: usize) {
+        let depth = self.depth.fetch_add(count, Ordering::Relaxed) + count;
+        if depth > self.high.load(Ordering::Relaxed) {
+            // races here only make the watermark a bit lower, which is fine for a gauge
+            self.high.store(depth, Ordering::Relaxed);
+        }
+    }
+
+    pub fn pop(&self, count: usize) {
+        self.depth.fetch_sub(count, Ordering::Relaxed);
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::Relaxed)
+    }
+
+    /// Maximum depth since the previous call, the watermark starts from the current depth then
+    pub fn take_high(&self) -> usize {
+        self.high.swap(self.depth(), Ordering::Relaxed)
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity.load(Ordering::Relaxed)
+    }
+
+    pub fn set_capacity(&self, capacity: usize) {
+        self.capacity.store(capacity, Ordering::Relaxed)
+    }
+
+    pub fn values(&self) -> QueueValues {
+        QueueValues { depth: self.depth(), high_watermark: self.high.load(Ordering::Relaxed), capacity: self.capacity() }
+    }
+
+    /// Whether the queue reached it's soft capacity, queues without capacity are never full
+    pub fn is_full(&self) -> bool {
+        let capacity = self.capacity();
+        capacity > 0 && self.depth() >= capacity
+    }
+}
+
+/// Whether the task is counted in worker queue depth, control tasks are few and not counted
+pub fn is_ingestion(task: &Task) -> bool {
+    match task {
+        Task::Parse(..) | Task::AddMetric(..) | Task::AddMetrics(..) | Task::AddSnapshot(..) => true,
+        _ => false,
+    }
+}
+
+/// Put ingestion task to the worker queue without waiting, the task is returned if the queue is full
+pub fn try_send_task(chans: &mut [Sender<Task>], worker: usize, task: Task) -> Result<(), Task> {
+    let queue = &WORKER_QUEUES[worker % MAX_WORKERS];
+    if queue.is_full() {
+        return Err(task);
+    }
+    // counted before sending, so the worker cannot take it before it is counted
+    queue.push(1);
+    chans[worker].try_send(task).map_err(|e| {
+        queue.pop(1);
+        e.into_inner()
+    })
+}
+
+/// Send ingestion task to the worker waiting for the place in queue
+pub fn send_task(chan: Sender<Task>, worker: usize, task: Task) -> impl Future<Item = (), Error = ()> {
+    // counted before sending, so the worker cannot take it before it is counted
+    WORKER_QUEUES[worker % MAX_WORKERS].push(1);
+    chan.send(task).map(|_| ()).map_err(move |_| WORKER_QUEUES[worker % MAX_WORKERS].pop(1))
+}
+
+/// Worker queue depth, high watermark and capacity for every worker
+pub fn worker_queues(workers: usize) -> Vec<QueueValues> {
+    WORKER_QUEUES.iter().take(workers).map(QueueGauge::values).collect()
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct QueueValues {
+    pub depth: usize,
+    /// Maximum depth since start or the previous autotune check
+    pub high_watermark: usize,
+    /// Soft capacity, 0 if queue is not limited
+    pub capacity: usize,
+}
+
+/// New capacity of a queue given it's high watermark during the last period, queues that were almost full
+/// grow twice and queues that were mostly empty shrink twice, capacity stays between `min` and `max`
+pub fn tuned_capacity(capacity: usize, high: usize, min: usize, max: usize) -> usize {
+    let capacity = if high * 10 >= capacity * 9 {
+        capacity * 2
+    } else if high * 4 < capacity {
+        capacity / 2
+    } else {
+        capacity
+    };
+    capacity.max(min).min(max)
+}
+
+/// A future changing worker queue capacities according to their backlog every `interval`. Channels are created
+/// with the maximal capacity, so changing the soft one does not require recreating them. Workers added at runtime are
+/// tuned too. Never gets ready.
+pub fn autotune_queues(min: usize, max: usize, interval: Duration, log: Logger) -> impl Future<Item = (), Error = ()> {
+    let log = log.new(o!("source"=>"queue-autotune"));
+    let err_log = log.clone();
+    Interval::new(Instant::now() + interval, interval)
+        .map_err(move |e| {
+            warn!(err_log, "queue autotune timer failed"; "error"=>e.to_string());
+        })
+        .for_each(move |_| {
+            for (worker, queue) in WORKER_QUEUES.iter().take(worker_count()).enumerate() {
+                let capacity = queue.capacity();
+                let tuned = tuned_capacity(capacity, queue.take_high(), min, max);
+                if tuned != capacity {
+                    info!(log, "worker queue capacity changed"; "worker"=>worker, "old"=>capacity, "new"=>tuned);
+                    queue.set_capacity(tuned);
+                }
+            }
+            Ok(())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queue_gauge() {
+        let queue = QueueGauge::new();
+        queue.push(3);
+        queue.pop(2);
+        assert_eq!(queue.depth(), 1);
+        assert_eq!(queue.take_high(), 3);
+        assert_eq!(queue.take_high(), 1);
+        assert!(!queue.is_full());
+        queue.set_capacity(1);
+        assert!(queue.is_full());
+
+        assert_eq!(tuned_capacity(100, 95, 10, 1000), 200);
+        assert_eq!(tuned_capacity(100, 50, 10, 1000), 100);
+        assert_eq!(tuned_capacity(100, 10, 10, 1000), 50);
+        assert_eq!(tuned_capacity(800, 800, 10, 1000), 1000);
+        assert_eq!(tuned_capacity(10, 0, 10, 1000), 10);
+    }
+}
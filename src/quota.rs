@@ -0,0 +1,243 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::Instant;
+
+use lazy_static::lazy_static;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::cache::current_epoch;
+use crate::config::{OverQuota, Quota};
+use crate::errors::GeneralError;
+use crate::ratelimit::TokenBucket;
+use crate::QUOTA_DROPS;
+
+/// Name segment samples over quota are aggregated under with `over-quota = "overflow"`
+pub const OVERFLOW_SEGMENT: &str = "_overflow";
+
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    // longest prefixes first, so the most specific quota applies
+    static ref QUOTAS: RwLock<Vec<Mutex<QuotaState>>> = RwLock::new(Vec::new());
+}
+
+/// Samples of a quota since it was set
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct QuotaUsage {
+    /// Distinct names in the current interval
+    pub names: usize,
+    pub accepted: u64,
+    pub dropped: u64,
+    /// Samples over quota kept by sampling
+    pub sampled: u64,
+    /// Samples over quota aggregated into overflow metrics
+    pub overflowed: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct QuotaStatus {
+    pub quota: Quota,
+    pub usage: QuotaUsage,
+}
+
+/// What to do with a sample
+#[derive(Debug, Clone, PartialEq)]
+pub enum QuotaVerdict {
+    Pass,
+    Drop,
+    /// Aggregate the sample under another name
+    Rename(Vec<u8>),
+}
+
+#[derive(Debug)]
+struct QuotaState {
+    quota: Quota,
+    bucket: TokenBucket,
+    // hashes of names seen in the current interval
+    names: HashSet<u64>,
+    epoch: usize,
+    // samples over quota, every 1/sample-ratio'th of them is kept
+    over: u64,
+    usage: QuotaUsage,
+}
+
+impl QuotaState {
+    fn new(quota: Quota) -> Self {
+        let bucket = TokenBucket::new(&QUOTA_DROPS);
+        bucket.set(quota.max_rate, 1000);
+        Self { quota, bucket, names: HashSet::new(), epoch: current_epoch(), over: 0, usage: QuotaUsage::default() }
+    }
+
+    fn check(&mut self, name: &[u8], kind: &str, now: Instant) -> QuotaVerdict {
+        let epoch = current_epoch();
+        if epoch != self.epoch {
+            self.epoch = epoch;
+            self.names.clear();
+        }
+        let mut hasher = DefaultHasher::new();
+        name.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        let quota = &self.quota;
+        let new_name = quota.max_names > 0 && !self.names.contains(&hash);
+        let names_over = new_name && self.names.len() >= quota.max_names;
+        // rate is not spent on samples dropped for their names
+        if !names_over && (quota.max_rate == 0 || self.bucket.take(1, now) > 0) {
+            if new_name {
+                self.names.insert(hash);
+            }
+            self.usage.names = self.names.len();
+            self.usage.accepted += 1;
+            return QuotaVerdict::Pass;
+        }
+
+        match quota.over_quota {
+            OverQuota::Sample => {
+                self.over += 1;
+                let every = (1f64 / quota.sample_ratio).round().max(1f64) as u64;
+                if self.over % every == 0 {
+                    self.usage.sampled += 1;
+                    return QuotaVerdict::Pass;
+                }
+            }
+            OverQuota::Overflow => {
+                self.usage.overflowed += 1;
+                return QuotaVerdict::Rename(overflow_name(&quota.prefix, kind));
+            }
+            OverQuota::Drop => (),
+        }
+        self.usage.dropped += 1;
+        QUOTA_DROPS.add(1);
+        QuotaVerdict::Drop
+    }
+}
+
+/// Name samples of the type over quota of the prefix are aggregated under
+pub fn overflow_name(prefix: &str, kind: &str) -> Vec<u8> {
+    let prefix = prefix.trim_end_matches('.');
+    format!("{}.{}.{}", prefix, OVERFLOW_SEGMENT, kind).into_bytes()
+}
+
+// prefix matches whole name segments only
+fn matches(prefix: &str, name: &[u8]) -> bool {
+    let prefix = prefix.as_bytes();
+    if !name.starts_with(prefix) {
+        return false;
+    }
+    prefix.ends_with(b".") || name.len() == prefix.len() || name[prefix.len()] == b'.' || name[prefix.len()] == b';'
+}
+
+/// Check quotas without applying them
+pub fn validate_quotas(quotas: &[Quota]) -> Result<(), GeneralError> {
+    for (idx, quota) in quotas.iter().enumerate() {
+        let bad = |reason: &str| GeneralError::Quota(quota.prefix.clone(), reason.to_string());
+        if quota.prefix.len() == 0 {
+            return Err(bad("prefix cannot be empty"));
+        }
+        if quota.max_names == 0 && quota.max_rate == 0 {
+            return Err(bad("either max-names or max-rate must be set"));
+        }
+        if !(quota.sample_ratio > 0f64 && quota.sample_ratio <= 1f64) {
+            return Err(bad("sample-ratio must be in (0, 1]"));
+        }
+        if quotas[..idx].iter().any(|other| other.prefix == quota.prefix) {
+            return Err(bad("prefix is set more than once"));
+        }
+    }
+    Ok(())
+}
+
+/// Replace all quotas, usage of quotas is counted from zero again
+pub fn set_quotas(quotas: &[Quota]) -> Result<(), GeneralError> {
+    validate_quotas(quotas)?;
+    let mut states = quotas.iter().cloned().map(QuotaState::new).collect::<Vec<_>>();
+    states.sort_by(|a, b| b.quota.prefix.len().cmp(&a.quota.prefix.len()));
+    let mut current = QUOTAS.write().unwrap();
+    *current = states.into_iter().map(Mutex::new).collect();
+    ACTIVE.store(current.len() > 0, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Add a quota or replace the one with the same prefix, usage of other quotas is kept
+pub fn put_quota(quota: Quota) -> Result<(), GeneralError> {
+    validate_quotas(&[quota.clone()])?;
+    let mut current = QUOTAS.write().unwrap();
+    current.retain(|state| state.lock().unwrap().quota.prefix != quota.prefix);
+    let pos = current.iter().position(|state| state.lock().unwrap().quota.prefix.len() < quota.prefix.len()).unwrap_or(current.len());
+    current.insert(pos, Mutex::new(QuotaState::new(quota)));
+    ACTIVE.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Remove the quota of the prefix, returns false if there was none
+pub fn remove_quota(prefix: &str) -> bool {
+    let mut current = QUOTAS.write().unwrap();
+    let len = current.len();
+    current.retain(|state| state.lock().unwrap().quota.prefix != prefix);
+    ACTIVE.store(current.len() > 0, Ordering::Relaxed);
+    current.len() != len
+}
+
+/// Quotas with their usage, sorted by prefix
+pub fn quotas() -> Vec<QuotaStatus> {
+    let mut quotas = QUOTAS.read().unwrap().iter().map(|state| {
+        let state = state.lock().unwrap();
+        QuotaStatus { quota: state.quota.clone(), usage: state.usage }
+    }).collect::<Vec<_>>();
+    quotas.sort_by(|a, b| a.quota.prefix.cmp(&b.quota.prefix));
+    quotas
+}
+
+/// Check the sample of metric type named `kind` against the quota of the longest matching prefix
+pub fn check_quota(name: &[u8], kind: &str) -> QuotaVerdict {
+    if !ACTIVE.load(Ordering::Relaxed) {
+        return QuotaVerdict::Pass;
+    }
+    let quotas = QUOTAS.read().unwrap();
+    for state in quotas.iter() {
+        let mut state = state.lock().unwrap();
+        if matches(&state.quota.prefix, name) {
+            return state.check(name, kind, Instant::now());
+        }
+    }
+    QuotaVerdict::Pass
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quota_verdicts() {
+        assert!(matches("team", b"team.requests"));
+        assert!(matches("team", b"team;env=prod"));
+        assert!(!matches("team", b"teamcity.requests"));
+        assert!(matches("team.", b"team.requests"));
+
+        let mut names = QuotaState::new(Quota { prefix: "team".to_string(), max_names: 2, ..Quota::default() });
+        let now = Instant::now();
+        assert_eq!(names.check(b"team.a", "counter", now), QuotaVerdict::Pass);
+        assert_eq!(names.check(b"team.b", "counter", now), QuotaVerdict::Pass);
+        assert_eq!(names.check(b"team.a", "counter", now), QuotaVerdict::Pass);
+        assert_eq!(names.check(b"team.c", "counter", now), QuotaVerdict::Drop);
+        assert_eq!(names.usage, QuotaUsage { names: 2, accepted: 3, dropped: 1, sampled: 0, overflowed: 0 });
+
+        let mut overflow = QuotaState::new(Quota { prefix: "team.".to_string(), max_names: 1, over_quota: OverQuota::Overflow, ..Quota::default() });
+        assert_eq!(overflow.check(b"team.a", "timer", now), QuotaVerdict::Pass);
+        assert_eq!(overflow.check(b"team.b", "timer", now), QuotaVerdict::Rename(b"team._overflow.timer".to_vec()));
+
+        let mut sampled = QuotaState::new(Quota { prefix: "team".to_string(), max_rate: 1, over_quota: OverQuota::Sample, sample_ratio: 0.5, ..Quota::default() });
+        let verdicts = (0..100).map(|_| sampled.check(b"team.a", "gauge", now)).filter(|verdict| *verdict == QuotaVerdict::Pass).count();
+        // a token for the first sample, then every second one of the rest
+        assert_eq!(verdicts, 50);
+        assert_eq!(sampled.usage, QuotaUsage { names: 0, accepted: 1, dropped: 50, sampled: 49, overflowed: 0 });
+
+        assert!(validate_quotas(&[Quota { prefix: "team".to_string(), ..Quota::default() }]).is_err());
+        assert!(validate_quotas(&[Quota { prefix: "team".to_string(), max_rate: 10, sample_ratio: 0f64, ..Quota::default() }]).is_err());
+    }
+}
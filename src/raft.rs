@@ -2,6 +2,7 @@ use std::collections::HashMap;
 use std::io;
 use std::net::TcpStream as StdTcpStream;
 use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::atomic::Ordering;
 
 use rand::random;
 
@@ -23,12 +24,15 @@ use raft_tokio::Notifier;
 
 use crate::config::Raft;
 use crate::util::{get_hostname, switch_leader, try_resolve};
+use crate::CONSENSUS_REACHABLE;
 
 #[derive(Clone)]
 pub struct LeaderNotifier(Logger);
 
 impl Notifier for LeaderNotifier {
     fn state_changed(&mut self, old: ConsensusState, new: ConsensusState) {
+        // being a candidate means there is no quorum visible from this node
+        CONSENSUS_REACHABLE.store(new != ConsensusState::Candidate, Ordering::Relaxed);
         if old != new {
             if new == ConsensusState::Leader {
                 switch_leader(true, &self.0)
@@ -46,7 +50,7 @@ impl ConnectionSolver for LeaderNotifier {
     }
 }
 
-pub(crate) fn start_internal_raft(options: Raft, logger: Logger) {
+pub fn start_internal_raft(options: Raft, logger: Logger) {
     let this = if let Some(name) = options.this_node.clone() {
         try_resolve(&name)
     } else {
@@ -0,0 +1,150 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use lazy_static::lazy_static;
+
+use crate::config::Network;
+use crate::counter::Counter;
+use crate::{PEER_RATE_DROPS, STATSD_RATE_DROPS};
+
+// tokens taken from a bucket at once, so the lock is taken once per this many metrics
+const GRANT: usize = 64;
+
+lazy_static! {
+    /// Metrics parsed from statsd packets, shared by all workers
+    pub static ref STATSD_LIMIT: TokenBucket = TokenBucket::new(&STATSD_RATE_DROPS);
+    /// Metrics sent by agents over peer protocol, snapshots of other nodes are never limited
+    pub static ref PEER_LIMIT: TokenBucket = TokenBucket::new(&PEER_RATE_DROPS);
+}
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket refilled with `rate` tokens per second up to `rate * burst / 1000`, a token per metric accepted
+#[derive(Debug)]
+pub struct TokenBucket {
+    // 0 for unlimited
+    rate: AtomicUsize,
+    burst: AtomicUsize,
+    state: Mutex<BucketState>,
+    drops: &'static Counter,
+}
+
+impl TokenBucket {
+    pub fn new(drops: &'static Counter) -> Self {
+        Self { rate: AtomicUsize::new(0), burst: AtomicUsize::new(1000), state: Mutex::new(BucketState { tokens: 0f64, updated: Instant::now() }), drops }
+    }
+
+    /// Change the rate, the bucket starts full
+    pub fn set(&self, rate: usize, burst: u64) {
+        let mut state = self.state.lock().unwrap();
+        self.burst.store(burst.max(1) as usize, Ordering::Relaxed);
+        self.rate.store(rate, Ordering::Relaxed);
+        state.tokens = self.capacity(rate);
+        state.updated = Instant::now();
+    }
+
+    fn capacity(&self, rate: usize) -> f64 {
+        // at least a single token, so a low rate is not made zero by a short burst
+        (rate as f64 * self.burst.load(Ordering::Relaxed) as f64 / 1000f64).max(1f64)
+    }
+
+    pub fn is_limited(&self) -> bool {
+        self.rate.load(Ordering::Relaxed) > 0
+    }
+
+    /// Take up to `wanted` tokens, returns the number taken
+    pub fn take(&self, wanted: usize, now: Instant) -> usize {
+        let rate = self.rate.load(Ordering::Relaxed);
+        if rate == 0 {
+            return wanted;
+        }
+        let mut state = self.state.lock().unwrap();
+        if now > state.updated {
+            let elapsed = now.duration_since(state.updated);
+            let added = (elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9) * rate as f64;
+            state.tokens = (state.tokens + added).min(self.capacity(rate));
+            state.updated = now;
+        }
+        let taken = (state.tokens as usize).min(wanted);
+        state.tokens -= taken as f64;
+        taken
+    }
+
+    /// Return tokens taken, but not used
+    pub fn give_back(&self, tokens: usize) {
+        let rate = self.rate.load(Ordering::Relaxed);
+        if rate == 0 || tokens == 0 {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.tokens = (state.tokens + tokens as f64).min(self.capacity(rate));
+    }
+}
+
+/// Tokens taken from a bucket a few at a time, unused ones are given back when it is dropped
+#[derive(Debug)]
+pub struct Allowance {
+    bucket: &'static TokenBucket,
+    left: usize,
+}
+
+impl Allowance {
+    pub fn new(bucket: &'static TokenBucket) -> Self {
+        Self { bucket, left: 0 }
+    }
+
+    /// Check if one more metric may be accepted, counting the drop if it may not
+    pub fn allow(&mut self) -> bool {
+        if self.left == 0 {
+            self.left = self.bucket.take(GRANT, Instant::now());
+            if self.left == 0 {
+                self.bucket.drops.add(1);
+                return false;
+            }
+        }
+        self.left -= 1;
+        true
+    }
+}
+
+impl Drop for Allowance {
+    fn drop(&mut self) {
+        self.bucket.give_back(self.left);
+    }
+}
+
+/// Apply `network.statsd-rate-limit` and `network.peer-rate-limit`, can be called again on reload
+pub fn set_rate_limits(network: &Network) {
+    STATSD_LIMIT.set(network.statsd_rate_limit, network.rate_limit_burst);
+    PEER_LIMIT.set(network.peer_rate_limit, network.rate_limit_burst);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    static TEST_DROPS: Counter = Counter::new();
+
+    #[test]
+    fn token_bucket() {
+        let bucket = TokenBucket::new(&TEST_DROPS);
+        let start = Instant::now();
+        assert_eq!(bucket.take(1_000_000, start), 1_000_000);
+
+        bucket.set(1000, 500);
+        let start = Instant::now();
+        assert_eq!(bucket.take(10_000, start), 500);
+        assert_eq!(bucket.take(10, start), 0);
+        // refilled at the rate, but never over the burst
+        assert_eq!(bucket.take(10_000, start + Duration::from_millis(100)), 100);
+        assert_eq!(bucket.take(10_000, start + Duration::from_secs(10)), 500);
+        bucket.give_back(20);
+        assert_eq!(bucket.take(10_000, start + Duration::from_secs(10)), 20);
+    }
+}
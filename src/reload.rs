@@ -0,0 +1,443 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use serde_derive::{Deserialize, Serialize};
+use slog::{info, o, warn, Logger};
+use toml::value::{Table, Value};
+
+use crate::auth::{client_tls_config, reload_server_tls, server_tls, tls_config, tls_name};
+use crate::config::System;
+use crate::errors::GeneralError;
+use crate::events::event;
+use crate::quota::{set_quotas, validate_quotas};
+use crate::ratelimit::set_rate_limits;
+use crate::tags::set_tag_options;
+use crate::util::resolve_addr;
+use crate::windows::{validate_window, window_overrides};
+use crate::RUNTIME_CONFIG;
+
+// Options that can be changed without restarting the server. Anything else
+// will only be reported as ignored.
+const RELOADABLE: &[&str] = &[
+    "carbon.address",
+    "carbon.bind-address",
+    "carbon.tls",
+    "carbon.connect-delay",
+    "carbon.connect-delay-multiplier",
+    "carbon.connect-delay-max",
+    "carbon.send-retries",
+    "carbon.chunks",
+    "carbon.max-paused-intervals",
+    "carbon.spool-file",
+    "carbon.spool-max-size",
+    "carbon.backfill",
+    "carbon.backfill-value",
+    "memory.budget",
+    "memory.limit-ratio",
+    "memory.cap-samples-ratio",
+    "memory.timer-sample-cap",
+    "memory.shed-ratio",
+    "memory.flush-ratio",
+    "metrics.count-updates",
+    "metrics.update-counter-prefix",
+    "metrics.update-counter-suffix",
+    "metrics.update-counter-threshold",
+    "metrics.aggregation-mode",
+    "metrics.aggregation-threads",
+    "metrics.lowercase-tag-names",
+    "metrics.tag-chars",
+    "metrics.max-tags",
+    "metrics.max-tag-length",
+    "metrics.max-tag-bytes",
+    "metrics.tag-overflow",
+    "network.nodes",
+    "network.statsd-rate-limit",
+    "network.peer-rate-limit",
+    "network.rate-limit-burst",
+    "network.maintenance-forward",
+    "accounting.quotas",
+    "management.tokens",
+    "management.client-token",
+    "management.client-token-file",
+    "management.dump-dir",
+    "management.audit-log",
+    "management.state-report-file",
+    "management.profiling",
+    "management.profile-max-duration",
+    "management.profile-frequency",
+    "tls.cert",
+    "tls.key",
+    "tls.client-ca",
+    "tls.ca",
+    "tls.server-name",
+    "tls.min-version",
+    "tls.cipher-policy",
+    "windows",
+];
+
+// Options read by tag normalization, they are kept in globals which are set again when any of them changes
+const TAG_OPTIONS: &[&str] = &["metrics.lowercase-tag-names", "metrics.tag-chars", "metrics.max-tags", "metrics.max-tag-length", "metrics.max-tag-bytes", "metrics.tag-overflow"];
+
+/// A single changed option, values are in TOML form, `None` means option is not set
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ConfigChange {
+    pub key: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+// Sections are applied in this order, network goes last because it changes peers
+// the server talks to and should not be changed if anything else cannot be applied
+const SECTIONS: &[&str] = &["metrics", "tls", "management", "carbon", "memory", "accounting", "windows", "network"];
+
+/// What happened to changes of a configuration section
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SectionStatus {
+    Applied,
+    /// The section could not be applied, so nothing was
+    Failed,
+    /// The section was fine, but was rolled back because one of the following sections failed
+    RolledBack,
+    /// The section was not tried because one of the previous sections failed
+    Skipped,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct SectionResult {
+    pub section: String,
+    pub status: SectionStatus,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ReloadReport {
+    /// Changes applied to running server
+    pub applied: Vec<ConfigChange>,
+    /// Changes found in file, but requiring restart to be applied
+    pub ignored: Vec<ConfigChange>,
+    /// Results for sections with reloadable changes in the order they were applied
+    pub sections: Vec<SectionResult>,
+}
+
+impl ReloadReport {
+    /// Section that failed to apply, if any
+    pub fn failed(&self) -> Option<&SectionResult> {
+        self.sections.iter().find(|result| result.status == SectionStatus::Failed)
+    }
+}
+
+// make a flat key -> value map out of nested tables, so tables can be compared key by key
+fn flatten(prefix: &str, table: &Table, acc: &mut BTreeMap<String, Value>) {
+    for (key, value) in table {
+        let key = if prefix.len() > 0 { format!("{}.{}", prefix, key) } else { key.clone() };
+        match value {
+            Value::Table(inner) => flatten(&key, inner, acc),
+            value => {
+                acc.insert(key, value.clone());
+            }
+        }
+    }
+}
+
+fn to_flat(system: &System) -> Result<BTreeMap<String, Value>, GeneralError> {
+    let mut flat = BTreeMap::new();
+    match Value::try_from(system).map_err(GeneralError::ConfigConvert)? {
+        Value::Table(table) => flatten("", &table, &mut flat),
+        _ => unreachable!("config is always serialized to table"),
+    }
+    Ok(flat)
+}
+
+/// Whether the option can be changed without restart, by full name like "carbon.address"
+pub fn is_reloadable(key: &str) -> bool {
+    RELOADABLE.iter().any(|allowed| *allowed == key)
+}
+
+/// Compare two configurations returning all the changed keys
+pub fn diff(old: &System, new: &System) -> Result<Vec<ConfigChange>, GeneralError> {
+    let old = to_flat(old)?;
+    let new = to_flat(new)?;
+    let mut changes = Vec::new();
+    for key in old.keys().chain(new.keys().filter(|key| !old.contains_key(*key))) {
+        let (old_value, new_value) = (old.get(key), new.get(key));
+        if old_value != new_value {
+            changes.push(ConfigChange { key: key.clone(), old: old_value.map(|v| v.to_string()), new: new_value.map(|v| v.to_string()) });
+        }
+    }
+    Ok(changes)
+}
+
+// put the value into nested table by it's dotted key
+fn set_key(table: &mut Table, key: &str, value: Option<Value>) {
+    let mut parts = key.splitn(2, '.');
+    let head = parts.next().unwrap();
+    match parts.next() {
+        Some(rest) => {
+            if let Some(Value::Table(inner)) = table.get_mut(head) {
+                set_key(inner, rest, value);
+            }
+        }
+        None => match value {
+            Some(value) => {
+                table.insert(head.to_string(), value);
+            }
+            None => {
+                table.remove(head);
+            }
+        },
+    }
+}
+
+fn to_system(table: Table, current: &System) -> Result<System, GeneralError> {
+    let mut system: System = Value::Table(table).try_into().map_err(GeneralError::ConfigParse)?;
+    system.config_path = current.config_path.clone();
+    system.config_format = current.config_format;
+    system.overrides = current.overrides.clone();
+    Ok(system)
+}
+
+/// Check options of the section that can only be checked in runtime
+pub fn validate_section(section: &str, system: &System) -> Result<(), GeneralError> {
+    match section {
+        "carbon" => {
+            resolve_addr(&system.carbon.address)?;
+            if system.carbon.tls {
+                tls_name(&system.tls, &system.carbon.address)?;
+            }
+        }
+        "network" => {
+            for node in &system.network.nodes {
+                resolve_addr(node)?;
+                if system.network.peer_tls {
+                    tls_name(&system.tls, node)?;
+                }
+            }
+            if let Some(ref sibling) = system.network.maintenance_forward {
+                resolve_addr(sibling)?;
+            }
+        }
+        "memory" => {
+            let memory = &system.memory;
+            if !(memory.cap_samples_ratio <= memory.shed_ratio && memory.shed_ratio <= memory.flush_ratio) {
+                return Err(GeneralError::Configuration("memory ratios must grow from cap-samples-ratio to shed-ratio to flush-ratio"));
+            }
+            if memory.timer_sample_cap == 0 {
+                return Err(GeneralError::Configuration("memory.timer-sample-cap cannot be 0"));
+            }
+            if !(memory.limit_ratio >= 0f32 && memory.limit_ratio <= 1f32) {
+                return Err(GeneralError::Configuration("memory.limit-ratio must be from 0 to 1"));
+            }
+        }
+        "accounting" => {
+            validate_quotas(&system.accounting.quotas)?;
+        }
+        "windows" => {
+            for window in &system.windows {
+                validate_window(window).map_err(|e| GeneralError::Window(window.name.clone(), e))?;
+            }
+        }
+        "metrics" => {
+            if system.metrics.aggregation_threads == Some(0) {
+                return Err(GeneralError::Configuration("metrics.aggregation-threads cannot be 0"));
+            }
+        }
+        "tls" => {
+            // certificate files are read to be checked, TLS cannot be switched on or off though
+            if tls_config(&system.tls)?.is_some() != server_tls().is_some() {
+                return Err(GeneralError::Configuration("enabling or disabling TLS needs restart"));
+            }
+            client_tls_config(&system.tls)?;
+        }
+        "management" => {
+            let management = &system.management;
+            if management.tokens.iter().any(|token| token.token.len() == 0) {
+                return Err(GeneralError::Configuration("management.tokens cannot contain empty tokens"));
+            }
+            if let Some(ref dir) = management.dump_dir {
+                if !Path::new(dir).is_dir() {
+                    return Err(GeneralError::Configuration("management.dump-dir is not a directory"));
+                }
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+/// Take reloadable options from `new` config and put them into a copy of `current` section by section,
+/// validating every section after it is put. If any section fails, the copy is dropped and
+/// `GeneralError::ReloadRollback` with the report is returned.
+pub fn merge_reloadable(current: &System, new: &System) -> Result<(System, ReloadReport), GeneralError> {
+    let changes = diff(current, new)?;
+    let new_flat = to_flat(new)?;
+    let mut staged = match Value::try_from(current).map_err(GeneralError::ConfigConvert)? {
+        Value::Table(table) => table,
+        _ => unreachable!("config is always serialized to table"),
+    };
+
+    let mut report = ReloadReport::default();
+    let (reloadable, ignored): (Vec<_>, Vec<_>) = changes.into_iter().partition(|change| is_reloadable(&change.key));
+    report.ignored = ignored;
+
+    let mut failed = None;
+    for section in SECTIONS {
+        let prefix = format!("{}.", section);
+        let section_changes = reloadable.iter().filter(|change| change.key == *section || change.key.starts_with(&prefix)).cloned().collect::<Vec<_>>();
+        if section_changes.len() == 0 {
+            continue;
+        }
+        if failed.is_some() {
+            report.sections.push(SectionResult { section: section.to_string(), status: SectionStatus::Skipped, error: None });
+            continue;
+        }
+
+        let mut attempt = staged.clone();
+        for change in &section_changes {
+            set_key(&mut attempt, &change.key, new_flat.get(&change.key).cloned());
+        }
+        match to_system(attempt.clone(), current).and_then(|system| validate_section(section, &system)) {
+            Ok(()) => {
+                staged = attempt;
+                report.applied.extend(section_changes);
+                report.sections.push(SectionResult { section: section.to_string(), status: SectionStatus::Applied, error: None });
+            }
+            Err(e) => {
+                report.sections.push(SectionResult { section: section.to_string(), status: SectionStatus::Failed, error: Some(e.to_string()) });
+                failed = Some(section.to_string());
+            }
+        }
+    }
+
+    if let Some(section) = failed {
+        for result in report.sections.iter_mut().filter(|result| result.status == SectionStatus::Applied) {
+            result.status = SectionStatus::RolledBack;
+        }
+        report.ignored.extend(report.applied.drain(..));
+        return Err(GeneralError::ReloadRollback(section, report));
+    }
+    Ok((to_system(staged, current)?, report))
+}
+
+/// Rereads configuration file and applies the reloadable part of it
+#[derive(Clone)]
+pub struct Reloader {
+    log: Logger,
+}
+
+impl Reloader {
+    pub fn new(log: &Logger) -> Self {
+        Self { log: log.new(o!("source"=>"config-reload")) }
+    }
+
+    /// Reload configuration file, the result is recorded to event log
+    pub fn reload(&self) -> Result<ReloadReport, GeneralError> {
+        let result = self.reload_file();
+        match result {
+            Ok(ref report) => event("reload", format!("config reloaded, {} options applied, {} ignored", report.applied.len(), report.ignored.len())),
+            Err(ref e) => event("reload", format!("config reload failed: {}", e)),
+        }
+        result
+    }
+
+    fn reload_file(&self) -> Result<ReloadReport, GeneralError> {
+        let current = RUNTIME_CONFIG.read().unwrap().clone();
+        let path = current.config_path.clone().ok_or(GeneralError::Configuration("configuration was not loaded from file"))?;
+        // options of active windows go on top of the command line ones
+        let mut overrides = current.overrides.clone();
+        overrides.extend(window_overrides());
+        let mut new = System::from_file(&path, current.config_format, &overrides)?;
+        // window options are not kept as overrides, so they are not applied again after the window ends
+        new.overrides = current.overrides.clone();
+        for message in &new.migration_warnings {
+            warn!(self.log, "outdated configuration"; "message"=>message);
+        }
+        let (merged, report) = merge_reloadable(&current, &new).map_err(|e| {
+            if let GeneralError::ReloadRollback(_, ref report) = e {
+                for result in &report.sections {
+                    warn!(self.log, "config section reload"; "section"=>&result.section, "status"=>format!("{:?}", result.status), "error"=>result.error.clone().unwrap_or_default());
+                }
+            }
+            e
+        })?;
+
+        for change in &report.applied {
+            info!(self.log, "config option changed"; "key"=>&change.key, "old"=>format!("{:?}", change.old), "new"=>format!("{:?}", change.new));
+        }
+        for change in &report.ignored {
+            warn!(self.log, "config option cannot be changed without restart, ignored"; "key"=>&change.key, "old"=>format!("{:?}", change.old), "new"=>format!("{:?}", change.new));
+        }
+        if report.applied.len() == 0 {
+            info!(self.log, "config reloaded, nothing to apply");
+        }
+
+        // certificates are read again even if options are not changed, so rotated files are picked up
+        if reload_server_tls(&merged.tls)? {
+            info!(self.log, "TLS certificates reloaded"; "cert"=>merged.tls.cert.clone().unwrap_or_default());
+        }
+        if report.applied.iter().any(|change| change.key.contains("rate-limit")) {
+            set_rate_limits(&merged.network);
+        }
+        // names already in caches keep their form until they expire
+        if report.applied.iter().any(|change| TAG_OPTIONS.contains(&change.key.as_str())) {
+            set_tag_options(&merged.metrics);
+        }
+        // quotas changed through API are replaced only if they are changed in file too
+        if report.applied.iter().any(|change| change.key == "accounting.quotas") {
+            set_quotas(&merged.accounting.quotas)?;
+        }
+        *RUNTIME_CONFIG.write().unwrap() = Arc::new(merged);
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ThreadCount;
+
+    #[test]
+    fn merge_only_reloadable_options() {
+        let current = System::default();
+        let mut new = System::default();
+        new.carbon.address = "127.0.0.2:2003".to_string();
+        new.network.nodes = vec!["127.0.0.1:8136".to_string()];
+        new.n_threads = ThreadCount::Fixed(16);
+
+        let (merged, report) = merge_reloadable(&current, &new).unwrap();
+        assert_eq!(merged.carbon.address, "127.0.0.2:2003".to_string());
+        assert_eq!(merged.network.nodes, vec!["127.0.0.1:8136".to_string()]);
+        assert_eq!(merged.n_threads, current.n_threads);
+
+        let applied = report.applied.iter().map(|change| change.key.as_str()).collect::<Vec<_>>();
+        assert_eq!(applied, vec!["carbon.address", "network.nodes"]);
+        assert_eq!(report.ignored, vec![ConfigChange { key: "n-threads".to_string(), old: Some("4".to_string()), new: Some("16".to_string()) }]);
+        let sections = report.sections.iter().map(|result| (result.section.as_str(), result.status)).collect::<Vec<_>>();
+        assert_eq!(sections, vec![("carbon", SectionStatus::Applied), ("network", SectionStatus::Applied)]);
+    }
+
+    #[test]
+    fn failed_section_rolls_back() {
+        let current = System::default();
+        let mut new = System::default();
+        new.carbon.address = "127.0.0.2:2003".to_string();
+        new.metrics.aggregation_threads = Some(0);
+        new.network.nodes = vec!["127.0.0.1:8136".to_string()];
+
+        let report = match merge_reloadable(&current, &new) {
+            Err(GeneralError::ReloadRollback(section, report)) => {
+                assert_eq!(section, "metrics".to_string());
+                report
+            }
+            other => panic!("expected rollback, got {:?}", other.map(|(_, report)| report)),
+        };
+        assert_eq!(report.applied, Vec::new());
+        let sections = report.sections.iter().map(|result| (result.section.as_str(), result.status)).collect::<Vec<_>>();
+        assert_eq!(sections, vec![("metrics", SectionStatus::Failed), ("carbon", SectionStatus::Skipped), ("network", SectionStatus::Skipped)]);
+        assert!(report.failed().unwrap().error.is_some());
+    }
+}
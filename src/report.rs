@@ -0,0 +1,167 @@
+use std::fmt::Write as FmtWrite;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use futures::future::Future;
+use futures::sync::mpsc::Sender;
+use slog::{info, o, warn, Logger};
+use tokio::timer::Timeout;
+
+use crate::carbon::BACKEND_QUEUE_BYTES;
+use crate::cluster::{cluster_view, now_ms, ConsensusView, PeerView};
+use crate::queue::{worker_queues, QueueValues};
+use crate::stats::{collect_top, uptime_ms, worker_stats, TopBy, TopEntry, WorkerStats};
+use crate::task::Task;
+use crate::tunables::WORKER_PING_TIMEOUT;
+use crate::{BACKEND_OK, FLUSH_PAUSED, INGESTION_PAUSED, IS_LEADER, PEER_LISTENING, RUNTIME_CONFIG, STATSD_LISTENING};
+
+// prefixes listed in the report, ranked by number of names under them
+const TOP_PREFIXES: usize = 20;
+const TOP_DEPTH: usize = 2;
+
+/// Everything the state report tells, collected at once
+#[derive(Debug, Clone)]
+pub struct StateReport {
+    pub at: u64,
+    pub uptime_ms: u64,
+    pub leader: bool,
+    pub consensus: ConsensusView,
+    pub ingestion_paused: bool,
+    pub flush_paused: bool,
+    pub statsd_listening: bool,
+    pub peer_listening: bool,
+    pub backend_ok: bool,
+    pub backend_queue_bytes: usize,
+    pub queues: Vec<QueueValues>,
+    /// `None` for workers not answering in time
+    pub workers: Vec<Option<WorkerStats>>,
+    pub peers: Vec<PeerView>,
+    /// `None` if workers did not answer in time
+    pub top: Option<Vec<TopEntry>>,
+}
+
+impl StateReport {
+    /// Human-readable report, one fact per line, so it reads fine both in a file and in a log record
+    pub fn render(&self) -> String {
+        let yes_no = |flag: bool| if flag { "yes" } else { "no" };
+        let ago = |at: Option<u64>| at.map(|at| format!("{}ms ago", self.at.saturating_sub(at))).unwrap_or_else(|| "never".to_string());
+        // writing to string cannot fail
+        let mut out = String::new();
+        writeln!(out, "bioyino state at {} ms since epoch, uptime {} ms", self.at, self.uptime_ms).unwrap();
+        writeln!(out, "leader: {}, consensus: {:?} {:?}, reachable: {}", yes_no(self.leader), self.consensus.kind, self.consensus.state, yes_no(self.consensus.reachable)).unwrap();
+        writeln!(out, "ingestion paused: {}, flush paused: {}", yes_no(self.ingestion_paused), yes_no(self.flush_paused)).unwrap();
+        writeln!(out, "statsd listening: {}, peer listening: {}", yes_no(self.statsd_listening), yes_no(self.peer_listening)).unwrap();
+        writeln!(out, "backend ok: {}, backend queue: {} bytes", yes_no(self.backend_ok), self.backend_queue_bytes).unwrap();
+
+        writeln!(out, "workers:").unwrap();
+        for (idx, worker) in self.workers.iter().enumerate() {
+            let queue = self.queues.get(idx).cloned().unwrap_or_default();
+            write!(out, "  {}: queue {}/{} (high {})", idx, queue.depth, queue.capacity, queue.high_watermark).unwrap();
+            match worker {
+                Some(stats) => writeln!(out, ", short {}, long {}, buffers {}, ~{} bytes, answered in {}ms", stats.short_entries, stats.long_entries, stats.buffers, stats.estimated_bytes, stats.response_ms).unwrap(),
+                None => writeln!(out, ", not answering").unwrap(),
+            }
+        }
+
+        writeln!(out, "peers:").unwrap();
+        if self.peers.len() == 0 {
+            writeln!(out, "  none").unwrap();
+        }
+        for peer in &self.peers {
+            let resolved = if peer.resolved { "" } else { ", not resolved" };
+            writeln!(out, "  {}: alive {}{}, sent {}, send errors {}, received {}", peer.address, yes_no(peer.alive), resolved, ago(peer.times.last_sent), peer.times.send_errors, ago(peer.times.last_received)).unwrap();
+        }
+
+        writeln!(out, "top prefixes by names:").unwrap();
+        match self.top {
+            Some(ref top) if top.len() > 0 => {
+                for entry in top {
+                    writeln!(out, "  {}: {}", entry.name, entry.value).unwrap();
+                }
+            }
+            Some(_) => writeln!(out, "  none").unwrap(),
+            None => writeln!(out, "  not collected, workers did not answer").unwrap(),
+        }
+        out
+    }
+}
+
+/// Collect the report, workers are given `WORKER_PING_TIMEOUT` to answer, so a stuck one does not hold it
+pub fn collect_report(chans: &[Sender<Task>]) -> impl Future<Item = StateReport, Error = ()> {
+    let timeout = Duration::from_millis(WORKER_PING_TIMEOUT.get() as u64);
+    let workers_count = chans.len();
+    let workers = Timeout::new(worker_stats(chans), timeout).then(move |workers| Ok::<_, ()>(workers.unwrap_or_else(|_| vec![None; workers_count])));
+    let top = Timeout::new(collect_top(chans, TopBy::Names(TOP_DEPTH), TOP_PREFIXES), timeout).then(|top| Ok::<_, ()>(top.ok()));
+    workers.join(top).map(move |(workers, top)| {
+        let view = cluster_view(&RUNTIME_CONFIG.read().unwrap());
+        StateReport {
+            at: now_ms(),
+            uptime_ms: uptime_ms(),
+            leader: IS_LEADER.load(Ordering::SeqCst),
+            consensus: view.consensus,
+            ingestion_paused: INGESTION_PAUSED.load(Ordering::SeqCst),
+            flush_paused: FLUSH_PAUSED.load(Ordering::SeqCst),
+            statsd_listening: STATSD_LISTENING.load(Ordering::Relaxed),
+            peer_listening: PEER_LISTENING.load(Ordering::Relaxed),
+            backend_ok: BACKEND_OK.load(Ordering::Relaxed),
+            backend_queue_bytes: BACKEND_QUEUE_BYTES.load(Ordering::Relaxed),
+            queues: worker_queues(workers_count),
+            workers,
+            peers: view.peers,
+            top,
+        }
+    })
+}
+
+/// Write the state report to `management.state-report-file` or to the log if it is not set,
+/// used on SIGUSR1 where management API cannot be reached
+pub fn dump_state(chans: &[Sender<Task>], log: &Logger) -> impl Future<Item = (), Error = ()> {
+    let log = log.new(o!("source"=>"state-report"));
+    collect_report(chans).map(move |report| {
+        let text = report.render();
+        let path = RUNTIME_CONFIG.read().unwrap().management.state_report_file.clone();
+        match path {
+            // reports are appended, so the file can be rotated by moving it
+            Some(path) => match OpenOptions::new().create(true).append(true).open(&path).and_then(|mut file| file.write_all(format!("{}\n", text).as_bytes())) {
+                Ok(()) => info!(log, "state report written"; "file"=>&path),
+                Err(e) => warn!(log, "error writing state report"; "file"=>&path, "error"=>e.to_string()),
+            },
+            None => info!(log, "state report\n{}", text),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cluster::PeerTimes;
+    use crate::{ConsensusKind, ConsensusState};
+
+    #[test]
+    fn rendered_report() {
+        let report = StateReport {
+            at: 10000,
+            uptime_ms: 5000,
+            leader: true,
+            consensus: ConsensusView { kind: ConsensusKind::None, state: ConsensusState::Disabled, reachable: false },
+            ingestion_paused: false,
+            flush_paused: false,
+            statsd_listening: true,
+            peer_listening: true,
+            backend_ok: true,
+            backend_queue_bytes: 0,
+            queues: vec![QueueValues { depth: 3, high_watermark: 10, capacity: 2048 }],
+            workers: vec![Some(WorkerStats { short_entries: 100, response_ms: 2, ..WorkerStats::default() }), None],
+            peers: vec![PeerView { address: "127.0.0.1:8136".to_string(), resolved: true, alive: true, raft_member: false, times: PeerTimes { last_sent: Some(9000), ..PeerTimes::default() } }],
+            top: None,
+        };
+        let text = report.render();
+        assert!(text.contains("leader: yes, consensus: None Disabled, reachable: no\n"));
+        assert!(text.contains("  0: queue 3/2048 (high 10), short 100, long 0, buffers 0, ~0 bytes, answered in 2ms\n"));
+        assert!(text.contains("  1: queue 0/0 (high 0), not answering\n"));
+        assert!(text.contains("  127.0.0.1:8136: alive yes, sent 1000ms ago, send errors 0, received never\n"));
+        assert!(text.ends_with("  not collected, workers did not answer\n"));
+    }
+}
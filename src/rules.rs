@@ -0,0 +1,477 @@
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::sync::{Arc, RwLock};
+
+use lazy_static::lazy_static;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::errors::GeneralError;
+use crate::tags::{canonical, split_tags, tags, TagOptions};
+use crate::util::{glob_captures, glob_match};
+
+lazy_static! {
+    /// Currently active ingestion rules, replaced as a whole on every change
+    pub static ref RULES: RwLock<Arc<Rules>> = { RwLock::new(Arc::new(Rules::default())) };
+}
+
+/// Replace the beginning of metric name
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct RewriteRule {
+    pub prefix: String,
+    pub replacement: String,
+}
+
+/// Match metrics by tags: the name without tags matches `name` glob and every tag in `tags` is present with
+/// the value matching the glob, tag names are compared as they are after normalization
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct TagRule {
+    pub name: String,
+    pub tags: BTreeMap<String, String>,
+}
+
+impl Default for TagRule {
+    fn default() -> Self {
+        Self { name: "*".to_string(), tags: BTreeMap::new() }
+    }
+}
+
+impl TagRule {
+    pub fn matches(&self, name: &[u8]) -> bool {
+        let (base, _) = split_tags(name);
+        glob_match(self.name.as_bytes(), base) && self.tags.iter().all(|(tag, pattern)| tags(name).any(|(name, value)| name == tag.as_bytes() && glob_match(pattern.as_bytes(), value)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub enum RelabelAction {
+    /// Set `target` tag to the value of `source` tag
+    Copy,
+    /// Move the value of `source` tag to `target` tag
+    Rename,
+    /// Remove `source` tag
+    Drop,
+    /// Set `target` tag to `value` with `$1` to `$9` replaced by parts of the name matched by `*` and `?` of `name` pattern
+    FromName,
+    /// Set `target` tag, `source` if it is empty, to the value `map` has for the value of `source` tag, values
+    /// not in `map` are kept
+    Map,
+}
+
+fn all_names() -> String {
+    "*".to_string()
+}
+
+/// Change of tags of metrics with names without tags matching `name` glob
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct RelabelRule {
+    pub action: RelabelAction,
+    #[serde(default = "all_names")]
+    pub name: String,
+    #[serde(default)]
+    pub source: String,
+    #[serde(default)]
+    pub target: String,
+    #[serde(default)]
+    pub value: String,
+    #[serde(default)]
+    pub map: BTreeMap<String, String>,
+}
+
+// value of `template` with `$1` to `$9` replaced by captures, references to missing captures are left empty
+fn expand(template: &str, captures: &[&[u8]]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(template.len());
+    let mut bytes = template.as_bytes().iter().peekable();
+    while let Some(c) = bytes.next() {
+        match (*c, bytes.peek()) {
+            (b'$', Some(digit)) if (b'1'..=b'9').contains(*digit) => {
+                out.extend_from_slice(captures.get((**digit - b'1') as usize).map(|capture| *capture).unwrap_or(b""));
+                bytes.next();
+            }
+            (c, _) => out.push(c),
+        }
+    }
+    out
+}
+
+// set the tag replacing the value it had
+fn set_tag(tags: &mut Vec<(Vec<u8>, Vec<u8>)>, tag: &str, value: Vec<u8>) -> bool {
+    match tags.iter_mut().find(|(name, _)| name == tag.as_bytes()) {
+        Some((_, existing)) => *existing = value,
+        None => tags.push((tag.as_bytes().to_vec(), value)),
+    }
+    true
+}
+
+impl RelabelRule {
+    /// Check the rule has all it needs for the action
+    pub fn validate(&self) -> Result<(), String> {
+        let needs_source = self.action != RelabelAction::FromName;
+        let needs_target = self.action != RelabelAction::Drop && self.action != RelabelAction::Map;
+        if needs_source && self.source.len() == 0 {
+            return Err(format!("{:?} needs source tag", self.action));
+        }
+        if needs_target && self.target.len() == 0 {
+            return Err(format!("{:?} needs target tag", self.action));
+        }
+        let wildcards = self.name.bytes().filter(|c| *c == b'*' || *c == b'?').count();
+        let refs = self.value.as_bytes().windows(2).filter(|pair| pair[0] == b'$' && pair[1] >= b'1' && pair[1] <= b'9').map(|pair| (pair[1] - b'0') as usize);
+        if let Some(max) = refs.max().filter(|max| *max > wildcards) {
+            return Err(format!("value {:?} refers to ${} but name pattern {:?} has {} wildcards", self.value, max, self.name, wildcards));
+        }
+        Ok(())
+    }
+
+    /// Check if the rule may change tags of the metric `name`, so they only have to be copied for such rules
+    pub fn matches(&self, name: &[u8]) -> bool {
+        let (base, _) = split_tags(name);
+        glob_match(self.name.as_bytes(), base) && (self.action == RelabelAction::FromName || tags(name).any(|(tag, _)| tag == self.source.as_bytes()))
+    }
+
+    /// Apply the rule to `tags` of the metric named `base`, returns true if they were changed
+    pub fn apply(&self, base: &[u8], tags: &mut Vec<(Vec<u8>, Vec<u8>)>) -> bool {
+        if !glob_match(self.name.as_bytes(), base) {
+            return false;
+        }
+        let source = tags.iter().position(|(name, _)| name == self.source.as_bytes());
+        match (self.action, source) {
+            (RelabelAction::Copy, Some(idx)) => {
+                let value = tags[idx].1.clone();
+                set_tag(tags, &self.target, value)
+            }
+            (RelabelAction::Rename, Some(idx)) => {
+                let (_, value) = tags.remove(idx);
+                set_tag(tags, &self.target, value)
+            }
+            (RelabelAction::Drop, Some(idx)) => {
+                tags.remove(idx);
+                true
+            }
+            (RelabelAction::FromName, _) => {
+                // captures are only needed here, so other actions don't pay for them
+                let value = match glob_captures(self.name.as_bytes(), base) {
+                    Some(captures) => expand(&self.value, &captures),
+                    None => return false,
+                };
+                value.len() > 0 && set_tag(tags, &self.target, value)
+            }
+            (RelabelAction::Map, Some(idx)) => {
+                let mapped = self.map.get(&*String::from_utf8_lossy(&tags[idx].1)).map(|value| value.as_bytes().to_vec());
+                match mapped {
+                    Some(value) => set_tag(tags, if self.target.len() > 0 { &self.target } else { &self.source }, value),
+                    None => false,
+                }
+            }
+            (_, None) => false,
+        }
+    }
+}
+
+/// Rules applied to every incoming metric before it gets to cache
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct Rules {
+    /// Glob patterns of metric names without tags to drop, checked after rewriting
+    pub block: Vec<String>,
+
+    /// Maximum number of unique metric names in a single worker per interval,
+    /// metrics with new names are dropped after reaching it
+    pub max_names: Option<usize>,
+
+    /// Prefix rewrites, the first matching one is applied
+    pub rewrite: Vec<RewriteRule>,
+
+    /// Tag matches of metrics to drop, checked after rewriting
+    pub block_tagged: Vec<TagRule>,
+
+    /// Tag changes applied in order after rewriting and before blocking
+    pub relabel: Vec<RelabelRule>,
+}
+
+/// What the rule check decided about the metric
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict<'a> {
+    /// Name is only copied if it was rewritten
+    Pass(Cow<'a, [u8]>),
+    Block,
+}
+
+impl Rules {
+    pub fn is_empty(&self) -> bool {
+        self.block.len() == 0 && self.rewrite.len() == 0 && self.max_names.is_none() && self.block_tagged.len() == 0 && self.relabel.len() == 0
+    }
+
+    /// Rewrite the name, relabel it's tags and check it against block list. Cardinality is checked by worker
+    /// since it only knows the number of names.
+    pub fn check<'a>(&self, name: &'a [u8]) -> Verdict<'a> {
+        let name = match self.rewrite.iter().find(|rule| name.starts_with(rule.prefix.as_bytes())) {
+            Some(rule) => {
+                let mut buf = Vec::with_capacity(name.len() - rule.prefix.len() + rule.replacement.len());
+                buf.extend_from_slice(rule.replacement.as_bytes());
+                buf.extend_from_slice(&name[rule.prefix.len()..]);
+                Cow::Owned(buf)
+            }
+            None => Cow::Borrowed(name),
+        };
+        let name = self.relabel(name);
+        // tags are only matched by tag rules, so patterns written for untagged names keep blocking tagged ones
+        let (base, _) = split_tags(&name);
+        if self.block.iter().any(|pattern| glob_match(pattern.as_bytes(), base)) || self.block_tagged.iter().any(|rule| rule.matches(&name)) {
+            Verdict::Block
+        } else {
+            Verdict::Pass(name)
+        }
+    }
+
+    // relabeled tags are normalized again, since the rules may add any tags
+    fn relabel<'a>(&self, name: Cow<'a, [u8]>) -> Cow<'a, [u8]> {
+        // tags are only copied when some rule may change them, rules before it would not change anything
+        let first = match self.relabel.iter().position(|rule| rule.matches(&name)) {
+            Some(first) => first,
+            None => return name,
+        };
+        let (base, _) = split_tags(&name);
+        let mut list = tags(&name).map(|(tag, value)| (tag.to_vec(), value.to_vec())).collect::<Vec<_>>();
+        let changed = self.relabel[first..].iter().fold(false, |changed, rule| rule.apply(base, &mut list) || changed);
+        if !changed {
+            return name;
+        }
+        let list = list.iter().map(|(tag, value)| (&tag[..], &value[..])).collect::<Vec<_>>();
+        Cow::Owned(canonical(base, &list, TagOptions::current()).into_owned())
+    }
+
+    /// Check every relabel rule, so rules that cannot work are never installed
+    pub fn validate(&self) -> Result<(), GeneralError> {
+        for rule in &self.relabel {
+            rule.validate().map_err(|e| GeneralError::Relabel(rule.name.clone(), e))?;
+        }
+        Ok(())
+    }
+
+    pub fn from_file(path: &str) -> Result<Self, GeneralError> {
+        let mut file = File::open(path).map_err(GeneralError::Io)?;
+        let mut rules = String::new();
+        file.read_to_string(&mut rules).map_err(GeneralError::Io)?;
+        let rules: Self = toml::de::from_str(&rules).map_err(GeneralError::ConfigParse)?;
+        rules.validate()?;
+        Ok(rules)
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), GeneralError> {
+        let rules = toml::ser::to_string(self).map_err(GeneralError::ConfigConvert)?;
+        // write to temporary file first, so the rules file is never half-written
+        let tmp = format!("{}.tmp", path);
+        fs::write(&tmp, rules).map_err(GeneralError::Io)?;
+        fs::rename(&tmp, path).map_err(GeneralError::Io)
+    }
+}
+
+/// A change of rules made by management API
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub enum RulesChange {
+    Replace(Rules),
+    AddBlock(String),
+    RemoveBlock(String),
+    AddRewrite(RewriteRule),
+    RemoveRewrite(String),
+    SetMaxNames(Option<usize>),
+    AddBlockTagged(TagRule),
+    RemoveBlockTagged(TagRule),
+    AddRelabel(RelabelRule),
+    RemoveRelabel(RelabelRule),
+}
+
+impl RulesChange {
+    /// Apply change to a copy of rules, returns false if there was nothing to remove
+    pub fn apply(self, rules: &mut Rules) -> bool {
+        match self {
+            RulesChange::Replace(new) => *rules = new,
+            RulesChange::AddBlock(pattern) => {
+                if !rules.block.contains(&pattern) {
+                    rules.block.push(pattern);
+                }
+            }
+            RulesChange::RemoveBlock(pattern) => {
+                let len = rules.block.len();
+                rules.block.retain(|existing| existing != &pattern);
+                return rules.block.len() != len;
+            }
+            RulesChange::AddRewrite(rule) => {
+                // prefix is a rule identifier, so adding an existing one replaces it
+                rules.rewrite.retain(|existing| existing.prefix != rule.prefix);
+                rules.rewrite.push(rule);
+            }
+            RulesChange::RemoveRewrite(prefix) => {
+                let len = rules.rewrite.len();
+                rules.rewrite.retain(|existing| existing.prefix != prefix);
+                return rules.rewrite.len() != len;
+            }
+            RulesChange::SetMaxNames(max) => rules.max_names = max,
+            RulesChange::AddBlockTagged(rule) => {
+                if !rules.block_tagged.contains(&rule) {
+                    rules.block_tagged.push(rule);
+                }
+            }
+            RulesChange::RemoveBlockTagged(rule) => {
+                let len = rules.block_tagged.len();
+                rules.block_tagged.retain(|existing| existing != &rule);
+                return rules.block_tagged.len() != len;
+            }
+            // relabel rules are applied in order, so the same rule may be needed more than once
+            RulesChange::AddRelabel(rule) => rules.relabel.push(rule),
+            RulesChange::RemoveRelabel(rule) => {
+                let len = rules.relabel.len();
+                rules.relabel.retain(|existing| existing != &rule);
+                return rules.relabel.len() != len;
+            }
+        }
+        true
+    }
+}
+
+/// Load rules at start. Missing file is not an error, it will be created when rules are persisted.
+pub fn init_rules(path: &Option<String>) -> Result<(), GeneralError> {
+    if let Some(path) = path {
+        match Rules::from_file(path) {
+            Ok(rules) => *RULES.write().unwrap() = Arc::new(rules),
+            Err(GeneralError::Io(ref e)) if e.kind() == ::std::io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Change active rules, saving them to `path` if it is set. Rules are not changed if the new ones are not valid.
+pub fn change_rules(change: RulesChange, path: Option<&str>) -> Result<Option<Arc<Rules>>, GeneralError> {
+    let mut rules = RULES.write().unwrap();
+    let mut new = (**rules).clone();
+    if !change.apply(&mut new) {
+        return Ok(None);
+    }
+    new.validate()?;
+    if let Some(path) = path {
+        new.save(path)?;
+    }
+    *rules = Arc::new(new);
+    Ok(Some(rules.clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewrite_then_block() {
+        let rules = Rules {
+            block: vec!["blocked.*".to_string()],
+            max_names: None,
+            rewrite: vec![RewriteRule { prefix: "old.".to_string(), replacement: "blocked.".to_string() }, RewriteRule { prefix: "legacy.".to_string(), replacement: "new.".to_string() }],
+            block_tagged: Vec::new(),
+            relabel: Vec::new(),
+        };
+        assert_eq!(rules.check(b"legacy.metric"), Verdict::Pass(Cow::Borrowed(b"new.metric")));
+        assert_eq!(rules.check(b"old.metric"), Verdict::Block);
+        assert_eq!(rules.check(b"blocked.metric"), Verdict::Block);
+        assert_eq!(rules.check(b"other.metric"), Verdict::Pass(Cow::Borrowed(b"other.metric")));
+    }
+
+    #[test]
+    fn block_tagged_names_by_base() {
+        let rules = Rules { block: vec!["foo".to_string(), "bar.*".to_string()], ..Rules::default() };
+        assert_eq!(rules.check(b"foo"), Verdict::Block);
+        assert_eq!(rules.check(b"foo;env=prod"), Verdict::Block);
+        assert_eq!(rules.check(b"bar.baz;env=prod;host=web1"), Verdict::Block);
+        assert_eq!(rules.check(b"foobar;env=foo"), Verdict::Pass(Cow::Borrowed(b"foobar;env=foo")));
+    }
+
+    #[test]
+    fn block_by_tags() {
+        let mut staging = TagRule::default();
+        staging.tags.insert("env".to_string(), "staging*".to_string());
+        let mut debug = TagRule { name: "debug.*".to_string(), ..TagRule::default() };
+        debug.tags.insert("team".to_string(), "payments".to_string());
+        let mut rules = Rules::default();
+        assert!(RulesChange::AddBlockTagged(staging.clone()).apply(&mut rules));
+        assert!(RulesChange::AddBlockTagged(debug.clone()).apply(&mut rules));
+
+        assert_eq!(rules.check(b"requests;env=staging2;host=web1"), Verdict::Block);
+        assert_eq!(rules.check(b"requests;env=prod"), Verdict::Pass(Cow::Borrowed(b"requests;env=prod")));
+        assert_eq!(rules.check(b"debug.calls;team=payments"), Verdict::Block);
+        assert_eq!(rules.check(b"calls;team=payments"), Verdict::Pass(Cow::Borrowed(b"calls;team=payments")));
+        // a tag is not matched by a value of another one
+        assert_eq!(rules.check(b"requests;host=staging"), Verdict::Pass(Cow::Borrowed(b"requests;host=staging")));
+
+        assert!(RulesChange::RemoveBlockTagged(staging.clone()).apply(&mut rules));
+        assert!(!RulesChange::RemoveBlockTagged(staging).apply(&mut rules));
+        assert_eq!(rules.block_tagged, vec![debug]);
+    }
+
+    #[test]
+    fn relabel_tags() {
+        let rule = |action, name: &str, source: &str, target: &str| RelabelRule { action, name: name.to_string(), source: source.to_string(), target: target.to_string(), value: String::new(), map: BTreeMap::new() };
+        let mut from_name = rule(RelabelAction::FromName, "servers.*.cpu.*", "", "host");
+        from_name.value = "$1".to_string();
+        let mut map = rule(RelabelAction::Map, "*", "env", "");
+        map.map.insert("production".to_string(), "prod".to_string());
+        let rules = Rules {
+            relabel: vec![rule(RelabelAction::Rename, "*", "environment", "env"), map, rule(RelabelAction::Copy, "*", "dc", "region"), rule(RelabelAction::Drop, "*", "pod", ""), from_name],
+            block_tagged: vec![TagRule { name: "*".to_string(), tags: vec![("region".to_string(), "test".to_string())].into_iter().collect() }],
+            ..Rules::default()
+        };
+        for rule in &rules.relabel {
+            assert_eq!(rule.validate(), Ok(()));
+        }
+
+        let relabeled = rules.check(b"servers.web1.cpu.user;dc=eu;environment=production;pod=web-5d8f");
+        assert_eq!(relabeled, Verdict::Pass(Cow::Owned(b"servers.web1.cpu.user;dc=eu;env=prod;host=web1;region=eu".to_vec())));
+        // relabeled tags are blocked by tag rules
+        assert_eq!(rules.check(b"requests;dc=test"), Verdict::Block);
+        // unchanged names are not copied
+        match rules.check(b"requests;env=dev") {
+            Verdict::Pass(Cow::Borrowed(name)) => assert_eq!(name, b"requests;env=dev"),
+            other => panic!("unchanged name is {:?}", other),
+        }
+
+        assert_eq!(glob_captures(b"a.*.b.?", b"a.xy.b.z"), Some(vec![&b"xy"[..], b"z"]));
+        assert_eq!(glob_captures(b"a.*", b"b.c"), None);
+        let mut bad = rule(RelabelAction::FromName, "servers.*", "", "host");
+        bad.value = "$2".to_string();
+        assert!(bad.validate().is_err());
+        assert!(rule(RelabelAction::Copy, "*", "dc", "").validate().is_err());
+
+        // invalid rules are not installed from any source
+        match change_rules(RulesChange::AddRelabel(bad.clone()), None) {
+            Err(GeneralError::Relabel(name, _)) => assert_eq!(name, "servers.*"),
+            other => panic!("adding invalid rule gave {:?}", other),
+        }
+        assert!(change_rules(RulesChange::Replace(Rules { relabel: vec![bad], ..Rules::default() }), None).is_err());
+        assert!(!RULES.read().unwrap().relabel.iter().any(|rule| rule.value == "$2"));
+        let path = std::env::temp_dir().join(format!("bioyino-rules-{}.toml", std::process::id()));
+        fs::write(&path, "[[relabel]]\naction = \"rename\"\nsource = \"env\"\n").unwrap();
+        let loaded = Rules::from_file(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        assert!(loaded.is_err());
+    }
+
+    #[test]
+    fn relabel_many_wildcards() {
+        let mut from_name = RelabelRule { action: RelabelAction::FromName, name: "*a*a*a*a*a*a*a*a*a*b".to_string(), source: String::new(), target: "x".to_string(), value: "$1".to_string(), map: BTreeMap::new() };
+        let rules = Rules { relabel: vec![from_name.clone()], ..Rules::default() };
+        // would take forever with backtracking over every star
+        let name = vec![b'a'; 10000];
+        assert_eq!(rules.check(&name), Verdict::Pass(Cow::Borrowed(&name[..])));
+
+        from_name.name = "*.*.*".to_string();
+        from_name.value = "$2".to_string();
+        assert_eq!(from_name.validate(), Ok(()));
+        let rules = Rules { relabel: vec![from_name], ..Rules::default() };
+        assert_eq!(rules.check(b"a.b.c.d;env=prod"), Verdict::Pass(Cow::Owned(b"a.b.c.d;env=prod;x=b".to_vec())));
+        assert_eq!(glob_captures(b"*.*", b"a.b.c"), Some(vec![&b"a"[..], b"b.c"]));
+    }
+}
@@ -0,0 +1,275 @@
+use std::cell::Cell;
+use std::ffi::CString;
+use std::io;
+
+use libc::c_long;
+use slog::{debug, warn, Logger};
+
+use crate::config::Sandbox;
+use crate::errors::GeneralError;
+
+// linux/landlock.h, ABI version 1
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+const ACCESS_FS_EXECUTE: u64 = 1;
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+// removing and making files, directories, devices, sockets, pipes and links
+const ACCESS_FS_CHANGE: u64 = 0b1_1111_1111_0000;
+const ACCESS_FS_ALL: u64 = ACCESS_FS_EXECUTE | ACCESS_FS_WRITE_FILE | ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR | ACCESS_FS_CHANGE;
+
+// the same for all architectures using the generic syscall table, x86_64 and aarch64 among them
+const SYS_LANDLOCK_CREATE_RULESET: c_long = 444;
+const SYS_LANDLOCK_ADD_RULE: c_long = 445;
+const SYS_LANDLOCK_RESTRICT_SELF: c_long = 446;
+
+// linux/filter.h and linux/seccomp.h
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_JEQ_K: u16 = 0x15;
+const BPF_RET_K: u16 = 0x06;
+const SECCOMP_MODE_FILTER: libc::c_int = 2;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+// lower half of the second argument, architectures we support are little-endian
+const SECCOMP_DATA_ARG1: u32 = 16 + 8;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const AUDIT_ARCH: u32 = 0;
+
+/// Syscalls threads handling untrusted input are allowed to make, others fail with EPERM.
+/// Sockets, processes and programs cannot be made, files can only be opened where Landlock allows it.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const ALLOWED_SYSCALLS: &[c_long] = &[
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_close,
+    libc::SYS_openat,
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_lseek,
+    libc::SYS_fcntl,
+    libc::SYS_brk,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_mprotect,
+    libc::SYS_madvise,
+    libc::SYS_futex,
+    libc::SYS_sched_yield,
+    libc::SYS_sched_getaffinity,
+    libc::SYS_nanosleep,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_clock_gettime,
+    libc::SYS_gettimeofday,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_rt_sigaction,
+    libc::SYS_sigaltstack,
+    libc::SYS_getrandom,
+    libc::SYS_getpid,
+    libc::SYS_gettid,
+    libc::SYS_tgkill,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    libc::SYS_epoll_create1,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    libc::SYS_eventfd2,
+    libc::SYS_pipe2,
+    libc::SYS_ppoll,
+    libc::SYS_recvfrom,
+    libc::SYS_recvmsg,
+    libc::SYS_recvmmsg,
+    libc::SYS_getsockopt,
+    // forwarding in maintenance sends on a socket made by the main thread
+    libc::SYS_sendto,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_epoll_wait,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_poll,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_open,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_stat,
+];
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const ALLOWED_SYSCALLS: &[c_long] = &[];
+
+thread_local! {
+    // supervised threads run their bodies again after panics, but restrictions are applied once
+    static SANDBOXED: Cell<bool> = Cell::new(false);
+}
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+#[repr(C)]
+struct SockFilter {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+#[repr(C)]
+struct SockFprog {
+    len: u16,
+    filter: *const SockFilter,
+}
+
+fn os_error(what: &str) -> GeneralError {
+    GeneralError::Sandbox(format!("{}: {}", what, io::Error::last_os_error()))
+}
+
+fn add_path(ruleset: libc::c_int, path: &str, access: u64) -> Result<(), GeneralError> {
+    let cpath = CString::new(path).map_err(|_| GeneralError::Sandbox(format!("bad path {:?}", path)))?;
+    let fd = unsafe { libc::open(cpath.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(os_error(&format!("opening {}", path)));
+    }
+    // rights only applying to directories cannot be given for a file
+    let is_dir = std::fs::metadata(path).map(|meta| meta.is_dir()).unwrap_or(false);
+    let access = if is_dir { access } else { access & (ACCESS_FS_EXECUTE | ACCESS_FS_WRITE_FILE | ACCESS_FS_READ_FILE) };
+    let rule = PathBeneathAttr { allowed_access: access, parent_fd: fd };
+    let res = unsafe { libc::syscall(SYS_LANDLOCK_ADD_RULE, ruleset, LANDLOCK_RULE_PATH_BENEATH, &rule as *const PathBeneathAttr, 0u32) };
+    unsafe { libc::close(fd) };
+    if res != 0 {
+        return Err(os_error(&format!("allowing {}", path)));
+    }
+    Ok(())
+}
+
+/// Deny the thread any filesystem access beside the paths in options, returns false if the kernel has no Landlock
+fn restrict_filesystem(options: &Sandbox) -> Result<bool, GeneralError> {
+    let version = unsafe { libc::syscall(SYS_LANDLOCK_CREATE_RULESET, std::ptr::null::<RulesetAttr>(), 0usize, LANDLOCK_CREATE_RULESET_VERSION) };
+    if version < 1 {
+        return Ok(false);
+    }
+    let attr = RulesetAttr { handled_access_fs: ACCESS_FS_ALL };
+    let ruleset = unsafe { libc::syscall(SYS_LANDLOCK_CREATE_RULESET, &attr as *const RulesetAttr, std::mem::size_of::<RulesetAttr>(), 0u32) } as libc::c_int;
+    if ruleset < 0 {
+        return Err(os_error("creating Landlock ruleset"));
+    }
+    let rules = options.read_paths.iter().map(|path| add_path(ruleset, path, ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR)).chain(options.write_paths.iter().map(|path| add_path(ruleset, path, ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR | ACCESS_FS_WRITE_FILE | ACCESS_FS_CHANGE))).collect::<Result<Vec<_>, _>>();
+    let res = rules.and_then(|_| if unsafe { libc::syscall(SYS_LANDLOCK_RESTRICT_SELF, ruleset, 0u32) } != 0 { Err(os_error("applying Landlock ruleset")) } else { Ok(true) });
+    unsafe { libc::close(ruleset) };
+    res
+}
+
+/// BPF program allowing only the syscalls in the list on the architecture the server is built for. `ioctl` is only
+/// allowed with FIONBIO, it is made for every socket added to event loop, including sockets of restarted threads.
+fn seccomp_program(allowed: &[c_long]) -> Vec<SockFilter> {
+    let mut program = vec![
+        SockFilter { code: BPF_LD_W_ABS, jt: 0, jf: 0, k: SECCOMP_DATA_ARCH },
+        SockFilter { code: BPF_JEQ_K, jt: 1, jf: 0, k: AUDIT_ARCH },
+        SockFilter { code: BPF_RET_K, jt: 0, jf: 0, k: SECCOMP_RET_ERRNO | libc::EPERM as u32 },
+        SockFilter { code: BPF_LD_W_ABS, jt: 0, jf: 0, k: SECCOMP_DATA_NR },
+    ];
+    for nr in allowed {
+        program.push(SockFilter { code: BPF_JEQ_K, jt: 0, jf: 1, k: *nr as u32 });
+        program.push(SockFilter { code: BPF_RET_K, jt: 0, jf: 0, k: SECCOMP_RET_ALLOW });
+    }
+    program.push(SockFilter { code: BPF_JEQ_K, jt: 0, jf: 3, k: libc::SYS_ioctl as u32 });
+    program.push(SockFilter { code: BPF_LD_W_ABS, jt: 0, jf: 0, k: SECCOMP_DATA_ARG1 });
+    program.push(SockFilter { code: BPF_JEQ_K, jt: 0, jf: 1, k: libc::FIONBIO as u32 });
+    program.push(SockFilter { code: BPF_RET_K, jt: 0, jf: 0, k: SECCOMP_RET_ALLOW });
+    program.push(SockFilter { code: BPF_RET_K, jt: 0, jf: 0, k: SECCOMP_RET_ERRNO | libc::EPERM as u32 });
+    program
+}
+
+/// Restrict the calling thread to the filesystem paths and syscalls it needs. Restrictions cannot be lifted and
+/// are inherited by threads it starts. Returns false if Landlock is not supported by the kernel, in this case
+/// only the syscalls are limited.
+pub fn sandbox_thread(options: &Sandbox) -> Result<bool, GeneralError> {
+    if ALLOWED_SYSCALLS.len() == 0 {
+        return Err(GeneralError::Sandbox("not supported on this architecture".to_string()));
+    }
+    // required for unprivileged processes to restrict themselves, also disables gaining privileges by setuid programs
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(os_error("setting no_new_privs"));
+    }
+    let landlock = restrict_filesystem(options)?;
+    let program = seccomp_program(ALLOWED_SYSCALLS);
+    let prog = SockFprog { len: program.len() as u16, filter: program.as_ptr() };
+    if unsafe { libc::prctl(libc::PR_SET_SECCOMP, SECCOMP_MODE_FILTER, &prog as *const SockFprog, 0, 0) } != 0 {
+        return Err(os_error("applying seccomp filter"));
+    }
+    Ok(landlock)
+}
+
+/// Sandbox a thread going to handle untrusted input if `sandbox.enabled` is set. Failures are logged,
+/// but do not stop the thread.
+pub fn enter_sandbox(options: &Sandbox, log: &Logger) {
+    if !options.enabled || SANDBOXED.with(|done| done.replace(true)) {
+        return;
+    }
+    match sandbox_thread(options) {
+        Ok(true) => debug!(log, "thread is sandboxed"),
+        Ok(false) => warn!(log, "kernel does not support Landlock, only syscalls of the thread are restricted"),
+        Err(e) => warn!(log, "sandboxing thread failed"; "error"=>e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    fn syscall_filter() {
+        let program = seccomp_program(&[libc::SYS_read, libc::SYS_write]);
+        assert_eq!(program.len(), 4 + 2 * 2 + 4 + 1);
+        assert_eq!(program[4].k, libc::SYS_read as u32);
+        assert_eq!(program[5].k, SECCOMP_RET_ALLOW);
+        assert_eq!(program[program.len() - 1].k, SECCOMP_RET_ERRNO | libc::EPERM as u32);
+        assert!(!ALLOWED_SYSCALLS.contains(&libc::SYS_execve));
+        assert!(!ALLOWED_SYSCALLS.contains(&libc::SYS_socket));
+        assert!(!ALLOWED_SYSCALLS.contains(&libc::SYS_ioctl));
+    }
+
+    #[test]
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    fn sandboxed_thread_registers_socket() {
+        use std::os::unix::io::AsRawFd;
+        use futures::future::lazy;
+        use tokio::net::UdpSocket;
+        use tokio::reactor::Handle;
+        use tokio::runtime::current_thread::Runtime;
+
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let options = Sandbox { enabled: true, read_paths: Vec::new(), write_paths: Vec::new() };
+        // restrictions only apply to the thread, so the test runner is not affected
+        std::thread::spawn(move || {
+            let mut runtime = Runtime::new().unwrap();
+            sandbox_thread(&options).unwrap();
+            // the same as a restarted network thread does
+            let registered = runtime.block_on(lazy(|| UdpSocket::from_std(socket.try_clone().unwrap(), &Handle::default()))).unwrap();
+            drop(registered);
+            std::fs::metadata(".").unwrap();
+            assert!(std::net::UdpSocket::bind("127.0.0.1:0").is_err());
+            // any ioctl but FIONBIO is denied
+            let mut pending: libc::c_int = 0;
+            assert_eq!(unsafe { libc::ioctl(socket.as_raw_fd(), libc::FIONREAD, &mut pending) }, -1);
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -4,24 +4,35 @@ use std::hash::{Hash, Hasher};
 use std::net::SocketAddr;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Instant;
 
 use bytes::{BufMut, BytesMut};
-use futures::sync::mpsc;
-use futures::{Future, IntoFuture, Sink};
-use tokio::executor::current_thread::spawn;
-use tokio::net::UdpSocket;
+use futures::Future;
+use futures03::compat::Future01CompatExt;
+use tokio1::net::UdpSocket;
+use tokio1::task::spawn_local;
 
-use crate::{DROPS, INGRESS};
+use crate::{DROPS, INGESTION_PAUSED, INGRESS, PAUSED_DROPS, SHED_DROPS};
+use crate::acl::STATSD_SOURCES;
 use crate::config::System;
+use crate::handoff::HANDED_OFF;
+use crate::maintenance::forward;
+use crate::memory::shedding;
+use crate::queue::send_task;
+use crate::signing::signed_payload;
+use crate::stats::STATSD_UDP;
 use crate::task::Task;
+use crate::trace::receive_span;
+use crate::workers::WorkerSet;
 
+/// Statsd UDP listener running on tokio 1.x. It must be run on a `LocalSet`, because
+/// buffers are sent to workers' futures 0.1 channels by tasks spawned on the same thread.
 #[derive(Debug)]
 pub struct StatsdServer {
     socket: UdpSocket,
-    chans: Vec<mpsc::Sender<Task>>,
-    bufmap: HashMap<SocketAddr, BytesMut>,
+    chans: WorkerSet,
+    bufmap: HashMap<SocketAddr, (Instant, BytesMut)>,
     config: Arc<System>,
-    bufsize: usize,
     recv_counter: usize,
     next: usize,
     readbuf: BytesMut,
@@ -32,10 +43,9 @@ pub struct StatsdServer {
 impl StatsdServer {
     pub(crate) fn new(
         socket: UdpSocket,
-        chans: Vec<mpsc::Sender<Task>>,
-        bufmap: HashMap<SocketAddr, BytesMut>,
+        chans: WorkerSet,
+        bufmap: HashMap<SocketAddr, (Instant, BytesMut)>,
         config: Arc<System>,
-        bufsize: usize,
         recv_counter: usize,
         next: usize,
         readbuf: BytesMut,
@@ -47,7 +57,6 @@ impl StatsdServer {
             chans,
             bufmap,
             config,
-            bufsize,
             recv_counter,
             next,
             readbuf,
@@ -55,117 +64,143 @@ impl StatsdServer {
             thread_idx,
         }
     }
-}
-
-impl IntoFuture for StatsdServer {
-    type Item = ();
-    type Error = ();
-    type Future = Box<Future<Item = Self::Item, Error = ()>>;
 
-    fn into_future(self) -> Self::Future {
+    /// Receive packets until the socket fails. Packets are collected into per-source buffers
+    /// sent to workers when enough data is received or a flush is requested.
+    pub(crate) async fn run(self) {
         let Self {
             socket,
-            chans,
+            mut chans,
             mut bufmap,
             config,
-            bufsize,
             mut recv_counter,
             mut next,
-            readbuf,
+            mut readbuf,
             flush_flags,
             thread_idx,
         } = self;
 
-        let future = socket
-            .recv_dgram(readbuf)
-            .map_err(|e| println!("error receiving UDP packet {:?}", e))
-            .and_then(move |(socket, received, size, addr)| {
-                INGRESS.fetch_add(1, Ordering::Relaxed);
-                if size == 0 {
-                    return Ok(());
+        loop {
+            let (size, addr) = match socket.recv_from(&mut readbuf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    println!("error receiving UDP packet {:?}", e);
+                    return;
                 }
+            };
+            INGRESS.add(1);
+            STATSD_UDP.packets.add(1);
+            if size == 0 {
+                continue;
+            }
 
-                {
-                    let buf = bufmap
-                        .entry(addr)
-                        .or_insert(BytesMut::with_capacity(config.network.buffer_flush_length));
-                    recv_counter += size;
-                    // check we can fit the buffer
-                    if buf.remaining_mut() < size {
-                        buf.reserve(size + 1)
-                    }
-
-                    buf.put(&received[0..size]);
+            let payload = if STATSD_SOURCES.allows(addr.ip()) { signed_payload(&readbuf[0..size]) } else { None };
+            if payload.is_none() {
+                STATSD_UDP.drops.add(1);
+            } else if forward(&readbuf[0..size]) {
+                // handled by the sibling while this node is in maintenance
+            } else if INGESTION_PAUSED.load(Ordering::Relaxed) {
+                PAUSED_DROPS.add(1);
+                STATSD_UDP.drops.add(1);
+            } else if shedding() {
+                SHED_DROPS.add(1);
+                STATSD_UDP.drops.add(1);
+            } else {
+                // buffer is stamped with the time of the first packet to measure ingestion latency
+                let (_, ref mut buf) = *bufmap
+                    .entry(addr)
+                    .or_insert_with(|| (Instant::now(), BytesMut::with_capacity(config.network.buffer_flush_length)));
+                let size = payload.unwrap_or(0);
+                recv_counter += size;
+                // check we can fit the buffer
+                if buf.remaining_mut() < size {
+                    buf.reserve(size + 1)
                 }
 
-                let flush = flush_flags
-                    .get(thread_idx)
-                    .unwrap()
-                    .swap(false, Ordering::SeqCst);
+                buf.put(&readbuf[0..size]);
+            }
 
-                if recv_counter >= config.network.buffer_flush_length || flush {
-                    bufmap
-                        .drain()
-                        .map(|(addr, buf)| {
-                            let mut hasher = DefaultHasher::new();
-                            addr.hash(&mut hasher);
-                            let ahash = hasher.finish();
-                            let chan = if config.metrics.consistent_parsing {
-                                let chlen = chans.len();
-                                chans[ahash as usize % chlen].clone()
-                            } else {
-                                if next >= chans.len() {
-                                    next = 0;
-                                }
-                                let chan = chans[next].clone();
-                                next = next + 1;
-                                chan
-                            };
+            let flush = flush_flags
+                .get(thread_idx)
+                .unwrap()
+                .swap(false, Ordering::SeqCst);
+            // the socket is read by the new process, so buffers are sent for the last time and it is not read again
+            let handed_off = HANDED_OFF.load(Ordering::Relaxed);
 
-                            spawn(
-                                chan.send(Task::Parse(ahash, buf))
-                                .map_err(|_| {
-                                    DROPS.fetch_add(1, Ordering::Relaxed);
-                                })
-                                .map(|_| ()),
-                                )
-                        })
-                    .last();
+            if recv_counter >= config.network.buffer_flush_length || flush || handed_off {
+                // workers may be added or removed at runtime
+                chans.refresh();
+                bufmap
+                    .drain()
+                    .map(|(addr, (received, buf))| {
+                        let mut hasher = DefaultHasher::new();
+                        addr.hash(&mut hasher);
+                        let ahash = hasher.finish();
+                        let worker = if config.metrics.consistent_parsing {
+                            ahash as usize % chans.len()
+                        } else {
+                            if next >= chans.len() {
+                                next = 0;
+                            }
+                            next = next + 1;
+                            next - 1
+                        };
 
-                    spawn(
-                        StatsdServer::new(
-                            socket,
-                            chans,
-                            bufmap,
-                            config,
-                            bufsize,
-                            0,
-                            next,
-                            received,
-                            flush_flags,
-                            thread_idx,
-                            )
-                        .into_future(),
-                        );
-                } else {
-                    spawn(
-                        StatsdServer::new(
-                            socket,
-                            chans,
-                            bufmap,
-                            config,
-                            bufsize,
-                            recv_counter,
-                            next,
-                            received,
-                            flush_flags,
-                            thread_idx,
-                            )
-                        .into_future(),
-                        );
+                        let trace = receive_span(received, buf.len(), worker);
+                        // receiving is not blocked by a full worker queue
+                        spawn_local(
+                            send_task(chans[worker].clone(), worker, Task::Parse(ahash, buf, received, trace))
+                            .map_err(|_| {
+                                DROPS.add(1);
+                                STATSD_UDP.drops.add(1);
+                            })
+                            .compat(),
+                            );
+                    })
+                .last();
+                if handed_off {
+                    return;
                 }
-                Ok(())
-            });
-        Box::new(future)
+                recv_counter = 0;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::sync::mpsc;
+    use futures::Stream;
+    use tokio1::runtime::Builder;
+    use tokio1::task::LocalSet;
+
+    #[test]
+    fn buffers_sent_to_workers() {
+        let runtime = Builder::new_current_thread().enable_io().build().unwrap();
+        let local = LocalSet::new();
+        let (tx, rx) = mpsc::channel(4);
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        socket.set_nonblocking(true).unwrap();
+        let socket = {
+            let _runtime = runtime.enter();
+            UdpSocket::from_std(socket).unwrap()
+        };
+        let mut readbuf = BytesMut::with_capacity(1500);
+        unsafe { readbuf.set_len(1500) }
+        // flush is requested, so the first packet is sent to the worker right away
+        let flush_flags = Arc::new(vec![AtomicBool::new(true)]);
+        let server = StatsdServer::new(socket, WorkerSet::for_ingestion(vec![tx]), HashMap::new(), Arc::new(System::default()), 0, 0, readbuf, flush_flags, 0);
+        local.spawn_local(server.run());
+
+        let client = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.send_to(b"server.counter:1|c\n", addr).unwrap();
+        let (task, _) = local.block_on(&runtime, rx.into_future().compat()).map_err(|_| ()).unwrap();
+        match task {
+            Some(Task::Parse(_, buf, _, _)) => assert_eq!(&buf[..], &b"server.counter:1|c\n"[..]),
+            _ => panic!("buffer was not sent to the worker"),
+        }
     }
 }
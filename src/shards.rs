@@ -0,0 +1,176 @@
+use std::env;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::process::Command;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+use slog::{info, o, warn, Logger};
+
+use crate::config::System;
+use crate::events::event;
+use crate::shutdown::SHUTTING_DOWN;
+use crate::ConsensusKind;
+
+/// Environment variable shard processes get their index in, it is taken as `shards.index` override
+pub const SHARD_INDEX_VAR: &str = "BIOYINO_SHARDS__INDEX";
+
+// a shard exiting sooner than this after start is restarted with a delay, so a broken one does not spin
+const RESTART_DELAY: u64 = 5000;
+
+lazy_static! {
+    // process ids of running shards by index, 0 for ones being restarted
+    static ref SHARDS: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+}
+
+fn shift_port(addr: &mut SocketAddr, index: usize, step: u16) {
+    let port = addr.port() as usize + index * step as usize;
+    // ports out of range are reported by the configuration check
+    addr.set_port(port.min(u16::max_value() as usize) as u16);
+}
+
+/// Peer address of the first shard for the others to send snapshots to
+pub fn first_shard_peer(system: &System) -> SocketAddr {
+    let mut addr = system.network.peer_listen;
+    if addr.ip().is_unspecified() {
+        addr.set_ip(if addr.is_ipv4() { IpAddr::V4(Ipv4Addr::LOCALHOST) } else { IpAddr::V6(Ipv6Addr::LOCALHOST) });
+    }
+    addr
+}
+
+/// Make the configuration of shard `shards.index` out of the common one. Every shard gets it's own peer and management
+/// port, a `shard` tag in own stats and a share of the memory budget. Shards beside the first one never lead: they take
+/// no part in consensus and send snapshots to the first shard and to `network.nodes`, and files they write get
+/// the shard index as suffix. Nothing is changed with a single shard.
+pub fn apply_shard(system: &mut System) {
+    let (count, index, step) = (system.shards.count, system.shards.index, system.shards.port_step);
+    if count <= 1 {
+        return;
+    }
+    let first = first_shard_peer(system);
+    shift_port(&mut system.network.peer_listen, index, step);
+    shift_port(&mut system.network.mgmt_listen, index, step);
+    system.stats_tags.insert("shard".to_string(), index.to_string());
+    system.memory.budget /= count;
+    system.memory.limit_ratio /= count as f32;
+    if index == 0 {
+        return;
+    }
+
+    system.consensus = ConsensusKind::None;
+    system.start_as_leader = false;
+    system.network.nodes.push(first.to_string());
+    let suffix = |path: &mut Option<String>| {
+        if let Some(path) = path.as_mut() {
+            path.push_str(&format!(".{}", index));
+        }
+    };
+    suffix(&mut system.shutdown.state_file);
+    suffix(&mut system.shutdown.handoff_socket);
+    suffix(&mut system.carbon.spool_file);
+    suffix(&mut system.carbon.flush_mark_file);
+    suffix(&mut system.management.state_report_file);
+    suffix(&mut system.log.file);
+}
+
+/// CPUs of a pinning list taken by the shard, the list is split into equal parts in order
+pub fn shard_cpus(cpus: &[usize], index: usize, count: usize) -> Vec<usize> {
+    if count <= 1 || cpus.len() < count {
+        return cpus.to_vec();
+    }
+    let part = cpus.len() / count;
+    cpus[index * part..(index + 1) * part].to_vec()
+}
+
+fn spawn_shard(index: usize) -> std::io::Result<std::process::Child> {
+    let mut args = env::args_os();
+    let program = args.next().ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "no program name in arguments"))?;
+    // shards are not main processes of the service, the first one reports to service manager for all of them
+    Command::new(program).args(args).env(SHARD_INDEX_VAR, index.to_string()).env_remove("WATCHDOG_PID").env_remove("NOTIFY_SOCKET").spawn()
+}
+
+/// Start processes of shards beside the first one, which is this process. Every shard is watched in it's own thread
+/// and started again if it exits before shutdown.
+pub fn start_shards(count: usize, log: &Logger) {
+    let log = log.new(o!("source"=>"shards"));
+    *SHARDS.lock().unwrap() = vec![0; count];
+    for index in 1..count {
+        let log = log.clone();
+        let spawned = thread::Builder::new().name(format!("bioyino_shard{}", index)).spawn(move || loop {
+            let started = Instant::now();
+            match spawn_shard(index) {
+                Ok(mut child) => {
+                    SHARDS.lock().unwrap()[index] = child.id();
+                    info!(log, "shard started"; "shard"=>index, "pid"=>child.id());
+                    let status = child.wait().map(|status| status.to_string()).unwrap_or_else(|e| e.to_string());
+                    SHARDS.lock().unwrap()[index] = 0;
+                    if SHUTTING_DOWN.load(Ordering::SeqCst) {
+                        info!(log, "shard stopped"; "shard"=>index, "status"=>status);
+                        return;
+                    }
+                    warn!(log, "shard exited, starting it again"; "shard"=>index, "status"=>&status);
+                    event("shards", format!("shard {} exited with {}, starting it again", index, status));
+                }
+                Err(e) => warn!(log, "starting shard"; "shard"=>index, "error"=>e.to_string()),
+            }
+            if started.elapsed() < Duration::from_millis(RESTART_DELAY) {
+                thread::sleep(Duration::from_millis(RESTART_DELAY));
+            }
+            if SHUTTING_DOWN.load(Ordering::SeqCst) {
+                return;
+            }
+        });
+        if let Err(e) = spawned {
+            warn!(log, "starting shard watcher"; "shard"=>index, "error"=>e.to_string());
+        }
+    }
+}
+
+/// Send a signal to all running shards started by this process
+pub fn signal_shards(signal: libc::c_int) {
+    for pid in SHARDS.lock().unwrap().iter().filter(|pid| **pid > 0) {
+        unsafe { libc::kill(*pid as libc::pid_t, signal) };
+    }
+}
+
+/// Make a shard process stop when the first shard exits, returns false if it has exited already
+pub fn follow_first_shard() -> bool {
+    unsafe {
+        libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM);
+        // the first shard could exit before the signal was asked for
+        libc::getppid() != 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shard_configs() {
+        let mut system = System::default();
+        system.shards.count = 4;
+        system.shards.index = 2;
+        system.memory.budget = 4000;
+        system.shutdown.state_file = Some("/var/lib/bioyino/state".to_string());
+        let (peer, mgmt) = (system.network.peer_listen.port(), system.network.mgmt_listen.port());
+        let first = first_shard_peer(&system);
+        apply_shard(&mut system);
+        assert_eq!(system.network.peer_listen.port(), peer + 200);
+        assert_eq!(system.network.mgmt_listen.port(), mgmt + 200);
+        assert_eq!(system.memory.budget, 1000);
+        assert_eq!(system.consensus, ConsensusKind::None);
+        assert_eq!(system.network.nodes.last(), Some(&first.to_string()));
+        assert_eq!(system.shutdown.state_file, Some("/var/lib/bioyino/state.2".to_string()));
+        assert_eq!(system.stats_tags.get("shard"), Some(&"2".to_string()));
+
+        let mut single = System::default();
+        apply_shard(&mut single);
+        assert_eq!(single.network.peer_listen, System::default().network.peer_listen);
+
+        assert_eq!(shard_cpus(&[0, 1, 2, 3, 4, 5, 6, 7], 1, 4), vec![2, 3]);
+        assert_eq!(shard_cpus(&[0, 1], 1, 4), vec![0, 1]);
+    }
+}
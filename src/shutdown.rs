@@ -0,0 +1,356 @@
+use std::fs;
+use std::os::unix::net::UnixStream;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc as std_mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use bytes::Bytes;
+use futures::future::{join_all, Future};
+use futures::sync::mpsc::Sender;
+use futures::sync::oneshot;
+use futures::Sink;
+use slog::{info, o, warn, Logger};
+use tokio::runtime::current_thread::Runtime;
+use tokio::timer::Timeout;
+
+use crate::carbon::flush_to_carbon;
+use crate::config::Shutdown;
+use crate::errors::GeneralError;
+use crate::daemon::remove_pid_file;
+use crate::events::event;
+use crate::handoff::{hand_state, HANDED_OFF};
+use crate::intern::pin;
+use crate::notify::sd_notify;
+use crate::peer::{decode_message, serialize_snapshot};
+use crate::shards::signal_shards;
+use crate::task::Task;
+use crate::{Cache, ConsensusState, CONSENSUS_STATE, INGESTION_PAUSED, IS_LEADER, STATSD_LISTENING};
+
+// metrics restored from state file are sent to workers in batches of this size
+const RESTORE_BATCH: usize = 10000;
+
+/// Set when shutdown is started, the second signal exits right away
+pub static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// A future resolved when every worker has run all the tasks queued before it was made
+pub fn drain_workers(chans: &[Sender<Task>]) -> impl Future<Item = (), Error = ()> {
+    let pings = chans
+        .iter()
+        .map(|chan| {
+            let (tx, rx) = oneshot::channel();
+            // queues are FIFO, so the answer comes after the tasks sent before
+            chan.clone().send(Task::Ping(tx)).map_err(|_| ()).and_then(|_| rx.map_err(|_| ()))
+        })
+        .collect::<Vec<_>>();
+    join_all(pings).map(|_| ())
+}
+
+/// Take caches of the whole interval out of workers and serialize them as a peer snapshot message,
+/// returns the message with the number of metrics in it
+pub fn take_state(chans: &[Sender<Task>], runtime: &mut Runtime, timeout: Duration) -> Result<(Bytes, usize), String> {
+    let _pin = pin();
+    let rotations = chans
+        .iter()
+        .map(|chan| {
+            let (short_tx, short_rx) = oneshot::channel();
+            let (tx, rx) = oneshot::channel();
+            // metrics not sent to peers with a snapshot yet are taken too, nobody else has them
+            chan.clone()
+                .send(Task::TakeSnapshot(short_tx))
+                .and_then(move |chan| chan.send(Task::Rotate(None, tx)))
+                .map_err(|_| ())
+                .and_then(|_| short_rx.join(rx).map(|(_, caches)| caches).map_err(|_| ()))
+        })
+        .collect::<Vec<_>>();
+    let caches = runtime.block_on(Timeout::new(join_all(rotations), timeout)).map_err(|_| "caches not taken from workers in time".to_string())?;
+    serialize_caches(caches)
+}
+
+/// Take metrics not sent to peers yet out of workers and serialize them as a peer snapshot message, returns
+/// the message with the number of metrics in it. Workers keep the metrics in caches of the interval.
+pub fn take_snapshot(chans: &[Sender<Task>], runtime: &mut Runtime, timeout: Duration) -> Result<(Bytes, usize), String> {
+    let _pin = pin();
+    let snapshots = chans
+        .iter()
+        .map(|chan| {
+            let (tx, rx) = oneshot::channel();
+            chan.clone().send(Task::TakeSnapshot(tx)).map_err(|_| ()).and_then(|_| rx.map_err(|_| ()))
+        })
+        .collect::<Vec<_>>();
+    let caches = runtime.block_on(Timeout::new(join_all(snapshots), timeout)).map_err(|_| "snapshots not taken from workers in time".to_string())?;
+    serialize_caches(caches)
+}
+
+// names must be pinned by the caller from before the caches are taken
+fn serialize_caches(caches: Vec<Vec<Cache>>) -> Result<(Bytes, usize), String> {
+    let caches = caches.into_iter().flat_map(|shards| shards.into_iter()).collect::<Vec<Cache>>();
+    let metrics = caches.iter().map(|cache| cache.len()).sum();
+    let snapshot = serialize_snapshot(&caches).map_err(|e| e.to_string())?;
+    Ok((snapshot, metrics))
+}
+
+/// Take caches out of workers and write them to `path`. The file is written next to it first and renamed,
+/// so a partially written one is never read.
+pub fn save_state(path: &str, chans: &[Sender<Task>], runtime: &mut Runtime, timeout: Duration) -> Result<usize, GeneralError> {
+    let state_error = |e: String| GeneralError::State(path.to_string(), e);
+    let (snapshot, metrics) = take_state(chans, runtime, timeout).map_err(state_error)?;
+    let tmp = format!("{}.tmp", path);
+    fs::write(&tmp, &snapshot).and_then(|_| fs::rename(&tmp, path)).map_err(|e| state_error(e.to_string()))?;
+    Ok(metrics)
+}
+
+/// Send metrics saved on shutdown to workers to be aggregated with the first interval. The file is removed,
+/// so it is never restored twice, and ignored if it is older than `max_age`, because the interval it was
+/// taken from has been sent by another node already. Returns the number of metrics restored.
+pub fn restore_state(path: &str, max_age: Duration, chans: &[Sender<Task>], log: &Logger) -> Result<usize, GeneralError> {
+    let state_error = |e: String| GeneralError::State(path.to_string(), e);
+    let modified = match fs::metadata(path).and_then(|meta| meta.modified()) {
+        Ok(modified) => modified,
+        Err(_) => return Ok(0),
+    };
+    let state = fs::read(path).map_err(|e| state_error(e.to_string()))?;
+    fs::remove_file(path).map_err(|e| state_error(e.to_string()))?;
+    let age = SystemTime::now().duration_since(modified).unwrap_or_default();
+    if age > max_age {
+        warn!(log, "cache state is too old to be restored, ignored"; "file"=>path, "age-ms"=>age.as_secs() * 1000 + age.subsec_millis() as u64);
+        return Ok(0);
+    }
+    send_state(&state, chans).map_err(state_error)
+}
+
+/// Decode caches serialized by `take_state` and send them to workers, returns the number of metrics sent
+pub fn send_state(state: &[u8], chans: &[Sender<Task>]) -> Result<usize, String> {
+    let mut metrics = decode_message(state).map_err(|e| e.to_string())?;
+    let restored = metrics.len();
+    let mut next = 0;
+    while metrics.len() > 0 {
+        let rest = metrics.split_off(metrics.len().min(RESTORE_BATCH));
+        let batch = std::mem::replace(&mut metrics, rest);
+        // snapshots go to long caches, so restored metrics are not replicated to other nodes again
+        chans[next].clone().send(Task::AddSnapshot(batch)).wait().map_err(|_| "worker is gone".to_string())?;
+        next = (next + 1) % chans.len();
+    }
+    Ok(restored)
+}
+
+// non-leaders have sent their data to the leader with snapshots already, they just drop it
+fn flush_last(chans: Vec<Sender<Task>>, deadline: Instant, log: &Logger) {
+    let leader = IS_LEADER.load(Ordering::SeqCst);
+    match flush_to_carbon(chans, None, log.clone()) {
+        Ok(flush) => {
+            let (tx, rx) = std_mpsc::channel();
+            thread::spawn(move || tx.send(flush.join().is_ok()));
+            match rx.recv_timeout(remaining(deadline)) {
+                Ok(true) if leader => info!(log, "last interval sent to backend"),
+                Ok(true) => info!(log, "last interval aggregated, not sent as this node is not the leader"),
+                Ok(false) => warn!(log, "last flush failed"),
+                Err(_) => warn!(log, "last flush not finished in time, metrics not sent yet are lost"),
+            }
+        }
+        Err(e) => warn!(log, "last flush failed"; "error"=>e.to_string()),
+    }
+}
+
+fn remaining(deadline: Instant) -> Duration {
+    let now = Instant::now();
+    if now < deadline {
+        deadline - now
+    } else {
+        Duration::from_millis(0)
+    }
+}
+
+/// Stop receiving, wait for workers to parse what is queued, then pass the rest of the interval on: to the new
+/// process if it is given `handoff` connection to it, to `shutdown.state-file` if it is set or to backend if
+/// this node is the leader. Leadership is given up and the process exits. Steps not done by `shutdown.timeout`
+/// are abandoned.
+pub fn stop(options: &Shutdown, chans: Vec<Sender<Task>>, buffer_flags: Arc<Vec<AtomicBool>>, handoff: Option<UnixStream>, log: &Logger) -> ! {
+    let deadline = Instant::now() + Duration::from_millis(options.timeout);
+    // after handoff the next process is the main one already
+    if handoff.is_none() {
+        sd_notify("STOPPING=1").unwrap_or_else(|e| warn!(log, "notifying service manager"; "error"=>e.to_string()));
+    }
+    if handoff.is_some() {
+        // sockets are read by the new process already, network threads send what they have to workers and stop
+        HANDED_OFF.store(true, Ordering::SeqCst);
+    } else {
+        // anything received from now is dropped, buffers collected by network threads are sent to workers
+        INGESTION_PAUSED.store(true, Ordering::SeqCst);
+        STATSD_LISTENING.store(false, Ordering::SeqCst);
+    }
+    buffer_flags.iter().map(|flag| flag.store(true, Ordering::SeqCst)).last();
+
+    let mut runtime = match Runtime::new() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            warn!(log, "creating runtime for shutdown"; "error"=>e.to_string());
+            process::exit(1);
+        }
+    };
+    match runtime.block_on(Timeout::new(drain_workers(&chans), remaining(deadline))) {
+        Ok(()) => info!(log, "worker queues drained"),
+        Err(_) => warn!(log, "worker queues not drained in time, the rest of them is lost"),
+    }
+
+    // the interval is continued by the next process, so it is not flushed here to not be sent twice
+    match (handoff, &options.state_file) {
+        (Some(stream), _) if options.handoff_caches => match hand_state(stream, &chans, &mut runtime, remaining(deadline)) {
+            Ok(metrics) => info!(log, "caches handed off"; "metrics"=>metrics),
+            Err(e) => warn!(log, "handing caches off failed, they are lost"; "error"=>e),
+        },
+        (None, Some(path)) => match save_state(path, &chans, &mut runtime, remaining(deadline)) {
+            Ok(metrics) => info!(log, "caches saved"; "file"=>path, "metrics"=>metrics),
+            Err(e) => warn!(log, "saving caches failed, they are lost"; "error"=>e.to_string()),
+        },
+        _ => flush_last(chans, deadline, log),
+    }
+
+    // consul session is not renewed in disabled state, so other nodes can take the lock
+    *CONSENSUS_STATE.lock().unwrap() = ConsensusState::Disabled;
+    if IS_LEADER.swap(false, Ordering::SeqCst) {
+        event("leader", "leadership released on shutdown".to_string());
+        info!(log, "leadership released");
+    }
+    info!(log, "shutdown finished");
+    remove_pid_file();
+    // let the asynchronous log drain write the last records
+    thread::sleep(Duration::from_millis(100));
+    process::exit(0);
+}
+
+/// Start stopping on a signal in it's own thread, because waiting for the flush blocks
+pub fn shutdown(options: &Shutdown, chans: Vec<Sender<Task>>, buffer_flags: Arc<Vec<AtomicBool>>, signal: &str, log: &Logger) {
+    let log = log.new(o!("source"=>"shutdown"));
+    if SHUTTING_DOWN.swap(true, Ordering::SeqCst) {
+        warn!(log, "signal received again during shutdown, exiting now"; "signal"=>signal);
+        remove_pid_file();
+        process::exit(1);
+    }
+    // shards stop the same way on their own, in parallel with this one
+    signal_shards(libc::SIGTERM);
+    if !options.graceful {
+        info!(log, "exiting without flushing"; "signal"=>signal);
+        remove_pid_file();
+        process::exit(0);
+    }
+    info!(log, "shutting down"; "signal"=>signal, "timeout"=>options.timeout);
+    event("shutdown", format!("{} received, shutting down", signal));
+    let (options, thread_log) = (options.clone(), log.clone());
+    let spawned = thread::Builder::new().name("bioyino_shutdown".into()).spawn(move || stop(&options, chans, buffer_flags, None, &thread_log));
+    if let Err(e) = spawned {
+        warn!(log, "starting shutdown thread failed, exiting now"; "error"=>e.to_string());
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    use bytes::{Bytes, BytesMut};
+    use futures::sync::mpsc;
+    use futures::Stream;
+
+    use bioyino_metric::{Metric, MetricType};
+
+    use crate::config::System;
+    use crate::task::TaskRunner;
+    use crate::util::prepare_log;
+
+    #[test]
+    fn state_taken_with_short_cache() {
+        let mut runtime = Runtime::new().unwrap();
+        let (tx, rx) = mpsc::channel(16);
+        let mut runner = TaskRunner::new(prepare_log("state_taken_with_short_cache"), Arc::new(System::default()), 16);
+        runtime.spawn(rx.for_each(move |task| {
+            runner.run(task);
+            Ok(())
+        }));
+        let chans = vec![tx];
+        // the first metric goes to long cache with the snapshot, the second one is still waiting for the next one
+        let data = BytesMut::from(&b"state.snapshotted.counter:1|c\n"[..]);
+        runtime.block_on(chans[0].clone().send(Task::Parse(1, data, Instant::now(), None))).unwrap();
+        let (snapshot_tx, snapshot_rx) = oneshot::channel();
+        runtime.block_on(chans[0].clone().send(Task::TakeSnapshot(snapshot_tx))).unwrap();
+        assert_eq!(runtime.block_on(snapshot_rx).unwrap().iter().map(|shard| shard.len()).sum::<usize>(), 1);
+        let data = BytesMut::from(&b"state.short.counter:2|c\n"[..]);
+        runtime.block_on(chans[0].clone().send(Task::Parse(1, data, Instant::now(), None))).unwrap();
+
+        let path = std::env::temp_dir().join(format!("bioyino-state-short-{}.capnp", process::id()));
+        let path = path.to_str().unwrap();
+        assert_eq!(save_state(path, &chans, &mut runtime, Duration::from_secs(5)).unwrap(), 2);
+        let state = fs::read(path).unwrap();
+        fs::remove_file(path).unwrap();
+        let mut names = decode_message(&state).unwrap().into_iter().map(|(name, _)| name).collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, vec![Bytes::from("state.short.counter"), Bytes::from("state.snapshotted.counter")]);
+    }
+
+    #[test]
+    fn last_interval_flushed() {
+        let log = prepare_log("last_interval_flushed");
+        // backend address of the default configuration
+        let backend = TcpListener::bind("127.0.0.1:2003").unwrap();
+        let (tx, rx) = mpsc::channel(16);
+        thread::spawn(move || {
+            let mut runner = TaskRunner::new(prepare_log("last_interval_worker"), Arc::new(System::default()), 16);
+            Runtime::new().unwrap().block_on(rx.for_each(move |task| {
+                runner.run(task);
+                Ok(())
+            }))
+        });
+        let chans = vec![tx];
+        let data = BytesMut::from(&b"last.interval.counter:1|c\nlast.interval.counter:2|c\n"[..]);
+        chans[0].clone().send(Task::Parse(1, data, Instant::now(), None)).wait().unwrap();
+
+        // metrics queued before shutdown are parsed before the last interval is taken
+        drain_workers(&chans).wait().unwrap();
+        IS_LEADER.store(true, Ordering::SeqCst);
+        flush_last(chans, Instant::now() + Duration::from_secs(10), &log);
+
+        // the flush is finished, so the connection is waiting already, if there is one
+        backend.set_nonblocking(true).unwrap();
+        let (mut conn, _) = backend.accept().expect("backend connection");
+        conn.set_nonblocking(false).unwrap();
+        conn.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        let mut sent = Vec::new();
+        conn.read_to_end(&mut sent).unwrap_or(0);
+        let sent = String::from_utf8_lossy(&sent);
+        let counter_sent = sent.lines().any(|line| {
+            let mut parts = line.split(' ');
+            parts.next().map_or(false, |name| name.starts_with("last.interval.counter")) && parts.next().and_then(|value| value.parse::<f64>().ok()) == Some(3f64)
+        });
+        assert!(counter_sent, "counter not sent: {:?}", sent);
+    }
+
+    #[test]
+    fn restored_state() {
+        let log = prepare_log("restored_state");
+        let path = std::env::temp_dir().join(format!("bioyino-state-{}.capnp", process::id()));
+        let path = path.to_str().unwrap();
+        let (tx, rx) = mpsc::channel(4);
+        assert_eq!(restore_state(path, Duration::from_secs(60), &[tx.clone()], &log).unwrap(), 0);
+
+        let metric = Metric::new(1f64, MetricType::Counter, None, None).unwrap();
+        let mut cache = Cache::new();
+        cache.insert(crate::intern::intern(b"restored.state.metric"), metric.clone());
+        fs::write(path, serialize_snapshot(&[cache]).unwrap()).unwrap();
+        assert_eq!(restore_state(path, Duration::from_secs(60), &[tx.clone()], &log).unwrap(), 1);
+        // the file is never restored twice
+        assert!(fs::metadata(path).is_err());
+        match rx.wait().next() {
+            Some(Ok(Task::AddSnapshot(metrics))) => assert_eq!(metrics, vec![(Bytes::from("restored.state.metric"), metric)]),
+            task => panic!("unexpected task {:?}", task),
+        }
+
+        fs::write(path, b"stale").unwrap();
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(restore_state(path, Duration::from_millis(0), &[tx], &log).unwrap(), 0);
+        assert!(fs::metadata(path).is_err());
+    }
+}
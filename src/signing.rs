@@ -0,0 +1,155 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::RwLock;
+
+use lazy_static::lazy_static;
+use ring::{constant_time, digest, hmac};
+
+use crate::cluster::now_ms;
+use crate::config::Network;
+use crate::errors::GeneralError;
+use crate::{BAD_SIGNATURE_DROPS, EXPIRED_DROPS, UNSIGNED_DROPS};
+
+/// Start of the signature line, the last line of a signed packet
+pub const SIGNATURE_PREFIX: &[u8] = b"#hmac:";
+
+// length of hex encoded HMAC-SHA256
+const SIGNATURE_LEN: usize = 64;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static MAX_AGE: AtomicUsize = AtomicUsize::new(30000);
+
+lazy_static! {
+    static ref KEY: RwLock<Option<hmac::SigningKey>> = RwLock::new(None);
+}
+
+/// Why a packet was not accepted in signed mode
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rejected {
+    /// There is no signature line
+    Unsigned,
+    /// Timestamp is too far from the current time, in either direction
+    Expired,
+    /// Signature is malformed or does not match the payload
+    BadSignature,
+}
+
+/// Require statsd packets to be signed with `network.statsd-hmac-key`, must be called before network threads are started
+pub fn init_signing(network: &Network) -> Result<(), GeneralError> {
+    match network.statsd_hmac_key {
+        Some(ref key) if key.len() > 0 => {
+            *KEY.write().unwrap() = Some(hmac::SigningKey::new(&digest::SHA256, key.as_bytes()));
+            MAX_AGE.store(network.statsd_hmac_max_age as usize, Ordering::Relaxed);
+            ENABLED.store(true, Ordering::Relaxed);
+        }
+        Some(_) => return Err(GeneralError::Configuration("network.statsd-hmac-key cannot be empty")),
+        None => ENABLED.store(false, Ordering::Relaxed),
+    }
+    Ok(())
+}
+
+fn signature(key: &hmac::SigningKey, payload: &[u8], timestamp: &[u8]) -> hmac::Signature {
+    let mut context = hmac::SigningContext::with_key(key);
+    context.update(payload);
+    context.update(timestamp);
+    context.sign()
+}
+
+fn unhex(hex: &[u8]) -> Option<Vec<u8>> {
+    let digit = |c: u8| (c as char).to_digit(16).map(|d| d as u8);
+    hex.chunks(2).map(|pair| if pair.len() == 2 { Some(digit(pair[0])? << 4 | digit(pair[1])?) } else { None }).collect()
+}
+
+/// Append the signature line to the payload, the way clients sign packets. Payload must end with a newline
+/// unless it is empty. `timestamp` is in seconds since the UNIX epoch.
+pub fn sign_packet(key: &[u8], payload: &[u8], timestamp: u64) -> Vec<u8> {
+    let key = hmac::SigningKey::new(&digest::SHA256, key);
+    let timestamp = timestamp.to_string();
+    let mut packet = Vec::with_capacity(payload.len() + SIGNATURE_PREFIX.len() + timestamp.len() + SIGNATURE_LEN + 1);
+    packet.extend_from_slice(payload);
+    packet.extend_from_slice(SIGNATURE_PREFIX);
+    packet.extend_from_slice(timestamp.as_bytes());
+    packet.push(b':');
+    for byte in signature(&key, payload, timestamp.as_bytes()).as_ref() {
+        packet.extend_from_slice(format!("{:02x}", byte).as_bytes());
+    }
+    packet
+}
+
+/// Verify the signature line `#hmac:<timestamp>:<hex HMAC-SHA256 of payload followed by timestamp>`
+/// ending the packet, returning the length of payload before it
+pub fn verify_packet(key: &hmac::SigningKey, packet: &[u8], now_ms: u64, max_age: u64) -> Result<usize, Rejected> {
+    let packet = if packet.ends_with(b"\n") { &packet[..packet.len() - 1] } else { packet };
+    let start = packet.iter().rposition(|c| *c == b'\n').map(|pos| pos + 1).unwrap_or(0);
+    let line = &packet[start..];
+    if !line.starts_with(SIGNATURE_PREFIX) {
+        return Err(Rejected::Unsigned);
+    }
+    let mut fields = line[SIGNATURE_PREFIX.len()..].splitn(2, |c| *c == b':');
+    let timestamp = fields.next().unwrap_or_default();
+    let hex = fields.next().ok_or(Rejected::BadSignature)?;
+    let seconds = std::str::from_utf8(timestamp).ok().and_then(|ts| ts.parse::<u64>().ok()).ok_or(Rejected::BadSignature)?;
+    let expected = unhex(hex).ok_or(Rejected::BadSignature)?;
+    // signature is checked before the time, so forged timestamps are not reported as expired
+    constant_time::verify_slices_are_equal(signature(key, &packet[..start], timestamp).as_ref(), &expected).map_err(|_| Rejected::BadSignature)?;
+    let at = seconds.saturating_mul(1000);
+    if at.max(now_ms) - at.min(now_ms) > max_age {
+        return Err(Rejected::Expired);
+    }
+    Ok(start)
+}
+
+/// Length of the statsd packet payload to accept, the whole packet if signing is disabled.
+/// Rejected packets are counted and `None` is returned, so they are dropped.
+pub fn signed_payload(packet: &[u8]) -> Option<usize> {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return Some(packet.len());
+    }
+    let key = KEY.read().unwrap();
+    let key = key.as_ref()?;
+    match verify_packet(key, packet, now_ms(), MAX_AGE.load(Ordering::Relaxed) as u64) {
+        Ok(len) => Some(len),
+        Err(Rejected::Unsigned) => {
+            UNSIGNED_DROPS.add(1);
+            None
+        }
+        Err(Rejected::Expired) => {
+            EXPIRED_DROPS.add(1);
+            None
+        }
+        Err(Rejected::BadSignature) => {
+            BAD_SIGNATURE_DROPS.add(1);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signed_packets() {
+        let key = hmac::SigningKey::new(&digest::SHA256, b"secret");
+        let payload = b"some.counter:1|c\nsome.timer:12|ms\n";
+        let packet = sign_packet(b"secret", payload, 1_500_000_000);
+        assert_eq!(verify_packet(&key, &packet, 1_500_000_010_000, 30000), Ok(payload.len()));
+
+        let mut with_newline = packet.clone();
+        with_newline.push(b'\n');
+        assert_eq!(verify_packet(&key, &with_newline, 1_500_000_000_000, 30000), Ok(payload.len()));
+
+        assert_eq!(verify_packet(&key, &packet, 1_500_000_040_000, 30000), Err(Rejected::Expired));
+        assert_eq!(verify_packet(&key, &packet, 1_499_999_960_000, 30000), Err(Rejected::Expired));
+        assert_eq!(verify_packet(&key, payload, 1_500_000_000_000, 30000), Err(Rejected::Unsigned));
+
+        let mut tampered = packet.clone();
+        tampered[0] = b'x';
+        assert_eq!(verify_packet(&key, &tampered, 1_500_000_000_000, 30000), Err(Rejected::BadSignature));
+        let other = sign_packet(b"other", payload, 1_500_000_000);
+        assert_eq!(verify_packet(&key, &other, 1_500_000_000_000, 30000), Err(Rejected::BadSignature));
+        assert_eq!(verify_packet(&key, b"#hmac:1500000000:zz", 1_500_000_000_000, 30000), Err(Rejected::BadSignature));
+
+        let empty = sign_packet(b"secret", b"", 1_500_000_000);
+        assert_eq!(verify_packet(&key, &empty, 1_500_000_000_000, 30000), Ok(0));
+    }
+}
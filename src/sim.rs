@@ -0,0 +1,356 @@
+//! Deterministic simulation of cluster logic for tests. Time only moves when the simulation says so,
+//! network loses messages by a seeded random generator, so every run of a test is the same.
+use std::cell::{Cell, RefCell, RefMut};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr};
+use std::rc::Rc;
+use std::time::Duration;
+
+use futures::future::{result, Future};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::cluster::{Clock, PeerTable};
+use crate::consul::{send_lease_request, ConsulError, ConsulLease, ConsulTransport};
+
+/// Node id of consul agent in simulated network
+pub const CONSUL: usize = usize::max_value();
+
+/// Simulated time in milliseconds, shared by all clones
+#[derive(Debug, Clone, Default)]
+pub struct SimClock(Rc<Cell<u64>>);
+
+impl SimClock {
+    pub fn advance(&self, ms: u64) {
+        self.0.set(self.0.get() + ms);
+    }
+}
+
+impl Clock for SimClock {
+    fn now_ms(&self) -> u64 {
+        self.0.get()
+    }
+}
+
+/// Network where every message is lost with the same probability, nodes may also be cut off from others
+pub struct SimNet {
+    rng: StdRng,
+    loss: f64,
+    isolated: HashSet<usize>,
+    cut: HashSet<(usize, usize)>,
+}
+
+impl SimNet {
+    pub fn new(seed: u64) -> Self {
+        Self { rng: StdRng::seed_from_u64(seed), loss: 0f64, isolated: HashSet::new(), cut: HashSet::new() }
+    }
+
+    pub fn set_loss(&mut self, loss: f64) {
+        self.loss = loss;
+    }
+
+    /// Cut the node off from everyone, consul included
+    pub fn isolate(&mut self, node: usize) {
+        self.isolated.insert(node);
+    }
+
+    /// Break the link between two nodes in both directions
+    pub fn cut(&mut self, a: usize, b: usize) {
+        self.cut.insert((a.min(b), a.max(b)));
+    }
+
+    pub fn heal(&mut self) {
+        self.isolated.clear();
+        self.cut.clear();
+    }
+
+    /// Decide if the message sent now will be delivered
+    pub fn delivers(&mut self, from: usize, to: usize) -> bool {
+        if self.isolated.contains(&from) || self.isolated.contains(&to) || self.cut.contains(&(from.min(to), from.max(to))) {
+            return false;
+        }
+        self.loss == 0f64 || self.rng.gen::<f64>() >= self.loss
+    }
+}
+
+/// The part of consul needed for leader election: sessions expiring after ttl and a single key
+/// with lock-delay equal to session ttl, the same way consensus creates sessions
+#[derive(Debug, Default)]
+pub struct SimConsul {
+    next_id: usize,
+    // session id -> (ttl, expiration time)
+    sessions: HashMap<String, (u64, u64)>,
+    holder: Option<String>,
+    // key cannot be acquired until this time after the holding session expired
+    lock_delay_until: u64,
+}
+
+impl SimConsul {
+    fn expire(&mut self, now: u64) {
+        if let Some(holder) = self.holder.clone() {
+            match self.sessions.get(&holder) {
+                Some((ttl, expires)) if *expires <= now => {
+                    self.lock_delay_until = expires + ttl;
+                    self.holder = None;
+                }
+                _ => (),
+            }
+        }
+        self.sessions.retain(|_, (_, expires)| *expires > now);
+    }
+
+    fn create_session(&mut self, now: u64, ttl: u64) -> String {
+        self.expire(now);
+        self.next_id += 1;
+        let id = format!("session-{}", self.next_id);
+        self.sessions.insert(id.clone(), (ttl, now + ttl));
+        id
+    }
+
+    fn renew(&mut self, now: u64, session: &str) -> Result<(), ConsulError> {
+        self.expire(now);
+        let (ttl, expires) = self.sessions.get_mut(session).ok_or_else(|| ConsulError::Renew("session not found".to_string()))?;
+        *expires = now + *ttl;
+        Ok(())
+    }
+
+    fn acquire(&mut self, now: u64, session: &str) -> Result<bool, ConsulError> {
+        self.expire(now);
+        if !self.sessions.contains_key(session) {
+            return Err(ConsulError::Session("invalid session".to_string()));
+        }
+        match self.holder {
+            Some(ref holder) => Ok(holder == session),
+            None if now >= self.lock_delay_until => {
+                self.holder = Some(session.to_string());
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+pub struct SimWorld {
+    pub clock: SimClock,
+    pub net: SimNet,
+    pub consul: SimConsul,
+}
+
+/// Transport of one node to simulated consul. Answers come at once, lost requests or answers come
+/// as errors, a request may be applied by consul even if it's answer was lost.
+pub struct SimTransport {
+    node: usize,
+    world: Rc<RefCell<SimWorld>>,
+}
+
+impl SimTransport {
+    fn call<T: 'static, F: FnOnce(&mut SimConsul, u64) -> Result<T, ConsulError>>(&self, f: F) -> Box<Future<Item = T, Error = ConsulError>> {
+        let mut world = self.world.borrow_mut();
+        let world = &mut *world;
+        let now = world.clock.now_ms();
+        if !world.net.delivers(self.node, CONSUL) {
+            return Box::new(result(Err(ConsulError::ConnectionTimeout)));
+        }
+        let answer = f(&mut world.consul, now);
+        if !world.net.delivers(CONSUL, self.node) {
+            return Box::new(result(Err(ConsulError::ConnectionTimeout)));
+        }
+        Box::new(result(answer))
+    }
+}
+
+fn ms(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + duration.subsec_millis() as u64
+}
+
+impl ConsulTransport for SimTransport {
+    fn create_session(&self, ttl: Duration) -> Box<Future<Item = Option<String>, Error = ConsulError>> {
+        self.call(move |consul, now| Ok(Some(consul.create_session(now, ms(ttl)))))
+    }
+
+    fn renew(&self, session: String, _ttl: Duration) -> Box<Future<Item = (), Error = ConsulError>> {
+        self.call(move |consul, now| consul.renew(now, &session))
+    }
+
+    fn acquire(&self, session: String, _key: String) -> Box<Future<Item = bool, Error = ConsulError>> {
+        self.call(move |consul, now| consul.acquire(now, &session))
+    }
+}
+
+/// Nodes electing a leader with consul. Only leases are simulated, global leader state of the process is not touched.
+pub struct ConsulSim {
+    world: Rc<RefCell<SimWorld>>,
+    ttl: Duration,
+    nodes: Vec<(ConsulLease, SimTransport)>,
+}
+
+impl ConsulSim {
+    /// Nodes with default consul settings: session ttl of 11s renewed every second
+    pub fn new(nodes: usize, seed: u64) -> Self {
+        let world = Rc::new(RefCell::new(SimWorld { clock: SimClock::default(), net: SimNet::new(seed), consul: SimConsul::default() }));
+        let (ttl, renew_time, error_pause) = (Duration::from_millis(11000), Duration::from_millis(1000), Duration::from_millis(1000));
+        let nodes = (0..nodes).map(|node| (ConsulLease::new(ttl, renew_time, error_pause), SimTransport { node, world: world.clone() })).collect();
+        Self { world, ttl, nodes }
+    }
+
+    pub fn net(&self) -> RefMut<SimNet> {
+        RefMut::map(self.world.borrow_mut(), |world| &mut world.net)
+    }
+
+    /// Advance time and let every node do what it would do at that moment
+    pub fn step(&mut self, ms: u64) {
+        let clock = self.world.borrow().clock.clone();
+        clock.advance(ms);
+        let now = clock.now_ms();
+        for (lease, transport) in &mut self.nodes {
+            for request in lease.poll(now, true) {
+                // simulated answers are always ready
+                let answer = send_lease_request(&*transport, request, "sim", self.ttl).wait().unwrap();
+                lease.on_answer(now, &answer);
+            }
+        }
+    }
+
+    /// Nodes considering themselves leaders now
+    pub fn leaders(&self) -> Vec<usize> {
+        self.nodes.iter().enumerate().filter(|(_, (lease, _))| lease.is_leader()).map(|(node, _)| node).collect()
+    }
+}
+
+/// Nodes sending peer snapshots to each other every interval and tracking each other's liveness
+pub struct PeerSim {
+    clock: SimClock,
+    pub net: SimNet,
+    interval: u64,
+    tables: Vec<PeerTable>,
+}
+
+impl PeerSim {
+    pub fn new(nodes: usize, interval: u64, seed: u64) -> Self {
+        Self { clock: SimClock::default(), net: SimNet::new(seed), interval, tables: (0..nodes).map(|_| PeerTable::default()).collect() }
+    }
+
+    pub fn addr(node: usize) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(10, 0, 0, node as u8 + 1))
+    }
+
+    /// Let one snapshot interval pass, every node sends a snapshot to every other one at it's end
+    pub fn interval(&mut self) {
+        self.clock.advance(self.interval);
+        let now = self.clock.now_ms();
+        for from in 0..self.tables.len() {
+            for to in (0..self.tables.len()).filter(|to| *to != from) {
+                if self.net.delivers(from, to) {
+                    self.tables[from].sent(Self::addr(to), now);
+                    self.tables[to].received(Self::addr(from), now);
+                } else {
+                    self.tables[from].send_failed(Self::addr(to), now);
+                }
+            }
+        }
+    }
+
+    /// Whether `node` sees `peer` alive, the same way cluster view does
+    pub fn is_alive(&self, node: usize, peer: usize, alive_intervals: u64) -> bool {
+        let alive_after = self.clock.now_ms().saturating_sub(self.interval * alive_intervals);
+        self.tables[node].get(&Self::addr(peer)).map(|times| times.is_alive(alive_after)).unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // checks there is never more than one leader, returns the number of leader changes
+    fn run_checked(sim: &mut ConsulSim, seconds: u64) -> usize {
+        let mut changes = 0;
+        let mut leaders = sim.leaders();
+        for _ in 0..seconds * 10 {
+            sim.step(100);
+            let now = sim.leaders();
+            assert!(now.len() <= 1, "more than one leader: {:?}", now);
+            if now != leaders {
+                changes += 1;
+                leaders = now;
+            }
+        }
+        changes
+    }
+
+    #[test]
+    fn consul_leader_failover() {
+        let mut sim = ConsulSim::new(3, 1);
+        run_checked(&mut sim, 10);
+        let leaders = sim.leaders();
+        assert_eq!(leaders.len(), 1);
+
+        // the old leader steps down when it's session expires, the new one waits for lock-delay too
+        sim.net().isolate(leaders[0]);
+        run_checked(&mut sim, 40);
+        let new_leaders = sim.leaders();
+        assert_eq!(new_leaders.len(), 1);
+        assert_ne!(new_leaders, leaders);
+
+        // coming back does not take leadership away
+        sim.net().heal();
+        assert_eq!(run_checked(&mut sim, 30), 0);
+        assert_eq!(sim.leaders(), new_leaders);
+    }
+
+    #[test]
+    fn consul_lossy_network() {
+        for seed in 0..5 {
+            let mut sim = ConsulSim::new(3, seed);
+            sim.net().set_loss(0.3);
+            run_checked(&mut sim, 300);
+            sim.net().set_loss(0f64);
+            run_checked(&mut sim, 40);
+            assert_eq!(sim.leaders().len(), 1);
+        }
+    }
+
+    #[test]
+    fn consul_flapping_partitions() {
+        let mut sim = ConsulSim::new(3, 7);
+        run_checked(&mut sim, 10);
+        let mut changes = 0;
+        for round in 0..20 {
+            // every partition makes the leader lose it's session, leaders must never overlap anyway
+            let leaders = sim.leaders();
+            if let Some(leader) = leaders.first() {
+                sim.net().isolate(*leader);
+            }
+            changes += run_checked(&mut sim, if round % 2 == 0 { 3 } else { 15 });
+            sim.net().heal();
+            changes += run_checked(&mut sim, 5);
+        }
+        assert!(changes > 0);
+        run_checked(&mut sim, 40);
+        assert_eq!(sim.leaders().len(), 1);
+    }
+
+    #[test]
+    fn peer_liveness() {
+        let mut sim = PeerSim::new(3, 1000, 1);
+        for _ in 0..3 {
+            sim.interval();
+        }
+        assert!(sim.is_alive(0, 1, 3) && sim.is_alive(1, 0, 3) && sim.is_alive(0, 2, 3));
+
+        sim.net.cut(0, 1);
+        for _ in 0..4 {
+            sim.interval();
+        }
+        assert!(!sim.is_alive(0, 1, 3));
+        assert!(!sim.is_alive(1, 0, 3));
+        assert!(sim.is_alive(0, 2, 3));
+
+        // a lossy link is still alive if some snapshots get through during the window
+        sim.net.heal();
+        sim.net.set_loss(0.2);
+        for _ in 0..10 {
+            sim.interval();
+        }
+        assert!(sim.is_alive(0, 1, 3) || sim.is_alive(1, 0, 3));
+    }
+}
@@ -0,0 +1,226 @@
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{self, Duration, SystemTime};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use ftoa;
+use futures::future::{self, Future};
+use lazy_static::lazy_static;
+use slog::{error, info, o, warn, Logger};
+use tokio::runtime::current_thread::Runtime;
+
+use crate::auth::tls_name;
+use crate::carbon::{CarbonBackend, CarbonClientOptions};
+use crate::config::{Carbon, Tls};
+use crate::errors::GeneralError;
+use crate::util::{resolve_addr, BackoffRetryBuilder};
+use crate::{Float, DROPS, SPOOLED_POINTS, SPOOL_DISCARDS, SPOOL_REPLAYED};
+
+lazy_static! {
+    // chunks given up on at the same time are appended one by one, so their lines are not mixed
+    static ref SPOOL_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// Metrics read from a spool, grouped by timestamp
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SpoolContents {
+    pub batches: Vec<(Duration, Vec<(Bytes, Float)>)>,
+    pub points: usize,
+    /// Lines torn by a crash, not parsed, older than the age cap or over the size cap
+    pub discarded: usize,
+}
+
+/// Append metrics given up on to the spool in carbon plaintext format, one `name value timestamp` line per point.
+/// Points not fitting into `max_size` are dropped. Returns the number of points spooled.
+pub fn spool_metrics(path: &str, max_size: usize, ts: Duration, metrics: &[(Bytes, Float)]) -> Result<usize, GeneralError> {
+    let spool_error = |e: String| GeneralError::Spool(path.to_string(), e);
+    let _lock = SPOOL_LOCK.lock().unwrap();
+    let size = fs::metadata(path).map(|meta| meta.len() as usize).unwrap_or(0);
+    let ts = ts.as_secs().to_string();
+    let mut buf = BytesMut::with_capacity(metrics.len() * 200);
+    let mut spooled = 0;
+    for (name, value) in metrics {
+        let mut line = Vec::with_capacity(name.len() + ts.len() + 32);
+        line.extend_from_slice(name);
+        line.push(b' ');
+        if ftoa::write(&mut line, *value).is_err() {
+            continue;
+        }
+        line.push(b' ');
+        line.extend_from_slice(ts.as_bytes());
+        line.push(b'\n');
+        if size + buf.len() + line.len() > max_size {
+            break;
+        }
+        buf.reserve(line.len());
+        buf.put(line);
+        spooled += 1;
+    }
+    DROPS.add(metrics.len() - spooled);
+    if spooled == 0 {
+        return Ok(0);
+    }
+    // a crash in the middle of writing leaves a torn last line, which is discarded on replay
+    let mut file = OpenOptions::new().create(true).append(true).open(path).map_err(|e| spool_error(e.to_string()))?;
+    file.write_all(&buf).and_then(|_| file.sync_data()).map_err(|e| spool_error(e.to_string()))?;
+    SPOOLED_POINTS.add(spooled);
+    Ok(spooled)
+}
+
+/// Validate and parse spooled lines. Only the last `max_size` bytes are taken, as older lines are at the start,
+/// and points with timestamps before `oldest`(seconds since UNIX epoch) are discarded.
+pub fn parse_spool(data: &[u8], max_size: usize, oldest: u64) -> SpoolContents {
+    let mut contents = SpoolContents::default();
+    let mut data = data;
+    if data.len() > max_size {
+        let cut = data.len() - max_size;
+        // the line cut in the middle is discarded too
+        let start = data[cut..].iter().position(|c| *c == b'\n').map(|pos| cut + pos + 1).unwrap_or(data.len());
+        contents.discarded += data[..start].iter().filter(|c| **c == b'\n').count();
+        data = &data[start..];
+    }
+
+    let mut batches = BTreeMap::new();
+    let mut lines = data.split(|c| *c == b'\n').peekable();
+    while let Some(line) = lines.next() {
+        if lines.peek().is_none() {
+            // text after the last newline is a torn line, nothing if the file is complete
+            if line.len() > 0 {
+                contents.discarded += 1;
+            }
+            break;
+        }
+        let mut parts = line.rsplitn(3, |c| *c == b' ');
+        let ts = parts.next().and_then(|ts| str::from_utf8(ts).ok()).and_then(|ts| ts.parse::<u64>().ok());
+        let value = parts.next().and_then(|value| str::from_utf8(value).ok()).and_then(|value| value.parse::<Float>().ok());
+        let name = parts.next().filter(|name| name.len() > 0);
+        match (name, value, ts) {
+            (Some(name), Some(value), Some(ts)) if ts >= oldest => {
+                batches.entry(ts).or_insert_with(Vec::new).push((Bytes::from(name), value));
+                contents.points += 1;
+            }
+            _ => contents.discarded += 1,
+        }
+    }
+    contents.batches = batches.into_iter().map(|(ts, metrics)| (Duration::from_secs(ts), metrics)).collect();
+    contents
+}
+
+/// Replay the spool left from a previous run to carbon backend in a separate thread. The spool is moved aside first,
+/// so chunks failing meanwhile are spooled anew, and removed when replay ends. Batches failing again go back to the spool.
+/// After a crash in the middle of replay, the moved spool is replayed again on the next start, resending the same points,
+/// which backend overwrites. Returns none if there is nothing to replay.
+pub fn replay_spool(options: &Carbon, tls: &Tls, log: &Logger) -> Result<Option<thread::JoinHandle<()>>, GeneralError> {
+    let path = match options.spool_file {
+        Some(ref path) => path.clone(),
+        None => return Ok(None),
+    };
+    let spool_error = |e: String| GeneralError::Spool(path.clone(), e);
+    let replaying = format!("{}.replay", path);
+    if Path::new(&path).exists() {
+        if Path::new(&replaying).exists() {
+            let data = fs::read(&path).map_err(|e| spool_error(e.to_string()))?;
+            OpenOptions::new().append(true).open(&replaying).and_then(|mut file| file.write_all(&data)).map_err(|e| spool_error(e.to_string()))?;
+            fs::remove_file(&path).map_err(|e| spool_error(e.to_string()))?;
+        } else {
+            fs::rename(&path, &replaying).map_err(|e| spool_error(e.to_string()))?;
+        }
+    }
+    let data = match fs::read(&replaying) {
+        Ok(data) => data,
+        Err(_) => return Ok(None),
+    };
+
+    let now = SystemTime::now().duration_since(time::UNIX_EPOCH).map_err(GeneralError::Time)?.as_secs();
+    let contents = parse_spool(&data, options.spool_max_size, now.saturating_sub(options.spool_max_age / 1000));
+    SPOOL_DISCARDS.add(contents.discarded);
+    let log = log.new(o!("source"=>"spool-replay"));
+    if contents.discarded > 0 {
+        warn!(log, "spooled points discarded"; "file"=>&replaying, "discarded"=>contents.discarded);
+    }
+    if contents.points == 0 {
+        fs::remove_file(&replaying).map_err(|e| spool_error(e.to_string()))?;
+        return Ok(None);
+    }
+
+    let addr = resolve_addr(&options.address)?;
+    let tls = if options.tls { Some(tls_name(tls, &options.address)?) } else { None };
+    let options = options.clone();
+    info!(log, "replaying spool"; "file"=>&replaying, "points"=>contents.points, "intervals"=>contents.batches.len());
+    thread::Builder::new()
+        .name("bioyino_spool".into())
+        .spawn(move || {
+            let mut runtime = match Runtime::new() {
+                Ok(runtime) => runtime,
+                Err(e) => {
+                    // the spool is kept to be replayed on the next start
+                    error!(log, "creating runtime for replay"; "error"=>e.to_string());
+                    return;
+                }
+            };
+            let replays = contents.batches.into_iter().map(|(ts, metrics)| {
+                let client = CarbonClientOptions { addr, bind: options.bind_address, tls: tls.clone() };
+                let metrics = Arc::new(metrics);
+                let backend = CarbonBackend::new(client, ts, metrics.clone(), log.clone());
+                let retrier = BackoffRetryBuilder { delay: options.connect_delay, delay_mul: options.connect_delay_multiplier, delay_max: options.connect_delay_max, retries: options.send_retries };
+                let log = log.clone();
+                let options = options.clone();
+                retrier.spawn(backend).then(move |result| {
+                    match result {
+                        Ok(()) => SPOOL_REPLAYED.add(metrics.len()),
+                        Err(e) => {
+                            warn!(log, "replaying spooled interval failed, spooling it again"; "timestamp"=>ts.as_secs(), "error"=>format!("{:?}", e));
+                            spool_metrics(options.spool_file.as_ref().unwrap(), options.spool_max_size, ts, &metrics).map(|_| ()).unwrap_or_else(|e| error!(log, "spooling failed"; "error"=>e.to_string()));
+                        }
+                    }
+                    Ok::<(), ()>(())
+                })
+            });
+            runtime.block_on(future::join_all(replays.collect::<Vec<_>>())).unwrap_or_default();
+            match fs::remove_file(&replaying) {
+                Ok(()) => info!(log, "spool replayed"; "replayed"=>SPOOL_REPLAYED.get()),
+                Err(e) => error!(log, "removing replayed spool"; "file"=>&replaying, "error"=>e.to_string()),
+            }
+        })
+        .map(Some)
+        .map_err(GeneralError::Io)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spool_roundtrip() {
+        let path = std::env::temp_dir().join(format!("bioyino-spool-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        let metrics = vec![(Bytes::from("some.counter"), 42f64), (Bytes::from("some.timer.max"), 1.5f64)];
+        assert_eq!(spool_metrics(path, 1 << 20, Duration::from_secs(1000), &metrics).unwrap(), 2);
+        assert_eq!(spool_metrics(path, 1 << 20, Duration::from_secs(1030), &metrics[..1]).unwrap(), 1);
+        let mut data = fs::read(path).unwrap();
+        fs::remove_file(path).unwrap();
+        // torn by a crash
+        data.extend_from_slice(b"some.gau");
+
+        let contents = parse_spool(&data, 1 << 20, 0);
+        assert_eq!(contents.points, 3);
+        assert_eq!(contents.discarded, 1);
+        assert_eq!(contents.batches[0], (Duration::from_secs(1000), metrics.clone()));
+        assert_eq!(contents.batches[1], (Duration::from_secs(1030), vec![metrics[0].clone()]));
+
+        // too old
+        let contents = parse_spool(&data, 1 << 20, 1001);
+        assert_eq!((contents.points, contents.discarded), (1, 3));
+        // over the size cap, only the last complete line is kept
+        let contents = parse_spool(&data, 40, 0);
+        assert_eq!((contents.points, contents.discarded), (1, 3));
+
+        let contents = parse_spool(b"no.value 1000\nbad.value x 1000\n 1 1000\n", 1 << 20, 0);
+        assert_eq!((contents.points, contents.discarded), (0, 3));
+    }
+}
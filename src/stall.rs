@@ -0,0 +1,203 @@
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+
+use futures::{Async, Future, Poll};
+use slog::{info, warn, Logger};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::timer::Delay;
+
+use crate::{KILLED_CONNECTIONS, RUNTIME_CONFIG, SLOW_CONNECTIONS};
+
+/// Stall detection settings taken from runtime config, so they can be changed by reload
+#[derive(Debug, Clone, Copy)]
+pub struct StallOptions {
+    /// Connection not making progress for this long is logged as slow
+    pub report: Option<Duration>,
+    /// Slow connection is closed after making no progress for this long
+    pub kill: Option<Duration>,
+}
+
+impl StallOptions {
+    pub fn current() -> Self {
+        let config = RUNTIME_CONFIG.read().unwrap();
+        let ms = |ms: u64| if ms > 0 { Some(Duration::from_millis(ms)) } else { None };
+        Self { report: ms(config.network.slow_connection), kill: ms(config.network.slow_connection_kill) }
+    }
+
+    fn first(&self) -> Option<Duration> {
+        match (self.report, self.kill) {
+            (Some(report), Some(kill)) => Some(report.min(kill)),
+            (report, kill) => report.or(kill),
+        }
+    }
+}
+
+/// TCP connection, or any other stream, watched for stalled reads and writes. A connection where
+/// some operation is waiting without progress longer than the report timeout is logged with byte counts,
+/// after the kill timeout all it's operations fail with `TimedOut` error, so the connection and it's buffers are dropped.
+pub struct StallWatch<S> {
+    inner: S,
+    kind: &'static str,
+    options: StallOptions,
+    started: Instant,
+    last_progress: Instant,
+    read: u64,
+    written: u64,
+    timer: Option<Delay>,
+    reported: bool,
+    log: Logger,
+}
+
+impl<S> StallWatch<S> {
+    pub fn new(inner: S, kind: &'static str, options: StallOptions, log: Logger) -> Self {
+        let now = Instant::now();
+        let timer = options.first().map(|timeout| Delay::new(now + timeout));
+        Self { inner, kind, options, started: now, last_progress: now, read: 0, written: 0, timer, reported: false, log }
+    }
+
+    fn progress(&mut self) {
+        let now = Instant::now();
+        if self.reported {
+            info!(self.log, "slow connection recovered"; "kind"=>self.kind, "stalled-ms"=>now.duration_since(self.last_progress).as_millis() as u64);
+            self.reported = false;
+        }
+        self.last_progress = now;
+        if let Some(timeout) = self.options.first() {
+            match self.timer {
+                Some(ref mut timer) => timer.reset(now + timeout),
+                None => self.timer = Some(Delay::new(now + timeout)),
+            }
+        }
+    }
+
+    // called when the operation cannot progress, the timer is polled, so the task is woken up when it fires
+    fn check(&mut self) -> io::Result<()> {
+        loop {
+            let fired = match self.timer.as_mut().map(|timer| timer.poll()) {
+                Some(Ok(Async::Ready(()))) => true,
+                // timer errors only happen on runtime shutdown, stalls are not watched after it
+                Some(Ok(Async::NotReady)) | Some(Err(_)) | None => false,
+            };
+            if !fired {
+                return Ok(());
+            }
+            let stalled = self.last_progress.elapsed();
+            if let Some(kill) = self.options.kill {
+                if stalled >= kill {
+                    KILLED_CONNECTIONS.add(1);
+                    warn!(self.log, "closing stalled connection"; "kind"=>self.kind, "read-bytes"=>self.read, "written-bytes"=>self.written, "stalled-ms"=>stalled.as_millis() as u64, "age-ms"=>self.started.elapsed().as_millis() as u64);
+                    self.timer = None;
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, format!("no progress for {}ms", stalled.as_millis())));
+                }
+            }
+            match self.options.report {
+                Some(report) if stalled >= report && !self.reported => {
+                    SLOW_CONNECTIONS.add(1);
+                    warn!(self.log, "slow connection"; "kind"=>self.kind, "read-bytes"=>self.read, "written-bytes"=>self.written, "stalled-ms"=>stalled.as_millis() as u64, "age-ms"=>self.started.elapsed().as_millis() as u64);
+                    self.reported = true;
+                }
+                _ => (),
+            }
+            // after reporting only the kill timeout is left to wait for
+            match self.options.kill {
+                Some(kill) if self.last_progress + kill > Instant::now() => {
+                    let deadline = self.last_progress + kill;
+                    if let Some(ref mut timer) = self.timer {
+                        timer.reset(deadline);
+                    }
+                }
+                _ => self.timer = None,
+            }
+        }
+    }
+}
+
+impl<S: Read> Read for StallWatch<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.inner.read(buf) {
+            Ok(read) => {
+                self.read += read as u64;
+                self.progress();
+                Ok(read)
+            }
+            Err(e) => {
+                if e.kind() == io::ErrorKind::WouldBlock {
+                    self.check()?;
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<S: Write> Write for StallWatch<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.inner.write(buf) {
+            Ok(written) => {
+                self.written += written as u64;
+                self.progress();
+                Ok(written)
+            }
+            Err(e) => {
+                if e.kind() == io::ErrorKind::WouldBlock {
+                    self.check()?;
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush().map_err(|e| {
+            if e.kind() == io::ErrorKind::WouldBlock {
+                if let Err(e) = self.check() {
+                    return e;
+                }
+            }
+            e
+        })
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for StallWatch<S> {}
+
+impl<S: AsyncWrite> AsyncWrite for StallWatch<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.inner.shutdown()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::prepare_log;
+    use futures::future::poll_fn;
+    use tokio::runtime::current_thread::Runtime;
+
+    // a stream that never has any data
+    struct Silent;
+
+    impl Read for Silent {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    impl AsyncRead for Silent {}
+
+    #[test]
+    fn stalled_connection() {
+        let options = StallOptions { report: Some(Duration::from_millis(10)), kill: Some(Duration::from_millis(50)) };
+        let mut watch = StallWatch::new(Silent, "test", options, prepare_log("stalled_connection"));
+        let mut runtime = Runtime::new().unwrap();
+        let started = Instant::now();
+        let slow_before = SLOW_CONNECTIONS.get();
+        let result = runtime.block_on(poll_fn(move || {
+            let mut buf = [0u8; 16];
+            watch.poll_read(&mut buf)
+        }));
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert_eq!(SLOW_CONNECTIONS.get(), slow_before + 1);
+    }
+}
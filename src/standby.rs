@@ -0,0 +1,160 @@
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use futures::future::{Either, Future};
+use futures::Stream;
+use lazy_static::lazy_static;
+use serde_derive::{Deserialize, Serialize};
+use slog::{info, o, warn, Logger};
+use tokio::timer::Interval;
+
+use crate::cluster::{last_received, Clock, SystemClock};
+use crate::config::{Standby, StandbyRole};
+use crate::events::event;
+use crate::util::resolve_addr;
+use crate::{ConsensusState, CONSENSUS_REACHABLE, CONSENSUS_STATE, IS_LEADER};
+
+lazy_static! {
+    static ref STATUS: Mutex<StandbyStatus> = Mutex::new(StandbyStatus::default());
+}
+
+/// State of the pair as seen by this node
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct StandbyStatus {
+    pub role: Option<StandbyRole>,
+    /// Time of the last snapshot from the primary, ms since UNIX epoch
+    pub primary_last_seen: Option<u64>,
+    pub primary_alive: bool,
+    /// Time the standby took over by itself, none if it does not lead or was promoted manually
+    pub took_over: Option<u64>,
+}
+
+/// Pair status, role is none if consensus is not `standby`
+pub fn standby_status() -> StandbyStatus {
+    STATUS.lock().unwrap().clone()
+}
+
+/// Leadership decisions of a standby by times of snapshots from it's primary, all times are ms of a `Clock`
+#[derive(Debug, Clone)]
+pub struct StandbyWatch {
+    failover_timeout: u64,
+    // the primary is given failover-timeout since this moment to be heard of
+    since: u64,
+    // when the standby took over by itself
+    took_over: Option<u64>,
+}
+
+impl StandbyWatch {
+    pub fn new(failover_timeout: u64, now: u64) -> Self {
+        Self { failover_timeout, since: now, took_over: None }
+    }
+
+    pub fn primary_alive(&self, last_seen: Option<u64>, now: u64) -> bool {
+        now.saturating_sub(last_seen.unwrap_or(0).max(self.since)) < self.failover_timeout
+    }
+
+    /// Whether the standby must lead now, given the time of the last snapshot from the primary
+    pub fn leads(&mut self, last_seen: Option<u64>, now: u64) -> bool {
+        match self.took_over {
+            // the primary is back and leads again as it always does
+            Some(at) if last_seen.map(|seen| seen > at).unwrap_or(false) => {
+                self.took_over = None;
+                false
+            }
+            Some(_) => true,
+            None if !self.primary_alive(last_seen, now) => {
+                self.took_over = Some(now);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Forget the decisions made, used while leadership is overridden manually, so watching starts over after it
+    pub fn reset(&mut self, now: u64) {
+        self.since = now;
+        self.took_over = None;
+    }
+}
+
+/// Take the role of the pair, the standby watches snapshots from the primary and takes over flushing when they stop
+/// coming for `standby.failover-timeout`, stepping down when they come again. Leadership set by management commands
+/// is not changed until consensus is enabled again.
+pub fn watch_standby(options: Standby, log: Logger) -> impl Future<Item = (), Error = ()> {
+    let log = log.new(o!("source"=>"standby"));
+    *CONSENSUS_STATE.lock().unwrap() = ConsensusState::Enabled;
+    STATUS.lock().unwrap().role = Some(options.role.clone());
+    if options.role == StandbyRole::Primary {
+        info!(log, "leading as primary of the pair");
+        IS_LEADER.store(true, Ordering::SeqCst);
+        CONSENSUS_REACHABLE.store(true, Ordering::Relaxed);
+        return Either::A(futures::future::ok(()));
+    }
+
+    IS_LEADER.store(false, Ordering::SeqCst);
+    let primary = options.primary.clone().unwrap_or_default();
+    let mut watch = StandbyWatch::new(options.failover_timeout, SystemClock.now_ms());
+    let interval = Duration::from_millis((options.failover_timeout / 4).max(100));
+    let err_log = log.clone();
+    let future = Interval::new(Instant::now() + interval, interval)
+        .map_err(move |e| warn!(err_log, "standby timer failed"; "error"=>e.to_string()))
+        .for_each(move |_| {
+            let now = SystemClock.now_ms();
+            // resolved every time, so the primary may move
+            let last_seen = match resolve_addr(&primary) {
+                Ok(addr) => last_received(&addr.ip()),
+                Err(e) => {
+                    warn!(log, "resolving primary"; "primary"=>&primary, "error"=>e.to_string());
+                    None
+                }
+            };
+            let alive = watch.primary_alive(last_seen, now);
+            CONSENSUS_REACHABLE.store(alive, Ordering::Relaxed);
+
+            if *CONSENSUS_STATE.lock().unwrap() != ConsensusState::Enabled {
+                watch.reset(now);
+            } else {
+                let leads = watch.leads(last_seen, now);
+                if IS_LEADER.swap(leads, Ordering::SeqCst) != leads {
+                    if leads {
+                        warn!(log, "primary is not heard of, taking over"; "primary"=>&primary, "last-seen"=>last_seen.unwrap_or(0));
+                        event("leader", format!("standby took over, primary {} is not heard of", primary));
+                    } else {
+                        info!(log, "primary is back, stepping down"; "primary"=>&primary);
+                        event("leader", format!("standby stepped down, primary {} is back", primary));
+                    }
+                }
+            }
+            let mut status = STATUS.lock().unwrap();
+            status.primary_last_seen = last_seen;
+            status.primary_alive = alive;
+            status.took_over = watch.took_over;
+            Ok(())
+        });
+    Either::B(future)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn standby_failover() {
+        let mut watch = StandbyWatch::new(5000, 1000);
+        // the primary is given the timeout after start even if it was never heard of
+        assert!(!watch.leads(None, 4000));
+        assert!(watch.leads(None, 6000));
+        assert_eq!(watch.took_over, Some(6000));
+        // old snapshots do not bring the primary back
+        assert!(watch.leads(Some(5500), 7000));
+        assert!(!watch.leads(Some(7500), 8000));
+        assert!(!watch.leads(Some(9000), 13000));
+        assert!(watch.leads(Some(9000), 14000));
+
+        watch.reset(20000);
+        assert!(watch.primary_alive(Some(9000), 24000));
+        assert!(!watch.leads(Some(9000), 24000));
+    }
+}
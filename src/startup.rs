@@ -0,0 +1,181 @@
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use net2::unix::UnixUdpBuilderExt;
+use net2::UdpBuilder;
+use slog::{info, warn, Logger};
+
+use crate::activation::{is_passed, MANAGEMENT_SOCKET, PEER_SOCKET, STATSD_SOCKET};
+use crate::config::{StartupTimeout, System};
+use crate::errors::GeneralError;
+use crate::util::{resolve_addr, reusing_listener};
+use crate::ConsensusKind;
+
+// consul agent is asked for the leader with this timeout
+const CONSUL_TIMEOUT: u64 = 2000;
+
+/// Something the server needs to work, checked on start in the order of `dependencies`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Dependency {
+    /// Name of an option with a host name resolving to an address
+    Resolve(&'static str, String),
+    /// Consul agent knowing the leader of it's cluster
+    Consul(SocketAddr),
+    /// Address a listener binds to, UDP for statsd
+    Bind(&'static str, SocketAddr),
+}
+
+impl fmt::Display for Dependency {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Dependency::Resolve(option, name) => write!(f, "{} {}", option, name),
+            Dependency::Consul(agent) => write!(f, "consul agent {}", agent),
+            Dependency::Bind(name, addr) => write!(f, "{} listener address {}", name, addr),
+        }
+    }
+}
+
+impl Dependency {
+    /// Check the dependency once
+    pub fn check(&self) -> Result<(), String> {
+        match self {
+            Dependency::Resolve(_, name) => resolve_addr(name).map(|_| ()).map_err(|e| e.to_string()),
+            Dependency::Consul(agent) => consul_leader(agent),
+            // the socket is closed right away, it is bound again by the listener
+            Dependency::Bind(name, addr) if *name == STATSD_SOCKET => {
+                let socket = if addr.is_ipv4() { UdpBuilder::new_v4() } else { UdpBuilder::new_v6() }.map_err(|e| e.to_string())?;
+                socket.reuse_address(true).and_then(|socket| socket.reuse_port(true)).map_err(|e| e.to_string())?;
+                socket.bind(addr).map(|_| ()).map_err(|e| e.to_string())
+            }
+            Dependency::Bind(_, addr) => reusing_listener(addr).map(|_| ()).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// What the server depends on in the order it is brought up: names are resolved first, as consul and
+/// listeners do not need them, then consul is asked, then listening addresses are checked. Sockets passed
+/// by systemd or by the previous process are bound already, so they are not checked.
+pub fn dependencies(system: &System) -> Vec<Dependency> {
+    let mut deps = system.network.nodes.iter().map(|node| Dependency::Resolve("network.nodes", node.clone())).collect::<Vec<_>>();
+    deps.push(Dependency::Resolve("carbon.address", system.carbon.address.clone()));
+    match system.consensus {
+        ConsensusKind::Internal => {
+            let mut nodes = system.raft.nodes.keys().cloned().collect::<Vec<_>>();
+            nodes.sort();
+            deps.extend(nodes.into_iter().map(|node| Dependency::Resolve("raft.nodes", node)));
+        }
+        ConsensusKind::Standby => deps.extend(system.standby.primary.clone().map(|primary| Dependency::Resolve("standby.primary", primary))),
+        _ => (),
+    }
+    if let Some(ref sibling) = system.network.maintenance_forward {
+        deps.push(Dependency::Resolve("network.maintenance-forward", sibling.clone()));
+    }
+
+    if system.consensus == ConsensusKind::Consul {
+        deps.push(Dependency::Consul(system.consul.agent));
+    }
+
+    let network = &system.network;
+    for (name, udp, addr) in &[(STATSD_SOCKET, true, network.listen), (PEER_SOCKET, false, network.peer_listen), (MANAGEMENT_SOCKET, false, network.mgmt_listen)] {
+        if !is_passed(*udp, name, addr) {
+            deps.push(Dependency::Bind(name, *addr));
+        }
+    }
+    deps
+}
+
+// consul answers /v1/status/leader with an empty string while it's cluster has no leader
+fn consul_leader(agent: &SocketAddr) -> Result<(), String> {
+    let timeout = Duration::from_millis(CONSUL_TIMEOUT);
+    let mut stream = TcpStream::connect_timeout(agent, timeout).map_err(|e| e.to_string())?;
+    stream.set_read_timeout(Some(timeout)).and_then(|_| stream.set_write_timeout(Some(timeout))).map_err(|e| e.to_string())?;
+    stream.write_all(format!("GET /v1/status/leader HTTP/1.0\r\nHost: {}\r\n\r\n", agent).as_bytes()).map_err(|e| e.to_string())?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).map_err(|e| e.to_string())?;
+    leader_known(&response)
+}
+
+/// Check the HTTP answer to /v1/status/leader names a leader
+pub fn leader_known(response: &str) -> Result<(), String> {
+    let status = response.lines().next().unwrap_or("");
+    if status.split(' ').nth(1) != Some("200") {
+        return Err(format!("agent answered {:?}", status));
+    }
+    let body = response.splitn(2, "\r\n\r\n").nth(1).unwrap_or("").trim();
+    if body.len() == 0 || body == "\"\"" {
+        return Err("consul cluster has no leader".to_string());
+    }
+    Ok(())
+}
+
+/// Delay before the next check, doubled up to `max`
+pub fn next_delay(delay: Duration, max: Duration) -> Duration {
+    (delay * 2).min(max).max(delay.min(max))
+}
+
+/// Wait for every dependency in order, checking each again with growing delays until `startup.timeout` passes.
+/// Then, with `startup.on-timeout = "fail"` the error is returned, with `"start"` dependencies not up are reported
+/// and the server starts without them. Nothing is waited for without `startup.wait`.
+pub fn wait_dependencies(system: &System, log: &Logger) -> Result<(), GeneralError> {
+    let options = &system.startup;
+    if !options.wait {
+        return Ok(());
+    }
+    let deadline = Instant::now() + Duration::from_millis(options.timeout);
+    let max_delay = Duration::from_millis(options.retry_delay_max.max(1));
+    for dependency in dependencies(system) {
+        let mut delay = Duration::from_millis(options.retry_delay.max(1)).min(max_delay);
+        let mut waited = false;
+        loop {
+            match dependency.check() {
+                Ok(()) => {
+                    if waited {
+                        info!(log, "dependency is up"; "dependency"=>dependency.to_string());
+                    }
+                    break;
+                }
+                Err(e) if Instant::now() + delay < deadline => {
+                    info!(log, "waiting for dependency"; "dependency"=>dependency.to_string(), "error"=>&e, "retry-ms"=>delay.as_secs() * 1000 + delay.subsec_millis() as u64);
+                    thread::sleep(delay);
+                    delay = next_delay(delay, max_delay);
+                    waited = true;
+                }
+                Err(e) if options.on_timeout == StartupTimeout::Start => {
+                    warn!(log, "dependency is not up, starting without it"; "dependency"=>dependency.to_string(), "error"=>e);
+                    break;
+                }
+                Err(e) => return Err(GeneralError::Startup(dependency.to_string(), e)),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn startup_dependencies() {
+        let mut system = System::default();
+        system.network.nodes = vec!["127.0.0.2:8136".to_string()];
+        system.consensus = ConsensusKind::Consul;
+        let deps = dependencies(&system);
+        assert_eq!(deps[0], Dependency::Resolve("network.nodes", "127.0.0.2:8136".to_string()));
+        assert_eq!(deps[1], Dependency::Resolve("carbon.address", system.carbon.address.clone()));
+        assert_eq!(deps[2], Dependency::Consul(system.consul.agent));
+        assert_eq!(deps[3], Dependency::Bind(STATSD_SOCKET, system.network.listen));
+        assert_eq!(deps.len(), 6);
+
+        assert!(leader_known("HTTP/1.0 200 OK\r\nContent-Type: application/json\r\n\r\n\"10.0.0.1:8300\"").is_ok());
+        assert!(leader_known("HTTP/1.0 200 OK\r\n\r\n\"\"").is_err());
+        assert!(leader_known("HTTP/1.0 500 Internal Server Error\r\n\r\nNo cluster leader").is_err());
+
+        let max = Duration::from_millis(5000);
+        assert_eq!(next_delay(Duration::from_millis(500), max), Duration::from_millis(1000));
+        assert_eq!(next_delay(Duration::from_millis(4000), max), max);
+    }
+}
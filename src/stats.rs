@@ -0,0 +1,914 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::mem;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use futures::future::{join_all, Future};
+use futures::sync::mpsc::Sender;
+use futures::sync::oneshot;
+use futures::Sink;
+use bytes::Bytes;
+use lazy_static::lazy_static;
+use serde_derive::{Deserialize, Serialize};
+
+use bioyino_metric::{Metric, MetricType};
+
+use crate::acl::{RuleDrops, PEER_SOURCES, STATSD_SOURCES};
+use crate::alloc::{allocator_stats, AllocatorStats};
+use crate::buildinfo::BuildInfo;
+use crate::cache::ShardedCache;
+use crate::carbon::{paused_flush_bytes, BACKEND_QUEUE_BYTES};
+use crate::counter::Counter;
+use crate::intern::{NameId, NAMES};
+use crate::latency::{histograms, LatencyValues, BOUNDS_US};
+use crate::limits::{ResourceLimits, RESOURCE_LIMITS};
+use crate::memory::Pressure;
+use crate::parse_errors::{ParseErrorReport, PARSE_ERROR_STATS};
+use crate::peer::PEER_SNAPSHOT_BYTES;
+use crate::queue::{worker_queues, QueueValues, FLUSH_QUEUE};
+use crate::task::Task;
+use crate::tunables::TUNABLES;
+use crate::udp::{SocketValues, STATSD_UDP_SOCKET};
+use crate::{Cache, Float, RUNTIME_CONFIG};
+use crate::{AGG_ERRORS, ARENA_OVERFLOWS, AUDIT_EVENTS, CAPPED_METRICS, CAPPED_SAMPLES, DROPS, EARLY_FLUSHES, EGRESS, FILTERED, INGRESS, INGRESS_METRICS, PARSE_ERRORS, PAUSED_DROPS, PEER_ERRORS, SHED_DROPS, SLOW_TASKS, CACHE_SHRINKS, SLOW_CONNECTIONS, KILLED_CONNECTIONS, PANICS, SOURCE_DROPS, UNSIGNED_DROPS, EXPIRED_DROPS, BAD_SIGNATURE_DROPS, STATSD_RATE_DROPS, PEER_RATE_DROPS, QUOTA_DROPS, SPOOLED_POINTS, SPOOL_REPLAYED, SPOOL_DISCARDS, FLUSH_GAPS, BACKFILLED_POINTS, DROPPED_TAGS, TAG_OVERFLOWS};
+use crate::{BACKEND_OK, CONSENSUS_REACHABLE, FLUSH_PAUSED, INGESTION_PAUSED, IS_LEADER, PEER_LISTENING, STATSD_LISTENING};
+
+lazy_static! {
+    // moment and counter values of the previous /stats request
+    static ref LAST_SCRAPE: Mutex<Option<(Instant, Counters)>> = { Mutex::new(None) };
+    static ref STARTED: Instant = Instant::now();
+}
+
+/// Counters of a single listener
+pub struct ListenerCounters {
+    pub packets: Counter,
+    pub metrics: Counter,
+    pub parse_errors: Counter,
+    pub drops: Counter,
+}
+
+impl ListenerCounters {
+    const fn new() -> Self {
+        Self { packets: Counter::new(), metrics: Counter::new(), parse_errors: Counter::new(), drops: Counter::new() }
+    }
+
+    fn load(&self) -> ListenerValues {
+        let metrics = self.metrics.get();
+        let parse_errors = self.parse_errors.get();
+        ListenerValues {
+            packets: self.packets.get(),
+            // every line is either parsed into a metric or skipped with a parse error
+            lines: metrics + parse_errors,
+            metrics,
+            parse_errors,
+            drops: self.drops.get(),
+        }
+    }
+}
+
+/// Statsd UDP listener, packets are datagrams
+pub static STATSD_UDP: ListenerCounters = ListenerCounters::new();
+/// Peer TCP listener, packets are capnp messages, parse errors are bad messages
+pub static PEER_TCP: ListenerCounters = ListenerCounters::new();
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ListenerValues {
+    pub packets: usize,
+    pub lines: usize,
+    pub metrics: usize,
+    pub parse_errors: usize,
+    pub drops: usize,
+}
+
+impl ListenerValues {
+    fn delta(&self, prev: &ListenerValues) -> Self {
+        Self {
+            packets: self.packets.wrapping_sub(prev.packets),
+            lines: self.lines.wrapping_sub(prev.lines),
+            metrics: self.metrics.wrapping_sub(prev.metrics),
+            parse_errors: self.parse_errors.wrapping_sub(prev.parse_errors),
+            drops: self.drops.wrapping_sub(prev.drops),
+        }
+    }
+
+    // names are in the same order as fields
+    fn push_to(&self, names: [&'static str; 5], acc: &mut Vec<(&'static str, usize)>) {
+        acc.extend(names.iter().cloned().zip(vec![self.packets, self.lines, self.metrics, self.parse_errors, self.drops]));
+    }
+}
+
+/// Counters of a single backend
+pub struct BackendCounters {
+    pub metrics: Counter,
+    pub chunks: Counter,
+    pub errors: Counter,
+}
+
+impl BackendCounters {
+    const fn new() -> Self {
+        Self { metrics: Counter::new(), chunks: Counter::new(), errors: Counter::new() }
+    }
+
+    fn load(&self) -> BackendValues {
+        BackendValues { metrics: self.metrics.get(), chunks: self.chunks.get(), errors: self.errors.get() }
+    }
+}
+
+/// Carbon backend, chunks are sent in separate connections, errors are chunks given up on after retries
+pub static CARBON_BACKEND: BackendCounters = BackendCounters::new();
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct BackendValues {
+    pub metrics: usize,
+    pub chunks: usize,
+    pub errors: usize,
+}
+
+impl BackendValues {
+    fn delta(&self, prev: &BackendValues) -> Self {
+        Self { metrics: self.metrics.wrapping_sub(prev.metrics), chunks: self.chunks.wrapping_sub(prev.chunks), errors: self.errors.wrapping_sub(prev.errors) }
+    }
+
+    fn push_to(&self, names: [&'static str; 3], acc: &mut Vec<(&'static str, usize)>) {
+        acc.extend(names.iter().cloned().zip(vec![self.metrics, self.chunks, self.errors]));
+    }
+}
+
+/// Values of all global counters at some moment. Counters only grow,
+/// so rates are counted as a difference between two snapshots.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Counters {
+    pub egress: usize,
+    pub ingress: usize,
+    pub ingress_metric: usize,
+    pub agg_error: usize,
+    pub parse_error: usize,
+    pub peer_error: usize,
+    pub drop: usize,
+    pub paused_drop: usize,
+    pub filtered: usize,
+    pub audit: usize,
+    #[serde(default)]
+    pub shed_drop: usize,
+    #[serde(default)]
+    pub capped_sample: usize,
+    #[serde(default)]
+    pub capped_metric: usize,
+    #[serde(default)]
+    pub early_flush: usize,
+    #[serde(default)]
+    pub arena_overflow: usize,
+    #[serde(default)]
+    pub slow_task: usize,
+    #[serde(default)]
+    pub cache_shrink: usize,
+    #[serde(default)]
+    pub slow_connection: usize,
+    #[serde(default)]
+    pub killed_connection: usize,
+    #[serde(default)]
+    pub panic: usize,
+    #[serde(default)]
+    pub source_drop: usize,
+    #[serde(default)]
+    pub unsigned_drop: usize,
+    #[serde(default)]
+    pub expired_drop: usize,
+    #[serde(default)]
+    pub bad_signature_drop: usize,
+    #[serde(default)]
+    pub statsd_rate_drop: usize,
+    #[serde(default)]
+    pub peer_rate_drop: usize,
+    #[serde(default)]
+    pub quota_drop: usize,
+    #[serde(default)]
+    pub spooled: usize,
+    #[serde(default)]
+    pub spool_replayed: usize,
+    #[serde(default)]
+    pub spool_discard: usize,
+    #[serde(default)]
+    pub flush_gap: usize,
+    #[serde(default)]
+    pub backfilled: usize,
+    #[serde(default)]
+    pub dropped_tags: usize,
+    #[serde(default)]
+    pub tag_overflow: usize,
+    pub statsd_udp: ListenerValues,
+    pub peer_tcp: ListenerValues,
+    #[serde(default)]
+    pub carbon: BackendValues,
+}
+
+impl Counters {
+    pub fn load() -> Self {
+        Self {
+            egress: EGRESS.get(),
+            ingress: INGRESS.get(),
+            ingress_metric: INGRESS_METRICS.get(),
+            agg_error: AGG_ERRORS.get(),
+            parse_error: PARSE_ERRORS.get(),
+            peer_error: PEER_ERRORS.get(),
+            drop: DROPS.get(),
+            paused_drop: PAUSED_DROPS.get(),
+            filtered: FILTERED.get(),
+            audit: AUDIT_EVENTS.get(),
+            shed_drop: SHED_DROPS.get(),
+            capped_sample: CAPPED_SAMPLES.get(),
+            capped_metric: CAPPED_METRICS.get(),
+            early_flush: EARLY_FLUSHES.get(),
+            arena_overflow: ARENA_OVERFLOWS.get(),
+            slow_task: SLOW_TASKS.get(),
+            cache_shrink: CACHE_SHRINKS.get(),
+            slow_connection: SLOW_CONNECTIONS.get(),
+            killed_connection: KILLED_CONNECTIONS.get(),
+            panic: PANICS.get(),
+            source_drop: SOURCE_DROPS.get(),
+            unsigned_drop: UNSIGNED_DROPS.get(),
+            expired_drop: EXPIRED_DROPS.get(),
+            bad_signature_drop: BAD_SIGNATURE_DROPS.get(),
+            statsd_rate_drop: STATSD_RATE_DROPS.get(),
+            peer_rate_drop: PEER_RATE_DROPS.get(),
+            quota_drop: QUOTA_DROPS.get(),
+            spooled: SPOOLED_POINTS.get(),
+            spool_replayed: SPOOL_REPLAYED.get(),
+            spool_discard: SPOOL_DISCARDS.get(),
+            flush_gap: FLUSH_GAPS.get(),
+            backfilled: BACKFILLED_POINTS.get(),
+            dropped_tags: DROPPED_TAGS.get(),
+            tag_overflow: TAG_OVERFLOWS.get(),
+            statsd_udp: STATSD_UDP.load(),
+            peer_tcp: PEER_TCP.load(),
+            carbon: CARBON_BACKEND.load(),
+        }
+    }
+
+    /// Counter increase since `prev`
+    pub fn delta(&self, prev: &Counters) -> Self {
+        Self {
+            egress: self.egress.wrapping_sub(prev.egress),
+            ingress: self.ingress.wrapping_sub(prev.ingress),
+            ingress_metric: self.ingress_metric.wrapping_sub(prev.ingress_metric),
+            agg_error: self.agg_error.wrapping_sub(prev.agg_error),
+            parse_error: self.parse_error.wrapping_sub(prev.parse_error),
+            peer_error: self.peer_error.wrapping_sub(prev.peer_error),
+            drop: self.drop.wrapping_sub(prev.drop),
+            paused_drop: self.paused_drop.wrapping_sub(prev.paused_drop),
+            filtered: self.filtered.wrapping_sub(prev.filtered),
+            audit: self.audit.wrapping_sub(prev.audit),
+            shed_drop: self.shed_drop.wrapping_sub(prev.shed_drop),
+            capped_sample: self.capped_sample.wrapping_sub(prev.capped_sample),
+            capped_metric: self.capped_metric.wrapping_sub(prev.capped_metric),
+            early_flush: self.early_flush.wrapping_sub(prev.early_flush),
+            arena_overflow: self.arena_overflow.wrapping_sub(prev.arena_overflow),
+            slow_task: self.slow_task.wrapping_sub(prev.slow_task),
+            cache_shrink: self.cache_shrink.wrapping_sub(prev.cache_shrink),
+            slow_connection: self.slow_connection.wrapping_sub(prev.slow_connection),
+            killed_connection: self.killed_connection.wrapping_sub(prev.killed_connection),
+            panic: self.panic.wrapping_sub(prev.panic),
+            source_drop: self.source_drop.wrapping_sub(prev.source_drop),
+            unsigned_drop: self.unsigned_drop.wrapping_sub(prev.unsigned_drop),
+            expired_drop: self.expired_drop.wrapping_sub(prev.expired_drop),
+            bad_signature_drop: self.bad_signature_drop.wrapping_sub(prev.bad_signature_drop),
+            statsd_rate_drop: self.statsd_rate_drop.wrapping_sub(prev.statsd_rate_drop),
+            peer_rate_drop: self.peer_rate_drop.wrapping_sub(prev.peer_rate_drop),
+            quota_drop: self.quota_drop.wrapping_sub(prev.quota_drop),
+            spooled: self.spooled.wrapping_sub(prev.spooled),
+            spool_replayed: self.spool_replayed.wrapping_sub(prev.spool_replayed),
+            spool_discard: self.spool_discard.wrapping_sub(prev.spool_discard),
+            flush_gap: self.flush_gap.wrapping_sub(prev.flush_gap),
+            backfilled: self.backfilled.wrapping_sub(prev.backfilled),
+            dropped_tags: self.dropped_tags.wrapping_sub(prev.dropped_tags),
+            tag_overflow: self.tag_overflow.wrapping_sub(prev.tag_overflow),
+            statsd_udp: self.statsd_udp.delta(&prev.statsd_udp),
+            peer_tcp: self.peer_tcp.delta(&prev.peer_tcp),
+            carbon: self.carbon.delta(&prev.carbon),
+        }
+    }
+
+    /// Counter names(the same as used in metric names) with their values
+    pub fn to_vec(&self) -> Vec<(&'static str, usize)> {
+        let mut values = vec![
+            ("egress", self.egress),
+            ("ingress", self.ingress),
+            ("ingress-metric", self.ingress_metric),
+            ("agg-error", self.agg_error),
+            ("parse-error", self.parse_error),
+            ("peer-error", self.peer_error),
+            ("drop", self.drop),
+            ("paused-drop", self.paused_drop),
+            ("filtered", self.filtered),
+            ("audit", self.audit),
+            ("shed-drop", self.shed_drop),
+            ("capped-sample", self.capped_sample),
+            ("capped-metric", self.capped_metric),
+            ("early-flush", self.early_flush),
+            ("arena-overflow", self.arena_overflow),
+            ("slow-task", self.slow_task),
+            ("cache-shrink", self.cache_shrink),
+            ("slow-connection", self.slow_connection),
+            ("killed-connection", self.killed_connection),
+            ("panic", self.panic),
+            ("source-drop", self.source_drop),
+            ("unsigned-drop", self.unsigned_drop),
+            ("expired-drop", self.expired_drop),
+            ("bad-signature-drop", self.bad_signature_drop),
+            ("statsd-rate-drop", self.statsd_rate_drop),
+            ("peer-rate-drop", self.peer_rate_drop),
+            ("quota-drop", self.quota_drop),
+            ("spooled", self.spooled),
+            ("spool-replayed", self.spool_replayed),
+            ("spool-discard", self.spool_discard),
+            ("flush-gap", self.flush_gap),
+            ("backfilled", self.backfilled),
+            ("dropped-tags", self.dropped_tags),
+            ("tag-overflow", self.tag_overflow),
+        ];
+        self.statsd_udp.push_to(["listener.statsd-udp.packet", "listener.statsd-udp.line", "listener.statsd-udp.metric", "listener.statsd-udp.parse-error", "listener.statsd-udp.drop"], &mut values);
+        self.peer_tcp.push_to(["listener.peer-tcp.packet", "listener.peer-tcp.line", "listener.peer-tcp.metric", "listener.peer-tcp.parse-error", "listener.peer-tcp.drop"], &mut values);
+        self.carbon.push_to(["backend.carbon.metric", "backend.carbon.chunk", "backend.carbon.error"], &mut values);
+        values
+    }
+
+    /// Aggregation degradation over the period of counter delta
+    pub fn accuracy(&self) -> Accuracy {
+        let estimated_error = if self.ingress_metric > 0 { (self.capped_sample as Float / self.ingress_metric as Float).min(1f64) } else { 0f64 };
+        Accuracy { capped_metrics: self.capped_metric, capped_samples: self.capped_sample, shed_drops: self.shed_drop, estimated_error }
+    }
+}
+
+/// How much aggregated values became approximate because of memory pressure
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct Accuracy {
+    /// Timers that reached the sample cap, their percentiles are counted from part of the samples
+    pub capped_metrics: usize,
+    /// Timer samples dropped because of the cap
+    pub capped_samples: usize,
+    /// Packets dropped by shedding, metrics in them are lost completely
+    pub shed_drops: usize,
+    /// Share of received metrics left out of aggregation by the cap, from 0 to 1.
+    /// Shed packets are not included, because the number of metrics in them is not known.
+    pub estimated_error: Float,
+}
+
+impl Accuracy {
+    /// Whether any aggregated value may be approximate
+    pub fn approximate(&self) -> bool {
+        self.capped_samples > 0 || self.shed_drops > 0
+    }
+}
+
+/// Cache state of a single worker thread
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct WorkerStats {
+    pub short_entries: usize,
+    pub long_entries: usize,
+    pub buffers: usize,
+    pub buffer_bytes: usize,
+    /// Estimated bytes taken by short cache entries, including timer samples
+    #[serde(default)]
+    pub short_bytes: usize,
+    #[serde(default)]
+    pub long_bytes: usize,
+    /// Part of cache bytes taken by timer samples
+    #[serde(default)]
+    pub timer_bytes: usize,
+    /// Rough estimation of memory taken by caches and buffers
+    pub estimated_bytes: usize,
+    /// Time the stats request spent in queue and processing, shows how loaded the worker is
+    #[serde(default)]
+    pub response_ms: u64,
+}
+
+fn timer_size(metric: &Metric<Float>) -> usize {
+    match metric.mtype {
+        MetricType::Timer(ref samples) => samples.capacity() * mem::size_of::<Float>(),
+        _ => 0,
+    }
+}
+
+// approximate size of a cache entry, not counting hashmap internals, names are counted in the name table
+pub fn entry_size(metric: &Metric<Float>) -> usize {
+    mem::size_of::<NameId>() + mem::size_of::<Metric<Float>>() + timer_size(metric)
+}
+
+/// Approximate size of all entries in cache
+pub fn cache_size(cache: &Cache) -> usize {
+    cache.values().map(entry_size).sum()
+}
+
+impl WorkerStats {
+    pub fn new(short: &ShardedCache, long: &ShardedCache, buffers: usize, buffer_bytes: usize) -> Self {
+        let (short_bytes, long_bytes) = (short.shards().iter().map(cache_size).sum(), long.shards().iter().map(cache_size).sum());
+        let timer_bytes = short.iter().chain(long.iter()).map(|(_, metric)| timer_size(metric)).sum();
+        Self {
+            short_entries: short.len(),
+            long_entries: long.len(),
+            buffers,
+            buffer_bytes,
+            short_bytes,
+            long_bytes,
+            timer_bytes,
+            estimated_bytes: short_bytes + long_bytes + buffer_bytes,
+            response_ms: 0,
+        }
+    }
+}
+
+/// Estimated memory taken by each subsystem
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct MemoryReport {
+    pub total_bytes: usize,
+    /// Caches and parsing buffers of each worker, `None` if worker did not answer
+    pub workers: Vec<Option<WorkerMemory>>,
+    /// The last snapshot taken for sending to peers
+    pub peer_snapshot_bytes: usize,
+    /// Aggregated metrics kept while flushing is paused
+    pub paused_flush_bytes: usize,
+    /// Metrics being sent to backend
+    pub backend_queue_bytes: usize,
+    /// Table of metric names shared by all caches
+    #[serde(default)]
+    pub name_table_bytes: usize,
+    #[serde(default)]
+    pub names: usize,
+    /// Memory budget in effect, configured or taken from cgroup memory limit, 0 if there is none
+    #[serde(default)]
+    pub budget_bytes: usize,
+    #[serde(default = "normal_pressure")]
+    pub pressure: Pressure,
+}
+
+fn normal_pressure() -> Pressure {
+    Pressure::Normal
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct WorkerMemory {
+    pub short_cache_bytes: usize,
+    pub long_cache_bytes: usize,
+    pub timer_sample_bytes: usize,
+    pub parse_buffer_bytes: usize,
+}
+
+/// Collect memory estimations from workers and other subsystems
+pub fn collect_memory(chans: &[Sender<Task>]) -> impl Future<Item = MemoryReport, Error = ()> + Send {
+    worker_stats(chans).map(|workers| {
+        let workers = workers
+            .into_iter()
+            .map(|stats| stats.map(|stats| WorkerMemory { short_cache_bytes: stats.short_bytes, long_cache_bytes: stats.long_bytes, timer_sample_bytes: stats.timer_bytes, parse_buffer_bytes: stats.buffer_bytes }))
+            .collect::<Vec<_>>();
+        let peer_snapshot_bytes = PEER_SNAPSHOT_BYTES.load(Ordering::Relaxed);
+        let paused_flush_bytes = paused_flush_bytes();
+        let backend_queue_bytes = BACKEND_QUEUE_BYTES.load(Ordering::Relaxed);
+        let (name_table_bytes, names) = {
+            let table = NAMES.read().unwrap();
+            (table.size(), table.len())
+        };
+        let total_bytes = workers.iter().filter_map(|worker| worker.as_ref()).map(|worker| worker.short_cache_bytes + worker.long_cache_bytes + worker.parse_buffer_bytes).sum::<usize>() + peer_snapshot_bytes + paused_flush_bytes + backend_queue_bytes + name_table_bytes;
+        let budget_bytes = RESOURCE_LIMITS.memory_budget(&RUNTIME_CONFIG.read().unwrap().memory);
+        MemoryReport { total_bytes, workers, peer_snapshot_bytes, paused_flush_bytes, backend_queue_bytes, name_table_bytes, names, budget_bytes, pressure: Pressure::current() }
+    })
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct StatsReport {
+    pub uptime_ms: u64,
+    /// Version and features of the server and hash of it's configuration
+    #[serde(default)]
+    pub build: BuildInfo,
+    /// Time since previous stats request, rates are counted over this period
+    pub since_last_ms: u64,
+    pub counters: Counters,
+    /// Aggregation degradation since previous stats request
+    #[serde(default)]
+    pub accuracy: Accuracy,
+    /// Per second values since previous stats request
+    pub rates: Vec<(String, Float)>,
+    /// Stats for each worker, `None` if worker did not answer
+    pub workers: Vec<Option<WorkerStats>>,
+    pub listeners: Vec<ListenerReport>,
+    /// Current values of runtime tunables
+    pub tunables: Vec<(String, usize)>,
+    /// Statistics of global allocator, `None` if they could not be read
+    #[serde(default)]
+    pub allocator: Option<AllocatorStats>,
+    /// Ingestion task queue of every worker
+    #[serde(default)]
+    pub worker_queues: Vec<QueueValues>,
+    /// Aggregated metrics waiting to be sent to backend
+    #[serde(default)]
+    pub flush_queue: QueueValues,
+    /// Statsd parsing errors by kind and recent lines having them
+    #[serde(default)]
+    pub parse_errors: ParseErrorReport,
+    /// Pipeline latency histograms since start
+    #[serde(default)]
+    pub latency: Vec<(String, LatencyValues)>,
+    /// CPU and memory limits detected on start
+    #[serde(default)]
+    pub limits: ResourceLimits,
+}
+
+/// Per second values of a single listener since previous stats request
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ListenerReport {
+    pub protocol: String,
+    pub address: String,
+    pub packets: Float,
+    pub lines: Float,
+    pub metrics: Float,
+    pub parse_errors: Float,
+    pub drops: Float,
+    /// Socket settings in effect, for listeners that can be tuned
+    #[serde(default)]
+    pub socket: Option<SocketValues>,
+    /// Drops by every source rule since start
+    #[serde(default)]
+    pub source_drops: Vec<RuleDrops>,
+}
+
+impl ListenerReport {
+    fn new(protocol: &str, address: SocketAddr, delta: &ListenerValues, seconds: Float) -> Self {
+        let rate = |value: usize| if seconds > 0f64 { value as Float / seconds } else { 0f64 };
+        Self {
+            protocol: protocol.to_string(),
+            address: address.to_string(),
+            packets: rate(delta.packets),
+            lines: rate(delta.lines),
+            metrics: rate(delta.metrics),
+            parse_errors: rate(delta.parse_errors),
+            drops: rate(delta.drops),
+            socket: None,
+            source_drops: Vec::new(),
+        }
+    }
+}
+
+fn as_millis(d: Duration) -> u64 {
+    d.as_secs() * 1000 + d.subsec_millis() as u64
+}
+
+/// Time since the stats were initialized at start, ms
+pub fn uptime_ms() -> u64 {
+    as_millis(STARTED.elapsed())
+}
+
+/// Ask every worker about it's state, `None` is returned for workers that did not answer
+pub fn worker_stats(chans: &[Sender<Task>]) -> impl Future<Item = Vec<Option<WorkerStats>>, Error = ()> + Send {
+    let workers = chans
+        .iter()
+        .map(|chan| {
+            let (tx, rx) = oneshot::channel();
+            let sent = Instant::now();
+            chan.clone().send(Task::Stats(tx)).map_err(|_| ()).and_then(|_| rx.map_err(|_| ())).then(move |res| {
+                Ok::<_, ()>(res.ok().map(|mut stats: WorkerStats| {
+                    stats.response_ms = as_millis(sent.elapsed());
+                    stats
+                }))
+            })
+        })
+        .collect::<Vec<_>>();
+
+    join_all(workers)
+}
+
+/// Collect counters and ask every worker about it's state
+pub fn collect_stats(chans: &[Sender<Task>]) -> impl Future<Item = StatsReport, Error = ()> + Send {
+    let worker_count = chans.len();
+    worker_stats(chans).map(move |workers| {
+        let now = Instant::now();
+        let counters = Counters::load();
+        let (since_last, delta) = {
+            let mut last = LAST_SCRAPE.lock().unwrap();
+            let (since_last, delta) = match *last {
+                Some((at, ref prev)) => (now.duration_since(at), counters.delta(prev)),
+                None => (now.duration_since(*STARTED), counters.clone()),
+            };
+            *last = Some((now, counters.clone()));
+            (since_last, delta)
+        };
+        let seconds = as_millis(since_last) as Float / 1000f64;
+        let rates = delta.to_vec().into_iter().map(|(name, value)| (name.to_string(), if seconds > 0f64 { value as Float / seconds } else { 0f64 })).collect();
+        let listeners = {
+            let config = RUNTIME_CONFIG.read().unwrap();
+            let mut statsd = ListenerReport::new("statsd-udp", config.network.listen, &delta.statsd_udp, seconds);
+            statsd.socket = Some(STATSD_UDP_SOCKET.values(config.network.listen.port()));
+            statsd.source_drops = STATSD_SOURCES.drops();
+            let mut peer = ListenerReport::new("peer-tcp", config.network.peer_listen, &delta.peer_tcp, seconds);
+            peer.source_drops = PEER_SOURCES.drops();
+            vec![statsd, peer]
+        };
+        let tunables = TUNABLES.iter().map(|tunable| (tunable.name.to_string(), tunable.get())).collect();
+        StatsReport { uptime_ms: as_millis(now.duration_since(*STARTED)), build: BuildInfo::current(), since_last_ms: as_millis(since_last), counters, accuracy: delta.accuracy(), rates, workers, listeners, tunables, allocator: allocator_stats(), worker_queues: worker_queues(worker_count), flush_queue: FLUSH_QUEUE.values(), parse_errors: PARSE_ERROR_STATS.report(), latency: histograms().iter().map(|histogram| (histogram.name.to_string(), histogram.values())).collect(), limits: RESOURCE_LIMITS.clone() }
+    })
+}
+
+/// What to rank metrics by in top requests
+#[derive(Debug, Clone, PartialEq)]
+pub enum TopBy {
+    /// Number of updates received
+    Samples,
+    /// Estimated memory taken
+    Bytes,
+    /// Number of unique names under prefix of this number of dot-separated parts
+    Names(usize),
+}
+
+// first `depth` dot-separated parts of the name
+fn name_prefix(name: &[u8], depth: usize) -> &[u8] {
+    match name.iter().enumerate().filter(|(_, c)| **c == b'.').nth(depth.max(1) - 1) {
+        Some((pos, _)) => &name[..pos],
+        None => name,
+    }
+}
+
+/// Rank names or prefixes in worker caches returning `n` heaviest ones
+pub fn worker_top(short: &ShardedCache, long: &ShardedCache, by: &TopBy, n: usize) -> Vec<(Bytes, u64)> {
+    let names = NAMES.read().unwrap();
+    let mut ranks: HashMap<Bytes, u64> = HashMap::new();
+    match by {
+        TopBy::Samples => short.iter().chain(long.iter()).map(|(id, metric)| *ranks.entry(names.name(*id).clone()).or_insert(0) += metric.update_counter as u64).last(),
+        TopBy::Bytes => short.iter().chain(long.iter()).map(|(id, metric)| *ranks.entry(names.name(*id).clone()).or_insert(0) += (names.name(*id).len() + entry_size(metric)) as u64).last(),
+        TopBy::Names(depth) => short
+            .iter()
+            .map(|(id, _)| id)
+            .chain(long.iter().map(|(id, _)| id).filter(|id| !short.contains_key(*id)))
+            .map(|id| *ranks.entry(Bytes::from(name_prefix(names.name(*id), *depth))).or_insert(0) += 1)
+            .last(),
+    };
+    let mut ranks = ranks.into_iter().collect::<Vec<_>>();
+    ranks.sort_unstable_by(|a, b| b.1.cmp(&a.1));
+    ranks.truncate(n);
+    ranks
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct TopEntry {
+    pub name: String,
+    pub value: u64,
+}
+
+/// Ask workers for their heaviest metrics and join the answers. Every worker only returns it's own top,
+/// so values for names spread across many workers may be a bit lower than real ones.
+pub fn collect_top(chans: &[Sender<Task>], by: TopBy, n: usize) -> impl Future<Item = Vec<TopEntry>, Error = ()> + Send {
+    let answers = chans
+        .iter()
+        .map(|chan| {
+            let (tx, rx) = oneshot::channel();
+            chan.clone().send(Task::Top(by.clone(), n, tx)).map_err(|_| ()).and_then(|_| rx.map_err(|_| ()))
+        })
+        .collect::<Vec<_>>();
+
+    join_all(answers).map(move |answers| {
+        let mut joined: HashMap<Bytes, u64> = HashMap::new();
+        answers.into_iter().flat_map(|answer| answer.into_iter()).map(|(name, value)| *joined.entry(name).or_insert(0) += value).last();
+        let mut joined = joined.into_iter().map(|(name, value)| TopEntry { name: String::from_utf8_lossy(&name).into_owned(), value }).collect::<Vec<_>>();
+        joined.sort_unstable_by(|a, b| b.value.cmp(&a.value).then_with(|| a.name.cmp(&b.name)));
+        joined.truncate(n);
+        joined
+    })
+}
+
+// write a single metric family in Prometheus text format
+fn write_family(out: &mut String, name: &str, kind: &str, help: &str, values: &[(Option<usize>, Float)]) {
+    // writing to string cannot fail
+    writeln!(out, "# HELP bioyino_{} {}", name, help).unwrap();
+    writeln!(out, "# TYPE bioyino_{} {}", name, kind).unwrap();
+    for (worker, value) in values {
+        match worker {
+            Some(worker) => writeln!(out, "bioyino_{}{{worker=\"{}\"}} {}", name, worker, value).unwrap(),
+            None => writeln!(out, "bioyino_{} {}", name, value).unwrap(),
+        }
+    }
+}
+
+fn write_histogram(out: &mut String, name: &str, help: &str, values: &LatencyValues) {
+    writeln!(out, "# HELP bioyino_{} {}", name, help).unwrap();
+    writeln!(out, "# TYPE bioyino_{} histogram", name).unwrap();
+    let mut cumulative = 0;
+    for (idx, count) in values.buckets.iter().enumerate() {
+        cumulative += count;
+        match BOUNDS_US.get(idx) {
+            Some(bound) => writeln!(out, "bioyino_{}_bucket{{le=\"{}\"}} {}", name, *bound as Float / 1_000_000f64, cumulative).unwrap(),
+            None => writeln!(out, "bioyino_{}_bucket{{le=\"+Inf\"}} {}", name, cumulative).unwrap(),
+        }
+    }
+    writeln!(out, "bioyino_{}_sum {}", name, values.sum_us as Float / 1_000_000f64).unwrap();
+    writeln!(out, "bioyino_{}_count {}", name, cumulative).unwrap();
+}
+
+/// Render bioyino own metrics in Prometheus text exposition format
+pub fn render_prometheus(counters: &Counters, workers: &[Option<WorkerStats>]) -> String {
+    let mut out = String::new();
+    for (name, value) in counters.to_vec() {
+        let name = format!("{}_total", name.replace('-', "_").replace('.', "_"));
+        write_family(&mut out, &name, "counter", "Internal counter, see /stats for details", &[(None, value as Float)]);
+    }
+
+    let flag = |flag: &AtomicBool| if flag.load(Ordering::Relaxed) { 1f64 } else { 0f64 };
+    let flags: &[(&str, &AtomicBool, &str)] = &[
+        ("is_leader", &IS_LEADER, "1 if this node is sending metrics to backend"),
+        ("ingestion_paused", &INGESTION_PAUSED, "1 if receiving metrics is paused"),
+        ("flush_paused", &FLUSH_PAUSED, "1 if sending metrics to backend is paused"),
+        ("statsd_listening", &STATSD_LISTENING, "1 if statsd socket is bound"),
+        ("peer_listening", &PEER_LISTENING, "1 if peer server is listening"),
+        ("consensus_reachable", &CONSENSUS_REACHABLE, "1 if consensus is reachable"),
+        ("backend_ok", &BACKEND_OK, "1 if last attempt to send metrics to backend succeeded"),
+    ];
+    for (name, value, help) in flags {
+        write_family(&mut out, name, "gauge", help, &[(None, flag(value))]);
+    }
+    write_family(&mut out, "uptime_seconds", "gauge", "Time since server start", &[(None, STARTED.elapsed().as_secs() as Float)]);
+    for (name, value) in allocator_stats().map(|stats| stats.to_vec()).unwrap_or_default() {
+        let (name, help) = match name {
+            "allocator.fragmentation" => ("allocator_fragmentation_ratio".to_string(), "Share of resident memory not holding any allocations"),
+            _ => (format!("{}_bytes", name.replace('.', "_")), "Memory as reported by global allocator"),
+        };
+        write_family(&mut out, &name, "gauge", help, &[(None, value)]);
+    }
+
+    let per_worker = |field: &Fn(&WorkerStats) -> usize| workers.iter().enumerate().filter_map(|(idx, stats)| stats.as_ref().map(|stats| (Some(idx), field(stats) as Float))).collect::<Vec<_>>();
+    write_family(&mut out, "worker_short_entries", "gauge", "Metrics in worker short cache", &per_worker(&|stats| stats.short_entries));
+    write_family(&mut out, "worker_long_entries", "gauge", "Metrics in worker long cache", &per_worker(&|stats| stats.long_entries));
+    write_family(&mut out, "worker_buffers", "gauge", "Incoming data buffers held by worker", &per_worker(&|stats| stats.buffers));
+    write_family(&mut out, "worker_estimated_bytes", "gauge", "Estimated memory taken by worker caches and buffers", &per_worker(&|stats| stats.estimated_bytes));
+    write_family(&mut out, "worker_response_milliseconds", "gauge", "Time worker took to answer stats request", &per_worker(&|stats| stats.response_ms as usize));
+    let queues = worker_queues(workers.len());
+    let per_queue = |field: &Fn(&QueueValues) -> usize| queues.iter().enumerate().map(|(idx, queue)| (Some(idx), field(queue) as Float)).collect::<Vec<_>>();
+    write_family(&mut out, "worker_queue_depth", "gauge", "Ingestion tasks waiting in worker queue", &per_queue(&|queue| queue.depth));
+    write_family(&mut out, "worker_queue_high_watermark", "gauge", "Maximum depth of worker queue since start or the last autotune check", &per_queue(&|queue| queue.high_watermark));
+    write_family(&mut out, "worker_queue_capacity", "gauge", "Worker queue size, ingestion tasks are dropped or wait when it is reached", &per_queue(&|queue| queue.capacity));
+    write_family(&mut out, "flush_queue_depth", "gauge", "Aggregated metrics waiting to be sent to backend", &[(None, FLUSH_QUEUE.depth() as Float)]);
+    let answered = workers.iter().filter(|stats| stats.is_some()).count();
+    write_family(&mut out, "workers_responding", "gauge", "Number of workers answered stats request", &[(None, answered as Float)]);
+    for histogram in histograms().iter() {
+        write_histogram(&mut out, &format!("{}_latency_seconds", histogram.name), "Time from receiving metrics to cache(ingest) or from interval end to backend(flush)", &histogram.values());
+    }
+    out
+}
+
+/// Make sure uptime is counted from program start rather than the first request
+pub fn init_stats() {
+    lazy_static::initialize(&STARTED);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    use bytes::BytesMut;
+    use futures::sync::mpsc;
+    use futures::Stream;
+    use tokio::runtime::current_thread::Runtime;
+
+    use crate::config::System;
+    use crate::intern::intern;
+    use crate::task::TaskRunner;
+    use crate::util::prepare_log;
+
+    // worker answering tasks on the runtime, with cache filled by parsing the data
+    fn spawn_worker(runtime: &mut Runtime, data: &[u8]) -> Sender<Task> {
+        let (worker, tasks) = mpsc::channel(4);
+        let mut runner = TaskRunner::new(prepare_log("stats_worker"), Arc::new(System::default()), 16);
+        runner.run(Task::Parse(1, BytesMut::from(data), Instant::now(), None));
+        runtime.spawn(tasks.for_each(move |task| {
+            runner.run(task);
+            Ok(())
+        }));
+        worker
+    }
+
+    #[test]
+    fn json_stats() {
+        let mut runtime = Runtime::new().unwrap();
+        let worker = spawn_worker(&mut runtime, b"json.stats:1|c\n");
+        // worker that has gone is reported as not answering
+        let (gone, _) = mpsc::channel(4);
+        let report = runtime.block_on(collect_stats(&[worker, gone])).unwrap();
+        assert_eq!(report.workers.len(), 2);
+        assert_eq!(report.workers[0].as_ref().unwrap().short_entries, 1);
+        assert!(report.workers[1].is_none());
+        assert_eq!(report.listeners.iter().map(|listener| listener.protocol.as_str()).collect::<Vec<_>>(), vec!["statsd-udp", "peer-tcp"]);
+        assert!(report.rates.iter().any(|(name, _)| name == "ingress"));
+        assert!(report.since_last_ms <= report.uptime_ms);
+
+        let json = serde_json::to_string(&report).unwrap();
+        assert!(json.contains("\"since-last-ms\":"));
+        assert!(json.contains("\"short-entries\":1"));
+        let parsed: StatsReport = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.counters, report.counters);
+        assert_eq!(parsed.workers[1], None);
+        assert_eq!(parsed.tunables, report.tunables);
+    }
+
+    #[test]
+    fn memory_report() {
+        let mut runtime = Runtime::new().unwrap();
+        let worker = spawn_worker(&mut runtime, b"memory.report.counter:1|c\nmemory.report.timer:1|ms\nmemory.report.timer:2|ms\n");
+        let (gone, _) = mpsc::channel(4);
+        let report = runtime.block_on(collect_memory(&[worker, gone])).unwrap();
+        assert_eq!(report.workers.len(), 2);
+        assert!(report.workers[1].is_none());
+        let memory = report.workers[0].clone().unwrap();
+        assert!(memory.short_cache_bytes > 0);
+        assert_eq!(memory.long_cache_bytes, 0);
+        assert!(memory.timer_sample_bytes >= 2 * mem::size_of::<Float>());
+        assert!(report.names >= 2 && report.name_table_bytes > 0);
+        assert!(report.total_bytes >= memory.short_cache_bytes + report.name_table_bytes);
+
+        // timer samples are counted on top of the entry
+        let counter = Metric::new(1f64, MetricType::Counter, None, None).unwrap();
+        let timer = Metric::new(1f64, MetricType::Timer(vec![1f64, 2f64, 3f64]), None, None).unwrap();
+        assert!(timer_size(&timer) >= 3 * mem::size_of::<Float>());
+        assert_eq!(entry_size(&timer), entry_size(&counter) + timer_size(&timer));
+    }
+
+    #[test]
+    fn listener_counters() {
+        static COUNTERS: ListenerCounters = ListenerCounters::new();
+        let counters = &COUNTERS;
+        counters.packets.add(2);
+        counters.metrics.add(5);
+        counters.parse_errors.add(1);
+        let prev = counters.load();
+        // every line is either a metric or a parse error
+        assert_eq!(prev, ListenerValues { packets: 2, lines: 6, metrics: 5, parse_errors: 1, drops: 0 });
+
+        counters.packets.add(10);
+        counters.metrics.add(20);
+        counters.drops.add(4);
+        let delta = counters.load().delta(&prev);
+        assert_eq!(delta, ListenerValues { packets: 10, lines: 20, metrics: 20, parse_errors: 0, drops: 4 });
+
+        let report = ListenerReport::new("statsd-udp", "127.0.0.1:8125".parse().unwrap(), &delta, 2f64);
+        assert_eq!((report.packets, report.lines, report.drops), (5f64, 10f64, 2f64));
+        assert_eq!(report.address, "127.0.0.1:8125");
+        assert_eq!(ListenerReport::new("peer-tcp", "127.0.0.1:8136".parse().unwrap(), &delta, 0f64).packets, 0f64);
+
+        let values = Counters { statsd_udp: delta, ..Default::default() }.to_vec();
+        assert!(values.contains(&("listener.statsd-udp.packet", 10)));
+        assert!(values.contains(&("listener.statsd-udp.drop", 4)));
+        assert!(values.contains(&("listener.peer-tcp.line", 0)));
+    }
+
+    #[test]
+    fn prometheus_format() {
+        let counters = Counters { ingress: 10, ..Default::default() };
+        let workers = vec![Some(WorkerStats { short_entries: 3, ..Default::default() }), None];
+        let rendered = render_prometheus(&counters, &workers);
+        assert!(rendered.contains("# TYPE bioyino_ingress_total counter\nbioyino_ingress_total 10\n"));
+        assert!(rendered.contains("bioyino_worker_short_entries{worker=\"0\"} 3\n"));
+        assert!(!rendered.contains("worker=\"1\""));
+        assert!(rendered.contains("bioyino_workers_responding 1\n"));
+        assert!(rendered.contains("# TYPE bioyino_ingest_latency_seconds histogram\n"));
+        assert!(rendered.contains("bioyino_flush_latency_seconds_bucket{le=\"+Inf\"}"));
+        assert!(rendered.contains("bioyino_listener_statsd_udp_packet_total 0\n"));
+        assert!(rendered.contains("bioyino_backend_carbon_error_total 0\n"));
+    }
+
+    #[test]
+    fn aggregation_accuracy() {
+        let counters = Counters { ingress_metric: 1000, capped_sample: 250, capped_metric: 2, ..Default::default() };
+        let accuracy = counters.accuracy();
+        assert!(accuracy.approximate());
+        assert_eq!(accuracy.capped_metrics, 2);
+        assert_eq!(accuracy.estimated_error, 0.25);
+        assert!(!Counters::default().accuracy().approximate());
+        assert_eq!(Counters { shed_drop: 1, ..Default::default() }.accuracy().estimated_error, 0f64);
+    }
+
+    #[test]
+    fn top_prefixes() {
+        let mut short = ShardedCache::new(4, 16);
+        let mut long = ShardedCache::new(4, 16);
+        let metric = Metric::new(1f64, MetricType::Counter, None, None).unwrap();
+        for name in &["a.b.c", "a.b.d", "a.x", "b"] {
+            short.update(intern(name.as_bytes()), metric.clone());
+        }
+        long.update(intern(b"a.b.c"), metric.clone());
+        long.update(intern(b"a.b.e"), metric.clone());
+
+        let top = worker_top(&short, &long, &TopBy::Names(2), 2);
+        assert_eq!(top.len(), 2);
+        assert_eq!(top[0], (Bytes::from("a.b"), 3));
+        assert_eq!(name_prefix(b"a.b.c", 1), b"a");
+        assert_eq!(name_prefix(b"abc", 2), b"abc");
+    }
+}
@@ -0,0 +1,164 @@
+use std::backtrace::Backtrace;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io;
+use std::panic::{self, catch_unwind, AssertUnwindSafe};
+use std::process;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use futures::future::{err, loop_fn, ok, Either, Future, IntoFuture, Loop};
+use slog::{crit, error, Logger};
+use tokio::timer::Delay;
+
+use crate::config::Supervision;
+use crate::events::event;
+use crate::{PANICS, RUNTIME_CONFIG};
+
+thread_local! {
+    // message and backtrace of the last panic of the thread, taken by the supervisor after catching it
+    static LAST_PANIC: RefCell<Option<(String, String)>> = RefCell::new(None);
+}
+
+/// Keep the message and backtrace of every panic for supervisors, must be called before any threads are started
+pub fn init_supervision() {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let message = info.to_string();
+        let backtrace = Backtrace::force_capture().to_string();
+        let _ = LAST_PANIC.try_with(|last| *last.borrow_mut() = Some((message, backtrace)));
+        default_hook(info);
+    }));
+}
+
+/// Restarts of a single subsystem: delays grow with every crash in restart window, and the whole
+/// process is stopped if there are more crashes than allowed, so a crash loop does not go unnoticed
+pub struct Supervisor {
+    name: String,
+    crashes: VecDeque<Instant>,
+    log: Logger,
+}
+
+impl Supervisor {
+    pub fn new(name: &str, log: &Logger) -> Self {
+        Self { name: name.to_string(), crashes: VecDeque::new(), log: log.clone() }
+    }
+
+    /// Delay before the next restart after the given number of crashes in window, None if it is a crash loop
+    pub fn restart_delay(options: &Supervision, crashes: usize) -> Option<Duration> {
+        if crashes > options.max_restarts {
+            return None;
+        }
+        let delay = options.restart_delay.saturating_mul(1u64 << (crashes.saturating_sub(1)).min(32) as u32);
+        Some(Duration::from_millis(delay.min(options.restart_delay_max)))
+    }
+
+    /// Count and log a panic caught, returning how long to wait before restarting. Exits the process on crash loop.
+    pub fn crashed(&mut self) -> Duration {
+        PANICS.add(1);
+        let (message, backtrace) = LAST_PANIC.with(|last| last.borrow_mut().take()).unwrap_or_else(|| ("unknown panic".to_string(), String::new()));
+        let options = RUNTIME_CONFIG.read().unwrap().supervision.clone();
+        let now = Instant::now();
+        let window = Duration::from_millis(options.restart_window);
+        while self.crashes.front().map(|crash| now.duration_since(*crash) > window).unwrap_or(false) {
+            self.crashes.pop_front();
+        }
+        self.crashes.push_back(now);
+
+        error!(self.log, "subsystem panicked"; "subsystem"=>&self.name, "panic"=>&message, "crashes-in-window"=>self.crashes.len(), "backtrace"=>backtrace);
+        event("panic", format!("{} panicked: {}", self.name, message));
+        match Self::restart_delay(&options, self.crashes.len()) {
+            Some(delay) => delay,
+            None => {
+                crit!(self.log, "subsystem is in crash loop, stopping"; "subsystem"=>&self.name, "crashes"=>self.crashes.len(), "window-ms"=>options.restart_window);
+                event("panic", format!("{} crashed {} times in {}ms, stopping", self.name, self.crashes.len(), options.restart_window));
+                // let the asynchronous logger write the records
+                thread::sleep(Duration::from_millis(500));
+                process::exit(1);
+            }
+        }
+    }
+}
+
+/// Spawn a named thread running `body` again after it panics
+pub fn spawn_supervised<F>(name: String, log: Logger, body: F) -> io::Result<JoinHandle<()>>
+where
+    F: Fn() + Send + 'static,
+{
+    thread::Builder::new().name(name.clone()).spawn(move || {
+        let mut supervisor = Supervisor::new(&name, &log);
+        while catch_unwind(AssertUnwindSafe(&body)).is_err() {
+            thread::sleep(supervisor.crashed());
+        }
+    })
+}
+
+/// Run a future made by `make` again after it or `make` itself panics, errors and normal completion are passed as is
+pub fn supervised<F, R>(name: &str, log: &Logger, make: F) -> impl Future<Item = (), Error = ()>
+where
+    F: FnMut() -> R,
+    R: IntoFuture<Item = (), Error = ()>,
+{
+    loop_fn((make, Supervisor::new(name, log)), |(mut make, mut supervisor)| {
+        let future = match catch_unwind(AssertUnwindSafe(|| make().into_future())) {
+            Ok(future) => Either::A(AssertUnwindSafe(future).catch_unwind()),
+            Err(panic) => Either::B(err(panic)),
+        };
+        future.then(move |result| match result {
+            Ok(Ok(())) => Either::A(ok(Loop::Break(()))),
+            Ok(Err(())) => Either::A(err(())),
+            Err(_) => {
+                let delay = supervisor.crashed();
+                Either::B(Delay::new(Instant::now() + delay).then(move |_| Ok::<_, ()>(Loop::Continue((make, supervisor)))))
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::prepare_log;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::runtime::current_thread::Runtime;
+
+    #[test]
+    fn restart_delays() {
+        let options = Supervision { restart_delay: 100, restart_delay_max: 1000, max_restarts: 5, restart_window: 60000 };
+        assert_eq!(Supervisor::restart_delay(&options, 1), Some(Duration::from_millis(100)));
+        assert_eq!(Supervisor::restart_delay(&options, 3), Some(Duration::from_millis(400)));
+        assert_eq!(Supervisor::restart_delay(&options, 5), Some(Duration::from_millis(1000)));
+        assert_eq!(Supervisor::restart_delay(&options, 6), None);
+    }
+
+    #[test]
+    fn restart_panicked() {
+        let log = prepare_log("restart_panicked");
+        let panics = PANICS.get();
+        let runs = Arc::new(AtomicUsize::new(0));
+        let thread_runs = runs.clone();
+        let handle = spawn_supervised("test".to_string(), log.clone(), move || {
+            if thread_runs.fetch_add(1, Ordering::SeqCst) == 0 {
+                panic!("first run");
+            }
+        })
+        .unwrap();
+        handle.join().unwrap();
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+
+        let future_runs = Arc::new(AtomicUsize::new(0));
+        let counted_runs = future_runs.clone();
+        let future = supervised("test", &log, move || {
+            if counted_runs.fetch_add(1, Ordering::SeqCst) == 0 {
+                panic!("first run");
+            }
+            Ok::<(), ()>(())
+        });
+        Runtime::new().unwrap().block_on(future).unwrap();
+        assert_eq!(future_runs.load(Ordering::SeqCst), 2);
+        // the panic of the supervised thread is counted in it's own cell, which is retired when the thread finishes,
+        // other tests may panic at the same time, so only the growth is checked
+        assert!(PANICS.get() >= panics + 2);
+    }
+}
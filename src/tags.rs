@@ -0,0 +1,219 @@
+use std::borrow::Cow;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::config::{Metrics, TagChars, TagOverflow};
+use crate::{DROPPED_TAGS, TAG_OVERFLOWS};
+
+/// Tags are kept in metric names in canonical form: the name followed by `;tag=value` for every tag, sorted by tag name,
+/// which is also the syntax of graphite tagged series. The same series gets the same name whatever the order of tags
+/// it was sent with, so it is the same cache entry on every node and in every snapshot.
+pub const TAG_SEPARATOR: u8 = b';';
+
+// normalization options, taken by parsing threads for every tagged line
+static LOWERCASE_NAMES: AtomicBool = AtomicBool::new(false);
+static STRICT_CHARS: AtomicBool = AtomicBool::new(false);
+static MAX_TAGS: AtomicUsize = AtomicUsize::new(0);
+static MAX_TAG_LENGTH: AtomicUsize = AtomicUsize::new(0);
+static MAX_TAG_BYTES: AtomicUsize = AtomicUsize::new(0);
+static OVERFLOW_BUCKET: AtomicBool = AtomicBool::new(false);
+
+/// The only tag of metrics with tags over limits when they are put to the overflow bucket
+pub const OVERFLOW_TAG: &[u8] = b";overflow=tags";
+
+/// How tags are normalized besides sorting and removing duplicates, limits of 0 are not checked
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TagOptions {
+    /// Tag names are lowercased, so `Env` and `env` are the same tag
+    pub lowercase_names: bool,
+    /// Only ASCII letters, digits, `_`, `-` and `.` are kept, other bytes are replaced with `_`
+    pub strict_chars: bool,
+    /// Number of tags kept, the first ones by name
+    pub max_tags: usize,
+    /// Tags with longer names or values are over limit
+    pub max_tag_length: usize,
+    /// Bytes of tags in canonical form kept, separators included
+    pub max_tag_bytes: usize,
+    /// Tags over limits make the metric get `OVERFLOW_TAG` as the only tag instead of being dropped alone
+    pub overflow_bucket: bool,
+}
+
+impl TagOptions {
+    pub fn current() -> Self {
+        Self {
+            lowercase_names: LOWERCASE_NAMES.load(Ordering::Relaxed),
+            strict_chars: STRICT_CHARS.load(Ordering::Relaxed),
+            max_tags: MAX_TAGS.load(Ordering::Relaxed),
+            max_tag_length: MAX_TAG_LENGTH.load(Ordering::Relaxed),
+            max_tag_bytes: MAX_TAG_BYTES.load(Ordering::Relaxed),
+            overflow_bucket: OVERFLOW_BUCKET.load(Ordering::Relaxed),
+        }
+    }
+
+    // whether another tag fits into limits after `kept` tags of `bytes` bytes
+    fn fits(&self, kept: usize, bytes: usize, tag: &[u8], value: &[u8]) -> bool {
+        let over = |len: usize, max: usize| max > 0 && len > max;
+        !over(kept + 1, self.max_tags) && !over(tag.len(), self.max_tag_length) && !over(value.len(), self.max_tag_length) && !over(bytes + tag.len() + value.len() + 2, self.max_tag_bytes)
+    }
+
+    // separators of canonical form cannot be a part of tag names or values in any case, graphite does not
+    // allow `!` and `^` in tag names either
+    fn clean(&self, part: &[u8], is_value: bool) -> Vec<u8> {
+        part.iter()
+            .map(|c| match *c {
+                c if self.strict_chars && !(c.is_ascii_alphanumeric() || c == b'_' || c == b'-' || c == b'.') => b'_',
+                TAG_SEPARATOR | b' ' => b'_',
+                b'=' | b'!' | b'^' if !is_value => b'_',
+                c if self.lowercase_names && !is_value => c.to_ascii_lowercase(),
+                c => c,
+            })
+            .collect()
+    }
+}
+
+/// Apply `metrics` tag options to names parsed from now on
+pub fn set_tag_options(metrics: &Metrics) {
+    LOWERCASE_NAMES.store(metrics.lowercase_tag_names, Ordering::Relaxed);
+    STRICT_CHARS.store(metrics.tag_chars == TagChars::Strict, Ordering::Relaxed);
+    MAX_TAGS.store(metrics.max_tags, Ordering::Relaxed);
+    MAX_TAG_LENGTH.store(metrics.max_tag_length, Ordering::Relaxed);
+    MAX_TAG_BYTES.store(metrics.max_tag_bytes, Ordering::Relaxed);
+    OVERFLOW_BUCKET.store(metrics.tag_overflow == TagOverflow::Bucket, Ordering::Relaxed);
+}
+
+/// Split canonical name into the name itself and tags, the tags part starts with the separator or is empty
+pub fn split_tags(name: &[u8]) -> (&[u8], &[u8]) {
+    match name.iter().position(|c| *c == TAG_SEPARATOR) {
+        Some(pos) => name.split_at(pos),
+        None => (name, &[]),
+    }
+}
+
+/// Tag name and value pairs of a canonical name
+pub fn tags(name: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    let (_, tags) = split_tags(name);
+    tags.split(|c| *c == TAG_SEPARATOR).filter(|tag| tag.len() > 0).map(|tag| match tag.iter().position(|c| *c == b'=') {
+        Some(pos) => (&tag[..pos], &tag[pos + 1..]),
+        None => (tag, &[][..]),
+    })
+}
+
+/// Build canonical name of `name` with `tags`: tag names and values are cleaned by `options`, then tags are sorted by name
+/// and a tag given more than once keeps the last value, so the same tags in any order make the same name.
+/// Tags over limits of `options` are dropped and counted in `dropped-tags` stat, or, with overflow bucket, replace all
+/// tags with `OVERFLOW_TAG` and are counted in `tag-overflow` stat. Names without tags are borrowed as is.
+pub fn canonical<'a>(name: &'a [u8], tags: &[(&[u8], &[u8])], options: TagOptions) -> Cow<'a, [u8]> {
+    if tags.len() == 0 {
+        return Cow::Borrowed(name);
+    }
+    let mut tags = tags.iter().map(|(tag, value)| (options.clean(tag, false), options.clean(value, true))).collect::<Vec<_>>();
+    // stable, so the last of the same tags stays the last one, names are compared after cleaning, as they may become equal
+    tags.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut out = Vec::with_capacity(name.len() + tags.iter().map(|(tag, value)| tag.len() + value.len() + 2).sum::<usize>());
+    out.extend_from_slice(name);
+    let (mut kept, mut dropped) = (0, 0);
+    for (idx, (tag, value)) in tags.iter().enumerate() {
+        if tags.get(idx + 1).map(|next| next.0 == *tag).unwrap_or(false) {
+            continue;
+        }
+        if !options.fits(kept, out.len() - name.len(), tag, value) {
+            dropped += 1;
+            continue;
+        }
+        kept += 1;
+        out.push(TAG_SEPARATOR);
+        out.extend_from_slice(tag);
+        out.push(b'=');
+        out.extend_from_slice(value);
+    }
+    if dropped > 0 {
+        if options.overflow_bucket {
+            TAG_OVERFLOWS.add(1);
+            out.truncate(name.len());
+            out.extend_from_slice(OVERFLOW_TAG);
+        } else {
+            DROPPED_TAGS.add(dropped);
+        }
+    }
+    Cow::Owned(out)
+}
+
+/// Canonical form of a name received from peers or agents, which may have tags in any order or come from
+/// older versions, names already canonical are kept as is
+pub fn normalize(name: Bytes) -> Bytes {
+    let (base, tag_part) = split_tags(&name);
+    if tag_part.len() == 0 {
+        return name;
+    }
+    let list = tags(&name).filter(|(tag, value)| tag.len() > 0 && value.len() > 0).collect::<Vec<_>>();
+    let normalized = match canonical(base, &list, TagOptions::current()) {
+        Cow::Owned(normalized) => normalized,
+        Cow::Borrowed(_) => return Bytes::from(base),
+    };
+    if normalized[..] == name[..] {
+        name
+    } else {
+        Bytes::from(normalized)
+    }
+}
+
+/// Put aggregated series name to the buffer: `prefix.` if it is not empty, the name, the suffix of aggregate, then tags,
+/// so aggregates of a tagged series are tagged series with the same tags, in graphite syntax carbon understands natively
+pub fn put_series(buf: &mut BytesMut, prefix: &[u8], name: &[u8], suffix: &[u8]) {
+    let (name, tags) = split_tags(name);
+    buf.reserve(prefix.len() + name.len() + suffix.len() + tags.len() + 1);
+    if prefix.len() > 0 {
+        buf.put_slice(prefix);
+        buf.put_slice(b".");
+    }
+    buf.put_slice(name);
+    buf.put_slice(suffix);
+    buf.put_slice(tags);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn canonical_tags() {
+        let list: Vec<(&[u8], &[u8])> = vec![(b"host", b"web1"), (b"env", b"prod"), (b"host", b"web2"), (b"bad;tag", b"a=b c")];
+        let name = canonical(b"requests", &list, TagOptions::default());
+        assert_eq!(&name[..], &b"requests;bad_tag=a=b_c;env=prod;host=web2"[..]);
+        assert_eq!(split_tags(&name), (&b"requests"[..], &b";bad_tag=a=b_c;env=prod;host=web2"[..]));
+        assert_eq!(tags(&name).collect::<Vec<_>>(), vec![(&b"bad_tag"[..], &b"a=b_c"[..]), (b"env", b"prod"), (b"host", b"web2")]);
+
+        // the same series with tags in another order
+        let reordered: Vec<(&[u8], &[u8])> = vec![(b"bad;tag", b"a=b c"), (b"host", b"web2"), (b"env", b"prod")];
+        assert_eq!(canonical(b"requests", &reordered, TagOptions::default()), name);
+
+        let options = TagOptions { lowercase_names: true, strict_chars: true, ..TagOptions::default() };
+        let mixed: Vec<(&[u8], &[u8])> = vec![(b"Env", b"Prod"), (b"env", b"dev"), (b"path", b"/api/v1")];
+        assert_eq!(&canonical(b"requests", &mixed, options)[..], &b"requests;env=dev;path=_api_v1"[..]);
+
+        // limits keep the first tags by name that fit
+        let many: Vec<(&[u8], &[u8])> = vec![(b"d", b"4"), (b"c", b"3"), (b"b", b"2"), (b"a", b"1"), (b"long", b"value")];
+        let limited = TagOptions { max_tags: 3, max_tag_length: 4, ..TagOptions::default() };
+        assert_eq!(&canonical(b"requests", &many, limited)[..], &b"requests;a=1;b=2;c=3"[..]);
+        let limited = TagOptions { max_tag_bytes: 8, ..TagOptions::default() };
+        assert_eq!(&canonical(b"requests", &many, limited)[..], &b"requests;a=1;b=2"[..]);
+        let bucket = TagOptions { max_tags: 3, overflow_bucket: true, ..TagOptions::default() };
+        assert_eq!(&canonical(b"requests", &many, bucket)[..], &b"requests;overflow=tags"[..]);
+
+        match canonical(b"plain", &[], TagOptions::default()) {
+            Cow::Borrowed(name) => assert_eq!(name, b"plain"),
+            Cow::Owned(_) => panic!("untagged name is copied"),
+        }
+
+        assert_eq!(normalize(Bytes::from("requests;host=web1;env=prod")), Bytes::from("requests;env=prod;host=web1"));
+        assert_eq!(normalize(Bytes::from("requests;env=prod")), Bytes::from("requests;env=prod"));
+        assert_eq!(normalize(Bytes::from("requests;")), Bytes::from("requests"));
+
+        let mut buf = BytesMut::new();
+        put_series(&mut buf, b"", b"timer;env=prod", b".percentile.99");
+        assert_eq!(&buf.take()[..], &b"timer.percentile.99;env=prod"[..]);
+        put_series(&mut buf, b"updates", b"counter", b".count");
+        assert_eq!(&buf.take()[..], &b"updates.counter.count"[..]);
+    }
+}
@@ -0,0 +1,154 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use futures::sync::mpsc::{self, Receiver, Sender};
+use lazy_static::lazy_static;
+use serde_derive::Serialize;
+use serde_json;
+
+use bioyino_metric::{Metric, MetricType};
+
+use crate::task::MetricQuery;
+use crate::tunables::{MAX_TAILS, TAIL_BUFFER};
+use crate::Float;
+
+
+// fast check for workers to avoid taking a lock when nobody is tailing
+static TAIL_ACTIVE: AtomicBool = AtomicBool::new(false);
+static NEXT_TAIL_ID: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    static ref TAILS: RwLock<Vec<Arc<Tail>>> = { RwLock::new(Vec::new()) };
+}
+
+struct TailState {
+    window_start: Instant,
+    sent: u32,
+    tx: Sender<Bytes>,
+}
+
+struct Tail {
+    id: usize,
+    query: MetricQuery,
+    // maximum samples per second
+    max_rate: u32,
+    state: Mutex<TailState>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct TailSample<'a> {
+    name: &'a str,
+    #[serde(rename = "type")]
+    mtype: &'static str,
+    value: Float,
+}
+
+// format a sample as server-sent event
+fn sse_event(name: &[u8], metric: &Metric<Float>) -> Bytes {
+    let mtype = match metric.mtype {
+        MetricType::Counter => "counter",
+        MetricType::Gauge(_) => "gauge",
+        MetricType::Timer(_) => "timer",
+        MetricType::Set(_) => "set",
+        _ => "other",
+    };
+    let sample = TailSample { name: &String::from_utf8_lossy(name), mtype, value: metric.value };
+    // serializing a struct of plain values cannot fail
+    let mut event = b"data: ".to_vec();
+    event.extend_from_slice(&serde_json::to_vec(&sample).unwrap());
+    event.extend_from_slice(b"\n\n");
+    Bytes::from(event)
+}
+
+/// Start tailing metrics matching the query. Returns `None` if there are too many tails already.
+/// Tail is removed when receiver is dropped.
+pub fn subscribe(query: MetricQuery, max_rate: u32) -> Option<Receiver<Bytes>> {
+    let mut tails = TAILS.write().unwrap();
+    // tails are usually removed when a sample cannot be sent, but some of them may never receive one
+    tails.retain(|tail| !tail.state.lock().unwrap().tx.is_closed());
+    if tails.len() >= MAX_TAILS.get() {
+        return None;
+    }
+    let (tx, rx) = mpsc::channel(TAIL_BUFFER.get());
+    let id = NEXT_TAIL_ID.fetch_add(1, Ordering::Relaxed);
+    tails.push(Arc::new(Tail { id, query, max_rate, state: Mutex::new(TailState { window_start: Instant::now(), sent: 0, tx }) }));
+    TAIL_ACTIVE.store(true, Ordering::Relaxed);
+    Some(rx)
+}
+
+/// Send incoming metric to all tails interested in it
+pub fn publish(name: &[u8], metric: &Metric<Float>) {
+    if !TAIL_ACTIVE.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut closed = Vec::new();
+    {
+        let tails = TAILS.read().unwrap();
+        for tail in tails.iter().filter(|tail| tail.query.matches(name)) {
+            let mut state = tail.state.lock().unwrap();
+            let now = Instant::now();
+            if now.duration_since(state.window_start) >= Duration::from_secs(1) {
+                state.window_start = now;
+                state.sent = 0;
+            }
+            if state.sent >= tail.max_rate {
+                continue;
+            }
+            state.sent += 1;
+            if let Err(e) = state.tx.try_send(sse_event(name, metric)) {
+                // full buffer only means the client is slow, the sample is skipped then
+                if e.is_disconnected() {
+                    closed.push(tail.id);
+                }
+            }
+        }
+    }
+
+    if closed.len() > 0 {
+        let mut tails = TAILS.write().unwrap();
+        tails.retain(|tail| !closed.contains(&tail.id));
+        TAIL_ACTIVE.store(tails.len() > 0, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::future::lazy;
+    use futures::{Async, Future, Stream};
+
+    // take a sample from tail without waiting for it
+    fn try_next(rx: &mut Receiver<Bytes>) -> Option<Bytes> {
+        match lazy(|| rx.poll()).wait().unwrap() {
+            Async::Ready(sample) => sample,
+            Async::NotReady => None,
+        }
+    }
+
+    #[test]
+    fn tail_samples() {
+        let counter = Metric::new(1f64, MetricType::Counter, None, None).unwrap();
+        let gauge = Metric::new(0.5f64, MetricType::Gauge(None), None, None).unwrap();
+        let mut rx = subscribe(MetricQuery::Glob("tail.samples.*".to_string()), 2).unwrap();
+
+        publish(&Bytes::from("tail.other"), &counter);
+        publish(&Bytes::from("tail.samples.first"), &counter);
+        publish(&Bytes::from("tail.samples.second"), &gauge);
+        // only 2 samples per second are sent
+        publish(&Bytes::from("tail.samples.third"), &counter);
+
+        assert_eq!(try_next(&mut rx), Some(Bytes::from(&b"data: {\"name\":\"tail.samples.first\",\"type\":\"counter\",\"value\":1.0}\n\n"[..])));
+        assert_eq!(try_next(&mut rx), Some(Bytes::from(&b"data: {\"name\":\"tail.samples.second\",\"type\":\"gauge\",\"value\":0.5}\n\n"[..])));
+        assert_eq!(try_next(&mut rx), None);
+
+        // tail is removed when the client is gone
+        let id = NEXT_TAIL_ID.load(Ordering::Relaxed) - 1;
+        drop(rx);
+        let _rx = subscribe(MetricQuery::All, 1).unwrap();
+        assert!(!TAILS.read().unwrap().iter().any(|tail| tail.id == id));
+    }
+}
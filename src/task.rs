@@ -1,7 +1,6 @@
-use std::collections::hash_map::Entry;
 use std::collections::HashMap;
-use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use bytes::{BufMut, Bytes, BytesMut};
 use futures::sync::mpsc::UnboundedSender;
@@ -10,13 +9,34 @@ use futures::{Future, Sink};
 use slog::{debug, warn, Logger};
 use tokio::runtime::current_thread::spawn;
 
-use bioyino_metric::parser::{MetricParser, ParseErrorHandler};
-use bioyino_metric::Metric;
+use bioyino_metric::parser::ParseErrorHandler;
+use bioyino_metric::{Metric, MetricType};
 
+use crate::accounting::{count_sample, flush_samples};
 use crate::aggregate::AggregateOptions;
+use crate::arena::SampleArena;
+use crate::cache::{current_epoch, new_generation, update_metric, ShardedCache, CACHE_SHARDS};
 use crate::config::System;
+use crate::counter::Counter;
+use crate::parse_errors::PARSE_ERROR_STATS;
+use crate::parser::StatsdParser;
+use crate::tags::put_series;
+use crate::intern::{LocalNames, NAMES};
+use crate::latency::INGEST_LATENCY;
+use crate::queue::FLUSH_QUEUE;
+use crate::quota::{check_quota, QuotaVerdict};
+use crate::ratelimit::{Allowance, STATSD_LIMIT};
+use crate::rules::{Rules, Verdict, RULES};
+use crate::stats::{worker_top, TopBy, WorkerStats, STATSD_UDP};
+use crate::tail::publish;
+use crate::trace::{Span, SpanContext};
+use crate::tunables::MAX_UNPARSED_BUFFER;
+use crate::util::glob_match;
 
-use crate::{Cache, Float, AGG_ERRORS, DROPS, INGRESS_METRICS, PARSE_ERRORS, PEER_ERRORS};
+use crate::{Cache, Float, AGG_ERRORS, DROPS, FILTERED, INGRESS_METRICS, PARSE_ERRORS, PEER_ERRORS, SLOW_TASKS, CACHE_SHRINKS};
+
+// sources sending metrics are far fewer than metrics, so their buffers are not allocated for all of them
+const MAX_SOURCES_CAPACITY: usize = 8192;
 
 #[derive(Debug)]
 pub struct AggregateData {
@@ -27,34 +47,242 @@ pub struct AggregateData {
     pub response: UnboundedSender<(Bytes, Float)>,
 }
 
+/// A way to select metrics from worker caches without changing them
+#[derive(Debug, Clone)]
+pub enum MetricQuery {
+    /// Exact metric name
+    Exact(Bytes),
+    /// Shell-like glob pattern, see `util::glob_match`
+    Glob(String),
+    /// All metrics in cache
+    All,
+}
+
+impl MetricQuery {
+    pub fn matches(&self, name: &[u8]) -> bool {
+        match self {
+            MetricQuery::Exact(exact) => &exact[..] == name,
+            MetricQuery::Glob(pattern) => glob_match(pattern.as_bytes(), name),
+            MetricQuery::All => true,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Task {
-    Parse(u64, BytesMut),
+    /// Buffer from a source with the time it's first packet was received and the trace of receiving it if sampled
+    Parse(u64, BytesMut, Instant, Option<SpanContext>),
     AddMetric(Bytes, Metric<Float>),
     AddMetrics(Vec<(Bytes, Metric<Float>)>),
     AddSnapshot(Vec<(Bytes, Metric<Float>)>),
-    TakeSnapshot(oneshot::Sender<Cache>),
-    Rotate(oneshot::Sender<Cache>),
+    // answered with cache shards
+    TakeSnapshot(oneshot::Sender<Vec<Cache>>),
+    // move metrics not sent to peers yet to the generation being rotated, so the leader flushes everything received
+    TakeShort,
+    // rotate only metrics with the specified prefix if it is set
+    Rotate(Option<Bytes>, oneshot::Sender<Vec<Cache>>),
+    // emptied shards of a rotated generation and timer sample buffers taken from it given back
+    // to be reused by the next one
+    Recycle(Vec<Cache>, Vec<Vec<Float>>),
     Aggregate(AggregateData),
+    Query(MetricQuery, oneshot::Sender<Cache>),
+    Ping(oneshot::Sender<()>),
+    Stats(oneshot::Sender<WorkerStats>),
+    Top(TopBy, usize, oneshot::Sender<Vec<(Bytes, u64)>>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TaskKind {
+    Parse,
+    AddMetric,
+    AddMetrics,
+    AddSnapshot,
+    TakeSnapshot,
+    TakeShort,
+    Rotate,
+    Recycle,
+    Aggregate,
+    Query,
+    Ping,
+    Stats,
+    Top,
 }
 
-fn update_metric(cache: &mut Cache, name: Bytes, metric: Metric<Float>) {
-    match cache.entry(name) {
-        Entry::Occupied(ref mut entry) => {
-            entry.get_mut().aggregate(metric).unwrap_or_else(|_| {
-                AGG_ERRORS.fetch_add(1, Ordering::Relaxed);
-            });
+impl TaskKind {
+    pub const ALL: [TaskKind; 13] = [TaskKind::Parse, TaskKind::AddMetric, TaskKind::AddMetrics, TaskKind::AddSnapshot, TaskKind::TakeSnapshot, TaskKind::TakeShort, TaskKind::Rotate, TaskKind::Recycle, TaskKind::Aggregate, TaskKind::Query, TaskKind::Ping, TaskKind::Stats, TaskKind::Top];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            TaskKind::Parse => "parse",
+            TaskKind::AddMetric => "add-metric",
+            TaskKind::AddMetrics => "add-metrics",
+            TaskKind::AddSnapshot => "add-snapshot",
+            TaskKind::TakeSnapshot => "take-snapshot",
+            TaskKind::TakeShort => "take-short",
+            TaskKind::Rotate => "rotate",
+            TaskKind::Recycle => "recycle",
+            TaskKind::Aggregate => "aggregate",
+            TaskKind::Query => "query",
+            TaskKind::Ping => "ping",
+            TaskKind::Stats => "stats",
+            TaskKind::Top => "top",
         }
-        Entry::Vacant(entry) => {
-            entry.insert(metric);
+    }
+}
+
+// tasks run by all workers and time spent running them by task kind
+pub static TASK_COUNTS: [Counter; 13] = [Counter::new(), Counter::new(), Counter::new(), Counter::new(), Counter::new(), Counter::new(), Counter::new(), Counter::new(), Counter::new(), Counter::new(), Counter::new(), Counter::new(), Counter::new()];
+pub static TASK_TIME_US: [Counter; 13] = [Counter::new(), Counter::new(), Counter::new(), Counter::new(), Counter::new(), Counter::new(), Counter::new(), Counter::new(), Counter::new(), Counter::new(), Counter::new(), Counter::new(), Counter::new()];
+
+/// Number of tasks of every kind run since start and microseconds spent running them
+pub fn task_counts() -> Vec<(TaskKind, usize, usize)> {
+    TaskKind::ALL.iter().map(|kind| (*kind, TASK_COUNTS[*kind as usize].get(), TASK_TIME_US[*kind as usize].get())).collect()
+}
+
+/// What a task was about, taken before running it to report the task if it runs too long
+#[derive(Debug, PartialEq)]
+pub struct TaskSummary {
+    pub kind: &'static str,
+    /// Metric name or prefix the task is for
+    pub name: Option<Bytes>,
+    /// Number of metrics, shards or bytes in the task
+    pub size: usize,
+}
+
+impl Task {
+    pub fn kind(&self) -> TaskKind {
+        match self {
+            Task::Parse(..) => TaskKind::Parse,
+            Task::AddMetric(..) => TaskKind::AddMetric,
+            Task::AddMetrics(..) => TaskKind::AddMetrics,
+            Task::AddSnapshot(..) => TaskKind::AddSnapshot,
+            Task::TakeSnapshot(..) => TaskKind::TakeSnapshot,
+            Task::TakeShort => TaskKind::TakeShort,
+            Task::Rotate(..) => TaskKind::Rotate,
+            Task::Recycle(..) => TaskKind::Recycle,
+            Task::Aggregate(..) => TaskKind::Aggregate,
+            Task::Query(..) => TaskKind::Query,
+            Task::Ping(..) => TaskKind::Ping,
+            Task::Stats(..) => TaskKind::Stats,
+            Task::Top(..) => TaskKind::Top,
+        }
+    }
+
+    pub fn summary(&self) -> TaskSummary {
+        let (name, size) = match self {
+            Task::Parse(_, buf, _, _) => (None, buf.len()),
+            Task::AddMetric(name, _) => (Some(name.clone()), 1),
+            Task::AddMetrics(list) => (None, list.len()),
+            Task::AddSnapshot(list) => (None, list.len()),
+            Task::TakeSnapshot(_) => (None, 0),
+            Task::TakeShort => (None, 0),
+            Task::Rotate(prefix, _) => (prefix.clone(), 0),
+            Task::Recycle(shards, samples) => (None, shards.len() + samples.len()),
+            Task::Aggregate(data) => (Some(data.name.clone()), 1),
+            Task::Query(MetricQuery::Exact(name), _) => (Some(name.clone()), 1),
+            Task::Query(MetricQuery::Glob(pattern), _) => (Some(Bytes::from(pattern.as_bytes())), 0),
+            Task::Query(MetricQuery::All, _) => (None, 0),
+            Task::Ping(_) => (None, 0),
+            Task::Stats(_) => (None, 0),
+            Task::Top(_, n, _) => (None, *n),
+        };
+        TaskSummary { kind: self.kind().name(), name, size }
+    }
+}
+
+fn is_untyped(line: &[u8]) -> bool {
+    line.contains(&b':') && !line.contains(&b'|')
+}
+
+// add type to `name:value` lines parser would consider errors, buffers consist of whole lines,
+// because every datagram is a separate buffer
+fn type_untyped(buf: BytesMut, suffix: &[u8]) -> BytesMut {
+    if !buf.split(|c| *c == b'\n').any(is_untyped) {
+        return buf;
+    }
+    let mut typed = BytesMut::with_capacity(buf.len() + suffix.len() * 16);
+    for (idx, line) in buf.split(|c| *c == b'\n').enumerate() {
+        if idx > 0 {
+            typed.extend_from_slice(b"\n");
+        }
+        typed.extend_from_slice(line);
+        if is_untyped(line) {
+            typed.extend_from_slice(suffix);
+        }
+    }
+    typed
+}
+
+// apply ingestion rules to a new metric and put it to short cache if it passes,
+// `unmerged` are short cache shards sent as snapshot, but not merged to long cache yet
+// name of metric type quota overflow metrics are split by
+fn type_name(metric: &Metric<Float>) -> &'static str {
+    match metric.mtype {
+        MetricType::Counter => "counter",
+        MetricType::Gauge(_) => "gauge",
+        MetricType::Timer(_) => "timer",
+        MetricType::Set(_) => "set",
+        _ => "other",
+    }
+}
+
+// the last step of adding a metric, passed ingestion rules
+fn add_accepted(short: &mut ShardedCache, names: &mut LocalNames, name: &[u8], metric: Metric<Float>) {
+    let renamed = match check_quota(name, type_name(&metric)) {
+        QuotaVerdict::Pass => None,
+        QuotaVerdict::Drop => return,
+        QuotaVerdict::Rename(overflow) => Some(overflow),
+    };
+    let name = renamed.as_ref().map(|overflow| &overflow[..]).unwrap_or(name);
+    count_sample(name);
+    publish(name, &metric);
+    short.update(names.intern(name), metric);
+}
+
+fn add_checked(short: &mut ShardedCache, long: &ShardedCache, unmerged: &[Cache], names: &mut LocalNames, rules: &Rules, name: &[u8], metric: Metric<Float>) {
+    if rules.is_empty() {
+        return add_accepted(short, names, name, metric);
+    }
+    let name = match rules.check(name) {
+        Verdict::Pass(name) => name,
+        Verdict::Block => {
+            FILTERED.add(1);
+            return;
         }
     };
+    if let Some(max_names) = rules.max_names {
+        // names may be in both caches, so this is only an upper estimation of unique names
+        if short.len() + long.len() + unmerged.iter().map(|shard| shard.len()).sum::<usize>() >= max_names {
+            // names over the limit are not interned to keep the name table small too
+            let known = names.get(&name).map(|id| short.contains_key(&id) || long.contains_key(&id) || unmerged.iter().any(|shard| shard.contains_key(&id))).unwrap_or(false);
+            if !known {
+                FILTERED.add(1);
+                return;
+            }
+        }
+    }
+    add_accepted(short, names, &name, metric);
 }
 
 #[derive(Debug)]
 pub struct TaskRunner {
-    long: HashMap<Bytes, Metric<Float>>,
-    short: HashMap<Bytes, Metric<Float>>,
+    long: ShardedCache,
+    short: ShardedCache,
+    // ids of names in caches of this worker
+    names: LocalNames,
+    // short cache shards already sent to peers, they are merged to long cache one per task
+    unmerged: Vec<Cache>,
+    // long cache generation, a new one is started as soon as the global epoch changes
+    epoch: usize,
+    // previous generations swapped out, but not taken by rotation yet
+    rotated: Option<Vec<Cache>>,
+    // emptied shards returned after rotation, they become the next generation
+    spare: Option<Vec<Cache>>,
+    // metrics a new generation is allocated for if there are no spare shards
+    capacity: usize,
+    // metrics in the last swapped out generation
+    generation_len: usize,
+    arena: SampleArena,
     buffers: HashMap<u64, (usize, BytesMut)>,
     config: Arc<System>,
     log: Logger,
@@ -62,13 +290,78 @@ pub struct TaskRunner {
 
 impl TaskRunner {
     pub fn new(log: Logger, config: Arc<System>, cap: usize) -> Self {
-        Self { long: HashMap::with_capacity(cap), short: HashMap::with_capacity(cap), buffers: HashMap::with_capacity(cap), config, log }
+        Self { long: ShardedCache::new(CACHE_SHARDS, cap), short: ShardedCache::new(CACHE_SHARDS, cap), names: LocalNames::default(), unmerged: Vec::new(), epoch: current_epoch(), rotated: None, spare: None, capacity: cap, generation_len: 0, arena: SampleArena::new(config.metrics.sample_arena_size), buffers: HashMap::with_capacity(cap.min(MAX_SOURCES_CAPACITY)), config, log }
+    }
+
+    // swap long cache with an empty generation, the old one waits for rotation task to take it,
+    // so the interval ends at the same moment for all workers regardless of their queues
+    fn swap_generation(&mut self) {
+        let capacity = self.capacity;
+        let spare = self.spare.take().unwrap_or_else(|| new_generation(capacity));
+        let mut old = self.long.swap(spare);
+        self.generation_len = old.iter().map(|shard| shard.len()).sum();
+        // unmerged shards are rotated as is, aggregation joins all shards anyway
+        old.extend(self.unmerged.drain(..));
+        match self.rotated {
+            Some(ref mut rotated) => rotated.extend(old),
+            None => self.rotated = Some(old),
+        }
+    }
+
+    // shards keep the capacity they had at a spike of new names, empty shards having too much of it
+    // are allocated again for the number of names the last generation had
+    fn shrink(&self, shards: &mut [Cache]) {
+        let ratio = self.config.memory.shrink_ratio;
+        if ratio <= 0f32 {
+            return;
+        }
+        let expected = (self.generation_len.max(self.capacity) / CACHE_SHARDS) + 1;
+        for shard in shards.iter_mut().filter(|shard| (expected as f32) < shard.capacity() as f32 * ratio) {
+            *shard = Cache::with_capacity(expected);
+            CACHE_SHRINKS.add(1);
+        }
+    }
+
+    fn merge_all(&mut self) {
+        let (long, arena) = (&mut self.long, &mut self.arena);
+        self.unmerged.drain(..).map(|shard| long.merge(shard, arena)).last();
     }
 
     pub fn run(&mut self, task: Task) {
+        let epoch = current_epoch();
+        if epoch != self.epoch {
+            self.epoch = epoch;
+            self.swap_generation();
+        }
+        let kind = task.kind();
+        // summary takes a copy of metric name, so it is only taken when slow tasks are reported
+        let summary = if self.config.metrics.slow_task > 0 { Some(task.summary()) } else { None };
+        let start = Instant::now();
+        self.run_task(task);
+        flush_samples();
+        let elapsed = start.elapsed();
+        TASK_COUNTS[kind as usize].add(1);
+        TASK_TIME_US[kind as usize].add(elapsed.as_secs() as usize * 1_000_000 + elapsed.subsec_micros() as usize);
+        if let Some(summary) = summary {
+            if elapsed >= Duration::from_millis(self.config.metrics.slow_task) {
+                SLOW_TASKS.add(1);
+                let name = summary.name.as_ref().map(|name| String::from_utf8_lossy(name).into_owned()).unwrap_or_default();
+                warn!(self.log, "slow task"; "task"=>summary.kind, "name"=>name, "size"=>summary.size, "elapsed"=>format!("{:?}", elapsed));
+            }
+        }
+        if let Some(shard) = self.unmerged.pop() {
+            self.long.merge(shard, &mut self.arena);
+        }
+    }
+
+    fn run_task(&mut self, task: Task) {
         match task {
-            Task::Parse(addr, buf) => {
+            Task::Parse(addr, buf, received, trace) => {
                 let log = if self.config.metrics.log_parse_errors { Some(self.log.clone()) } else { None };
+                let buf = match self.config.statsd_untyped_as() {
+                    Some(untyped) => type_untyped(buf, untyped.suffix()),
+                    None => buf,
+                };
                 let buf = {
                     let len = buf.len();
                     let (_, ref mut prev_buf) = self
@@ -83,62 +376,179 @@ impl TaskRunner {
                     prev_buf
                 };
 
-                let parser = MetricParser::new(buf, self.config.metrics.max_unparsed_buffer, TaskParseErrorHandler(log));
-
-                for (name, metric) in parser {
-                    INGRESS_METRICS.fetch_add(1, Ordering::Relaxed);
-                    update_metric(&mut self.short, name, metric);
+                // names are slices of the buffer, they are only copied for names new to name table
+                let rules = RULES.read().unwrap().clone();
+                let mut parser = StatsdParser::new(&buf[..], MAX_UNPARSED_BUFFER.get(), TaskParseErrorHandler(log));
+                let mut allowance = Allowance::new(&STATSD_LIMIT);
+                match Span::child_of(trace, "parse") {
+                    None => {
+                        for (name, metric) in &mut parser {
+                            INGRESS_METRICS.add(1);
+                            STATSD_UDP.metrics.add(1);
+                            if !allowance.allow() {
+                                STATSD_UDP.drops.add(1);
+                                continue;
+                            }
+                            add_checked(&mut self.short, &self.long, &self.unmerged, &mut self.names, &rules, &name, metric);
+                        }
+                    }
+                    Some(mut span) => {
+                        // traced buffers are parsed fully before inserting, so both stages get their own span
+                        let parsed = (&mut parser).collect::<Vec<_>>();
+                        span.attr("bytes", buf.len());
+                        span.attr("metrics", parsed.len());
+                        drop(span);
+                        let _insert = Span::child_of(trace, "cache-insert");
+                        for (name, metric) in parsed {
+                            INGRESS_METRICS.add(1);
+                            STATSD_UDP.metrics.add(1);
+                            if !allowance.allow() {
+                                STATSD_UDP.drops.add(1);
+                                continue;
+                            }
+                            add_checked(&mut self.short, &self.long, &self.unmerged, &mut self.names, &rules, &name, metric);
+                        }
+                    }
                 }
+                let consumed = parser.consumed();
+                buf.split_to(consumed);
+                INGEST_LATENCY.record(received.elapsed());
+            }
+            Task::AddMetric(name, metric) => {
+                let rules = RULES.read().unwrap().clone();
+                add_checked(&mut self.short, &self.long, &self.unmerged, &mut self.names, &rules, &name, metric);
             }
-            Task::AddMetric(name, metric) => update_metric(&mut self.short, name, metric),
             Task::AddMetrics(mut list) => {
-                list.drain(..).map(|(name, metric)| update_metric(&mut self.short, name, metric)).last();
+                let rules = RULES.read().unwrap().clone();
+                let (short, long, unmerged, names) = (&mut self.short, &self.long, &self.unmerged, &mut self.names);
+                list.drain(..).map(|(name, metric)| add_checked(short, long, unmerged, names, &rules, &name, metric)).last();
             }
             Task::AddSnapshot(mut list) => {
                 // snapshots go to long cache to avoid being duplicated to other nodes
-                list.drain(..).map(|(name, metric)| update_metric(&mut self.long, name, metric)).last();
+                let (long, names, arena) = (&mut self.long, &mut self.names, &mut self.arena);
+                list.drain(..).map(|(name, metric)| long.update_in(names.intern(&name), metric, arena)).last();
             }
             Task::TakeSnapshot(channel) => {
-                // clone short cache for further sending
-                let short = self.short.clone();
-                // join short cache to long cache removing data from short
-                {
-                    let mut long = &mut self.long; // self.long cannot be borrowed in map, so we borrow it earlier
-                    self.short.drain().map(|(name, metric)| update_metric(&mut long, name, metric)).last();
-                }
+                // shards of the previous snapshot must be in long cache before the new ones are taken
+                self.merge_all();
+                // short cache shards are taken out as is, copies are sent, while the shards themselves
+                // are merged to long cache one by one between the next tasks
+                self.unmerged = self.short.take().into_iter().filter(|shard| shard.len() > 0).collect();
+                let snapshot = self.unmerged.clone();
 
-                // self.short now contains empty hashmap because of draining
-                // give a copy of snapshot to requestor
-                channel.send(short).unwrap_or_else(|_| {
-                    PEER_ERRORS.fetch_add(1, Ordering::Relaxed);
+                channel.send(snapshot).unwrap_or_else(|_| {
+                    PEER_ERRORS.add(1);
                     debug!(self.log, "shapshot not sent");
                 });
             }
-            Task::Rotate(channel) => {
-                let rotated = self.long.clone();
-                self.long.clear();
+            Task::TakeShort => {
+                // metrics are not snapshotted to peers after this, which is fine for the leader sending them
+                let short = self.short.take().into_iter().filter(|shard| shard.len() > 0);
+                match self.rotated {
+                    Some(ref mut rotated) => rotated.extend(short),
+                    // partial rotation takes metrics from long cache after merging
+                    None => self.unmerged.extend(short),
+                }
+            }
+            Task::Rotate(None, channel) => {
+                // generation is usually swapped already when epoch was advanced
+                if self.rotated.is_none() {
+                    self.swap_generation();
+                }
+                let rotated = self.rotated.take().unwrap_or_default();
                 let log = self.log.clone();
                 channel.send(rotated).unwrap_or_else(|_| {
                     debug!(log, "rotated data not sent");
-                    DROPS.fetch_add(1, Ordering::Relaxed);
+                    DROPS.add(1);
                 });
 
+                // names still in caches are marked in the global table, so their ids are not given to other names
+                // however many rotations they stay in short cache without being moved
+                let (short, long) = (&self.short, &self.long);
+                let forgotten = self.names.rotate(|id| short.contains_key(id) || long.contains_key(id));
+                debug!(self.log, "unused names forgotten"; "forgotten"=>forgotten, "names"=>self.names.len());
+
                 self.buffers.retain(|_, (ref mut times, _)| {
                     *times += 1;
                     *times < 5
                 });
+                let ratio = self.config.memory.shrink_ratio;
+                if ratio > 0f32 && (self.buffers.len() as f32) < self.buffers.capacity() as f32 * ratio {
+                    self.buffers.shrink_to_fit();
+                }
+            }
+            Task::Recycle(mut shards, samples) => {
+                // the interval is aggregated, so all samples buffers lent by arena are free again
+                self.arena.reset(samples);
+                // shards of unmerged caches are recycled too, only the needed number of them is kept
+                shards.truncate(CACHE_SHARDS);
+                if self.spare.is_none() && shards.len() == CACHE_SHARDS && shards.iter().all(|shard| shard.is_empty()) {
+                    self.shrink(&mut shards);
+                    self.spare = Some(shards);
+                }
+            }
+            Task::Rotate(Some(prefix), channel) => {
+                // partial rotation is not a real interval end, so buffers are not touched here
+                self.merge_all();
+                let names = NAMES.read().unwrap();
+                let rotated = self.long.shards().iter().map(|shard| shard.iter().filter(|(id, _)| names.name(**id).starts_with(&prefix)).map(|(id, metric)| (*id, metric.clone())).collect::<Cache>()).collect::<Vec<_>>();
+                self.long.retain(|id, _| !names.name(*id).starts_with(&prefix));
+                let log = self.log.clone();
+                channel.send(rotated).unwrap_or_else(|_| {
+                    debug!(log, "rotated data not sent");
+                    DROPS.add(1);
+                });
             }
 
             Task::Aggregate(data) => aggregate_task(data),
+            Task::Query(query, channel) => {
+                // metric may be in both caches at the same time, so values are joined here
+                // the same way it will be done on rotation
+                self.merge_all();
+                let mut found = Cache::new();
+                match query {
+                    MetricQuery::Exact(ref name) => {
+                        if let Some(id) = self.names.get(name) {
+                            self.long.get(&id).into_iter().chain(self.short.get(&id)).map(|metric| update_metric(&mut found, id, metric.clone())).last();
+                        }
+                    }
+                    MetricQuery::Glob(_) | MetricQuery::All => {
+                        let names = NAMES.read().unwrap();
+                        self.long.iter().chain(self.short.iter()).filter(|(id, _)| query.matches(names.name(**id))).map(|(id, metric)| update_metric(&mut found, *id, metric.clone())).last();
+                    }
+                }
+                channel.send(found).unwrap_or_else(|_| {
+                    debug!(self.log, "query result not sent");
+                });
+            }
+            Task::Ping(channel) => {
+                channel.send(()).unwrap_or_else(|_| {
+                    debug!(self.log, "ping response not sent");
+                });
+            }
+            Task::Stats(channel) => {
+                self.merge_all();
+                let buffer_bytes = self.buffers.values().map(|(_, buf)| buf.capacity()).sum();
+                channel.send(WorkerStats::new(&self.short, &self.long, self.buffers.len(), buffer_bytes)).unwrap_or_else(|_| {
+                    debug!(self.log, "stats response not sent");
+                });
+            }
+            Task::Top(by, n, channel) => {
+                self.merge_all();
+                channel.send(worker_top(&self.short, &self.long, &by, n)).unwrap_or_else(|_| {
+                    debug!(self.log, "top response not sent");
+                });
+            }
         }
     }
 
     // used in tests in peer.rs
-    pub fn get_long_entry(&self, e: &Bytes) -> Option<&Metric<Float>> {
-        self.long.get(e)
+    pub fn get_long_entry(&mut self, e: &Bytes) -> Option<&Metric<Float>> {
+        self.merge_all();
+        self.names.get(e).and_then(|id| self.long.get(&id))
     }
     pub fn get_short_entry(&self, e: &Bytes) -> Option<&Metric<Float>> {
-        self.short.get(e)
+        self.names.get(e).and_then(|id| self.short.get(&id))
     }
 }
 
@@ -146,20 +556,9 @@ pub fn aggregate_task(data: AggregateData) {
     let AggregateData { mut buf, name, metric, options, response } = data;
     let upd = if let Some(options) = options.update_counter {
         if metric.update_counter > options.threshold {
-            // + 2 is for dots
-            let cut_len = options.prefix.len() + name.len() + options.suffix.len() + 2;
-            buf.reserve(cut_len);
-            if options.prefix.len() > 0 {
-                buf.put_slice(&options.prefix);
-                buf.put_slice(b".");
-            }
-
-            buf.put_slice(&name);
-            if options.suffix.len() > 0 {
-                buf.put_slice(b".");
-                buf.put_slice(&options.suffix);
-            }
-
+            // update counters of tagged metrics keep the tags
+            let suffix = if options.suffix.len() > 0 { [&b"."[..], &options.suffix[..]].concat() } else { Vec::new() };
+            put_series(&mut buf, &options.prefix, &name, &suffix);
             let counter = buf.take().freeze();
             Some((counter, metric.update_counter.into()))
         } else {
@@ -172,19 +571,20 @@ pub fn aggregate_task(data: AggregateData) {
     metric
         .into_iter()
         .map(move |(suffix, value)| {
-            buf.extend_from_slice(&name);
-            buf.extend_from_slice(suffix.as_bytes());
+            put_series(&mut buf, b"", &name, suffix.as_bytes());
             let name = buf.take().freeze();
             (name, value)
         })
     .chain(upd)
         .map(|data| {
+            FLUSH_QUEUE.push(1);
             spawn(
                 response
                 .clone()
                 .send(data)
                 .map_err(|_| {
-                    AGG_ERRORS.fetch_add(1, Ordering::Relaxed);
+                    FLUSH_QUEUE.pop(1);
+                    AGG_ERRORS.add(1);
                 })
                 .map(|_| ()),
                 );
@@ -196,7 +596,9 @@ struct TaskParseErrorHandler(Option<Logger>);
 
 impl ParseErrorHandler for TaskParseErrorHandler {
     fn handle(&self, input: &[u8], pos: usize) {
-        PARSE_ERRORS.fetch_add(1, Ordering::Relaxed);
+        PARSE_ERRORS.add(1);
+        STATSD_UDP.parse_errors.add(1);
+        PARSE_ERROR_STATS.record(input, pos);
         if let Some(ref log) = self.0 {
             if let Ok(string) = std::str::from_utf8(input) {
                 warn!(log, "parsing error"; "buffer"=> format!("{:?}", string), "position"=>format!("{}", pos));
@@ -212,6 +614,9 @@ mod tests {
     use super::*;
     use metric::MetricType;
 
+    use crate::cache::advance_epoch;
+    use crate::config::UntypedAs;
+    use crate::intern::{intern, name_of, rotate_names};
     use crate::util::prepare_log;
 
     #[test]
@@ -222,18 +627,192 @@ mod tests {
         let mut config = System::default();
         config.metrics.log_parse_errors = true;
         let mut runner = TaskRunner::new(prepare_log("parse_trashed"), Arc::new(config), 16);
-        runner.run(Task::Parse(2, data));
+        runner.run(Task::Parse(2, data, Instant::now(), None));
 
         let key: Bytes = "gorets1".into();
-        let metric = runner.short.get(&key).unwrap().clone();
+        let metric = runner.get_short_entry(&key).unwrap().clone();
         assert_eq!(metric.value, 1000f64);
         assert_eq!(metric.mtype, MetricType::Gauge(Some(1i8)));
         assert_eq!(metric.sampling, None);
 
         let key: Bytes = "gorets2".into();
-        let metric = runner.short.get(&key).unwrap().clone();
+        let metric = runner.get_short_entry(&key).unwrap().clone();
         assert_eq!(metric.value, 1000f64);
         assert_eq!(metric.mtype, MetricType::Gauge(Some(-1i8)));
         assert_eq!(metric.sampling, Some(0.5f32));
     }
+
+    #[test]
+    fn parse_untyped_metrics() {
+        let mut config = System::default();
+        config.metrics.untyped_as = Some(UntypedAs::Counter);
+        config.network.untyped_as = Some(UntypedAs::Gauge);
+        let mut runner = TaskRunner::new(prepare_log("parse_untyped"), Arc::new(config), 16);
+
+        let mut data = BytesMut::new();
+        data.extend_from_slice(b"legacy.gauge:5\ntyped.counter:1|c\nlegacy.counter:7\n");
+        runner.run(Task::Parse(1, data, Instant::now(), None));
+
+        assert_eq!(runner.get_short_entry(&"legacy.gauge".into()).unwrap().mtype, MetricType::Gauge(None));
+        assert_eq!(runner.get_short_entry(&"typed.counter".into()).unwrap().mtype, MetricType::Counter);
+        assert_eq!(runner.get_short_entry(&"legacy.counter".into()).unwrap().mtype, MetricType::Gauge(None));
+
+        let mut runner = TaskRunner::new(prepare_log("parse_untyped"), Arc::new(System::default()), 16);
+        let mut data = BytesMut::new();
+        data.extend_from_slice(b"legacy.gauge:5\n");
+        runner.run(Task::Parse(1, data, Instant::now(), None));
+        assert!(runner.get_short_entry(&"legacy.gauge".into()).is_none());
+    }
+
+    #[test]
+    fn query_metrics_from_both_caches() {
+        let mut runner = TaskRunner::new(prepare_log("query_metrics"), Arc::new(System::default()), 16);
+
+        let mut data = BytesMut::new();
+        data.extend_from_slice(b"some.test.counter:1|c\nsome.other.counter:1|c\n");
+        runner.run(Task::Parse(1, data, Instant::now(), None));
+
+        // move everything to long cache
+        let (tx, _rx) = oneshot::channel();
+        runner.run(Task::TakeSnapshot(tx));
+
+        let mut data = BytesMut::new();
+        data.extend_from_slice(b"some.test.counter:2|c\n");
+        runner.run(Task::Parse(1, data, Instant::now(), None));
+
+        let (tx, mut rx) = oneshot::channel();
+        runner.run(Task::Query(MetricQuery::Exact("some.test.counter".into()), tx));
+        let found = rx.try_recv().unwrap().unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found.get(&intern(b"some.test.counter")).unwrap().value, 3f64);
+
+        let (tx, mut rx) = oneshot::channel();
+        runner.run(Task::Query(MetricQuery::Glob("some.*.counter".into()), tx));
+        let found = rx.try_recv().unwrap().unwrap();
+        assert_eq!(found.len(), 2);
+
+        // querying must not change the caches
+        assert_eq!(runner.get_short_entry(&"some.test.counter".into()).unwrap().value, 2f64);
+        assert_eq!(runner.get_long_entry(&"some.test.counter".into()).unwrap().value, 1f64);
+    }
+
+    #[test]
+    fn rotate_swapped_generation() {
+        let mut runner = TaskRunner::new(prepare_log("rotate_generation"), Arc::new(System::default()), 16);
+
+        let mut data = BytesMut::new();
+        data.extend_from_slice(b"generation.counter:1|c\n");
+        runner.run(Task::Parse(1, data, Instant::now(), None));
+        let (tx, _rx) = oneshot::channel();
+        runner.run(Task::TakeSnapshot(tx));
+
+        // the same as epoch change seen by the worker, metrics coming after it belong to the next interval
+        runner.swap_generation();
+        let (tx, _rx) = oneshot::channel();
+        runner.run(Task::TakeSnapshot(tx));
+        assert!(runner.get_long_entry(&"generation.counter".into()).is_none());
+
+        let (tx, mut rx) = oneshot::channel();
+        runner.run(Task::Rotate(None, tx));
+        let mut rotated = rx.try_recv().unwrap().unwrap();
+        assert_eq!(rotated.iter().map(|shard| shard.len()).sum::<usize>(), 1);
+
+        rotated.iter_mut().map(|shard| shard.clear()).last();
+        runner.run(Task::Recycle(rotated, Vec::new()));
+        assert!(runner.spare.is_some());
+        runner.swap_generation();
+        assert!(runner.spare.is_none());
+    }
+
+    #[test]
+    fn rotate_with_short_cache() {
+        let mut runner = TaskRunner::new(prepare_log("rotate_short"), Arc::new(System::default()), 16);
+        let mut data = BytesMut::new();
+        data.extend_from_slice(b"short.counter:1|c\nshort.prefixed.counter:1|c\n");
+        runner.run(Task::Parse(1, data, Instant::now(), None));
+
+        // partial rotation takes metrics still waiting for a snapshot
+        runner.run(Task::TakeShort);
+        let (tx, mut rx) = oneshot::channel();
+        runner.run(Task::Rotate(Some("short.prefixed.".into()), tx));
+        let rotated = rx.try_recv().unwrap().unwrap();
+        assert_eq!(rotated.iter().map(|shard| shard.len()).sum::<usize>(), 1);
+        assert!(runner.get_short_entry(&"short.counter".into()).is_none());
+
+        let mut data = BytesMut::new();
+        data.extend_from_slice(b"short.counter:2|c\n");
+        runner.run(Task::Parse(1, data, Instant::now(), None));
+        // full rotation right after epoch change
+        runner.swap_generation();
+        runner.run(Task::TakeShort);
+        let (tx, mut rx) = oneshot::channel();
+        runner.run(Task::Rotate(None, tx));
+        let rotated = rx.try_recv().unwrap().unwrap();
+        let id = intern(b"short.counter");
+        assert_eq!(rotated.iter().filter_map(|shard| shard.get(&id)).map(|metric| metric.value).sum::<f64>(), 3f64);
+        assert!(runner.get_short_entry(&"short.counter".into()).is_none());
+        assert!(runner.get_long_entry(&"short.counter".into()).is_none());
+    }
+
+    #[test]
+    fn names_in_short_cache_survive_rotations() {
+        let mut runner = TaskRunner::new(prepare_log("names_survive_rotations"), Arc::new(System::default()), 16);
+        let mut data = BytesMut::new();
+        data.extend_from_slice(b"survivor.counter:1|c\n");
+        runner.run(Task::Parse(1, data, Instant::now(), None));
+        let id = intern(b"survivor.counter");
+
+        // extra full rotations, like forced flushes, while the metric stays in short cache
+        for _ in 0..5 {
+            let epoch = advance_epoch();
+            let (tx, _rx) = oneshot::channel();
+            runner.run(Task::Rotate(None, tx));
+            rotate_names(epoch);
+        }
+        assert_eq!(name_of(id), Bytes::from("survivor.counter"));
+        assert_eq!(runner.get_short_entry(&"survivor.counter".into()).unwrap().value, 1f64);
+    }
+
+    #[test]
+    fn slow_task_summary() {
+        let mut data = BytesMut::new();
+        data.extend_from_slice(b"slow.counter:1|c\n");
+        let task = Task::Parse(1, data, Instant::now(), None);
+        assert_eq!(task.summary(), TaskSummary { kind: "parse", name: None, size: 17 });
+
+        let metric = Metric::new(1f64, MetricType::Counter, None, None).unwrap();
+        let task = Task::AddMetrics(vec![("slow.counter".into(), metric.clone()), ("slow.other".into(), metric.clone())]);
+        assert_eq!(task.summary(), TaskSummary { kind: "add-metrics", name: None, size: 2 });
+
+        let (tx, _rx) = oneshot::channel();
+        let task = Task::Query(MetricQuery::Glob("slow.*".into()), tx);
+        assert_eq!(task.summary(), TaskSummary { kind: "query", name: Some("slow.*".into()), size: 0 });
+        assert_eq!(task.kind(), TaskKind::Query);
+
+        // tasks are run the same way when they are timed
+        let mut config = System::default();
+        config.metrics.slow_task = 1;
+        let mut runner = TaskRunner::new(prepare_log("slow_task"), Arc::new(config), 16);
+        let before = TASK_COUNTS[TaskKind::AddMetric as usize].get();
+        runner.run(Task::AddMetric("slow.counter".into(), metric));
+        assert!(runner.get_short_entry(&"slow.counter".into()).is_some());
+        // other tests may run tasks at the same time
+        assert!(TASK_COUNTS[TaskKind::AddMetric as usize].get() > before);
+    }
+
+    #[test]
+    fn shrink_spare_shards() {
+        let mut runner = TaskRunner::new(prepare_log("shrink_spare"), Arc::new(System::default()), 16);
+        // shards after a spike of names, the last generation had only a few of them
+        let shards = (0..CACHE_SHARDS).map(|_| Cache::with_capacity(10000)).collect::<Vec<_>>();
+        runner.run(Task::Recycle(shards, Vec::new()));
+        assert!(runner.spare.as_ref().unwrap().iter().all(|shard| shard.capacity() < 100));
+
+        let mut config = System::default();
+        config.memory.shrink_ratio = 0f32;
+        let mut runner = TaskRunner::new(prepare_log("shrink_spare"), Arc::new(config), 16);
+        let shards = (0..CACHE_SHARDS).map(|_| Cache::with_capacity(10000)).collect::<Vec<_>>();
+        runner.run(Task::Recycle(shards, Vec::new()));
+        assert!(runner.spare.as_ref().unwrap().iter().all(|shard| shard.capacity() >= 10000));
+    }
 }
@@ -0,0 +1,320 @@
+use toml::{self, Value};
+
+use crate::config::System;
+
+// Option key with dotted path, its description and an example value for options not set by default.
+// Options are printed in the order of this list.
+struct OptionDoc {
+    key: &'static str,
+    description: &'static str,
+    example: Option<&'static str>,
+}
+
+const fn opt(key: &'static str, description: &'static str, example: Option<&'static str>) -> OptionDoc {
+    OptionDoc { key, description, example }
+}
+
+const OPTIONS: &[OptionDoc] = &[
+    opt("config-version", "Version of configuration layout, options changed since this version are migrated with warnings", None),
+    opt("verbosity", "Logging level: \"error\", \"warn\", \"info\", \"debug\" or \"trace\"", None),
+    opt("n-threads", "Number of network worker threads in any mode, use 0(not recommended) to use all CPU cores\nor \"auto\" to use network-threads-ratio of available CPUs", None),
+    opt("w-threads", "Number of aggregating and counting threads, use 0(not recommended) to use all CPU cores\nor \"auto\" to use the rest of available CPUs after network threads", None),
+    opt("network-threads-ratio", "Share of available CPUs for network threads when thread numbers are \"auto\", CPU limits\nof containers are taken into account", None),
+    opt("network-cpus", "CPUs to pin network threads to, a CPU list like \"0-3,8\" or all CPUs of NUMA node like \"node0\",\nthreads are spread over the CPUs round-robin and allocate their buffers on the local node", Some("\"0-3\"")),
+    opt("counting-cpus", "CPUs to pin counting threads to, in the same format as network-cpus", Some("\"node0\"")),
+    opt("task-queue-size", "Queue size for single counting thread before task is dropped", None),
+    opt("task-queue-autotune", "Change queue sizes of counting threads between task-queue-size and task-queue-max-size\ndepending on their backlog, queues that were almost full grow and mostly empty ones shrink", None),
+    opt("task-queue-max-size", "Maximum queue size for single counting thread when queue sizes are tuned", None),
+    opt("start-as-leader", "If server should become leader from it's very start", None),
+    opt("stats-interval", "How often to gather own stats, in ms. Use 0 to disable (stats are still gathered and printed to log,\nbut not included in metric dump)", None),
+    opt("stats-prefix", "Prefix for sending own stats", None),
+    opt("stats-tags", "Graphite tags added to own stats names as ;name=value, i.e. { dc = \"dc1\" }", None),
+    opt("stats-node-tag", "Tag to add to own stats with the name of this node(raft.this-node or hostname)", Some("\"node\"")),
+    opt("consensus", "What consensus to use: \"consul\", \"internal\", \"standby\" for a hot-standby pair or \"none\"", None),
+    opt("include", "Directory with configuration fragments(*.toml, *.yaml, *.yml or *.json), relative to the directory of this file,\nmerged into this configuration in the order of file names", Some("\"conf.d\"")),
+    opt("windows", "Scheduled windows changing reloadable options, i.e. [{ name = \"deploy\", schedule = \"0 10 * * 1-5\", duration = \"2h\",\nset = { \"accounting.quotas\" = \"[]\" } }], schedule is cron-like \"minute hour day-of-month month day-of-week\" in UTC", None),
+    opt("metrics", "Metric processing settings", None),
+    opt("metrics.count-updates", "Should we provide metrics that update more than update-counter-threshold times during aggregation interval", None),
+    opt("metrics.update-counter-prefix", "Prefix for metric update statistics (no trailing dot!)", None),
+    opt("metrics.update-counter-suffix", "Suffix for metric update statistics (no leading dot!)", None),
+    opt("metrics.update-counter-threshold", "Minimal update counter to be reported", None),
+    opt("metrics.aggregation-mode", "Aggregation mode: \"single\", \"common\" or \"separate\", see doc/aggregation.md", None),
+    opt("metrics.aggregation-threads", "Number of threads to use for aggregation in \"separate\" mode", Some("4")),
+    opt("metrics.consistent-parsing", "Process buffers from different hosts separately, this gives more guarantee to parse\nmetrics from different hosts correctly", None),
+    opt("metrics.log-parse-errors", "Log all buffers being dropped due to parsing errors. Can be very spammy.", None),
+    opt("metrics.parse-error-samples", "Number of recent lines with parsing errors shown in stats, every 100th error of each kind is sampled, 0 to disable", None),
+    opt("metrics.parse-error-summary", "Interval of logging the number of parsing errors of every kind, ms, 0 to disable", None),
+    opt("metrics.max-unparsed-buffer", "Size of buffer that parser considers invalid. Used to avoid DoS attacks on parser.", None),
+    opt("metrics.rules-file", "File with ingestion rules: name rewrites, blocked names and tags and unique name limit", Some("\"/etc/bioyino/rules.toml\"")),
+    opt("metrics.sample-arena-size", "Number of timer samples every worker keeps in buffers reused by the next intervals instead of allocating new ones, 0 to disable.\nTimers over this number get their samples allocated as usual", None),
+    opt("metrics.slow-task", "Worker tasks running longer than this are logged with the metric name or number of metrics in them and counted in slow-task stat, ms, 0 to disable", None),
+    opt("metrics.lowercase-tag-names", "Lowercase tag names, so tags differing only in case are one tag", None),
+    opt("metrics.tag-chars", "Characters allowed in tag names and values, others are replaced with \"_\":\n\"graphite\" - anything Graphite allows in tagged series, \"strict\" - ASCII letters, digits, \"_\", \"-\" and \".\" only", None),
+    opt("metrics.max-tags", "Maximum number of tags of a metric, the first ones by name are kept, 0 for no limit", None),
+    opt("metrics.max-tag-length", "Maximum length of a tag name or value, 0 for no limit", None),
+    opt("metrics.max-tag-bytes", "Maximum length of all tags of a metric as sent to backend, 0 for no limit", None),
+    opt("metrics.tag-overflow", "What to do with tags over limits: \"drop\" them and count in dropped-tags stat, or \"bucket\" to replace all tags\nof the metric with overflow=tags and count it in tag-overflow stat", None),
+    opt("metrics.expected-metrics", "Number of unique metric names expected, worker caches and name table are allocated for them at start\nto avoid rehashing them while metrics come after restart, 0 means caches grow as needed", None),
+    opt("metrics.warmup-dump", "Metrics dump made by POST /dump to load metric names from at start, JSON unless file name ends with .capnp.\nNames that do not come again are removed after a few intervals", Some("\"/var/lib/bioyino/dump.json\"")),
+    opt("metrics.untyped-as", "Type of metrics sent without type(`name:value`): \"gauge\" or \"counter\", they are parse errors by default", Some("\"gauge\"")),
+    opt("carbon", "Carbon backend settings", None),
+    opt("carbon.address", "IP and port of the carbon-protocol backend to send aggregated data to", None),
+    opt("carbon.bind-address", "Address to bind carbon client to when connecting, no bind happens by default", Some("\"127.0.0.1:2003\"")),
+    opt("carbon.tls", "Connect to the backend over TLS with [tls] settings, the certificate is verified for the host of carbon.address\nor tls.server-name", None),
+    opt("carbon.interval", "How often to send metrics to carbon backend, ms", None),
+    opt("carbon.connect-delay", "How much to sleep when connection to backend fails, ms", None),
+    opt("carbon.connect-delay-multiplier", "Multiply delay to this value for each consequent connection failure, float", None),
+    opt("carbon.connect-delay-max", "Maximum retry delay, ms", None),
+    opt("carbon.send-retries", "How much times to retry when sending data to backend before giving up and dropping all metrics,\nnote, that 0 means 1 try", None),
+    opt("carbon.chunks", "Number of chunks to split metrics into, each chunk is sent in a separate connection", None),
+    opt("carbon.max-paused-intervals", "How many aggregated intervals to keep in memory while flushing is paused by management command", None),
+    opt("carbon.flush-offset", "Flush at this offset from the start of every interval counted from UNIX epoch, so nodes sending\nto the same backend can be staggered, \"hash\" derives the offset from node name(raft.this-node or hostname),\nflushes are not aligned by default", Some("\"5s\"")),
+    opt("carbon.spool-file", "Append metrics given up on after all send retries to this file and replay them to backend on the next start,\nso backlogs of backend outages drain themselves", Some("\"/var/lib/bioyino/carbon.spool\"")),
+    opt("carbon.spool-max-size", "Maximum size of the spool file, points over it are dropped and only the newest part is replayed", None),
+    opt("carbon.spool-max-age", "Spooled points older than this are not replayed, ms", None),
+    opt("carbon.flush-mark-file", "File to keep the timestamp of the last interval flushed completely in. Intervals missed while the server\nwas not running are counted in flush-gap stat on start", Some("\"/var/lib/bioyino/flush.mark\"")),
+    opt("carbon.backfill", "Names of critical metrics, as sent to backend, to send points for in intervals missed during restart,\nso alerts on missing data do not fire during deploys. Needs flush-mark-file", None),
+    opt("carbon.backfill-value", "Value of backfilled points: \"zero\" or \"last\" sent before restart", None),
+    opt("carbon.backfill-max-gap", "Intervals missed for longer than this are only counted, not backfilled, ms", None),
+    opt("memory", "Memory budget settings", None),
+    opt("memory.budget", "Memory allowed for caches, timer samples, peer snapshots and backend queues, 0 to disable the budget.\nWhen usage gets close to the budget, timer samples are capped, then incoming metrics are dropped and\nfinally metrics are flushed to backend early", None),
+    opt("memory.limit-ratio", "Share of cgroup memory limit to take as the budget when budget is 0, so the budget is set inside containers\nwithout configuring it, 0 to only use the budget set", None),
+    opt("memory.check-interval", "How often to check memory usage against the budget, ms", None),
+    opt("memory.cap-samples-ratio", "Share of budget after which timers stop accepting samples over timer-sample-cap", None),
+    opt("memory.timer-sample-cap", "Maximum number of samples in a timer when samples are capped", None),
+    opt("memory.shed-ratio", "Share of budget after which incoming metrics are dropped", None),
+    opt("memory.flush-ratio", "Share of budget after which metrics are flushed to backend without waiting for the interval end", None),
+    opt("memory.shrink-ratio", "Worker cache shards filled less than this share of their capacity after rotation are allocated again\nfor the number of metrics they had, so memory taken at a spike of new names is freed, 0 to never shrink them", None),
+    opt("memory.trim-interval", "How often to give free memory back to the system, ms, 0 to disable. Only does something with the system allocator", None),
+    opt("log", "Log settings, log is written to terminal if no file is set", None),
+    opt("log.target", "Where to write log: \"terminal\", \"file\", \"syslog\" or \"journald\", file if log.file is set and terminal otherwise by default", Some("\"syslog\"")),
+    opt("log.file", "File to write log to, it is opened again on SIGUSR2, so it can be rotated by logrotate too", Some("\"/var/log/bioyino/bioyino.log\"")),
+    opt("log.max-size", "Size of log file it is rotated at, 0 to not rotate by size", None),
+    opt("log.rotate-interval", "How often to rotate log file, ms, 0 to not rotate by time", None),
+    opt("log.max-files", "Number of rotated files to keep as <file>.1, <file>.2 etc, the newest first", None),
+    opt("log.compress", "Compress rotated files with gzip", None),
+    opt("log.syslog-address", "Unix socket path or host:port of UDP syslog server, messages are sent in RFC5424 format", None),
+    opt("log.syslog-facility", "Syslog facility: kern, user, mail, daemon, auth, syslog, lpr, news, uucp, cron, authpriv, ftp or local0-local7", None),
+    opt("log.rate-limit", "Maximum number of records logged by the same line of code per rate-limit-interval, the number of suppressed ones is logged after the interval, 0 to log everything", None),
+    opt("log.rate-limit-interval", "Interval log records are limited in, ms", None),
+    opt("tracing", "Tracing of pipeline stages with OpenTelemetry", None),
+    opt("tracing.endpoint", "OTLP/HTTP endpoint of a collector to send spans to in JSON, tracing is disabled if not set", Some("\"http://localhost:4318/v1/traces\"")),
+    opt("tracing.sample-ratio", "Share of received buffers, peer snapshots and flushes to trace, from 0 to 1", None),
+    opt("tracing.export-interval", "How often to send finished spans, ms", None),
+    opt("tracing.max-queued-spans", "Maximum number of finished spans waiting to be sent, new spans are dropped over it", None),
+    opt("tracing.service-name", "Service name spans are sent with", None),
+    opt("incidents", "Reporting of panics and repeated failures to Sentry or a webhook", None),
+    opt("incidents.sentry-dsn", "Sentry DSN to report incidents to", Some("\"https://<key>@sentry.example.com/1\"")),
+    opt("incidents.sentry-dsn-file", "File to read sentry-dsn from, i.e. mounted by orchestrator", Some("\"/run/secrets/bioyino-sentry-dsn\"")),
+    opt("incidents.webhook", "URL to POST incidents to in JSON", Some("\"http://alerts.example.com/bioyino\"")),
+    opt("incidents.webhook-file", "File to read webhook URL from, the URL may have credentials in it", Some("\"/run/secrets/bioyino-webhook\"")),
+    opt("incidents.backend-failures", "Report backend as unreachable after this number of failed sends in a row, 0 to disable", None),
+    opt("incidents.consensus-lost", "Report consensus as lost after being unreachable for this long, ms, 0 to disable", None),
+    opt("incidents.check-interval", "How often to check for repeated failures, ms", None),
+    opt("supervision", "Restarting of counting workers, UDP receivers, peer server, snapshot sender and carbon timer after panics", None),
+    opt("supervision.restart-delay", "Delay before restarting a panicked subsystem, doubled with every crash in restart window, ms", None),
+    opt("supervision.restart-delay-max", "Maximum delay before restarting, ms", None),
+    opt("supervision.max-restarts", "Stop the server when a subsystem panics more times than this in restart window", None),
+    opt("supervision.restart-window", "Window crashes are counted in, ms", None),
+    opt("health", "Health score from 0 to 100 counted from worker queues(30), dropped packets(30), backend(20) and consensus(20) states", None),
+    opt("health.degraded-score", "Node is reported as degraded by /readyz when health score is below this, 0 to never report it", None),
+    opt("health.queue-ratio", "Share of worker queue capacity at which queues score nothing", None),
+    opt("health.drop-ratio", "Share of dropped packets at which drops score nothing", None),
+    opt("health.check-interval", "How often to count the score, ms", None),
+    opt("accounting", "Counting of samples received and points sent to backend per name prefix, sent with own stats as\naccounting.<prefix>.samples and accounting.<prefix>.points and logged as a summary", None),
+    opt("accounting.enabled", "Count metric volume for every name prefix", None),
+    opt("accounting.depth", "Number of name segments making a prefix", None),
+    opt("accounting.max-prefixes", "Maximum number of prefixes counted separately, the rest are counted as \"_other\"", None),
+    opt("accounting.summary-interval", "How often to log totals of every prefix, ms", None),
+    opt("accounting.quotas", "Limits of distinct names per interval(max-names) and samples per second(max-rate) of name prefixes,\nsamples over quota are dropped, sampled keeping sample-ratio of them or aggregated into <prefix>._overflow.<type>,\ni.e. [{ prefix = \"team\", max-names = 10000, over-quota = \"overflow\" }]", None),
+    opt("privileges", "Switching to another user after starting as root, after listeners are bound and before any of them is started", None),
+    opt("privileges.user", "User, by name or id, to switch to before receiving any data when started as root", Some("\"bioyino\"")),
+    opt("privileges.group", "Group to switch to, the primary group of the user if not set", Some("\"bioyino\"")),
+    opt("privileges.keep-capabilities", "Capabilities to keep after switching: net-bind-service, net-admin, net-raw, ipc-lock, sys-nice or sys-resource.\nListeners are bound before it, so ports below 1024 need no capability", None),
+    opt("sandbox", "Restrictions of threads parsing untrusted input from network", None),
+    opt("sandbox.enabled", "Restrict syscalls(seccomp) and filesystem access(Landlock) of network and worker threads after they start.\nThe threads cannot start programs, processes or sockets, other syscalls fail with EPERM", None),
+    opt("sandbox.read-paths", "Paths sandboxed threads may read under, nothing is readable by default", None),
+    opt("sandbox.write-paths", "Paths sandboxed threads may read and write under", None),
+    opt("shutdown", "Stopping on SIGTERM and SIGINT", None),
+    opt("shutdown.graceful", "Stop receiving, aggregate metrics in caches and send them to backend if this node is the leader before exiting.\nThe second signal exits immediately", None),
+    opt("shutdown.timeout", "Exit after this long even if the last flush is not finished, ms", None),
+    opt("shutdown.state-file", "Write caches to this file(capnp snapshot) instead of flushing them on shutdown and merge them into the first\ninterval after start, so restarts leave no gaps. State older than carbon.interval is ignored", Some("\"/var/lib/bioyino/state.capnp\"")),
+    opt("shutdown.handoff-socket", "Unix socket a new process takes listening sockets of this one over. A process started with the same option while\nthis one runs, or by sending SIGWINCH to it, receives on the same sockets, and this one stops when it is started", Some("\"/run/bioyino/handoff.sock\"")),
+    opt("shutdown.handoff-caches", "Pass caches to the new process on handoff, so the interval is continued there, they are flushed otherwise", None),
+    opt("startup", "Waiting for dependencies on start", None),
+    opt("startup.wait", "Wait for names in network.nodes, carbon.address, raft.nodes and standby.primary to resolve, then for consul to\nhave a leader, then for listening addresses to be free, in this order, instead of failing at the first one not up", None),
+    opt("startup.timeout", "Stop waiting after this long since start, ms", None),
+    opt("startup.retry-delay", "Delay before checking a dependency again, doubled after each check, ms", None),
+    opt("startup.retry-delay-max", "Maximum delay between checks, ms", None),
+    opt("startup.on-timeout", "What to do when waiting times out: \"fail\" exits with the dependency not up, \"start\" logs it and\nstarts without it", None),
+    opt("daemon", "Running as a classic daemon from init scripts", None),
+    opt("daemon.pid-file", "File to write process id to, removed on exit. Start fails if it names a running process", Some("\"/var/run/bioyino.pid\"")),
+    opt("daemon.detach", "Fork to background and detach from the terminal, the command exits when the server is started", None),
+    opt("daemon.umask", "Permission mask for created files, octal", Some("\"027\"")),
+    opt("daemon.work-dir", "Directory to change to, \"/\" when detached if not set. Relative paths in other options are resolved from it", Some("\"/var/lib/bioyino\"")),
+    opt("shards", "Running several independent instances sharing statsd port on one host", None),
+    opt("shards.count", "Number of instances to run, each in it's own process with it's own statsd sockets, workers and caches.\nThe first instance starts the others, which never lead and send snapshots to it and to network.nodes", None),
+    opt("shards.index", "Index of this instance, set for started instances by the first one", None),
+    opt("shards.port-step", "Peer and management ports of every next instance are this much higher", None),
+    opt("network", "Network settings", None),
+    opt("network.listen", "Address and UDP port to listen for statsd metrics at", None),
+    opt("network.peer-listen", "Address and port for replication server to listen on", None),
+    opt("network.peer-client-bind", "Address for peer client to bind to, no bind happens by default", Some("\"127.0.0.1:8136\"")),
+    opt("network.peer-tls", "Use TLS with [tls] settings for peer server and snapshot client, tls.cert and tls.key must be set,\npeer certificates are verified for the hosts in network.nodes or tls.server-name", None),
+    opt("network.mgmt-listen", "Address and port for management server to listen on", None),
+    opt("network.bufsize", "UDP buffer size for single packet. Needs to be around MTU", None),
+    opt("network.multimessage", "Enable multimessage(recvmmsg) mode", None),
+    opt("network.mm-packets", "Number of multimessage packets to receive at once if in multimessage mode", None),
+    opt("network.mm-async", "Do multimessage operations in async mode", None),
+    opt("network.mm-timeout", "A timeout to return from multimessage mode syscall, 0 means buffer-flush-time", None),
+    opt("network.buffer-flush-time", "Flush incoming data buffer by timer, ms, 0 disables flushing by time", None),
+    opt("network.buffer-flush-length", "Flush incoming data buffer when it reaches this length, 0 means automatic management", None),
+    opt("network.greens", "Number of green threads for single-message mode", None),
+    opt("network.async-sockets", "Socket pool size for single-message mode", None),
+    opt("network.nodes", "List of nodes to replicate metrics to", None),
+    opt("network.snapshot-interval", "Interval to send snapshots to nodes, ms", None),
+    opt("network.untyped-as", "Type of untyped metrics received by statsd listener, overrides metrics.untyped-as", Some("\"counter\"")),
+    opt("network.batch-size", "Number of metrics received from agents by peer listener to collect before sending them to a worker", None),
+    opt("network.batch-flush-time", "A timer to send incomplete batches of metrics from agents to workers, ms, 0 to only send full batches", None),
+    opt("network.recv-buffer", "Receive buffer of statsd UDP sockets(SO_RCVBUF), 0 leaves the system default.\nThe kernel limits it by net.core.rmem_max", None),
+    opt("network.busy-poll", "Microseconds to busy poll statsd UDP sockets for new packets(SO_BUSY_POLL), 0 to disable.\nLowers latency at the cost of CPU, values over net.core.busy_read need CAP_NET_ADMIN", None),
+    opt("network.socket-autotune", "Grow receive buffer and multimessage batch of statsd UDP listener when the kernel drops packets,\nchosen values are shown in /stats", None),
+    opt("network.max-recv-buffer", "Maximum receive buffer socket autotune can set", None),
+    opt("network.max-mm-packets", "Maximum multimessage batch socket autotune can set. Every network thread takes\nmax-mm-packets * max-mm-packets * bufsize bytes of memory for receiving at this batch", None),
+    opt("network.slow-connection", "Peer or backend TCP connection making no progress in reading or writing for this long is logged as slow, ms, 0 to disable", None),
+    opt("network.slow-connection-kill", "Close slow TCP connections after making no progress for this long, ms, 0 to never close them", None),
+    opt("network.statsd-allow", "Networks(CIDR) statsd packets are accepted from, like \"10.0.0.0/8\" or \"::1\", all if empty.\nPackets from other sources are dropped before parsing and counted in source-drop stat", None),
+    opt("network.statsd-deny", "Networks(CIDR) statsd packets are dropped from, deny rules win over allowing ones", None),
+    opt("network.peer-allow", "Networks(CIDR) peer connections are accepted from, all if empty", None),
+    opt("network.peer-deny", "Networks(CIDR) peer connections are closed from, deny rules win over allowing ones", None),
+    opt("network.statsd-hmac-key", "Shared key statsd packets must be signed with. Every packet must end with a line\n#hmac:<unix seconds>:<hex HMAC-SHA256 of payload followed by the seconds>, packets without it are dropped", Some("\"secret\"")),
+    opt("network.statsd-hmac-key-file", "File to read statsd-hmac-key from", Some("\"/etc/bioyino/statsd.key\"")),
+    opt("network.statsd-hmac-max-age", "Signed packets with timestamp differing from the current time more than this are dropped, ms", None),
+    opt("network.statsd-rate-limit", "Metrics per second accepted from statsd packets by all workers together, the rest are dropped, 0 for no limit", None),
+    opt("network.peer-rate-limit", "Metrics per second accepted from agents over peer protocol, snapshots of other nodes are not limited, 0 for no limit", None),
+    opt("network.rate-limit-burst", "Unused rate is saved up for at most this long, allowing bursts over the limit, ms", None),
+    opt("network.maintenance-forward", "Statsd address of a sibling node to forward statsd packets to while this one is in maintenance", Some("\"10.0.0.2:8125\"")),
+    opt("management", "Management API security settings", None),
+    opt("management.tokens", "Bearer tokens allowed to access the API with \"read-only\" or \"admin\" role,\nwhen empty, no authentication is done", None),
+    opt("management.client-token", "Token sent by query subcommand", Some("\"secret-for-operators\"")),
+    opt("management.client-token-file", "File to read client-token from, i.e. mounted by orchestrator", Some("\"/run/secrets/bioyino-client-token\"")),
+    opt("management.dump-dir", "Directory where POST /dump?file=<name> writes cache dumps", Some("\"/var/tmp/bioyino\"")),
+    opt("management.audit-log", "File to append records about state-changing management calls to", Some("\"/var/log/bioyino/audit.log\"")),
+    opt("management.state-report-file", "File to append human-readable state reports made on SIGUSR1 to, they are logged when this is not set", Some("\"/var/log/bioyino/state.log\"")),
+    opt("management.event-log-size", "Number of last leader changes, backend failures and recoveries, reloads and memory pressure changes kept for /events, 0 to disable", None),
+    opt("management.profiling", "Allow taking CPU profiles with GET /debug/pprof/profile and /debug/pprof/flamegraph,\nserver must be built with pprof feature", None),
+    opt("management.profile-max-duration", "Maximum duration of a profile", None),
+    opt("management.profile-frequency", "CPU samples per second taken while profiling", None),
+    opt("tls", "Certificates and TLS settings shared by TLS servers and clients, now used by management API.\nFiles are read again on reload, so certificates can be rotated without restart", None),
+    opt("tls.cert", "PEM file with certificate chain, TLS is enabled when both certificate and key are set", Some("\"/etc/bioyino/bioyino.crt\"")),
+    opt("tls.key", "PEM file with private key", Some("\"/etc/bioyino/bioyino.key\"")),
+    opt("tls.client-ca", "PEM file with CA certificates to verify clients with, enables mutual TLS", Some("\"/etc/bioyino/clients-ca.crt\"")),
+    opt("tls.ca", "PEM file with CA certificates to verify peer and carbon servers with, well-known CAs are used if not set", Some("\"/etc/bioyino/servers-ca.crt\"")),
+    opt("tls.server-name", "Name peer and carbon certificates are verified for instead of the host part of their addresses,\nneeded when addresses are IPs", Some("\"bioyino.example.com\"")),
+    opt("tls.min-version", "Lowest TLS version accepted: \"1.2\" or \"1.3\"", None),
+    opt("tls.cipher-policy", "Cipher suites offered: \"default\" for all supported ones or \"strict\" for ones with 256 bit keys only", None),
+    opt("raft", "Settings for internal Raft", None),
+    opt("raft.start-delay", "Defer start of raft consensus to avoid node becoming leader too early, ms", None),
+    opt("raft.heartbeat-timeout", "Raft heartbeat timeout, ms", None),
+    opt("raft.election-timeout-min", "Minimal Raft election timeout, ms", None),
+    opt("raft.election-timeout-max", "Maximal Raft election timeout, ms", None),
+    opt("raft.this-node", "Name of this node, taken from hostname by default", Some("\"node1:8138\"")),
+    opt("raft.nodes", "A map of raft nodes, keys are hostname:port or IP:port, values are integer ids", None),
+    opt("raft.client-bind", "Bind raft outgoing connections to specific IP, no bind happens by default", Some("\"127.0.0.1:8138\"")),
+    opt("consul", "Consul settings", None),
+    opt("consul.start-as", "Start in disabled leader finding mode. This only works while consul is bootstrapping.", None),
+    opt("consul.agent", "Consul agent address", None),
+    opt("consul.session-ttl", "TTL of consul session, ms (Consul cannot set it to less than 10s)", None),
+    opt("consul.renew-time", "How often to renew Consul session, ms", None),
+    opt("consul.key-name", "Key name to lock in Consul", None),
+    opt("standby", "Hot-standby pair settings, used with consensus = \"standby\"", None),
+    opt("standby.role", "Role of this node: \"primary\" always leads, \"standby\" leads while the primary is not heard of", None),
+    opt("standby.primary", "Peer address of the primary, set on the standby. Snapshots from it's IP show the primary is alive,\nso the primary must have the standby in network.nodes", Some("\"10.0.0.1:8136\"")),
+    opt("standby.failover-timeout", "Standby takes over when there were no snapshots from the primary for this long, ms", None),
+];
+
+fn comment(out: &mut String, description: &str) {
+    for line in description.lines() {
+        out.push_str("# ");
+        out.push_str(line);
+        out.push('\n');
+    }
+}
+
+// direct children of the section in the order of option list
+fn children(section: &str) -> impl Iterator<Item = (&'static str, &'static OptionDoc)> + '_ {
+    OPTIONS.iter().filter_map(move |option| {
+        let name = if section.len() == 0 { option.key } else if option.key.starts_with(section) && option.key[section.len()..].starts_with('.') { &option.key[section.len() + 1..] } else { return None };
+        if name.contains('.') {
+            None
+        } else {
+            Some((name, option))
+        }
+    })
+}
+
+fn write_section(out: &mut String, section: &str, table: &toml::value::Table) {
+    // TOML requires plain values to go before subsections
+    let mut subsections = Vec::new();
+    for (name, option) in children(section) {
+        match table.get(name) {
+            Some(Value::Table(inner)) if children(option.key).next().is_some() => subsections.push((option, inner)),
+            // maps, like raft nodes, are empty by default
+            Some(Value::Table(_)) => {
+                comment(out, option.description);
+                out.push_str(&format!("{} = {{}}\n\n", name));
+            }
+            Some(value) => {
+                comment(out, option.description);
+                out.push_str(&format!("{} = {}\n\n", name, value));
+            }
+            None => {
+                comment(out, option.description);
+                out.push_str(&format!("#{} = {}\n\n", name, option.example.unwrap_or("")));
+            }
+        }
+    }
+    for (option, inner) in subsections {
+        comment(out, option.description);
+        out.push_str(&format!("[{}]\n", option.key));
+        write_section(out, option.key, inner);
+    }
+}
+
+/// Default configuration with all the options and their descriptions, options not set by default are commented out
+pub fn default_config() -> String {
+    let defaults = match Value::try_from(System::default()) {
+        Ok(Value::Table(table)) => table,
+        _ => unreachable!("default config is always serialized to table"),
+    };
+    let mut out = format!("# Default configuration of bioyino {}\n", env!("CARGO_PKG_VERSION"));
+    out.push_str("# Intervals(ms) and sizes(bytes) can also be written with units, like \"30s\" or \"64KiB\"\n\n");
+    write_section(&mut out, "", &defaults);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // every option must have a description, otherwise it will be silently missing from the template
+    fn described(prefix: &str, table: &toml::value::Table) {
+        for (name, value) in table {
+            let key = if prefix.len() > 0 { format!("{}.{}", prefix, name) } else { name.clone() };
+            assert!(OPTIONS.iter().any(|option| option.key == key), "option {} has no description", key);
+            if let Value::Table(inner) = value {
+                described(&key, inner);
+            }
+        }
+    }
+
+    #[test]
+    fn template_matches_defaults() {
+        let defaults = Value::try_from(System::default()).unwrap();
+        described("", defaults.as_table().unwrap());
+        assert!(OPTIONS.iter().all(|option| option.example.is_some() || option.key.split('.').fold(Some(&defaults), |value, part| value.and_then(|value| value.get(part))).is_some()));
+
+        let parsed: System = toml::de::from_str(&default_config()).unwrap();
+        assert_eq!(Value::try_from(parsed).unwrap(), defaults);
+    }
+}
@@ -0,0 +1,265 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use futures::future::{ok, Either};
+use futures::{Future, Stream};
+use hyper::header::{HeaderValue, CONTENT_TYPE};
+use hyper::{Body, Method, Request, Uri};
+use lazy_static::lazy_static;
+use rand::random;
+use serde_json::{json, Value};
+use slog::{o, warn, Logger};
+use tokio::timer::Interval;
+
+use crate::config::Tracing;
+use crate::util::get_hostname;
+
+// spans are sampled when a random u32 is below this, 0 disables tracing
+static SAMPLE_THRESHOLD: AtomicU64 = AtomicU64::new(0);
+static MAX_QUEUED: AtomicUsize = AtomicUsize::new(0);
+// spans not queued since the last export because the queue was full
+static DROPPED_SPANS: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static! {
+    static ref FINISHED: Mutex<Vec<FinishedSpan>> = Mutex::new(Vec::new());
+}
+
+/// Start sampling with the configured ratio, spans are only kept after this is called
+pub fn init_tracing(options: &Tracing) {
+    let ratio = options.sample_ratio.max(0f64).min(1f64);
+    MAX_QUEUED.store(options.max_queued_spans, Ordering::Relaxed);
+    SAMPLE_THRESHOLD.store(sample_threshold(ratio), Ordering::Relaxed);
+}
+
+// threshold is counted in u64, so ratio of 1 gives 2^32, which all u32 values are below, on any platform
+fn sample_threshold(ratio: f64) -> u64 {
+    (ratio * (1u64 << 32) as f64) as u64
+}
+
+fn sampled() -> bool {
+    let threshold = SAMPLE_THRESHOLD.load(Ordering::Relaxed);
+    threshold > 0 && u64::from(random::<u32>()) < threshold
+}
+
+fn span_id() -> u64 {
+    random::<u64>().max(1)
+}
+
+/// Ids a span can be continued with in another thread
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpanContext {
+    pub trace_id: u128,
+    pub span_id: u64,
+}
+
+#[derive(Debug)]
+struct FinishedSpan {
+    trace_id: u128,
+    span_id: u64,
+    parent: Option<u64>,
+    name: &'static str,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, String)>,
+}
+
+/// A stage of the pipeline being timed, it ends and is queued for export when dropped
+#[derive(Debug)]
+pub struct Span {
+    context: SpanContext,
+    parent: Option<u64>,
+    name: &'static str,
+    start: SystemTime,
+    attributes: Vec<(&'static str, String)>,
+}
+
+impl Span {
+    /// A new trace if this one is sampled
+    pub fn root(name: &'static str) -> Option<Span> {
+        Self::root_at(name, SystemTime::now())
+    }
+
+    /// A new trace started some time ago, i.e. when the first packet of a buffer was received
+    pub fn root_since(name: &'static str, started: Instant) -> Option<Span> {
+        Self::root_at(name, SystemTime::now() - started.elapsed())
+    }
+
+    fn root_at(name: &'static str, start: SystemTime) -> Option<Span> {
+        if !sampled() {
+            return None;
+        }
+        let trace_id = (u128::from(random::<u64>()) << 64 | u128::from(random::<u64>())).max(1);
+        Some(Span { context: SpanContext { trace_id, span_id: span_id() }, parent: None, name, start, attributes: Vec::new() })
+    }
+
+    /// Continue a trace, nothing is traced if the parent was not sampled
+    pub fn child_of(parent: Option<SpanContext>, name: &'static str) -> Option<Span> {
+        parent.map(|parent| Span { context: SpanContext { trace_id: parent.trace_id, span_id: span_id() }, parent: Some(parent.span_id), name, start: SystemTime::now(), attributes: Vec::new() })
+    }
+
+    pub fn child(&self, name: &'static str) -> Span {
+        Span { context: SpanContext { trace_id: self.context.trace_id, span_id: span_id() }, parent: Some(self.context.span_id), name, start: SystemTime::now(), attributes: Vec::new() }
+    }
+
+    pub fn context(&self) -> SpanContext {
+        self.context
+    }
+
+    pub fn attr<V: ToString>(&mut self, key: &'static str, value: V) {
+        self.attributes.push((key, value.to_string()));
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let span = FinishedSpan {
+            trace_id: self.context.trace_id,
+            span_id: self.context.span_id,
+            parent: self.parent,
+            name: self.name,
+            start: self.start,
+            end: SystemTime::now(),
+            attributes: self.attributes.drain(..).collect(),
+        };
+        let mut finished = FINISHED.lock().unwrap();
+        if finished.len() < MAX_QUEUED.load(Ordering::Relaxed) {
+            finished.push(span);
+        } else {
+            DROPPED_SPANS.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Trace a buffer of statsd packets from the first packet to sending it to a worker,
+/// the returned context goes with the buffer, so parsing is traced too
+pub fn receive_span(received: Instant, bytes: usize, worker: usize) -> Option<SpanContext> {
+    Span::root_since("receive", received).map(|mut span| {
+        span.attr("bytes", bytes);
+        span.attr("worker", worker);
+        span.context()
+    })
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    (u128::from(since.as_secs()) * 1_000_000_000 + u128::from(since.subsec_nanos())).to_string()
+}
+
+// export request in OTLP/HTTP JSON encoding, ids are hex strings and times are decimal strings there
+fn otlp_request(service_name: &str, hostname: &str, spans: &[FinishedSpan]) -> Value {
+    let spans = spans
+        .iter()
+        .map(|span| {
+            let attributes = span.attributes.iter().map(|(key, value)| json!({ "key": key, "value": { "stringValue": value } })).collect::<Vec<_>>();
+            let mut value = json!({
+                "traceId": format!("{:032x}", span.trace_id),
+                "spanId": format!("{:016x}", span.span_id),
+                "name": span.name,
+                // SPAN_KIND_INTERNAL
+                "kind": 1,
+                "startTimeUnixNano": unix_nanos(span.start),
+                "endTimeUnixNano": unix_nanos(span.end),
+                "attributes": attributes
+            });
+            if let Some(parent) = span.parent {
+                value["parentSpanId"] = json!(format!("{:016x}", parent));
+            }
+            value
+        })
+        .collect::<Vec<_>>();
+    json!({
+        "resourceSpans": [{
+            "resource": { "attributes": [
+                { "key": "service.name", "value": { "stringValue": service_name } },
+                { "key": "host.name", "value": { "stringValue": hostname } }
+            ]},
+            "scopeSpans": [{ "scope": { "name": "bioyino", "version": env!("CARGO_PKG_VERSION") }, "spans": spans }]
+        }]
+    })
+}
+
+/// Send finished spans to OTLP collector every export interval
+pub fn export_spans(options: Tracing, log: Logger) -> impl Future<Item = (), Error = ()> {
+    let log = log.new(o!("source"=>"tracing"));
+    let err_log = log.clone();
+    // endpoint is checked at start
+    let uri: Uri = options.endpoint.clone().unwrap_or_default().parse().expect("bad tracing endpoint");
+    let hostname = get_hostname().unwrap_or_else(|| "unknown".to_string());
+    let client = hyper::Client::new();
+    let interval = Duration::from_millis(options.export_interval.max(100));
+    Interval::new(Instant::now() + interval, interval)
+        .map_err(move |e| {
+            warn!(err_log, "span export timer failed"; "error"=>e.to_string());
+        })
+        .for_each(move |_| {
+            let dropped = DROPPED_SPANS.swap(0, Ordering::Relaxed);
+            if dropped > 0 {
+                warn!(log, "spans dropped, export queue was full"; "dropped"=>dropped);
+            }
+            let spans = FINISHED.lock().unwrap().drain(..).collect::<Vec<_>>();
+            if spans.len() == 0 {
+                return Either::A(ok(()));
+            }
+            let body = otlp_request(&options.service_name, &hostname, &spans).to_string();
+            let mut request = Request::default();
+            *request.method_mut() = Method::POST;
+            *request.uri_mut() = uri.clone();
+            request.headers_mut().insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+            *request.body_mut() = Body::from(body);
+            let log = log.clone();
+            // failed exports lose their spans, tracing must not hold memory while collector is down
+            Either::B(client.request(request).then(move |response| {
+                match response {
+                    Ok(ref response) if response.status().is_success() => (),
+                    Ok(response) => warn!(log, "span export rejected"; "status"=>response.status().as_u16(), "spans"=>spans.len()),
+                    Err(e) => warn!(log, "span export failed"; "error"=>e.to_string(), "spans"=>spans.len()),
+                }
+                Ok(())
+            }))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sampling_threshold() {
+        assert_eq!(sample_threshold(0f64), 0);
+        assert_eq!(sample_threshold(0.5f64), 1u64 << 31);
+        // every span is sampled with ratio of 1
+        assert_eq!(sample_threshold(1f64), 1u64 << 32);
+        assert!(u64::from(u32::max_value()) < sample_threshold(1f64));
+    }
+
+    #[test]
+    fn trace_spans() {
+        // not sampled until tracing is started
+        assert!(Span::root("test").is_none());
+        assert!(Span::child_of(None, "test").is_none());
+
+        init_tracing(&Tracing { endpoint: Some("http://localhost:4318/v1/traces".to_string()), sample_ratio: 1f64, ..Default::default() });
+        let context = {
+            let mut root = Span::root("flush").unwrap();
+            root.attr("metrics", 10);
+            let child = root.child("flush-chunk");
+            assert_eq!(child.context().trace_id, root.context().trace_id);
+            root.context()
+        };
+        drop(Span::child_of(Some(context), "aggregate"));
+        let spans = FINISHED.lock().unwrap().drain(..).filter(|span| span.trace_id == context.trace_id).collect::<Vec<_>>();
+        assert_eq!(spans.iter().map(|span| span.name).collect::<Vec<_>>(), vec!["flush-chunk", "flush", "aggregate"]);
+        assert_eq!(spans[0].parent, Some(context.span_id));
+        assert_eq!(spans[2].parent, Some(context.span_id));
+
+        let request = otlp_request("bioyino", "host", &spans);
+        let exported = &request["resourceSpans"][0]["scopeSpans"][0]["spans"];
+        assert_eq!(exported[1]["name"], "flush");
+        assert_eq!(exported[1]["traceId"], json!(format!("{:032x}", context.trace_id)));
+        assert_eq!(exported[1]["parentSpanId"], Value::Null);
+        assert_eq!(exported[1]["attributes"][0], json!({ "key": "metrics", "value": { "stringValue": "10" } }));
+        assert_eq!(exported[0]["parentSpanId"], json!(format!("{:016x}", context.span_id)));
+        assert_eq!(unix_nanos(UNIX_EPOCH + Duration::new(1, 5)), "1000000005");
+    }
+}
@@ -0,0 +1,92 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::config::System;
+
+/// A parameter that can be read and changed in runtime by management API without reloading configuration.
+/// Changed values are lost on restart.
+pub struct Tunable {
+    pub name: &'static str,
+    pub description: &'static str,
+    value: AtomicUsize,
+    pub min: usize,
+    pub max: usize,
+}
+
+impl Tunable {
+    const fn new(name: &'static str, description: &'static str, value: usize, min: usize, max: usize) -> Self {
+        Self { name, description, value: AtomicUsize::new(value), min, max }
+    }
+
+    pub fn get(&self) -> usize {
+        self.value.load(Ordering::Relaxed)
+    }
+
+    /// Set the new value returning the previous one, values out of bounds are not set
+    pub fn set(&self, value: usize) -> Result<usize, String> {
+        if value < self.min || value > self.max {
+            return Err(format!("{} must be between {} and {}", self.name, self.min, self.max));
+        }
+        Ok(self.value.swap(value, Ordering::Relaxed))
+    }
+}
+
+pub static MAX_UNPARSED_BUFFER: Tunable = Tunable::new("max-unparsed-buffer", "size of buffer parser considers invalid, bytes, metrics.max-unparsed-buffer at start", 10000, 1, 64 * 1024 * 1024);
+pub static MAX_TAILS: Tunable = Tunable::new("max-tails", "maximum number of simultaneous tails, each one makes ingestion a bit slower", 16, 0, 1024);
+pub static TAIL_BUFFER: Tunable = Tunable::new("tail-buffer", "events buffered for a slow tail client before samples start being skipped, for new tails", 128, 1, 65536);
+pub static WORKER_PING_TIMEOUT: Tunable = Tunable::new("worker-ping-timeout", "time for a worker to answer liveness check before it is considered stuck, ms", 1000, 10, 60000);
+pub static PEER_ALIVE_INTERVALS: Tunable = Tunable::new("peer-alive-intervals", "number of snapshot intervals a peer is considered alive after the last snapshot exchange", 3, 1, 1000);
+pub static SNAPSHOT_SCRATCH: Tunable = Tunable::new("snapshot-scratch", "space kept between intervals to build peer snapshots in, bytes, bigger snapshots allocate the rest as usual", 64 * 1024 * 1024, 0, 1024 * 1024 * 1024);
+
+pub static TUNABLES: [&Tunable; 6] = [&MAX_UNPARSED_BUFFER, &MAX_TAILS, &TAIL_BUFFER, &WORKER_PING_TIMEOUT, &PEER_ALIVE_INTERVALS, &SNAPSHOT_SCRATCH];
+
+/// Current value of a tunable with its bounds
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct TunableValue {
+    pub name: String,
+    pub value: usize,
+    pub min: usize,
+    pub max: usize,
+    pub description: String,
+}
+
+/// Body of tunable change request
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct TunableChange {
+    pub value: usize,
+}
+
+pub fn find_tunable(name: &str) -> Option<&'static Tunable> {
+    TUNABLES.iter().find(|tunable| tunable.name == name).map(|tunable| *tunable)
+}
+
+pub fn tunable_values() -> Vec<TunableValue> {
+    TUNABLES.iter().map(|tunable| TunableValue { name: tunable.name.to_string(), value: tunable.get(), min: tunable.min, max: tunable.max, description: tunable.description.to_string() }).collect()
+}
+
+/// Set tunables having configuration options to configured values
+pub fn init_tunables(system: &System) -> Result<(), String> {
+    MAX_UNPARSED_BUFFER.set(system.metrics.max_unparsed_buffer).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tunable_bounds() {
+        let tunable = Tunable::new("test", "test tunable", 5, 1, 10);
+        assert_eq!(tunable.set(7), Ok(5));
+        assert_eq!(tunable.get(), 7);
+        assert!(tunable.set(0).is_err());
+        assert!(tunable.set(11).is_err());
+        assert_eq!(tunable.get(), 7);
+
+        assert_eq!(find_tunable("max-tails").map(|tunable| tunable.name), Some("max-tails"));
+        assert!(find_tunable("unknown").is_none());
+        assert_eq!(tunable_values().len(), TUNABLES.len());
+    }
+}
@@ -1,37 +1,86 @@
 use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
+use std::fs;
 use std::hash::Hasher;
 use std::io;
-use std::net::SocketAddr;
+use std::mem;
+use std::net::{SocketAddr, UdpSocket as StdUdpSocket};
 use std::ptr::null_mut;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
-use std::thread;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 
 use bytes::{BufMut, BytesMut};
-use futures::future::empty;
 use futures::sync::mpsc::Sender;
-use futures::IntoFuture;
+use futures03::future::pending;
+use futures::{Future, Stream};
+use lazy_static::lazy_static;
 use net2::unix::UnixUdpBuilderExt;
 use net2::UdpBuilder;
+use serde_derive::{Deserialize, Serialize};
 use slog::{Logger, info, warn, debug, o};
-use std::os::unix::io::AsRawFd;
-use tokio::net::UdpSocket;
-use tokio::runtime::current_thread::Runtime;
-use tokio::reactor::Handle;
-
+use std::os::unix::io::{AsRawFd, RawFd};
+use tokio1::net::UdpSocket;
+use tokio1::runtime::Builder;
+use tokio1::task::LocalSet;
+use tokio::timer::Interval;
+
+use crate::acl::STATSD_SOURCES;
+use crate::activation::activated_udp;
 use crate::config::System;
+use crate::handoff::HANDED_OFF;
+use crate::maintenance::forward;
+use crate::memory::shedding;
+use crate::queue::try_send_task;
+use crate::sandbox::enter_sandbox;
 use crate::server::StatsdServer;
+use crate::signing::signed_payload;
 use crate::task::Task;
-use crate::{DROPS, INGRESS};
+use crate::stats::STATSD_UDP;
+use crate::supervise::spawn_supervised;
+use crate::trace::receive_span;
+use crate::util::pin_thread;
+use crate::workers::WorkerSet;
+use crate::{DROPS, INGESTION_PAUSED, INGRESS, PAUSED_DROPS, SHED_DROPS, STATSD_LISTENING};
+
+/// Bind statsd sockets, or take ones passed by systemd or previous process, before privileges are dropped, so ports
+/// below 1024 and socket options needing capabilities can be used. Multimessage mode shares a single socket between
+/// threads, async mode uses `count` ones.
+pub fn bind_udp(log: &Logger, listen: SocketAddr, config: &System, multimessage: bool, count: usize, bufsize: usize, mm_packets: usize) -> Vec<StdUdpSocket> {
+    // It is crucial for recvmmsg to have one socket per many threads
+    // to avoid drops because at lease two threads have to work on socket
+    // simultaneously
+    let count = if multimessage { 1 } else { count };
+    STATSD_UDP_SOCKET.init(bufsize, if multimessage { mm_packets } else { 0 });
+    // sockets passed by systemd are shared when there are less of them than needed
+    let activated = activated_udp(&listen);
+    if activated.len() > 0 {
+        info!(log, "using statsd sockets passed by systemd or previous process"; "sockets"=>activated.len());
+    }
+    let mut sockets = Vec::with_capacity(count);
+    for idx in 0..count {
+        let socket = if activated.len() > 0 {
+            activated[idx % activated.len()].try_clone().unwrap()
+        } else {
+            let socket = UdpBuilder::new_v4().unwrap();
+            socket.reuse_address(true).unwrap();
+            socket.reuse_port(true).unwrap();
+            socket.bind(&listen).unwrap()
+        };
+        STATSD_UDP_SOCKET.add_socket(socket.as_raw_fd(), config.network.recv_buffer, config.network.busy_poll, log);
+        sockets.push(socket);
+    }
+    sockets
+}
 
-pub(crate) fn start_sync_udp(
+pub fn start_sync_udp(
     log: Logger,
-    listen: SocketAddr,
+    sockets: Vec<StdUdpSocket>,
     chans: &Vec<Sender<Task>>,
     config: Arc<System>,
     n_threads: usize,
+    cpus: &[usize],
     bufsize: usize,
     mm_packets: usize,
     mm_async: bool,
@@ -40,14 +89,9 @@ pub(crate) fn start_sync_udp(
     ) {
     info!(log, "multimessage enabled, starting in sync UDP mode"; "socket-is-blocking"=>!mm_async, "packets"=>mm_packets);
 
-    // It is crucial for recvmmsg to have one socket per many threads
-    // to avoid drops because at lease two threads have to work on socket
-    // simultaneously
-    let socket = UdpBuilder::new_v4().unwrap();
-    socket.reuse_address(true).unwrap();
-    socket.reuse_port(true).unwrap();
-    let sck = socket.bind(listen).unwrap();
+    let sck = sockets.into_iter().next().expect("statsd socket is not bound");
     sck.set_nonblocking(mm_async).unwrap();
+    STATSD_LISTENING.store(true, Ordering::Relaxed);
 
     let mm_timeout = if mm_timeout == 0 {
         config.network.buffer_flush_time
@@ -62,189 +106,228 @@ pub(crate) fn start_sync_udp(
         let sck = sck.try_clone().unwrap();
         let flush_flags = flush_flags.clone();
         let config = config.clone();
-        thread::Builder::new()
-            .name(format!("bioyino_mudp{}", i).into())
-            .spawn(move || {
+        let cpus = cpus.to_vec();
+        spawn_supervised(format!("bioyino_mudp{}", i), log.clone(), move || {
+                // message buffers are allocated after pinning to be local to thread's NUMA node
+                if let Err(e) = pin_thread(&cpus, i) {
+                    warn!(log, "pinning network thread to CPU"; "error"=>e.to_string());
+                }
+                enter_sandbox(&config.sandbox, &log);
                 let fd = sck.as_raw_fd();
                 {
                     // <--- this limits the use of `use::libc::*` scope
                     use libc::*;
 
-                    let chlen = chans.len();
-                    let mut ichans = chans.iter().cycle();
-
-                    // store mmsghdr array so Rust won't free it's memory
-                    let mut mheaders: Vec<mmsghdr> = Vec::with_capacity(mm_packets);
-
-                    // allocate space for address information
-                    // addr len is 16 bytes for ipv6 address + 4 bytes for port totalling 20 bytes
-                    // the structure is only used for advanced information and not copied anywhere
-                    // so it can be initialized before main cycle
-                    let mut addrs: Vec<[u8; 20]> = Vec::with_capacity(mm_packets);
-                    addrs.resize(mm_packets, [0; 20]);
-
-                    for i in 0..mm_packets {
-                        let m = mmsghdr {
-                            msg_hdr: msghdr {
-                                msg_name: addrs[i].as_mut_ptr() as *mut c_void,
-                                msg_namelen: 20 as socklen_t,
-                                msg_iov: null_mut(),     // this will change later
-                                msg_iovlen: 0,           // this will change later
-                                msg_control: null_mut(), // we won't need this
-                                msg_controllen: 0,       // and this
-                                msg_flags: 0,            // and of couse this
-                            },
-                            msg_len: 0,
-                        };
-                        mheaders.push(m);
-                    }
-
-                    let mhptr = mheaders.as_mut_ptr();
-                    let mhlen = mheaders.len();
+                    let mut chans = WorkerSet::for_ingestion(chans.clone());
+                    let mut next = 0;
 
                     let flags = if mm_async { MSG_WAITFORONE } else { 0 };
 
                     // this will store resulting per-source buffers
                     let mut bufmap = HashMap::new();
                     let mut total_received = 0;
-                    let min_bytes = mm_packets * mm_packets * bufsize;
-                    let rowsize = bufsize * mm_packets;
-
-                    let mut recv_buffer = Vec::new();
-                    recv_buffer.reserve_exact(min_bytes);
-
-                    // prepare scatter-gather buffers (iovecs)
-                    // We allocate (mm_packets x mm_packets*bufsize) matrix to guarantee fitting of all
-                    // the messages into memory. For doing this se have to consider 2 edge cases here.
-                    // We know that recvmmsg places all messages from a single source to
-                    // the same iovecs bucket. That is the first case is when all data come from
-                    // single address, so we will have row filled with bytes. The second case is when
-                    // all data come from different addresses, so buffers are filled in
-                    // columns. The default value - 1500 does not consider IP fragmentation here, so the ideal
-                    // value would be maximum IP packet size (~ 65535 - 8 = 65507), but this is the
-                    // rare case in modern networks, at least at datacenters, which are our
-                    // main use case.
-
-                    recv_buffer.resize(min_bytes, 0);
-                    // we don't want rust to forget about intermediate iovecs so we put them into
-                    // separate vector
-                    let mut chunks = Vec::with_capacity(mm_packets);
-
-                    for i in 0..mm_packets {
-                        let chunk = iovec {
-                            iov_base: recv_buffer[i * rowsize..i * rowsize + rowsize].as_mut_ptr()
-                                as *mut c_void,
-                                iov_len: rowsize,
-                        };
-                        chunks.push(chunk);
-                        // put the result to mheaders
-                        mheaders[i].msg_hdr.msg_iov = &mut chunks[i];
-                        mheaders[i].msg_hdr.msg_iovlen = 1;
-                    }
 
+                    // autotune may change the batch size, buffers are allocated again for the new one then,
+                    // received data is already copied to bufmap, so nothing is lost
                     loop {
-                        debug!(log, "recvmsg start");
-                        // timeout is mutable and changed by every recvmmsg call, so it MUST be inside loop
-                        // creating timeout as &mut fails because it's supposedly not dropped
-                        let mut timeout = if mm_timeout > 0 {
-                            timespec {
-                                tv_sec: (mm_timeout / 1000u64) as i64,
-                                tv_nsec: ((mm_timeout % 1000u64) * 1_000_000u64) as i64,
-                            }
-                        } else {
-                            timespec {
-                                tv_sec: 0,
-                                tv_nsec: 0,
-                            }
-                        };
-
-                        let res = unsafe {
-                            recvmmsg(
-                                fd as c_int,
-                                mhptr,
-                                mhlen as c_uint,
-                                flags,
-                                if mm_timeout > 0 {
-                                    &mut timeout
-                                } else {
-                                    null_mut()
+                        let mm_packets = STATSD_UDP_SOCKET.batch();
+                        // store mmsghdr array so Rust won't free it's memory
+                        let mut mheaders: Vec<mmsghdr> = Vec::with_capacity(mm_packets);
+
+                        // allocate space for address information
+                        // addr len is 16 bytes for ipv6 address + 4 bytes for port totalling 20 bytes
+                        // the structure is only used for advanced information and not copied anywhere
+                        // so it can be initialized before main cycle
+                        let mut addrs: Vec<[u8; 20]> = Vec::with_capacity(mm_packets);
+                        addrs.resize(mm_packets, [0; 20]);
+
+                        for i in 0..mm_packets {
+                            let m = mmsghdr {
+                                msg_hdr: msghdr {
+                                    msg_name: addrs[i].as_mut_ptr() as *mut c_void,
+                                    msg_namelen: 20 as socklen_t,
+                                    msg_iov: null_mut(),     // this will change later
+                                    msg_iovlen: 0,           // this will change later
+                                    msg_control: null_mut(), // we won't need this
+                                    msg_controllen: 0,       // and this
+                                    msg_flags: 0,            // and of couse this
                                 },
-                                )
-                        };
+                                msg_len: 0,
+                            };
+                            mheaders.push(m);
+                        }
 
-                        if res == 0 {
-                            // skip this shit
-                        } else if res > 0 {
-                            let messages = res as usize;
-                            // we've received some messages
-                            for i in 0..messages {
-                                let mlen = mheaders[i].msg_len as usize;
-                                total_received += mlen;
-
-                                INGRESS.fetch_add(mlen, Ordering::Relaxed);
-
-                                // create address entry in messagemap
-                                let entry = bufmap
-                                    .entry(addrs[i])
-                                    .or_insert(BytesMut::with_capacity(mlen));
-
-                                // check we can fit the buffer
-                                if entry.remaining_mut() < mlen + 1 {
-                                    entry.reserve(mlen)
-                                }
+                        let mhptr = mheaders.as_mut_ptr();
+                        let mhlen = mheaders.len();
+
+                        let min_bytes = mm_packets * mm_packets * bufsize;
+                        let rowsize = bufsize * mm_packets;
+
+                        let mut recv_buffer = Vec::new();
+                        recv_buffer.reserve_exact(min_bytes);
+
+                        // prepare scatter-gather buffers (iovecs)
+                        // We allocate (mm_packets x mm_packets*bufsize) matrix to guarantee fitting of all
+                        // the messages into memory. For doing this se have to consider 2 edge cases here.
+                        // We know that recvmmsg places all messages from a single source to
+                        // the same iovecs bucket. That is the first case is when all data come from
+                        // single address, so we will have row filled with bytes. The second case is when
+                        // all data come from different addresses, so buffers are filled in
+                        // columns. The default value - 1500 does not consider IP fragmentation here, so the ideal
+                        // value would be maximum IP packet size (~ 65535 - 8 = 65507), but this is the
+                        // rare case in modern networks, at least at datacenters, which are our
+                        // main use case.
+
+                        recv_buffer.resize(min_bytes, 0);
+                        // we don't want rust to forget about intermediate iovecs so we put them into
+                        // separate vector
+                        let mut chunks = Vec::with_capacity(mm_packets);
+
+                        for i in 0..mm_packets {
+                            let chunk = iovec {
+                                iov_base: recv_buffer[i * rowsize..i * rowsize + rowsize].as_mut_ptr()
+                                    as *mut c_void,
+                                    iov_len: rowsize,
+                            };
+                            chunks.push(chunk);
+                            // put the result to mheaders
+                            mheaders[i].msg_hdr.msg_iov = &mut chunks[i];
+                            mheaders[i].msg_hdr.msg_iovlen = 1;
+                        }
 
-                                // and put the buffer into the map
-                                entry.put(&recv_buffer[i * rowsize..i * rowsize + mlen]);
 
-                                // reset addres to be used in next cycle
-                                addrs[i] = [0; 20];
-                                mheaders[i].msg_hdr.msg_namelen = 20;
-                            }
+                        while STATSD_UDP_SOCKET.batch() == mm_packets {
+                            debug!(log, "recvmsg start");
+                            // timeout is mutable and changed by every recvmmsg call, so it MUST be inside loop
+                            // creating timeout as &mut fails because it's supposedly not dropped
+                            let mut timeout = if mm_timeout > 0 {
+                                timespec {
+                                    tv_sec: (mm_timeout / 1000u64) as i64,
+                                    tv_nsec: ((mm_timeout % 1000u64) * 1_000_000u64) as i64,
+                                }
+                            } else {
+                                timespec {
+                                    tv_sec: 0,
+                                    tv_nsec: 0,
+                                }
+                            };
+
+                            let res = unsafe {
+                                recvmmsg(
+                                    fd as c_int,
+                                    mhptr,
+                                    mhlen as c_uint,
+                                    flags,
+                                    if mm_timeout > 0 {
+                                        &mut timeout
+                                    } else {
+                                        null_mut()
+                                    },
+                                    )
+                            };
+
+                            if res == 0 {
+                                // skip this shit
+                            } else if res > 0 {
+                                let messages = res as usize;
+                                let paused = INGESTION_PAUSED.load(Ordering::Relaxed);
+                                let shed = shedding();
+                                // we've received some messages
+                                for i in 0..messages {
+                                    let mlen = mheaders[i].msg_len as usize;
+
+                                    INGRESS.add(mlen);
+                                    STATSD_UDP.packets.add(1);
+
+                                    let packet = &recv_buffer[i * rowsize..i * rowsize + mlen];
+                                    let payload = if STATSD_SOURCES.allows_raw(&addrs[i]) { signed_payload(packet) } else { None };
+                                    if payload.is_none() {
+                                        STATSD_UDP.drops.add(1);
+                                    } else if forward(packet) {
+                                        // handled by the sibling while this node is in maintenance
+                                    } else if paused {
+                                        PAUSED_DROPS.add(1);
+                                        STATSD_UDP.drops.add(1);
+                                    } else if shed {
+                                        SHED_DROPS.add(1);
+                                        STATSD_UDP.drops.add(1);
+                                    } else {
+                                        let packet = &packet[..payload.unwrap_or(0)];
+                                        total_received += packet.len();
+
+                                        // create address entry in messagemap
+                                        // buffer is stamped with the time of the first packet to measure ingestion latency
+                                        let (_, ref mut entry) = *bufmap
+                                            .entry(addrs[i])
+                                            .or_insert_with(|| (Instant::now(), BytesMut::with_capacity(packet.len())));
+
+                                        // check we can fit the buffer
+                                        if entry.remaining_mut() < packet.len() + 1 {
+                                            entry.reserve(packet.len())
+                                        }
+
+                                        // and put the buffer into the map
+                                        entry.put(packet);
+                                    }
+
+                                    // reset addres to be used in next cycle
+                                    addrs[i] = [0; 20];
+                                    mheaders[i].msg_hdr.msg_namelen = 20;
+                                }
 
-                            // when it's time to send bytes, send them
-                            let flush = flush_flags.get(i).unwrap().swap(false, Ordering::SeqCst);
-                            if flush || total_received >= config.network.buffer_flush_length {
-                                total_received = 0;
-                                bufmap
-                                    .drain()
-                                    .map(|(addr, mut buf)| {
-                                        // in some ideal world we want all values from the same host to be parsed by the
-                                        // same thread, but this could cause load unbalancing between threads in some
-                                        // corner cases, i.e. when only few hosts are sending most
-                                        // metrics
-                                        // TODO: we can work this around by fast-scanning buffer
-                                        // for newlines. if more than 2 newlines are there, buffer
-                                        // can be split into 3 parts and the middle part can be
-                                        // cropped from the buffer and relatively safely given to other nodes for
-                                        // parsing. It would be WAY better to do this in counting
-                                        // nodes rather than networking ones, but could be harder
-                                        // than it seems because of queue reordering
-                                        let mut hasher = DefaultHasher::new();
-                                        hasher.write(&addr);
-                                        let ahash = hasher.finish();
-                                        let mut chan = if config.metrics.consistent_parsing {
-                                            chans[ahash as usize % chlen].clone()
-                                        } else {
-                                            ichans.next().unwrap().clone()
-                                        };
-                                        chan.try_send(Task::Parse(ahash, buf.take()))
-                                            .map_err(|_| {
-                                                warn!(log, "error sending buffer(queue full?)");
-                                                DROPS.fetch_add(
-                                                    messages as usize,
-                                                    Ordering::Relaxed,
-                                                    );
-                                            }).unwrap_or(());
-                                    }).last();
-                            }
-                        } else {
-                            let errno = unsafe { *__errno_location() };
-                            if errno == EAGAIN {
+                                // when it's time to send bytes, send them
+                                let flush = flush_flags.get(i).unwrap().swap(false, Ordering::SeqCst);
+                                // the socket is read by the new process, so buffers are sent for the last time and the thread stops
+                                let handed_off = HANDED_OFF.load(Ordering::Relaxed);
+                                if flush || handed_off || total_received >= config.network.buffer_flush_length {
+                                    total_received = 0;
+                                    // workers may be added or removed at runtime
+                                    chans.refresh();
+                                    let chlen = chans.len();
+                                    bufmap
+                                        .drain()
+                                        .map(|(addr, (received, mut buf))| {
+                                            // in some ideal world we want all values from the same host to be parsed by the
+                                            // same thread, but this could cause load unbalancing between threads in some
+                                            // corner cases, i.e. when only few hosts are sending most
+                                            // metrics
+                                            // TODO: we can work this around by fast-scanning buffer
+                                            // for newlines. if more than 2 newlines are there, buffer
+                                            // can be split into 3 parts and the middle part can be
+                                            // cropped from the buffer and relatively safely given to other nodes for
+                                            // parsing. It would be WAY better to do this in counting
+                                            // nodes rather than networking ones, but could be harder
+                                            // than it seems because of queue reordering
+                                            let mut hasher = DefaultHasher::new();
+                                            hasher.write(&addr);
+                                            let ahash = hasher.finish();
+                                            let worker = if config.metrics.consistent_parsing {
+                                                ahash as usize % chlen
+                                            } else {
+                                                next = (next + 1) % chlen;
+                                                next
+                                            };
+                                            let trace = receive_span(received, buf.len(), worker);
+                                            try_send_task(&mut chans, worker, Task::Parse(ahash, buf.take(), received, trace))
+                                                .map_err(|_| {
+                                                    warn!(log, "error sending buffer(queue full?)");
+                                                    DROPS.add(messages as usize);
+                                                    STATSD_UDP.drops.add(messages as usize);
+                                                }).unwrap_or(());
+                                        }).last();
+                                    if handed_off {
+                                        return;
+                                    }
+                                }
                             } else {
-                                warn!(log, "UDP receive error";
-                                      "code"=> format!("{}", res),
-                                      "error"=>format!("{}", io::Error::last_os_error())
-                                );
+                                let errno = unsafe { *__errno_location() };
+                                if errno == EAGAIN {
+                                } else {
+                                    warn!(log, "UDP receive error";
+                                          "code"=> format!("{}", res),
+                                          "error"=>format!("{}", io::Error::last_os_error())
+                                    );
+                                }
                             }
                         }
                     }
@@ -253,28 +336,24 @@ pub(crate) fn start_sync_udp(
     }
 }
 
-pub(crate) fn start_async_udp(
+pub fn start_async_udp(
     log: Logger,
-    listen: SocketAddr,
+    sockets: Vec<StdUdpSocket>,
     chans: &Vec<Sender<Task>>,
     config: Arc<System>,
     n_threads: usize,
+    cpus: &[usize],
     greens: usize,
-    async_sockets: usize,
     bufsize: usize,
     flush_flags: Arc<Vec<AtomicBool>>,
     ) {
     info!(log, "multimessage is disabled, starting in async UDP mode");
 
-    // Create a pool of listener sockets
-    let mut sockets = Vec::new();
-    for _ in 0..async_sockets {
-        let socket = UdpBuilder::new_v4().unwrap();
-        socket.reuse_address(true).unwrap();
-        socket.reuse_port(true).unwrap();
-        let socket = socket.bind(&listen).unwrap();
-        sockets.push(socket);
+    for socket in sockets.iter() {
+        // tokio 1.x expects sockets to be non-blocking already
+        socket.set_nonblocking(true).unwrap();
     }
+    STATSD_LISTENING.store(true, Ordering::Relaxed);
 
     for i in 0..n_threads {
         // Each thread gets the clone of a socket pool
@@ -286,12 +365,17 @@ pub(crate) fn start_async_udp(
         let chans = chans.clone();
         let flush_flags = flush_flags.clone();
         let config = config.clone();
-        thread::Builder::new()
-            .name(format!("bioyino_udp{}", i).into())
-            .spawn(move || {
+        let cpus = cpus.to_vec();
+        let tlog = log.new(o!("source"=>"udp_thread"));
+        spawn_supervised(format!("bioyino_udp{}", i), tlog.clone(), move || {
+                if let Err(e) = pin_thread(&cpus, i) {
+                    warn!(tlog, "pinning network thread to CPU"; "error"=>e.to_string());
+                }
                 // each thread runs it's own runtime
-                let mut runtime = Runtime::new().expect("creating runtime for counting worker");
+                let runtime = Builder::new_current_thread().enable_io().build().expect("creating runtime for async UDP");
 
+                // sockets are added to event loop before sandboxing, it may need syscalls the sandbox denies
+                let mut servers = Vec::with_capacity(greens * sockets.len());
                 // Inside each green thread
                 for _ in 0..greens {
                     // start a listener for all sockets
@@ -301,16 +385,17 @@ pub(crate) fn start_async_udp(
                         let chans = chans.clone();
                         // create UDP listener
                         let socket = socket.try_clone().expect("cloning socket");
-                        let socket =
-                            UdpSocket::from_std(socket, &Handle::default())
-                            .expect("adding socket to event loop");
+                        let socket = {
+                            // sockets are registered in the reactor of the runtime entered
+                            let _runtime = runtime.enter();
+                            UdpSocket::from_std(socket).expect("adding socket to event loop")
+                        };
 
                         let server = StatsdServer::new(
                             socket,
-                            chans.clone(),
+                            WorkerSet::for_ingestion(chans.clone()),
                             HashMap::new(),
                             config.clone(),
-                            bufsize,
                             0,
                             i,
                             readbuf,
@@ -318,13 +403,188 @@ pub(crate) fn start_async_udp(
                             i,
                             );
 
-                        runtime.spawn(server.into_future());
+                        servers.push(server);
                     }
                 }
+                enter_sandbox(&config.sandbox, &tlog);
+                // servers spawn sending to workers on the same thread
+                let local = LocalSet::new();
+                for server in servers {
+                    local.spawn_local(server.run());
+                }
 
-                runtime
-                    .block_on(empty::<(), ()>())
-                    .expect("starting runtime for async UDP");
+                local.block_on(&runtime, pending::<()>());
             }).expect("creating UDP reader thread");
     }
 }
+
+lazy_static! {
+    /// Socket settings of statsd UDP listener in effect now
+    pub static ref STATSD_UDP_SOCKET: SocketTuning = SocketTuning::default();
+}
+
+/// Settings of listener sockets that can be changed while they are receiving data
+#[derive(Debug, Default)]
+pub struct SocketTuning {
+    bufsize: AtomicUsize,
+    // multimessage batch, 0 if listener is not in multimessage mode
+    batch: AtomicUsize,
+    busy_poll: AtomicUsize,
+    fds: Mutex<Vec<RawFd>>,
+}
+
+/// Socket settings of a listener, as shown in stats
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct SocketValues {
+    pub bufsize: usize,
+    /// Datagrams received by one recvmmsg call, 0 if multimessage mode is off
+    pub batch: usize,
+    /// Receive buffer as reported by the kernel, it is double of the requested one
+    pub recv_buffer: usize,
+    pub busy_poll: usize,
+    /// Datagrams dropped by the kernel because receive buffer was full
+    pub kernel_drops: u64,
+}
+
+fn set_option(fd: RawFd, option: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    let res = unsafe { libc::setsockopt(fd, libc::SOL_SOCKET, option, &value as *const libc::c_int as *const libc::c_void, mem::size_of::<libc::c_int>() as libc::socklen_t) };
+    if res < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+fn get_option(fd: RawFd, option: libc::c_int) -> io::Result<libc::c_int> {
+    let mut value: libc::c_int = 0;
+    let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let res = unsafe { libc::getsockopt(fd, libc::SOL_SOCKET, option, &mut value as *mut libc::c_int as *mut libc::c_void, &mut len) };
+    if res < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(value)
+    }
+}
+
+impl SocketTuning {
+    fn init(&self, bufsize: usize, batch: usize) {
+        self.bufsize.store(bufsize, Ordering::Relaxed);
+        self.batch.store(batch, Ordering::Relaxed);
+    }
+
+    /// Apply configured options to a new socket, options that cannot be set are only logged,
+    /// so listener works with system defaults then
+    fn add_socket(&self, fd: RawFd, recv_buffer: usize, busy_poll: u32, log: &Logger) {
+        if recv_buffer > 0 {
+            if let Err(e) = set_option(fd, libc::SO_RCVBUF, recv_buffer as libc::c_int) {
+                warn!(log, "setting UDP receive buffer"; "error"=>e.to_string());
+            }
+        }
+        if busy_poll > 0 {
+            match set_option(fd, libc::SO_BUSY_POLL, busy_poll as libc::c_int) {
+                Ok(()) => self.busy_poll.store(busy_poll as usize, Ordering::Relaxed),
+                Err(e) => warn!(log, "setting UDP busy poll"; "error"=>e.to_string()),
+            }
+        }
+        self.fds.lock().unwrap().push(fd);
+    }
+
+    /// Descriptors of statsd sockets
+    pub fn fds(&self) -> Vec<RawFd> {
+        self.fds.lock().unwrap().clone()
+    }
+
+    pub fn batch(&self) -> usize {
+        self.batch.load(Ordering::Relaxed)
+    }
+
+    /// Receive buffer of listener sockets as reported by the kernel
+    pub fn recv_buffer(&self) -> usize {
+        self.fds.lock().unwrap().first().and_then(|fd| get_option(*fd, libc::SO_RCVBUF).ok()).unwrap_or(0) as usize
+    }
+
+    fn set_recv_buffer(&self, size: usize) -> io::Result<()> {
+        self.fds.lock().unwrap().iter().map(|fd| set_option(*fd, libc::SO_RCVBUF, size as libc::c_int)).collect()
+    }
+
+    pub fn values(&self, port: u16) -> SocketValues {
+        SocketValues { bufsize: self.bufsize.load(Ordering::Relaxed), batch: self.batch(), recv_buffer: self.recv_buffer(), busy_poll: self.busy_poll.load(Ordering::Relaxed), kernel_drops: listener_drops(port) }
+    }
+}
+
+/// Sum of drops of all sockets bound to local `port` in a table like /proc/net/udp
+pub fn udp_drops(table: &str, port: u16) -> u64 {
+    table
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let local_port = fields.get(1)?.rsplit(':').next().and_then(|port| u16::from_str_radix(port, 16).ok())?;
+            if local_port != port {
+                return None;
+            }
+            // drops are the last column
+            fields.last()?.parse::<u64>().ok()
+        })
+        .sum()
+}
+
+/// Datagrams dropped by the kernel for UDP sockets on `port`, 0 if the tables cannot be read
+pub fn listener_drops(port: u16) -> u64 {
+    ["/proc/net/udp", "/proc/net/udp6"].iter().filter_map(|path| fs::read_to_string(path).ok()).map(|table| udp_drops(&table, port)).sum()
+}
+
+/// A future doubling receive buffer and multimessage batch of statsd UDP listener every `interval`
+/// the kernel dropped packets during, up to the maximums. Maximum receive buffer is the requested size
+/// like in configuration, not the one reported by the kernel. Never gets ready.
+pub fn autotune_udp(port: u16, max_recv_buffer: usize, max_batch: usize, interval: Duration, log: Logger) -> impl Future<Item = (), Error = ()> {
+    let log = log.new(o!("source"=>"udp-autotune"));
+    let err_log = log.clone();
+    let mut last_drops = listener_drops(port);
+    Interval::new(Instant::now() + interval, interval)
+        .map_err(move |e| {
+            warn!(err_log, "UDP autotune timer failed"; "error"=>e.to_string());
+        })
+        .for_each(move |_| {
+            let drops = listener_drops(port);
+            if drops > last_drops {
+                let socket = &*STATSD_UDP_SOCKET;
+                // the kernel reports double of the requested size, so requesting the reported one doubles it
+                let recv_buffer = socket.recv_buffer();
+                if recv_buffer > 0 && recv_buffer < max_recv_buffer * 2 {
+                    match socket.set_recv_buffer(recv_buffer.min(max_recv_buffer)) {
+                        Ok(()) if socket.recv_buffer() > recv_buffer => info!(log, "UDP receive buffer increased"; "drops"=>drops - last_drops, "old"=>recv_buffer, "new"=>socket.recv_buffer()),
+                        Ok(()) => debug!(log, "UDP receive buffer is limited by net.core.rmem_max"; "size"=>recv_buffer),
+                        Err(e) => warn!(log, "increasing UDP receive buffer"; "error"=>e.to_string()),
+                    }
+                }
+                let batch = socket.batch();
+                if batch > 0 && batch < max_batch {
+                    let new = (batch * 2).min(max_batch);
+                    socket.batch.store(new, Ordering::Relaxed);
+                    info!(log, "UDP multimessage batch increased"; "drops"=>drops - last_drops, "old"=>batch, "new"=>new);
+                }
+            }
+            last_drops = drops;
+            Ok(())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn proc_udp_drops() {
+        let table = "   sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode ref pointer drops
+  118: 00000000:1FBD 00000000:0000 07 00000000:00000000 00:00000000 00000000     0        0 21045 2 0000000000000000 17
+  119: 0100007F:1FBD 00000000:0000 07 00000000:00000000 00:00000000 00000000     0        0 21046 2 0000000000000000 3
+  120: 00000000:0035 00000000:0000 07 00000000:00000000 00:00000000 00000000     0        0 11210 2 0000000000000000 100
+";
+        assert_eq!(udp_drops(table, 8125), 20);
+        assert_eq!(udp_drops(table, 53), 100);
+        assert_eq!(udp_drops(table, 8136), 0);
+        assert_eq!(udp_drops("", 8125), 0);
+    }
+}
@@ -0,0 +1,103 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::marker::PhantomData;
+
+use serde::de::{self, Deserializer, Visitor};
+
+// suffixes are checked in order, so longer ones sharing the ending must go first
+const DURATION_UNITS: &[(&str, f64)] = &[("ms", 1f64), ("s", 1000f64), ("m", 60_000f64), ("h", 3_600_000f64), ("d", 86_400_000f64)];
+
+const SIZE_UNITS: &[(&str, f64)] = &[
+    ("KiB", 1024f64),
+    ("MiB", 1_048_576f64),
+    ("GiB", 1_073_741_824f64),
+    ("KB", 1000f64),
+    ("MB", 1_000_000f64),
+    ("GB", 1_000_000_000f64),
+    ("K", 1024f64),
+    ("M", 1_048_576f64),
+    ("G", 1_073_741_824f64),
+    ("B", 1f64),
+];
+
+fn parse_with_units(value: &str, units: &[(&str, f64)], default: f64) -> Result<u64, String> {
+    let value = value.trim();
+    let (number, multiplier) = units.iter().find(|(suffix, _)| value.ends_with(suffix)).map(|(suffix, multiplier)| (&value[..value.len() - suffix.len()], *multiplier)).unwrap_or((value, default));
+    let number = number.trim().parse::<f64>().map_err(|_| format!("bad value {:?}, expected number with one of units: {}", value, units.iter().map(|(suffix, _)| *suffix).collect::<Vec<_>>().join(", ")))?;
+    if number < 0f64 || !number.is_finite() {
+        return Err(format!("bad value {:?}, must not be negative", value));
+    }
+    Ok((number * multiplier).round() as u64)
+}
+
+/// Parse duration like "30s", "2m" or "100ms" to milliseconds, numbers without units are milliseconds
+pub fn parse_duration(value: &str) -> Result<u64, String> {
+    parse_with_units(value, DURATION_UNITS, 1f64)
+}
+
+/// Parse size like "64KiB" or "1GiB" to bytes, numbers without units are bytes
+pub fn parse_size(value: &str) -> Result<u64, String> {
+    parse_with_units(value, SIZE_UNITS, 1f64)
+}
+
+struct UnitsVisitor<T> {
+    parse: fn(&str) -> Result<u64, String>,
+    expected: &'static str,
+    output: PhantomData<T>,
+}
+
+impl<'de, T: TryFrom<u64>> Visitor<'de> for UnitsVisitor<T> {
+    type Value = T;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str(self.expected)
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<T, E> {
+        T::try_from(value).map_err(|_| E::custom(format!("value {} is too big", value)))
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<T, E> {
+        if value < 0 {
+            return Err(E::custom(format!("bad value {}, must not be negative", value)));
+        }
+        self.visit_u64(value as u64)
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<T, E> {
+        let parsed = (self.parse)(value).map_err(E::custom)?;
+        self.visit_u64(parsed)
+    }
+}
+
+/// Deserialize milliseconds from either integer or string with units, like "30s"
+pub fn duration_ms<'de, D: Deserializer<'de>, T: TryFrom<u64>>(deserializer: D) -> Result<T, D::Error> {
+    deserializer.deserialize_any(UnitsVisitor { parse: parse_duration, expected: "milliseconds or duration with units, like \"30s\"", output: PhantomData })
+}
+
+/// Deserialize bytes from either integer or string with units, like "64KiB"
+pub fn size_bytes<'de, D: Deserializer<'de>, T: TryFrom<u64>>(deserializer: D) -> Result<T, D::Error> {
+    deserializer.deserialize_any(UnitsVisitor { parse: parse_size, expected: "bytes or size with units, like \"64KiB\"", output: PhantomData })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_units() {
+        assert_eq!(parse_duration("30s"), Ok(30000));
+        assert_eq!(parse_duration("2m"), Ok(120_000));
+        assert_eq!(parse_duration("100ms"), Ok(100));
+        assert_eq!(parse_duration("1.5h"), Ok(5_400_000));
+        assert_eq!(parse_duration("250"), Ok(250));
+        assert!(parse_duration("10 parsecs").is_err());
+        assert!(parse_duration("-1s").is_err());
+
+        assert_eq!(parse_size("64KiB"), Ok(65536));
+        assert_eq!(parse_size("1GiB"), Ok(1_073_741_824));
+        assert_eq!(parse_size("1500"), Ok(1500));
+        assert_eq!(parse_size("2 MB"), Ok(2_000_000));
+        assert!(parse_size("1XB").is_err());
+    }
+}
@@ -1,7 +1,9 @@
 use libc;
+use std::collections::BTreeMap;
 use std::ffi::CStr;
+use std::fs;
 use std::io;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::net::TcpStream as StdTcpStream;
 use std::sync::atomic::Ordering;
 use std::time::{Duration, Instant};
@@ -17,12 +19,22 @@ use tokio::executor::current_thread::spawn;
 use tokio::net::TcpListener;
 use tokio::timer::{Delay, Interval};
 
-use crate::task::Task;
+use crate::accounting::take_interval;
+use crate::alloc::allocator_stats;
+use crate::carbon::BACKEND_QUEUE_BYTES;
+use crate::cluster::{peer_times, PeerTimes};
+use crate::errors::GeneralError;
+use crate::events::event;
+use crate::health::HealthScore;
+use crate::latency::{histograms, LatencyValues};
+use crate::queue::{worker_queues, FLUSH_QUEUE};
+use crate::stats::Counters;
+use crate::task::{task_counts, Task, TaskKind};
+use crate::workers::worker_count;
 use crate::Float;
-use crate::{AGG_ERRORS, DROPS, EGRESS, INGRESS, INGRESS_METRICS, PARSE_ERRORS, PEER_ERRORS};
 use bioyino_metric::{Metric, MetricType};
 
-use crate::{ConsensusState, CONSENSUS_STATE, IS_LEADER};
+use crate::{ConsensusState, BACKEND_OK, CONSENSUS_STATE, IS_LEADER};
 
 pub fn prepare_log(root: &'static str) -> Logger {
     // Set logging
@@ -34,6 +46,19 @@ pub fn prepare_log(root: &'static str) -> Logger {
     return rlog;
 }
 
+/// Same as `try_resolve`, but returns an error instead of panicking, so it can be used
+/// for checking addresses in runtime
+pub fn resolve_addr(s: &str) -> Result<SocketAddr, GeneralError> {
+    if let Ok(addr) = s.parse() {
+        return Ok(addr);
+    }
+    let mut split = s.split(':');
+    let host = split.next().unwrap(); // Split always has first element
+    let port = split.next().and_then(|port| port.parse().ok()).ok_or_else(|| GeneralError::Resolve(s.to_string()))?;
+    let first_ip = resolver::resolve_host(host).ok().and_then(|mut ips| ips.next()).ok_or_else(|| GeneralError::Resolve(s.to_string()))?;
+    Ok(SocketAddr::new(first_ip, port))
+}
+
 pub fn try_resolve(s: &str) -> SocketAddr {
     s.parse().unwrap_or_else(|_| {
         // for name that have failed to be parsed we try to resolve it via DNS
@@ -110,6 +135,145 @@ pub fn get_hostname() -> Option<String> {
     }
 }
 
+/// CPU limit set by cgroup quota, rounded up. Takes contents of cgroup v2 `cpu.max` or of
+/// cgroup v1 `cpu.cfs_quota_us` and `cpu.cfs_period_us` files, no quota gives `None`
+pub fn cgroup_cpu_limit(cpu_max: Option<&str>, v1_quota: Option<&str>, v1_period: Option<&str>) -> Option<usize> {
+    let (quota, period) = match (cpu_max, v1_quota, v1_period) {
+        (Some(cpu_max), _, _) => {
+            let mut parts = cpu_max.split_whitespace();
+            (parts.next()?.parse::<i64>().ok()?, parts.next().unwrap_or("100000").parse::<i64>().ok()?)
+        }
+        (None, Some(quota), Some(period)) => (quota.trim().parse::<i64>().ok()?, period.trim().parse::<i64>().ok()?),
+        _ => return None,
+    };
+    // "max" in v2 fails to parse above, -1 means no quota in v1
+    if quota <= 0 || period <= 0 {
+        return None;
+    }
+    Some(((quota + period - 1) / period) as usize)
+}
+
+/// Parse Linux CPU list like "0-3,8,10-11" to CPU numbers
+pub fn parse_cpu_list(list: &str) -> Result<Vec<usize>, String> {
+    let mut cpus = Vec::new();
+    for range in list.trim().split(',').filter(|range| range.len() > 0) {
+        let number = |value: &str| value.trim().parse::<usize>().map_err(|_| format!("bad CPU number {:?} in {:?}", value, list));
+        let mut bounds = range.splitn(2, '-');
+        let first = number(bounds.next().unwrap_or(""))?;
+        let last = match bounds.next() {
+            Some(last) => number(last)?,
+            None => first,
+        };
+        if last < first {
+            return Err(format!("bad CPU range {:?} in {:?}", range, list));
+        }
+        cpus.extend(first..=last);
+    }
+    if cpus.len() == 0 {
+        return Err(format!("empty CPU list {:?}", list));
+    }
+    Ok(cpus)
+}
+
+/// Resolve thread placement option to CPU numbers, it is either a CPU list like "0-3,8"
+/// or a NUMA node like "node1", which means all CPUs of the node
+pub fn resolve_cpus(spec: &str) -> Result<Vec<usize>, String> {
+    if spec.starts_with("node") {
+        let path = format!("/sys/devices/system/node/{}/cpulist", spec);
+        let list = fs::read_to_string(&path).map_err(|e| format!("reading CPUs of NUMA {}: {}", spec, e))?;
+        parse_cpu_list(&list)
+    } else {
+        parse_cpu_list(spec)
+    }
+}
+
+/// Pin the current thread to CPU from the list, threads are spread over the list by their index.
+/// Memory is allocated on the NUMA node thread runs at when it is touched for the first time,
+/// so thread buffers should be created after pinning. Nothing is done for empty list.
+#[cfg(target_os = "linux")]
+pub fn pin_thread(cpus: &[usize], idx: usize) -> Result<(), io::Error> {
+    if cpus.len() == 0 {
+        return Ok(());
+    }
+    let cpu = cpus[idx % cpus.len()];
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_ZERO(&mut set);
+        libc::CPU_SET(cpu, &mut set);
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn pin_thread(cpus: &[usize], _idx: usize) -> Result<(), io::Error> {
+    if cpus.len() == 0 {
+        return Ok(());
+    }
+    Err(io::Error::new(io::ErrorKind::Other, "thread pinning is only supported on Linux"))
+}
+
+/// Match a metric name against a shell-like glob pattern. `*` matches any number of bytes
+/// (including none), `?` matches exactly one byte, everything else matches literally.
+pub fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // position of the last seen star in pattern and the name position it was tried at
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((sp, sn)) = star {
+            // backtrack: let the star consume one more byte
+            p = sp + 1;
+            n = sn + 1;
+            star = Some((sp, sn + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+/// Match like `glob_match`, giving parts of the name matched by every `*` and `?` in order. Like in
+/// `glob_match` only the last star is backtracked, so the earlier ones match as few bytes as they can.
+pub fn glob_captures<'a>(pattern: &[u8], name: &'a [u8]) -> Option<Vec<&'a [u8]>> {
+    // name position every pattern position started matching at
+    let mut starts = vec![0; pattern.len() + 1];
+    let (mut p, mut n) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == name[n]) {
+            starts[p] = n;
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == b'*' {
+            starts[p] = n;
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((sp, sn)) = star {
+            p = sp + 1;
+            n = sn + 1;
+            star = Some((sp, sn + 1));
+        } else {
+            return None;
+        }
+    }
+    if !pattern[p..].iter().all(|c| *c == b'*') {
+        return None;
+    }
+    for start in &mut starts[p..] {
+        *start = name.len();
+    }
+    let captures = pattern.iter().enumerate().filter(|(_, c)| **c == b'*' || **c == b'?').map(|(p, _)| &name[starts[p]..starts[p + 1]]).collect();
+    Some(captures)
+}
+
 pub fn switch_leader(acquired: bool, log: &Logger) {
     let should_set = {
         let state = &*CONSENSUS_STATE.lock().unwrap();
@@ -120,63 +284,158 @@ pub fn switch_leader(acquired: bool, log: &Logger) {
         let is_leader = IS_LEADER.load(Ordering::SeqCst);
         if is_leader != acquired {
             warn!(log, "leader state change: {} -> {}", is_leader, acquired);
+            event("leader", if acquired { "became leader".to_string() } else { "stopped being leader".to_string() });
         }
         IS_LEADER.store(acquired, Ordering::SeqCst);
     }
 }
 
+/// Graphite tags own metrics are sent with, `;name=value` pairs in the order of names. Tag
+/// `node_tag` is given the name of this node.
+pub fn stats_tags(tags: &BTreeMap<String, String>, node_tag: &Option<String>, node: &str) -> String {
+    let mut tags = tags.clone();
+    if let Some(node_tag) = node_tag {
+        tags.insert(node_tag.clone(), node.to_string());
+    }
+    // `;` separates tags, so it cannot be a part of value
+    tags.iter().map(|(name, value)| format!(";{}={}", name, value.replace(';', "_"))).collect()
+}
+
+// peer IP as a single part of metric name
+fn peer_name(ip: &IpAddr) -> String {
+    ip.to_string().replace('.', "_").replace(':', "_")
+}
+
 // A future to send own stats. Never gets ready.
 pub struct OwnStats {
     interval: u64,
     prefix: String,
+    // added to every metric name
+    tags: String,
     timer: Interval,
     chan: Sender<Task>,
+    // global counters only grow, so we remember previous values to count the difference
+    last: Counters,
+    last_latency: Vec<LatencyValues>,
+    last_tasks: Vec<(TaskKind, usize, usize)>,
+    last_peers: BTreeMap<IpAddr, PeerTimes>,
     log: Logger,
 }
 
 impl OwnStats {
-    pub fn new(interval: u64, prefix: String, chan: Sender<Task>, log: Logger) -> Self {
+    pub fn new(interval: u64, prefix: String, tags: String, chan: Sender<Task>, log: Logger) -> Self {
         let log = log.new(o!("source"=>"stats"));
         let now = Instant::now();
         let dur = Duration::from_millis(if interval < 100 { 1000 } else { interval }); // exclude too small intervals
-        Self { interval, prefix, timer: Interval::new(now + dur, dur), chan, log }
+        Self {
+            interval,
+            prefix,
+            tags,
+            timer: Interval::new(now + dur, dur),
+            chan,
+            last: Counters::load(),
+            last_latency: histograms().iter().map(|histogram| histogram.values()).collect(),
+            last_tasks: task_counts(),
+            last_peers: peer_times().into_iter().collect(),
+            log,
+        }
+    }
+
+    fn send(&self, buf: &mut BytesMut, suffix: &str, metric: Metric<Float>) {
+        buf.reserve(self.prefix.len() + suffix.len() + self.tags.len() + 1);
+        buf.put(&self.prefix);
+        buf.put(".");
+        buf.put(suffix);
+        buf.put(&self.tags);
+        let name = buf.take().freeze();
+        let log = self.log.clone();
+        let sender = self.chan.clone().send(Task::AddMetric(name, metric)).map(|_| ()).map_err(move |_| warn!(log, "stats future could not send metric to task"));
+        spawn(sender);
     }
 
     pub fn get_stats(&mut self) {
-        let mut buf = BytesMut::with_capacity((self.prefix.len() + 10) * 7); // 10 is suffix len, 7 is number of metrics
-        macro_rules! add_metric {
-            ($global:ident, $value:ident, $suffix:expr) => {
-                let $value = $global.swap(0, Ordering::Relaxed) as Float;
-                if self.interval > 0 {
-                    buf.put(&self.prefix);
-                    buf.put(".");
-                    buf.put(&$suffix);
-                    let name = buf.take().freeze();
-                    let metric = Metric::new($value, MetricType::Counter, None, None).unwrap();
-                    let log = self.log.clone();
-                    let sender = self.chan.clone().send(Task::AddMetric(name, metric)).map(|_| ()).map_err(move |_| warn!(log, "stats future could not send metric to task"));
-                    spawn(sender);
-                }
-            };
-        };
-        add_metric!(EGRESS, egress, "egress");
-        add_metric!(INGRESS, ingress, "ingress");
-        add_metric!(INGRESS_METRICS, ingress_m, "ingress-metric");
-        add_metric!(AGG_ERRORS, agr_errors, "agg-error");
-        add_metric!(PARSE_ERRORS, parse_errors, "parse-error");
-        add_metric!(PEER_ERRORS, peer_errors, "peer-error");
-        add_metric!(DROPS, drops, "drop");
+        let current = Counters::load();
+        let delta = current.delta(&self.last);
+        self.last = current;
+        let accuracy = delta.accuracy();
+        if accuracy.approximate() {
+            warn!(self.log, "aggregation is approximate because of memory pressure"; "capped-metrics"=>accuracy.capped_metrics, "capped-samples"=>accuracy.capped_samples, "shed-drops"=>accuracy.shed_drops, "estimated-error"=>accuracy.estimated_error);
+        }
+        let delta = delta.to_vec();
+
+        // per task kind and per peer values are counters too
+        let tasks = task_counts();
+        let peers = peer_times().into_iter().collect::<BTreeMap<_, _>>();
+        let mut counters = delta.iter().map(|(name, value)| (name.to_string(), *value)).collect::<Vec<_>>();
+        for ((kind, count, time_us), (_, prev_count, prev_time_us)) in tasks.iter().zip(self.last_tasks.iter()) {
+            counters.push((format!("task.{}.count", kind.name()), count.wrapping_sub(*prev_count)));
+            counters.push((format!("task.{}.time-us", kind.name()), time_us.wrapping_sub(*prev_time_us)));
+        }
+        for (ip, times) in &peers {
+            let prev = self.last_peers.get(ip).cloned().unwrap_or_default();
+            let name = peer_name(ip);
+            counters.push((format!("peer.{}.snapshot-sent", name), times.sent.wrapping_sub(prev.sent)));
+            counters.push((format!("peer.{}.snapshot-send-error", name), times.send_errors.wrapping_sub(prev.send_errors)));
+            counters.push((format!("peer.{}.snapshot-received", name), times.received.wrapping_sub(prev.received)));
+        }
+        self.last_tasks = tasks;
+        self.last_peers = peers;
+        for (prefix, tally) in take_interval() {
+            let prefix = String::from_utf8_lossy(&prefix);
+            counters.push((format!("accounting.{}.samples", prefix), tally.samples as usize));
+            counters.push((format!("accounting.{}.points", prefix), tally.points as usize));
+        }
+
         if self.interval > 0 {
-            let s_interval = self.interval as f64 / 1000f64;
+            let mut buf = BytesMut::with_capacity((self.prefix.len() + self.tags.len() + 32) * counters.len()); // 32 is max suffix len with a dot
+            for (suffix, value) in &counters {
+                let metric = Metric::new(*value as Float, MetricType::Counter, None, None).unwrap();
+                self.send(&mut buf, suffix, metric);
+            }
 
+            // allocator stats and queue depths are levels, not counters, so they are sent as gauges
+            let mut gauges = allocator_stats().map(|stats| stats.to_vec().into_iter().map(|(name, value)| (name.to_string(), value)).collect()).unwrap_or_else(Vec::new);
+            for (worker, queue) in worker_queues(worker_count()).iter().enumerate() {
+                gauges.push((format!("queue.worker.{}.depth", worker), queue.depth as Float));
+                gauges.push((format!("queue.worker.{}.high-watermark", worker), queue.high_watermark as Float));
+            }
+            gauges.push(("queue.flush.depth".to_string(), FLUSH_QUEUE.depth() as Float));
+            gauges.push(("backend.carbon.queue-bytes".to_string(), BACKEND_QUEUE_BYTES.load(Ordering::Relaxed) as Float));
+            gauges.push(("backend.carbon.ok".to_string(), if BACKEND_OK.load(Ordering::Relaxed) { 1f64 } else { 0f64 }));
+            // counts of capped and shed data go with other counters, these tell whether the interval can be trusted
+            gauges.push(("accuracy.approximate".to_string(), if accuracy.approximate() { 1f64 } else { 0f64 }));
+            gauges.push(("accuracy.estimated-error".to_string(), accuracy.estimated_error));
+            let health = HealthScore::current();
+            gauges.push(("health.score".to_string(), health.score));
+            gauges.push(("health.degraded".to_string(), if health.degraded { 1f64 } else { 0f64 }));
+            // latency is sent as quantiles of the interval, intervals without values send nothing
+            let latency = histograms().iter().map(|histogram| histogram.values()).collect::<Vec<_>>();
+            for (histogram, (values, prev)) in histograms().iter().zip(latency.iter().zip(self.last_latency.iter())) {
+                let delta = values.delta(prev);
+                if delta.count() > 0 {
+                    for (suffix, q) in &[("p50", 0.5f64), ("p90", 0.9), ("p99", 0.99)] {
+                        gauges.push((format!("latency.{}.{}", histogram.name, suffix), delta.quantile(*q)));
+                    }
+                }
+            }
+            self.last_latency = latency;
+            for (suffix, value) in gauges {
+                let metric = Metric::new(value, MetricType::Gauge(None), None, None).unwrap();
+                self.send(&mut buf, &suffix, metric);
+            }
+
+            let s_interval = self.interval as f64 / 1000f64;
+            let rate = |idx: usize| format!("{:2}", delta[idx].1 as Float / s_interval);
             info!(self.log, "stats";
-                  "egress" => format!("{:2}", egress / s_interval),
-                  "ingress" => format!("{:2}", ingress / s_interval),
-                  "ingress-m" => format!("{:2}", ingress_m / s_interval),
-                  "a-err" => format!("{:2}", agr_errors / s_interval),
-                  "p-err" => format!("{:2}", parse_errors / s_interval),
-                  "pe-err" => format!("{:2}", peer_errors / s_interval),
-                  "drops" => format!("{:2}", drops / s_interval),
+                  "egress" => rate(0),
+                  "ingress" => rate(1),
+                  "ingress-m" => rate(2),
+                  "a-err" => rate(3),
+                  "p-err" => rate(4),
+                  "pe-err" => rate(5),
+                  "drops" => rate(6),
+                  "paused-drops" => rate(7),
+                  "filtered" => rate(8),
                   );
         }
     }
@@ -285,3 +544,27 @@ F: IntoFuture + Clone,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn own_stats_tags() {
+        let mut tags = BTreeMap::new();
+        tags.insert("dc".to_string(), "dc1".to_string());
+        assert_eq!(stats_tags(&tags, &Some("node".to_string()), "host;1"), ";dc=dc1;node=host_1");
+        assert_eq!(stats_tags(&BTreeMap::new(), &None, "host"), "");
+        assert_eq!(peer_name(&"10.0.0.1".parse().unwrap()), "10_0_0_1");
+    }
+
+    #[test]
+    fn cpu_lists() {
+        assert_eq!(parse_cpu_list("0-3,8\n"), Ok(vec![0, 1, 2, 3, 8]));
+        assert_eq!(parse_cpu_list("5"), Ok(vec![5]));
+        assert!(parse_cpu_list("").is_err());
+        assert!(parse_cpu_list("3-1").is_err());
+        assert!(parse_cpu_list("a-2").is_err());
+        assert_eq!(pin_thread(&[], 3).ok(), Some(()));
+    }
+}
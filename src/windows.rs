@@ -0,0 +1,239 @@
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+use std::time::{self, Duration, Instant, SystemTime};
+
+use futures::{Future, Stream};
+use lazy_static::lazy_static;
+use serde_derive::{Deserialize, Serialize};
+use slog::{info, o, warn, Logger};
+use tokio::timer::Interval;
+
+use crate::config::Window;
+use crate::events::event;
+use crate::reload::{is_reloadable, Reloader};
+use crate::RUNTIME_CONFIG;
+
+// windows are checked this often, so a window starts and ends within this time of it's schedule
+const CHECK_INTERVAL: u64 = 10000;
+
+// windows longer than this would need too many minutes to be checked
+pub const MAX_WINDOW_DURATION: u64 = 7 * 86400 * 1000;
+
+lazy_static! {
+    // names of windows with options applied to running configuration
+    static ref ACTIVE: RwLock<Vec<String>> = RwLock::new(Vec::new());
+}
+
+/// Cron-like schedule of minutes: minute, hour, day of month, month and day of week fields, in UTC
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    // cron matches either of days and weekdays if both are restricted
+    any_day: bool,
+}
+
+// bits of values allowed by a field like "*", "*/15", "1-5", "8-18/2" or a comma-separated list of them
+fn parse_field(field: &str, min: u64, max: u64) -> Result<(u64, bool), String> {
+    let mut bits = 0u64;
+    let mut all = false;
+    for part in field.split(',') {
+        let (range, step) = match part.find('/') {
+            Some(pos) => (&part[..pos], part[pos + 1..].parse::<u64>().map_err(|_| format!("bad step in {:?}", part))?),
+            None => (part, 1),
+        };
+        if step == 0 {
+            return Err(format!("zero step in {:?}", part));
+        }
+        let number = |value: &str| value.parse::<u64>().ok().filter(|value| *value >= min && *value <= max).ok_or_else(|| format!("{:?} is not from {} to {}", value, min, max));
+        let (first, last) = if range == "*" {
+            all = all || step == 1;
+            (min, max)
+        } else {
+            match range.find('-') {
+                Some(pos) => (number(&range[..pos])?, number(&range[pos + 1..])?),
+                None => {
+                    let value = number(range)?;
+                    (value, if step > 1 { max } else { value })
+                }
+            }
+        };
+        if first > last {
+            return Err(format!("bad range {:?}", range));
+        }
+        bits |= (first..=last).step_by(step as usize).fold(0, |bits, value| bits | 1u64 << value);
+    }
+    Ok((bits, all))
+}
+
+impl Schedule {
+    pub fn parse(schedule: &str) -> Result<Self, String> {
+        let fields = schedule.split_whitespace().collect::<Vec<_>>();
+        if fields.len() != 5 {
+            return Err(format!("schedule {:?} must have 5 fields: minute, hour, day of month, month and day of week", schedule));
+        }
+        let (minutes, _) = parse_field(fields[0], 0, 59)?;
+        let (hours, _) = parse_field(fields[1], 0, 23)?;
+        let (days, all_days) = parse_field(fields[2], 1, 31)?;
+        let (months, _) = parse_field(fields[3], 1, 12)?;
+        let (mut weekdays, all_weekdays) = parse_field(fields[4], 0, 7)?;
+        // both 0 and 7 are sunday
+        if weekdays & 1 << 7 != 0 {
+            weekdays |= 1;
+        }
+        Ok(Self { minutes, hours, days, months, weekdays, any_day: !all_days && !all_weekdays })
+    }
+
+    /// Whether the minute starting at `secs` since UNIX epoch is in the schedule
+    pub fn matches(&self, secs: u64) -> bool {
+        let (days_since_epoch, day_secs) = (secs / 86400, secs % 86400);
+        let (_, month, day) = civil_date(days_since_epoch);
+        // 1970-01-01 was thursday
+        let weekday = (days_since_epoch + 4) % 7;
+        let day_matches = if self.any_day { self.days & 1 << day != 0 || self.weekdays & 1 << weekday != 0 } else { self.days & 1 << day != 0 && self.weekdays & 1 << weekday != 0 };
+        self.minutes & 1 << (day_secs % 3600 / 60) != 0 && self.hours & 1 << (day_secs / 3600) != 0 && self.months & 1 << month != 0 && day_matches
+    }
+
+    /// Whether a window of `duration` ms starting at a scheduled minute covers `secs`
+    pub fn covers(&self, secs: u64, duration: u64) -> bool {
+        let minute = secs - secs % 60;
+        let minutes = (duration.min(MAX_WINDOW_DURATION) + 59999) / 60000;
+        (0..minutes).map(|back| minute.saturating_sub(back * 60)).filter(|start| secs < start + duration / 1000).any(|start| self.matches(start))
+    }
+}
+
+// year, month and day of the day since UNIX epoch, in proleptic gregorian calendar
+fn civil_date(days: u64) -> (u64, u64, u64) {
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    (yoe + era * 400 + if month <= 2 { 1 } else { 0 }, month, day)
+}
+
+/// Check a window can be applied: it's schedule is valid and it only sets options that can be reloaded
+pub fn validate_window(window: &Window) -> Result<(), String> {
+    Schedule::parse(&window.schedule)?;
+    if window.duration == 0 || window.duration > MAX_WINDOW_DURATION {
+        return Err(format!("duration must be from 1ms to {}ms", MAX_WINDOW_DURATION));
+    }
+    match window.set.keys().find(|key| !is_reloadable(key)) {
+        Some(key) => Err(format!("{} cannot be changed without restart", key)),
+        None => Ok(()),
+    }
+}
+
+/// Names of windows covering `secs`, windows with invalid schedules are never active
+pub fn active_windows(windows: &[Window], secs: u64) -> Vec<String> {
+    windows.iter().filter(|window| Schedule::parse(&window.schedule).map(|schedule| schedule.covers(secs, window.duration)).unwrap_or(false)).map(|window| window.name.clone()).collect()
+}
+
+/// Options set by active windows, to be applied on top of the file and command line, later windows win
+pub fn window_overrides() -> Vec<(String, String)> {
+    let active = ACTIVE.read().unwrap();
+    let config = RUNTIME_CONFIG.read().unwrap();
+    config.windows.iter().filter(|window| active.contains(&window.name)).flat_map(|window| window.set.iter().map(|(key, value)| (key.clone(), value.clone()))).collect()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct WindowStatus {
+    pub name: String,
+    pub schedule: String,
+    pub duration: u64,
+    pub set: BTreeMap<String, String>,
+    /// Options of the window are applied now
+    pub active: bool,
+}
+
+pub fn window_status() -> Vec<WindowStatus> {
+    let active = ACTIVE.read().unwrap();
+    RUNTIME_CONFIG.read().unwrap().windows.iter().map(|window| WindowStatus { name: window.name.clone(), schedule: window.schedule.clone(), duration: window.duration, set: window.set.clone(), active: active.contains(&window.name) }).collect()
+}
+
+/// A future starting and ending windows by their schedules, options of windows are applied by reloading configuration
+/// with them on top of it, the same way it is reloaded on SIGHUP. A reload failing is tried again on the next check.
+/// Never gets ready.
+pub fn run_windows(reloader: Reloader, log: Logger) -> impl Future<Item = (), Error = ()> {
+    let log = log.new(o!("source"=>"windows"));
+    let err_log = log.clone();
+    let interval = Duration::from_millis(CHECK_INTERVAL);
+    Interval::new(Instant::now(), interval)
+        .map_err(move |e| {
+            warn!(err_log, "window timer failed"; "error"=>e.to_string());
+        })
+        .for_each(move |_| {
+            let now = SystemTime::now().duration_since(time::UNIX_EPOCH).map(|now| now.as_secs()).unwrap_or(0);
+            let windows = RUNTIME_CONFIG.read().unwrap().windows.clone();
+            let active = active_windows(&windows, now);
+            let previous = ACTIVE.read().unwrap().clone();
+            if active == previous {
+                return Ok(());
+            }
+            *ACTIVE.write().unwrap() = active.clone();
+            match reloader.reload() {
+                Ok(report) => {
+                    for name in active.iter().filter(|name| !previous.contains(name)) {
+                        info!(log, "window started"; "window"=>name, "applied"=>report.applied.len());
+                        event("windows", format!("window {} started", name));
+                    }
+                    for name in previous.iter().filter(|name| !active.contains(name)) {
+                        info!(log, "window ended"; "window"=>name, "applied"=>report.applied.len());
+                        event("windows", format!("window {} ended", name));
+                    }
+                }
+                Err(e) => {
+                    warn!(log, "applying windows failed, trying again later"; "active"=>active.join(","), "error"=>e.to_string());
+                    *ACTIVE.write().unwrap() = previous;
+                }
+            }
+            Ok(())
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_schedules() {
+        // 2021-03-01 was monday
+        let monday = 18687 * 86400;
+        assert_eq!(civil_date(18687), (2021, 3, 1));
+        let schedule = Schedule::parse("0 10-18/4 * * 1-5").unwrap();
+        assert!(schedule.matches(monday + 10 * 3600));
+        assert!(schedule.matches(monday + 14 * 3600));
+        assert!(!schedule.matches(monday + 12 * 3600));
+        assert!(!schedule.matches(monday + 10 * 3600 + 60));
+        // sunday
+        assert!(!schedule.matches(monday - 86400 + 10 * 3600));
+        assert!(Schedule::parse("0 10 * *").is_err());
+        assert!(Schedule::parse("60 * * * *").is_err());
+        assert!(Schedule::parse("*/0 * * * *").is_err());
+
+        // days of month and of week match either
+        let schedule = Schedule::parse("30 2 15 * 0").unwrap();
+        assert!(schedule.matches(monday - 86400 + 2 * 3600 + 1800));
+        assert!(schedule.matches(monday + 14 * 86400 + 2 * 3600 + 1800));
+        assert!(!schedule.matches(monday + 2 * 3600 + 1800));
+
+        let schedule = Schedule::parse("0 10 * * *").unwrap();
+        assert!(schedule.covers(monday + 10 * 3600 + 1799, 1800000));
+        assert!(!schedule.covers(monday + 10 * 3600 + 1800, 1800000));
+        assert!(!schedule.covers(monday + 10 * 3600 - 1, 1800000));
+
+        let mut window = Window { name: "deploy".to_string(), schedule: "0 10 * * *".to_string(), duration: 1800000, set: BTreeMap::new() };
+        window.set.insert("carbon.address".to_string(), "127.0.0.2:2003".to_string());
+        assert_eq!(validate_window(&window), Ok(()));
+        assert_eq!(active_windows(&[window.clone()], monday + 10 * 3600 + 60), vec!["deploy".to_string()]);
+        window.set.insert("n-threads".to_string(), "8".to_string());
+        assert!(validate_window(&window).is_err());
+    }
+}
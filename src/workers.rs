@@ -0,0 +1,271 @@
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+
+use futures::sync::mpsc::Sender;
+use futures::sync::oneshot;
+use futures::{Future, Sink};
+use lazy_static::lazy_static;
+use serde_derive::{Deserialize, Serialize};
+use slog::{info, o, warn, Logger};
+use tokio::runtime::current_thread::Runtime;
+
+use crate::errors::GeneralError;
+use crate::events::event;
+use crate::queue::MAX_WORKERS;
+use crate::peer::send_to_peers;
+use crate::shutdown::{send_state, take_snapshot, take_state};
+use crate::task::Task;
+
+// caches of a removed worker must be taken in this time, or they are lost
+const DRAIN_TIMEOUT: u64 = 10000;
+
+/// Starts a counting worker with the index, it exits when the flag is set and it gets the next task
+pub type WorkerSpawner = Box<Fn(usize, Arc<AtomicBool>) -> io::Result<Sender<Task>> + Send + Sync>;
+
+struct Worker {
+    chan: Sender<Task>,
+    retired: Arc<AtomicBool>,
+}
+
+#[derive(Default)]
+struct WorkerPool {
+    workers: Vec<Worker>,
+    // workers below this index get ingested data, the rest are being drained
+    ingesting: usize,
+}
+
+lazy_static! {
+    static ref POOL: RwLock<WorkerPool> = RwLock::new(WorkerPool::default());
+    static ref SPAWNER: Mutex<Option<WorkerSpawner>> = Mutex::new(None);
+}
+
+// changed every time the pool is, so holders of worker sets know to take them again
+static GENERATION: AtomicUsize = AtomicUsize::new(0);
+// generation of channels returned by `init_workers`
+const INITIAL_GENERATION: usize = 1;
+// only one change of worker number at a time, draining takes a while
+static SCALING: AtomicBool = AtomicBool::new(false);
+
+/// Start `count` workers by `spawner`, which is kept to start more of them later, returns their channels
+pub fn init_workers(count: usize, spawner: WorkerSpawner) -> io::Result<Vec<Sender<Task>>> {
+    let workers = (0..count)
+        .map(|idx| {
+            let retired = Arc::new(AtomicBool::new(false));
+            spawner(idx, retired.clone()).map(|chan| Worker { chan, retired })
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    let chans = workers.iter().map(|worker| worker.chan.clone()).collect();
+    *POOL.write().unwrap() = WorkerPool { workers, ingesting: count };
+    *SPAWNER.lock().unwrap() = Some(spawner);
+    GENERATION.store(INITIAL_GENERATION, Ordering::SeqCst);
+    Ok(chans)
+}
+
+/// Channels of all workers holding caches, including ones being drained
+pub fn all_workers() -> Vec<Sender<Task>> {
+    POOL.read().unwrap().workers.iter().map(|worker| worker.chan.clone()).collect()
+}
+
+/// Channels of workers received metrics are distributed to
+pub fn ingesting_workers() -> Vec<Sender<Task>> {
+    let pool = POOL.read().unwrap();
+    pool.workers[..pool.ingesting].iter().map(|worker| worker.chan.clone()).collect()
+}
+
+/// Number of all workers, including ones being drained
+pub fn worker_count() -> usize {
+    POOL.read().unwrap().workers.len()
+}
+
+/// Worker channels kept by long-living senders, taken again when the number of workers changes.
+/// Channels given to a set must be the ones returned by `init_workers`, they are used as is until workers are changed
+/// for the first time, so sets made by restarted threads from them are still correct.
+#[derive(Debug, Clone)]
+pub struct WorkerSet {
+    chans: Vec<Sender<Task>>,
+    generation: usize,
+    ingesting: bool,
+}
+
+impl WorkerSet {
+    /// Workers to distribute received metrics to
+    pub fn for_ingestion(chans: Vec<Sender<Task>>) -> Self {
+        Self { chans, generation: INITIAL_GENERATION, ingesting: true }
+    }
+
+    /// All workers, for tasks that must reach every cache
+    pub fn for_caches(chans: Vec<Sender<Task>>) -> Self {
+        Self { chans, generation: INITIAL_GENERATION, ingesting: false }
+    }
+
+    /// Take the channels again if workers were changed since they were taken, must be called before choosing a worker
+    pub fn refresh(&mut self) {
+        let generation = GENERATION.load(Ordering::SeqCst);
+        if generation > self.generation {
+            self.chans = if self.ingesting { ingesting_workers() } else { all_workers() };
+            self.generation = generation;
+        }
+    }
+}
+
+impl Deref for WorkerSet {
+    type Target = Vec<Sender<Task>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.chans
+    }
+}
+
+impl DerefMut for WorkerSet {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.chans
+    }
+}
+
+/// Body of POST /workers
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct WorkersCommand {
+    pub count: usize,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct WorkersStatus {
+    /// Workers getting received metrics
+    pub workers: usize,
+    /// Removed workers not drained yet
+    pub draining: usize,
+}
+
+pub fn workers_status() -> WorkersStatus {
+    let pool = POOL.read().unwrap();
+    WorkersStatus { workers: pool.ingesting, draining: pool.workers.len() - pool.ingesting }
+}
+
+/// Workers to remove when scaling from `current` to `count`, none if workers are added or not changed
+pub fn retiring(current: usize, count: usize) -> std::ops::Range<usize> {
+    count.min(current)..current
+}
+
+/// Change the number of counting workers. New workers get received metrics right away. Removed ones stop getting them
+/// at once and are drained in background: metrics not replicated yet are sent to peer nodes with a snapshot of their own,
+/// then the caches of the interval are moved to the remaining workers, since this node flushes them when it is the leader,
+/// and the removed workers exit.
+pub fn scale_workers(count: usize, log: &Logger) -> Result<WorkersStatus, GeneralError> {
+    if count == 0 || count > MAX_WORKERS {
+        return Err(GeneralError::Workers(format!("number of workers must be from 1 to {}", MAX_WORKERS)));
+    }
+    if SCALING.swap(true, Ordering::SeqCst) {
+        return Err(GeneralError::Workers("removed workers are still being drained".to_string()));
+    }
+    let log = log.new(o!("source"=>"workers"));
+    let current = worker_count();
+    if count > current {
+        let spawner = SPAWNER.lock().unwrap();
+        let spawner = match spawner.as_ref() {
+            Some(spawner) => spawner,
+            None => {
+                SCALING.store(false, Ordering::SeqCst);
+                return Err(GeneralError::Workers("workers are not started yet".to_string()));
+            }
+        };
+        let mut added = Vec::new();
+        for idx in current..count {
+            let retired = Arc::new(AtomicBool::new(false));
+            match spawner(idx, retired.clone()) {
+                Ok(chan) => added.push(Worker { chan, retired }),
+                Err(e) => {
+                    // workers started already are used anyway
+                    warn!(log, "starting worker"; "worker"=>idx, "error"=>e.to_string());
+                    break;
+                }
+            }
+        }
+        let mut pool = POOL.write().unwrap();
+        pool.workers.extend(added);
+        pool.ingesting = pool.workers.len();
+        GENERATION.fetch_add(1, Ordering::SeqCst);
+        SCALING.store(false, Ordering::SeqCst);
+        info!(log, "workers added"; "workers"=>pool.ingesting);
+        event("workers", format!("workers scaled from {} to {}", current, pool.ingesting));
+        return Ok(WorkersStatus { workers: pool.ingesting, draining: 0 });
+    }
+    if count == current {
+        SCALING.store(false, Ordering::SeqCst);
+        return Ok(workers_status());
+    }
+
+    // removed workers are taken out of routing before their caches are taken, senders take the channels again
+    // before the next task, and tasks queued already are run by workers before the ones taking caches
+    let (remaining, draining) = {
+        let mut pool = POOL.write().unwrap();
+        pool.ingesting = count;
+        GENERATION.fetch_add(1, Ordering::SeqCst);
+        let chans = pool.workers.iter().map(|worker| worker.chan.clone()).collect::<Vec<_>>();
+        (chans[..count].to_vec(), chans[retiring(current, count)].to_vec())
+    };
+    info!(log, "workers removed, draining them"; "workers"=>count, "draining"=>current - count);
+    event("workers", format!("workers scaled from {} to {}", current, count));
+
+    thread::Builder::new()
+        .name("bioyino_drain".into())
+        .spawn(move || {
+            let timeout = Duration::from_millis(DRAIN_TIMEOUT);
+            let moved = Runtime::new().map_err(|e| e.to_string()).and_then(|mut runtime| {
+                // peers get metrics they have not got yet right away, instead of waiting for the snapshot sender
+                let (snapshot, replicated) = take_snapshot(&draining, &mut runtime, timeout)?;
+                if replicated > 0 {
+                    let peers = runtime.block_on(send_to_peers(snapshot, &log)).unwrap_or(0);
+                    info!(log, "metrics of removed workers sent to peers"; "metrics"=>replicated, "peers"=>peers);
+                }
+                let (state, _) = take_state(&draining, &mut runtime, timeout)?;
+                send_state(&state, &remaining)
+            });
+            match moved {
+                Ok(metrics) => info!(log, "removed workers drained"; "metrics"=>metrics),
+                Err(e) => warn!(log, "draining removed workers failed, their metrics are lost"; "error"=>e),
+            }
+
+            let retired = {
+                let mut pool = POOL.write().unwrap();
+                let retired = pool.workers.split_off(count);
+                GENERATION.fetch_add(1, Ordering::SeqCst);
+                retired
+            };
+            for worker in retired {
+                // the task wakes the worker up to see it is retired
+                worker.retired.store(true, Ordering::SeqCst);
+                let (tx, _) = oneshot::channel();
+                worker.chan.send(Task::Ping(tx)).wait().map(|_| ()).unwrap_or(());
+            }
+            SCALING.store(false, Ordering::SeqCst);
+        })
+        .map_err(|e| {
+            SCALING.store(false, Ordering::SeqCst);
+            GeneralError::Io(e)
+        })?;
+    Ok(workers_status())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn workers_retiring() {
+        assert_eq!(retiring(8, 6), 6..8);
+        assert_eq!(retiring(4, 6).len(), 0);
+        assert_eq!(retiring(4, 4).len(), 0);
+
+        let (tx, _rx) = futures::sync::mpsc::channel(1);
+        let mut set = WorkerSet::for_ingestion(vec![tx.clone(), tx]);
+        // the pool is not changed by tests, so channels given are kept
+        set.refresh();
+        assert_eq!(set.len(), 2);
+    }
+}